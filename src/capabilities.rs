@@ -0,0 +1,620 @@
+use crate::datetime::now_instant;
+use crate::model::CapabilityStatement::CapabilityStatement;
+use crate::model::CapabilityStatement_Interaction::CapabilityStatement_InteractionCode;
+use crate::model::CapabilityStatement_Interaction1::CapabilityStatement_Interaction1Code;
+use crate::model::CapabilityStatement_Resource::{
+  CapabilityStatement_ResourceConditionalDelete, CapabilityStatement_ResourceVersioning,
+};
+use crate::model::OperationDefinition::OperationDefinition;
+use crate::model::OperationOutcome_Issue::{
+  OperationOutcome_IssueCode, OperationOutcome_IssueSeverity,
+};
+use crate::model::SearchParameter::SearchParameter;
+use crate::outcome::Issue;
+use crate::search::SearchParameters;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+
+const EXPECTATION: &str = "http://hl7.org/fhir/StructureDefinition/capabilitystatement-expectation";
+
+/// How a server handles one resource type: the interactions, search
+/// parameters and operations it supports for it, which become a
+/// `CapabilityStatement.rest.resource`.
+#[derive(Debug, Clone)]
+pub struct ResourceHandler {
+  pub(crate) value: Value,
+}
+
+impl ResourceHandler {
+  pub fn new(resource_type: &str) -> ResourceHandler {
+    ResourceHandler {
+      value: json!({ "type": resource_type }),
+    }
+  }
+
+  pub fn resource_type(&self) -> &str {
+    self.value["type"].as_str().unwrap_or_default()
+  }
+
+  /// The profile that describes the resources overall.
+  pub fn profile<'a>(&'a mut self, url: &str) -> &'a mut ResourceHandler {
+    self.value["profile"] = json!(url);
+    self
+  }
+
+  pub fn supported_profile<'a>(&'a mut self, url: &str) -> &'a mut ResourceHandler {
+    push(&mut self.value, "supportedProfile", json!(url));
+    self
+  }
+
+  pub fn interaction(&mut self, code: CapabilityStatement_InteractionCode) -> &mut ResourceHandler {
+    push(
+      &mut self.value,
+      "interaction",
+      json!({ "code": code.to_string() }),
+    );
+    self
+  }
+
+  pub fn search_param<'a>(&'a mut self, parameter: &SearchParameter) -> &'a mut ResourceHandler {
+    push(&mut self.value, "searchParam", search_param(parameter));
+    self
+  }
+
+  /// Adds every parameter that applies to the resource type.
+  pub fn search_params<'a>(&'a mut self, parameters: &SearchParameters) -> &'a mut ResourceHandler {
+    let resource_type = self.resource_type().to_string();
+    for parameter in parameters.for_type(&resource_type) {
+      self.search_param(&parameter);
+    }
+    self
+  }
+
+  /// An `_include` the server supports, e.g. `Observation:subject`.
+  pub fn search_include<'a>(&'a mut self, include: &str) -> &'a mut ResourceHandler {
+    push(&mut self.value, "searchInclude", json!(include));
+    self
+  }
+
+  pub fn search_rev_include<'a>(&'a mut self, include: &str) -> &'a mut ResourceHandler {
+    push(&mut self.value, "searchRevInclude", json!(include));
+    self
+  }
+
+  pub fn operation<'a>(&'a mut self, operation: &OperationDefinition) -> &'a mut ResourceHandler {
+    push(&mut self.value, "operation", operation_entry(operation));
+    self
+  }
+
+  pub fn versioning(
+    &mut self,
+    versioning: CapabilityStatement_ResourceVersioning,
+  ) -> &mut ResourceHandler {
+    self.value["versioning"] = json!(versioning.to_string());
+    self
+  }
+
+  pub fn read_history(&mut self, read_history: bool) -> &mut ResourceHandler {
+    self.value["readHistory"] = json!(read_history);
+    self
+  }
+
+  /// Whether updates may create resources with ids the client chooses.
+  pub fn update_create(&mut self, update_create: bool) -> &mut ResourceHandler {
+    self.value["updateCreate"] = json!(update_create);
+    self
+  }
+
+  pub fn conditional_create(&mut self, conditional_create: bool) -> &mut ResourceHandler {
+    self.value["conditionalCreate"] = json!(conditional_create);
+    self
+  }
+
+  pub fn conditional_update(&mut self, conditional_update: bool) -> &mut ResourceHandler {
+    self.value["conditionalUpdate"] = json!(conditional_update);
+    self
+  }
+
+  pub fn conditional_delete(
+    &mut self,
+    conditional_delete: CapabilityStatement_ResourceConditionalDelete,
+  ) -> &mut ResourceHandler {
+    self.value["conditionalDelete"] = json!(conditional_delete.to_string());
+    self
+  }
+
+  pub fn documentation<'a>(&'a mut self, documentation: &str) -> &'a mut ResourceHandler {
+    self.value["documentation"] = json!(documentation);
+    self
+  }
+}
+
+/// Generates the CapabilityStatement of a server from the handlers it's
+/// configured with, so that what it publishes matches what it does.
+///
+/// ```
+/// use fhir_rs::capabilities::{CapabilityStatementGenerator, ResourceHandler};
+/// use fhir_rs::model::CapabilityStatement_Interaction::CapabilityStatement_InteractionCode;
+///
+/// let mut patients = ResourceHandler::new("Patient");
+/// patients
+///   .interaction(CapabilityStatement_InteractionCode::Read)
+///   .interaction(CapabilityStatement_InteractionCode::SearchType);
+/// let statement = CapabilityStatementGenerator::new()
+///   .software("Example Server", "1.0")
+///   .resource(patients)
+///   .generate()
+///   .to_json();
+/// assert_eq!(statement["rest"][0]["resource"][0]["interaction"][1]["code"], "search-type");
+/// ```
+#[derive(Debug, Clone)]
+pub struct CapabilityStatementGenerator {
+  value: Value,
+  rest: Value,
+  resources: Vec<ResourceHandler>,
+}
+
+impl Default for CapabilityStatementGenerator {
+  fn default() -> CapabilityStatementGenerator {
+    CapabilityStatementGenerator::new()
+  }
+}
+
+impl CapabilityStatementGenerator {
+  /// A generator for an `instance` statement of a FHIR 4.0.1 server that
+  /// speaks JSON.
+  pub fn new() -> CapabilityStatementGenerator {
+    CapabilityStatementGenerator {
+      value: json!({
+        "resourceType": "CapabilityStatement",
+        "status": "active",
+        "kind": "instance",
+        "fhirVersion": "4.0.1",
+        "format": ["json"],
+      }),
+      rest: json!({ "mode": "server" }),
+      resources: vec![],
+    }
+  }
+
+  pub fn url<'a>(&'a mut self, url: &str) -> &'a mut CapabilityStatementGenerator {
+    self.value["url"] = json!(url);
+    self
+  }
+
+  pub fn name<'a>(&'a mut self, name: &str) -> &'a mut CapabilityStatementGenerator {
+    self.value["name"] = json!(name);
+    self
+  }
+
+  pub fn software<'a>(
+    &'a mut self,
+    name: &str,
+    version: &str,
+  ) -> &'a mut CapabilityStatementGenerator {
+    self.value["software"] = json!({ "name": name, "version": version });
+    self
+  }
+
+  /// The deployment the statement describes, at its service base.
+  pub fn implementation<'a>(
+    &'a mut self,
+    description: &str,
+    url: &str,
+  ) -> &'a mut CapabilityStatementGenerator {
+    self.value["implementation"] = json!({ "description": description, "url": url });
+    self
+  }
+
+  /// Replaces the formats supported, e.g. `json` and `xml`.
+  pub fn formats<'a>(&'a mut self, formats: &[&str]) -> &'a mut CapabilityStatementGenerator {
+    self.value["format"] = json!(formats);
+    self
+  }
+
+  pub fn patch_format<'a>(&'a mut self, format: &str) -> &'a mut CapabilityStatementGenerator {
+    push(&mut self.value, "patchFormat", json!(format));
+    self
+  }
+
+  pub fn resource(&mut self, handler: ResourceHandler) -> &mut CapabilityStatementGenerator {
+    self.resources.push(handler);
+    self
+  }
+
+  /// A system-wide interaction, such as `transaction`.
+  pub fn interaction(
+    &mut self,
+    code: CapabilityStatement_Interaction1Code,
+  ) -> &mut CapabilityStatementGenerator {
+    push(
+      &mut self.rest,
+      "interaction",
+      json!({ "code": code.to_string() }),
+    );
+    self
+  }
+
+  /// A parameter for searches across all types.
+  pub fn search_param<'a>(
+    &'a mut self,
+    parameter: &SearchParameter,
+  ) -> &'a mut CapabilityStatementGenerator {
+    push(&mut self.rest, "searchParam", search_param(parameter));
+    self
+  }
+
+  /// An operation invoked at the base or on all types.
+  pub fn operation<'a>(
+    &'a mut self,
+    operation: &OperationDefinition,
+  ) -> &'a mut CapabilityStatementGenerator {
+    push(&mut self.rest, "operation", operation_entry(operation));
+    self
+  }
+
+  pub fn generate(&self) -> CapabilityStatement<'static> {
+    let mut rest = self.rest.clone();
+    if !self.resources.is_empty() {
+      rest["resource"] = Value::Array(self.resources.iter().map(|r| r.value.clone()).collect());
+    }
+    let mut value = self.value.clone();
+    value["date"] = json!(now_instant());
+    value["rest"] = json!([rest]);
+    CapabilityStatement {
+      value: Cow::Owned(value),
+    }
+  }
+}
+
+/// Adds an item to a list in a value, starting the list if there's none.
+fn push(value: &mut Value, key: &str, item: Value) {
+  match value[key].as_array_mut() {
+    Some(items) if items.contains(&item) => {}
+    Some(items) => items.push(item),
+    None => value[key] = json!([item]),
+  }
+}
+
+fn search_param(parameter: &SearchParameter) -> Value {
+  let mut entry = json!({
+    "name": parameter.code().unwrap_or_default(),
+    "type": parameter.value["type"],
+  });
+  if let Some(url) = parameter.url() {
+    entry["definition"] = json!(url);
+  }
+  entry
+}
+
+fn operation_entry(operation: &OperationDefinition) -> Value {
+  json!({
+    "name": operation.code().unwrap_or_default(),
+    "definition": operation.url().unwrap_or_default(),
+  })
+}
+
+/// Compares what a client requires, as a CapabilityStatement like those that
+/// describe what a kind of client needs, with what a server's statement
+/// offers. Each format, interaction, resource type, search parameter and
+/// operation that isn't offered is reported with the location of the
+/// requirement. Requirements marked `SHOULD` with the expectation extension
+/// give warnings and `MAY` information; `SHOULD-NOT` ones aren't reported.
+pub fn check_capabilities(
+  required: &CapabilityStatement,
+  offered: &CapabilityStatement,
+) -> Vec<Issue> {
+  let (required, offered) = (&*required.value, &*offered.value);
+  let mut issues = vec![];
+  if let (Some(version), Some(offered_version)) = (
+    required["fhirVersion"].as_str(),
+    offered["fhirVersion"].as_str(),
+  ) {
+    if version != offered_version {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::NotSupported,
+          &format!(
+            "FHIR {} is required, but the server offers {}",
+            version, offered_version
+          ),
+        )
+        .at("CapabilityStatement.fhirVersion"),
+      );
+    }
+  }
+  for key in &["format", "patchFormat"] {
+    for (index, format) in strings(&required[key]).enumerate() {
+      if !strings(&offered[key]).any(|offered| same_format(format, offered)) {
+        let path = format!("CapabilityStatement.{}[{}]", key, index);
+        let expected = &required[format!("_{}", key)][index];
+        let message = format!("The format {} isn't supported", format);
+        issues.extend(missing(expected, &message, &path));
+      }
+    }
+  }
+
+  let offered_rest = items(&offered["rest"]).collect::<Vec<_>>();
+  for (index, rest) in items(&required["rest"]).enumerate() {
+    let path = format!("CapabilityStatement.rest[{}]", index);
+    // A client's requirements are met by a server, and a server's by another.
+    let server = offered_rest
+      .iter()
+      .find(|offered| offered["mode"] == "server")
+      .or_else(|| offered_rest.first());
+    let server = match server {
+      Some(server) => server,
+      None => {
+        issues.extend(missing(rest, "No RESTful capabilities are offered", &path));
+        continue;
+      }
+    };
+    compare(rest, server, &path, "", &mut issues);
+    let offered_resources = items(&server["resource"]).collect::<Vec<_>>();
+    for (index, resource) in items(&rest["resource"]).enumerate() {
+      let path = format!("{}.resource[{}]", path, index);
+      let resource_type = resource["type"].as_str().unwrap_or_default();
+      match offered_resources
+        .iter()
+        .find(|offered| offered["type"] == resource_type)
+      {
+        Some(offered) => compare(resource, offered, &path, resource_type, &mut issues),
+        None => issues.extend(missing(
+          resource,
+          &format!("{} isn't supported", resource_type),
+          &path,
+        )),
+      }
+    }
+  }
+  issues
+}
+
+/// Compares the interactions, search parameters and operations of a
+/// `rest` element or one of its resources.
+fn compare(required: &Value, offered: &Value, path: &str, on: &str, issues: &mut Vec<Issue>) {
+  let on = match on {
+    "" => String::new(),
+    resource_type => format!(" on {}", resource_type),
+  };
+  let lists = [
+    ("interaction", "The interaction"),
+    ("searchParam", "The search parameter"),
+    ("operation", "The operation"),
+  ];
+  for (key, what) in &lists {
+    for (index, item) in items(&required[key]).enumerate() {
+      if !items(&offered[key]).any(|offered| same(key, item, offered)) {
+        let name = item["code"].as_str().or_else(|| item["name"].as_str());
+        let message = format!(
+          "{} {} isn't supported{}",
+          what,
+          name.unwrap_or_default(),
+          on
+        );
+        issues.extend(missing(
+          item,
+          &message,
+          &format!("{}.{}[{}]", path, key, index),
+        ));
+      }
+    }
+  }
+}
+
+/// Whether an offered interaction, search parameter or operation is the one
+/// required.
+fn same(key: &str, required: &Value, offered: &Value) -> bool {
+  match key {
+    "interaction" => required["code"] == offered["code"],
+    "operation" => {
+      let definition = canonical(&required["definition"]);
+      required["name"] == offered["name"]
+        || definition.is_some() && definition == canonical(&offered["definition"])
+    }
+    _ => required["name"] == offered["name"],
+  }
+}
+
+/// The issue for a requirement that isn't met, as serious as its
+/// expectation.
+fn missing(required: &Value, message: &str, path: &str) -> Option<Issue> {
+  let expectation = items(&required["extension"])
+    .find(|extension| extension["url"] == EXPECTATION)
+    .and_then(|extension| extension["valueCode"].as_str());
+  let severity = match expectation {
+    None | Some("SHALL") => OperationOutcome_IssueSeverity::Error,
+    Some("SHOULD") => OperationOutcome_IssueSeverity::Warning,
+    Some("MAY") => OperationOutcome_IssueSeverity::Information,
+    Some(_) => return None,
+  };
+  Some(Issue::new(severity, OperationOutcome_IssueCode::NotSupported, message).at(path))
+}
+
+/// Whether two formats, as codes like `json` or MIME types, are the same.
+fn same_format(a: &str, b: &str) -> bool {
+  fn family(format: &str) -> &str {
+    match format.split(';').next().unwrap_or_default().trim() {
+      "json" | "application/fhir+json" | "application/json+fhir" | "application/json" => "json",
+      "xml" | "application/fhir+xml" | "application/xml+fhir" | "application/xml" => "xml",
+      "ttl" | "text/turtle" | "application/x-turtle" => "ttl",
+      other => other,
+    }
+  }
+  family(a) == family(b)
+}
+
+/// A canonical URL without its version.
+fn canonical(url: &Value) -> Option<&str> {
+  url.as_str().map(|url| url.split('|').next().unwrap_or(url))
+}
+
+fn items(value: &Value) -> impl Iterator<Item = &Value> {
+  value.as_array().into_iter().flatten()
+}
+
+fn strings(value: &Value) -> impl Iterator<Item = &str> {
+  items(value).filter_map(Value::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::Path;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  fn diagnostics(issues: &[Issue]) -> Vec<(&str, &str)> {
+    issues
+      .iter()
+      .map(|issue| {
+        (
+          issue.expression.as_deref().unwrap_or_default(),
+          issue.diagnostics.as_str(),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_generate() {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let mut patients = ResourceHandler::new("Patient");
+    patients
+      .interaction(CapabilityStatement_InteractionCode::Read)
+      .interaction(CapabilityStatement_InteractionCode::Read)
+      .interaction(CapabilityStatement_InteractionCode::SearchType)
+      .search_params(&parameters)
+      .search_rev_include("Observation:subject")
+      .versioning(CapabilityStatement_ResourceVersioning::VersionedUpdate)
+      .conditional_delete(CapabilityStatement_ResourceConditionalDelete::Single);
+    let statement = CapabilityStatementGenerator::new()
+      .software("Example Server", "1.0")
+      .patch_format("application/json-patch+json")
+      .resource(patients)
+      .interaction(CapabilityStatement_Interaction1Code::Transaction)
+      .generate();
+    let json = statement.to_json();
+    assert_eq!(json["fhirVersion"], "4.0.1");
+    assert!(json["date"].is_string());
+    let rest = &json["rest"][0];
+    assert_eq!(rest["mode"], "server");
+    assert_eq!(rest["interaction"], json!([{ "code": "transaction" }]));
+    let resource = &rest["resource"][0];
+    assert_eq!(
+      resource["interaction"],
+      json!([{ "code": "read" }, { "code": "search-type" }])
+    );
+    assert_eq!(resource["versioning"], "versioned-update");
+    assert_eq!(resource["conditionalDelete"], "single");
+    let family = resource["searchParam"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|parameter| parameter["name"] == "family")
+      .unwrap();
+    assert_eq!(family["type"], "string");
+    assert_eq!(
+      family["definition"],
+      "http://hl7.org/fhir/SearchParameter/individual-family"
+    );
+
+    assert!(check_capabilities(&statement, &statement).is_empty());
+  }
+
+  #[test]
+  fn test_check() {
+    let required = load("examples-json/capabilitystatement-phr-example.json");
+    let offered = load("examples-json/capabilitystatement-example.json");
+    let issues = check_capabilities(
+      &CapabilityStatement {
+        value: Cow::Borrowed(&required),
+      },
+      &CapabilityStatement {
+        value: Cow::Borrowed(&offered),
+      },
+    );
+    assert_eq!(
+      diagnostics(&issues),
+      vec![
+        (
+          "CapabilityStatement.rest[0].resource[0].interaction[1]",
+          "The interaction search-type isn't supported on Patient"
+        ),
+        (
+          "CapabilityStatement.rest[0].resource[1]",
+          "DocumentReference isn't supported"
+        ),
+        (
+          "CapabilityStatement.rest[0].resource[2]",
+          "Condition isn't supported"
+        ),
+        (
+          "CapabilityStatement.rest[0].resource[3]",
+          "DiagnosticReport isn't supported"
+        ),
+      ]
+    );
+
+    let mut required = json!({
+      "resourceType": "CapabilityStatement",
+      "format": ["application/fhir+json", "ttl"],
+      "rest": [{
+        "mode": "client",
+        "resource": [{
+          "type": "Patient",
+          "interaction": [
+            { "code": "read" },
+            { "code": "delete", "extension": [{ "url": EXPECTATION, "valueCode": "SHOULD" }] },
+            { "code": "patch", "extension": [{ "url": EXPECTATION, "valueCode": "SHOULD-NOT" }] }
+          ],
+          "searchParam": [
+            { "name": "identifier", "type": "token" },
+            { "name": "birthdate", "type": "date",
+              "extension": [{ "url": EXPECTATION, "valueCode": "MAY" }] }
+          ]
+        }]
+      }]
+    });
+    required["_format"] =
+      json!([null, { "extension": [{ "url": EXPECTATION, "valueCode": "SHOULD" }] }]);
+    let issues = check_capabilities(
+      &CapabilityStatement {
+        value: Cow::Borrowed(&required),
+      },
+      &CapabilityStatement {
+        value: Cow::Borrowed(&offered),
+      },
+    );
+    let severities = issues
+      .iter()
+      .map(|issue| {
+        (
+          issue.expression.as_deref().unwrap(),
+          issue.severity.to_string(),
+        )
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      severities,
+      vec![
+        ("CapabilityStatement.format[1]", "warning".to_string()),
+        (
+          "CapabilityStatement.rest[0].resource[0].interaction[1]",
+          "warning".to_string()
+        ),
+        (
+          "CapabilityStatement.rest[0].resource[0].searchParam[1]",
+          "information".to_string()
+        ),
+      ]
+    );
+  }
+}
@@ -0,0 +1,683 @@
+//! An async client for the FHIR RESTful API
+//! (http://hl7.org/fhir/http.html) with typed interactions, e.g.
+//! `client.read::<Patient>("example")`. Only built with the `client` feature.
+//!
+//! Requests go through a [`Transport`], by default [`HttpTransport`], which
+//! speaks plain HTTP, and https with the `tls` feature. Without that feature,
+//! reaching a server over TLS takes a custom [`Transport`]. Error responses become [`Error::Status`], with the issues of
+//! the OperationOutcome the server sent.
+
+mod resource;
+mod transport;
+
+pub use self::resource::Resource;
+pub use self::transport::{HttpTransport, Sending, Transport};
+pub use crate::http::{Request, Response};
+/// The TLS library for [`HttpTransport::tls`].
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+
+use crate::capabilities::check_capabilities;
+use crate::http::reason;
+use crate::model::Bundle::Bundle;
+use crate::model::CapabilityStatement::CapabilityStatement;
+use crate::model::OperationOutcome::OperationOutcome;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::Parameters::Parameters;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::{issues, Issue};
+use crate::search::query_string;
+use crate::util::service_base;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+
+const FHIR_JSON: &str = "application/fhir+json";
+const JSON_PATCH: &str = "application/json-patch+json";
+const RETURN_REPRESENTATION: (&str, &str) = ("Prefer", "return=representation");
+
+/// Why an interaction failed.
+#[derive(Debug)]
+pub enum Error {
+  /// The server couldn't be reached, or the connection failed.
+  Io(io::Error),
+  /// The server refused the request with this status, for the reasons in
+  /// the OperationOutcome it sent, or else the one the status gives.
+  Status { status: u16, issues: Vec<Issue> },
+  /// A resource to send, or the response, isn't what the interaction needs.
+  Invalid(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Io(error) => write!(f, "{}", error),
+      Error::Status { status, issues } => {
+        let reasons = issues
+          .iter()
+          .map(|issue| issue.diagnostics.as_str())
+          .collect::<Vec<_>>();
+        write!(f, "HTTP {}: {}", status, reasons.join("; "))
+      }
+      Error::Invalid(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for Error {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      Error::Io(error) => Some(error),
+      _ => None,
+    }
+  }
+}
+
+impl From<io::Error> for Error {
+  fn from(error: io::Error) -> Error {
+    Error::Io(error)
+  }
+}
+
+/// A client for one FHIR server.
+///
+/// ```no_run
+/// use fhir_rs::client::Client;
+/// use fhir_rs::model::Observation::Observation;
+/// use fhir_rs::model::Patient::Patient;
+///
+/// # async fn example() -> Result<(), fhir_rs::client::Error> {
+/// let client = Client::new("http://example.org/fhir");
+/// let patient = client.read::<Patient>("example").await?;
+/// let observations = client
+///   .search::<Observation>(&[("subject", "Patient/example"), ("code", "8867-4")])
+///   .await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client {
+  base: String,
+  transport: Box<dyn Transport>,
+  headers: Vec<(String, String)>,
+}
+
+impl Client {
+  /// A client for the server at a service base URL, e.g.
+  /// `http://example.org/fhir`, over an [`HttpTransport`]. Without the
+  /// `tls` feature, that can't reach https URLs.
+  pub fn new(base: &str) -> Client {
+    Client::with_transport(base, HttpTransport::new())
+  }
+
+  pub fn with_transport<T: Transport + 'static>(base: &str, transport: T) -> Client {
+    let base = service_base(base);
+    Client {
+      base,
+      transport: Box::new(transport),
+      headers: vec![],
+    }
+  }
+
+  /// The service base URL, ending with `/`.
+  pub fn base(&self) -> &str {
+    &self.base
+  }
+
+  /// Adds a header to every request.
+  pub fn header<'a>(&'a mut self, name: &str, value: &str) -> &'a mut Client {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  /// What the server supports, from its CapabilityStatement.
+  pub async fn capabilities(&self) -> Result<CapabilityStatement<'static>, Error> {
+    typed(self.send("GET", &self.url("metadata"), None, &[]).await?)
+  }
+
+  /// Checks what the server supports against what's required, as
+  /// [`check_capabilities`] does.
+  pub async fn check_capabilities(
+    &self,
+    required: &CapabilityStatement<'_>,
+  ) -> Result<Vec<Issue>, Error> {
+    Ok(check_capabilities(required, &self.capabilities().await?))
+  }
+
+  pub async fn read<R: Resource>(&self, id: &str) -> Result<R, Error> {
+    let url = self.url(&format!("{}/{}", R::TYPE, id));
+    typed(self.send("GET", &url, None, &[]).await?)
+  }
+
+  pub async fn vread<R: Resource>(&self, id: &str, version_id: &str) -> Result<R, Error> {
+    let url = self.url(&format!("{}/{}/_history/{}", R::TYPE, id, version_id));
+    typed(self.send("GET", &url, None, &[]).await?)
+  }
+
+  /// Every resource of a type that matches a search, from all the pages of
+  /// results. Resources that are only included aren't among them; they're in
+  /// the Bundles [`Client::pages`] gives.
+  pub async fn search<R: Resource>(&self, parameters: &[(&str, &str)]) -> Result<Vec<R>, Error> {
+    let mut pages = self.pages(Some(R::TYPE), parameters);
+    let mut found = vec![];
+    while let Some(page) = pages.next().await {
+      for entry in items(&page?.value["entry"]) {
+        let mode = entry["search"]["mode"].as_str();
+        if mode.is_none_or(|mode| mode == "match") && entry["resource"]["resourceType"] == R::TYPE {
+          found.push(R::from_json(entry["resource"].clone()));
+        }
+      }
+    }
+    Ok(found)
+  }
+
+  /// The pages of results of a search of a type, or of all types when
+  /// there's none, fetched as they're asked for.
+  pub fn pages<'c>(
+    &'c self,
+    resource_type: Option<&str>,
+    parameters: &[(&str, &str)],
+  ) -> Pages<'c> {
+    let mut url = self.url(resource_type.unwrap_or_default());
+    if !parameters.is_empty() {
+      url = format!("{}?{}", url, query_string(parameters));
+    }
+    Pages {
+      client: self,
+      next: Some(url),
+    }
+  }
+
+  /// Creates a resource, giving it back as the server stored it.
+  pub async fn create<R: Resource>(&self, resource: &R) -> Result<R, Error> {
+    let url = self.url(R::TYPE);
+    let body = Some((FHIR_JSON, resource.json()));
+    let response = self
+      .send("POST", &url, body, &[RETURN_REPRESENTATION])
+      .await?;
+    self.written(response).await
+  }
+
+  /// Updates a resource, or creates it with the id it has, giving it back as
+  /// the server stored it. With a `version_id`, the update only happens if
+  /// that's still the current version.
+  pub async fn update<R: Resource>(
+    &self,
+    resource: &R,
+    version_id: Option<&str>,
+  ) -> Result<R, Error> {
+    let id = match resource.json()["id"].as_str() {
+      Some(id) => id,
+      None => {
+        return Err(Error::Invalid(format!(
+          "A {} needs an id to be updated",
+          R::TYPE
+        )))
+      }
+    };
+    let url = self.url(&format!("{}/{}", R::TYPE, id));
+    let if_match = version_id.map(|version_id| format!("W/\"{}\"", version_id));
+    let mut headers = vec![RETURN_REPRESENTATION];
+    headers.extend(if_match.as_deref().map(|if_match| ("If-Match", if_match)));
+    let body = Some((FHIR_JSON, resource.json()));
+    let response = self.send("PUT", &url, body, &headers).await?;
+    self.written(response).await
+  }
+
+  /// Applies a JSON Patch document to a resource, giving back the patched
+  /// resource. With a `version_id`, the patch only applies if that's still the
+  /// current version.
+  pub async fn patch<R: Resource>(
+    &self,
+    id: &str,
+    patch: &Value,
+    version_id: Option<&str>,
+  ) -> Result<R, Error> {
+    let url = self.url(&format!("{}/{}", R::TYPE, id));
+    let if_match = version_id.map(|version_id| format!("W/\"{}\"", version_id));
+    let mut headers = vec![RETURN_REPRESENTATION];
+    headers.extend(if_match.as_deref().map(|if_match| ("If-Match", if_match)));
+    let response = self
+      .send("PATCH", &url, Some((JSON_PATCH, patch)), &headers)
+      .await?;
+    self.written(response).await
+  }
+
+  pub async fn delete<R: Resource>(&self, id: &str) -> Result<(), Error> {
+    let url = self.url(&format!("{}/{}", R::TYPE, id));
+    self.send("DELETE", &url, None, &[]).await.map(|_| ())
+  }
+
+  /// Sends a `transaction` or `batch` Bundle, giving back the Bundle of
+  /// responses.
+  pub async fn transaction(&self, bundle: &Bundle<'_>) -> Result<Bundle<'static>, Error> {
+    let body = Some((FHIR_JSON, &*bundle.value));
+    typed(self.send("POST", &self.base, body, &[]).await?)
+  }
+
+  /// Invokes an operation such as `$everything` on the server when `target`
+  /// is empty, on a type such as `Patient`, or on an instance such as
+  /// `Patient/example`. The result is the resource the operation returns,
+  /// often Parameters.
+  pub async fn operation(
+    &self,
+    target: &str,
+    name: &str,
+    parameters: &Parameters<'_>,
+  ) -> Result<ResourceList<'static>, Error> {
+    let name = name.trim_start_matches('$');
+    let url = match target.trim_matches('/') {
+      "" => self.url(&format!("${}", name)),
+      target => self.url(&format!("{}/${}", target, name)),
+    };
+    let body = Some((FHIR_JSON, &*parameters.value));
+    let response = self.send("POST", &url, body, &[]).await?;
+    match json(&response)? {
+      value if value["resourceType"].is_string() => Ok(ResourceList {
+        value: Cow::Owned(value),
+      }),
+      _ => Err(Error::Invalid(format!(
+        "The ${} operation didn't return a resource",
+        name
+      ))),
+    }
+  }
+
+  fn url(&self, path: &str) -> String {
+    format!("{}{}", self.base, path)
+  }
+
+  /// A URL the server gave, such as a `Location` or a `next` link, which
+  /// must be on the server so that requests, and any credentials they
+  /// carry, don't go elsewhere.
+  fn on_server(&self, url: &str) -> Result<String, Error> {
+    let absolute = url.starts_with("http://") || url.starts_with("https://");
+    match url {
+      _ if !absolute => Ok(self.url(url.trim_start_matches('/'))),
+      _ if url.starts_with(&self.base) || format!("{}/", url) == self.base => Ok(url.to_string()),
+      _ => Err(Error::Invalid(format!("{} isn't on the server", url))),
+    }
+  }
+
+  async fn send(
+    &self,
+    method: &str,
+    url: &str,
+    body: Option<(&str, &Value)>,
+    headers: &[(&str, &str)],
+  ) -> Result<Response, Error> {
+    let mut request = Request::new(method, url);
+    request.header("Accept", FHIR_JSON);
+    for (name, value) in &self.headers {
+      request.header(name, value);
+    }
+    for (name, value) in headers {
+      request.header(name, value);
+    }
+    if let Some((content_type, body)) = body {
+      request
+        .header("Content-Type", content_type)
+        .body(serde_json::to_vec(body).unwrap_or_default());
+    }
+    let response = self.transport.send(request).await?;
+    match response.status {
+      200..=299 => Ok(response),
+      status => Err(Error::Status {
+        status,
+        issues: failure(&response),
+      }),
+    }
+  }
+
+  /// The resource a write gives back, or else the one at its `Location`
+  /// when the server sent nothing.
+  async fn written<R: Resource>(&self, response: Response) -> Result<R, Error> {
+    if !response.body.is_empty() {
+      return typed(response);
+    }
+    match response
+      .get("Location")
+      .or_else(|| response.get("Content-Location"))
+    {
+      Some(location) => {
+        let url = self.on_server(location)?;
+        typed(self.send("GET", &url, None, &[]).await?)
+      }
+      None => Err(Error::Invalid(format!(
+        "The server didn't return the {}",
+        R::TYPE
+      ))),
+    }
+  }
+}
+
+/// The pages of results of a search, each a `searchset` Bundle, which are
+/// fetched by following `next` links.
+pub struct Pages<'c> {
+  client: &'c Client,
+  next: Option<String>,
+}
+
+impl Pages<'_> {
+  /// The next page, or `None` after the last one.
+  pub async fn next(&mut self) -> Option<Result<Bundle<'static>, Error>> {
+    let url = self.next.take()?;
+    let page = match self.client.send("GET", &url, None, &[]).await {
+      Ok(response) => typed::<Bundle>(response),
+      Err(error) => Err(error),
+    };
+    if let Ok(page) = &page {
+      let next = items(&page.value["link"])
+        .find(|link| link["relation"] == "next")
+        .and_then(|link| link["url"].as_str());
+      match next.map(|next| self.client.on_server(next)) {
+        Some(Ok(next)) if next != url => self.next = Some(next),
+        Some(Err(error)) => return Some(Err(error)),
+        _ => {}
+      }
+    }
+    Some(page)
+  }
+}
+
+fn json(response: &Response) -> Result<Value, Error> {
+  serde_json::from_slice(&response.body)
+    .map_err(|error| Error::Invalid(format!("The response isn't JSON: {}", error)))
+}
+
+fn typed<R: Resource>(response: Response) -> Result<R, Error> {
+  let value = json(&response)?;
+  match value["resourceType"].as_str() {
+    Some(resource_type) if resource_type == R::TYPE => Ok(R::from_json(value)),
+    resource_type => Err(Error::Invalid(format!(
+      "Expected a {} but got {}",
+      R::TYPE,
+      resource_type.unwrap_or("something else")
+    ))),
+  }
+}
+
+/// The issues of an error response, from its OperationOutcome or else its
+/// status.
+fn failure(response: &Response) -> Vec<Issue> {
+  let value = serde_json::from_slice::<Value>(&response.body).unwrap_or_default();
+  let mut found = match value["resourceType"] == "OperationOutcome" {
+    true => issues(&OperationOutcome {
+      value: Cow::Borrowed(&value),
+    }),
+    false => vec![],
+  };
+  if found.is_empty() {
+    let code = match response.status {
+      401 => OperationOutcome_IssueCode::Login,
+      403 => OperationOutcome_IssueCode::Forbidden,
+      404 => OperationOutcome_IssueCode::NotFound,
+      405 | 501 => OperationOutcome_IssueCode::NotSupported,
+      409 | 412 => OperationOutcome_IssueCode::Conflict,
+      410 => OperationOutcome_IssueCode::Deleted,
+      422 => OperationOutcome_IssueCode::Processing,
+      400..=499 => OperationOutcome_IssueCode::Invalid,
+      _ => OperationOutcome_IssueCode::Exception,
+    };
+    let diagnostics = format!("{} {}", response.status, reason(response.status));
+    found.push(Issue::error(code, diagnostics.trim_end()));
+  }
+  found
+}
+
+fn items(value: &Value) -> impl Iterator<Item = &Value> {
+  value.as_array().into_iter().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::http::stand_in;
+  use crate::model::Observation::Observation;
+  use crate::model::Patient::Patient;
+  use serde_json::json;
+  use std::future::Future;
+  use std::sync::{Arc, Mutex};
+
+  pub(super) fn run<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+      .enable_all()
+      .build()
+      .unwrap()
+      .block_on(future)
+  }
+
+  fn respond(status: u16, body: Value) -> Response {
+    let mut response = Response::new(status);
+    response.header("Content-Type", FHIR_JSON);
+    response.body = serde_json::to_vec(&body).unwrap();
+    response
+  }
+
+  #[test]
+  fn test_client() {
+    let requests = Arc::new(Mutex::new(vec![]));
+    let seen = requests.clone();
+    let address = stand_in(move |request| {
+      seen.lock().unwrap().push(request.clone());
+      let patient = json!({"resourceType": "Patient", "id": "1", "meta": {"versionId": "2"}});
+      let observation = |id: &str| {
+        json!({"fullUrl": format!("Observation/{}", id), "search": {"mode": "match"},
+               "resource": {"resourceType": "Observation", "id": id}})
+      };
+      match (request.method.as_str(), request.target.as_str()) {
+        ("GET", "/fhir/metadata") => respond(
+          200,
+          json!({"resourceType": "CapabilityStatement", "fhirVersion": "4.0.1", "format": ["json"],
+                 "rest": [{"mode": "server", "resource": [{"type": "Patient", "interaction": [{"code": "read"}]}]}]}),
+        ),
+        ("GET", "/fhir/Patient/1") | ("GET", "/fhir/Patient/1/_history/2") => respond(200, patient),
+        ("GET", "/fhir/Patient/2") => respond(
+          404,
+          json!({"resourceType": "OperationOutcome", "issue": [
+            {"severity": "error", "code": "not-found", "diagnostics": "Patient/2 isn't known"}]}),
+        ),
+        ("GET", "/fhir/Patient/3") => Response::new(500),
+        ("GET", "/fhir/Patient/4") => respond(200, json!({"resourceType": "Observation"})),
+        ("GET", "/fhir/Observation?code=http://loinc.org%7C8867-4") => respond(
+          200,
+          json!({"resourceType": "Bundle", "type": "searchset",
+                 "link": [{"relation": "next", "url": format!("{}/fhir/Observation?_offset=1", request.get("Host").map(|host| format!("http://{}", host)).unwrap())}],
+                 "entry": [observation("a"), {"search": {"mode": "include"}, "resource": {"resourceType": "Patient", "id": "1"}}]}),
+        ),
+        ("GET", "/fhir/Observation?_offset=1") => respond(
+          200,
+          json!({"resourceType": "Bundle", "type": "searchset", "entry": [observation("b")]}),
+        ),
+        ("GET", "/fhir/Observation?status=final") => respond(
+          200,
+          json!({"resourceType": "Bundle", "type": "searchset",
+                 "link": [{"relation": "next", "url": "http://elsewhere.example.org/fhir/Observation"}]}),
+        ),
+        ("POST", "/fhir/Patient") => {
+          let mut response = Response::new(201);
+          response.header("Location", "Patient/1/_history/2");
+          response
+        }
+        ("PUT", "/fhir/Patient/1") | ("PATCH", "/fhir/Patient/1") => respond(200, patient),
+        ("DELETE", "/fhir/Patient/1") => Response::new(204),
+        ("POST", "/fhir/") => respond(
+          200,
+          json!({"resourceType": "Bundle", "type": "transaction-response"}),
+        ),
+        ("POST", "/fhir/Patient/1/$everything") => {
+          respond(200, json!({"resourceType": "Bundle", "type": "searchset"}))
+        }
+        _ => Response::new(400),
+      }
+    });
+    let mut client = Client::new(&format!("http://{}/fhir", address));
+    client.header("Authorization", "Bearer secret");
+
+    run(async {
+      let statement = client.capabilities().await.unwrap();
+      assert_eq!(statement.fhir_version().unwrap().to_string(), "4.0.1");
+      let required = json!({"resourceType": "CapabilityStatement", "rest": [{"mode": "client",
+        "resource": [{"type": "Patient", "interaction": [{"code": "read"}, {"code": "delete"}]}]}]});
+      let issues = client
+        .check_capabilities(&CapabilityStatement {
+          value: Cow::Borrowed(&required),
+        })
+        .await
+        .unwrap();
+      assert_eq!(issues.len(), 1);
+
+      let patient = client.read::<Patient>("1").await.unwrap();
+      assert_eq!(patient.id(), Some("1"));
+      assert!(client.vread::<Patient>("1", "2").await.is_ok());
+      match client.read::<Patient>("2").await {
+        Err(Error::Status {
+          status: 404,
+          issues,
+        }) => {
+          assert_eq!(issues[0].code.to_string(), "not-found");
+          assert_eq!(issues[0].diagnostics, "Patient/2 isn't known");
+        }
+        other => panic!("{:?}", other.map(|patient| patient.to_json())),
+      }
+      match client.read::<Patient>("3").await {
+        Err(Error::Status {
+          status: 500,
+          issues,
+        }) => {
+          assert_eq!(issues[0].diagnostics, "500 Internal Server Error")
+        }
+        other => panic!("{:?}", other.map(|patient| patient.to_json())),
+      }
+      assert!(matches!(
+        client.read::<Patient>("4").await,
+        Err(Error::Invalid(_))
+      ));
+
+      let observations = client
+        .search::<Observation>(&[("code", "http://loinc.org|8867-4")])
+        .await
+        .unwrap();
+      let ids = observations
+        .iter()
+        .map(|observation| observation.id().unwrap())
+        .collect::<Vec<_>>();
+      assert_eq!(ids, vec!["a", "b"]);
+      let elsewhere = client.search::<Observation>(&[("status", "final")]).await;
+      assert!(matches!(elsewhere, Err(Error::Invalid(_))));
+
+      assert_eq!(client.create(&patient).await.unwrap().id(), Some("1"));
+      client.update(&patient, Some("2")).await.unwrap();
+      let patch = json!([{"op": "replace", "path": "/active", "value": false}]);
+      client.patch::<Patient>("1", &patch, None).await.unwrap();
+      client.delete::<Patient>("1").await.unwrap();
+      let unsaved = Patient::from_json(json!({"resourceType": "Patient"}));
+      assert!(matches!(
+        client.update(&unsaved, None).await,
+        Err(Error::Invalid(_))
+      ));
+
+      let bundle = Bundle::from_json(json!({"resourceType": "Bundle", "type": "transaction"}));
+      let response = client.transaction(&bundle).await.unwrap();
+      assert_eq!(response.value["type"], "transaction-response");
+      let parameters = Parameters::from_json(json!({"resourceType": "Parameters"}));
+      let everything = client
+        .operation("Patient/1", "$everything", &parameters)
+        .await
+        .unwrap();
+      assert_eq!(everything.value["resourceType"], "Bundle");
+    });
+
+    let requests = requests.lock().unwrap();
+    assert!(requests
+      .iter()
+      .all(|request| request.get("Authorization") == Some("Bearer secret")));
+    let find = |method: &str| {
+      requests
+        .iter()
+        .find(|request| request.method == method)
+        .unwrap()
+    };
+    assert_eq!(find("PUT").get("If-Match"), Some("W/\"2\""));
+    assert_eq!(find("PATCH").get("Content-Type"), Some(JSON_PATCH));
+    assert_eq!(find("POST").get("Prefer"), Some("return=representation"));
+
+    // Without the tls feature, https isn't supported rather than sent in the clear.
+    #[cfg(not(feature = "tls"))]
+    {
+      let secure = Client::new("https://example.org/fhir");
+      match run(secure.read::<Patient>("1")) {
+        Err(Error::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::Unsupported),
+        other => panic!("{:?}", other.map(|patient| patient.to_json())),
+      }
+    }
+  }
+
+  #[cfg(feature = "server")]
+  #[test]
+  fn test_with_server() {
+    use crate::repository::Repository;
+    use crate::search::SearchParameters;
+    use crate::server::Server;
+    use std::path::Path;
+
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let running = Server::new(Repository::new(parameters))
+      .bind("127.0.0.1:0")
+      .unwrap();
+    let client = Client::new(&format!("http://{}", running.address()));
+
+    run(async {
+      let patient =
+        Patient::from_json(json!({"resourceType": "Patient", "name": [{"family": "Chalmers"}]}));
+      let created = client.create(&patient).await.unwrap();
+      let id = created.id().unwrap();
+      let meta = created.meta().unwrap();
+      let version = meta.version_id().unwrap();
+      let mut changed = created.to_json();
+      changed["active"] = json!(true);
+      let updated = client
+        .update(&Patient::from_json(changed.clone()), Some(version))
+        .await
+        .unwrap();
+      assert_eq!(updated.active(), Some(true));
+      match client
+        .update(&Patient::from_json(changed), Some(version))
+        .await
+      {
+        Err(Error::Status { status, .. }) => assert_eq!(status, 412),
+        other => panic!("{:?}", other.map(|patient| patient.to_json())),
+      }
+
+      for family in &["Windsor", "Smith", "Jones"] {
+        let patient = json!({"resourceType": "Patient", "name": [{"family": family}]});
+        client.create(&Patient::from_json(patient)).await.unwrap();
+      }
+      let found = client
+        .search::<Patient>(&[("_count", "1"), ("_sort", "family")])
+        .await
+        .unwrap();
+      assert_eq!(found.len(), 4);
+
+      let bundle = Bundle::from_json(
+        json!({"resourceType": "Bundle", "type": "transaction", "entry": [
+          {"request": {"method": "DELETE", "url": format!("Patient/{}", id)}}
+        ]}),
+      );
+      client.transaction(&bundle).await.unwrap();
+      match client.read::<Patient>(id).await {
+        Err(Error::Status { status, .. }) => assert_eq!(status, 410),
+        other => panic!("{:?}", other.map(|patient| patient.to_json())),
+      }
+      let statement = client.capabilities().await.unwrap();
+      assert!(client
+        .check_capabilities(&statement)
+        .await
+        .unwrap()
+        .is_empty());
+    });
+  }
+}
@@ -0,0 +1,353 @@
+use crate::http::{
+  chunk_size, framing, has_body, invalid, read_response_head, split_url, write_request, Framing,
+  Request, Response, MAX_BODY, MAX_HEADERS, MAX_LINE,
+};
+#[cfg(feature = "tls")]
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+#[cfg(feature = "tls")]
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{
+  AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::TcpStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::{self, pki_types::ServerName, ClientConfig};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsConnector;
+
+/// A response on its way from a [`Transport`].
+pub type Sending<'a> = Pin<Box<dyn Future<Output = io::Result<Response>> + Send + 'a>>;
+
+/// Sends the requests of a [`Client`](super::Client), whose targets are
+/// absolute URLs. [`HttpTransport`] speaks plain HTTP, and https too with
+/// the `tls` feature; without it, servers behind TLS need a transport over
+/// another HTTP stack. Transports can also wrap each other, e.g. to add
+/// headers to every request.
+///
+/// ```
+/// use fhir_rs::client::{Client, HttpTransport, Request, Sending, Transport};
+///
+/// /// Sends every request with an API key.
+/// struct Keyed(HttpTransport);
+///
+/// impl Transport for Keyed {
+///   fn send(&self, mut request: Request) -> Sending<'_> {
+///     request.header("X-Api-Key", "secret");
+///     self.0.send(request)
+///   }
+/// }
+///
+/// let client = Client::with_transport("http://example.org/fhir", Keyed(HttpTransport::new()));
+/// ```
+pub trait Transport: Send + Sync {
+  fn send(&self, request: Request) -> Sending<'_>;
+}
+
+/// Sends each request over a new HTTP/1.1 connection. With the `tls`
+/// feature, https URLs are sent over TLS, trusting the Mozilla root
+/// certificates unless [`tls`](HttpTransport::tls) says otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct HttpTransport {
+  timeout: Option<Duration>,
+  #[cfg(feature = "tls")]
+  tls: Option<Arc<ClientConfig>>,
+}
+
+impl HttpTransport {
+  pub fn new() -> HttpTransport {
+    HttpTransport::default()
+  }
+
+  /// How long to wait for a response, including connecting, before giving up.
+  pub fn timeout(&mut self, timeout: Duration) -> &mut HttpTransport {
+    self.timeout = Some(timeout);
+    self
+  }
+
+  /// The TLS configuration for https URLs, e.g. to trust a private
+  /// certificate authority or to present a client certificate.
+  #[cfg(feature = "tls")]
+  pub fn tls(&mut self, config: Arc<ClientConfig>) -> &mut HttpTransport {
+    self.tls = Some(config);
+    self
+  }
+
+  #[cfg(feature = "tls")]
+  fn tls_config(&self) -> Arc<ClientConfig> {
+    static DEFAULT: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+    let default = || {
+      let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+      };
+      let provider = Arc::new(rustls::crypto::ring::default_provider());
+      let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .expect("The ring provider supports the default TLS versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+      Arc::new(config)
+    };
+    match &self.tls {
+      Some(config) => config.clone(),
+      None => DEFAULT.get_or_init(default).clone(),
+    }
+  }
+}
+
+impl Transport for HttpTransport {
+  fn send(&self, request: Request) -> Sending<'_> {
+    let timeout = self.timeout;
+    Box::pin(async move {
+      match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, self.exchange(request))
+          .await
+          .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "No response in time"))?,
+        None => self.exchange(request).await,
+      }
+    })
+  }
+}
+
+impl HttpTransport {
+  async fn exchange(&self, request: Request) -> io::Result<Response> {
+    let (scheme, authority) = match split_url(&request.target) {
+      Some((scheme, authority, _)) => (scheme, authority),
+      None => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          "Requests must have an absolute http URL",
+        ))
+      }
+    };
+    let mut message = vec![];
+    write_request(&mut message, &request)?;
+    match scheme {
+      "http" => talk(TcpStream::connect(address(authority, 80)).await?, &message).await,
+      #[cfg(feature = "tls")]
+      "https" => {
+        // The name to verify is the host, without the brackets of an IPv6 address.
+        let host = match authority.rsplit_once(':') {
+          Some((host, port)) if !port.contains(']') => host,
+          _ => authority,
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let name = ServerName::try_from(host.to_string())
+          .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid host name"))?;
+        let stream = TcpStream::connect(address(authority, 443)).await?;
+        let connector = TlsConnector::from(self.tls_config());
+        talk(connector.connect(name, stream).await?, &message).await
+      }
+      #[cfg(not(feature = "tls"))]
+      "https" => Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "https URLs need the tls feature, or a transport that supports them",
+      )),
+      _ => Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} URLs need a transport that supports them", scheme),
+      )),
+    }
+  }
+}
+
+/// The address to connect to for an authority, with the scheme's port unless
+/// it has one. A port follows the last colon, unless that's inside an IPv6
+/// address.
+fn address(authority: &str, port: u16) -> String {
+  match authority.rsplit_once(':') {
+    Some((_, given)) if !given.contains(']') => authority.to_string(),
+    _ => format!("{}:{}", authority, port),
+  }
+}
+
+/// Sends a request over a connection and reads the response.
+async fn talk<S: AsyncRead + AsyncWrite + Unpin>(
+  mut stream: S,
+  message: &[u8],
+) -> io::Result<Response> {
+  stream.write_all(message).await?;
+  stream.flush().await?;
+  // The head is read first, then as much of the body as it says there is.
+  let mut reader = BufReader::new(stream);
+  let head = read_lines(&mut reader).await?;
+  let mut response = read_response_head(&mut head.as_bytes())?;
+  if has_body(response.status) {
+    response.body = read_body(&mut reader, &response.headers).await?;
+  }
+  Ok(response)
+}
+
+async fn read_body<R: AsyncBufRead + Unpin>(
+  reader: &mut R,
+  headers: &[(String, String)],
+) -> io::Result<Vec<u8>> {
+  let mut body = vec![];
+  match framing(headers, true)? {
+    Framing::Empty => {}
+    Framing::Length(length) => {
+      body.resize(length, 0);
+      reader.read_exact(&mut body).await?;
+    }
+    Framing::Chunked => loop {
+      let mut line = String::new();
+      read_line(reader, &mut line).await?;
+      let size = chunk_size(&line, body.len())?;
+      let start = body.len();
+      body.resize(start + size, 0);
+      reader.read_exact(&mut body[start..]).await?;
+      // The last chunk is followed by trailers, and the others by a line break.
+      if size == 0 {
+        read_lines(reader).await?;
+        break;
+      }
+      read_line(reader, &mut String::new()).await?;
+    },
+    Framing::ToEnd => {
+      reader
+        .take(MAX_BODY as u64 + 1)
+        .read_to_end(&mut body)
+        .await?;
+      if body.len() > MAX_BODY {
+        return Err(invalid("Body too large"));
+      }
+    }
+  }
+  Ok(body)
+}
+
+/// Reads lines up to and including a blank one, as a response's head or a
+/// chunked body's trailers are, within the limits the server has for them.
+async fn read_lines<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<String> {
+  let mut lines = String::new();
+  for _ in 0..MAX_HEADERS + 2 {
+    let start = lines.len();
+    if read_line(reader, &mut lines).await? == 0 {
+      return Err(invalid("Connection closed in the headers"));
+    }
+    if lines[start..].trim().is_empty() {
+      return Ok(lines);
+    }
+  }
+  Err(invalid("Too many headers"))
+}
+
+async fn read_line<R: AsyncBufRead + Unpin>(
+  reader: &mut R,
+  line: &mut String,
+) -> io::Result<usize> {
+  let read = reader.take(MAX_LINE as u64 + 1).read_line(line).await?;
+  match read > MAX_LINE {
+    true => Err(invalid("Line too long")),
+    false => Ok(read),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::client::tests::run;
+  use crate::http::read_request;
+  use std::io::Write;
+
+  #[test]
+  fn test_framing() {
+    // Responses are read by their framing, without waiting for the server to
+    // close the connection, which this one never does.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+      let mut open = vec![];
+      for stream in listener.incoming().flatten() {
+        let request = read_request(&mut io::BufReader::new(&stream))
+          .unwrap()
+          .unwrap();
+        let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let raw = match request.target.as_str() {
+          "/length" => "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}".to_string(),
+          "/chunked" => format!("{}1\r\n{{\r\n1\r\n}}\r\n0\r\nX-A: b\r\n\r\n", head),
+          _ => format!("{}1\r\n{{\r\nffffffffffffffff\r\n", head),
+        };
+        (&stream).write_all(raw.as_bytes()).unwrap();
+        open.push(stream);
+      }
+    });
+    let mut transport = HttpTransport::new();
+    transport.timeout(Duration::from_secs(10));
+    let get = |path: &str| {
+      let request = Request::new("GET", &format!("http://{}{}", address, path));
+      run(transport.send(request))
+    };
+    assert_eq!(get("/length").unwrap().body, b"{}");
+    assert_eq!(get("/chunked").unwrap().body, b"{}");
+    let error = get("/huge").unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(error.to_string(), "Body too large");
+  }
+
+  #[cfg(feature = "tls")]
+  #[test]
+  fn test_https() {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    let certificate =
+      CertificateDer::from(include_bytes!("../../examples-tls/localhost.der").to_vec());
+    let key =
+      PrivateKeyDer::try_from(include_bytes!("../../examples-tls/localhost.key.der").to_vec())
+        .unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = rustls::ServerConfig::builder_with_provider(provider.clone())
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_no_client_auth()
+      .with_single_cert(vec![certificate], key)
+      .unwrap();
+    let config = Arc::new(config);
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+      for stream in listener.incoming().flatten() {
+        let connection = rustls::ServerConnection::new(config.clone()).unwrap();
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        let request = match read_request(&mut io::BufReader::new(&mut stream)) {
+          Ok(Some(request)) => request,
+          _ => continue,
+        };
+        let body = format!("{{\"target\":\"{}\"}}", request.target);
+        let raw = format!(
+          "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+          body.len(),
+          body
+        );
+        stream.write_all(raw.as_bytes()).unwrap();
+        stream.flush().unwrap();
+      }
+    });
+    let url = format!("https://localhost:{}/fhir/metadata", port);
+    let get = |transport: &HttpTransport| run(transport.send(Request::new("GET", &url)));
+    // The test authority isn't among the default roots, so the server isn't trusted.
+    let mut transport = HttpTransport::new();
+    transport.timeout(Duration::from_secs(10));
+    let error = get(&transport).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    // Trusting it, the request gets through.
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+      .add(CertificateDer::from(
+        include_bytes!("../../examples-tls/ca.der").to_vec(),
+      ))
+      .unwrap();
+    let config = ClientConfig::builder_with_provider(provider)
+      .with_safe_default_protocol_versions()
+      .unwrap()
+      .with_root_certificates(roots)
+      .with_no_client_auth();
+    transport.tls(Arc::new(config));
+    assert_eq!(
+      get(&transport).unwrap().body,
+      br#"{"target":"/fhir/metadata"}"#
+    );
+  }
+}
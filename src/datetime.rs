@@ -0,0 +1,65 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The current time as a FHIR `instant` in UTC, e.g. `2020-03-04T05:06:07.123Z`.
+pub fn now_instant() -> String {
+  let elapsed = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .unwrap_or_default();
+  format_instant(elapsed.as_secs() as i64, elapsed.subsec_millis())
+}
+
+/// Formats seconds since the Unix epoch (plus milliseconds) as a FHIR `instant`.
+pub fn format_instant(seconds: i64, millis: u32) -> String {
+  let days = seconds.div_euclid(86_400);
+  let secs_of_day = seconds.rem_euclid(86_400);
+  let (year, month, day) = civil_from_days(days);
+  format!(
+    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+    year,
+    month,
+    day,
+    secs_of_day / 3600,
+    secs_of_day % 3600 / 60,
+    secs_of_day % 60,
+    millis
+  )
+}
+
+/// Converts a proleptic Gregorian date into days since 1970-01-01.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+  let year = if month <= 2 { year - 1 } else { year };
+  let era = year.div_euclid(400);
+  let yoe = year.rem_euclid(400);
+  let month = month as i64;
+  let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+  let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+  era * 146_097 + doe - 719_468
+}
+
+/// Converts days since 1970-01-01 into a proleptic Gregorian (year, month, day).
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_instant() {
+    assert_eq!(format_instant(0, 0), "1970-01-01T00:00:00.000Z");
+    assert_eq!(format_instant(951_827_696, 5), "2000-02-29T12:34:56.005Z");
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+    assert_eq!(days_from_civil(1969, 12, 31), -1);
+  }
+}
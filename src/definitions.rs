@@ -0,0 +1,231 @@
+use crate::fhirpath::ModelInfo;
+use crate::model::ElementDefinition::ElementDefinition;
+use crate::model::StructureDefinition::StructureDefinition;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The StructureDefinitions available for validation, indexed by canonical URL
+/// and, for the base definitions of resources and data types, by type name.
+#[derive(Debug, Default)]
+pub struct Definitions {
+  structures: HashMap<String, Value>,
+  types: HashMap<String, String>,
+  /// The position of each snapshot element of the base definitions by path.
+  elements: HashMap<String, usize>,
+}
+
+impl Definitions {
+  pub fn new() -> Definitions {
+    Definitions::default()
+  }
+
+  /// Loads every StructureDefinition in a directory of JSON files, including
+  /// those collected in Bundles such as `profiles-types.json`.
+  pub fn load_dir(dir: &str) -> io::Result<Definitions> {
+    let mut definitions = Definitions::new();
+    for entry in fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.extension().is_some_and(|e| e == "json") {
+        definitions.load_file(&path)?;
+      }
+    }
+    Ok(definitions)
+  }
+
+  /// Loads a JSON file holding a StructureDefinition or a Bundle of them. Files
+  /// with other content are ignored.
+  pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    if let Ok(value) = serde_json::from_str::<Value>(&contents) {
+      self.add(value);
+    }
+    Ok(())
+  }
+
+  /// Adds a StructureDefinition, or every StructureDefinition in a Bundle.
+  pub fn add(&mut self, resource: Value) {
+    match resource["resourceType"].as_str() {
+      Some("Bundle") => {
+        if let Value::Object(mut bundle) = resource {
+          if let Some(Value::Array(entries)) = bundle.remove("entry") {
+            for mut entry in entries {
+              if let Some(resource) = entry.get_mut("resource") {
+                self.add(resource.take());
+              }
+            }
+          }
+        }
+      }
+      Some("StructureDefinition") => {
+        let url = match resource["url"].as_str() {
+          Some(url) => url.to_string(),
+          None => return,
+        };
+        let specializes = resource["derivation"].as_str() != Some("constraint");
+        if specializes && resource["kind"].as_str() != Some("logical") {
+          if let Some(type_name) = resource["type"].as_str() {
+            self.types.insert(type_name.to_string(), url.clone());
+            let elements = resource["snapshot"]["element"].as_array();
+            for (index, element) in elements.into_iter().flatten().enumerate() {
+              if let Some(path) = element["path"].as_str() {
+                self.elements.insert(path.to_string(), index);
+              }
+            }
+          }
+        }
+        self.structures.insert(url, resource);
+      }
+      _ => {}
+    }
+  }
+
+  /// Finds a StructureDefinition by canonical URL, ignoring any `|version`.
+  pub fn get(&self, url: &str) -> Option<StructureDefinition<'_>> {
+    self.structure(url).map(|value| StructureDefinition {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  /// The base definition of a resource or data type, e.g. `Patient`.
+  pub fn for_type(&self, type_name: &str) -> Option<StructureDefinition<'_>> {
+    self.get(self.type_url(type_name)?)
+  }
+
+  pub(crate) fn structure(&self, url: &str) -> Option<&Value> {
+    let url = url.split('|').next().unwrap_or(url);
+    self.structures.get(url)
+  }
+
+  pub(crate) fn structure_mut(&mut self, url: &str) -> Option<&mut Value> {
+    self.structures.get_mut(url)
+  }
+
+  pub(crate) fn structures(&self) -> impl Iterator<Item = (&str, &Value)> {
+    self
+      .structures
+      .iter()
+      .map(|(url, value)| (url.as_str(), value))
+  }
+
+  pub(crate) fn type_url(&self, type_name: &str) -> Option<&str> {
+    self.types.get(type_name).map(String::as_str)
+  }
+
+  /// The snapshot element of a base definition at a path such as
+  /// `Patient.contact.name`, following `contentReference`s for paths like
+  /// `Questionnaire.item.item.linkId`.
+  pub fn element(&self, path: &str) -> Option<ElementDefinition<'_>> {
+    let type_name = path.split('.').next()?;
+    let structure = self.structures.get(self.types.get(type_name)?)?;
+    let find = |path: &str| {
+      let element = structure["snapshot"]["element"].get(*self.elements.get(path)?)?;
+      Some(element).filter(|e| e["path"] == path)
+    };
+    if let Some(element) = find(path) {
+      return Some(ElementDefinition {
+        value: Cow::Borrowed(element),
+      });
+    }
+    // Replace the longest prefix that is a content reference with the path
+    // it refers to.
+    let mut end = path.len();
+    while let Some(dot) = path[..end].rfind('.') {
+      end = dot;
+      let reference = find(&path[..end])
+        .and_then(|e| e["contentReference"].as_str())
+        .and_then(|r| r.strip_prefix('#'));
+      if let Some(reference) = reference {
+        return self.element(&format!("{}{}", reference, &path[end..]));
+      }
+    }
+    None
+  }
+}
+
+/// The single type of an element. Choice elements have no single type.
+pub(crate) fn element_type(element: &ElementDefinition) -> Option<String> {
+  match element.value.get("type")?.as_array()?.as_slice() {
+    [single] => type_code(single).map(str::to_string),
+    _ => None,
+  }
+}
+
+/// The code of an `ElementDefinition.type`, using the FHIR type for elements
+/// like `Resource.id` whose type is a FHIRPath system type.
+pub(crate) fn type_code(element_type: &Value) -> Option<&str> {
+  let fhir_type = element_type["extension"].as_array().and_then(|extensions| {
+    extensions
+      .iter()
+      .find(|e| e["url"] == "http://hl7.org/fhir/StructureDefinition/structuredefinition-fhir-type")
+      .and_then(|e| e["valueUrl"].as_str())
+  });
+  fhir_type.or_else(|| element_type["code"].as_str())
+}
+
+impl ModelInfo for Definitions {
+  fn element_type(&self, path: &str) -> Option<String> {
+    let element = self.element(path)?;
+    if element.content_reference().is_some() {
+      return Some("BackboneElement".to_string());
+    }
+    element_type(&element)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_definitions() {
+    let mut definitions = Definitions::new();
+    definitions
+      .load_file(Path::new("examples-json/profiles-types.json"))
+      .unwrap();
+    for file in &["patient", "questionnaire", "bodyweight"] {
+      let path = format!("examples-json/{}.profile.json", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+
+    assert_eq!(
+      definitions.for_type("Patient").unwrap().url(),
+      Some("http://hl7.org/fhir/StructureDefinition/Patient")
+    );
+    assert!(definitions.for_type("HumanName").is_some());
+    assert!(definitions
+      .get("http://hl7.org/fhir/StructureDefinition/bodyweight|4.0.1")
+      .is_some());
+    // bodyweight constrains Observation rather than defining it
+    assert!(definitions.for_type("Observation").is_none());
+
+    let contact = definitions.element("Patient.contact").unwrap();
+    let keys = contact
+      .constraint()
+      .unwrap()
+      .iter()
+      .filter_map(|c| c.key().map(str::to_string))
+      .collect::<Vec<_>>();
+    assert_eq!(keys, vec!["ele-1", "pat-1"]);
+
+    let model: &dyn ModelInfo = &definitions;
+    assert_eq!(model.element_type("Patient.birthDate").unwrap(), "date");
+    assert_eq!(model.element_type("Patient.id").unwrap(), "string");
+    assert_eq!(model.element_type("Patient.name").unwrap(), "HumanName");
+    assert_eq!(model.element_type("Patient.deceased[x]"), None);
+    assert_eq!(
+      model
+        .element_type("Questionnaire.item.item.linkId")
+        .unwrap(),
+      "string"
+    );
+    assert_eq!(
+      model.element_type("Questionnaire.item.item").unwrap(),
+      "BackboneElement"
+    );
+    assert_eq!(model.element_type("Patient.unknown"), None);
+  }
+}
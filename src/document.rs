@@ -0,0 +1,400 @@
+use crate::datetime::now_instant;
+use crate::ids::{new_uuid, new_uuid_urn};
+use crate::model::Bundle::{Bundle, BundleType};
+use crate::model::Composition::Composition;
+use crate::model::OperationOutcome_Issue::{
+  OperationOutcome_IssueCode, OperationOutcome_IssueSeverity,
+};
+use crate::outcome::Issue;
+use crate::resolve::{is_absolute, Resolver, ResourceSource};
+use crate::util::{array, service_base};
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+
+/// Builds `document` Bundles (http://hl7.org/fhir/documents.html) from a
+/// Composition, pulling the resources it references out of a `ResourceSource`.
+pub struct DocumentAssembler<'a> {
+  base: String,
+  source: &'a dyn ResourceSource,
+}
+
+impl<'a> DocumentAssembler<'a> {
+  /// `base` is the service base that relative references in the Composition
+  /// are resolved against, e.g. `http://example.org/fhir`.
+  pub fn new(base: &str, source: &'a dyn ResourceSource) -> DocumentAssembler<'a> {
+    let base = service_base(base);
+    DocumentAssembler { base, source }
+  }
+
+  /// Assembles the document with the Composition as the first entry followed by
+  /// every resource it references, or reports the references that could not be
+  /// found in the source.
+  pub fn assemble(&self, composition: &Composition) -> Result<Bundle<'static>, Vec<Issue>> {
+    let mut composition = (*composition.value).clone();
+    composition["resourceType"] = json!("Composition");
+    if composition.get("id").is_none() {
+      composition["id"] = json!(new_uuid());
+    }
+    let composition_url = format!(
+      "{}Composition/{}",
+      self.base,
+      composition["id"].as_str().unwrap_or_default()
+    );
+
+    let mut issues = vec![];
+    let mut entries = vec![json!({"fullUrl": composition_url, "resource": composition})];
+    for (path, reference) in composition_references(&entries[0]["resource"]) {
+      let full_url = if is_absolute(&reference) {
+        reference.clone()
+      } else {
+        format!("{}{}", self.base, reference)
+      };
+      if entries.iter().any(|e| e["fullUrl"] == full_url.as_str()) {
+        continue;
+      }
+      match self.source.fetch(&reference) {
+        Some(resource) => entries.push(json!({
+          "fullUrl": full_url,
+          "resource": resource.value.into_owned(),
+        })),
+        None => issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::NotFound,
+            &format!("Referenced resource '{}' was not found", reference),
+          )
+          .at(&path),
+        ),
+      }
+    }
+    if !issues.is_empty() {
+      return Err(issues);
+    }
+    Ok(Bundle {
+      value: Cow::Owned(json!({
+        "resourceType": "Bundle",
+        "identifier": {"system": "urn:ietf:rfc:3986", "value": new_uuid_urn()},
+        "type": BundleType::Document.to_string(),
+        "timestamp": now_instant(),
+        "entry": entries,
+      })),
+    })
+  }
+}
+
+/// Checks a received Bundle against the rules for documents: its type,
+/// identifier and timestamp, the Composition coming first, and every resource
+/// the Composition references being present in the Bundle.
+pub fn validate_document(bundle: &Bundle) -> Vec<Issue> {
+  let mut issues = vec![];
+  if !matches!(bundle.fhir_type(), Some(BundleType::Document)) {
+    issues.push(
+      Issue::error(
+        OperationOutcome_IssueCode::Invalid,
+        "Bundle type must be document",
+      )
+      .at("Bundle.type"),
+    );
+  }
+  let identifier = &bundle.value["identifier"];
+  if identifier["system"].as_str().is_none() || identifier["value"].as_str().is_none() {
+    issues.push(
+      Issue::error(
+        OperationOutcome_IssueCode::Required,
+        "A document must have an identifier with a system and a value (bdl-9)",
+      )
+      .at("Bundle.identifier"),
+    );
+  }
+  if bundle.timestamp().is_none() {
+    issues.push(
+      Issue::error(
+        OperationOutcome_IssueCode::Required,
+        "A document must have a date (bdl-10)",
+      )
+      .at("Bundle.timestamp"),
+    );
+  }
+  let entries = match bundle.value.get("entry") {
+    Some(Value::Array(entries)) => entries.as_slice(),
+    _ => &[],
+  };
+  if entries.first().map(|e| &e["resource"]["resourceType"]) != Some(&json!("Composition")) {
+    issues.push(
+      Issue::error(
+        OperationOutcome_IssueCode::Structure,
+        "A document must have a Composition as the first resource (bdl-11)",
+      )
+      .at("Bundle.entry[0]"),
+    );
+    return issues;
+  }
+  for (index, entry) in entries.iter().enumerate() {
+    if entry["fullUrl"].as_str().is_none() {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Required,
+          "Every entry in a document must have a fullUrl",
+        )
+        .at(&format!("Bundle.entry[{}]", index)),
+      );
+    }
+    if entry.get("request").is_some() || entry.get("response").is_some() {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Structure,
+          "Document entries must not have a request or response (bdl-3, bdl-4)",
+        )
+        .at(&format!("Bundle.entry[{}]", index)),
+      );
+    }
+  }
+
+  let resolver = Resolver::new(bundle);
+  for (path, reference) in composition_references(&entries[0]["resource"]) {
+    if resolver.resolve_from_entry(0, &reference).is_none() {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::NotFound,
+          &format!("The document does not contain '{}'", reference),
+        )
+        .at(&format!(
+          "Bundle.entry[0].resource.{}",
+          &path["Composition.".len()..]
+        )),
+      );
+    }
+  }
+  for mut issue in resolver.dangling_references() {
+    if !issue
+      .expression
+      .as_deref()
+      .unwrap_or_default()
+      .starts_with("Bundle.entry[0].")
+    {
+      issue.severity = OperationOutcome_IssueSeverity::Warning;
+      issues.push(issue);
+    }
+  }
+  issues
+}
+
+/// Renders the attested narrative of a document, the Composition and its
+/// sections, as a standalone HTML page. Returns `None` unless the Bundle starts
+/// with a Composition.
+pub fn render_html(bundle: &Bundle) -> Option<String> {
+  let composition = bundle.value.get("entry")?.get(0)?.get("resource")?;
+  if composition["resourceType"] != "Composition" {
+    return None;
+  }
+  let resolver = Resolver::new(bundle);
+  let title = escape(composition["title"].as_str().unwrap_or("Document"));
+
+  let mut html = String::new();
+  html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\"/>\n");
+  html.push_str(&format!("<title>{}</title>\n</head>\n<body>\n", title));
+  html.push_str(&format!("<h1>{}</h1>\n", title));
+
+  let mut header = vec![];
+  if let Some(subject) = composition["subject"].as_object() {
+    header.push((
+      "Subject",
+      display(&resolver, &Value::Object(subject.clone())),
+    ));
+  }
+  if let Some(date) = composition["date"].as_str() {
+    header.push(("Date", escape(date)));
+  }
+  if let Some(authors) = composition["author"].as_array() {
+    let names = authors
+      .iter()
+      .map(|a| display(&resolver, a))
+      .collect::<Vec<_>>();
+    header.push(("Author", names.join(", ")));
+  }
+  if !header.is_empty() {
+    html.push_str("<table>\n");
+    for (label, value) in header {
+      html.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", label, value));
+    }
+    html.push_str("</table>\n");
+  }
+  if let Some(div) = composition["text"]["div"].as_str() {
+    html.push_str(div);
+    html.push('\n');
+  }
+  if let Some(subject) = composition["subject"]["reference"].as_str() {
+    if let Some(resource) = resolver.resolve_from_entry(0, subject) {
+      if let Some(div) = resource.value["text"]["div"].as_str() {
+        html.push_str(div);
+        html.push('\n');
+      }
+    }
+  }
+  if let Some(sections) = composition["section"].as_array() {
+    render_sections(sections, 2, &mut html);
+  }
+  html.push_str("</body>\n</html>\n");
+  Some(html)
+}
+
+fn render_sections(sections: &[Value], level: usize, html: &mut String) {
+  let level = level.min(6);
+  for section in sections {
+    html.push_str("<section>\n");
+    if let Some(title) = section["title"].as_str() {
+      html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, escape(title)));
+    }
+    if let Some(div) = section["text"]["div"].as_str() {
+      html.push_str(div);
+      html.push('\n');
+    } else if let Some(reason) = section["emptyReason"]["text"].as_str() {
+      html.push_str(&format!("<p>{}</p>\n", escape(reason)));
+    }
+    if let Some(children) = section["section"].as_array() {
+      render_sections(children, level + 1, html);
+    }
+    html.push_str("</section>\n");
+  }
+}
+
+/// A readable name for a reference: its display, or failing that the name or
+/// id of the resource it points at.
+fn display(resolver: &Resolver, reference: &Value) -> String {
+  if let Some(display) = reference["display"].as_str() {
+    return escape(display);
+  }
+  let literal = reference["reference"].as_str().unwrap_or_default();
+  if let Some(resource) = resolver.resolve_from_entry(0, literal) {
+    if let Some(name) = resource.value["name"][0]["text"].as_str() {
+      return escape(name);
+    }
+    if let Some(name) = resource.value["name"].as_str() {
+      return escape(name);
+    }
+  }
+  escape(literal)
+}
+
+fn escape(text: &str) -> String {
+  text
+    .replace('&', "&amp;")
+    .replace('<', "&lt;")
+    .replace('>', "&gt;")
+    .replace('"', "&quot;")
+}
+
+/// The literal references a document has to include, with the path of each:
+/// subject, encounter, authors, attesters, custodian and everything referenced
+/// from sections.
+fn composition_references(composition: &Value) -> Vec<(String, String)> {
+  let mut references = vec![];
+  let mut add = |path: String, value: &Value| {
+    if let Some(reference) = value["reference"].as_str() {
+      if !reference.starts_with('#') {
+        references.push((path, reference.to_string()));
+      }
+    }
+  };
+  add("Composition.subject".to_string(), &composition["subject"]);
+  add(
+    "Composition.encounter".to_string(),
+    &composition["encounter"],
+  );
+  for (i, author) in array(&composition["author"]).iter().enumerate() {
+    add(format!("Composition.author[{}]", i), author);
+  }
+  for (i, attester) in array(&composition["attester"]).iter().enumerate() {
+    add(
+      format!("Composition.attester[{}].party", i),
+      &attester["party"],
+    );
+  }
+  add(
+    "Composition.custodian".to_string(),
+    &composition["custodian"],
+  );
+  let mut sections = array(&composition["section"])
+    .iter()
+    .enumerate()
+    .map(|(i, s)| (format!("Composition.section[{}]", i), s))
+    .collect::<Vec<_>>();
+  while let Some((path, section)) = sections.pop() {
+    for (i, author) in array(&section["author"]).iter().enumerate() {
+      add(format!("{}.author[{}]", path, i), author);
+    }
+    add(format!("{}.focus", path), &section["focus"]);
+    for (i, entry) in array(&section["entry"]).iter().enumerate() {
+      add(format!("{}.entry[{}]", path, i), entry);
+    }
+    for (i, child) in array(&section["section"]).iter().enumerate().rev() {
+      sections.push((format!("{}.section[{}]", path, i), child));
+    }
+  }
+  references
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_assembles_document() {
+    let value = load("examples-json/document-example-dischargesummary.json");
+    let source = Bundle::new(&value);
+    let composition = Composition::new(&value["entry"][0]["resource"]);
+    let document = DocumentAssembler::new("http://fhir.healthintersections.com.au/open", &source)
+      .assemble(&composition)
+      .unwrap();
+    let json = document.to_json();
+    assert_eq!(json["entry"].as_array().unwrap().len(), 8);
+    assert_eq!(json["entry"][0]["resource"]["resourceType"], "Composition");
+    assert_eq!(
+      json["entry"][3]["fullUrl"],
+      "http://fhir.healthintersections.com.au/open/Practitioner/example"
+    );
+    let issues = validate_document(&document);
+    assert!(issues.iter().all(|i| !i.is_error()), "{:?}", issues);
+  }
+
+  #[test]
+  fn test_reports_missing_resources() {
+    let composition = json!({"resourceType": "Composition", "id": "c", "subject": {"reference": "Patient/missing"}});
+    let source = std::collections::HashMap::<String, Value>::new();
+    let issues = DocumentAssembler::new("http://example.org/fhir", &source)
+      .assemble(&Composition::new(&composition))
+      .unwrap_err();
+    assert_eq!(issues[0].expression.as_deref(), Some("Composition.subject"));
+  }
+
+  #[test]
+  fn test_validates_and_renders_document() {
+    let value = load("examples-json/document-example-dischargesummary.json");
+    let bundle = Bundle::new(&value);
+    assert!(validate_document(&bundle).iter().all(|i| !i.is_error()));
+
+    let html = render_html(&bundle).unwrap();
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<h1>Discharge Summary</h1>"));
+    assert!(html.contains("<h2>Medications on Discharge</h2>"));
+
+    let mut broken = value.clone();
+    broken["entry"].as_array_mut().unwrap().remove(2);
+    broken.as_object_mut().unwrap().remove("timestamp");
+    let issues = validate_document(&Bundle::new(&broken));
+    let errors = issues
+      .iter()
+      .filter(|i| i.is_error())
+      .map(|i| i.expression.as_deref().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      errors,
+      vec!["Bundle.timestamp", "Bundle.entry[0].resource.subject"]
+    );
+  }
+}
@@ -0,0 +1,867 @@
+use super::functions;
+use super::parser::{Expr, Literal};
+use super::value::{Decimal, Item, Node, Quantity};
+use super::{Context, Error};
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// The evaluation state of a (sub-)expression: what `$this`, `$index` and
+/// `$total` refer to, plus the focus the whole expression started from.
+#[derive(Clone)]
+pub(crate) struct Env<'a, 'c> {
+  pub(crate) context: &'c Context<'a>,
+  pub(crate) this: Vec<Item<'a>>,
+  pub(crate) index: Option<usize>,
+  pub(crate) total: Option<Vec<Item<'a>>>,
+  pub(crate) focus: &'c [Item<'a>],
+  /// Results of subexpressions that don't depend on `$this`, such as
+  /// `%resource.descendants()`, so that iterations can share them.
+  pub(crate) cache: &'c RefCell<HashMap<*const Expr, Vec<Item<'a>>>>,
+}
+
+impl<'a, 'c> Env<'a, 'c> {
+  /// The environment for evaluating an argument against one input item.
+  pub(crate) fn iteration(&self, item: &Item<'a>, index: usize) -> Env<'a, 'c> {
+    Env {
+      this: vec![item.clone()],
+      index: Some(index),
+      ..self.clone()
+    }
+  }
+}
+
+const PRIMITIVES: &[&str] = &[
+  "base64Binary",
+  "boolean",
+  "canonical",
+  "code",
+  "date",
+  "dateTime",
+  "decimal",
+  "id",
+  "instant",
+  "integer",
+  "integer64",
+  "markdown",
+  "oid",
+  "positiveInt",
+  "string",
+  "time",
+  "unsignedInt",
+  "uri",
+  "url",
+  "uuid",
+  "xhtml",
+];
+
+/// Complex types that may appear as the suffix of a choice element.
+const DATA_TYPES: &[&str] = &[
+  "Address",
+  "Age",
+  "Annotation",
+  "Attachment",
+  "Availability",
+  "CodeableConcept",
+  "CodeableReference",
+  "Coding",
+  "ContactDetail",
+  "ContactPoint",
+  "Contributor",
+  "Count",
+  "DataRequirement",
+  "Distance",
+  "Dosage",
+  "Duration",
+  "Expression",
+  "ExtendedContactDetail",
+  "HumanName",
+  "Identifier",
+  "Meta",
+  "Money",
+  "MoneyQuantity",
+  "ParameterDefinition",
+  "Period",
+  "Quantity",
+  "Range",
+  "Ratio",
+  "RatioRange",
+  "Reference",
+  "RelatedArtifact",
+  "SampledData",
+  "Signature",
+  "SimpleQuantity",
+  "Timing",
+  "TriggerDefinition",
+  "UsageContext",
+];
+
+pub(crate) fn evaluate<'a>(expr: &Expr, env: &Env<'a, '_>) -> Result<Vec<Item<'a>>, Error> {
+  // Outside an iteration every subexpression is evaluated once anyway.
+  let cached = env.index.is_some()
+    && matches!(expr, Expr::Member(..) | Expr::Call(..) | Expr::Binary(..))
+    && is_invariant(expr);
+  if !cached {
+    return evaluate_uncached(expr, env);
+  }
+  let key = expr as *const Expr;
+  if let Some(result) = env.cache.borrow().get(&key) {
+    return Ok(result.clone());
+  }
+  let result = evaluate_uncached(expr, env)?;
+  env.cache.borrow_mut().insert(key, result.clone());
+  Ok(result)
+}
+
+/// True when an expression gives the same result whatever `$this`, `$index`
+/// and `$total` are.
+fn is_invariant(expr: &Expr) -> bool {
+  match expr {
+    Expr::Literal(_) | Expr::Constant(_) => true,
+    Expr::This | Expr::Index | Expr::Total => false,
+    Expr::Member(target, _) => target.as_deref().is_some_and(is_invariant),
+    // The argument of these is a type name rather than an expression.
+    Expr::Call(Some(target), name, _) if matches!(name.as_str(), "as" | "is" | "ofType") => {
+      is_invariant(target)
+    }
+    Expr::Call(target, _, args) => {
+      target.as_deref().is_some_and(is_invariant) && args.iter().all(is_invariant)
+    }
+    Expr::Indexer(target, index) => is_invariant(target) && is_invariant(index),
+    Expr::Negate(operand) => is_invariant(operand),
+    Expr::Binary(_, left, right) => is_invariant(left) && is_invariant(right),
+    Expr::Type(_, operand, _) => is_invariant(operand),
+  }
+}
+
+fn evaluate_uncached<'a>(expr: &Expr, env: &Env<'a, '_>) -> Result<Vec<Item<'a>>, Error> {
+  match expr {
+    Expr::Literal(literal) => Ok(match literal {
+      Literal::Empty => vec![],
+      Literal::Boolean(value) => vec![Item::Boolean(*value)],
+      Literal::String(value) => vec![Item::String(value.clone())],
+      Literal::Integer(value) => vec![Item::Integer(*value)],
+      Literal::Decimal(value) => vec![Item::Decimal(*value)],
+      Literal::Date(value) => vec![Item::Date(*value)],
+      Literal::DateTime(value) => vec![Item::DateTime(*value)],
+      Literal::Time(value) => vec![Item::Time(*value)],
+      Literal::Quantity(value) => vec![Item::Quantity(value.clone())],
+    }),
+    Expr::Constant(name) => constant(env, name),
+    Expr::This => Ok(env.this.clone()),
+    Expr::Index => Ok(
+      env
+        .index
+        .map(|i| Item::Integer(i as i64))
+        .into_iter()
+        .collect(),
+    ),
+    Expr::Total => Ok(env.total.clone().unwrap_or_default()),
+    Expr::Member(None, name) => {
+      // A type name at the start of a path, as in `Patient.name`, selects the
+      // focus when it is of that type. Resources also match the types they
+      // specialise, as in `Resource.id`.
+      let starts_upper = name.chars().next().is_some_and(char::is_uppercase);
+      let mut result = vec![];
+      for item in &env.this {
+        match item {
+          Item::Node(node)
+            if starts_upper
+              && (node.type_name.as_deref() == Some(name.as_str())
+                || node
+                  .value
+                  .as_deref()
+                  .is_some_and(|v| v.get("resourceType").is_some())
+                  && is_type(item, name)) =>
+          {
+            result.push(item.clone())
+          }
+          _ => result.extend(member(env.context, item, name)),
+        }
+      }
+      Ok(result)
+    }
+    Expr::Member(Some(target), name) => Ok(
+      evaluate(target, env)?
+        .iter()
+        .flat_map(|item| member(env.context, item, name))
+        .collect(),
+    ),
+    Expr::Call(target, name, args) => {
+      let input = match target {
+        Some(target) => evaluate(target, env)?,
+        None => env.this.clone(),
+      };
+      functions::call(env, name, input, args)
+    }
+    Expr::Indexer(target, index) => {
+      let input = evaluate(target, env)?;
+      match singleton(&evaluate(index, env)?)?.map(|i| i.primitive()) {
+        None => Ok(vec![]),
+        Some(Item::Integer(index)) if index >= 0 => {
+          Ok(input.into_iter().nth(index as usize).into_iter().collect())
+        }
+        Some(Item::Integer(_)) => Ok(vec![]),
+        Some(_) => Err(Error::new("An indexer must be an integer")),
+      }
+    }
+    Expr::Negate(operand) => match singleton(&evaluate(operand, env)?)?.map(|i| i.primitive()) {
+      None => Ok(vec![]),
+      Some(Item::Integer(value)) => Ok(integer(-(value as i128)).into_iter().collect()),
+      Some(Item::Decimal(value)) => Ok(vec![Item::Decimal(-value)]),
+      Some(Item::Quantity(value)) => Ok(vec![Item::Quantity(Quantity::new(
+        -value.value,
+        &value.unit,
+      ))]),
+      Some(_) => Err(Error::new("Only numbers and quantities can be negated")),
+    },
+    Expr::Binary(op, left, right) => binary(env, op, left, right),
+    Expr::Type(op, operand, type_name) => {
+      let input = evaluate(operand, env)?;
+      if *op == "is" {
+        Ok(match singleton(&input)? {
+          Some(item) => vec![Item::Boolean(is_type(&item, type_name))],
+          None => vec![],
+        })
+      } else {
+        Ok(
+          input
+            .into_iter()
+            .filter(|i| is_type(i, type_name))
+            .collect(),
+        )
+      }
+    }
+  }
+}
+
+fn constant<'a>(env: &Env<'a, '_>, name: &str) -> Result<Vec<Item<'a>>, Error> {
+  let context = env.context;
+  if let Some(value) = context.variables.get(name) {
+    return Ok(value.clone());
+  }
+  let text = |s: String| Ok(vec![Item::String(s)]);
+  match name {
+    "resource" => Ok(vec![context.node(context.resource, None)]),
+    "rootResource" => Ok(vec![context.node(context.root, None)]),
+    "context" => Ok(env.focus.to_vec()),
+    "ucum" => text("http://unitsofmeasure.org".to_string()),
+    "sct" => text("http://snomed.info/sct".to_string()),
+    "loinc" => text("http://loinc.org".to_string()),
+    _ => {
+      if let Some(id) = name.strip_prefix("vs-") {
+        text(format!("http://hl7.org/fhir/ValueSet/{}", id))
+      } else if let Some(id) = name.strip_prefix("ext-") {
+        text(format!("http://hl7.org/fhir/StructureDefinition/{}", id))
+      } else {
+        Err(Error::new(&format!("Unknown constant '%{}'", name)))
+      }
+    }
+  }
+}
+
+/// The children of an item with the given name, including choice elements
+/// such as `valueQuantity` for `value` and the `_name` siblings of primitives.
+pub(crate) fn member<'a>(context: &Context<'a>, item: &Item<'a>, name: &str) -> Vec<Item<'a>> {
+  let node = match item {
+    Item::Node(node) => node,
+    _ => return vec![],
+  };
+  let container = match node.value.as_deref() {
+    Some(Value::Object(_)) => node.value.as_ref(),
+    _ => node.element.as_ref(),
+  };
+  let container = match container {
+    Some(container) => container,
+    None => return vec![],
+  };
+  let object = match container.as_ref() {
+    Value::Object(object) => object,
+    _ => return vec![],
+  };
+  let path = format!("{}.{}", node.path, name);
+  let shadow = format!("_{}", name);
+  if object.contains_key(name) || object.contains_key(&shadow) {
+    let declared = match name {
+      "extension" | "modifierExtension" => Some("Extension".to_string()),
+      _ => context.model.and_then(|m| m.element_type(&path)),
+    };
+    return children(container, name, &shadow, declared, &path);
+  }
+  // An absent element the model knows isn't a choice, such as `response`
+  // next to `responseCode`.
+  if context.model.and_then(|m| m.element_type(&path)).is_some() {
+    return vec![];
+  }
+  let mut result = vec![];
+  for key in object.keys() {
+    let suffix = match key.strip_prefix(name) {
+      Some(suffix) if suffix.starts_with(char::is_uppercase) => suffix,
+      _ => continue,
+    };
+    let type_name = choice_type(suffix);
+    if let Some(type_name) = type_name {
+      let path = format!("{}.{}[x]", node.path, name);
+      let shadow = format!("_{}", key);
+      result.extend(children(container, key, &shadow, Some(type_name), &path));
+    }
+  }
+  result
+}
+
+/// The element name for a JSON property, e.g. `value` for `valueQuantity`,
+/// unless the model says the property is an element in its own right.
+pub(crate) fn element_name<'k>(context: &Context, path: &str, key: &'k str) -> &'k str {
+  let known = |name: &str| {
+    context
+      .model
+      .and_then(|m| m.element_type(&format!("{}.{}", path, name)))
+      .is_some()
+  };
+  if known(key) {
+    return key;
+  }
+  key
+    .char_indices()
+    .skip(1)
+    .find(|(index, c)| c.is_uppercase() && choice_type(&key[*index..]).is_some())
+    .map(|(index, _)| &key[..index])
+    .filter(|name| !known(name))
+    .unwrap_or(key)
+}
+
+fn choice_type(suffix: &str) -> Option<String> {
+  let mut chars = suffix.chars();
+  let lower = chars
+    .next()?
+    .to_lowercase()
+    .chain(chars)
+    .collect::<String>();
+  if PRIMITIVES.contains(&lower.as_str()) {
+    Some(lower)
+  } else if DATA_TYPES.contains(&suffix) {
+    Some(suffix.to_string())
+  } else {
+    None
+  }
+}
+
+/// Pairs up the values of `name` with the `_name` entries that carry their ids
+/// and extensions, which line up by index for repeating primitives.
+fn children<'a>(
+  container: &Cow<'a, Value>,
+  name: &str,
+  shadow: &str,
+  declared: Option<String>,
+  path: &str,
+) -> Vec<Item<'a>> {
+  let values = project(container, name);
+  let elements = project(container, shadow);
+  let count = values.len().max(elements.len());
+  let mut result = vec![];
+  for index in 0..count {
+    let value = values.get(index).cloned().filter(|v| !v.is_null());
+    let element = elements.get(index).cloned().filter(|v| !v.is_null());
+    if value.is_none() && element.is_none() {
+      continue;
+    }
+    let type_name = value
+      .as_deref()
+      .and_then(|v| v.get("resourceType"))
+      .and_then(Value::as_str)
+      .map(str::to_string)
+      .or_else(|| declared.clone());
+    let path = match &type_name {
+      Some(type_name) => child_path(path, type_name),
+      None => path.to_string(),
+    };
+    result.push(Item::Node(Node {
+      value,
+      element,
+      type_name,
+      path,
+    }));
+  }
+  result
+}
+
+/// Complex types restart the path at the type, so `Patient.name` has children
+/// like `HumanName.given`. Backbone elements keep the path they were found at.
+fn child_path(path: &str, type_name: &str) -> String {
+  match type_name {
+    "BackboneElement" | "Element" => path.to_string(),
+    t if t.starts_with(char::is_uppercase) => t.to_string(),
+    _ => path.to_string(),
+  }
+}
+
+/// The values (flattening arrays) of a property of a JSON object, borrowing
+/// when the object is borrowed.
+pub(crate) fn project<'a>(container: &Cow<'a, Value>, name: &str) -> Vec<Cow<'a, Value>> {
+  fn values(value: Option<&Value>) -> Vec<&Value> {
+    match value {
+      Some(Value::Array(items)) => items.iter().collect(),
+      Some(value) => vec![value],
+      None => vec![],
+    }
+  }
+  match container {
+    Cow::Borrowed(value) => values(value.get(name))
+      .into_iter()
+      .map(Cow::Borrowed)
+      .collect(),
+    Cow::Owned(value) => values(value.get(name))
+      .into_iter()
+      .map(|v| Cow::Owned(v.clone()))
+      .collect(),
+  }
+}
+
+/// Whether an item is of the given type or one of its specialisations. Type
+/// names may be qualified with `FHIR.` or `System.`.
+pub(crate) fn is_type(item: &Item, specifier: &str) -> bool {
+  let (namespace, name) = match specifier.split_once('.') {
+    Some((namespace, name)) if namespace == "FHIR" || namespace == "System" => {
+      (Some(namespace), name)
+    }
+    _ => (None, specifier),
+  };
+  let node = match item {
+    Item::Node(node) => node,
+    _ => {
+      let system = item.type_name().unwrap_or_default();
+      return namespace != Some("FHIR") && system.strip_prefix("System.") == Some(name);
+    }
+  };
+  if namespace == Some("System") {
+    return false;
+  }
+  let mut current = match &node.type_name {
+    Some(type_name) => type_name.clone(),
+    None => match node.value.as_deref() {
+      Some(Value::Bool(_)) => "boolean".to_string(),
+      Some(Value::Number(n)) if n.is_i64() => "integer".to_string(),
+      Some(Value::Number(_)) => "decimal".to_string(),
+      Some(Value::String(_)) => "string".to_string(),
+      _ => return false,
+    },
+  };
+  let is_resource = node
+    .value
+    .as_deref()
+    .is_some_and(|v| v.get("resourceType").is_some());
+  loop {
+    if current == name {
+      return true;
+    }
+    // FHIR primitives also count as the System type they hold.
+    if namespace.is_none() && system_type(&current) == Some(name) {
+      return true;
+    }
+    current = match parent_type(&current, is_resource) {
+      Some(parent) => parent.to_string(),
+      None => return false,
+    };
+  }
+}
+
+fn system_type(primitive: &str) -> Option<&'static str> {
+  Some(match primitive {
+    "boolean" => "Boolean",
+    "integer" | "positiveInt" | "unsignedInt" | "integer64" => "Integer",
+    "decimal" => "Decimal",
+    "date" => "Date",
+    "dateTime" | "instant" => "DateTime",
+    "time" => "Time",
+    t if PRIMITIVES.contains(&t) => "String",
+    _ => return None,
+  })
+}
+
+fn parent_type(type_name: &str, is_resource: bool) -> Option<&'static str> {
+  Some(match type_name {
+    "code" | "id" | "markdown" => "string",
+    "url" | "canonical" | "oid" | "uuid" => "uri",
+    "positiveInt" | "unsignedInt" => "integer",
+    "Age" | "Count" | "Distance" | "Duration" | "MoneyQuantity" | "SimpleQuantity" => "Quantity",
+    "Resource" | "Element" => return None,
+    "DomainResource" => "Resource",
+    "Bundle" | "Binary" | "Parameters" if is_resource => "Resource",
+    _ if is_resource => "DomainResource",
+    t if t.starts_with(char::is_uppercase) => "Element",
+    _ => return None,
+  })
+}
+
+/// The single item of a collection, or an error if there are several.
+pub(crate) fn singleton<'a>(items: &[Item<'a>]) -> Result<Option<Item<'a>>, Error> {
+  match items {
+    [] => Ok(None),
+    [item] => Ok(Some(item.clone())),
+    _ => Err(Error::new(&format!(
+      "Expected a single item but found {}",
+      items.len()
+    ))),
+  }
+}
+
+/// Singleton evaluation of a collection as a Boolean: a single non-Boolean
+/// item counts as `true`.
+pub(crate) fn boolean(items: &[Item]) -> Result<Option<bool>, Error> {
+  Ok(singleton(items)?.map(|item| match item.primitive() {
+    Item::Boolean(value) => value,
+    _ => true,
+  }))
+}
+
+fn binary<'a>(
+  env: &Env<'a, '_>,
+  op: &str,
+  left: &Expr,
+  right: &Expr,
+) -> Result<Vec<Item<'a>>, Error> {
+  let boolean_result = |value: Option<bool>| Ok(value.map(Item::Boolean).into_iter().collect());
+  match op {
+    "and" | "or" | "xor" | "implies" => {
+      let a = boolean(&evaluate(left, env)?)?;
+      match (op, a) {
+        ("and", Some(false)) => return boolean_result(Some(false)),
+        ("or", Some(true)) => return boolean_result(Some(true)),
+        ("implies", Some(false)) => return boolean_result(Some(true)),
+        _ => {}
+      }
+      let b = boolean(&evaluate(right, env)?)?;
+      boolean_result(match (op, a, b) {
+        ("and", _, Some(false)) => Some(false),
+        ("and", Some(true), Some(true)) => Some(true),
+        ("or", _, Some(true)) => Some(true),
+        ("or", Some(false), Some(false)) => Some(false),
+        ("xor", Some(a), Some(b)) => Some(a != b),
+        ("implies", Some(true), b) => b,
+        ("implies", None, Some(true)) => Some(true),
+        _ => None,
+      })
+    }
+    _ => {
+      let a = evaluate(left, env)?;
+      let b = evaluate(right, env)?;
+      operate(op, a, b)
+    }
+  }
+}
+
+/// Applies a non-logical binary operator to two evaluated operands.
+pub(crate) fn operate<'a>(
+  op: &str,
+  a: Vec<Item<'a>>,
+  b: Vec<Item<'a>>,
+) -> Result<Vec<Item<'a>>, Error> {
+  let boolean_result = |value: Option<bool>| Ok(value.map(Item::Boolean).into_iter().collect());
+  match op {
+    "|" => Ok(union(a, b)),
+    "=" => boolean_result(equal(&a, &b)),
+    "!=" => boolean_result(equal(&a, &b).map(|e| !e)),
+    "~" => boolean_result(Some(equivalent(&a, &b))),
+    "!~" => boolean_result(Some(!equivalent(&a, &b))),
+    "<" | ">" | "<=" | ">=" => {
+      let (a, b) = match (singleton(&a)?, singleton(&b)?) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(vec![]),
+      };
+      boolean_result(compare(&a, &b)?.map(|ordering| match op {
+        "<" => ordering == Ordering::Less,
+        ">" => ordering == Ordering::Greater,
+        "<=" => ordering != Ordering::Greater,
+        _ => ordering != Ordering::Less,
+      }))
+    }
+    "in" | "contains" => {
+      let (needle, haystack) = if op == "in" { (a, b) } else { (b, a) };
+      match singleton(&needle)? {
+        None => Ok(vec![]),
+        Some(needle) => boolean_result(Some(
+          haystack
+            .iter()
+            .any(|item| equal_items(&needle, item) == Some(true)),
+        )),
+      }
+    }
+    "&" => {
+      let text = |items: &[Item]| -> Result<String, Error> {
+        Ok(match singleton(items)?.map(|i| i.primitive()) {
+          None => String::new(),
+          Some(Item::String(text)) => text,
+          Some(_) => return Err(Error::new("'&' can only concatenate strings")),
+        })
+      };
+      Ok(vec![Item::String(text(&a)? + &text(&b)?)])
+    }
+    _ => match (singleton(&a)?, singleton(&b)?) {
+      (Some(a), Some(b)) => Ok(
+        arithmetic(op, &a.primitive(), &b.primitive())?
+          .into_iter()
+          .collect(),
+      ),
+      _ => Ok(vec![]),
+    },
+  }
+}
+
+fn arithmetic<'a>(op: &str, a: &Item<'a>, b: &Item<'a>) -> Result<Option<Item<'a>>, Error> {
+  use Item::*;
+  Ok(match (op, a, b) {
+    ("+", Integer(a), Integer(b)) => integer(*a as i128 + *b as i128),
+    ("-", Integer(a), Integer(b)) => integer(*a as i128 - *b as i128),
+    ("*", Integer(a), Integer(b)) => integer(*a as i128 * *b as i128),
+    ("div", Integer(a), Integer(b)) => (*a as i128).checked_div(*b as i128).and_then(integer),
+    ("mod", Integer(a), Integer(b)) => (*a as i128).checked_rem(*b as i128).and_then(integer),
+    ("+", String(a), String(b)) => Some(String(format!("{}{}", a, b))),
+    ("+", Date(t), Quantity(q)) | ("-", Date(t), Quantity(q)) => {
+      let sign = if op == "-" { -1.0 } else { 1.0 };
+      t.add(sign * q.value.to_f64(), &q.unit, false).map(Date)
+    }
+    ("+", DateTime(t), Quantity(q)) | ("-", DateTime(t), Quantity(q)) => {
+      let sign = if op == "-" { -1.0 } else { 1.0 };
+      t.add(sign * q.value.to_f64(), &q.unit, false).map(DateTime)
+    }
+    ("+", Time(t), Quantity(q)) | ("-", Time(t), Quantity(q)) => {
+      let sign = if op == "-" { -1.0 } else { 1.0 };
+      t.add(sign * q.value.to_f64(), &q.unit, true).map(Time)
+    }
+    ("+", Quantity(a), Quantity(b)) => a
+      .align(b)
+      .and_then(|b| a.value.add(b))
+      .map(|value| Quantity(super::Quantity::new(value, &a.unit))),
+    ("-", Quantity(a), Quantity(b)) => a
+      .align(b)
+      .and_then(|b| a.value.sub(b))
+      .map(|value| Quantity(super::Quantity::new(value, &a.unit))),
+    ("*", Quantity(a), Quantity(b)) => a.value.mul(b.value).map(|value| {
+      Quantity(super::Quantity::new(
+        value,
+        &combine_units(&a.unit, &b.unit, '.'),
+      ))
+    }),
+    ("/", Quantity(a), Quantity(b)) => a.value.div(b.value).map(|value| {
+      Quantity(super::Quantity::new(
+        value,
+        &combine_units(&a.unit, &b.unit, '/'),
+      ))
+    }),
+    ("*", Quantity(q), n) | ("*", n, Quantity(q)) if number(n).is_some() => number(n)
+      .and_then(|n| q.value.mul(n))
+      .map(|value| Quantity(super::Quantity::new(value, &q.unit))),
+    ("/", Quantity(q), n) if number(n).is_some() => number(n)
+      .and_then(|n| q.value.div(n))
+      .map(|value| Quantity(super::Quantity::new(value, &q.unit))),
+    (_, a, b) if number(a).is_some() && number(b).is_some() => {
+      let (x, y) = (number(a).unwrap_or_default(), number(b).unwrap_or_default());
+      match op {
+        "+" => x.add(y).map(Decimal),
+        "-" => x.sub(y).map(Decimal),
+        "*" => x.mul(y).map(Decimal),
+        "/" => x.div(y).map(Decimal),
+        "div" => x.div_truncate(y).and_then(integer),
+        "mod" => x.rem(y).map(Decimal),
+        _ => return Err(Error::new(&format!("Unknown operator '{}'", op))),
+      }
+    }
+    _ => {
+      return Err(Error::new(&format!(
+        "Operator '{}' can't be applied to {} and {}",
+        op,
+        a.type_name().unwrap_or_else(|| "an element".to_string()),
+        b.type_name().unwrap_or_else(|| "an element".to_string())
+      )))
+    }
+  })
+}
+
+fn combine_units(a: &str, b: &str, separator: char) -> String {
+  match (a, b) {
+    (a, "1") => a.to_string(),
+    ("1", b) if separator == '.' => b.to_string(),
+    (a, b) => format!("{}{}{}", a, separator, b),
+  }
+}
+
+pub(crate) fn number(item: &Item) -> Option<Decimal> {
+  match item {
+    Item::Integer(value) => Some(Decimal::from(*value)),
+    Item::Decimal(value) => Some(*value),
+    _ => None,
+  }
+}
+
+/// An Integer result, which is empty when it doesn't fit in the 32 bits
+/// FHIRPath gives Integers.
+pub(crate) fn integer<'a>(value: i128) -> Option<Item<'a>> {
+  i32::try_from(value)
+    .ok()
+    .map(|value| Item::Integer(value as i64))
+}
+
+/// Unions two collections, dropping duplicates.
+pub(crate) fn union<'a>(a: Vec<Item<'a>>, b: Vec<Item<'a>>) -> Vec<Item<'a>> {
+  distinct(a.into_iter().chain(b))
+}
+
+pub(crate) fn distinct<'a>(items: impl IntoIterator<Item = Item<'a>>) -> Vec<Item<'a>> {
+  let mut result: Vec<Item<'a>> = vec![];
+  for item in items {
+    if !result
+      .iter()
+      .any(|existing| equal_items(existing, &item) == Some(true))
+    {
+      result.push(item);
+    }
+  }
+  result
+}
+
+/// `=` on collections: `None` when either side is empty or the answer depends
+/// on precision the values don't share.
+pub(crate) fn equal(a: &[Item], b: &[Item]) -> Option<bool> {
+  if a.is_empty() || b.is_empty() {
+    return None;
+  }
+  if a.len() != b.len() {
+    return Some(false);
+  }
+  let mut result = Some(true);
+  for (a, b) in a.iter().zip(b) {
+    match equal_items(a, b) {
+      Some(false) => return Some(false),
+      None => result = None,
+      Some(true) => {}
+    }
+  }
+  result
+}
+
+/// Converts both items to System values, reading untyped JSON strings as dates
+/// or times when compared with one.
+fn coerce<'a>(a: &Item<'a>, b: &Item<'a>) -> (Item<'a>, Item<'a>) {
+  let convert = |item: &Item<'a>, other: &Item<'a>| -> Item<'a> {
+    if let Item::Node(node) = item {
+      if let Some(text) = node.untyped_string() {
+        let parsed = match other {
+          Item::Date(_) | Item::DateTime(_) => super::Temporal::parse_date_time(text).map(|t| {
+            if text.contains('T') {
+              Item::DateTime(t)
+            } else {
+              Item::Date(t)
+            }
+          }),
+          Item::Time(_) => super::Temporal::parse_time(text).map(Item::Time),
+          _ => None,
+        };
+        if let Some(parsed) = parsed {
+          return parsed;
+        }
+      }
+      if let (Some(value), Item::Quantity(_)) = (node.value(), other) {
+        if let Some(quantity) = Quantity::from_json(value) {
+          return Item::Quantity(quantity);
+        }
+      }
+    }
+    item.primitive()
+  };
+  let a = convert(a, &b.primitive());
+  let b = convert(b, &a);
+  (a, b)
+}
+
+pub(crate) fn equal_items(a: &Item, b: &Item) -> Option<bool> {
+  use Item::*;
+  let (a, b) = coerce(a, b);
+  match (&a, &b) {
+    (Boolean(a), Boolean(b)) => Some(a == b),
+    (String(a), String(b)) => Some(a == b),
+    (Date(a), Date(b))
+    | (Date(a), DateTime(b))
+    | (DateTime(a), Date(b))
+    | (DateTime(a), DateTime(b))
+    | (Time(a), Time(b)) => a.compare(b).map(|o| o == Ordering::Equal),
+    (Quantity(a), Quantity(b)) => Some(a.compare(b, false) == Some(Ordering::Equal)),
+    (Quantity(q), n) | (n, Quantity(q)) if number(n).is_some() => Some(
+      q.compare(
+        &super::Quantity::new(number(n).unwrap_or_default(), "1"),
+        false,
+      ) == Some(Ordering::Equal),
+    ),
+    (a, b) if number(a).is_some() && number(b).is_some() => Some(number(a) == number(b)),
+    (Node(a), Node(b)) => Some(a.value() == b.value()),
+    _ => Some(false),
+  }
+}
+
+/// `~`: like `=` but case and whitespace insensitive for strings, rounded to
+/// the least precise decimal, false instead of empty, and order-independent.
+pub(crate) fn equivalent(a: &[Item], b: &[Item]) -> bool {
+  a.len() == b.len()
+    && a.iter().all(|x| b.iter().any(|y| equivalent_items(x, y)))
+    && b.iter().all(|y| a.iter().any(|x| equivalent_items(x, y)))
+}
+
+fn equivalent_items(a: &Item, b: &Item) -> bool {
+  use Item::*;
+  let (a, b) = coerce(a, b);
+  match (&a, &b) {
+    (String(a), String(b)) => normalize(a) == normalize(b),
+    (Decimal(_), _) | (_, Decimal(_)) if number(&a).is_some() && number(&b).is_some() => {
+      let (x, y) = (
+        number(&a).unwrap_or_default(),
+        number(&b).unwrap_or_default(),
+      );
+      let places = x.scale().min(y.scale());
+      x.round(places) == y.round(places)
+    }
+    (Quantity(a), Quantity(b)) => a.compare(b, true) == Some(Ordering::Equal),
+    (Date(_), _) | (DateTime(_), _) | (Time(_), _) => equal_items(&a, &b) == Some(true),
+    _ => equal_items(&a, &b) == Some(true),
+  }
+}
+
+fn normalize(text: &str) -> std::string::String {
+  text
+    .split_whitespace()
+    .collect::<Vec<_>>()
+    .join(" ")
+    .to_lowercase()
+}
+
+/// Orders two singletons, `None` when they can't be ordered because of
+/// differing precision or incommensurable units.
+pub(crate) fn compare(a: &Item, b: &Item) -> Result<Option<Ordering>, Error> {
+  use Item::*;
+  let (a, b) = coerce(a, b);
+  Ok(match (&a, &b) {
+    (String(a), String(b)) => Some(a.cmp(b)),
+    (Date(a), Date(b))
+    | (Date(a), DateTime(b))
+    | (DateTime(a), Date(b))
+    | (DateTime(a), DateTime(b))
+    | (Time(a), Time(b)) => a.compare(b),
+    (Quantity(a), Quantity(b)) => a.compare(b, false),
+    (Quantity(q), n) if number(n).is_some() => q.compare(
+      &super::Quantity::new(number(n).unwrap_or_default(), "1"),
+      false,
+    ),
+    (n, Quantity(q)) if number(n).is_some() => {
+      super::Quantity::new(number(n).unwrap_or_default(), "1").compare(q, false)
+    }
+    (a, b) if number(a).is_some() && number(b).is_some() => Some(number(a).cmp(&number(b))),
+    _ => {
+      return Err(Error::new(&format!(
+        "Can't compare {} with {}",
+        a.type_name().unwrap_or_else(|| "an element".to_string()),
+        b.type_name().unwrap_or_else(|| "an element".to_string())
+      )))
+    }
+  })
+}
@@ -0,0 +1,845 @@
+use super::eval::{
+  boolean, distinct, element_name, equal_items, evaluate, integer, is_type, member, number,
+  singleton, union, Env,
+};
+use super::parser::Expr;
+use super::value::{Decimal, Item, Node, Precision, Quantity, Temporal};
+use super::Error;
+use crate::datetime::now_instant;
+use crate::resolve::bundle_entry;
+use regex::Regex;
+use serde_json::value::Value;
+use std::convert::TryFrom;
+
+type Collection<'a> = Vec<Item<'a>>;
+
+/// Evaluates a call to a function from the FHIRPath function library or the
+/// FHIR-specific additions (`extension`, `resolve`, `hasValue`...).
+pub(crate) fn call<'a>(
+  env: &Env<'a, '_>,
+  name: &str,
+  input: Collection<'a>,
+  args: &[Expr],
+) -> Result<Collection<'a>, Error> {
+  let expect = |min: usize, max: usize| -> Result<(), Error> {
+    if args.len() < min || args.len() > max {
+      let count = if min == max {
+        format!("{}", min)
+      } else {
+        format!("{} to {}", min, max)
+      };
+      Err(Error::new(&format!(
+        "Function '{}' expects {} arguments but was given {}",
+        name,
+        count,
+        args.len()
+      )))
+    } else {
+      Ok(())
+    }
+  };
+  let arg = |index: usize| evaluate(&args[index], env);
+  let result = |items: Collection<'a>| Ok(items);
+  let boolean_result = |value: bool| Ok(vec![Item::Boolean(value)]);
+  match name {
+    // Existence
+    "empty" => {
+      expect(0, 0)?;
+      boolean_result(input.is_empty())
+    }
+    "exists" => {
+      expect(0, 1)?;
+      if args.is_empty() {
+        boolean_result(!input.is_empty())
+      } else {
+        boolean_result(!filter(env, input, &args[0])?.is_empty())
+      }
+    }
+    "all" => {
+      expect(1, 1)?;
+      for (index, item) in input.iter().enumerate() {
+        if boolean(&evaluate(&args[0], &env.iteration(item, index))?)? != Some(true) {
+          return boolean_result(false);
+        }
+      }
+      boolean_result(true)
+    }
+    "allTrue" | "anyTrue" | "allFalse" | "anyFalse" => {
+      expect(0, 0)?;
+      let wanted = name.ends_with("True");
+      let mut matches = input
+        .iter()
+        .map(|item| matches!(item.primitive(), Item::Boolean(b) if b == wanted));
+      boolean_result(if name.starts_with("all") {
+        matches.all(|m| m)
+      } else {
+        matches.any(|m| m)
+      })
+    }
+    "subsetOf" | "supersetOf" => {
+      expect(1, 1)?;
+      let other = arg(0)?;
+      let (subset, superset) = if name == "subsetOf" {
+        (&input, &other)
+      } else {
+        (&other, &input)
+      };
+      boolean_result(
+        subset
+          .iter()
+          .all(|a| superset.iter().any(|b| equal_items(a, b) == Some(true))),
+      )
+    }
+    "count" => {
+      expect(0, 0)?;
+      result(vec![Item::Integer(input.len() as i64)])
+    }
+    "distinct" => {
+      expect(0, 0)?;
+      result(distinct(input))
+    }
+    "isDistinct" => {
+      expect(0, 0)?;
+      let count = input.len();
+      boolean_result(distinct(input).len() == count)
+    }
+
+    // Filtering and projection
+    "where" => {
+      expect(1, 1)?;
+      filter(env, input, &args[0])
+    }
+    "select" => {
+      expect(1, 1)?;
+      let mut selected = vec![];
+      for (index, item) in input.iter().enumerate() {
+        selected.extend(evaluate(&args[0], &env.iteration(item, index))?);
+      }
+      result(selected)
+    }
+    "repeat" => {
+      expect(1, 1)?;
+      let mut found: Collection<'a> = vec![];
+      let mut pending = input;
+      while !pending.is_empty() {
+        let mut next = vec![];
+        for (index, item) in pending.iter().enumerate() {
+          for child in evaluate(&args[0], &env.iteration(item, index))? {
+            if !found.iter().any(|f| same(f, &child)) {
+              found.push(child.clone());
+              next.push(child);
+            }
+          }
+        }
+        pending = next;
+      }
+      result(found)
+    }
+    "ofType" => {
+      expect(1, 1)?;
+      let type_name = type_argument(&args[0])?;
+      result(
+        input
+          .into_iter()
+          .filter(|i| is_type(i, &type_name))
+          .collect(),
+      )
+    }
+    "is" => {
+      expect(1, 1)?;
+      let type_name = type_argument(&args[0])?;
+      Ok(match singleton(&input)? {
+        Some(item) => vec![Item::Boolean(is_type(&item, &type_name))],
+        None => vec![],
+      })
+    }
+    "as" => {
+      expect(1, 1)?;
+      let type_name = type_argument(&args[0])?;
+      result(
+        input
+          .into_iter()
+          .filter(|i| is_type(i, &type_name))
+          .collect(),
+      )
+    }
+
+    // Subsetting
+    "single" => {
+      expect(0, 0)?;
+      result(singleton(&input)?.into_iter().collect())
+    }
+    "first" => {
+      expect(0, 0)?;
+      result(input.into_iter().take(1).collect())
+    }
+    "last" => {
+      expect(0, 0)?;
+      result(input.into_iter().last().into_iter().collect())
+    }
+    "tail" => {
+      expect(0, 0)?;
+      result(input.into_iter().skip(1).collect())
+    }
+    "skip" | "take" => {
+      expect(1, 1)?;
+      let count = integer_argument(name, &arg(0)?)?.max(0) as usize;
+      result(if name == "skip" {
+        input.into_iter().skip(count).collect()
+      } else {
+        input.into_iter().take(count).collect()
+      })
+    }
+    "intersect" => {
+      expect(1, 1)?;
+      let other = arg(0)?;
+      result(distinct(input.into_iter().filter(|a| {
+        other.iter().any(|b| equal_items(a, b) == Some(true))
+      })))
+    }
+    "exclude" => {
+      expect(1, 1)?;
+      let other = arg(0)?;
+      result(
+        input
+          .into_iter()
+          .filter(|a| !other.iter().any(|b| equal_items(a, b) == Some(true)))
+          .collect(),
+      )
+    }
+    "union" => {
+      expect(1, 1)?;
+      result(union(input, arg(0)?))
+    }
+    "combine" => {
+      expect(1, 1)?;
+      let mut combined = input;
+      combined.extend(arg(0)?);
+      result(combined)
+    }
+
+    // Conversion
+    "iif" => {
+      expect(2, 3)?;
+      let scope;
+      let env = if input.len() == 1 {
+        scope = env.iteration(&input[0], 0);
+        &scope
+      } else {
+        env
+      };
+      match boolean(&evaluate(&args[0], env)?)? {
+        Some(true) => evaluate(&args[1], env),
+        _ if args.len() == 3 => evaluate(&args[2], env),
+        _ => result(vec![]),
+      }
+    }
+    "toBoolean" | "toInteger" | "toDecimal" | "toString" | "toDate" | "toDateTime" | "toTime" => {
+      expect(0, 0)?;
+      Ok(match singleton(&input)? {
+        Some(item) => convert(name, &item.primitive()).into_iter().collect(),
+        None => vec![],
+      })
+    }
+    "convertsToBoolean" | "convertsToInteger" | "convertsToDecimal" | "convertsToString"
+    | "convertsToDate" | "convertsToDateTime" | "convertsToTime" => {
+      expect(0, 0)?;
+      let target = format!("to{}", &name["convertsTo".len()..]);
+      Ok(match singleton(&input)? {
+        Some(item) => vec![Item::Boolean(convert(&target, &item.primitive()).is_some())],
+        None => vec![],
+      })
+    }
+    "toQuantity" | "convertsToQuantity" => {
+      expect(0, 1)?;
+      let item = match singleton(&input)? {
+        Some(item) => item,
+        None => return result(vec![]),
+      };
+      let mut quantity = to_quantity(&item);
+      if let (Some(q), false) = (&quantity, args.is_empty()) {
+        let unit = string_argument(name, &arg(0)?)?;
+        quantity = Quantity::new(Decimal::from(1), &unit)
+          .align(q)
+          .map(|value| Quantity::new(value, &unit));
+      }
+      Ok(if name == "toQuantity" {
+        quantity.map(Item::Quantity).into_iter().collect()
+      } else {
+        vec![Item::Boolean(quantity.is_some())]
+      })
+    }
+
+    // Strings
+    "indexOf" => {
+      expect(1, 1)?;
+      string_function(name, &input, |text| {
+        let substring = string_argument(name, &arg(0)?)?;
+        Ok(Some(Item::Integer(match text.find(&substring) {
+          Some(byte) => text[..byte].chars().count() as i64,
+          None => -1,
+        })))
+      })
+    }
+    "substring" => {
+      expect(1, 2)?;
+      string_function(name, &input, |text| {
+        let start = integer_argument(name, &arg(0)?)?;
+        let chars = text.chars().collect::<Vec<_>>();
+        if start < 0 || start as usize >= chars.len() {
+          return Ok(None);
+        }
+        let length = if args.len() == 2 {
+          integer_argument(name, &arg(1)?)?.max(0) as usize
+        } else {
+          chars.len()
+        };
+        Ok(Some(Item::String(
+          chars.iter().skip(start as usize).take(length).collect(),
+        )))
+      })
+    }
+    "startsWith" | "endsWith" | "contains" => {
+      expect(1, 1)?;
+      string_function(name, &input, |text| {
+        let other = string_argument(name, &arg(0)?)?;
+        Ok(Some(Item::Boolean(match name {
+          "startsWith" => text.starts_with(&other),
+          "endsWith" => text.ends_with(&other),
+          _ => text.contains(&other),
+        })))
+      })
+    }
+    "upper" | "lower" | "trim" | "length" | "toChars" => {
+      expect(0, 0)?;
+      if name == "toChars" {
+        return Ok(match single_string(name, &input)? {
+          Some(text) => text.chars().map(|c| Item::String(c.to_string())).collect(),
+          None => vec![],
+        });
+      }
+      string_function(name, &input, |text| {
+        Ok(Some(match name {
+          "upper" => Item::String(text.to_uppercase()),
+          "lower" => Item::String(text.to_lowercase()),
+          "trim" => Item::String(text.trim().to_string()),
+          _ => Item::Integer(text.chars().count() as i64),
+        }))
+      })
+    }
+    "replace" => {
+      expect(2, 2)?;
+      string_function(name, &input, |text| {
+        let pattern = string_argument(name, &arg(0)?)?;
+        let substitution = string_argument(name, &arg(1)?)?;
+        Ok(Some(Item::String(text.replace(&pattern, &substitution))))
+      })
+    }
+    "matches" | "replaceMatches" => {
+      expect(
+        if name == "matches" { 1 } else { 2 },
+        if name == "matches" { 1 } else { 2 },
+      )?;
+      string_function(name, &input, |text| {
+        let pattern = string_argument(name, &arg(0)?)?;
+        let regex = Regex::new(&pattern)
+          .map_err(|e| Error::new(&format!("Invalid regular expression '{}': {}", pattern, e)))?;
+        Ok(Some(if name == "matches" {
+          Item::Boolean(regex.is_match(text))
+        } else {
+          let substitution = string_argument(name, &arg(1)?)?;
+          Item::String(regex.replace_all(text, substitution.as_str()).into_owned())
+        }))
+      })
+    }
+    "split" => {
+      expect(1, 1)?;
+      let separator = string_argument(name, &arg(0)?)?;
+      Ok(match single_string(name, &input)? {
+        Some(text) => text
+          .split(separator.as_str())
+          .map(|part| Item::String(part.to_string()))
+          .collect(),
+        None => vec![],
+      })
+    }
+    "join" => {
+      expect(0, 1)?;
+      let separator = if args.is_empty() {
+        String::new()
+      } else {
+        string_argument(name, &arg(0)?)?
+      };
+      let mut parts = vec![];
+      for item in &input {
+        match item.primitive() {
+          Item::String(text) => parts.push(text),
+          _ => {
+            return Err(Error::new(
+              "Function 'join' requires a collection of strings",
+            ))
+          }
+        }
+      }
+      result(vec![Item::String(parts.join(&separator))])
+    }
+
+    // Math
+    "abs" | "ceiling" | "floor" | "truncate" | "exp" | "ln" | "sqrt" => {
+      expect(0, 0)?;
+      let item = match singleton(&input)? {
+        Some(item) => item.primitive(),
+        None => return result(vec![]),
+      };
+      if let Item::Quantity(q) = &item {
+        if name == "abs" {
+          return result(vec![Item::Quantity(Quantity::new(q.value.abs(), &q.unit))]);
+        }
+      }
+      let value = number(&item)
+        .ok_or_else(|| Error::new(&format!("Function '{}' requires a number", name)))?;
+      // Integer results that don't fit in 32 bits, and the logarithm or root
+      // of a number that hasn't one, are empty.
+      let float = value.to_f64();
+      let value = match (name, &item) {
+        ("abs", Item::Integer(i)) => integer((*i as i128).abs()),
+        ("abs", _) => Some(Item::Decimal(value.abs())),
+        ("ceiling", _) => integer(value.ceiling()),
+        ("floor", _) => integer(value.floor()),
+        ("truncate", _) => integer(value.truncate()),
+        ("exp", _) => Decimal::from_f64(float.exp()).map(Item::Decimal),
+        ("ln", _) if value.signum() > 0 => Decimal::from_f64(float.ln()).map(Item::Decimal),
+        ("sqrt", _) if value.signum() >= 0 => Decimal::from_f64(float.sqrt()).map(Item::Decimal),
+        _ => None,
+      };
+      Ok(value.into_iter().collect())
+    }
+    "log" | "power" | "round" => {
+      expect(if name == "round" { 0 } else { 1 }, 1)?;
+      let item = match singleton(&input)? {
+        Some(item) => item.primitive(),
+        None => return result(vec![]),
+      };
+      let value = number(&item)
+        .ok_or_else(|| Error::new(&format!("Function '{}' requires a number", name)))?;
+      let operand = if args.is_empty() {
+        None
+      } else {
+        match singleton(&arg(0)?)?.map(|i| i.primitive()) {
+          None => return result(vec![]),
+          Some(item) => Some(item),
+        }
+      };
+      let operand_value = operand.as_ref().and_then(number);
+      // Whole powers are worked out exactly, and logarithms and other powers
+      // as floats.
+      let exponent = match &operand {
+        Some(Item::Integer(e)) => u32::try_from(*e).ok(),
+        _ => None,
+      };
+      let float = |value: f64| Decimal::from_f64(value).map(Item::Decimal);
+      let value = match (name, &item) {
+        ("round", _) => match &operand {
+          None => value.round(0),
+          Some(Item::Integer(places)) => u32::try_from(*places)
+            .ok()
+            .and_then(|places| value.round(places)),
+          Some(_) => None,
+        }
+        .map(Item::Decimal),
+        ("log", _) => match operand_value {
+          Some(base) if value.signum() > 0 && base.signum() > 0 && base != Decimal::from(1) => {
+            float(value.to_f64().ln() / base.to_f64().ln())
+          }
+          _ => None,
+        },
+        ("power", Item::Integer(b)) if exponent.is_some() => exponent
+          .and_then(|e| (*b as i128).checked_pow(e))
+          .and_then(integer),
+        ("power", _) if exponent.is_some() => {
+          exponent.and_then(|e| value.power(e)).map(Item::Decimal)
+        }
+        ("power", _) => operand_value.and_then(|e| float(value.to_f64().powf(e.to_f64()))),
+        _ => None,
+      };
+      Ok(value.into_iter().collect())
+    }
+
+    // Tree navigation
+    "children" => {
+      expect(0, 0)?;
+      result(input.iter().flat_map(|item| children(env, item)).collect())
+    }
+    "descendants" => {
+      expect(0, 0)?;
+      let mut found = vec![];
+      let mut pending = input;
+      while !pending.is_empty() {
+        let next = pending
+          .iter()
+          .flat_map(|item| children(env, item))
+          .collect::<Vec<_>>();
+        found.extend(next.iter().cloned());
+        pending = next;
+      }
+      result(found)
+    }
+
+    // Utility
+    "trace" => {
+      expect(1, 2)?;
+      result(input)
+    }
+    "now" | "today" | "timeOfDay" => {
+      expect(0, 0)?;
+      let now = Temporal::parse_date_time(&now_instant())
+        .ok_or_else(|| Error::new("Unable to read the current time"))?;
+      result(vec![match name {
+        "now" => Item::DateTime(now),
+        "today" => Item::Date(Temporal {
+          precision: Precision::Day,
+          offset: None,
+          ..now
+        }),
+        _ => Item::Time(now),
+      }])
+    }
+    "not" => {
+      expect(0, 0)?;
+      Ok(
+        boolean(&input)?
+          .map(|b| Item::Boolean(!b))
+          .into_iter()
+          .collect(),
+      )
+    }
+    "aggregate" => {
+      expect(1, 2)?;
+      let mut total = if args.len() == 2 { arg(1)? } else { vec![] };
+      for (index, item) in input.iter().enumerate() {
+        let mut scope = env.iteration(item, index);
+        scope.total = Some(total);
+        total = evaluate(&args[0], &scope)?;
+      }
+      result(total)
+    }
+
+    // FHIR additions (http://hl7.org/fhir/fhirpath.html#functions)
+    "extension" => {
+      expect(1, 1)?;
+      let url = string_argument(name, &arg(0)?)?;
+      result(
+        input
+          .iter()
+          .flat_map(|item| member(env.context, item, "extension"))
+          .filter(|extension| {
+            extension
+              .as_node()
+              .and_then(|n| n.value())
+              .is_some_and(|v| v["url"].as_str() == Some(url.as_str()))
+          })
+          .collect(),
+      )
+    }
+    "hasValue" => {
+      expect(0, 0)?;
+      boolean_result(match input.as_slice() {
+        [Item::Node(node)] => node
+          .value()
+          .is_some_and(|v| !v.is_object() && !v.is_array()),
+        [_] => true,
+        _ => false,
+      })
+    }
+    "getValue" => {
+      expect(0, 0)?;
+      result(
+        input
+          .iter()
+          .filter(|item| {
+            item
+              .as_node()
+              .and_then(|n| n.value())
+              .is_some_and(|v| !v.is_object())
+          })
+          .map(Item::primitive)
+          .collect(),
+      )
+    }
+    "resolve" => {
+      expect(0, 0)?;
+      result(input.iter().filter_map(|item| resolve(env, item)).collect())
+    }
+    "htmlChecks" => {
+      expect(0, 0)?;
+      boolean_result(input.iter().all(|item| match item.primitive() {
+        Item::String(html) => html_checks(&html),
+        _ => false,
+      }))
+    }
+    _ => Err(Error::new(&format!("Unknown function '{}'", name))),
+  }
+}
+
+fn filter<'a>(
+  env: &Env<'a, '_>,
+  input: Collection<'a>,
+  criteria: &Expr,
+) -> Result<Collection<'a>, Error> {
+  let mut kept = vec![];
+  for (index, item) in input.into_iter().enumerate() {
+    if boolean(&evaluate(criteria, &env.iteration(&item, index))?)? == Some(true) {
+      kept.push(item);
+    }
+  }
+  Ok(kept)
+}
+
+/// Identity for `repeat`, which must not loop on structurally equal nodes.
+fn same(a: &Item, b: &Item) -> bool {
+  match (a, b) {
+    (Item::Node(a), Item::Node(b)) => a.value() == b.value() && a.path == b.path,
+    _ => equal_items(a, b) == Some(true),
+  }
+}
+
+fn type_argument(arg: &Expr) -> Result<String, Error> {
+  arg
+    .type_specifier()
+    .ok_or_else(|| Error::new("Expected a type name"))
+}
+
+fn integer_argument(name: &str, items: &[Item]) -> Result<i64, Error> {
+  match singleton(items)?.map(|i| i.primitive()) {
+    Some(Item::Integer(value)) => Ok(value),
+    _ => Err(Error::new(&format!(
+      "Function '{}' expects an integer argument",
+      name
+    ))),
+  }
+}
+
+fn string_argument(name: &str, items: &[Item]) -> Result<String, Error> {
+  match singleton(items)?.map(|i| i.primitive()) {
+    Some(Item::String(value)) => Ok(value),
+    _ => Err(Error::new(&format!(
+      "Function '{}' expects a string argument",
+      name
+    ))),
+  }
+}
+
+fn single_string(name: &str, input: &[Item]) -> Result<Option<String>, Error> {
+  match singleton(input)?.map(|i| i.primitive()) {
+    None => Ok(None),
+    Some(Item::String(text)) => Ok(Some(text)),
+    Some(_) => Err(Error::new(&format!(
+      "Function '{}' can only be used on a string",
+      name
+    ))),
+  }
+}
+
+fn string_function<'a, F>(name: &str, input: &[Item<'a>], f: F) -> Result<Collection<'a>, Error>
+where
+  F: FnOnce(&str) -> Result<Option<Item<'a>>, Error>,
+{
+  match single_string(name, input)? {
+    Some(text) => Ok(f(&text)?.into_iter().collect()),
+    None => Ok(vec![]),
+  }
+}
+
+fn convert<'a>(name: &str, item: &Item<'a>) -> Option<Item<'a>> {
+  use Item::*;
+  match (name, item) {
+    ("toBoolean", Boolean(b)) => Some(Boolean(*b)),
+    ("toBoolean", Integer(1)) => Some(Boolean(true)),
+    ("toBoolean", Integer(0)) => Some(Boolean(false)),
+    ("toBoolean", Decimal(d)) if *d == super::Decimal::from(1) => Some(Boolean(true)),
+    ("toBoolean", Decimal(d)) if d.signum() == 0 => Some(Boolean(false)),
+    ("toBoolean", String(s)) => match s.to_lowercase().as_str() {
+      "true" | "t" | "yes" | "y" | "1" | "1.0" => Some(Boolean(true)),
+      "false" | "f" | "no" | "n" | "0" | "0.0" => Some(Boolean(false)),
+      _ => None,
+    },
+    ("toInteger", Integer(i)) => Some(Integer(*i)),
+    ("toInteger", Boolean(b)) => Some(Integer(*b as i64)),
+    ("toInteger", String(s)) => {
+      let digits = s.strip_prefix(|c| c == '+' || c == '-').unwrap_or(s);
+      if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        s.parse::<i32>().ok().map(|i| Integer(i as i64))
+      } else {
+        None
+      }
+    }
+    ("toDecimal", Integer(i)) => Some(Decimal((*i).into())),
+    ("toDecimal", Decimal(d)) => Some(Decimal(*d)),
+    ("toDecimal", Boolean(b)) => Some(Decimal((*b as i64).into())),
+    ("toDecimal", String(s)) => {
+      let digits = s.strip_prefix(|c| c == '+' || c == '-').unwrap_or(s);
+      let valid = match digits.split_once('.') {
+        Some((whole, fraction)) => !whole.is_empty() && !fraction.is_empty(),
+        None => !digits.is_empty(),
+      } && digits.chars().all(|c| c.is_ascii_digit() || c == '.');
+      if valid {
+        super::Decimal::parse(s).map(Decimal)
+      } else {
+        None
+      }
+    }
+    ("toString", Boolean(b)) => Some(String(b.to_string())),
+    ("toString", Integer(i)) => Some(String(i.to_string())),
+    ("toString", Decimal(d)) => Some(String(d.to_string())),
+    ("toString", String(s)) => Some(String(s.clone())),
+    ("toString", Date(t)) => Some(String(t.format_date())),
+    ("toString", DateTime(t)) => Some(String(t.format_date_time())),
+    ("toString", Time(t)) => Some(String(t.format_time())),
+    ("toString", Quantity(q)) => Some(String(format!("{} '{}'", q.value, q.unit))),
+    ("toDate", Date(t)) => Some(Date(*t)),
+    ("toDate", DateTime(t)) => Some(Date(Temporal {
+      precision: t.precision.min(Precision::Day),
+      offset: None,
+      ..*t
+    })),
+    ("toDate", String(s)) => Temporal::parse_date(s)
+      .or_else(|| {
+        Temporal::parse_date_time(s).map(|t| Temporal {
+          precision: t.precision.min(Precision::Day),
+          offset: None,
+          ..t
+        })
+      })
+      .map(Date),
+    ("toDateTime", Date(t)) | ("toDateTime", DateTime(t)) => Some(DateTime(*t)),
+    ("toDateTime", String(s)) => Temporal::parse_date_time(s).map(DateTime),
+    ("toTime", Time(t)) => Some(Time(*t)),
+    ("toTime", String(s)) => Temporal::parse_time(s.strip_prefix('T').unwrap_or(s)).map(Time),
+    _ => None,
+  }
+}
+
+fn to_quantity(item: &Item) -> Option<Quantity> {
+  if let Item::Node(node) = item {
+    if let Some(quantity) = node.value().and_then(Quantity::from_json) {
+      return Some(quantity);
+    }
+  }
+  match item.primitive() {
+    Item::Integer(i) => Some(Quantity::new(i.into(), "1")),
+    Item::Decimal(d) => Some(Quantity::new(d, "1")),
+    Item::Quantity(q) => Some(q),
+    Item::String(s) => {
+      let (number, unit) = match s.trim().split_once(' ') {
+        Some((number, unit)) => (number, unit.trim()),
+        None => (s.trim(), "'1'"),
+      };
+      let unit = match unit.strip_prefix('\'').and_then(|u| u.strip_suffix('\'')) {
+        Some(unit) => unit,
+        None if super::value::calendar_to_ucum(unit).is_some() => unit,
+        None => return None,
+      };
+      convert("toDecimal", &Item::String(number.to_string())).and_then(|n| match n {
+        Item::Decimal(value) => Some(Quantity::new(value, unit)),
+        _ => None,
+      })
+    }
+    _ => None,
+  }
+}
+
+/// Every child node of an item, in document order.
+fn children<'a>(env: &Env<'a, '_>, item: &Item<'a>) -> Collection<'a> {
+  let node = match item {
+    Item::Node(node) => node,
+    _ => return vec![],
+  };
+  let container = match node.value() {
+    Some(Value::Object(object)) => object,
+    _ => match node.element.as_deref() {
+      Some(Value::Object(object)) => object,
+      _ => return vec![],
+    },
+  };
+  let mut names = vec![];
+  for key in container.keys() {
+    let key = key.strip_prefix('_').unwrap_or(key);
+    let name = element_name(env.context, &node.path, key);
+    if name != "resourceType" && !names.contains(&name) {
+      names.push(name);
+    }
+  }
+  let mut result = vec![];
+  for name in names {
+    result.extend(member(env.context, item, name));
+  }
+  result
+}
+
+/// Resolves a Reference, or a canonical/uri string, to the resource it points
+/// at: a contained resource, a Bundle entry, or one from the context's source.
+fn resolve<'a>(env: &Env<'a, '_>, item: &Item<'a>) -> Option<Item<'a>> {
+  let context = env.context;
+  let reference = match item.primitive() {
+    Item::String(reference) => reference,
+    Item::Node(node) => node.value()?.get("reference")?.as_str()?.to_string(),
+    _ => return None,
+  };
+  if let Some(id) = reference.strip_prefix('#') {
+    if id.is_empty() {
+      return Some(context.node(context.resource, None));
+    }
+    for resource in &[context.resource, context.root] {
+      let contained = resource
+        .get("contained")
+        .and_then(Value::as_array)
+        .and_then(|contained| contained.iter().find(|c| c["id"].as_str() == Some(id)));
+      if let Some(contained) = contained {
+        return Some(context.node(contained, None));
+      }
+    }
+    return None;
+  }
+  for root in &[context.root, context.resource] {
+    if root["resourceType"] == "Bundle" {
+      if let Some(resource) = bundle_entry(root, &reference) {
+        return Some(context.node(resource, None));
+      }
+    }
+  }
+  let resource = context.source?.fetch(&reference)?;
+  let type_name = resource.value["resourceType"].as_str().map(str::to_string);
+  Some(Item::Node(Node {
+    path: type_name.clone().unwrap_or_default(),
+    type_name,
+    value: Some(resource.value),
+    element: None,
+  }))
+}
+
+/// The checks `htmlChecks()` makes on narrative: a single `div` with some
+/// content and none of the elements or attributes the FHIR narrative rules
+/// forbid.
+fn html_checks(html: &str) -> bool {
+  let trimmed = html.trim();
+  let lower = trimmed.to_lowercase();
+  trimmed.starts_with("<div")
+    && trimmed.ends_with("</div>")
+    && lower.contains("xmlns=\"http://www.w3.org/1999/xhtml\"")
+    && [
+      "<script",
+      "<object",
+      "<applet",
+      "<iframe",
+      "<form",
+      "<input",
+      "<embed",
+      "<base",
+      "<link",
+      "<meta",
+      " onclick=",
+      " onload=",
+    ]
+    .iter()
+    .all(|forbidden| !lower.contains(forbidden))
+}
@@ -0,0 +1,240 @@
+use super::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+  /// A name, which is a keyword unless it was written in backticks.
+  Identifier(String, bool),
+  String(String),
+  Number(String),
+  /// `@`-prefixed date, date/time or time literal, without the `@`.
+  Temporal(String),
+  /// `%name`, `%`backticked`` or `%'string'`.
+  Constant(String),
+  /// `$this`, `$index` or `$total`.
+  Variable(String),
+  Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+  "!=", "!~", "<=", ">=", ".", ",", "(", ")", "[", "]", "{", "}", "+", "-", "*", "/", "&", "|",
+  "=", "~", "<", ">",
+];
+
+/// Splits an expression into tokens, each paired with its character offset.
+pub(crate) fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, Error> {
+  let chars = source.chars().collect::<Vec<_>>();
+  let mut tokens = vec![];
+  let mut position = 0;
+  while position < chars.len() {
+    let c = chars[position];
+    let start = position;
+    if c.is_whitespace() {
+      position += 1;
+      continue;
+    }
+    if c == '/' && chars.get(position + 1) == Some(&'/') {
+      while position < chars.len() && chars[position] != '\n' {
+        position += 1;
+      }
+      continue;
+    }
+    if c == '/' && chars.get(position + 1) == Some(&'*') {
+      position += 2;
+      while position < chars.len()
+        && !(chars[position] == '*' && chars.get(position + 1) == Some(&'/'))
+      {
+        position += 1;
+      }
+      if position >= chars.len() {
+        return Err(Error::at("Unterminated comment", start));
+      }
+      position += 2;
+      continue;
+    }
+    let token = if c.is_alphabetic() || c == '_' {
+      while position < chars.len() && (chars[position].is_alphanumeric() || chars[position] == '_')
+      {
+        position += 1;
+      }
+      Token::Identifier(chars[start..position].iter().collect(), false)
+    } else if c == '`' {
+      Token::Identifier(quoted(&chars, &mut position, '`')?, true)
+    } else if c == '\'' {
+      Token::String(quoted(&chars, &mut position, '\'')?)
+    } else if c.is_ascii_digit() {
+      while position < chars.len() && chars[position].is_ascii_digit() {
+        position += 1;
+      }
+      if chars.get(position) == Some(&'.')
+        && chars.get(position + 1).is_some_and(char::is_ascii_digit)
+      {
+        position += 1;
+        while position < chars.len() && chars[position].is_ascii_digit() {
+          position += 1;
+        }
+      }
+      Token::Number(chars[start..position].iter().collect())
+    } else if c == '@' {
+      position += 1;
+      Token::Temporal(temporal(&chars, &mut position)?)
+    } else if c == '%' {
+      position += 1;
+      match chars.get(position) {
+        Some('`') => Token::Constant(quoted(&chars, &mut position, '`')?),
+        Some('\'') => Token::Constant(quoted(&chars, &mut position, '\'')?),
+        _ => {
+          let name_start = position;
+          while position < chars.len()
+            && (chars[position].is_alphanumeric() || chars[position] == '_')
+          {
+            position += 1;
+          }
+          if position == name_start {
+            return Err(Error::at("Expected a constant name after '%'", start));
+          }
+          Token::Constant(chars[name_start..position].iter().collect())
+        }
+      }
+    } else if c == '$' {
+      position += 1;
+      while position < chars.len() && chars[position].is_alphanumeric() {
+        position += 1;
+      }
+      let name = chars[start..position].iter().collect::<String>();
+      match name.as_str() {
+        "$this" | "$index" | "$total" => Token::Variable(name),
+        _ => return Err(Error::at(&format!("Unknown variable '{}'", name), start)),
+      }
+    } else {
+      let symbol = SYMBOLS.iter().find(|symbol| {
+        symbol
+          .chars()
+          .enumerate()
+          .all(|(i, s)| chars.get(position + i) == Some(&s))
+      });
+      match symbol {
+        Some(symbol) => {
+          position += symbol.len();
+          Token::Symbol(symbol)
+        }
+        None => return Err(Error::at(&format!("Unexpected character '{}'", c), start)),
+      }
+    };
+    tokens.push((token, start));
+  }
+  Ok(tokens)
+}
+
+/// Reads a string or delimited identifier, unescaping its contents.
+fn quoted(chars: &[char], position: &mut usize, quote: char) -> Result<String, Error> {
+  let start = *position;
+  *position += 1;
+  let mut text = String::new();
+  loop {
+    match chars.get(*position) {
+      None => return Err(Error::at("Unterminated string", start)),
+      Some(c) if *c == quote => {
+        *position += 1;
+        return Ok(text);
+      }
+      Some('\\') => {
+        let escaped = chars
+          .get(*position + 1)
+          .ok_or_else(|| Error::at("Unterminated string", start))?;
+        *position += 2;
+        match escaped {
+          'n' => text.push('\n'),
+          'r' => text.push('\r'),
+          't' => text.push('\t'),
+          'f' => text.push('\u{c}'),
+          'u' => {
+            let hex = chars
+              .get(*position..*position + 4)
+              .map(|h| h.iter().collect::<String>())
+              .ok_or_else(|| Error::at("Invalid unicode escape", *position))?;
+            let code = u32::from_str_radix(&hex, 16)
+              .ok()
+              .and_then(std::char::from_u32)
+              .ok_or_else(|| Error::at("Invalid unicode escape", *position))?;
+            text.push(code);
+            *position += 4;
+          }
+          other => text.push(*other),
+        }
+      }
+      Some(c) => {
+        text.push(*c);
+        *position += 1;
+      }
+    }
+  }
+}
+
+/// Reads the body of an `@` literal following the grammar's DATE, DATETIME
+/// and TIME productions, leaving validation of the values to the parser.
+fn temporal(chars: &[char], position: &mut usize) -> Result<String, Error> {
+  let start = *position;
+  let digit = |p: usize| chars.get(p).is_some_and(char::is_ascii_digit);
+  let run = |position: &mut usize, count: usize| -> bool {
+    if (0..count).all(|i| digit(*position + i)) {
+      *position += count;
+      true
+    } else {
+      false
+    }
+  };
+  let time = |position: &mut usize| {
+    if !run(position, 2) {
+      return false;
+    }
+    if chars.get(*position) == Some(&':') && digit(*position + 1) {
+      *position += 1;
+      run(position, 2);
+      if chars.get(*position) == Some(&':') && digit(*position + 1) {
+        *position += 1;
+        run(position, 2);
+        if chars.get(*position) == Some(&'.') && digit(*position + 1) {
+          *position += 1;
+          while digit(*position) {
+            *position += 1;
+          }
+        }
+      }
+    }
+    true
+  };
+  if chars.get(*position) == Some(&'T') {
+    *position += 1;
+    if !time(position) {
+      return Err(Error::at("Invalid time literal", start));
+    }
+  } else {
+    if !run(position, 4) {
+      return Err(Error::at("Invalid date literal", start));
+    }
+    for _ in 0..2 {
+      if chars.get(*position) == Some(&'-') && digit(*position + 1) {
+        *position += 1;
+        run(position, 2);
+      }
+    }
+    if chars.get(*position) == Some(&'T') {
+      *position += 1;
+      if time(position) {
+        match chars.get(*position) {
+          Some('Z') => *position += 1,
+          Some('+') | Some('-') if digit(*position + 1) => {
+            *position += 1;
+            run(position, 2);
+            if chars.get(*position) == Some(&':') {
+              *position += 1;
+              run(position, 2);
+            }
+          }
+          _ => {}
+        }
+      }
+    }
+  }
+  Ok(chars[start..*position].iter().collect())
+}
@@ -0,0 +1,670 @@
+//! A FHIRPath (http://hl7.org/fhirpath/) engine that evaluates expressions
+//! directly over the JSON form of resources, with the FHIR-specific additions
+//! from http://hl7.org/fhir/fhirpath.html.
+//!
+//! ```
+//! use fhir_rs::fhirpath::{Context, Expression};
+//! use serde_json::json;
+//!
+//! let patient = json!({"resourceType": "Patient", "name": [{"given": ["Peter", "James"]}]});
+//! let expression = Expression::parse("Patient.name.given.first()").unwrap();
+//! let given = expression.evaluate(&Context::new(&patient)).unwrap();
+//! assert_eq!(given[0].to_json(), "Peter");
+//! ```
+
+mod eval;
+mod functions;
+mod lexer;
+mod parser;
+mod value;
+
+pub use self::value::{Decimal, Item, Node, Precision, Quantity, Temporal};
+
+use self::eval::Env;
+use self::parser::Expr;
+use crate::model::ResourceList::ResourceList;
+use crate::resolve::ResourceSource;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A problem parsing or evaluating an expression. `offset` is the character
+/// position in the expression for syntax errors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+  pub message: String,
+  pub offset: Option<usize>,
+}
+
+impl Error {
+  pub(crate) fn new(message: &str) -> Error {
+    Error {
+      message: message.to_string(),
+      offset: None,
+    }
+  }
+
+  pub(crate) fn at(message: &str, offset: usize) -> Error {
+    Error {
+      message: message.to_string(),
+      offset: Some(offset),
+    }
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self.offset {
+      Some(offset) => write!(f, "{} at offset {}", self.message, offset),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+/// Supplies the declared type of elements, e.g. `date` for `Patient.birthDate`
+/// or `HumanName` for `Patient.name`. Without it, types come only from what the
+/// JSON says: `resourceType`, choice element suffixes and extensions.
+pub trait ModelInfo {
+  fn element_type(&self, path: &str) -> Option<String>;
+}
+
+/// What an expression is evaluated against: the resource for `%resource`, the
+/// containing resource or Bundle for `%rootResource`, external constants, and
+/// where `resolve()` finds referenced resources.
+pub struct Context<'a> {
+  pub(crate) resource: &'a Value,
+  pub(crate) root: &'a Value,
+  pub(crate) variables: HashMap<String, Vec<Item<'a>>>,
+  pub(crate) source: Option<&'a dyn ResourceSource>,
+  pub(crate) model: Option<&'a dyn ModelInfo>,
+}
+
+impl<'a> Context<'a> {
+  pub fn new(resource: &'a Value) -> Context<'a> {
+    Context {
+      resource,
+      root: resource,
+      variables: HashMap::new(),
+      source: None,
+      model: None,
+    }
+  }
+
+  /// The resource that contains `%resource`, such as the Bundle it is an entry
+  /// of. References are also resolved against a root Bundle's entries.
+  pub fn root<'b>(&'b mut self, root: &'a Value) -> &'b mut Context<'a> {
+    self.root = root;
+    self
+  }
+
+  /// Defines `%name` for the expression.
+  pub fn variable<'b>(&'b mut self, name: &str, value: Vec<Item<'a>>) -> &'b mut Context<'a> {
+    self.variables.insert(name.to_string(), value);
+    self
+  }
+
+  /// Where `resolve()` looks up references that aren't contained resources or
+  /// Bundle entries.
+  pub fn source<'b>(&'b mut self, source: &'a dyn ResourceSource) -> &'b mut Context<'a> {
+    self.source = Some(source);
+    self
+  }
+
+  pub fn model<'b>(&'b mut self, model: &'a dyn ModelInfo) -> &'b mut Context<'a> {
+    self.model = Some(model);
+    self
+  }
+
+  /// Wraps part of a resource as a node, for use as the focus of an
+  /// expression. `path` is where the value sits (e.g. `Patient.contact`) and
+  /// defaults to the resource type for resources.
+  pub fn node(&self, value: &'a Value, path: Option<&str>) -> Item<'a> {
+    let resource_type = value["resourceType"].as_str();
+    let path = path.or(resource_type).unwrap_or_default().to_string();
+    let type_name = match resource_type {
+      Some(resource_type) => Some(resource_type.to_string()),
+      None => self.model.and_then(|m| m.element_type(&path)),
+    };
+    Item::Node(Node {
+      value: Some(Cow::Borrowed(value)),
+      element: None,
+      type_name,
+      path,
+    })
+  }
+
+  /// The children of a node with a given element name, as the expression
+  /// `item.name` would select them. This includes choice elements such as
+  /// `valueQuantity` for `value` and primitives that only have extensions.
+  pub fn children(&self, item: &Item<'a>, name: &str) -> Vec<Item<'a>> {
+    eval::member(self, item, name)
+  }
+}
+
+/// A parsed FHIRPath expression that can be evaluated many times.
+#[derive(Debug, Clone)]
+pub struct Expression {
+  source: String,
+  expr: Expr,
+}
+
+impl Expression {
+  pub fn parse(source: &str) -> Result<Expression, Error> {
+    Ok(Expression {
+      source: source.to_string(),
+      expr: parser::parse(source)?,
+    })
+  }
+
+  pub fn source(&self) -> &str {
+    &self.source
+  }
+
+  /// Evaluates the expression with `%resource` as its focus.
+  pub fn evaluate<'a>(&self, context: &Context<'a>) -> Result<Vec<Item<'a>>, Error> {
+    let focus = vec![context.node(context.resource, None)];
+    self.evaluate_on(context, &focus)
+  }
+
+  /// Evaluates the expression against a focus other than the resource itself,
+  /// such as the element an invariant is declared on.
+  pub fn evaluate_on<'a>(
+    &self,
+    context: &Context<'a>,
+    focus: &[Item<'a>],
+  ) -> Result<Vec<Item<'a>>, Error> {
+    let env = Env {
+      context,
+      this: focus.to_vec(),
+      index: None,
+      total: None,
+      focus,
+      cache: &RefCell::new(HashMap::new()),
+    };
+    eval::evaluate(&self.expr, &env)
+  }
+
+  /// Evaluates the expression as a condition, such as an invariant. A single
+  /// non-Boolean item counts as `true`, and an empty result gives `None`.
+  pub fn evaluate_boolean<'a>(
+    &self,
+    context: &Context<'a>,
+    focus: &[Item<'a>],
+  ) -> Result<Option<bool>, Error> {
+    eval::boolean(&self.evaluate_on(context, focus)?)
+  }
+}
+
+/// Parses and evaluates an expression against a resource in one step.
+pub fn evaluate<'a>(resource: &'a ResourceList, expression: &str) -> Result<Vec<Item<'a>>, Error> {
+  Expression::parse(expression)?.evaluate(&Context::new(&resource.value))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::fs;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  fn run<'a>(context: &Context<'a>, expression: &str) -> Result<Vec<Value>, Error> {
+    Expression::parse(expression)?
+      .evaluate(context)
+      .map(|items| items.iter().map(Item::to_json).collect())
+  }
+
+  /// Hand-picked expressions covering the grammar and function library
+  /// against the official suite's example resources, beyond what
+  /// `test_official_suite` covers.
+  #[test]
+  fn test_fhirpath_suite() {
+    let patient = load("examples-json/patient-example.json");
+    let observation = load("examples-json/observation-example.json");
+    let patient_context = Context::new(&patient);
+    let observation_context = Context::new(&observation);
+
+    let outputs: &[(&str, Value)] = &[
+      (
+        "name.given",
+        json!(["Peter", "James", "Jim", "Peter", "James"]),
+      ),
+      ("name.suffix", json!([])),
+      (
+        "name.`given`",
+        json!(["Peter", "James", "Jim", "Peter", "James"]),
+      ),
+      ("Encounter.name.given", json!([])),
+      ("Patient.name.first().given", json!(["Peter", "James"])),
+      (
+        "Patient.name.skip(1).given",
+        json!(["Jim", "Peter", "James"]),
+      ),
+      (
+        "Patient.name.take(2).given",
+        json!(["Peter", "James", "Jim"]),
+      ),
+      (
+        "Patient.name.tail().given",
+        json!(["Jim", "Peter", "James"]),
+      ),
+      ("Patient.name[1].given", json!(["Jim"])),
+      (
+        "Patient.name.given.where(substring($this.length()-3) = 'ter')",
+        json!(["Peter", "Peter"]),
+      ),
+      (
+        "Patient.name.given.where(substring($this.length()-3) = 'out')",
+        json!([]),
+      ),
+      (
+        "Patient.telecom.where(use = 'mobile').value",
+        json!(["(03) 3410 5613"]),
+      ),
+      (
+        "Patient.name.given.distinct()",
+        json!(["Peter", "James", "Jim"]),
+      ),
+      ("(1 | 2 | 2).count()", json!([2])),
+      ("1.combine(1).count()", json!([2])),
+      ("'a,b,c'.split(',')", json!(["a", "b", "c"])),
+      (
+        "Patient.name.given.aggregate($this.length() + $total, 0)",
+        json!([23]),
+      ),
+      ("'12345'.substring(2)", json!(["345"])),
+      ("'12345'.substring(1, 2)", json!(["23"])),
+      ("'abc'.replaceMatches('(b)', '[$1]')", json!(["a[b]c"])),
+      ("7 div 2", json!([3])),
+      ("7 mod 2", json!([1])),
+      ("7 / 2", json!([3.5])),
+      ("2.power(3)", json!([8])),
+      ("2.4567.round(2)", json!([2.46])),
+      ("1.5.ceiling()", json!([2])),
+      ("(-5).abs()", json!([5])),
+      ("1.toString() + 'x'", json!(["1x"])),
+      ("'2012-04'.toDate()", json!(["2012-04"])),
+      ("@2012-01-31 + 1 month", json!(["2012-02-29"])),
+      (
+        "@2012-12-31T23:30:00Z + 45 minutes",
+        json!(["2013-01-01T00:15:00Z"]),
+      ),
+      ("@T23:00 + 2 hours", json!(["01:00"])),
+      ("%resource.id", json!(["example"])),
+      (
+        "%`ext-patient-birthTime`",
+        json!(["http://hl7.org/fhir/StructureDefinition/patient-birthTime"]),
+      ),
+      ("{}", json!([])),
+      ("(true and {})", json!([])),
+      ("@2012-04-15 = @2012-04-15T10:00:00", json!([])),
+    ];
+    for (expression, expected) in outputs {
+      let actual =
+        run(&patient_context, expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+      assert_eq!(&Value::Array(actual), expected, "{}", expression);
+    }
+
+    let truths = &[
+      "name.given.count() = 5",
+      "Patient.active = true",
+      "Patient.birthDate = @1974-12-25",
+      "Patient.birthDate < today()",
+      "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime').exists()",
+      "Patient.birthDate.extension(%`ext-patient-birthTime`).value < @1974-12-26",
+      "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime1').empty()",
+      "Patient.birthDate.hasValue() and Patient.name.hasValue().not()",
+      "Patient.deceased is boolean and Patient.deceased = false",
+      "Patient.gender in ('male' | 'female')",
+      "Patient.name.given contains 'Jim'",
+      "Patient.name.exists(use = 'maiden') and Patient.name.all(given.exists())",
+      "Patient.name.select(given.exists()).allTrue()",
+      "Patient.name.select(period.exists()).allFalse().not()",
+      "Patient.name.where(use = 'official').given.first() = 'Peter'",
+      "Patient.is(Patient) and Patient is DomainResource and Patient.ofType(Resource).count() = 1",
+      "Patient.text.div.htmlChecks()",
+      "Patient.descendants().where($this = 'Chalmers').exists()",
+      "Patient.children().where($this = 'male').exists()",
+      "iif(Patient.active, 'a', 'b') = 'a'",
+      "Patient.name.given.subsetOf(Patient.name.given | 'Bob')",
+      "Patient.name.given.isDistinct().not()",
+      "(Patient.name.given.first() | 'Jim').intersect(Patient.name.given).count() = 2",
+      "Patient.name.given.exclude('Peter').count() = 3",
+      "Patient.name.given.union(Patient.name.family).count() = 5",
+      "'abc'.indexOf('bc') = 1 and 'abc'.startsWith('ab') and 'abc'.endsWith('bc')",
+      "'abc'.upper() = 'ABC' and 'ABC'.lower() = 'abc' and ' a '.trim() = 'a'",
+      "'abc'.replace('b', 'x') = 'axc' and 'abc'.length() = 3 and 'abc'.toChars().count() = 3",
+      "'12345'.matches('^[0-9]+$') and '12a45'.matches('^[0-9]+$').not()",
+      "('a' | 'b').join('-') = 'a-b'",
+      "16.sqrt() = 4.0 and 100.log(10) = 2.0 and 1.exp() > 2.7 and 1.ln() = 0.0",
+      "1.9.truncate() = 1 and 1.1.floor() = 1",
+      "'true'.toBoolean() and '1'.toInteger() = 1 and '1.5'.toDecimal() = 1.5",
+      "'a'.convertsToInteger().not() and '2012-04-15T10:00:00'.convertsToDateTime()",
+      "'5 \\'mg\\''.toQuantity() = 5 'mg' and 1.toQuantity() = 1 '1'",
+      "1.0 = 1 and 1.0 ~ 1.00 and 'a' < 'b' and 'Abc  d' ~ 'abc d'",
+      "(false and {}) = false and (true or {}) = true and (false implies {}) = true",
+      "(true xor false) and ({} implies true)",
+      "@2012-04-15T15:00:00+02:00 = @2012-04-15T13:00:00Z",
+      "(@2012-04-15 ~ @2012-04-15T10:00:00).not()",
+      "@2012-04-15T10:00:00 > @2012-04-15T09:59:59.999",
+      "1 'cm' = 10 'mm' and 4 'g' = 4000 'mg' and 4 'g' < 4040 'mg'",
+      "7 days = 1 week and 1 'wk' = 7 'd'",
+      "2 'cm' + 1 'm' = 102 'cm' and 2 'm' * 3 = 6 'm'",
+      "now() > @2020-01-01T00:00:00Z and now() <= now() and timeOfDay() is Time",
+      "%ucum = 'http://unitsofmeasure.org' and %context.id = 'example'",
+    ];
+    for expression in truths.iter() {
+      let actual =
+        run(&patient_context, expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+      assert_eq!(actual, vec![json!(true)], "{}", expression);
+    }
+
+    let observations = &[
+      "Observation.value.unit = 'lbs'",
+      "Observation.value.is(Quantity) and Observation.value is Quantity",
+      "Observation.value.as(Quantity).unit = 'lbs' and (Observation.value as Quantity).unit = 'lbs'",
+      "Observation.value.value > 180.0 and Observation.valueQuantity.value < 190",
+      "Observation.value > 80 'kg' and Observation.value < 90 'kg'",
+      "Observation.value.ofType(FHIR.Quantity).exists() and Observation.value.ofType(string).empty()",
+    ];
+    for expression in observations.iter() {
+      let actual =
+        run(&observation_context, expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+      assert_eq!(actual, vec![json!(true)], "{}", expression);
+    }
+  }
+
+  /// Whether an item is the output the suite expects, given as its FHIRPath
+  /// type and the value as the suite writes it.
+  fn matches(item: &Item, expected: &Value) -> bool {
+    let text = expected["value"].as_str().unwrap();
+    match (expected["type"].as_str().unwrap(), item.primitive()) {
+      ("boolean", Item::Boolean(value)) => value.to_string() == text,
+      ("integer", Item::Integer(value)) => value.to_string() == text,
+      ("decimal", Item::Decimal(value)) => Decimal::parse(text) == Some(value),
+      ("date", Item::Date(value)) => value.format_date() == text,
+      ("dateTime", Item::DateTime(value)) => value.format_date_time() == text,
+      ("time", Item::Time(value)) => value.format_time() == text,
+      ("Quantity", Item::Quantity(value)) => {
+        let literal = Expression::parse(text).unwrap();
+        let patient = json!({});
+        let expected = literal.evaluate(&Context::new(&patient)).unwrap();
+        expected.len() == 1 && expected[0].to_json() == value.to_json()
+      }
+      ("string", item) | ("code", item) => item.to_json() == json!(text),
+      _ => false,
+    }
+  }
+
+  /// Runs one case of the suite, giving why it failed.
+  fn check(test: &Value) -> Result<(), String> {
+    let resource = load(&format!(
+      "examples-json/{}",
+      test["inputfile"].as_str().unwrap()
+    ));
+    let context = Context::new(&resource);
+    let source = test["expression"].as_str().unwrap();
+    let result = Expression::parse(source).and_then(|expression| {
+      if test["predicate"] == json!(true) {
+        let focus = [context.node(&resource, None)];
+        let value = expression.evaluate_boolean(&context, &focus)?;
+        Ok(value.map(Item::Boolean).into_iter().collect())
+      } else {
+        expression.evaluate(&context)
+      }
+    });
+    match (test["invalid"].as_str(), result) {
+      (Some(_), Err(_)) => Ok(()),
+      (Some(invalid), Ok(items)) => Err(format!(
+        "expected a {} error, got {:?}",
+        invalid,
+        items.iter().map(Item::to_json).collect::<Vec<_>>()
+      )),
+      (None, Err(error)) => Err(error.to_string()),
+      (None, Ok(items)) => {
+        let expected = test["output"].as_array().unwrap();
+        let same = items.len() == expected.len()
+          && items
+            .iter()
+            .zip(expected)
+            .all(|(item, output)| matches(item, output));
+        if same {
+          Ok(())
+        } else {
+          Err(format!(
+            "got {:?}",
+            items.iter().map(Item::to_json).collect::<Vec<_>>()
+          ))
+        }
+      }
+    }
+  }
+
+  /// The cases of the official suite that don't pass, and why.
+  const KNOWN_FAILURES: &[&str] = &[
+    // Without a ModelInfo, primitive elements are only as typed as their JSON,
+    // so birthDate is a string.
+    "testExtractBirthDate",
+    // There's no strict mode checking paths against the model.
+    "testSimpleFail",
+    "testSimpleWithWrongContext",
+    "testPolymorphismB",
+    "testPolymorphismAsB",
+    "testDollarOrderNotAllowed",
+    // A single 0 counts as true, as any single non-Boolean item does.
+    "testIntegerBooleanNotTrue",
+    // Booleans don't convert to Quantity.
+    "testBooleanLiteralConvertsToQuantity",
+    // type() isn't implemented.
+    "testType1",
+    "testType2",
+    "testType3",
+    "testType4",
+    "testType9",
+    "testType10",
+    "testType15",
+    "testType16",
+    "testType20",
+    "testType21",
+    "testType23",
+    // is(Boolean) also matches FHIR boolean elements.
+    "testType12",
+    // An empty argument to the string functions is an error, not empty.
+    "testReplace5",
+    "testReplace6",
+    "testReplaceMatches5",
+    "testReplaceMatches6",
+    "testIndexOf4",
+  ];
+
+  /// Runs the JSON port of the official FHIRPath suite for R4 in
+  /// examples-fhirpath. Every case passes except those in `KNOWN_FAILURES`,
+  /// so fixing one means taking it off the list.
+  #[test]
+  fn test_official_suite() {
+    let suite = load("examples-fhirpath/tests-fhir-r4.json");
+    let mut failures = Vec::new();
+    for group in suite["groups"].as_array().unwrap() {
+      for test in group["tests"].as_array().unwrap() {
+        let name = test["name"].as_str().unwrap();
+        if let Err(reason) = check(test) {
+          failures.push((name, reason));
+        }
+      }
+    }
+    let unexpected: Vec<_> = failures
+      .iter()
+      .filter(|(name, _)| !KNOWN_FAILURES.contains(name))
+      .collect();
+    assert!(unexpected.is_empty(), "{:#?}", unexpected);
+    let fixed: Vec<_> = KNOWN_FAILURES
+      .iter()
+      .filter(|name| !failures.iter().any(|(failure, _)| failure == *name))
+      .collect();
+    assert!(fixed.is_empty(), "now passing: {:?}", fixed);
+  }
+
+  #[test]
+  fn test_errors() {
+    let patient = load("examples-json/patient-example.json");
+    let context = Context::new(&patient);
+    let failures = &[
+      "Patient.name.given.substring(1, 2, 3)",
+      "Patient.name.given + 1",
+      "Patient.name.foo()",
+      "'abc' < 1",
+      "%unknown",
+      "'abc'.matches('(')",
+      "Patient.name.given.single()",
+    ];
+    for expression in failures.iter() {
+      assert!(run(&context, expression).is_err(), "{}", expression);
+    }
+    let error = Expression::parse("Patient.name.where(use = 'official'").unwrap_err();
+    assert_eq!(error.offset, Some(35));
+    let error = Expression::parse("1 + ").unwrap_err();
+    assert_eq!(error.message, "Unexpected end of expression");
+    assert_eq!(
+      Expression::parse("@2012-13-01").unwrap_err().offset,
+      Some(0)
+    );
+  }
+
+  #[test]
+  fn test_numbers() {
+    let patient = load("examples-json/patient-example.json");
+    let context = Context::new(&patient);
+    // Decimals are exact and keep the places they were written with.
+    let truths = &[
+      "(0.1 + 0.2) = 0.3 and 0.3 - 0.1 = 0.2 and 1.1 * 3 = 3.3",
+      "(1.0 / 3) * 3 ~ 1.0 and (1 / 3) ~ 0.333 and (1 / 3) != 0.333",
+      "7.5 mod 2 = 1.5 and 7.5 div 2 = 3 and (-7.5) div 2 = -3",
+      "2.5.round() = 3 and (-2.5).round() = -3 and 3.14159.round(3) = 3.142",
+      "(-1.5).floor() = -2 and (-1.5).ceiling() = -1 and (-1.5).truncate() = -1",
+      "0.5.power(2) = 0.25 and 1.50.toString() = '1.50' and 1.0 ~ 1.04",
+      "0.1 'mg' + 0.2 'mg' = 0.3 'mg' and '0.1'.toDecimal() * 3 = 0.3",
+      "2147483647 + 0 = 2147483647 and -2147483647 - 1 < 0",
+    ];
+    for expression in truths.iter() {
+      let actual = run(&context, expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+      assert_eq!(actual, vec![json!(true)], "{}", expression);
+    }
+    assert_eq!(run(&context, "0.1 + 0.2").unwrap(), vec![json!(0.3)]);
+    // Integers are 32 bits, and results that don't fit are empty.
+    let empties = &[
+      "2147483647 + 1",
+      "(-2147483647 - 1) - 1",
+      "65536 * 65536",
+      "2.power(31)",
+      "3000000000.0.floor()",
+      "'2147483648'.toInteger()",
+      "1 / 0",
+      "1.0 div 0",
+      "1 mod 0",
+    ];
+    for expression in empties.iter() {
+      let actual = run(&context, expression).unwrap_or_else(|e| panic!("{}: {}", expression, e));
+      assert_eq!(actual, Vec::<Value>::new(), "{}", expression);
+    }
+    assert!(Expression::parse("2147483648").is_err());
+  }
+
+  #[test]
+  fn test_types_and_resolution() {
+    struct Model;
+    impl ModelInfo for Model {
+      fn element_type(&self, path: &str) -> Option<String> {
+        match path {
+          "Patient.birthDate" => Some("date".to_string()),
+          "Patient.name" => Some("HumanName".to_string()),
+          "HumanName.given" => Some("string".to_string()),
+          _ => None,
+        }
+      }
+    }
+    let patient = load("examples-json/patient-example.json");
+    let mut context = Context::new(&patient);
+    context.model(&Model);
+    let types = |expression: &str| {
+      Expression::parse(expression)
+        .unwrap()
+        .evaluate(&context)
+        .unwrap()
+        .iter()
+        .map(|i| i.type_name().unwrap_or_default())
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(types("Patient"), vec!["FHIR.Patient"]);
+    assert_eq!(types("Patient.birthDate"), vec!["FHIR.date"]);
+    assert_eq!(types("Patient.name.given.first()"), vec!["FHIR.string"]);
+    assert_eq!(types("Patient.deceased"), vec!["FHIR.boolean"]);
+    assert_eq!(
+      types("1.5 | 1 | 'a' | 1 'mg'"),
+      vec![
+        "System.Decimal",
+        "System.Integer",
+        "System.String",
+        "System.Quantity"
+      ]
+    );
+    assert_eq!(
+      run(&context, "Patient.birthDate is date").unwrap(),
+      vec![json!(true)]
+    );
+    assert_eq!(
+      run(&context, "Patient.name.given.ofType(string).count()").unwrap(),
+      vec![json!(5)]
+    );
+
+    let bundle = json!({
+      "resourceType": "Bundle",
+      "type": "collection",
+      "entry": [
+        {"fullUrl": "http://example.org/fhir/Patient/p1", "resource": {"resourceType": "Patient", "id": "p1"}},
+        {"fullUrl": "http://example.org/fhir/Observation/o1", "resource": {
+          "resourceType": "Observation",
+          "id": "o1",
+          "contained": [{"resourceType": "Device", "id": "d1"}],
+          "subject": {"reference": "Patient/p1"},
+          "device": {"reference": "#d1"},
+          "performer": [{"reference": "Practitioner/x"}]
+        }}
+      ]
+    });
+    let observation = &bundle["entry"][1]["resource"];
+    let external = json!({"resourceType": "Practitioner", "id": "x"});
+    let mut source = HashMap::new();
+    source.insert("Practitioner/x".to_string(), external);
+    let mut context = Context::new(observation);
+    context
+      .root(&bundle)
+      .source(&source)
+      .variable("threshold", vec![Item::Integer(1)]);
+    assert_eq!(
+      run(&context, "subject.resolve().id").unwrap(),
+      vec![json!("p1")]
+    );
+    assert_eq!(
+      run(&context, "device.resolve().id").unwrap(),
+      vec![json!("d1")]
+    );
+    assert_eq!(
+      run(&context, "performer.resolve().id").unwrap(),
+      vec![json!("x")]
+    );
+    assert_eq!(
+      run(&context, "%rootResource.entry.count() > %threshold").unwrap(),
+      vec![json!(true)]
+    );
+    assert_eq!(
+      run(&context, "subject.resolve() is Patient").unwrap(),
+      vec![json!(true)]
+    );
+  }
+}
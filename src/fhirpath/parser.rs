@@ -0,0 +1,368 @@
+use super::lexer::{tokenize, Token};
+use super::value::{Decimal, Quantity, Temporal};
+use super::Error;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Literal {
+  Empty,
+  Boolean(bool),
+  String(String),
+  Integer(i64),
+  Decimal(Decimal),
+  Date(Temporal),
+  DateTime(Temporal),
+  Time(Temporal),
+  Quantity(Quantity),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Expr {
+  Literal(Literal),
+  /// `%name`.
+  Constant(String),
+  This,
+  Index,
+  Total,
+  /// A member of the target, or of `$this` when there is no target.
+  Member(Option<Box<Expr>>, String),
+  /// A function call on the target, or on `$this` when there is no target.
+  Call(Option<Box<Expr>>, String, Vec<Expr>),
+  Indexer(Box<Expr>, Box<Expr>),
+  Negate(Box<Expr>),
+  Binary(&'static str, Box<Expr>, Box<Expr>),
+  /// `is` and `as` with their (possibly qualified) type name.
+  Type(&'static str, Box<Expr>, String),
+}
+
+impl Expr {
+  /// Reads a type specifier written as an expression, as in `ofType(FHIR.Patient)`.
+  pub(crate) fn type_specifier(&self) -> Option<String> {
+    match self {
+      Expr::Member(None, name) => Some(name.clone()),
+      Expr::Member(Some(target), name) => Some(format!("{}.{}", target.type_specifier()?, name)),
+      _ => None,
+    }
+  }
+}
+
+/// Binding power of each binary operator, loosest first.
+const OPERATORS: &[(&str, u8)] = &[
+  ("implies", 1),
+  ("or", 2),
+  ("xor", 2),
+  ("and", 3),
+  ("in", 4),
+  ("contains", 4),
+  ("=", 5),
+  ("~", 5),
+  ("!=", 5),
+  ("!~", 5),
+  ("<", 6),
+  (">", 6),
+  ("<=", 6),
+  (">=", 6),
+  ("|", 7),
+  ("is", 8),
+  ("as", 8),
+  ("+", 9),
+  ("-", 9),
+  ("&", 9),
+  ("*", 10),
+  ("/", 10),
+  ("div", 10),
+  ("mod", 10),
+];
+
+const CALENDAR_UNITS: &[&str] = &[
+  "year",
+  "years",
+  "month",
+  "months",
+  "week",
+  "weeks",
+  "day",
+  "days",
+  "hour",
+  "hours",
+  "minute",
+  "minutes",
+  "second",
+  "seconds",
+  "millisecond",
+  "milliseconds",
+];
+
+pub(crate) fn parse(source: &str) -> Result<Expr, Error> {
+  let mut parser = Parser {
+    tokens: tokenize(source)?,
+    position: 0,
+    length: source.chars().count(),
+  };
+  let expr = parser.expression(0)?;
+  match parser.tokens.get(parser.position) {
+    None => Ok(expr),
+    Some((token, offset)) => Err(Error::at(
+      &format!("Unexpected {}", describe(token)),
+      *offset,
+    )),
+  }
+}
+
+struct Parser {
+  tokens: Vec<(Token, usize)>,
+  position: usize,
+  length: usize,
+}
+
+impl Parser {
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.position).map(|(token, _)| token)
+  }
+
+  fn offset(&self) -> usize {
+    self
+      .tokens
+      .get(self.position)
+      .map_or(self.length, |(_, offset)| *offset)
+  }
+
+  fn next(&mut self) -> Result<Token, Error> {
+    match self.tokens.get(self.position) {
+      Some((token, _)) => {
+        self.position += 1;
+        Ok(token.clone())
+      }
+      None => Err(Error::at("Unexpected end of expression", self.length)),
+    }
+  }
+
+  fn eat(&mut self, symbol: &str) -> bool {
+    if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+      self.position += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, symbol: &str) -> Result<(), Error> {
+    if self.eat(symbol) {
+      Ok(())
+    } else {
+      let found = self
+        .peek()
+        .map_or("end of expression".to_string(), describe);
+      Err(Error::at(
+        &format!("Expected '{}' but found {}", symbol, found),
+        self.offset(),
+      ))
+    }
+  }
+
+  /// The binary operator at the current token, if any.
+  fn operator(&self) -> Option<(&'static str, u8)> {
+    let text = match self.peek()? {
+      Token::Symbol(symbol) => *symbol,
+      Token::Identifier(name, false) => name.as_str(),
+      _ => return None,
+    };
+    OPERATORS.iter().find(|(op, _)| *op == text).cloned()
+  }
+
+  fn expression(&mut self, min_power: u8) -> Result<Expr, Error> {
+    let mut left = self.unary()?;
+    while let Some((op, power)) = self.operator() {
+      if power <= min_power {
+        break;
+      }
+      self.position += 1;
+      left = if op == "is" || op == "as" {
+        Expr::Type(op, Box::new(left), self.qualified_name()?)
+      } else {
+        let right = self.expression(power)?;
+        Expr::Binary(op, Box::new(left), Box::new(right))
+      };
+    }
+    Ok(left)
+  }
+
+  fn unary(&mut self) -> Result<Expr, Error> {
+    if self.eat("-") {
+      return Ok(Expr::Negate(Box::new(self.unary()?)));
+    }
+    if self.eat("+") {
+      return self.unary();
+    }
+    self.postfix()
+  }
+
+  fn postfix(&mut self) -> Result<Expr, Error> {
+    let mut expr = self.term()?;
+    loop {
+      if self.eat(".") {
+        expr = self.invocation(Some(expr))?;
+      } else if self.eat("[") {
+        let index = self.expression(0)?;
+        self.expect("]")?;
+        expr = Expr::Indexer(Box::new(expr), Box::new(index));
+      } else {
+        return Ok(expr);
+      }
+    }
+  }
+
+  fn term(&mut self) -> Result<Expr, Error> {
+    let offset = self.offset();
+    match self.peek().cloned() {
+      Some(Token::Symbol("(")) => {
+        self.position += 1;
+        let expr = self.expression(0)?;
+        self.expect(")")?;
+        Ok(expr)
+      }
+      Some(Token::Symbol("{")) => {
+        self.position += 1;
+        self.expect("}")?;
+        Ok(Expr::Literal(Literal::Empty))
+      }
+      Some(Token::String(text)) => {
+        self.position += 1;
+        Ok(Expr::Literal(Literal::String(text)))
+      }
+      Some(Token::Number(number)) => {
+        self.position += 1;
+        self.number(&number, offset)
+      }
+      Some(Token::Temporal(text)) => {
+        self.position += 1;
+        temporal_literal(&text, offset)
+      }
+      Some(Token::Constant(name)) => {
+        self.position += 1;
+        Ok(Expr::Constant(name))
+      }
+      Some(Token::Variable(name)) => {
+        self.position += 1;
+        Ok(match name.as_str() {
+          "$this" => Expr::This,
+          "$index" => Expr::Index,
+          _ => Expr::Total,
+        })
+      }
+      Some(Token::Identifier(name, false)) if name == "true" || name == "false" => {
+        self.position += 1;
+        Ok(Expr::Literal(Literal::Boolean(name == "true")))
+      }
+      Some(Token::Identifier(_, _)) => self.invocation(None),
+      Some(token) => Err(Error::at(
+        &format!("Unexpected {}", describe(&token)),
+        offset,
+      )),
+      None => Err(Error::at("Unexpected end of expression", offset)),
+    }
+  }
+
+  fn number(&mut self, number: &str, offset: usize) -> Result<Expr, Error> {
+    let unit = match self.peek() {
+      Some(Token::String(unit)) => Some(unit.clone()),
+      Some(Token::Identifier(unit, false)) if CALENDAR_UNITS.contains(&unit.as_str()) => {
+        Some(unit.clone())
+      }
+      _ => None,
+    };
+    let invalid = || Error::at(&format!("Invalid number '{}'", number), offset);
+    if let Some(unit) = unit {
+      self.position += 1;
+      let value = Decimal::parse(number).ok_or_else(invalid)?;
+      return Ok(Expr::Literal(Literal::Quantity(Quantity::new(
+        value, &unit,
+      ))));
+    }
+    // Integers are 32 bits, so longer whole numbers aren't valid literals.
+    if number.contains('.') {
+      Ok(Expr::Literal(Literal::Decimal(
+        Decimal::parse(number).ok_or_else(invalid)?,
+      )))
+    } else {
+      Ok(Expr::Literal(Literal::Integer(
+        number.parse::<i32>().map_err(|_| invalid())? as i64,
+      )))
+    }
+  }
+
+  fn invocation(&mut self, target: Option<Expr>) -> Result<Expr, Error> {
+    let offset = self.offset();
+    let name = match self.next()? {
+      Token::Identifier(name, _) => name,
+      token => {
+        return Err(Error::at(
+          &format!("Expected a name but found {}", describe(&token)),
+          offset,
+        ))
+      }
+    };
+    let target = target.map(Box::new);
+    if !self.eat("(") {
+      return Ok(Expr::Member(target, name));
+    }
+    let mut args = vec![];
+    if !self.eat(")") {
+      loop {
+        args.push(self.expression(0)?);
+        if self.eat(")") {
+          break;
+        }
+        self.expect(",")?;
+      }
+    }
+    Ok(Expr::Call(target, name, args))
+  }
+
+  fn qualified_name(&mut self) -> Result<String, Error> {
+    let offset = self.offset();
+    let mut name = match self.next()? {
+      Token::Identifier(name, _) => name,
+      token => {
+        return Err(Error::at(
+          &format!("Expected a type but found {}", describe(&token)),
+          offset,
+        ))
+      }
+    };
+    while self.peek() == Some(&Token::Symbol(".")) {
+      match self.tokens.get(self.position + 1) {
+        Some((Token::Identifier(part, _), _)) => {
+          name = format!("{}.{}", name, part);
+          self.position += 2;
+        }
+        _ => break,
+      }
+    }
+    Ok(name)
+  }
+}
+
+fn temporal_literal(text: &str, offset: usize) -> Result<Expr, Error> {
+  let literal = if let Some(time) = text.strip_prefix('T') {
+    Temporal::parse_time(time).map(Literal::Time)
+  } else if text.contains('T') {
+    Temporal::parse_date_time(text).map(Literal::DateTime)
+  } else {
+    Temporal::parse_date(text).map(Literal::Date)
+  };
+  literal
+    .map(Expr::Literal)
+    .ok_or_else(|| Error::at(&format!("Invalid date/time '@{}'", text), offset))
+}
+
+fn describe(token: &Token) -> String {
+  match token {
+    Token::Identifier(name, _) => format!("'{}'", name),
+    Token::String(text) => format!("string '{}'", text),
+    Token::Number(number) => format!("number {}", number),
+    Token::Temporal(text) => format!("'@{}'", text),
+    Token::Constant(name) => format!("'%{}'", name),
+    Token::Variable(name) => format!("'{}'", name),
+    Token::Symbol(symbol) => format!("'{}'", symbol),
+  }
+}
@@ -0,0 +1,904 @@
+use crate::datetime::{civil_from_days, days_from_civil};
+use crate::ucum;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Neg;
+
+/// One item of a FHIRPath collection: either a System value produced by the
+/// expression itself, or a node of the resource being evaluated.
+#[derive(Debug, Clone)]
+pub enum Item<'a> {
+  Boolean(bool),
+  Integer(i64),
+  Decimal(Decimal),
+  String(String),
+  Date(Temporal),
+  DateTime(Temporal),
+  Time(Temporal),
+  Quantity(Quantity),
+  Node(Node<'a>),
+}
+
+impl<'a> Item<'a> {
+  /// The namespace-qualified type of the item, e.g. `System.Integer` or
+  /// `FHIR.HumanName`. Nodes whose type can't be worked out return `None`.
+  pub fn type_name(&self) -> Option<String> {
+    let name = match self {
+      Item::Boolean(_) => "System.Boolean",
+      Item::Integer(_) => "System.Integer",
+      Item::Decimal(_) => "System.Decimal",
+      Item::String(_) => "System.String",
+      Item::Date(_) => "System.Date",
+      Item::DateTime(_) => "System.DateTime",
+      Item::Time(_) => "System.Time",
+      Item::Quantity(_) => "System.Quantity",
+      Item::Node(node) => return node.type_name.as_ref().map(|t| format!("FHIR.{}", t)),
+    };
+    Some(name.to_string())
+  }
+
+  /// The item as JSON: nodes are returned as they appear in the resource and
+  /// quantities as a FHIR `Quantity`.
+  pub fn to_json(&self) -> Value {
+    match self {
+      Item::Boolean(value) => json!(value),
+      Item::Integer(value) => json!(value),
+      Item::Decimal(value) => value.to_json(),
+      Item::String(value) => json!(value),
+      Item::Date(value) => json!(value.format_date()),
+      Item::DateTime(value) => json!(value.format_date_time()),
+      Item::Time(value) => json!(value.format_time()),
+      Item::Quantity(value) => value.to_json(),
+      Item::Node(node) => node.value().cloned().unwrap_or(Value::Null),
+    }
+  }
+
+  pub fn as_node(&self) -> Option<&Node<'a>> {
+    match self {
+      Item::Node(node) => Some(node),
+      _ => None,
+    }
+  }
+
+  /// Converts a primitive node to the System value it holds, leaving other
+  /// items untouched.
+  pub(crate) fn primitive(&self) -> Item<'a> {
+    match self {
+      Item::Node(node) => node.primitive().unwrap_or_else(|| self.clone()),
+      _ => self.clone(),
+    }
+  }
+}
+
+/// A node of the evaluated resource, along with the `_name` sibling that holds
+/// the `id` and extensions of a primitive element.
+#[derive(Debug, Clone)]
+pub struct Node<'a> {
+  pub(crate) value: Option<Cow<'a, Value>>,
+  pub(crate) element: Option<Cow<'a, Value>>,
+  pub(crate) type_name: Option<String>,
+  pub(crate) path: String,
+}
+
+const QUANTITY_TYPES: &[&str] = &[
+  "Quantity",
+  "Age",
+  "Count",
+  "Distance",
+  "Duration",
+  "MoneyQuantity",
+  "SimpleQuantity",
+];
+
+impl<'a> Node<'a> {
+  /// The JSON value of the node, which is absent for a primitive element that
+  /// only carries extensions.
+  pub fn value(&self) -> Option<&Value> {
+    self.value.as_deref()
+  }
+
+  /// The FHIR type of the node, e.g. `Patient`, `HumanName` or `date`, when it
+  /// is known.
+  pub fn type_name(&self) -> Option<&str> {
+    self.type_name.as_deref()
+  }
+
+  /// Where the node sits in the type model, e.g. `Patient.contact.name` or
+  /// `HumanName.given`.
+  pub fn path(&self) -> &str {
+    &self.path
+  }
+
+  fn primitive(&self) -> Option<Item<'a>> {
+    let value = self.value.as_deref()?;
+    let type_name = self.type_name.as_deref().unwrap_or_default();
+    match value {
+      Value::Bool(value) => Some(Item::Boolean(*value)),
+      Value::Number(number) => match (type_name, number.as_i64()) {
+        ("decimal", _) | (_, None) => Decimal::parse(&number.to_string()).map(Item::Decimal),
+        (_, Some(integer)) => Some(Item::Integer(integer)),
+      },
+      Value::String(text) => Some(match type_name {
+        "date" => Item::Date(Temporal::parse_date(text)?),
+        "dateTime" | "instant" => Item::DateTime(Temporal::parse_date_time(text)?),
+        "time" => Item::Time(Temporal::parse_time(text)?),
+        "integer64" => Item::Integer(text.parse().ok()?),
+        _ => Item::String(text.clone()),
+      }),
+      Value::Object(_) if QUANTITY_TYPES.contains(&type_name) => {
+        Quantity::from_json(value).map(Item::Quantity)
+      }
+      _ => None,
+    }
+  }
+
+  /// Whether the node is a JSON string of unknown type, which may be compared
+  /// against dates and times.
+  pub(crate) fn untyped_string(&self) -> Option<&str> {
+    match self.type_name {
+      None => self.value.as_deref().and_then(Value::as_str),
+      Some(_) => None,
+    }
+  }
+}
+
+/// The most places a decimal keeps after the point. Results that would need
+/// more, as `1 / 3` does, are rounded.
+const MAX_SCALE: u32 = 28;
+
+/// A FHIRPath Decimal: an exact base ten number that keeps the places it was
+/// written with, so `1.50` has two and `0.1 + 0.2` is exactly `0.3`. Numbers
+/// with more than 38 digits don't fit, and arithmetic that would need them
+/// has no result.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Decimal {
+  mantissa: i128,
+  scale: u32,
+}
+
+impl Decimal {
+  /// Parses a number such as `-1.50`, or `1e-7` as JSON may write it.
+  pub fn parse(text: &str) -> Option<Decimal> {
+    let (number, exponent) = match text.find(['e', 'E']) {
+      Some(index) => (&text[..index], text[index + 1..].parse::<i32>().ok()?),
+      None => (text, 0),
+    };
+    let (negative, digits) = match number.as_bytes().first()? {
+      b'-' => (true, &number[1..]),
+      b'+' => (false, &number[1..]),
+      _ => (false, number),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty() || !(whole.bytes().chain(fraction.bytes())).all(|b| b.is_ascii_digit()) {
+      return None;
+    }
+    let mut mantissa: i128 = 0;
+    for digit in whole.bytes().chain(fraction.bytes()) {
+      mantissa = mantissa
+        .checked_mul(10)?
+        .checked_add((digit - b'0') as i128)?;
+    }
+    if negative {
+      mantissa = -mantissa;
+    }
+    let scale = fraction.len() as i64 - exponent as i64;
+    let decimal = match scale {
+      scale if scale < 0 => Decimal {
+        mantissa: mantissa.checked_mul(power_of_ten(u32::try_from(-scale).ok()?)?)?,
+        scale: 0,
+      },
+      scale => Decimal {
+        mantissa,
+        scale: u32::try_from(scale).ok()?,
+      },
+    };
+    decimal.round(MAX_SCALE)
+  }
+
+  /// The nearest decimal to a float, for the results of functions such as
+  /// `sqrt()` that can't be worked out exactly.
+  pub fn from_f64(value: f64) -> Option<Decimal> {
+    match value.is_finite() {
+      true => Decimal::parse(&value.to_string()),
+      false => None,
+    }
+  }
+
+  pub fn to_f64(self) -> f64 {
+    self.to_string().parse().unwrap_or_default()
+  }
+
+  /// The number as JSON, which holds it as a float.
+  pub fn to_json(self) -> Value {
+    serde_json::from_str(&self.to_string()).unwrap_or(Value::Null)
+  }
+
+  /// The number of places after the point.
+  pub fn scale(self) -> u32 {
+    self.scale
+  }
+
+  pub(crate) fn signum(self) -> i128 {
+    self.mantissa.signum()
+  }
+
+  pub(crate) fn abs(self) -> Decimal {
+    Decimal {
+      mantissa: self.mantissa.abs(),
+      ..self
+    }
+  }
+
+  pub(crate) fn add(self, other: Decimal) -> Option<Decimal> {
+    let (a, b, scale) = self.align(other)?;
+    Some(Decimal {
+      mantissa: a.checked_add(b)?,
+      scale,
+    })
+  }
+
+  pub(crate) fn sub(self, other: Decimal) -> Option<Decimal> {
+    self.add(-other)
+  }
+
+  /// The product, rounded to the places a decimal keeps. A product too long
+  /// to work out exactly drops places from the longer operand until it fits.
+  pub(crate) fn mul(self, other: Decimal) -> Option<Decimal> {
+    let (mut a, mut b) = (self, other);
+    loop {
+      if let Some(mantissa) = a.mantissa.checked_mul(b.mantissa) {
+        let product = Decimal {
+          mantissa,
+          scale: a.scale + b.scale,
+        };
+        return product.round(MAX_SCALE);
+      }
+      match a.scale >= b.scale {
+        true if a.scale > 0 => a = a.round(a.scale - 1)?,
+        false => b = b.round(b.scale - 1)?,
+        true => return None,
+      }
+    }
+  }
+
+  /// The quotient, to as many places as a decimal keeps and fit, without
+  /// trailing zeros. Dividing by zero has no result.
+  pub(crate) fn div(self, other: Decimal) -> Option<Decimal> {
+    if other.mantissa == 0 {
+      return None;
+    }
+    let least = self.scale.saturating_sub(other.scale);
+    let mut scale = MAX_SCALE.max(least);
+    loop {
+      // The quotient at `scale` places is self's mantissa shifted by this much
+      // divided by other's.
+      let shift = scale + other.scale - self.scale;
+      let numerator = power_of_ten(shift).and_then(|power| self.mantissa.checked_mul(power));
+      match numerator {
+        Some(numerator) => {
+          let mantissa = round_div(numerator, other.mantissa);
+          return Some(Decimal { mantissa, scale }.trim());
+        }
+        None if scale > least => scale -= 1,
+        None => return None,
+      }
+    }
+  }
+
+  /// What's left after taking out whole multiples of `other`, with the sign of
+  /// `self`.
+  pub(crate) fn rem(self, other: Decimal) -> Option<Decimal> {
+    let (a, b, scale) = self.align(other)?;
+    Some(Decimal {
+      mantissa: a.checked_rem(b)?,
+      scale,
+    })
+  }
+
+  /// How many whole times `other` goes into `self`, truncated toward zero.
+  pub(crate) fn div_truncate(self, other: Decimal) -> Option<i128> {
+    let (a, b, _) = self.align(other)?;
+    a.checked_div(b)
+  }
+
+  /// Rounds half away from zero to at most `places` after the point.
+  pub(crate) fn round(self, places: u32) -> Option<Decimal> {
+    if places >= self.scale {
+      return Some(self);
+    }
+    Some(Decimal {
+      mantissa: round_div(self.mantissa, power_of_ten(self.scale - places)?),
+      scale: places,
+    })
+  }
+
+  pub(crate) fn truncate(self) -> i128 {
+    self.mantissa / ten_to(self.scale)
+  }
+
+  pub(crate) fn floor(self) -> i128 {
+    let (whole, fraction) = self.split();
+    whole - (fraction < 0) as i128
+  }
+
+  pub(crate) fn ceiling(self) -> i128 {
+    let (whole, fraction) = self.split();
+    whole + (fraction > 0) as i128
+  }
+
+  /// Raises the number to a whole power by repeated squaring.
+  pub(crate) fn power(self, mut exponent: u32) -> Option<Decimal> {
+    let (mut base, mut result) = (self, Decimal::from(1));
+    while exponent > 0 {
+      if exponent & 1 == 1 {
+        result = result.mul(base)?;
+      }
+      exponent >>= 1;
+      if exponent > 0 {
+        base = base.mul(base)?;
+      }
+    }
+    Some(result)
+  }
+
+  /// Both mantissas at the larger of the two scales.
+  fn align(self, other: Decimal) -> Option<(i128, i128, u32)> {
+    let scale = self.scale.max(other.scale);
+    let shift = |d: Decimal| d.mantissa.checked_mul(power_of_ten(scale - d.scale)?);
+    Some((shift(self)?, shift(other)?, scale))
+  }
+
+  /// The whole part and the fraction, the latter at the most places a decimal
+  /// keeps, both with the sign of the number.
+  fn split(self) -> (i128, i128) {
+    let unit = ten_to(self.scale);
+    let fraction = self.mantissa % unit * ten_to(MAX_SCALE - self.scale);
+    (self.mantissa / unit, fraction)
+  }
+
+  fn trim(mut self) -> Decimal {
+    while self.scale > 0 && self.mantissa % 10 == 0 {
+      self.mantissa /= 10;
+      self.scale -= 1;
+    }
+    self
+  }
+}
+
+fn power_of_ten(exponent: u32) -> Option<i128> {
+  10i128.checked_pow(exponent)
+}
+
+/// Ten to a power no more than a decimal's scale, which always fits.
+fn ten_to(exponent: u32) -> i128 {
+  10i128.pow(exponent)
+}
+
+/// Divides, rounding half away from zero.
+fn round_div(numerator: i128, denominator: i128) -> i128 {
+  let quotient = numerator / denominator;
+  let remainder = (numerator % denominator).unsigned_abs();
+  match remainder >= denominator.unsigned_abs() - remainder {
+    true if (numerator < 0) != (denominator < 0) => quotient - 1,
+    true => quotient + 1,
+    false => quotient,
+  }
+}
+
+impl From<i64> for Decimal {
+  fn from(value: i64) -> Decimal {
+    Decimal {
+      mantissa: value as i128,
+      scale: 0,
+    }
+  }
+}
+
+impl Neg for Decimal {
+  type Output = Decimal;
+
+  fn neg(self) -> Decimal {
+    Decimal {
+      mantissa: -self.mantissa,
+      ..self
+    }
+  }
+}
+
+// Decimals are equal when their values are, whatever places they were written
+// with.
+impl PartialEq for Decimal {
+  fn eq(&self, other: &Decimal) -> bool {
+    self.cmp(other) == Ordering::Equal
+  }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+  fn partial_cmp(&self, other: &Decimal) -> Option<Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Decimal {
+  fn cmp(&self, other: &Decimal) -> Ordering {
+    self.split().cmp(&other.split())
+  }
+}
+
+impl fmt::Display for Decimal {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let digits = format!(
+      "{:0>width$}",
+      self.mantissa.unsigned_abs(),
+      width = self.scale as usize + 1
+    );
+    let (whole, fraction) = digits.split_at(digits.len() - self.scale as usize);
+    let sign = if self.mantissa < 0 { "-" } else { "" };
+    match fraction.is_empty() {
+      true => write!(f, "{}{}", sign, whole),
+      false => write!(f, "{}{}.{}", sign, whole, fraction),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Precision {
+  Year,
+  Month,
+  Day,
+  Hour,
+  Minute,
+  Second,
+  Millisecond,
+}
+
+/// A FHIRPath Date, DateTime or Time, which may be partial (e.g. `@2012-04`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Temporal {
+  pub year: i64,
+  pub month: u32,
+  pub day: u32,
+  pub hour: u32,
+  pub minute: u32,
+  pub second: u32,
+  pub millisecond: u32,
+  /// Minutes east of UTC, when the value carries a timezone.
+  pub offset: Option<i32>,
+  pub precision: Precision,
+}
+
+impl Temporal {
+  fn empty(precision: Precision) -> Temporal {
+    Temporal {
+      year: 0,
+      month: 1,
+      day: 1,
+      hour: 0,
+      minute: 0,
+      second: 0,
+      millisecond: 0,
+      offset: None,
+      precision,
+    }
+  }
+
+  /// Parses `YYYY`, `YYYY-MM` or `YYYY-MM-DD`.
+  pub fn parse_date(text: &str) -> Option<Temporal> {
+    let bytes = text.as_bytes();
+    let mut position = 0;
+    let mut temporal = Temporal::empty(Precision::Year);
+    temporal.read_date(bytes, &mut position)?;
+    if position == bytes.len() {
+      Some(temporal)
+    } else {
+      None
+    }
+  }
+
+  /// Parses a date optionally followed by `T`, a time and a timezone, e.g.
+  /// `2015-02-07T13:28:17.239+02:00`.
+  pub fn parse_date_time(text: &str) -> Option<Temporal> {
+    let bytes = text.as_bytes();
+    let mut position = 0;
+    let mut temporal = Temporal::empty(Precision::Year);
+    temporal.read_date(bytes, &mut position)?;
+    if bytes.get(position) == Some(&b'T') {
+      position += 1;
+      if position < bytes.len() {
+        temporal.read_time(bytes, &mut position)?;
+        temporal.read_offset(bytes, &mut position)?;
+      }
+    }
+    if position == bytes.len() {
+      Some(temporal)
+    } else {
+      None
+    }
+  }
+
+  /// Parses `hh`, `hh:mm`, `hh:mm:ss` or `hh:mm:ss.fff`.
+  pub fn parse_time(text: &str) -> Option<Temporal> {
+    let bytes = text.as_bytes();
+    let mut position = 0;
+    let mut temporal = Temporal::empty(Precision::Hour);
+    temporal.read_time(bytes, &mut position)?;
+    if position == bytes.len() {
+      Some(temporal)
+    } else {
+      None
+    }
+  }
+
+  fn read_date(&mut self, bytes: &[u8], position: &mut usize) -> Option<()> {
+    self.year = digits(bytes, position, 4)? as i64;
+    self.precision = Precision::Year;
+    if bytes.get(*position) == Some(&b'-') && is_digit(bytes, *position + 1) {
+      *position += 1;
+      self.month = digits(bytes, position, 2)?;
+      self.precision = Precision::Month;
+      if bytes.get(*position) == Some(&b'-') && is_digit(bytes, *position + 1) {
+        *position += 1;
+        self.day = digits(bytes, position, 2)?;
+        self.precision = Precision::Day;
+      }
+    }
+    if self.month < 1
+      || self.month > 12
+      || self.day < 1
+      || self.day > days_in_month(self.year, self.month)
+    {
+      return None;
+    }
+    Some(())
+  }
+
+  fn read_time(&mut self, bytes: &[u8], position: &mut usize) -> Option<()> {
+    self.hour = digits(bytes, position, 2)?;
+    self.precision = Precision::Hour;
+    if bytes.get(*position) == Some(&b':') {
+      *position += 1;
+      self.minute = digits(bytes, position, 2)?;
+      self.precision = Precision::Minute;
+      if bytes.get(*position) == Some(&b':') {
+        *position += 1;
+        self.second = digits(bytes, position, 2)?;
+        self.precision = Precision::Second;
+        if bytes.get(*position) == Some(&b'.') && is_digit(bytes, *position + 1) {
+          *position += 1;
+          let start = *position;
+          while is_digit(bytes, *position) {
+            *position += 1;
+          }
+          let fraction = format!(
+            "{:0<3}",
+            std::str::from_utf8(&bytes[start..*position]).ok()?
+          );
+          self.millisecond = fraction[..3].parse().ok()?;
+          self.precision = Precision::Millisecond;
+        }
+      }
+    }
+    if self.hour > 23 || self.minute > 59 || self.second > 59 {
+      return None;
+    }
+    Some(())
+  }
+
+  fn read_offset(&mut self, bytes: &[u8], position: &mut usize) -> Option<()> {
+    match bytes.get(*position) {
+      Some(b'Z') => {
+        *position += 1;
+        self.offset = Some(0);
+      }
+      Some(sign @ b'+') | Some(sign @ b'-') => {
+        let sign = if *sign == b'-' { -1 } else { 1 };
+        *position += 1;
+        let hours = digits(bytes, position, 2)? as i32;
+        if bytes.get(*position) != Some(&b':') {
+          return None;
+        }
+        *position += 1;
+        let minutes = digits(bytes, position, 2)? as i32;
+        self.offset = Some(sign * (hours * 60 + minutes));
+      }
+      _ => {}
+    }
+    Some(())
+  }
+
+  pub fn format_date(&self) -> String {
+    let mut text = format!("{:04}", self.year);
+    if self.precision >= Precision::Month {
+      text.push_str(&format!("-{:02}", self.month));
+    }
+    if self.precision >= Precision::Day {
+      text.push_str(&format!("-{:02}", self.day));
+    }
+    text
+  }
+
+  pub fn format_date_time(&self) -> String {
+    if self.precision <= Precision::Day {
+      return self.format_date();
+    }
+    let mut text = format!("{}T{}", self.format_date(), self.format_time());
+    match self.offset {
+      Some(0) => text.push('Z'),
+      Some(offset) => text.push_str(&format!(
+        "{}{:02}:{:02}",
+        if offset < 0 { '-' } else { '+' },
+        offset.abs() / 60,
+        offset.abs() % 60
+      )),
+      None => {}
+    }
+    text
+  }
+
+  pub fn format_time(&self) -> String {
+    let mut text = format!("{:02}", self.hour);
+    if self.precision >= Precision::Minute {
+      text.push_str(&format!(":{:02}", self.minute));
+    }
+    if self.precision >= Precision::Second {
+      text.push_str(&format!(":{:02}", self.second));
+    }
+    if self.precision >= Precision::Millisecond {
+      text.push_str(&format!(".{:03}", self.millisecond));
+    }
+    text
+  }
+
+  /// Compares two values to the precision they share. Returns `None` when the
+  /// result depends on the parts only one of them specifies, e.g. `@2012` and
+  /// `@2012-04`.
+  pub(crate) fn compare(&self, other: &Temporal) -> Option<Ordering> {
+    let (a, b) = (self.to_utc(), other.to_utc());
+    // Seconds and milliseconds are compared as one decimal value.
+    let merge = |p: Precision| p.min(Precision::Second);
+    let shared = merge(a.precision).min(merge(b.precision));
+    let fields = |t: &Temporal| {
+      [
+        t.year,
+        t.month as i64,
+        t.day as i64,
+        t.hour as i64,
+        t.minute as i64,
+        t.second as i64 * 1000 + t.millisecond as i64,
+      ]
+    };
+    let (fa, fb) = (fields(&a), fields(&b));
+    for index in 0..=(shared as usize) {
+      match fa[index].cmp(&fb[index]) {
+        Ordering::Equal => {}
+        ordering => return Some(ordering),
+      }
+    }
+    if merge(a.precision) == merge(b.precision) {
+      Some(Ordering::Equal)
+    } else {
+      None
+    }
+  }
+
+  fn to_utc(self) -> Temporal {
+    match self.offset {
+      Some(offset) if offset != 0 && self.precision >= Precision::Hour => {
+        let minutes = days_from_civil(self.year, self.month, self.day) * 1440
+          + self.hour as i64 * 60
+          + self.minute as i64
+          - offset as i64;
+        let (year, month, day) = civil_from_days(minutes.div_euclid(1440));
+        let minute_of_day = minutes.rem_euclid(1440);
+        Temporal {
+          year,
+          month,
+          day,
+          hour: (minute_of_day / 60) as u32,
+          minute: (minute_of_day % 60) as u32,
+          offset: Some(0),
+          ..self
+        }
+      }
+      _ => self,
+    }
+  }
+
+  /// Adds a time-valued quantity, e.g. `1 month` or `30 'min'`. Time values
+  /// wrap around midnight; dates ignore parts smaller than their precision.
+  pub(crate) fn add(&self, amount: f64, unit: &str, time_only: bool) -> Option<Temporal> {
+    let millis_per_unit: f64 = match unit {
+      "year" | "years" | "a" => return Some(self.add_months(amount.trunc() as i64 * 12)),
+      "month" | "months" | "mo" => return Some(self.add_months(amount.trunc() as i64)),
+      "week" | "weeks" | "wk" => 604_800_000.0,
+      "day" | "days" | "d" => 86_400_000.0,
+      "hour" | "hours" | "h" => 3_600_000.0,
+      "minute" | "minutes" | "min" => 60_000.0,
+      "second" | "seconds" | "s" => 1000.0,
+      "millisecond" | "milliseconds" | "ms" => 1.0,
+      _ => return None,
+    };
+    if time_only && millis_per_unit >= 86_400_000.0 {
+      return None;
+    }
+    let mut delta = (amount * millis_per_unit).round() as i64;
+    if !time_only && self.precision <= Precision::Day {
+      delta -= delta % 86_400_000;
+    }
+    let days = if time_only {
+      0
+    } else {
+      days_from_civil(self.year, self.month, self.day)
+    };
+    let millis = days * 86_400_000
+      + self.hour as i64 * 3_600_000
+      + self.minute as i64 * 60_000
+      + self.second as i64 * 1000
+      + self.millisecond as i64
+      + delta;
+    let (days, millis) = if time_only {
+      (0, millis.rem_euclid(86_400_000))
+    } else {
+      (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000))
+    };
+    let mut result = Temporal {
+      hour: (millis / 3_600_000) as u32,
+      minute: (millis % 3_600_000 / 60_000) as u32,
+      second: (millis % 60_000 / 1000) as u32,
+      millisecond: (millis % 1000) as u32,
+      ..*self
+    };
+    if !time_only {
+      let (year, month, day) = civil_from_days(days);
+      result.year = year;
+      result.month = month;
+      result.day = day;
+    }
+    Some(result)
+  }
+
+  fn add_months(&self, months: i64) -> Temporal {
+    let total = self.year * 12 + self.month as i64 - 1 + months;
+    let year = total.div_euclid(12);
+    let month = total.rem_euclid(12) as u32 + 1;
+    Temporal {
+      year,
+      month,
+      day: self.day.min(days_in_month(year, month)),
+      ..*self
+    }
+  }
+}
+
+fn is_digit(bytes: &[u8], position: usize) -> bool {
+  bytes.get(position).is_some_and(u8::is_ascii_digit)
+}
+
+fn digits(bytes: &[u8], position: &mut usize, count: usize) -> Option<u32> {
+  let end = *position + count;
+  if end > bytes.len() || !bytes[*position..end].iter().all(u8::is_ascii_digit) {
+    return None;
+  }
+  let value = std::str::from_utf8(&bytes[*position..end])
+    .ok()?
+    .parse()
+    .ok()?;
+  *position = end;
+  Some(value)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+  match month {
+    2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+    2 => 28,
+    4 | 6 | 9 | 11 => 30,
+    _ => 31,
+  }
+}
+
+/// A FHIRPath Quantity. `unit` is a UCUM code or one of the calendar duration
+/// keywords such as `year` or `days`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantity {
+  pub value: Decimal,
+  pub unit: String,
+}
+
+impl Quantity {
+  pub fn new(value: Decimal, unit: &str) -> Quantity {
+    Quantity {
+      value,
+      unit: unit.to_string(),
+    }
+  }
+
+  /// Reads a FHIR Quantity (or one of its profiles such as Age), preferring
+  /// the UCUM `code` over the display `unit`.
+  pub fn from_json(value: &Value) -> Option<Quantity> {
+    let number = match value.get("value")? {
+      Value::Number(number) => Decimal::parse(&number.to_string())?,
+      _ => return None,
+    };
+    let ucum = value["system"].as_str() == Some("http://unitsofmeasure.org");
+    let unit = match (value["code"].as_str(), value["unit"].as_str()) {
+      (Some(code), _) if ucum || value.get("system").is_none() => code,
+      (_, Some(unit)) => unit,
+      (Some(code), None) => code,
+      (None, None) => "1",
+    };
+    Some(Quantity::new(number, unit))
+  }
+
+  pub fn to_json(&self) -> Value {
+    match calendar_to_ucum(&self.unit) {
+      Some(_) => json!({"value": self.value.to_json(), "unit": self.unit}),
+      None => json!({
+        "value": self.value.to_json(),
+        "unit": self.unit,
+        "system": "http://unitsofmeasure.org",
+        "code": self.unit,
+      }),
+    }
+  }
+
+  /// Compares quantities with commensurable units. Calendar years and months
+  /// only compare with the same keyword unless `equivalent` is set, in which
+  /// case they match the UCUM `a` and `mo`.
+  pub(crate) fn compare(&self, other: &Quantity, equivalent: bool) -> Option<Ordering> {
+    let unit = |q: &Quantity| -> String {
+      match calendar_to_ucum(&q.unit) {
+        Some("a") if !equivalent => "year".to_string(),
+        Some("mo") if !equivalent => "month".to_string(),
+        Some(ucum) => ucum.to_string(),
+        None => q.unit.clone(),
+      }
+    };
+    let (ua, ub) = (unit(self), unit(other));
+    if ua == ub {
+      return Some(self.value.cmp(&other.value));
+    }
+    // Values in different units are converted with UCUM's factors, which
+    // aren't exact.
+    let (a, ca) = ucum::canonicalize(self.value.to_f64(), &ua)?;
+    let (b, cb) = ucum::canonicalize(other.value.to_f64(), &ub)?;
+    if ca != cb {
+      return None;
+    }
+    if (a - b).abs() <= 1e-9 * a.abs().max(b.abs()) {
+      Some(Ordering::Equal)
+    } else {
+      a.partial_cmp(&b)
+    }
+  }
+
+  /// Expresses `other` in this quantity's unit, for addition and subtraction.
+  pub(crate) fn align(&self, other: &Quantity) -> Option<Decimal> {
+    let ucum = |q: &Quantity| calendar_to_ucum(&q.unit).unwrap_or(&q.unit).to_string();
+    let (from, to) = (ucum(other), ucum(self));
+    if from == to {
+      return Some(other.value);
+    }
+    Decimal::from_f64(ucum::convert(other.value.to_f64(), &from, &to)?)
+  }
+}
+
+/// The UCUM unit for a calendar duration keyword.
+pub(crate) fn calendar_to_ucum(unit: &str) -> Option<&'static str> {
+  Some(match unit {
+    "year" | "years" => "a",
+    "month" | "months" => "mo",
+    "week" | "weeks" => "wk",
+    "day" | "days" => "d",
+    "hour" | "hours" => "h",
+    "minute" | "minutes" => "min",
+    "second" | "seconds" => "s",
+    "millisecond" | "milliseconds" => "ms",
+    _ => return None,
+  })
+}
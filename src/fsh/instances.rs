@@ -0,0 +1,298 @@
+use super::parser::EntityKind;
+use super::rules::{FshValue, RuleKind};
+use super::{text, Exporter, Step};
+use serde_json::json;
+use serde_json::value::Value;
+use std::collections::HashMap;
+
+/// Content being filled in by assignment rules: an Instance, or a definition
+/// that caret rules set elements of.
+pub(crate) struct Target {
+  pub json: Value,
+  /// The type or element path whose definition gives the paths their types.
+  pub root: String,
+  /// The profile whose slices the content has.
+  pub profile: Option<String>,
+  /// Where each slice is in its list, by the path of the list and the slice.
+  pub slices: HashMap<String, usize>,
+}
+
+impl Target {
+  pub fn new(json: Value, root: &str) -> Target {
+    Target {
+      json,
+      root: root.to_string(),
+      profile: None,
+      slices: HashMap::new(),
+    }
+  }
+}
+
+impl<'c> Exporter<'c> {
+  /// The JSON of an Instance, compiled the first time it is needed.
+  pub(super) fn export_instance(&mut self, index: usize) -> Result<Value, String> {
+    let entity = &self.entities[index];
+    if let Some(instance) = self.instances.get(&index) {
+      return match instance {
+        Value::Null => Err(format!("The Instance {} has errors", entity.name)),
+        instance => Ok(instance.clone()),
+      };
+    }
+    if !self.exporting.insert(index) {
+      return Err(format!("The Instance {} contains itself", entity.name));
+    }
+    let instance = self.build_instance(index);
+    self.exporting.remove(&index);
+    self
+      .instances
+      .insert(index, instance.clone().unwrap_or(Value::Null));
+    instance.map_err(|_| format!("The Instance {} has errors", entity.name))
+  }
+
+  fn build_instance(&mut self, index: usize) -> Result<Value, ()> {
+    let entity = &self.entities[index];
+    let instance_of = match entity.metadata("InstanceOf").and_then(text) {
+      Some(instance_of) => instance_of,
+      None => {
+        self.error(entity.at, "An Instance needs an InstanceOf");
+        return Err(());
+      }
+    };
+    let structure = match self.structure(instance_of) {
+      Some(structure) => structure,
+      None => {
+        let at = entity.metadata("InstanceOf").map_or(entity.at, |m| m.at);
+        self.error(at, &format!("Can't find the definition of {}", instance_of));
+        return Err(());
+      }
+    };
+    let mut json = json!({});
+    if structure.kind == "resource" {
+      json["resourceType"] = json!(structure.type_name);
+      json["id"] = json!(self.id(index));
+    }
+    if structure.profile {
+      json["meta"] = json!({ "profile": [structure.url] });
+    }
+    let mut target = Target::new(json, &structure.type_name);
+    if structure.profile {
+      target.profile = Some(structure.url.clone());
+    }
+    let usage = entity.metadata("Usage").and_then(text);
+    if usage == Some("definition") {
+      for key in ["Title", "Description"] {
+        let element = format!("{}.{}", structure.type_name, key.to_lowercase());
+        let value = entity.metadata(key).and_then(text);
+        if let (Some(value), Some(_)) = (value, self.definitions.element(&element)) {
+          target.json[key.to_lowercase()] = json!(value);
+        }
+      }
+    }
+    let errors = self.issues.iter().filter(|(_, i)| i.is_error()).count();
+    for rule in &self.rules[index] {
+      let assigned = match &rule.kind {
+        RuleKind::Assignment { value, .. } => self.assign(&mut target, &rule.path, Some(value)),
+        RuleKind::Path => self.assign(&mut target, &rule.path, None),
+        _ => Err("Only assignment rules can be used in an Instance".to_string()),
+      };
+      if let Err(message) = assigned {
+        self.error(rule.at, &message);
+      }
+    }
+    if self.issues.iter().filter(|(_, i)| i.is_error()).count() > errors {
+      return Err(());
+    }
+    clean(&mut target.json);
+    Ok(target.json)
+  }
+
+  /// Whether an Instance is only for use inside others.
+  pub(super) fn is_inline(&self, index: usize) -> bool {
+    self.entities[index].metadata("Usage").and_then(text) == Some("inline")
+  }
+
+  /// The type and id of an Instance, which references to it are made of.
+  pub(super) fn identity(&mut self, index: usize) -> Option<(String, String)> {
+    let entity = &self.entities[index];
+    let structure = self.structure(entity.metadata("InstanceOf").and_then(text)?)?;
+    let id = self.rules[index]
+      .iter()
+      .find_map(|rule| match &rule.kind {
+        RuleKind::Assignment {
+          value: FshValue::String(id) | FshValue::Name(id),
+          ..
+        } if rule.path == "id" => Some(id.as_str()),
+        _ => None,
+      })
+      .unwrap_or_else(|| self.id(index));
+    Some((structure.type_name, id.to_string()))
+  }
+
+  /// Sets the element at a path of FSH to a value, creating the elements
+  /// along it. Without a value the element is only created, which gives soft
+  /// indexes like `[+]` in the path their place.
+  pub(super) fn assign(
+    &mut self,
+    target: &mut Target,
+    path: &str,
+    value: Option<&FshValue>,
+  ) -> Result<(), String> {
+    let steps = self.steps(&target.root, path)?;
+    let mut id = target.root.clone();
+    let mut list_path = String::new();
+    let mut current = &mut target.json;
+    for step in &steps {
+      id = format!("{}.{}", id, step.path);
+      list_path = format!("{}.{}", list_path, step.key);
+      if step.key.ends_with("[x]") {
+        return Err(format!(
+          "Name the type of {} in the path, like {}String",
+          step.key,
+          step.key.trim_end_matches("[x]")
+        ));
+      }
+      if current.is_null() {
+        *current = json!({});
+      }
+      let object = current
+        .as_object_mut()
+        .ok_or_else(|| format!("{} has no elements", id))?;
+      let slot = object.entry(step.key.clone()).or_insert(Value::Null);
+      if !step.repeats {
+        if step.index.as_deref().is_some_and(|i| i != "0" && i != "=") {
+          return Err(format!("{} is not a list", id));
+        }
+        current = slot;
+        continue;
+      }
+      if slot.is_null() {
+        *slot = json!([]);
+      }
+      let list = slot
+        .as_array_mut()
+        .ok_or_else(|| format!("{} is not a list", id))?;
+      let position = match (&step.slice, step.index.as_deref()) {
+        (Some(slice), index) => {
+          let sliced = self.slice(target.profile.as_deref(), &id, step, slice);
+          let key = format!("{}:{}", list_path, slice);
+          let existing = match sliced.get("url") {
+            Some(url) => list.iter().position(|item| item["url"] == *url),
+            None => target.slices.get(&key).copied(),
+          };
+          let position = match (existing, index) {
+            (Some(position), None | Some("=")) => position,
+            _ => {
+              list.push(sliced);
+              list.len() - 1
+            }
+          };
+          target.slices.insert(key, position);
+          id = format!("{}:{}", id, slice);
+          position
+        }
+        (None, Some("+")) => list.len(),
+        (None, Some("=")) => list
+          .len()
+          .checked_sub(1)
+          .ok_or_else(|| format!("{} has no item for [=] to refer to", id))?,
+        (None, Some(index)) => index
+          .parse::<usize>()
+          .map_err(|_| format!("{} is not an index", index))?,
+        (None, None) => 0,
+      };
+      list_path = format!("{}[{}]", list_path, position);
+      while list.len() <= position {
+        list.push(Value::Null);
+      }
+      current = &mut list[position];
+    }
+    match value {
+      Some(value) => {
+        let type_code = steps.last().and_then(|s| s.type_code.as_deref());
+        let value = self.convert(value, type_code)?;
+        match (current.as_object_mut(), value) {
+          // Keep what is already there, like the url of an extension.
+          (Some(object), Value::Object(value)) => object.extend(value),
+          (_, value) => *current = value,
+        }
+      }
+      None if current.is_null() => *current = json!({}),
+      None => {}
+    }
+    Ok(())
+  }
+
+  /// A new item of a slice: an extension with its url, or an item with the
+  /// patterns the profile gives the elements of the slice.
+  fn slice(&self, profile: Option<&str>, id: &str, step: &Step, slice: &str) -> Value {
+    let slice_id = format!("{}:{}", id, slice);
+    let differential = profile
+      .and_then(|url| self.structure_json(url))
+      .and_then(|profile| profile["differential"]["element"].as_array());
+    if step.key == "extension" || step.key == "modifierExtension" {
+      let url = differential
+        .and_then(|elements| elements.iter().find(|e| e["id"] == slice_id.as_str()))
+        .and_then(|element| element["type"][0]["profile"][0].as_str())
+        .map(str::to_string)
+        .or_else(|| {
+          self
+            .entity(slice, &[EntityKind::Extension])
+            .and_then(|index| self.entity_url(index))
+        })
+        .or_else(|| self.aliases.get(slice).map(|url| url.to_string()))
+        // Sub-extensions of complex extensions are named by their slice.
+        .unwrap_or_else(|| slice.to_string());
+      return json!({ "url": url });
+    }
+    let mut item = json!({});
+    let prefix = format!("{}.", slice_id);
+    for element in differential.into_iter().flatten() {
+      let child = match element["id"]
+        .as_str()
+        .and_then(|id| id.strip_prefix(&prefix))
+      {
+        Some(child) if !child.contains(['.', ':']) => child,
+        _ => continue,
+      };
+      let pattern = element
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find(|(key, _)| key.starts_with("pattern") || key.starts_with("fixed"));
+      if let Some((_, pattern)) = pattern {
+        item[child] = pattern.clone();
+      }
+    }
+    item
+  }
+}
+
+/// Removes the nulls and empty objects and lists that gaps in indexes and
+/// paths on their own leave.
+fn clean(value: &mut Value) {
+  match value {
+    Value::Object(object) => {
+      for child in object.values_mut() {
+        clean(child);
+      }
+      let children = std::mem::take(object).into_iter();
+      *object = children.filter(|(_, child)| !is_empty(child)).collect();
+    }
+    Value::Array(list) => {
+      for child in list.iter_mut() {
+        clean(child);
+      }
+      list.retain(|child| !is_empty(child));
+    }
+    _ => {}
+  }
+}
+
+fn is_empty(value: &Value) -> bool {
+  match value {
+    Value::Null => true,
+    Value::Object(object) => object.is_empty(),
+    Value::Array(list) => list.is_empty(),
+    _ => false,
+  }
+}
@@ -0,0 +1,1146 @@
+//! Compiles FHIR Shorthand (https://hl7.org/fhir/uv/shorthand/) into the
+//! resources it describes: Profiles and Extensions become StructureDefinitions
+//! with differentials, ValueSets and CodeSystems their resources, and
+//! Instances JSON content. The definitions that profiles build on and that
+//! give paths their types come from [`Definitions`]. Every problem is reported
+//! with the file, line and column of the FSH it is about.
+
+mod instances;
+mod parser;
+mod profiles;
+mod rules;
+mod vocabulary;
+
+use self::parser::{Entity, EntityKind, Metadata, TokenKind};
+use self::rules::{FshValue, Reader, Rule};
+use crate::definitions::{element_type, type_code, Definitions};
+use crate::model::CodeSystem::CodeSystem;
+use crate::model::ElementDefinition::ElementDefinition;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::StructureDefinition::StructureDefinition;
+use crate::model::ValueSet::ValueSet;
+use crate::outcome::Issue;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+const UCUM: &str = "http://unitsofmeasure.org";
+
+/// Where something is in the FSH sources: the index of the file and the line
+/// and column, counting from 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Location {
+  pub file: usize,
+  pub line: usize,
+  pub column: usize,
+}
+
+/// A problem with FSH, and the file, line and column it is at.
+#[derive(Debug)]
+pub struct Diagnostic {
+  pub file: String,
+  pub line: usize,
+  pub column: usize,
+  pub issue: Issue,
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}:{}:{}: {}: {}",
+      self.file,
+      self.line,
+      self.column,
+      self.issue.severity.to_string(),
+      self.issue.diagnostics
+    )
+  }
+}
+
+/// The resources compiled from FSH.
+#[derive(Debug, Default)]
+pub struct Package {
+  /// The Profiles and Extensions, with differentials but no snapshots.
+  pub structure_definitions: Vec<StructureDefinition<'static>>,
+  pub value_sets: Vec<ValueSet<'static>>,
+  pub code_systems: Vec<CodeSystem<'static>>,
+  /// The Instances, other than those with `Usage: #inline`, which only exist
+  /// to be used in other instances.
+  pub instances: Vec<Value>,
+  pub warnings: Vec<Diagnostic>,
+}
+
+/// Compiles FSH files into a [`Package`].
+///
+/// ```
+/// use fhir_rs::definitions::Definitions;
+/// use fhir_rs::fsh::Compiler;
+/// use std::path::Path;
+///
+/// let mut definitions = Definitions::new();
+/// definitions.load_file(Path::new("examples-json/profiles-types.json")).unwrap();
+/// definitions.load_file(Path::new("examples-json/patient.profile.json")).unwrap();
+/// let package = Compiler::new(&definitions)
+///   .canonical("http://example.org/fhir")
+///   .source(
+///     "patient.fsh",
+///     "Profile: NamedPatient\nParent: Patient\n* name 1..* MS",
+///   )
+///   .compile()
+///   .unwrap();
+/// let profile = &package.structure_definitions[0];
+/// assert_eq!(
+///   profile.url(),
+///   Some("http://example.org/fhir/StructureDefinition/NamedPatient")
+/// );
+/// ```
+pub struct Compiler<'d> {
+  definitions: &'d Definitions,
+  canonical: String,
+  sources: Vec<(String, String)>,
+}
+
+impl<'d> Compiler<'d> {
+  pub fn new(definitions: &'d Definitions) -> Compiler<'d> {
+    Compiler {
+      definitions,
+      canonical: "http://example.org".to_string(),
+      sources: vec![],
+    }
+  }
+
+  /// The url the urls of the compiled definitions start with, such as
+  /// `{canonical}/StructureDefinition/{id}`.
+  pub fn canonical<'b>(&'b mut self, canonical: &str) -> &'b mut Compiler<'d> {
+    self.canonical = canonical.trim_end_matches('/').to_string();
+    self
+  }
+
+  /// Adds a file of FSH, named for the diagnostics.
+  pub fn source<'b>(&'b mut self, name: &str, text: &str) -> &'b mut Compiler<'d> {
+    self.sources.push((name.to_string(), text.to_string()));
+    self
+  }
+
+  /// Compiles the sources, failing with every diagnostic if there are errors.
+  pub fn compile(&self) -> Result<Package, Vec<Diagnostic>> {
+    let mut problems = vec![];
+    let mut entities = vec![];
+    for (file, (_, text)) in self.sources.iter().enumerate() {
+      entities.extend(parser::entities(text, file, &mut problems));
+    }
+    let rule_sets = entities
+      .iter()
+      .filter(|e| e.kind == EntityKind::RuleSet)
+      .map(|e| (e.name.clone(), e))
+      .collect::<HashMap<_, _>>();
+    let mut reader = Reader {
+      rule_sets: &rule_sets,
+      problems: &mut problems,
+    };
+    let rules = entities
+      .iter()
+      .map(|e| match e.kind {
+        EntityKind::Alias | EntityKind::RuleSet => vec![],
+        _ => reader.rules(e),
+      })
+      .collect::<Vec<_>>();
+
+    let mut exporter = Exporter::new(self.definitions, &self.canonical, &entities, &rules);
+    for (at, message) in problems {
+      exporter.issue(
+        at,
+        Issue::error(OperationOutcome_IssueCode::Structure, &message),
+      );
+    }
+    let mut package = exporter.export();
+    let mut errors = vec![];
+    for (at, issue) in exporter.issues {
+      let diagnostic = Diagnostic {
+        file: self.sources[at.file].0.clone(),
+        line: at.line,
+        column: at.column,
+        issue,
+      };
+      match diagnostic.issue.is_error() {
+        true => errors.push(diagnostic),
+        false => package.warnings.push(diagnostic),
+      }
+    }
+    match errors.is_empty() {
+      true => Ok(package),
+      false => Err(errors),
+    }
+  }
+}
+
+/// A definition a name in FSH stands for, either compiled from FSH or one of
+/// the [`Definitions`].
+#[derive(Debug, Clone)]
+pub(crate) struct Structure {
+  pub url: String,
+  pub type_name: String,
+  pub kind: String,
+  /// Whether it constrains its type rather than defining it.
+  pub profile: bool,
+}
+
+/// An element along a path in FSH, such as `component[systolic]` or
+/// `valueQuantity`.
+#[derive(Debug, Clone)]
+pub(crate) struct Step {
+  /// The name of the element in JSON, e.g. `valueQuantity`.
+  pub key: String,
+  /// The element's part of an element id, e.g. `value[x]:valueQuantity`.
+  pub id: String,
+  /// The element's part of an element path, e.g. `value[x]`.
+  pub path: String,
+  /// The slice in brackets, like `systolic` in `component[systolic]`.
+  pub slice: Option<String>,
+  /// The type a choice element is narrowed to by its name.
+  pub choice: Option<String>,
+  /// A number, `+` or `=` in brackets.
+  pub index: Option<String>,
+  pub type_code: Option<String>,
+  pub repeats: bool,
+  /// The element in the base definition, when there is one.
+  pub base: Option<Value>,
+}
+
+/// Exports the entities of a compile, in an order that puts what a definition
+/// depends on first.
+pub(crate) struct Exporter<'c> {
+  definitions: &'c Definitions,
+  canonical: &'c str,
+  entities: &'c [Entity],
+  rules: &'c [Vec<Rule>],
+  aliases: HashMap<&'c str, &'c str>,
+  /// The StructureDefinitions compiled so far by url, which profiles of them
+  /// and their instances need.
+  structures: HashMap<String, Value>,
+  /// The urls of `structures` in the order they were compiled.
+  order: Vec<String>,
+  /// Compiled instances by entity, null for those that failed.
+  instances: HashMap<usize, Value>,
+  /// The entities being compiled, which can't be needed again until they are
+  /// done.
+  exporting: HashSet<usize>,
+  issues: Vec<(Location, Issue)>,
+}
+
+impl<'c> Exporter<'c> {
+  fn new(
+    definitions: &'c Definitions,
+    canonical: &'c str,
+    entities: &'c [Entity],
+    rules: &'c [Vec<Rule>],
+  ) -> Exporter<'c> {
+    let mut exporter = Exporter {
+      definitions,
+      canonical,
+      entities,
+      rules,
+      aliases: HashMap::new(),
+      structures: HashMap::new(),
+      order: vec![],
+      instances: HashMap::new(),
+      exporting: HashSet::new(),
+      issues: vec![],
+    };
+    let mut names = HashMap::new();
+    for (index, entity) in entities.iter().enumerate() {
+      if entity.kind == EntityKind::Alias {
+        if exporter
+          .aliases
+          .insert(&entity.name, &entity.value)
+          .is_some()
+        {
+          exporter.error(
+            entity.at,
+            &format!("The alias {} is defined more than once", entity.name),
+          );
+        }
+        continue;
+      }
+      if let Some(other) = names.insert(entity.name.as_str(), index) {
+        let other = &entities[other];
+        exporter.error(
+          entity.at,
+          &format!(
+            "{} is already defined at line {}",
+            entity.name, other.at.line
+          ),
+        );
+      }
+    }
+    exporter
+  }
+
+  fn export(&mut self) -> Package {
+    let mut package = Package::default();
+    let entities = self.entities;
+    for (index, entity) in entities.iter().enumerate() {
+      if entity.kind == EntityKind::Invariant {
+        self.check_invariant(index);
+      }
+    }
+    for (index, entity) in entities.iter().enumerate() {
+      if matches!(entity.kind, EntityKind::Profile | EntityKind::Extension) {
+        self.export_structure(index);
+      }
+    }
+    for (index, entity) in entities.iter().enumerate() {
+      if entity.kind == EntityKind::Mapping {
+        self.export_mapping(index);
+      }
+    }
+    for (index, entity) in entities.iter().enumerate() {
+      match entity.kind {
+        EntityKind::ValueSet => {
+          if let Some(value_set) = self.export_value_set(index) {
+            package.value_sets.push(value_set);
+          }
+        }
+        EntityKind::CodeSystem => {
+          if let Some(code_system) = self.export_code_system(index) {
+            package.code_systems.push(code_system);
+          }
+        }
+        _ => {}
+      }
+    }
+    for (index, entity) in entities.iter().enumerate() {
+      if entity.kind == EntityKind::Instance {
+        if let Ok(instance) = self.export_instance(index) {
+          if !self.is_inline(index) {
+            package.instances.push(instance);
+          }
+        }
+      }
+    }
+    for url in &self.order {
+      if let Some(structure) = self.structures.get(url) {
+        package.structure_definitions.push(StructureDefinition {
+          value: Cow::Owned(structure.clone()),
+        });
+      }
+    }
+    package
+  }
+
+  fn issue(&mut self, at: Location, issue: Issue) {
+    self.issues.push((at, issue));
+  }
+
+  fn error(&mut self, at: Location, message: &str) {
+    self.issue(
+      at,
+      Issue::error(OperationOutcome_IssueCode::Invalid, message),
+    );
+  }
+
+  fn warning(&mut self, at: Location, message: &str) {
+    self.issue(
+      at,
+      Issue::warning(OperationOutcome_IssueCode::Invalid, message),
+    );
+  }
+
+  /// The id of an entity, which is its name unless it has an `Id:`.
+  fn id(&self, index: usize) -> &'c str {
+    let entity = &self.entities[index];
+    entity.metadata("Id").and_then(text).unwrap_or(&entity.name)
+  }
+
+  /// The canonical url of a definition in FSH.
+  fn entity_url(&self, index: usize) -> Option<String> {
+    let resource_type = match self.entities[index].kind {
+      EntityKind::Profile | EntityKind::Extension => "StructureDefinition",
+      EntityKind::ValueSet => "ValueSet",
+      EntityKind::CodeSystem => "CodeSystem",
+      _ => return None,
+    };
+    Some(format!(
+      "{}/{}/{}",
+      self.canonical,
+      resource_type,
+      self.id(index)
+    ))
+  }
+
+  /// The entity of one of `kinds` with a name, or failing that an id.
+  fn entity(&self, name: &str, kinds: &[EntityKind]) -> Option<usize> {
+    let of_kind = |index: &usize| kinds.contains(&self.entities[*index].kind);
+    let indexes = 0..self.entities.len();
+    indexes
+      .clone()
+      .filter(of_kind)
+      .find(|index| self.entities[*index].name == name)
+      .or_else(|| {
+        indexes
+          .filter(of_kind)
+          .find(|index| self.id(*index) == name)
+      })
+  }
+
+  /// What a name of a definition in FSH stands for: the url of an entity or
+  /// the value of an alias. Anything else is taken to be a url already.
+  fn url(&self, name: &str) -> String {
+    let (name, version) = match name.split_once('|') {
+      Some((name, version)) => (name, Some(version)),
+      None => (name, None),
+    };
+    let kinds = [
+      EntityKind::Profile,
+      EntityKind::Extension,
+      EntityKind::ValueSet,
+      EntityKind::CodeSystem,
+    ];
+    let url = match self.entity(name, &kinds) {
+      Some(index) => self.entity_url(index).unwrap_or_default(),
+      None => self.aliases.get(name).unwrap_or(&name).to_string(),
+    };
+    match version {
+      Some(version) => format!("{}|{}", url, version),
+      None => url,
+    }
+  }
+
+  /// A StructureDefinition by url, compiled from FSH or one of the
+  /// definitions.
+  fn structure_json(&self, url: &str) -> Option<&Value> {
+    let url = url.split('|').next().unwrap_or(url);
+    self
+      .structures
+      .get(url)
+      .or_else(|| self.definitions.structure(url))
+  }
+
+  /// The definition a name stands for: a Profile or Extension in FSH, a type,
+  /// or the url of a definition, perhaps by an alias.
+  fn structure(&mut self, name: &str) -> Option<Structure> {
+    let kinds = [EntityKind::Profile, EntityKind::Extension];
+    let url = match self.entity(name, &kinds) {
+      Some(index) => self.export_structure(index)?,
+      None => match self.definitions.type_url(name) {
+        Some(url) => url.to_string(),
+        None => self.url(name),
+      },
+    };
+    let value = self.structure_json(&url)?;
+    Some(Structure {
+      url,
+      type_name: value["type"].as_str()?.to_string(),
+      kind: value["kind"].as_str().unwrap_or("resource").to_string(),
+      profile: value["derivation"] == "constraint",
+    })
+  }
+
+  /// Resolves a path in FSH to the elements along it, starting at a type or
+  /// an element path of a base definition such as `CodeSystem.concept`.
+  /// Elements of types without a definition can't be checked and are taken
+  /// as written.
+  fn steps(&self, root: &str, path: &str) -> Result<Vec<Step>, String> {
+    let mut lookup = root.to_string();
+    let mut known = self.definitions.element(root).is_some();
+    let mut steps = vec![];
+    for segment in split_path(path) {
+      let (name, brackets) = split_segment(segment)?;
+      let mut slice: Option<String> = None;
+      let mut index = None;
+      for bracket in brackets {
+        if is_index(bracket) {
+          index = Some(bracket.to_string());
+        } else {
+          slice = Some(match slice {
+            Some(outer) => format!("{}/{}", outer, bracket),
+            None => bracket.to_string(),
+          });
+        }
+      }
+      let element = self
+        .definitions
+        .element(&format!("{}.{}", lookup, name))
+        .map(|e| e.value.into_owned());
+      let (key, path, choice, element) = match element {
+        Some(element) => (name.to_string(), name.to_string(), None, Some(element)),
+        None => match self.choice(&lookup, name) {
+          Some((prefix, choice, element)) => (
+            name.to_string(),
+            format!("{}[x]", prefix),
+            Some(choice),
+            Some(element),
+          ),
+          None if !known => (name.to_string(), name.to_string(), None, None),
+          None => return Err(format!("{} has no element '{}'", lookup, name)),
+        },
+      };
+      let type_code = choice.clone().or_else(|| {
+        element
+          .as_ref()
+          .and_then(|e| element_type(&ElementDefinition::new(e)))
+      });
+      let repeats = match &element {
+        Some(element) => element["max"]
+          .as_str()
+          .is_some_and(|max| max != "1" && max != "0"),
+        None => index.is_some() || slice.is_some(),
+      };
+      let mut id = match &choice {
+        Some(_) => format!("{}:{}", path, key),
+        None => path.clone(),
+      };
+      if let Some(slice) = &slice {
+        id = format!("{}:{}", id, slice);
+      }
+      // Complex types have their elements in their own definitions, while
+      // backbone elements have them in the definition they are part of.
+      let next = format!("{}.{}", lookup, path);
+      match type_code.as_deref() {
+        Some(code) if code.starts_with(char::is_uppercase) && !is_backbone(code) => {
+          known = self.definitions.element(code).is_some() && code != "Resource";
+          lookup = code.to_string();
+        }
+        _ => {
+          known = known && element.is_some();
+          lookup = next;
+        }
+      }
+      steps.push(Step {
+        key,
+        id,
+        path,
+        slice,
+        choice,
+        index,
+        type_code,
+        repeats,
+        base: element,
+      });
+    }
+    Ok(steps)
+  }
+
+  /// The choice element a name like `valueQuantity` narrows to a type, as
+  /// the prefix, type and element.
+  fn choice(&self, lookup: &str, name: &str) -> Option<(String, String, Value)> {
+    let split = name.char_indices().filter(|(_, c)| c.is_uppercase());
+    for (at, _) in split {
+      let (prefix, suffix) = name.split_at(at);
+      let element = match self
+        .definitions
+        .element(&format!("{}.{}[x]", lookup, prefix))
+      {
+        Some(element) => element.value.into_owned(),
+        None => continue,
+      };
+      let types = element["type"].as_array().cloned().unwrap_or_default();
+      let choice = types
+        .iter()
+        .filter_map(type_code)
+        .find(|code| capitalize(code) == suffix);
+      if let Some(choice) = choice {
+        return Some((prefix.to_string(), choice.to_string(), element));
+      }
+    }
+    None
+  }
+
+  /// Gives a value the shape of an element of a type, or of its own if the
+  /// type isn't known.
+  fn convert(&mut self, value: &FshValue, type_code: Option<&str>) -> Result<Value, String> {
+    let type_code = match type_code {
+      Some(type_code) => type_code,
+      None => return self.convert_untyped(value),
+    };
+    let primitive = type_code.starts_with(char::is_lowercase);
+    let converted = match value {
+      FshValue::String(text) | FshValue::Name(text) if primitive && is_text(type_code) => {
+        json!(text)
+      }
+      FshValue::Code {
+        system: None, code, ..
+      } if primitive && is_text(type_code) => json!(code),
+      FshValue::Boolean(value) if type_code == "boolean" => json!(value),
+      FshValue::Number(number) if type_code == "decimal" => number_value(number)?,
+      FshValue::Number(number) if is_integer(type_code) => {
+        json!(number
+          .parse::<i64>()
+          .map_err(|_| format!("{} is not an integer", number))?)
+      }
+      FshValue::Canonical(target) if matches!(type_code, "canonical" | "uri" | "url") => {
+        json!(self.url(target))
+      }
+      FshValue::Code { .. } if type_code == "Coding" => self.coding(value),
+      FshValue::Code { .. } if type_code == "CodeableConcept" => {
+        json!({ "coding": [self.coding(value)] })
+      }
+      FshValue::Code {
+        system,
+        code,
+        display,
+      } if is_quantity(type_code) => {
+        let mut quantity = json!({ "code": code });
+        if let Some(system) = system {
+          quantity["system"] = json!(self.url(system));
+        }
+        if let Some(display) = display {
+          quantity["unit"] = json!(display);
+        }
+        quantity
+      }
+      FshValue::Quantity { .. } if is_quantity(type_code) => self.convert_untyped(value)?,
+      FshValue::Reference { .. } if type_code == "Reference" => self.convert_untyped(value)?,
+      FshValue::Name(name) if !primitive => self.inline(name)?,
+      _ => {
+        return Err(format!(
+          "{} can't be assigned to an element of type {}",
+          describe(value),
+          type_code
+        ))
+      }
+    };
+    Ok(converted)
+  }
+
+  /// Gives a value the shape it has on its own.
+  fn convert_untyped(&mut self, value: &FshValue) -> Result<Value, String> {
+    Ok(match value {
+      FshValue::String(text) => json!(text),
+      FshValue::Number(number) => number_value(number)?,
+      FshValue::Boolean(value) => json!(value),
+      FshValue::Code {
+        system: None, code, ..
+      } => json!(code),
+      FshValue::Code { .. } => self.coding(value),
+      FshValue::Quantity {
+        value,
+        unit,
+        display,
+      } => {
+        let mut quantity = json!({
+          "value": number_value(value)?,
+          "system": UCUM,
+          "code": unit,
+        });
+        if let Some(display) = display {
+          quantity["unit"] = json!(display);
+        }
+        quantity
+      }
+      FshValue::Reference { target, display } => {
+        let mut reference = json!({ "reference": self.reference(target) });
+        if let Some(display) = display {
+          reference["display"] = json!(display);
+        }
+        reference
+      }
+      FshValue::Canonical(target) => json!(self.url(target)),
+      FshValue::Name(name) => match self.entity(name, &[EntityKind::Instance]) {
+        Some(_) => self.inline(name)?,
+        None => json!(name),
+      },
+    })
+  }
+
+  fn coding(&self, value: &FshValue) -> Value {
+    let mut coding = json!({});
+    if let FshValue::Code {
+      system,
+      code,
+      display,
+    } = value
+    {
+      if let Some(system) = system {
+        match self.url(system).split_once('|') {
+          Some((system, version)) => {
+            coding["system"] = json!(system);
+            coding["version"] = json!(version);
+          }
+          None => coding["system"] = json!(self.url(system)),
+        }
+      }
+      coding["code"] = json!(code);
+      if let Some(display) = display {
+        coding["display"] = json!(display);
+      }
+    }
+    coding
+  }
+
+  /// The reference to an Instance, as `Type/id`, or the target as written.
+  fn reference(&mut self, target: &str) -> String {
+    match self.entity(target, &[EntityKind::Instance]) {
+      Some(index) => match self.identity(index) {
+        Some((type_name, id)) => format!("{}/{}", type_name, id),
+        None => target.to_string(),
+      },
+      None => self.aliases.get(target).unwrap_or(&target).to_string(),
+    }
+  }
+
+  /// The content of an Instance for use inside another.
+  fn inline(&mut self, name: &str) -> Result<Value, String> {
+    match self.entity(name, &[EntityKind::Instance]) {
+      Some(index) => self.export_instance(index),
+      None => Err(format!("There is no Instance named {}", name)),
+    }
+  }
+}
+
+/// The text of a keyword like `Title:`.
+fn text(metadata: &Metadata) -> Option<&str> {
+  match &metadata.tokens.first()?.kind {
+    TokenKind::Word(text) | TokenKind::String(text) | TokenKind::Code(_, text) => Some(text),
+    _ => None,
+  }
+}
+
+/// Splits a path at the dots outside brackets.
+fn split_path(path: &str) -> Vec<&str> {
+  let mut segments = vec![];
+  let mut depth = 0;
+  let mut start = 0;
+  for (at, c) in path.char_indices() {
+    match c {
+      '[' => depth += 1,
+      ']' => depth -= 1,
+      '.' if depth == 0 => {
+        segments.push(&path[start..at]);
+        start = at + 1;
+      }
+      _ => {}
+    }
+  }
+  if !path.is_empty() {
+    segments.push(&path[start..]);
+  }
+  segments
+}
+
+/// Splits `component[systolic][0]` into the name and what is in brackets,
+/// keeping the `[x]` of choice elements in the name.
+fn split_segment(segment: &str) -> Result<(&str, Vec<&str>), String> {
+  let (name, mut rest) = match segment.find('[') {
+    Some(at) if segment[at..].starts_with("[x]") => segment.split_at(at + 3),
+    Some(at) => segment.split_at(at),
+    None => (segment, ""),
+  };
+  if name.is_empty() {
+    return Err(format!("'{}' has no element name", segment));
+  }
+  let mut brackets = vec![];
+  while !rest.is_empty() {
+    let end = rest
+      .strip_prefix('[')
+      .and_then(|r| r.find(']'))
+      .ok_or_else(|| format!("The brackets in '{}' don't match", segment))?;
+    brackets.push(&rest[1..end + 1]);
+    rest = &rest[end + 2..];
+  }
+  Ok((name, brackets))
+}
+
+fn is_index(bracket: &str) -> bool {
+  bracket == "+" || bracket == "=" || bracket.chars().all(|c| c.is_ascii_digit())
+}
+
+fn is_backbone(type_code: &str) -> bool {
+  matches!(type_code, "BackboneElement" | "Element")
+}
+
+/// Whether a primitive type holds text, which strings and bare words are
+/// assigned to.
+fn is_text(type_code: &str) -> bool {
+  !matches!(type_code, "boolean" | "decimal") && !is_integer(type_code)
+}
+
+fn is_integer(type_code: &str) -> bool {
+  matches!(
+    type_code,
+    "integer" | "positiveInt" | "unsignedInt" | "integer64"
+  )
+}
+
+fn is_quantity(type_code: &str) -> bool {
+  matches!(
+    type_code,
+    "Quantity" | "Age" | "Count" | "Distance" | "Duration" | "MoneyQuantity" | "SimpleQuantity"
+  )
+}
+
+fn number_value(number: &str) -> Result<Value, String> {
+  serde_json::from_str::<Value>(number).map_err(|_| format!("{} is not a number", number))
+}
+
+fn capitalize(text: &str) -> String {
+  let mut chars = text.chars();
+  match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  }
+}
+
+fn describe(value: &FshValue) -> String {
+  match value {
+    FshValue::String(text) => format!("\"{}\"", text),
+    FshValue::Number(number) => number.clone(),
+    FshValue::Boolean(value) => value.to_string(),
+    FshValue::Code { system, code, .. } => {
+      format!("{}#{}", system.as_deref().unwrap_or_default(), code)
+    }
+    FshValue::Quantity { value, unit, .. } => format!("{} '{}'", value, unit),
+    FshValue::Reference { target, .. } => format!("Reference({})", target),
+    FshValue::Canonical(target) => format!("Canonical({})", target),
+    FshValue::Name(name) => name.clone(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::snapshot::generate_snapshot;
+  use std::path::Path;
+
+  fn definitions() -> Definitions {
+    let mut definitions = Definitions::new();
+    for name in &[
+      "profiles-types",
+      "patient.profile",
+      "observation.profile",
+      "structuredefinition.profile",
+      "valueset.profile",
+      "codesystem.profile",
+    ] {
+      let path = format!("examples-json/{}.json", name);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    definitions
+  }
+
+  const BLOOD_PRESSURE: &str = r#"
+Alias: $LNC = http://loinc.org
+Alias: $UCUM = http://unitsofmeasure.org
+
+RuleSet: Published(status)
+* ^status = #{status}
+* ^publisher = "Example Publisher"
+
+Invariant: bp-1
+Description: "A blood pressure has components"
+Expression: "component.exists()"
+Severity: #error
+
+Extension: BirthPlace
+Id: birth-place
+Title: "Birth place"
+Description: "Where the patient was born"
+Context: Patient
+* value[x] only string
+
+Extension: Ethnicity
+* extension contains detail 0..1 and text 1..1
+* extension[text].value[x] only string
+
+Profile: BloodPressure
+Parent: Observation
+Id: blood-pressure
+Title: "Blood pressure"
+* insert Published(active)
+* obeys bp-1
+* status MS
+* code = $LNC#85354-9
+* category 1..1
+* subject only Reference(Patient)
+* effective[x] only dateTime
+* component ^slicing.discriminator[0].type = #pattern
+* component ^slicing.discriminator[0].path = "code"
+* component ^slicing.rules = #open
+* component contains systolic 1..1 MS and diastolic 1..1 MS
+* component[systolic]
+  * code = $LNC#8480-6
+  * valueQuantity = $UCUM#mm[Hg]
+* component[diastolic].code = $LNC#8462-4
+* interpretation from ObservationInterpretations (extensible)
+
+Profile: BornPatient
+Parent: Patient
+* extension contains BirthPlace named birthPlace 0..1
+* name 1..* MS
+* gender 1..1
+
+ValueSet: ObservationInterpretations
+Title: "Interpretations"
+* include codes from system Interpretation where concept is-a #A
+* exclude Interpretation#H
+* $LNC#LA6576-8 "Excellent"
+
+CodeSystem: Interpretation
+* ^caseSensitive = false
+* #N "Normal" "Within range"
+* #A "Abnormal"
+  * #H "High"
+  * #L "Low"
+* #A #H ^definition = "Above range"
+
+Instance: Alice
+InstanceOf: BornPatient
+Usage: #example
+* extension[birthPlace].valueString = "Oslo"
+* name[0].family = "Smith"
+* name[=].given[+] = "Alice"
+* name[=].given[+] = "B."
+* gender = #female
+* birthDate = 1970-01-01
+
+Instance: AlicePressure
+InstanceOf: BloodPressure
+* status = #final
+* code = $LNC#85354-9
+* category = http://terminology.hl7.org/CodeSystem/observation-category#vital-signs
+* subject = Reference(Alice)
+* effectiveDateTime = "2020-01-01"
+* component[systolic].valueQuantity = 120 'mm[Hg]'
+* component[diastolic].valueQuantity = 80 'mm[Hg]' "mmHg"
+
+Mapping: BloodPressureToV2
+Source: BloodPressure
+Target: "http://hl7.org/v2"
+* -> "OBX"
+* status -> "OBX-11" "Result status"
+"#;
+
+  fn element<'v>(structure: &'v StructureDefinition, id: &str) -> &'v Value {
+    let elements = structure.value["differential"]["element"]
+      .as_array()
+      .unwrap();
+    elements.iter().find(|e| e["id"] == id).unwrap()
+  }
+
+  #[test]
+  fn test_compile() {
+    let definitions = definitions();
+    let package = Compiler::new(&definitions)
+      .canonical("http://example.org/fhir/")
+      .source("bp.fsh", BLOOD_PRESSURE)
+      .compile()
+      .unwrap_or_else(|errors| panic!("{}", errors[0]));
+    assert!(package.warnings.is_empty());
+
+    let urls = package
+      .structure_definitions
+      .iter()
+      .map(|s| s.url().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      urls,
+      vec![
+        "http://example.org/fhir/StructureDefinition/birth-place",
+        "http://example.org/fhir/StructureDefinition/Ethnicity",
+        "http://example.org/fhir/StructureDefinition/blood-pressure",
+        "http://example.org/fhir/StructureDefinition/BornPatient",
+      ]
+    );
+    let birth_place = &package.structure_definitions[0];
+    assert_eq!(
+      birth_place.value["context"],
+      json!([{"type": "element", "expression": "Patient"}])
+    );
+    assert_eq!(
+      element(birth_place, "Extension.url")["fixedUri"],
+      "http://example.org/fhir/StructureDefinition/birth-place"
+    );
+    assert_eq!(
+      element(birth_place, "Extension.value[x]")["type"],
+      json!([{"code": "string"}])
+    );
+    let ethnicity = &package.structure_definitions[1];
+    assert_eq!(
+      element(ethnicity, "Extension.extension:text.url")["fixedUri"],
+      "text"
+    );
+    assert_eq!(element(ethnicity, "Extension.extension:text")["min"], 1);
+    assert_eq!(element(ethnicity, "Extension.value[x]")["max"], "0");
+
+    let pressure = &package.structure_definitions[2];
+    assert_eq!(pressure.value["status"], "active");
+    assert_eq!(pressure.value["publisher"], "Example Publisher");
+    assert_eq!(
+      pressure.value["baseDefinition"],
+      "http://hl7.org/fhir/StructureDefinition/Observation"
+    );
+    assert_eq!(
+      element(pressure, "Observation")["constraint"][0]["key"],
+      "bp-1"
+    );
+    assert_eq!(
+      element(pressure, "Observation.code")["patternCodeableConcept"],
+      json!({"coding": [{"system": "http://loinc.org", "code": "85354-9"}]})
+    );
+    assert_eq!(
+      element(pressure, "Observation.subject")["type"],
+      json!([{"code": "Reference", "targetProfile": ["http://hl7.org/fhir/StructureDefinition/Patient"]}])
+    );
+    assert_eq!(
+      element(pressure, "Observation.component")["slicing"],
+      json!({"discriminator": [{"type": "pattern", "path": "code"}], "rules": "open"})
+    );
+    let systolic = element(pressure, "Observation.component:systolic");
+    assert_eq!(
+      (
+        &systolic["sliceName"],
+        &systolic["min"],
+        &systolic["mustSupport"]
+      ),
+      (&json!("systolic"), &json!(1), &json!(true))
+    );
+    assert_eq!(
+      element(
+        pressure,
+        "Observation.component:systolic.value[x]:valueQuantity"
+      )["patternQuantity"],
+      json!({"system": "http://unitsofmeasure.org", "code": "mm[Hg]"})
+    );
+    assert_eq!(
+      element(pressure, "Observation.interpretation")["binding"],
+      json!({"strength": "extensible", "valueSet": "http://example.org/fhir/ValueSet/ObservationInterpretations"})
+    );
+    assert_eq!(
+      pressure.value["mapping"],
+      json!([{"identity": "BloodPressureToV2", "uri": "http://hl7.org/v2"}])
+    );
+    assert_eq!(
+      element(pressure, "Observation.status")["mapping"],
+      json!([{"identity": "BloodPressureToV2", "map": "OBX-11", "comment": "Result status"}])
+    );
+
+    // The differentials are complete enough to generate snapshots from.
+    let mut definitions = definitions;
+    for structure in &package.structure_definitions {
+      definitions.add(structure.to_json());
+    }
+    for structure in &package.structure_definitions {
+      let snapshot = generate_snapshot(&definitions, structure)
+        .unwrap_or_else(|issues| panic!("{}", issues[0].diagnostics));
+      assert!(
+        snapshot.value["snapshot"]["element"]
+          .as_array()
+          .unwrap()
+          .len()
+          > 1
+      );
+    }
+
+    let value_set = &package.value_sets[0];
+    assert_eq!(
+      value_set.value["compose"],
+      json!({
+        "include": [
+          {
+            "system": "http://example.org/fhir/CodeSystem/Interpretation",
+            "filter": [{"property": "concept", "op": "is-a", "value": "A"}],
+          },
+          {
+            "system": "http://loinc.org",
+            "concept": [{"code": "LA6576-8", "display": "Excellent"}],
+          },
+        ],
+        "exclude": [
+          {
+            "system": "http://example.org/fhir/CodeSystem/Interpretation",
+            "concept": [{"code": "H"}],
+          },
+        ],
+      })
+    );
+    let code_system = &package.code_systems[0];
+    assert_eq!(code_system.value["caseSensitive"], false);
+    assert_eq!(code_system.value["count"], 4);
+    assert_eq!(
+      code_system.value["concept"][1],
+      json!({
+        "code": "A",
+        "display": "Abnormal",
+        "concept": [
+          {"code": "H", "display": "High", "definition": "Above range"},
+          {"code": "L", "display": "Low"},
+        ],
+      })
+    );
+
+    assert_eq!(
+      package.instances[0],
+      json!({
+        "resourceType": "Patient",
+        "id": "Alice",
+        "meta": {"profile": ["http://example.org/fhir/StructureDefinition/BornPatient"]},
+        "extension": [{
+          "url": "http://example.org/fhir/StructureDefinition/birth-place",
+          "valueString": "Oslo",
+        }],
+        "name": [{"family": "Smith", "given": ["Alice", "B."]}],
+        "gender": "female",
+        "birthDate": "1970-01-01",
+      })
+    );
+    let pressure = &package.instances[1];
+    assert_eq!(pressure["subject"], json!({"reference": "Patient/Alice"}));
+    assert_eq!(
+      pressure["component"],
+      json!([
+        {
+          "code": {"coding": [{"system": "http://loinc.org", "code": "8480-6"}]},
+          "valueQuantity": {"value": 120, "system": "http://unitsofmeasure.org", "code": "mm[Hg]"},
+        },
+        {
+          "code": {"coding": [{"system": "http://loinc.org", "code": "8462-4"}]},
+          "valueQuantity": {"value": 80, "system": "http://unitsofmeasure.org", "code": "mm[Hg]", "unit": "mmHg"},
+        },
+      ])
+    );
+  }
+
+  #[test]
+  fn test_diagnostics() {
+    let definitions = definitions();
+    let errors = Compiler::new(&definitions)
+      .source(
+        "bad.fsh",
+        "Profile: Bad\nParent: Patient\n* gender 2..1\n* foo 1..1\n  * birthDate = 12\n* birthDate = true\n\
+         * name only Reference(Nothing)\n\nInvariant: inv-1\nDescription: \"Named\"\nSeverity: #error\n\
+         Expression: \"name.exists() and\"\n\nInstance: Loose\nInstanceOf: Bad\n* name[=].family = \"Doe\"\n\
+         * active = \"yes\" extra\n",
+      )
+      .compile()
+      .unwrap_err();
+    let messages = errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+      messages,
+      vec![
+        "bad.fsh:17:18: error: Unexpected 'extra' at the end of the rule",
+        "bad.fsh:12:31: error: The expression of inv-1 is not valid: Unexpected end of expression",
+        "bad.fsh:3:1: error: The minimum 2 of Patient.gender is above its maximum 1",
+        "bad.fsh:4:1: error: Patient has no element 'foo'",
+        "bad.fsh:5:3: error: Patient has no element 'foo'",
+        "bad.fsh:6:1: error: true can't be assigned to an element of type date",
+        "bad.fsh:7:1: error: Can't find the definition of Nothing",
+        "bad.fsh:16:1: error: Patient.name has no item for [=] to refer to",
+      ]
+    );
+
+    let package = Compiler::new(&definitions)
+      .source(
+        "warn.fsh",
+        "Profile: Named\nParent: Patient\n* name contains official 1..1",
+      )
+      .compile()
+      .unwrap();
+    assert_eq!(
+      package.warnings[0].to_string(),
+      "warn.fsh:3:17: warning: Patient.name is sliced without saying how; add a ^slicing rule for it"
+    );
+  }
+}
@@ -0,0 +1,613 @@
+use super::Location;
+
+/// The keywords that start an entity, like `Profile:`.
+const ENTITY_KEYWORDS: &[&str] = &[
+  "Alias",
+  "Profile",
+  "Extension",
+  "Instance",
+  "ValueSet",
+  "CodeSystem",
+  "Invariant",
+  "RuleSet",
+  "Mapping",
+  "Logical",
+  "Resource",
+];
+
+const METADATA_KEYWORDS: &[&str] = &[
+  "Parent",
+  "Id",
+  "Title",
+  "Description",
+  "InstanceOf",
+  "Usage",
+  "Expression",
+  "XPath",
+  "Severity",
+  "Source",
+  "Target",
+  "Context",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum TokenKind {
+  /// A run of characters up to whitespace, a quote, a bracket or a comma.
+  Word(String),
+  /// A string in double quotes, or a multi-line string in triple quotes.
+  String(String),
+  /// A UCUM unit in single quotes.
+  Unit(String),
+  /// `system#code` or `#code`, where the system may be an alias or a name.
+  Code(Option<String>, String),
+  Open,
+  Close,
+  Comma,
+  /// The arguments of a parameterized RuleSet, in their `(...)` after its
+  /// name, as written.
+  Arguments(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Token {
+  pub kind: TokenKind,
+  pub at: Location,
+  /// Whether the token is the first on its line.
+  pub first: bool,
+  /// The character offsets of the token in the text.
+  pub start: usize,
+  pub end: usize,
+}
+
+impl Token {
+  pub fn word(&self) -> Option<&str> {
+    match &self.kind {
+      TokenKind::Word(word) => Some(word),
+      _ => None,
+    }
+  }
+
+  pub fn describe(&self) -> String {
+    match &self.kind {
+      TokenKind::Word(word) => format!("'{}'", word),
+      TokenKind::String(text) => format!("the string \"{}\"", text),
+      TokenKind::Unit(unit) => format!("the unit '{}'", unit),
+      TokenKind::Code(Some(system), code) => format!("the code {}#{}", system, code),
+      TokenKind::Code(None, code) => format!("the code #{}", code),
+      TokenKind::Open => "'('".to_string(),
+      TokenKind::Close => "')'".to_string(),
+      TokenKind::Comma => "','".to_string(),
+      TokenKind::Arguments(_) => "RuleSet arguments".to_string(),
+    }
+  }
+}
+
+pub(crate) type Problem = (Location, String);
+
+/// Splits FSH text into tokens. `line` and `column` are where the text starts
+/// in its file, for the text of RuleSets that is read again when inserted.
+pub(crate) fn tokenize(
+  text: &str,
+  file: usize,
+  line: usize,
+  column: usize,
+) -> Result<Vec<Token>, Problem> {
+  let mut lexer = Lexer {
+    chars: text.chars().collect(),
+    position: 0,
+    file,
+    line,
+    column,
+  };
+  let mut tokens: Vec<Token> = vec![];
+  let mut first = true;
+  loop {
+    if lexer.skip() {
+      first = true;
+    }
+    let c = match lexer.peek(0) {
+      Some(c) => c,
+      None => return Ok(tokens),
+    };
+    let at = lexer.location();
+    let start = lexer.position;
+    let kind = match c {
+      '"' => TokenKind::String(lexer.string(at)?),
+      '\'' => {
+        lexer.advance();
+        let unit = lexer.until('\'', at, "Unterminated unit")?;
+        TokenKind::Unit(unit)
+      }
+      '(' => {
+        lexer.advance();
+        TokenKind::Open
+      }
+      ')' => {
+        lexer.advance();
+        TokenKind::Close
+      }
+      ',' => {
+        lexer.advance();
+        TokenKind::Comma
+      }
+      _ => {
+        let mut word = String::new();
+        while let Some(c) = lexer.peek(0) {
+          if c.is_whitespace() || matches!(c, '(' | ')' | ',') {
+            break;
+          }
+          if c == '"' {
+            if !word.ends_with('#') {
+              break;
+            }
+            // A code in quotes, like `#"a code"`.
+            word.push_str(&lexer.string(lexer.location())?);
+            continue;
+          }
+          word.push(c);
+          lexer.advance();
+        }
+        match word.find('#') {
+          Some(hash) if !word.starts_with("http") || word[hash + 1..].find('/').is_none() => {
+            let system = Some(word[..hash].to_string()).filter(|s| !s.is_empty());
+            TokenKind::Code(system, word[hash + 1..].to_string())
+          }
+          _ => TokenKind::Word(word),
+        }
+      }
+    };
+    // The arguments of a RuleSet follow its name without a space.
+    let introduces_arguments = matches!(
+      (tokens.last().and_then(Token::word), &kind),
+      (Some("insert") | Some("RuleSet:"), TokenKind::Word(_))
+    );
+    tokens.push(Token {
+      kind,
+      at,
+      first,
+      start,
+      end: lexer.position,
+    });
+    first = false;
+    if introduces_arguments && lexer.peek(0) == Some('(') {
+      let at = lexer.location();
+      let start = lexer.position;
+      let arguments = lexer.arguments(at)?;
+      tokens.push(Token {
+        kind: TokenKind::Arguments(arguments),
+        at,
+        first,
+        start,
+        end: lexer.position,
+      });
+    }
+  }
+}
+
+struct Lexer {
+  chars: Vec<char>,
+  position: usize,
+  file: usize,
+  line: usize,
+  column: usize,
+}
+
+impl Lexer {
+  fn peek(&self, offset: usize) -> Option<char> {
+    self.chars.get(self.position + offset).copied()
+  }
+
+  fn advance(&mut self) {
+    if self.peek(0) == Some('\n') {
+      self.line += 1;
+      self.column = 1;
+    } else {
+      self.column += 1;
+    }
+    self.position += 1;
+  }
+
+  fn location(&self) -> Location {
+    Location {
+      file: self.file,
+      line: self.line,
+      column: self.column,
+    }
+  }
+
+  /// Moves past whitespace and comments, returning whether a line ended.
+  fn skip(&mut self) -> bool {
+    let mut newline = self.position == 0;
+    loop {
+      match (self.peek(0), self.peek(1)) {
+        (Some('\n'), _) => {
+          newline = true;
+          self.advance();
+        }
+        (Some(c), _) if c.is_whitespace() => self.advance(),
+        (Some('/'), Some('/')) => {
+          while self.peek(0).is_some_and(|c| c != '\n') {
+            self.advance();
+          }
+        }
+        (Some('/'), Some('*')) => {
+          while self.peek(0).is_some() && !(self.peek(0) == Some('*') && self.peek(1) == Some('/'))
+          {
+            if self.peek(0) == Some('\n') {
+              newline = true;
+            }
+            self.advance();
+          }
+          self.advance();
+          self.advance();
+        }
+        _ => return newline,
+      }
+    }
+  }
+
+  /// Reads up to `end`, which is consumed but not returned.
+  fn until(&mut self, end: char, at: Location, message: &str) -> Result<String, Problem> {
+    let mut text = String::new();
+    loop {
+      match self.peek(0) {
+        None => return Err((at, message.to_string())),
+        Some(c) if c == end => {
+          self.advance();
+          return Ok(text);
+        }
+        Some(c) => {
+          text.push(c);
+          self.advance();
+        }
+      }
+    }
+  }
+
+  fn string(&mut self, at: Location) -> Result<String, Problem> {
+    if self.peek(1) == Some('"') && self.peek(2) == Some('"') {
+      for _ in 0..3 {
+        self.advance();
+      }
+      let mut text = String::new();
+      while !(self.peek(0) == Some('"') && self.peek(1) == Some('"') && self.peek(2) == Some('"')) {
+        match self.peek(0) {
+          None => return Err((at, "Unterminated multi-line string".to_string())),
+          Some(c) => text.push(c),
+        }
+        self.advance();
+      }
+      for _ in 0..3 {
+        self.advance();
+      }
+      return Ok(dedent(&text));
+    }
+    self.advance();
+    let mut text = String::new();
+    loop {
+      match self.peek(0) {
+        None => return Err((at, "Unterminated string".to_string())),
+        Some('"') => {
+          self.advance();
+          return Ok(text);
+        }
+        Some('\\') => {
+          self.advance();
+          match self.peek(0) {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('r') => text.push('\r'),
+            Some(c) => text.push(c),
+            None => return Err((at, "Unterminated string".to_string())),
+          }
+          self.advance();
+        }
+        Some(c) => {
+          text.push(c);
+          self.advance();
+        }
+      }
+    }
+  }
+
+  /// Reads `(a, b, ...)`, where arguments in `[[...]]` may hold commas and
+  /// brackets.
+  fn arguments(&mut self, at: Location) -> Result<Vec<String>, Problem> {
+    self.advance();
+    let mut arguments = vec![];
+    let mut argument = String::new();
+    let mut depth = 0;
+    loop {
+      let c = self
+        .peek(0)
+        .ok_or_else(|| (at, "Unterminated RuleSet arguments".to_string()))?;
+      if c == '[' && self.peek(1) == Some('[') {
+        self.advance();
+        self.advance();
+        let mut quoted = String::new();
+        while !(self.peek(0) == Some(']') && self.peek(1) == Some(']')) {
+          quoted.push(
+            self
+              .peek(0)
+              .ok_or_else(|| (at, "Unterminated '[['".to_string()))?,
+          );
+          self.advance();
+        }
+        self.advance();
+        self.advance();
+        argument.push_str(&quoted);
+        continue;
+      }
+      if c == '"' {
+        let text = self.string(self.location())?;
+        argument.push_str(&format!(
+          "\"{}\"",
+          text.replace('\\', "\\\\").replace('"', "\\\"")
+        ));
+        continue;
+      }
+      self.advance();
+      match c {
+        '(' => depth += 1,
+        ')' if depth == 0 => {
+          arguments.push(argument.trim().to_string());
+          return Ok(arguments);
+        }
+        ')' => depth -= 1,
+        ',' if depth == 0 => {
+          arguments.push(argument.trim().to_string());
+          argument.clear();
+          continue;
+        }
+        _ => {}
+      }
+      argument.push(c);
+    }
+  }
+}
+
+/// Removes the indentation common to the lines of a multi-line string, and
+/// the blank first and last lines that the quotes are usually on.
+fn dedent(text: &str) -> String {
+  let mut lines = text.lines().collect::<Vec<_>>();
+  if lines.first().is_some_and(|l| l.trim().is_empty()) {
+    lines.remove(0);
+  }
+  if lines.last().is_some_and(|l| l.trim().is_empty()) {
+    lines.pop();
+  }
+  let indent = lines
+    .iter()
+    .filter(|l| !l.trim().is_empty())
+    .map(|l| l.len() - l.trim_start().len())
+    .min()
+    .unwrap_or(0);
+  lines
+    .iter()
+    .map(|l| l.get(indent..).unwrap_or("").trim_end())
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum EntityKind {
+  Alias,
+  Profile,
+  Extension,
+  Instance,
+  ValueSet,
+  CodeSystem,
+  Invariant,
+  RuleSet,
+  Mapping,
+}
+
+/// A definition in FSH, like a Profile, with its keywords and rules still as
+/// tokens.
+#[derive(Debug)]
+pub(crate) struct Entity {
+  pub kind: EntityKind,
+  pub name: String,
+  pub at: Location,
+  pub metadata: Vec<Metadata>,
+  pub rules: Vec<RawRule>,
+  /// The value of an Alias.
+  pub value: String,
+  /// The parameters of a RuleSet.
+  pub parameters: Vec<String>,
+  /// The text of a RuleSet's rules, which is read again for each insert
+  /// once the parameters are filled in, and where it starts.
+  pub body: String,
+  pub body_at: Location,
+}
+
+impl Entity {
+  /// The tokens of a keyword like `Parent:`, and where the keyword is.
+  pub fn metadata(&self, key: &str) -> Option<&Metadata> {
+    self.metadata.iter().find(|m| m.key == key)
+  }
+}
+
+#[derive(Debug)]
+pub(crate) struct Metadata {
+  pub key: String,
+  pub at: Location,
+  pub tokens: Vec<Token>,
+}
+
+/// A rule: the tokens after a `*`.
+#[derive(Debug, Clone)]
+pub(crate) struct RawRule {
+  pub at: Location,
+  pub indent: usize,
+  pub tokens: Vec<Token>,
+}
+
+/// Reads the entities in a file, reporting what can't be read and going on
+/// with the next rule, keyword or entity.
+pub(crate) fn entities(text: &str, file: usize, problems: &mut Vec<Problem>) -> Vec<Entity> {
+  let tokens = match tokenize(text, file, 1, 1) {
+    Ok(tokens) => tokens,
+    Err(problem) => {
+      problems.push(problem);
+      return vec![];
+    }
+  };
+  let chars = text.chars().collect::<Vec<_>>();
+  let mut entities: Vec<Entity> = vec![];
+  let mut index = 0;
+  while index < tokens.len() {
+    let end = (index + 1..tokens.len())
+      .find(|i| tokens[*i].first)
+      .unwrap_or(tokens.len());
+    let group = &tokens[index..end];
+    let head = &group[0];
+    let keyword = head.word().and_then(|w| w.strip_suffix(':'));
+    if let Some(kind) = keyword.filter(|k| ENTITY_KEYWORDS.contains(k)) {
+      match entity(kind, group, problems) {
+        Some(mut entity) => {
+          if entity.kind == EntityKind::RuleSet {
+            // The RuleSet's text runs to the next entity.
+            let next = tokens[end..].iter().find(|t| {
+              t.first
+                && t
+                  .word()
+                  .and_then(|w| w.strip_suffix(':'))
+                  .is_some_and(|k| ENTITY_KEYWORDS.contains(&k))
+            });
+            let body_end = next.map_or(chars.len(), |t| t.start);
+            let last = &group[group.len() - 1];
+            entity.body = chars[last.end..body_end].iter().collect();
+            entity.body_at = Location {
+              file,
+              line: last.at.line,
+              column: last.at.column + (last.end - last.start),
+            };
+          }
+          entities.push(entity);
+        }
+        None => {
+          // Skip the entity's rules too.
+          while index < tokens.len() && !(index >= end && starts_entity(&tokens[index])) {
+            index += 1;
+          }
+          continue;
+        }
+      }
+    } else if let Some(entity) = entities.last_mut() {
+      if head.word() == Some("*") {
+        entity.rules.push(RawRule {
+          at: head.at,
+          indent: head.at.column - 1,
+          tokens: group[1..].to_vec(),
+        });
+      } else if let Some(key) = keyword.filter(|k| METADATA_KEYWORDS.contains(k)) {
+        entity.metadata.push(Metadata {
+          key: key.to_string(),
+          at: head.at,
+          tokens: group[1..].to_vec(),
+        });
+      } else if entity.kind != EntityKind::RuleSet {
+        problems.push((
+          head.at,
+          format!("Expected a rule or a keyword but found {}", head.describe()),
+        ));
+      }
+    } else {
+      problems.push((
+        head.at,
+        format!(
+          "Expected an entity like 'Profile:' but found {}",
+          head.describe()
+        ),
+      ));
+    }
+    index = end;
+  }
+  entities
+}
+
+fn starts_entity(token: &Token) -> bool {
+  token.first
+    && token
+      .word()
+      .and_then(|w| w.strip_suffix(':'))
+      .is_some_and(|k| ENTITY_KEYWORDS.contains(&k))
+}
+
+fn entity(keyword: &str, group: &[Token], problems: &mut Vec<Problem>) -> Option<Entity> {
+  let head = &group[0];
+  let kind = match keyword {
+    "Alias" => EntityKind::Alias,
+    "Profile" => EntityKind::Profile,
+    "Extension" => EntityKind::Extension,
+    "Instance" => EntityKind::Instance,
+    "ValueSet" => EntityKind::ValueSet,
+    "CodeSystem" => EntityKind::CodeSystem,
+    "Invariant" => EntityKind::Invariant,
+    "RuleSet" => EntityKind::RuleSet,
+    "Mapping" => EntityKind::Mapping,
+    _ => {
+      problems.push((
+        head.at,
+        format!("{} definitions are not supported", keyword),
+      ));
+      return None;
+    }
+  };
+  let name = match group.get(1).map(|t| &t.kind) {
+    Some(TokenKind::Word(name)) => name.clone(),
+    _ => {
+      problems.push((head.at, format!("Expected a name after '{}:'", keyword)));
+      return None;
+    }
+  };
+  let mut entity = Entity {
+    kind,
+    name,
+    at: head.at,
+    metadata: vec![],
+    rules: vec![],
+    value: String::new(),
+    parameters: vec![],
+    body: String::new(),
+    body_at: head.at,
+  };
+  let rest = &group[2..];
+  match (kind, rest) {
+    (EntityKind::Alias, [equals, value]) if equals.word() == Some("=") => {
+      entity.value = match &value.kind {
+        TokenKind::Word(value) => value.clone(),
+        TokenKind::Code(system, code) => {
+          format!("{}#{}", system.as_deref().unwrap_or_default(), code)
+        }
+        _ => {
+          problems.push((
+            value.at,
+            "Expected the url the alias stands for".to_string(),
+          ));
+          return None;
+        }
+      };
+    }
+    (EntityKind::Alias, _) => {
+      problems.push((head.at, "Expected 'Alias: $name = url'".to_string()));
+      return None;
+    }
+    (
+      EntityKind::RuleSet,
+      [Token {
+        kind: TokenKind::Arguments(parameters),
+        ..
+      }],
+    ) => entity.parameters = parameters.clone(),
+    (_, []) => {}
+    (_, [extra, ..]) => {
+      problems.push((
+        extra.at,
+        format!("Unexpected {} after the name", extra.describe()),
+      ));
+    }
+  }
+  Some(entity)
+}
@@ -0,0 +1,745 @@
+use super::instances::Target;
+use super::parser::{EntityKind, TokenKind};
+use super::rules::{Item, RuleKind, TypeRef};
+use super::{capitalize, text, Exporter, Location, Step};
+use crate::definitions::type_code;
+use crate::fhirpath::Expression;
+use crate::model::ElementDefinition::ElementDefinitionBuilder;
+use crate::model::ElementDefinition_Binding::{
+  ElementDefinition_BindingBuilder, ElementDefinition_BindingStrength,
+};
+use crate::model::ElementDefinition_Constraint::{
+  ElementDefinition_ConstraintBuilder, ElementDefinition_ConstraintSeverity,
+};
+use crate::model::ElementDefinition_Discriminator::{
+  ElementDefinition_DiscriminatorBuilder, ElementDefinition_DiscriminatorType,
+};
+use crate::model::ElementDefinition_Mapping::ElementDefinition_MappingBuilder;
+use crate::model::ElementDefinition_Slicing::{
+  ElementDefinition_SlicingBuilder, ElementDefinition_SlicingRules,
+};
+use crate::model::ElementDefinition_Type::ElementDefinition_TypeBuilder;
+use crate::model::StructureDefinition::{
+  StructureDefinitionBuilder, StructureDefinitionDerivation, StructureDefinitionKind,
+  StructureDefinitionStatus,
+};
+use crate::model::StructureDefinition_Context::{
+  StructureDefinition_ContextBuilder, StructureDefinition_ContextType,
+};
+use crate::model::StructureDefinition_Differential::StructureDefinition_DifferentialBuilder;
+use crate::model::StructureDefinition_Mapping::StructureDefinition_MappingBuilder;
+use serde_json::json;
+use serde_json::value::Value;
+use std::mem;
+
+const STANDARDS_STATUS: &str =
+  "http://hl7.org/fhir/StructureDefinition/structuredefinition-standards-status";
+
+/// A Profile or Extension while its rules are applied.
+struct Profile {
+  url: String,
+  type_name: String,
+  /// Whether it is an Extension, whose sub-extensions are defined in place.
+  extension: bool,
+  builder: StructureDefinitionBuilder,
+  elements: Vec<ElementDefinitionBuilder>,
+}
+
+impl Profile {
+  /// The differential element with an id, added if it isn't there yet.
+  fn element(&mut self, id: &str, path: &str) -> &mut ElementDefinitionBuilder {
+    let position = self.elements.iter().position(|e| e.value["id"] == id);
+    let position = position.unwrap_or_else(|| {
+      let mut element = ElementDefinitionBuilder::new();
+      element.id(id).path(path);
+      let last = id.rsplit('.').next().unwrap_or(id);
+      if let Some((_, slice)) = last.split_once(':') {
+        element.slice_name(slice);
+      }
+      self.elements.push(element);
+      self.elements.len() - 1
+    });
+    &mut self.elements[position]
+  }
+
+  fn has_element(&self, id: &str) -> bool {
+    self.elements.iter().any(|e| e.value["id"] == id)
+  }
+}
+
+impl<'c> Exporter<'c> {
+  /// Compiles a Profile or Extension once, returning its url.
+  pub(super) fn export_structure(&mut self, index: usize) -> Option<String> {
+    let url = self.entity_url(index)?;
+    if self.structures.contains_key(&url) {
+      return Some(url);
+    }
+    let entity = &self.entities[index];
+    if !self.exporting.insert(index) {
+      self.error(entity.at, &format!("{} is its own parent", entity.name));
+      return None;
+    }
+    let profile = self.build_structure(index, url.clone());
+    self.exporting.remove(&index);
+    let profile = profile?;
+    let mut builder = profile.builder;
+    let elements = profile.elements.iter().map(|e| e.build()).collect();
+    builder.differential(StructureDefinition_DifferentialBuilder::new(elements).build());
+    self.structures.insert(url.clone(), builder.value);
+    self.order.push(url.clone());
+    Some(url)
+  }
+
+  fn build_structure(&mut self, index: usize, url: String) -> Option<Profile> {
+    let entity = &self.entities[index];
+    let extension = entity.kind == EntityKind::Extension;
+    let parent = match (entity.metadata("Parent").and_then(text), extension) {
+      (Some(parent), _) => parent,
+      (None, true) => "Extension",
+      (None, false) => {
+        self.error(entity.at, "A Profile needs a Parent");
+        return None;
+      }
+    };
+    let base = match self.structure(parent) {
+      Some(base) => base,
+      None => {
+        let at = entity.metadata("Parent").map_or(entity.at, |m| m.at);
+        self.error(at, &format!("Can't find the definition of {}", parent));
+        return None;
+      }
+    };
+    if extension && base.type_name != "Extension" {
+      self.error(entity.at, "The Parent of an Extension must be an extension");
+      return None;
+    }
+    let title = entity.metadata("Title").and_then(text);
+    let description = entity.metadata("Description").and_then(text);
+
+    let mut builder = StructureDefinitionBuilder::new();
+    builder.value["resourceType"] = json!("StructureDefinition");
+    builder
+      .id(self.id(index))
+      .url(&url)
+      .name(&entity.name)
+      .status(StructureDefinitionStatus::Draft)
+      .fhir_abstract(false)
+      .fhir_type(&base.type_name)
+      .base_definition(&base.url)
+      .derivation(StructureDefinitionDerivation::Constraint);
+    if let Some(kind) = StructureDefinitionKind::from_string(&base.kind) {
+      builder.kind(kind);
+    }
+    if let Some(title) = title {
+      builder.title(title);
+    }
+    if let Some(description) = description {
+      builder.description(description);
+    }
+    let mut profile = Profile {
+      url: url.clone(),
+      type_name: base.type_name.clone(),
+      extension,
+      builder,
+      elements: vec![],
+    };
+    if extension {
+      self.extension_context(index, &mut profile);
+      let root = profile.element("Extension", "Extension");
+      root.short(title.unwrap_or(&entity.name));
+      root.definition(description.or(title).unwrap_or(&entity.name));
+      let element = profile.element("Extension.url", "Extension.url");
+      element.value["fixedUri"] = json!(url);
+    }
+
+    for rule in &self.rules[index] {
+      if let Err(message) = self.apply(&mut profile, &rule.path, &rule.kind) {
+        self.error(rule.at, &message);
+      }
+    }
+
+    // An extension with sub-extensions has no value of its own.
+    let complex = profile.has_element("Extension.extension");
+    if extension && complex && !profile.has_element("Extension.value[x]") {
+      profile
+        .element("Extension.value[x]", "Extension.value[x]")
+        .min(0)
+        .max("0");
+    }
+    Some(profile)
+  }
+
+  /// Where an Extension can be used, from its `Context:`, or anywhere.
+  fn extension_context(&mut self, index: usize, profile: &mut Profile) {
+    let entity = &self.entities[index];
+    let mut contexts = vec![];
+    let tokens = entity.metadata("Context").map_or(&[][..], |m| &m.tokens);
+    for token in tokens {
+      let (context_type, expression) = match &token.kind {
+        TokenKind::Comma => continue,
+        TokenKind::String(expression) => (
+          StructureDefinition_ContextType::Fhirpath,
+          expression.clone(),
+        ),
+        TokenKind::Word(name) => match self.entity(name, &[EntityKind::Extension]) {
+          Some(other) => (
+            StructureDefinition_ContextType::Extension,
+            self.entity_url(other).unwrap_or_default(),
+          ),
+          None if name.contains("://") => {
+            (StructureDefinition_ContextType::Extension, name.clone())
+          }
+          None => {
+            let type_name = name.split('.').next().unwrap_or(name);
+            if self.definitions.type_url(type_name).is_none() {
+              self.error(token.at, &format!("{} is not a type or element", name));
+            }
+            (StructureDefinition_ContextType::Element, name.clone())
+          }
+        },
+        _ => {
+          self.error(token.at, &format!("Unexpected {}", token.describe()));
+          continue;
+        }
+      };
+      let mut context = StructureDefinition_ContextBuilder::new();
+      context.fhir_type(context_type).expression(&expression);
+      contexts.push(context);
+    }
+    if contexts.is_empty() {
+      let mut context = StructureDefinition_ContextBuilder::new();
+      context
+        .fhir_type(StructureDefinition_ContextType::Element)
+        .expression("Element");
+      contexts.push(context);
+    }
+    let contexts = contexts.iter().map(|c| c.build()).collect();
+    profile.builder.context(contexts);
+  }
+
+  fn apply(&mut self, profile: &mut Profile, path: &str, kind: &RuleKind) -> Result<(), String> {
+    if let RuleKind::Caret { caret, value } = kind {
+      let (json, root) = match path {
+        "" => (
+          &mut profile.builder.value,
+          "StructureDefinition".to_string(),
+        ),
+        path => {
+          let (id, element_path, _) = self.element(profile, path)?;
+          let element = profile.element(&id, &element_path);
+          (&mut element.value, "ElementDefinition".to_string())
+        }
+      };
+      let mut target = Target::new(mem::take(json), &root);
+      let assigned = self.assign(&mut target, caret, Some(value));
+      *json = target.json;
+      return assigned;
+    }
+    let (id, element_path, steps) = self.element(profile, path)?;
+    let last = steps.last();
+    let base = last.and_then(|s| s.base.as_ref());
+    match kind {
+      RuleKind::Path => {}
+      RuleKind::Cardinality { min, max, flags } => {
+        // Slices may narrow the cardinality of what they slice as they like.
+        let sliced = last.is_some_and(|s| s.slice.is_some() || s.choice.is_some());
+        let base_min = base.and_then(|b| b["min"].as_u64()).filter(|_| !sliced);
+        let base_max = base.and_then(|b| b["max"].as_str()).filter(|_| !sliced);
+        if let (Some(min), Some(base_min)) = (min, base_min) {
+          if *min < base_min {
+            return Err(format!(
+              "The minimum {} of {} is below the minimum {} it has already",
+              min, id, base_min
+            ));
+          }
+        }
+        if let (Some(max), Some(base_max)) = (max, base_max) {
+          if widens(max, base_max) {
+            return Err(format!(
+              "The maximum {} of {} is above the maximum {} it has already",
+              max, id, base_max
+            ));
+          }
+        }
+        if let (Some(min), Some(max)) = (min, max) {
+          if widens(&min.to_string(), max) {
+            return Err(format!(
+              "The minimum {} of {} is above its maximum {}",
+              min, id, max
+            ));
+          }
+        }
+        let element = profile.element(&id, &element_path);
+        if let Some(min) = min {
+          element.min(*min);
+        }
+        if let Some(max) = max {
+          element.max(max);
+        }
+        set_flags(element, flags);
+      }
+      RuleKind::Flags(flags) => set_flags(profile.element(&id, &element_path), flags),
+      RuleKind::Binding {
+        value_set,
+        strength,
+      } => {
+        let strength = ElementDefinition_BindingStrength::from_string(strength)
+          .ok_or_else(|| format!("'{}' is not a binding strength", strength))?;
+        let mut binding = ElementDefinition_BindingBuilder::new();
+        binding.strength(strength).value_set(&self.url(value_set));
+        profile.element(&id, &element_path).binding(binding.build());
+      }
+      RuleKind::Assignment { value, exactly } => {
+        let type_code = last.and_then(|s| s.type_code.clone()).ok_or_else(|| {
+          format!(
+            "{} has more than one type; name one, like in valueString",
+            id
+          )
+        })?;
+        let value = self.convert(value, Some(&type_code))?;
+        let key = match exactly {
+          true => format!("fixed{}", capitalize(&type_code)),
+          false => format!("pattern{}", capitalize(&type_code)),
+        };
+        profile.element(&id, &element_path).value[key] = value;
+      }
+      RuleKind::Only(types) => {
+        let types = self.types(types, base)?;
+        let types = types.iter().map(|t| t.build()).collect();
+        profile.element(&id, &element_path).fhir_type(types);
+      }
+      RuleKind::Contains(items) => {
+        let step = last.ok_or("Only elements can be sliced")?;
+        for item in items {
+          self.contains(profile, &id, &element_path, step, item)?;
+        }
+      }
+      RuleKind::Obeys(keys) => {
+        let mut constraints = vec![];
+        for key in keys {
+          constraints.push(self.constraint(key, &profile.url)?);
+        }
+        let element = profile.element(&id, &element_path);
+        match element.value["constraint"].as_array_mut() {
+          Some(existing) => existing.extend(constraints),
+          None => element.value["constraint"] = json!(constraints),
+        }
+      }
+      _ => {
+        return Err(format!(
+          "This rule can't be used in a{}",
+          match profile.extension {
+            true => "n Extension",
+            false => " Profile",
+          }
+        ))
+      }
+    }
+    Ok(())
+  }
+
+  /// The id and path of the element a path in FSH refers to, adding the
+  /// slices that names like `valueQuantity` make of choice elements.
+  fn element(
+    &self,
+    profile: &mut Profile,
+    path: &str,
+  ) -> Result<(String, String, Vec<Step>), String> {
+    let steps = self.steps(&profile.type_name, path)?;
+    let mut id = profile.type_name.clone();
+    let mut element_path = profile.type_name.clone();
+    for step in &steps {
+      element_path = format!("{}.{}", element_path, step.path);
+      if let Some(choice) = &step.choice {
+        let choice_id = format!("{}.{}", id, step.path);
+        let slice_id = format!("{}:{}", choice_id, step.key);
+        if !profile.has_element(&slice_id) {
+          let element = profile.element(&choice_id, &element_path);
+          if element.value.get("slicing").is_none() {
+            let mut discriminator = ElementDefinition_DiscriminatorBuilder::new();
+            discriminator
+              .fhir_type(ElementDefinition_DiscriminatorType::FhirType)
+              .path("$this");
+            let mut slicing = ElementDefinition_SlicingBuilder::new();
+            slicing
+              .discriminator(vec![discriminator.build()])
+              .rules(ElementDefinition_SlicingRules::Open)
+              .ordered(false);
+            element.slicing(slicing.build());
+          }
+          let mut element_type = ElementDefinition_TypeBuilder::new();
+          element_type.code(choice);
+          profile
+            .element(&slice_id, &element_path)
+            .fhir_type(vec![element_type.build()]);
+        }
+      }
+      id = format!("{}.{}", id, step.id);
+    }
+    Ok((id, element_path, steps))
+  }
+
+  /// The types of an `only` rule, which have to be among the types the
+  /// element has.
+  fn types(
+    &mut self,
+    types: &[TypeRef],
+    base: Option<&Value>,
+  ) -> Result<Vec<ElementDefinition_TypeBuilder>, String> {
+    let allowed = base.and_then(|b| b["type"].as_array()).map(|types| {
+      types
+        .iter()
+        .filter_map(type_code)
+        .map(str::to_string)
+        .collect::<Vec<_>>()
+    });
+    let mut builders = vec![];
+    for type_ref in types {
+      let mut builder = ElementDefinition_TypeBuilder::new();
+      let code = match type_ref.name.as_str() {
+        "Reference" | "CodeableReference" | "Canonical" if !type_ref.targets.is_empty() => {
+          let mut targets = vec![];
+          for target in &type_ref.targets {
+            let structure = self
+              .structure(target)
+              .ok_or_else(|| format!("Can't find the definition of {}", target))?;
+            targets.push(structure.url);
+          }
+          builder.target_profile(targets.iter().map(String::as_str).collect());
+          match type_ref.name.as_str() {
+            "Canonical" => "canonical".to_string(),
+            name => name.to_string(),
+          }
+        }
+        name => {
+          let structure = self
+            .structure(name)
+            .ok_or_else(|| format!("Can't find the definition of {}", name))?;
+          if structure.profile {
+            builder.profile(vec![&structure.url]);
+          }
+          structure.type_name
+        }
+      };
+      if let Some(allowed) = &allowed {
+        if !allowed.contains(&code) && !allowed.iter().any(|t| t == "Resource") {
+          return Err(format!(
+            "{} is not one of the types of the element: {}",
+            code,
+            allowed.join(", ")
+          ));
+        }
+      }
+      builder.code(&code);
+      builders.push(builder);
+    }
+    Ok(builders)
+  }
+
+  /// Adds a slice for an item of a `contains` rule.
+  fn contains(
+    &mut self,
+    profile: &mut Profile,
+    id: &str,
+    path: &str,
+    step: &Step,
+    item: &Item,
+  ) -> Result<(), String> {
+    let extension = step.key == "extension" || step.key == "modifierExtension";
+    let slice = item.named.as_deref().unwrap_or(&item.name);
+    if item.named.is_some() && !extension {
+      return Err("Only extensions are given their slice names with 'named'".to_string());
+    }
+    let slice_id = format!("{}:{}", id, slice);
+    if profile.has_element(&slice_id) {
+      return Err(format!("{} already has a slice {}", id, slice));
+    }
+    let sliced = profile.element(id, path);
+    if extension && sliced.value.get("slicing").is_none() {
+      let mut discriminator = ElementDefinition_DiscriminatorBuilder::new();
+      discriminator
+        .fhir_type(ElementDefinition_DiscriminatorType::Value)
+        .path("url");
+      let mut slicing = ElementDefinition_SlicingBuilder::new();
+      slicing
+        .discriminator(vec![discriminator.build()])
+        .rules(ElementDefinition_SlicingRules::Open)
+        .ordered(false);
+      sliced.slicing(slicing.build());
+    } else if !extension
+      && sliced.value.get("slicing").is_none()
+      && !self.inherits_slicing(profile, id)
+    {
+      self.warning(
+        item.at,
+        &format!(
+          "{} is sliced without saying how; add a ^slicing rule for it",
+          id
+        ),
+      );
+    }
+    let url = match extension {
+      true => match self.entity(&item.name, &[EntityKind::Extension]) {
+        Some(index) => Some(
+          self
+            .export_structure(index)
+            .ok_or_else(|| format!("The Extension {} has errors", item.name))?,
+        ),
+        None if self.aliases.contains_key(item.name.as_str()) || item.name.contains("://") => {
+          Some(self.url(&item.name))
+        }
+        // Sub-extensions of an Extension are defined in place.
+        None if profile.extension && item.named.is_none() => None,
+        None => return Err(format!("Can't find the extension {}", item.name)),
+      },
+      false => None,
+    };
+    let element = profile.element(&slice_id, path);
+    if let Some(min) = item.min {
+      element.min(min);
+    }
+    if let Some(max) = &item.max {
+      element.max(max);
+    }
+    set_flags(element, &item.flags);
+    if extension {
+      let mut element_type = ElementDefinition_TypeBuilder::new();
+      element_type.code("Extension");
+      if let Some(url) = &url {
+        element_type.profile(vec![url]);
+      }
+      element.fhir_type(vec![element_type.build()]);
+      if url.is_none() {
+        let url_id = format!("{}.url", slice_id);
+        let url_path = format!("{}.url", path);
+        profile.element(&url_id, &url_path).value["fixedUri"] = json!(item.name);
+      }
+    }
+    Ok(())
+  }
+
+  /// Whether the parent of a profile already slices an element.
+  fn inherits_slicing(&self, profile: &Profile, id: &str) -> bool {
+    let parent = profile.builder.value["baseDefinition"].as_str();
+    let parent = parent.and_then(|url| self.structure_json(url));
+    ["differential", "snapshot"].iter().any(|view| {
+      parent
+        .and_then(|p| p[view]["element"].as_array())
+        .into_iter()
+        .flatten()
+        .any(|e| e["id"] == id && e.get("slicing").is_some())
+    })
+  }
+
+  /// The constraint an Invariant stands for.
+  fn constraint(&self, key: &str, source: &str) -> Result<Value, String> {
+    let index = self
+      .entity(key, &[EntityKind::Invariant])
+      .ok_or_else(|| format!("The invariant {} is not defined", key))?;
+    let entity = &self.entities[index];
+    let mut constraint = ElementDefinition_ConstraintBuilder::new();
+    constraint.key(&entity.name).source(source);
+    if let Some(severity) = entity
+      .metadata("Severity")
+      .and_then(text)
+      .and_then(ElementDefinition_ConstraintSeverity::from_string)
+    {
+      constraint.severity(severity);
+    }
+    if let Some(human) = entity.metadata("Description").and_then(text) {
+      constraint.human(human);
+    }
+    if let Some(expression) = entity.metadata("Expression").and_then(text) {
+      constraint.expression(expression);
+    }
+    if let Some(xpath) = entity.metadata("XPath").and_then(text) {
+      constraint.xpath(xpath);
+    }
+    Ok(constraint.value)
+  }
+
+  /// Checks that an Invariant has what a constraint needs, and that its
+  /// expression is FHIRPath.
+  pub(super) fn check_invariant(&mut self, index: usize) {
+    let entity = &self.entities[index];
+    if entity.metadata("Description").is_none() {
+      self.error(entity.at, "An Invariant needs a Description");
+    }
+    match entity.metadata("Severity") {
+      None => self.error(entity.at, "An Invariant needs a Severity"),
+      Some(severity) => {
+        let valid = text(severity).and_then(ElementDefinition_ConstraintSeverity::from_string);
+        if valid.is_none() {
+          self.error(
+            severity.at,
+            "The Severity of an Invariant is #error or #warning",
+          );
+        }
+      }
+    }
+    if let Some(metadata) = entity.metadata("Expression") {
+      let token = match metadata.tokens.first() {
+        Some(token) => token,
+        None => return,
+      };
+      if let TokenKind::String(expression) = &token.kind {
+        if let Err(error) = Expression::parse(expression) {
+          // Point into the expression when it is on one line.
+          let offset = error.offset.filter(|_| !expression.contains('\n'));
+          let at = match offset {
+            Some(offset) => Location {
+              column: token.at.column + 1 + expression[..offset].chars().count(),
+              ..token.at
+            },
+            None => token.at,
+          };
+          self.error(
+            at,
+            &format!(
+              "The expression of {} is not valid: {}",
+              entity.name, error.message
+            ),
+          );
+        }
+      }
+    }
+    if !entity.rules.is_empty() {
+      self.error(entity.at, "An Invariant has no rules");
+    }
+  }
+
+  /// Adds the mappings of a Mapping to the Profile or Extension it maps.
+  pub(super) fn export_mapping(&mut self, index: usize) {
+    let entity = &self.entities[index];
+    let source = match entity.metadata("Source").and_then(text) {
+      Some(source) => source,
+      None => return self.error(entity.at, "A Mapping needs a Source"),
+    };
+    let url = self
+      .entity(source, &[EntityKind::Profile, EntityKind::Extension])
+      .and_then(|source| self.entity_url(source))
+      .filter(|url| self.structures.contains_key(url));
+    let url = match url {
+      Some(url) => url,
+      None => {
+        let at = entity.metadata("Source").map_or(entity.at, |m| m.at);
+        return self.error(
+          at,
+          &format!("{} is not a Profile or Extension defined in FSH", source),
+        );
+      }
+    };
+    let identity = self.id(index);
+    let mut mapping = StructureDefinition_MappingBuilder::new();
+    mapping.identity(identity);
+    if let Some(target) = entity.metadata("Target").and_then(text) {
+      mapping.uri(target);
+    }
+    if let Some(title) = entity.metadata("Title").and_then(text) {
+      mapping.name(title);
+    }
+    if let Some(description) = entity.metadata("Description").and_then(text) {
+      mapping.comment(description);
+    }
+
+    let structure = self.structures[&url].clone();
+    let mut profile = Profile {
+      url: url.clone(),
+      type_name: structure["type"].as_str().unwrap_or_default().to_string(),
+      extension: false,
+      builder: StructureDefinitionBuilder::new(),
+      elements: vec![],
+    };
+    for element in structure["differential"]["element"]
+      .as_array()
+      .into_iter()
+      .flatten()
+    {
+      let mut builder = ElementDefinitionBuilder::new();
+      builder.value = element.clone();
+      profile.elements.push(builder);
+    }
+    for rule in &self.rules[index] {
+      let (target, comment, language) = match &rule.kind {
+        RuleKind::Mapping {
+          target,
+          comment,
+          language,
+        } => (target, comment, language),
+        _ => {
+          self.error(rule.at, "Only mapping rules can be used in a Mapping");
+          continue;
+        }
+      };
+      let (id, path) = match self.element(&mut profile, &rule.path) {
+        Ok((id, path, _)) => (id, path),
+        Err(message) => {
+          self.error(rule.at, &message);
+          continue;
+        }
+      };
+      let mut element_mapping = ElementDefinition_MappingBuilder::new();
+      element_mapping.identity(identity).map(target);
+      if let Some(comment) = comment {
+        element_mapping.comment(comment);
+      }
+      if let Some(language) = language {
+        element_mapping.language(language);
+      }
+      let element = profile.element(&id, &path);
+      match element.value["mapping"].as_array_mut() {
+        Some(mappings) => mappings.push(element_mapping.value),
+        None => element.value["mapping"] = json!([element_mapping.value]),
+      }
+    }
+
+    let structure = self.structures.get_mut(&url).expect("checked above");
+    match structure["mapping"].as_array_mut() {
+      Some(mappings) => mappings.push(mapping.value),
+      None => structure["mapping"] = json!([mapping.value]),
+    }
+    let elements = profile.elements.iter().map(|e| e.build()).collect();
+    structure["differential"] = StructureDefinition_DifferentialBuilder::new(elements)
+      .build()
+      .to_json();
+  }
+}
+
+fn set_flags(element: &mut ElementDefinitionBuilder, flags: &[String]) {
+  for flag in flags {
+    match flag.as_str() {
+      "MS" => {
+        element.must_support(true);
+      }
+      "SU" => {
+        element.is_summary(true);
+      }
+      "?!" => {
+        element.is_modifier(true);
+      }
+      status => {
+        let status = match status {
+          "N" => "normative",
+          "TU" => "trial-use",
+          _ => "draft",
+        };
+        element.value["extension"] = json!([{
+          "url": STANDARDS_STATUS,
+          "valueCode": status,
+        }]);
+      }
+    }
+  }
+}
+
+/// Whether a maximum cardinality is above another.
+fn widens(max: &str, than: &str) -> bool {
+  match (max, than) {
+    (_, "*") => false,
+    ("*", _) => true,
+    (max, than) => match (max.parse::<u64>(), than.parse::<u64>()) {
+      (Ok(max), Ok(than)) => max > than,
+      _ => false,
+    },
+  }
+}
@@ -0,0 +1,813 @@
+use super::parser::{tokenize, Entity, EntityKind, Problem, RawRule, Token, TokenKind};
+use super::Location;
+use std::collections::HashMap;
+
+/// How deeply RuleSets may insert other RuleSets.
+const MAX_DEPTH: usize = 16;
+
+const FLAGS: &[&str] = &["MS", "SU", "?!", "N", "TU", "D"];
+
+/// A value in a rule, before it is given the shape of the element it is
+/// assigned to.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum FshValue {
+  String(String),
+  Number(String),
+  Boolean(bool),
+  Code {
+    system: Option<String>,
+    code: String,
+    display: Option<String>,
+  },
+  Quantity {
+    value: String,
+    unit: String,
+    display: Option<String>,
+  },
+  Reference {
+    target: String,
+    display: Option<String>,
+  },
+  Canonical(String),
+  /// A bare word: the name of an instance, a date or a url.
+  Name(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Code {
+  pub system: Option<String>,
+  pub code: String,
+}
+
+/// A type in an `only` rule: a type or profile, or `Reference(...)` and
+/// `Canonical(...)` with their targets.
+#[derive(Debug, Clone)]
+pub(crate) struct TypeRef {
+  pub name: String,
+  pub targets: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Item {
+  pub at: Location,
+  pub name: String,
+  /// The slice name after `named`, when `name` is an extension.
+  pub named: Option<String>,
+  pub min: Option<u64>,
+  pub max: Option<String>,
+  pub flags: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Filter {
+  pub property: String,
+  pub op: String,
+  pub value: FshValue,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum RuleKind {
+  /// A path on its own, which only sets the context of indented rules.
+  Path,
+  Cardinality {
+    min: Option<u64>,
+    max: Option<String>,
+    flags: Vec<String>,
+  },
+  Flags(Vec<String>),
+  Binding {
+    value_set: String,
+    strength: String,
+  },
+  Assignment {
+    value: FshValue,
+    exactly: bool,
+  },
+  Only(Vec<TypeRef>),
+  Contains(Vec<Item>),
+  Obeys(Vec<String>),
+  /// `^path = value`, setting an element of the definition itself rather than
+  /// of what it defines.
+  Caret {
+    caret: String,
+    value: FshValue,
+  },
+  /// A concept of a CodeSystem, with the codes of its ancestors first.
+  Concept {
+    codes: Vec<Code>,
+    display: Option<String>,
+    definition: Option<String>,
+  },
+  /// A caret rule on a concept of a CodeSystem.
+  ConceptCaret {
+    codes: Vec<Code>,
+    caret: String,
+    value: FshValue,
+  },
+  /// An include or exclude of a ValueSet.
+  Component {
+    include: bool,
+    concepts: Vec<(Code, Option<String>)>,
+    system: Option<String>,
+    value_sets: Vec<String>,
+    filters: Vec<Filter>,
+  },
+  Mapping {
+    target: String,
+    comment: Option<String>,
+    language: Option<String>,
+  },
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Rule {
+  pub at: Location,
+  /// The path of the element, with the path of any rules it is indented
+  /// under; empty for the root.
+  pub path: String,
+  pub kind: RuleKind,
+}
+
+/// The path and codes that indented rules are relative to.
+#[derive(Clone, Default)]
+struct Context {
+  path: String,
+  codes: Vec<Code>,
+}
+
+/// Reads the rules of entities, inserting RuleSets.
+pub(crate) struct Reader<'a> {
+  pub rule_sets: &'a HashMap<String, &'a Entity>,
+  pub problems: &'a mut Vec<Problem>,
+}
+
+impl<'a> Reader<'a> {
+  pub fn rules(&mut self, entity: &Entity) -> Vec<Rule> {
+    let mut rules = vec![];
+    self.read(
+      entity.kind,
+      &entity.rules,
+      &Context::default(),
+      0,
+      &mut rules,
+    );
+    rules
+  }
+
+  fn read(
+    &mut self,
+    kind: EntityKind,
+    raw: &[RawRule],
+    outer: &Context,
+    depth: usize,
+    rules: &mut Vec<Rule>,
+  ) {
+    let mut stack: Vec<(usize, Context)> = vec![];
+    for rule in raw {
+      while stack
+        .last()
+        .is_some_and(|(indent, _)| *indent >= rule.indent)
+      {
+        stack.pop();
+      }
+      let context = stack.last().map_or(outer, |(_, context)| context).clone();
+      match self.rule(kind, rule, &context, depth, rules) {
+        Ok(inner) => stack.push((rule.indent, inner)),
+        Err(problem) => self.problems.push(problem),
+      }
+    }
+  }
+
+  /// Reads a rule into `rules`, returning the context for the rules indented
+  /// under it.
+  fn rule(
+    &mut self,
+    kind: EntityKind,
+    raw: &RawRule,
+    context: &Context,
+    depth: usize,
+    rules: &mut Vec<Rule>,
+  ) -> Result<Context, Problem> {
+    let mut cursor = Cursor {
+      tokens: &raw.tokens,
+      index: 0,
+      at: raw.at,
+    };
+    let mut inner = context.clone();
+    if kind == EntityKind::CodeSystem {
+      if let Some(TokenKind::Code(..)) = cursor.peek() {
+        let mut codes = context.codes.clone();
+        while let Some(TokenKind::Code(system, code)) = cursor.peek().cloned() {
+          cursor.next()?;
+          codes.push(Code { system, code });
+        }
+        inner.codes = codes.clone();
+        let kind = match cursor.peek_word() {
+          Some(caret) if caret.starts_with('^') => {
+            let caret = caret[1..].to_string();
+            cursor.next()?;
+            cursor.expect_word("=")?;
+            let (value, _) = cursor.value()?;
+            RuleKind::ConceptCaret {
+              codes,
+              caret,
+              value,
+            }
+          }
+          _ => {
+            let display = cursor.optional_string()?;
+            let definition = cursor.optional_string()?;
+            RuleKind::Concept {
+              codes,
+              display,
+              definition,
+            }
+          }
+        };
+        cursor.end()?;
+        rules.push(Rule {
+          at: raw.at,
+          path: String::new(),
+          kind,
+        });
+        return Ok(inner);
+      }
+    }
+    if kind == EntityKind::ValueSet {
+      let component = match cursor.peek() {
+        Some(TokenKind::Word(word)) => matches!(word.as_str(), "include" | "exclude" | "codes"),
+        Some(TokenKind::Code(..)) => true,
+        _ => false,
+      };
+      if component {
+        let kind = cursor.component()?;
+        cursor.end()?;
+        rules.push(Rule {
+          at: raw.at,
+          path: String::new(),
+          kind,
+        });
+        return Ok(inner);
+      }
+    }
+
+    let path = match cursor.peek_word() {
+      Some(word)
+        if word.starts_with('^') || matches!(word, "insert" | "->" | "obeys" | "contains") =>
+      {
+        String::new()
+      }
+      Some(".") => {
+        cursor.next()?;
+        String::new()
+      }
+      Some(_) => cursor.word("a path")?,
+      None => return Err((raw.at, "Expected a path after '*'".to_string())),
+    };
+    let path = join(&context.path, &path);
+    // Soft indexes in the context have been resolved by the rule that set it.
+    inner.path = path.replace("[+]", "[=]");
+    let at = raw.at;
+    // The rules are only kept once the whole rule has been read.
+    let mut pending = vec![];
+    let mut push = |path: &str, kind: RuleKind| {
+      pending.push(Rule {
+        at,
+        path: path.to_string(),
+        kind,
+      })
+    };
+    let word = match cursor.peek_word() {
+      Some(word) => word.to_string(),
+      None => {
+        rules.push(Rule {
+          at,
+          path,
+          kind: RuleKind::Path,
+        });
+        return Ok(inner);
+      }
+    };
+    match word.as_str() {
+      caret if caret.starts_with('^') => {
+        cursor.next()?;
+        cursor.expect_word("=")?;
+        let (value, _) = cursor.value()?;
+        push(
+          &path,
+          RuleKind::Caret {
+            caret: caret[1..].to_string(),
+            value,
+          },
+        );
+      }
+      "=" => {
+        cursor.next()?;
+        let (value, exactly) = cursor.value()?;
+        push(&path, RuleKind::Assignment { value, exactly });
+      }
+      "from" => {
+        cursor.next()?;
+        let value_set = cursor.reference("a ValueSet")?;
+        let strength = match cursor.peek() {
+          Some(TokenKind::Open) => {
+            cursor.next()?;
+            let strength = cursor.word("a binding strength")?;
+            cursor.expect(TokenKind::Close)?;
+            strength
+          }
+          _ => "required".to_string(),
+        };
+        push(
+          &path,
+          RuleKind::Binding {
+            value_set,
+            strength,
+          },
+        );
+      }
+      "only" => {
+        cursor.next()?;
+        let types = cursor.types()?;
+        push(&path, RuleKind::Only(types));
+      }
+      "contains" => {
+        cursor.next()?;
+        let mut items = vec![cursor.item()?];
+        while cursor.eat_word("and") {
+          items.push(cursor.item()?);
+        }
+        push(&path, RuleKind::Contains(items));
+      }
+      "obeys" => {
+        cursor.next()?;
+        let mut keys = vec![cursor.word("an invariant")?];
+        while cursor.eat_word("and") {
+          keys.push(cursor.word("an invariant")?);
+        }
+        push(&path, RuleKind::Obeys(keys));
+      }
+      "->" => {
+        cursor.next()?;
+        let target = cursor.string("the mapping")?;
+        let comment = cursor.optional_string()?;
+        let language = match cursor.peek() {
+          Some(TokenKind::Code(_, code)) => {
+            let code = code.clone();
+            cursor.next()?;
+            Some(code)
+          }
+          _ => None,
+        };
+        push(
+          &path,
+          RuleKind::Mapping {
+            target,
+            comment,
+            language,
+          },
+        );
+      }
+      "insert" => {
+        cursor.next()?;
+        let name = cursor.word("the name of a RuleSet")?;
+        let arguments = match cursor.peek() {
+          Some(TokenKind::Arguments(arguments)) => {
+            let arguments = arguments.clone();
+            cursor.next()?;
+            arguments
+          }
+          _ => vec![],
+        };
+        cursor.end()?;
+        let context = Context {
+          path,
+          codes: inner.codes.clone(),
+        };
+        self.insert(kind, raw.at, &name, &arguments, &context, depth, rules)?;
+        return Ok(inner);
+      }
+      _ => {
+        let mut paths = vec![path];
+        while cursor.eat_word("and") {
+          paths.push(join(&context.path, &cursor.word("a path")?));
+        }
+        let (min, max) = match cursor.peek_word().and_then(cardinality) {
+          Some(cardinality) => {
+            cursor.next()?;
+            (Some(cardinality.0), Some(cardinality.1))
+          }
+          None => (None, None),
+        };
+        let flags = cursor.flags();
+        if min.is_none() && flags.is_empty() {
+          return Err(cursor.unexpected("a rule"));
+        }
+        for path in &paths {
+          let kind = match (&min, &max) {
+            (Some(min), Some(max)) => RuleKind::Cardinality {
+              min: *min,
+              max: max.clone(),
+              flags: flags.clone(),
+            },
+            _ => RuleKind::Flags(flags.clone()),
+          };
+          push(path, kind);
+        }
+      }
+    }
+    cursor.end()?;
+    rules.extend(pending);
+    Ok(inner)
+  }
+
+  #[allow(clippy::too_many_arguments)]
+  fn insert(
+    &mut self,
+    kind: EntityKind,
+    at: Location,
+    name: &str,
+    arguments: &[String],
+    context: &Context,
+    depth: usize,
+    rules: &mut Vec<Rule>,
+  ) -> Result<(), Problem> {
+    let rule_set = *self
+      .rule_sets
+      .get(name)
+      .ok_or_else(|| (at, format!("The RuleSet '{}' is not defined", name)))?;
+    if depth >= MAX_DEPTH {
+      return Err((at, format!("The RuleSet '{}' is inserted in a loop", name)));
+    }
+    if rule_set.parameters.len() != arguments.len() {
+      return Err((
+        at,
+        format!(
+          "The RuleSet '{}' takes {} arguments but {} were given",
+          name,
+          rule_set.parameters.len(),
+          arguments.len()
+        ),
+      ));
+    }
+    let mut body = rule_set.body.clone();
+    for (parameter, argument) in rule_set.parameters.iter().zip(arguments) {
+      body = body.replace(&format!("{{{}}}", parameter), argument);
+    }
+    let start = rule_set.body_at;
+    let tokens = tokenize(&body, start.file, start.line, start.column)?;
+    let mut raw = vec![];
+    let mut index = 0;
+    while index < tokens.len() {
+      let end = (index + 1..tokens.len())
+        .find(|i| tokens[*i].first)
+        .unwrap_or(tokens.len());
+      let head = &tokens[index];
+      if head.word() != Some("*") {
+        self.problems.push((
+          head.at,
+          format!("Expected a rule but found {}", head.describe()),
+        ));
+      } else {
+        raw.push(RawRule {
+          at: head.at,
+          indent: head.at.column - 1,
+          tokens: tokens[index + 1..end].to_vec(),
+        });
+      }
+      index = end;
+    }
+    // The rules of the RuleSet are indented relative to its first rule.
+    if let Some(base) = raw.iter().map(|r| r.indent).min() {
+      for rule in &mut raw {
+        rule.indent -= base;
+      }
+    }
+    self.read(kind, &raw, context, depth + 1, rules);
+    Ok(())
+  }
+}
+
+fn join(context: &str, path: &str) -> String {
+  match (context.is_empty(), path.is_empty()) {
+    (true, _) => path.to_string(),
+    (_, true) => context.to_string(),
+    _ => format!("{}.{}", context, path),
+  }
+}
+
+/// `min..max`, where either may be left out.
+fn cardinality(word: &str) -> Option<(Option<u64>, Option<String>)> {
+  let (min, max) = word.split_once("..")?;
+  let min = match min {
+    "" => None,
+    min => Some(min.parse().ok()?),
+  };
+  let max = match max {
+    "" => None,
+    "*" => Some("*".to_string()),
+    max => Some(max.parse::<u64>().ok()?.to_string()),
+  };
+  Some((min, max))
+}
+
+struct Cursor<'t> {
+  tokens: &'t [Token],
+  index: usize,
+  /// Where the rule starts, for problems at its end.
+  at: Location,
+}
+
+impl<'t> Cursor<'t> {
+  fn peek(&self) -> Option<&'t TokenKind> {
+    self.tokens.get(self.index).map(|t| &t.kind)
+  }
+
+  fn peek_word(&self) -> Option<&'t str> {
+    self.tokens.get(self.index).and_then(Token::word)
+  }
+
+  fn next(&mut self) -> Result<&'t Token, Problem> {
+    let token = self
+      .tokens
+      .get(self.index)
+      .ok_or_else(|| (self.at, "The rule ends too soon".to_string()))?;
+    self.index += 1;
+    Ok(token)
+  }
+
+  fn unexpected(&self, expected: &str) -> Problem {
+    match self.tokens.get(self.index) {
+      Some(token) => (
+        token.at,
+        format!("Expected {} but found {}", expected, token.describe()),
+      ),
+      None => (
+        self.at,
+        format!("Expected {} at the end of the rule", expected),
+      ),
+    }
+  }
+
+  fn end(&self) -> Result<(), Problem> {
+    match self.tokens.get(self.index) {
+      Some(token) => Err((
+        token.at,
+        format!("Unexpected {} at the end of the rule", token.describe()),
+      )),
+      None => Ok(()),
+    }
+  }
+
+  fn expect(&mut self, kind: TokenKind) -> Result<(), Problem> {
+    match self.peek() {
+      Some(found) if *found == kind => {
+        self.index += 1;
+        Ok(())
+      }
+      _ => Err(self.unexpected(match kind {
+        TokenKind::Open => "'('",
+        TokenKind::Close => "')'",
+        _ => "','",
+      })),
+    }
+  }
+
+  fn eat_word(&mut self, word: &str) -> bool {
+    let found = self.peek_word() == Some(word);
+    if found {
+      self.index += 1;
+    }
+    found
+  }
+
+  fn expect_word(&mut self, word: &str) -> Result<(), Problem> {
+    match self.eat_word(word) {
+      true => Ok(()),
+      false => Err(self.unexpected(&format!("'{}'", word))),
+    }
+  }
+
+  fn word(&mut self, expected: &str) -> Result<String, Problem> {
+    match self.peek_word() {
+      Some(word) => {
+        self.index += 1;
+        Ok(word.to_string())
+      }
+      None => Err(self.unexpected(expected)),
+    }
+  }
+
+  fn string(&mut self, expected: &str) -> Result<String, Problem> {
+    match self.peek() {
+      Some(TokenKind::String(text)) => {
+        self.index += 1;
+        Ok(text.clone())
+      }
+      _ => Err(self.unexpected(expected)),
+    }
+  }
+
+  fn optional_string(&mut self) -> Result<Option<String>, Problem> {
+    match self.peek() {
+      Some(TokenKind::String(_)) => self.string("a string").map(Some),
+      _ => Ok(None),
+    }
+  }
+
+  /// A name, alias or url referring to a definition.
+  fn reference(&mut self, expected: &str) -> Result<String, Problem> {
+    match self.peek() {
+      Some(TokenKind::Word(word)) => {
+        self.index += 1;
+        Ok(word.clone())
+      }
+      // Urls with fragments read as codes.
+      Some(TokenKind::Code(Some(system), code)) => {
+        self.index += 1;
+        Ok(format!("{}#{}", system, code))
+      }
+      _ => Err(self.unexpected(expected)),
+    }
+  }
+
+  fn flags(&mut self) -> Vec<String> {
+    let mut flags = vec![];
+    while let Some(flag) = self.peek_word().filter(|w| FLAGS.contains(w)) {
+      flags.push(flag.to_string());
+      self.index += 1;
+    }
+    flags
+  }
+
+  /// A value, and whether it is followed by `(exactly)`.
+  fn value(&mut self) -> Result<(FshValue, bool), Problem> {
+    let token = self.next()?;
+    let value = match &token.kind {
+      TokenKind::String(text) => FshValue::String(text.clone()),
+      TokenKind::Code(system, code) => FshValue::Code {
+        system: system.clone(),
+        code: code.clone(),
+        display: self.optional_string()?,
+      },
+      TokenKind::Word(word) if word == "true" || word == "false" => {
+        FshValue::Boolean(word == "true")
+      }
+      TokenKind::Word(word) if is_number(word) => match self.peek() {
+        Some(TokenKind::Unit(unit)) => {
+          let unit = unit.clone();
+          self.index += 1;
+          FshValue::Quantity {
+            value: word.clone(),
+            unit,
+            display: self.optional_string()?,
+          }
+        }
+        _ => FshValue::Number(word.clone()),
+      },
+      TokenKind::Word(word) if word == "Reference" || word == "Canonical" => {
+        self.expect(TokenKind::Open)?;
+        let target = self.reference("a target")?;
+        self.expect(TokenKind::Close)?;
+        match word.as_str() {
+          "Reference" => FshValue::Reference {
+            target,
+            display: self.optional_string()?,
+          },
+          _ => FshValue::Canonical(target),
+        }
+      }
+      TokenKind::Word(word) => FshValue::Name(word.clone()),
+      _ => {
+        self.index -= 1;
+        return Err(self.unexpected("a value"));
+      }
+    };
+    let exactly = matches!(
+      self.tokens.get(self.index..self.index + 3),
+      Some([open, exactly, close])
+        if open.kind == TokenKind::Open
+          && exactly.word() == Some("exactly")
+          && close.kind == TokenKind::Close
+    );
+    if exactly {
+      self.index += 3;
+    }
+    Ok((value, exactly))
+  }
+
+  /// `Type or Reference(A or B) or ...`.
+  fn types(&mut self) -> Result<Vec<TypeRef>, Problem> {
+    let mut types = vec![];
+    loop {
+      let name = self.reference("a type")?;
+      let mut targets = vec![];
+      if let Some(TokenKind::Open) = self.peek() {
+        self.index += 1;
+        targets.push(self.reference("a target")?);
+        while self.eat_word("or") || self.eat_word("|") {
+          targets.push(self.reference("a target")?);
+        }
+        self.expect(TokenKind::Close)?;
+      }
+      types.push(TypeRef { name, targets });
+      if !self.eat_word("or") {
+        return Ok(types);
+      }
+    }
+  }
+
+  /// An item of a `contains` rule: `name [named slice] min..max [flags]`.
+  fn item(&mut self) -> Result<Item, Problem> {
+    let at = self.tokens.get(self.index).map_or(self.at, |t| t.at);
+    let name = self.reference("a slice name")?;
+    let named = match self.eat_word("named") {
+      true => Some(self.word("a slice name")?),
+      false => None,
+    };
+    let (min, max) = self
+      .peek_word()
+      .and_then(cardinality)
+      .ok_or_else(|| self.unexpected("a cardinality"))?;
+    self.index += 1;
+    Ok(Item {
+      at,
+      name,
+      named,
+      min,
+      max,
+      flags: self.flags(),
+    })
+  }
+
+  /// The rule of a ValueSet that includes or excludes codes.
+  fn component(&mut self) -> Result<RuleKind, Problem> {
+    let include = !self.eat_word("exclude");
+    self.eat_word("include");
+    let mut concepts = vec![];
+    let mut system = None;
+    let mut value_sets = vec![];
+    let mut filters = vec![];
+    if self.eat_word("codes") {
+      self.expect_word("from")?;
+      loop {
+        match self.word("'system' or 'valueset'")?.as_str() {
+          "system" => system = Some(self.reference("a CodeSystem")?),
+          "valueset" => value_sets.push(self.reference("a ValueSet")?),
+          _ => {
+            self.index -= 1;
+            return Err(self.unexpected("'system' or 'valueset'"));
+          }
+        }
+        if !self.eat_word("and") {
+          break;
+        }
+      }
+      if self.eat_word("where") {
+        loop {
+          let property = self.word("a property")?;
+          let op = self.word("an operator")?;
+          let (value, _) = self.value()?;
+          filters.push(Filter {
+            property,
+            op,
+            value,
+          });
+          if !self.eat_word("and") {
+            break;
+          }
+        }
+      }
+    } else {
+      loop {
+        match self.peek().cloned() {
+          Some(TokenKind::Code(system, code)) => {
+            self.index += 1;
+            let display = self.optional_string()?;
+            concepts.push((Code { system, code }, display));
+          }
+          _ => return Err(self.unexpected("a code")),
+        }
+        if !self.eat_word("and") {
+          break;
+        }
+      }
+    }
+    Ok(RuleKind::Component {
+      include,
+      concepts,
+      system,
+      value_sets,
+      filters,
+    })
+  }
+}
+
+fn is_number(word: &str) -> bool {
+  let digits = word.strip_prefix('-').unwrap_or(word);
+  !digits.is_empty()
+    && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+    && digits.chars().filter(|c| *c == '.').count() <= 1
+    && !digits.starts_with('.')
+    && !digits.ends_with('.')
+}
@@ -0,0 +1,314 @@
+use super::instances::Target;
+use super::rules::{Code, Filter, FshValue, RuleKind};
+use super::{text, Exporter};
+use crate::model::CodeSystem::{
+  CodeSystem, CodeSystemBuilder, CodeSystemContent, CodeSystemStatus,
+};
+use crate::model::CodeSystem_Concept::CodeSystem_ConceptBuilder;
+use crate::model::ValueSet::{ValueSet, ValueSetBuilder, ValueSetStatus};
+use crate::model::ValueSet_Compose::ValueSet_ComposeBuilder;
+use crate::model::ValueSet_Concept::ValueSet_ConceptBuilder;
+use crate::model::ValueSet_Filter::{ValueSet_FilterBuilder, ValueSet_FilterOp};
+use crate::model::ValueSet_Include::ValueSet_IncludeBuilder;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::mem;
+
+/// An include or exclude of a ValueSet as its rules add to it.
+struct Component {
+  include: bool,
+  builder: ValueSet_IncludeBuilder,
+  concepts: Vec<ValueSet_ConceptBuilder>,
+  /// Whether it lists codes of its system, which later rules listing codes of
+  /// the same system add to.
+  listed: bool,
+}
+
+impl<'c> Exporter<'c> {
+  pub(super) fn export_value_set(&mut self, index: usize) -> Option<ValueSet<'static>> {
+    let entity = &self.entities[index];
+    let mut builder = ValueSetBuilder::new();
+    builder.value["resourceType"] = json!("ValueSet");
+    builder
+      .id(self.id(index))
+      .url(&self.entity_url(index)?)
+      .name(&entity.name)
+      .status(ValueSetStatus::Draft);
+    if let Some(title) = entity.metadata("Title").and_then(text) {
+      builder.title(title);
+    }
+    if let Some(description) = entity.metadata("Description").and_then(text) {
+      builder.description(description);
+    }
+
+    let mut components: Vec<Component> = vec![];
+    let mut carets = vec![];
+    for rule in &self.rules[index] {
+      let (include, concepts, system, value_sets, filters) = match &rule.kind {
+        RuleKind::Component {
+          include,
+          concepts,
+          system,
+          value_sets,
+          filters,
+        } => (*include, concepts, system, value_sets, filters),
+        RuleKind::Caret { caret, value } if rule.path.is_empty() => {
+          carets.push((rule.at, caret, value));
+          continue;
+        }
+        _ => {
+          self.error(rule.at, "This rule can't be used in a ValueSet");
+          continue;
+        }
+      };
+      if !concepts.is_empty() {
+        for (code, display) in concepts {
+          let system = match &code.system {
+            Some(system) => self.url(system),
+            None => {
+              self.error(
+                rule.at,
+                &format!(
+                  "The code #{} needs a system, like in SYSTEM#{}",
+                  code.code, code.code
+                ),
+              );
+              continue;
+            }
+          };
+          let mut concept = ValueSet_ConceptBuilder::new();
+          concept.code(&code.code);
+          if let Some(display) = display {
+            concept.display(display);
+          }
+          let same = components
+            .iter_mut()
+            .find(|c| c.listed && c.include == include && c.builder.value["system"] == system);
+          match same {
+            Some(component) => component.concepts.push(concept),
+            None => {
+              let mut builder = ValueSet_IncludeBuilder::new();
+              builder.system(&system);
+              components.push(Component {
+                include,
+                builder,
+                concepts: vec![concept],
+                listed: true,
+              });
+            }
+          }
+        }
+        continue;
+      }
+      let mut builder = ValueSet_IncludeBuilder::new();
+      if let Some(system) = system {
+        builder.system(&self.url(system));
+      }
+      if !value_sets.is_empty() {
+        let urls = value_sets.iter().map(|v| self.url(v)).collect::<Vec<_>>();
+        builder.value_set(urls.iter().map(String::as_str).collect());
+      }
+      if !filters.is_empty() {
+        let mut built = vec![];
+        for filter in filters {
+          match self.filter(filter) {
+            Ok(filter) => built.push(filter),
+            Err(message) => self.error(rule.at, &message),
+          }
+        }
+        let built = built.iter().map(|f| f.build()).collect();
+        builder.filter(built);
+      }
+      components.push(Component {
+        include,
+        builder,
+        concepts: vec![],
+        listed: false,
+      });
+    }
+
+    let mut includes = vec![];
+    let mut excludes = vec![];
+    for mut component in components {
+      if !component.concepts.is_empty() {
+        let concepts = component.concepts.iter().map(|c| c.build()).collect();
+        component.builder.concept(concepts);
+      }
+      match component.include {
+        true => includes.push(component.builder),
+        false => excludes.push(component.builder),
+      }
+    }
+    if !includes.is_empty() {
+      let mut compose = ValueSet_ComposeBuilder::new(includes.iter().map(|i| i.build()).collect());
+      if !excludes.is_empty() {
+        compose.exclude(excludes.iter().map(|e| e.build()).collect());
+      }
+      builder.compose(compose.build());
+    } else if !excludes.is_empty() {
+      self.error(
+        entity.at,
+        "A ValueSet that excludes codes has to include some",
+      );
+    }
+
+    let mut target = Target::new(mem::take(&mut builder.value), "ValueSet");
+    for (at, caret, value) in carets {
+      if let Err(message) = self.assign(&mut target, caret, Some(value)) {
+        self.error(at, &message);
+      }
+    }
+    Some(ValueSet {
+      value: Cow::Owned(target.json),
+    })
+  }
+
+  fn filter(&mut self, filter: &Filter) -> Result<ValueSet_FilterBuilder, String> {
+    let op = ValueSet_FilterOp::from_string(&filter.op)
+      .ok_or_else(|| format!("'{}' is not a filter operator", filter.op))?;
+    let value = match &filter.value {
+      FshValue::Code { code, .. } => code.clone(),
+      FshValue::String(text) | FshValue::Name(text) | FshValue::Number(text) => text.clone(),
+      FshValue::Boolean(value) => value.to_string(),
+      _ => {
+        return Err(format!(
+          "The value of the filter on {} can't be used",
+          filter.property
+        ))
+      }
+    };
+    let mut builder = ValueSet_FilterBuilder::new();
+    builder.property(&filter.property).op(op).value(&value);
+    Ok(builder)
+  }
+
+  pub(super) fn export_code_system(&mut self, index: usize) -> Option<CodeSystem<'static>> {
+    let entity = &self.entities[index];
+    let mut builder = CodeSystemBuilder::new();
+    builder.value["resourceType"] = json!("CodeSystem");
+    builder
+      .id(self.id(index))
+      .url(&self.entity_url(index)?)
+      .name(&entity.name)
+      .status(CodeSystemStatus::Draft)
+      .content(CodeSystemContent::Complete)
+      .case_sensitive(true);
+    if let Some(title) = entity.metadata("Title").and_then(text) {
+      builder.title(title);
+    }
+    if let Some(description) = entity.metadata("Description").and_then(text) {
+      builder.description(description);
+    }
+
+    // The concepts as JSON, so that caret rules can set their elements.
+    let mut concepts: Vec<Value> = vec![];
+    let mut count = 0;
+    let mut carets = vec![];
+    for rule in &self.rules[index] {
+      let result = match &rule.kind {
+        RuleKind::Concept {
+          codes,
+          display,
+          definition,
+        } => {
+          let mut concept = CodeSystem_ConceptBuilder::new();
+          let code = &codes[codes.len() - 1];
+          concept.code(&code.code);
+          if let Some(display) = display {
+            concept.display(display);
+          }
+          if let Some(definition) = definition {
+            concept.definition(definition);
+          }
+          let added = add_concept(&mut concepts, codes, concept.value);
+          count += added.is_ok() as u64;
+          added
+        }
+        RuleKind::ConceptCaret {
+          codes,
+          caret,
+          value,
+        } => match find_concept(&mut concepts, codes) {
+          Some(concept) => {
+            let mut target = Target::new(mem::take(concept), "CodeSystem.concept");
+            let assigned = self.assign(&mut target, caret, Some(value));
+            *concept = target.json;
+            assigned
+          }
+          None => Err(format!("The code {} is not defined", describe(codes))),
+        },
+        RuleKind::Caret { caret, value } if rule.path.is_empty() => {
+          carets.push((rule.at, caret, value));
+          Ok(())
+        }
+        _ => Err("This rule can't be used in a CodeSystem".to_string()),
+      };
+      if let Err(message) = result {
+        self.error(rule.at, &message);
+      }
+    }
+    if !concepts.is_empty() {
+      builder.value["concept"] = json!(concepts);
+    }
+    builder.count(count);
+
+    let mut target = Target::new(mem::take(&mut builder.value), "CodeSystem");
+    for (at, caret, value) in carets {
+      if let Err(message) = self.assign(&mut target, caret, Some(value)) {
+        self.error(at, &message);
+      }
+    }
+    Some(CodeSystem {
+      value: Cow::Owned(target.json),
+    })
+  }
+}
+
+/// Adds a concept under the concept its ancestor codes lead to.
+fn add_concept(concepts: &mut Vec<Value>, codes: &[Code], concept: Value) -> Result<(), String> {
+  if codes.iter().any(|c| c.system.is_some()) {
+    return Err(
+      "The concepts of a CodeSystem are written without a system, like #code".to_string(),
+    );
+  }
+  let (code, ancestors) = codes.split_last().expect("a concept has a code");
+  let siblings = match ancestors {
+    [] => concepts,
+    ancestors => {
+      let parent = find_concept(concepts, ancestors)
+        .ok_or_else(|| format!("The code {} is not defined", describe(ancestors)))?;
+      if parent.get("concept").is_none() {
+        parent["concept"] = json!([]);
+      }
+      parent["concept"]
+        .as_array_mut()
+        .expect("a list of concepts")
+    }
+  };
+  if siblings.iter().any(|c| c["code"] == code.code.as_str()) {
+    return Err(format!("The code #{} is already defined", code.code));
+  }
+  siblings.push(concept);
+  Ok(())
+}
+
+/// The concept that a code and the codes of its ancestors lead to.
+fn find_concept<'v>(concepts: &'v mut [Value], codes: &[Code]) -> Option<&'v mut Value> {
+  let (first, rest) = codes.split_first()?;
+  let concept = concepts
+    .iter_mut()
+    .find(|c| c["code"] == first.code.as_str())?;
+  match rest {
+    [] => Some(concept),
+    rest => find_concept(concept["concept"].as_array_mut()?, rest),
+  }
+}
+
+fn describe(codes: &[Code]) -> String {
+  codes
+    .iter()
+    .map(|c| format!("#{}", c.code))
+    .collect::<Vec<_>>()
+    .join(" ")
+}
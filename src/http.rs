@@ -0,0 +1,500 @@
+//! HTTP/1.1 messages, as the server reads and writes them and the client
+//! sends and receives them. Each uses only some of what's here.
+#![cfg_attr(not(all(feature = "server", feature = "client")), allow(dead_code))]
+
+use crate::datetime::{civil_from_days, days_from_civil};
+use std::io::{self, BufRead, Read, Write};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+  "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Messages with bodies larger than this are refused.
+pub(crate) const MAX_BODY: usize = 64 * 1024 * 1024;
+
+/// Messages with longer lines in their head, or more headers, are refused.
+pub(crate) const MAX_LINE: usize = 8 * 1024;
+pub(crate) const MAX_HEADERS: usize = 100;
+
+/// An HTTP request, as read from a connection or passed in by another HTTP
+/// stack that embeds the server, or as sent by the client.
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+  pub method: String,
+  /// The path and query, e.g. `/fhir/Patient?name=smith`, or the absolute
+  /// URL in requests the client sends.
+  pub target: String,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl Request {
+  pub fn new(method: &str, target: &str) -> Request {
+    Request {
+      method: method.to_string(),
+      target: target.to_string(),
+      ..Request::default()
+    }
+  }
+
+  pub fn header<'a>(&'a mut self, name: &str, value: &str) -> &'a mut Request {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  pub fn body(&mut self, body: Vec<u8>) -> &mut Request {
+    self.body = body;
+    self
+  }
+
+  /// The value of a header, by case-insensitive name.
+  pub fn get(&self, name: &str) -> Option<&str> {
+    find(&self.headers, name)
+  }
+}
+
+/// An HTTP response.
+#[derive(Debug, Clone, Default)]
+pub struct Response {
+  pub status: u16,
+  pub headers: Vec<(String, String)>,
+  pub body: Vec<u8>,
+}
+
+impl Response {
+  pub fn new(status: u16) -> Response {
+    Response {
+      status,
+      ..Response::default()
+    }
+  }
+
+  pub fn header<'a>(&'a mut self, name: &str, value: &str) -> &'a mut Response {
+    self.headers.push((name.to_string(), value.to_string()));
+    self
+  }
+
+  /// The value of a header, by case-insensitive name.
+  pub fn get(&self, name: &str) -> Option<&str> {
+    find(&self.headers, name)
+  }
+}
+
+fn find<'h>(headers: &'h [(String, String)], name: &str) -> Option<&'h str> {
+  headers
+    .iter()
+    .find(|(key, _)| key.eq_ignore_ascii_case(name))
+    .map(|(_, value)| value.as_str())
+}
+
+/// Reads a request from a connection, or `None` when the client has closed
+/// it. Bodies may be sent with `Content-Length` or chunked.
+pub(crate) fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Request>> {
+  let mut line = String::new();
+  // Clients may send blank lines between requests.
+  while line.trim().is_empty() {
+    line.clear();
+    if read_line(reader, &mut line)? == 0 {
+      return Ok(None);
+    }
+  }
+  let mut parts = line.split_whitespace();
+  let (method, target) = match (parts.next(), parts.next()) {
+    (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+    _ => return Err(invalid("Malformed request line")),
+  };
+  let mut request = Request::new(&method, &target);
+  request.headers = read_headers(reader)?;
+  request.body = read_body(reader, &request.headers, false)?;
+  Ok(Some(request))
+}
+
+/// Reads the status line and headers of the response to a request the
+/// client sent, leaving the body to be read by its [`Framing`].
+pub(crate) fn read_response_head<R: BufRead>(reader: &mut R) -> io::Result<Response> {
+  let mut line = String::new();
+  read_line(reader, &mut line)?;
+  let status = match line.split_whitespace().collect::<Vec<_>>()[..] {
+    [version, status, ..] if version.starts_with("HTTP/") => status
+      .parse::<u16>()
+      .map_err(|_| invalid("Malformed status"))?,
+    _ => return Err(invalid("Malformed status line")),
+  };
+  let mut response = Response::new(status);
+  response.headers = read_headers(reader)?;
+  Ok(response)
+}
+
+/// How the body of a message is delimited.
+pub(crate) enum Framing {
+  Empty,
+  Length(usize),
+  Chunked,
+  /// Up to the end of the connection, as a response with no length is.
+  ToEnd,
+}
+
+/// How the body of a message with these headers is delimited. The client
+/// closes connections after each response, so `to_end` is set for responses
+/// whose status lets them have a body.
+pub(crate) fn framing(headers: &[(String, String)], to_end: bool) -> io::Result<Framing> {
+  if find(headers, "Transfer-Encoding")
+    .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+  {
+    return Ok(Framing::Chunked);
+  }
+  match find(headers, "Content-Length") {
+    Some(length) => {
+      let length = length
+        .parse::<usize>()
+        .map_err(|_| invalid("Malformed Content-Length"))?;
+      match length > MAX_BODY {
+        true => Err(invalid("Body too large")),
+        false => Ok(Framing::Length(length)),
+      }
+    }
+    None if to_end => Ok(Framing::ToEnd),
+    None => Ok(Framing::Empty),
+  }
+}
+
+/// Whether a response with a status can have a body.
+pub(crate) fn has_body(status: u16) -> bool {
+  !(status < 200 || status == 204 || status == 304)
+}
+
+fn read_headers<R: BufRead>(reader: &mut R) -> io::Result<Vec<(String, String)>> {
+  let mut headers = vec![];
+  loop {
+    let mut line = String::new();
+    if read_line(reader, &mut line)? == 0 {
+      return Err(invalid("Connection closed in the headers"));
+    }
+    let line = line.trim_end();
+    if line.is_empty() {
+      return Ok(headers);
+    }
+    if headers.len() == MAX_HEADERS {
+      return Err(invalid("Too many headers"));
+    }
+    match line.split_once(':') {
+      Some((name, value)) => headers.push((name.trim().to_string(), value.trim().to_string())),
+      None => return Err(invalid("Malformed header")),
+    }
+  }
+}
+
+/// Reads a body sent with `Content-Length` or chunked, or else, when `to_end`,
+/// up to the end of the connection.
+pub(crate) fn read_body<R: BufRead>(
+  reader: &mut R,
+  headers: &[(String, String)],
+  to_end: bool,
+) -> io::Result<Vec<u8>> {
+  let mut body = vec![];
+  match framing(headers, to_end)? {
+    Framing::Empty => {}
+    Framing::Length(length) => {
+      body.resize(length, 0);
+      reader.read_exact(&mut body)?;
+    }
+    Framing::Chunked => return read_chunked(reader),
+    Framing::ToEnd => {
+      reader.take(MAX_BODY as u64 + 1).read_to_end(&mut body)?;
+      if body.len() > MAX_BODY {
+        return Err(invalid("Body too large"));
+      }
+    }
+  }
+  Ok(body)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+  let mut body = vec![];
+  loop {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let size = chunk_size(&line, body.len())?;
+    let start = body.len();
+    body.resize(start + size, 0);
+    reader.read_exact(&mut body[start..])?;
+    // The last chunk is followed by trailers, and the others by a line break.
+    if size == 0 {
+      read_headers(reader)?;
+      return Ok(body);
+    }
+    read_line(reader, &mut String::new())?;
+  }
+}
+
+/// The size of a chunk from the line it starts with, refused when it would
+/// make a body with `read` bytes already too large.
+pub(crate) fn chunk_size(line: &str, read: usize) -> io::Result<usize> {
+  let size = line.trim().split(';').next().unwrap_or_default();
+  let size = usize::from_str_radix(size, 16).map_err(|_| invalid("Malformed chunk size"))?;
+  // Compared this way round so a huge size can't overflow.
+  match size > MAX_BODY - read {
+    true => Err(invalid("Body too large")),
+    false => Ok(size),
+  }
+}
+
+/// Reads a line of a message's head, which may be no longer than `MAX_LINE`.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+  let read = reader.take(MAX_LINE as u64 + 1).read_line(line)?;
+  match read > MAX_LINE {
+    true => Err(invalid("Line too long")),
+    false => Ok(read),
+  }
+}
+
+pub(crate) fn write_response<W: Write>(
+  writer: &mut W,
+  response: &Response,
+  close: bool,
+) -> io::Result<()> {
+  let mut head = format!(
+    "HTTP/1.1 {} {}\r\n",
+    response.status,
+    reason(response.status)
+  );
+  for (name, value) in &response.headers {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+  if close {
+    head.push_str("Connection: close\r\n");
+  }
+  head.push_str("\r\n");
+  writer.write_all(head.as_bytes())?;
+  writer.write_all(&response.body)?;
+  writer.flush()
+}
+
+/// Writes a request the client sends, with the absolute URL in its target
+/// split into the path it's sent with and the `Host` header.
+pub(crate) fn write_request<W: Write>(writer: &mut W, request: &Request) -> io::Result<()> {
+  let (host, path) = match split_url(&request.target) {
+    Some((_, host, path)) => (host, path),
+    None => return Err(invalid("Requests must have an absolute http URL")),
+  };
+  let mut head = format!("{} {} HTTP/1.1\r\nHost: {}\r\n", request.method, path, host);
+  for (name, value) in &request.headers {
+    head.push_str(&format!("{}: {}\r\n", name, value));
+  }
+  head.push_str(&format!(
+    "Content-Length: {}\r\nConnection: close\r\n\r\n",
+    request.body.len()
+  ));
+  writer.write_all(head.as_bytes())?;
+  writer.write_all(&request.body)?;
+  writer.flush()
+}
+
+/// Splits an absolute URL into its scheme, authority and path with the query,
+/// e.g. `http`, `example.org:8080` and `/fhir/Patient?name=smith`.
+pub(crate) fn split_url(url: &str) -> Option<(&str, &str, &str)> {
+  let (scheme, rest) = url.split_once("://")?;
+  let (authority, path) = match rest.find('/') {
+    Some(index) => (&rest[..index], &rest[index..]),
+    None => (rest, "/"),
+  };
+  match authority.is_empty() {
+    true => None,
+    false => Some((scheme, authority, path)),
+  }
+}
+
+/// The reason phrase for a status, which is also used in
+/// `Bundle.entry.response.status`.
+pub(crate) fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    201 => "Created",
+    204 => "No Content",
+    304 => "Not Modified",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    403 => "Forbidden",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    406 => "Not Acceptable",
+    409 => "Conflict",
+    410 => "Gone",
+    412 => "Precondition Failed",
+    415 => "Unsupported Media Type",
+    422 => "Unprocessable Entity",
+    428 => "Precondition Required",
+    500 => "Internal Server Error",
+    501 => "Not Implemented",
+    503 => "Service Unavailable",
+    _ => "",
+  }
+}
+
+/// Formats seconds since the Unix epoch as an HTTP date, e.g.
+/// `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub(crate) fn format_http_date(seconds: i64) -> String {
+  let days = seconds.div_euclid(86_400);
+  let secs_of_day = seconds.rem_euclid(86_400);
+  let (year, month, day) = civil_from_days(days);
+  format!(
+    "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+    DAYS[days.rem_euclid(7) as usize],
+    day,
+    MONTHS[month as usize - 1],
+    year,
+    secs_of_day / 3600,
+    secs_of_day % 3600 / 60,
+    secs_of_day % 60
+  )
+}
+
+/// Parses an HTTP date in the preferred format into seconds since the Unix
+/// epoch.
+pub(crate) fn parse_http_date(date: &str) -> Option<i64> {
+  let parts = date.split_whitespace().collect::<Vec<_>>();
+  let (day, month, year, time) = match &parts[..] {
+    [_, day, month, year, time, "GMT"] => (day, month, year, time),
+    _ => return None,
+  };
+  let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+  let time = time
+    .split(':')
+    .map(|part| part.parse::<i64>().ok())
+    .collect::<Option<Vec<_>>>()?;
+  match &time[..] {
+    [hours, minutes, seconds] => {
+      let days = days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+      Some(days * 86_400 + hours * 3600 + minutes * 60 + seconds)
+    }
+    _ => None,
+  }
+}
+
+pub(crate) fn invalid(message: &str) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Answers requests on a local port with a handler, standing in for a server
+/// in tests, and gives its address.
+#[cfg(test)]
+pub(crate) fn stand_in<F>(handler: F) -> std::net::SocketAddr
+where
+  F: Fn(&Request) -> Response + Send + 'static,
+{
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  let address = listener.local_addr().unwrap();
+  std::thread::spawn(move || {
+    for stream in listener.incoming().flatten() {
+      let mut reader = io::BufReader::new(&stream);
+      if let Ok(Some(request)) = read_request(&mut reader) {
+        let _ = write_response(&mut &stream, &handler(&request), true);
+      }
+    }
+  });
+  address
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn read_response(raw: &str) -> io::Result<Response> {
+    let mut reader = raw.as_bytes();
+    let mut response = read_response_head(&mut reader)?;
+    if has_body(response.status) {
+      response.body = read_body(&mut reader, &response.headers, true)?;
+    }
+    Ok(response)
+  }
+
+  #[test]
+  fn test_http() {
+    let raw =
+      "POST /fhir/Patient HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+               4\r\n{\"a\"\r\n3\r\n:1}\r\n0\r\n\r\n";
+    let request = read_request(&mut raw.as_bytes()).unwrap().unwrap();
+    assert_eq!(request.target, "/fhir/Patient");
+    assert_eq!(request.get("host"), Some("localhost"));
+    assert_eq!(request.body, b"{\"a\":1}");
+
+    assert_eq!(
+      format_http_date(784_111_777),
+      "Sun, 06 Nov 1994 08:49:37 GMT"
+    );
+    assert_eq!(
+      parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+      Some(784_111_777)
+    );
+    assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+
+    let mut request = Request::new("GET", "http://example.org:8080/fhir/Patient?name=smith");
+    request.header("Accept", "application/fhir+json");
+    let mut written = vec![];
+    write_request(&mut written, &request).unwrap();
+    let sent = read_request(&mut &written[..]).unwrap().unwrap();
+    assert_eq!(sent.target, "/fhir/Patient?name=smith");
+    assert_eq!(sent.get("Host"), Some("example.org:8080"));
+    assert_eq!(sent.get("Connection"), Some("close"));
+    assert!(write_request(&mut vec![], &Request::new("GET", "/fhir/Patient")).is_err());
+
+    let raw = "HTTP/1.1 200 OK\r\nContent-Type: application/fhir+json\r\n\r\n{\"a\":1}";
+    let response = read_response(raw).unwrap();
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, b"{\"a\":1}");
+    let raw = "HTTP/1.1 304 Not Modified\r\nETag: W/\"1\"\r\n\r\n";
+    assert!(read_response(raw).unwrap().body.is_empty());
+  }
+
+  #[test]
+  fn test_http_limits() {
+    let refused = |raw: &str| {
+      let error = read_request(&mut raw.as_bytes()).unwrap_err();
+      (error.kind(), error.to_string())
+    };
+    let head = "POST /fhir/Patient HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+    assert_eq!(
+      refused(&format!("{}4\r\n{{}}{{}}\r\nffffffffffffffff\r\n", head)),
+      (io::ErrorKind::InvalidData, "Body too large".to_string())
+    );
+    assert_eq!(
+      refused(&format!("{}ffffffffffffffff\r\n", head)).1,
+      "Body too large"
+    );
+    let raw = format!(
+      "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n1\r\n{{\r\n{:x}\r\n",
+      usize::MAX
+    );
+    assert_eq!(
+      read_response(&raw).unwrap_err().to_string(),
+      "Body too large"
+    );
+
+    let long = format!(
+      "GET /fhir/Patient?name={} HTTP/1.1\r\n\r\n",
+      "a".repeat(MAX_LINE)
+    );
+    assert_eq!(refused(&long).1, "Line too long");
+    let many = format!(
+      "GET /fhir HTTP/1.1\r\n{}\r\n",
+      "X-A: b\r\n".repeat(MAX_HEADERS + 1)
+    );
+    assert_eq!(refused(&many).1, "Too many headers");
+    let trailers = format!("{}0\r\n{}\r\n", head, "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+    assert_eq!(refused(&trailers).1, "Too many headers");
+    let most = format!(
+      "GET /fhir HTTP/1.1\r\n{}\r\n",
+      "X-A: b\r\n".repeat(MAX_HEADERS)
+    );
+    assert_eq!(
+      read_request(&mut most.as_bytes())
+        .unwrap()
+        .unwrap()
+        .headers
+        .len(),
+      MAX_HEADERS
+    );
+  }
+}
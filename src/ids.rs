@@ -0,0 +1,43 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Generates a random (version 4) UUID in its lowercase hyphenated form. The
+/// randomness comes from the standard library's randomly keyed hasher, which is
+/// plenty for resource ids and keeps the crate free of extra dependencies.
+pub fn new_uuid() -> String {
+  let mut bytes = [0u8; 16];
+  let nanos = SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|d| d.as_nanos())
+    .unwrap_or(0);
+  for (index, chunk) in bytes.chunks_mut(8).enumerate() {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(index);
+    hasher.write_u128(nanos);
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+  }
+  bytes[6] = (bytes[6] & 0x0f) | 0x40;
+  bytes[8] = (bytes[8] & 0x3f) | 0x80;
+  let hex = bytes
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect::<String>();
+  format!(
+    "{}-{}-{}-{}-{}",
+    &hex[0..8],
+    &hex[8..12],
+    &hex[12..16],
+    &hex[16..20],
+    &hex[20..32]
+  )
+}
+
+/// A `urn:uuid:` URI suitable for `Bundle.entry.fullUrl` of a new resource.
+pub fn new_uuid_urn() -> String {
+  format!("urn:uuid:{}", new_uuid())
+}
@@ -1,7 +1,34 @@
 extern crate serde;
 extern crate serde_json;
 
+pub mod capabilities;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod datetime;
+pub mod definitions;
+pub mod document;
+pub mod fhirpath;
+pub mod fsh;
+#[cfg(any(feature = "client", feature = "server"))]
+mod http;
+pub mod ids;
+pub mod mapping;
+pub mod messaging;
 pub mod model;
+pub mod outcome;
+pub mod repository;
+pub mod resolve;
+pub mod search;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "smart")]
+pub mod smart;
+pub mod snapshot;
+pub mod terminology;
+pub mod transaction;
+pub mod ucum;
+mod util;
+pub mod validation;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,1292 @@
+//! The text form of StructureMaps, the FHIR Mapping Language
+//! (http://hl7.org/fhir/mapping-language.html). [`parse`] compiles it into a
+//! StructureMap and [`print`] renders a StructureMap back as text.
+//!
+//! ```
+//! use fhir_rs::mapping;
+//!
+//! let map = mapping::parse(
+//!   r#"map "http://example.org/StructureMap/names" = "Names"
+//!
+//!   group names(source src : Patient, target tgt : Person) {
+//!     src.name as n -> tgt.name = n "name";
+//!   }"#,
+//! )
+//! .unwrap();
+//! assert_eq!(map.to_json()["group"][0]["rule"][0]["name"], "name");
+//! assert!(mapping::print(&map).contains("src.name as n -> tgt.name = n \"name\";"));
+//! ```
+//!
+//! Besides the url and name in the `map` line, the only metadata the language
+//! carries is documentation: `//` comments before the `map` line become the
+//! description, and comments before a `uses`, a group or a rule become its
+//! documentation. The printer leaves out what the text cannot express, such as
+//! the other metadata, input documentation and complex default values.
+
+use crate::fhirpath::Expression;
+use crate::model::StructureMap::{StructureMap, StructureMapBuilder, StructureMapStatus};
+use crate::model::StructureMap_Dependent::StructureMap_DependentBuilder;
+use crate::model::StructureMap_Group::{StructureMap_GroupBuilder, StructureMap_GroupTypeMode};
+use crate::model::StructureMap_Input::{StructureMap_InputBuilder, StructureMap_InputMode};
+use crate::model::StructureMap_Parameter::StructureMap_ParameterBuilder;
+use crate::model::StructureMap_Rule::StructureMap_RuleBuilder;
+use crate::model::StructureMap_Source::{StructureMap_SourceBuilder, StructureMap_SourceListMode};
+use crate::model::StructureMap_Structure::{
+  StructureMap_StructureBuilder, StructureMap_StructureMode,
+};
+use crate::model::StructureMap_Target::{
+  StructureMap_TargetBuilder, StructureMap_TargetContextType, StructureMap_TargetTransform,
+};
+use crate::util::array;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::fmt;
+
+const SOURCE_LIST_MODES: &[&str] = &["first", "not_first", "last", "not_last", "only_one"];
+const TARGET_LIST_MODES: &[&str] = &["first", "share", "last", "collate"];
+
+/// A syntax error, with the line and column (both from 1) where it was found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+  pub message: String,
+  pub line: usize,
+  pub column: usize,
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} at line {}, column {}",
+      self.message, self.line, self.column
+    )
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Compiles mapping language text into a StructureMap.
+pub fn parse(text: &str) -> Result<StructureMap<'static>, ParseError> {
+  let mut parser = Parser {
+    chars: text.chars().collect(),
+    position: 0,
+    comments: vec![],
+  };
+  let builder = parser.map()?;
+  Ok(StructureMap {
+    value: Cow::Owned(builder.value),
+  })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  /// A name, which may be a keyword unless it was written in backticks.
+  Name(String, bool),
+  /// A string in single or double quotes.
+  String(String),
+  Number(String),
+  Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+  "->", "..", "<<", ">>", "(", ")", "{", "}", ",", ";", ":", ".", "=", "*", "-", "+",
+];
+
+struct Parser {
+  chars: Vec<char>,
+  position: usize,
+  /// The `//` comments since the last token, which document what follows.
+  comments: Vec<String>,
+}
+
+impl Parser {
+  fn error(&self, message: &str, position: usize) -> ParseError {
+    let before = &self.chars[..position.min(self.chars.len())];
+    let line_start = before.iter().rposition(|c| *c == '\n').map_or(0, |p| p + 1);
+    ParseError {
+      message: message.to_string(),
+      line: before.iter().filter(|c| **c == '\n').count() + 1,
+      column: position - line_start + 1,
+    }
+  }
+
+  /// Moves past whitespace and comments, keeping the text of line comments.
+  fn skip(&mut self) -> Result<(), ParseError> {
+    loop {
+      match self.chars.get(self.position) {
+        Some(c) if c.is_whitespace() => self.position += 1,
+        Some('/') if self.chars.get(self.position + 1) == Some(&'/') => {
+          let start = self.position + 2;
+          while self.position < self.chars.len() && self.chars[self.position] != '\n' {
+            self.position += 1;
+          }
+          let comment = self.chars[start..self.position].iter().collect::<String>();
+          let comment = comment.trim_end();
+          self
+            .comments
+            .push(comment.strip_prefix(' ').unwrap_or(comment).to_string());
+        }
+        Some('/') if self.chars.get(self.position + 1) == Some(&'*') => {
+          let start = self.position;
+          self.position += 2;
+          while self.position < self.chars.len()
+            && !(self.chars[self.position] == '*'
+              && self.chars.get(self.position + 1) == Some(&'/'))
+          {
+            self.position += 1;
+          }
+          if self.position >= self.chars.len() {
+            return Err(self.error("Unterminated comment", start));
+          }
+          self.position += 2;
+        }
+        _ => return Ok(()),
+      }
+    }
+  }
+
+  /// Reads the token at the current position, returning it with where it ends.
+  fn lex(&self) -> Result<Option<(Token, usize)>, ParseError> {
+    let chars = &self.chars;
+    let start = self.position;
+    let c = match chars.get(start) {
+      Some(c) => *c,
+      None => return Ok(None),
+    };
+    let mut position = start;
+    let token = if c.is_alphabetic() || c == '_' {
+      while position < chars.len() && (chars[position].is_alphanumeric() || chars[position] == '_')
+      {
+        position += 1;
+      }
+      Token::Name(chars[start..position].iter().collect(), false)
+    } else if c == '`' {
+      Token::Name(self.quoted(&mut position)?, true)
+    } else if c == '\'' || c == '"' {
+      Token::String(self.quoted(&mut position)?)
+    } else if c.is_ascii_digit() {
+      while position < chars.len() && chars[position].is_ascii_digit() {
+        position += 1;
+      }
+      if chars.get(position) == Some(&'.')
+        && chars.get(position + 1).is_some_and(char::is_ascii_digit)
+      {
+        position += 1;
+        while position < chars.len() && chars[position].is_ascii_digit() {
+          position += 1;
+        }
+      }
+      Token::Number(chars[start..position].iter().collect())
+    } else {
+      let symbol = SYMBOLS.iter().find(|symbol| {
+        symbol
+          .chars()
+          .enumerate()
+          .all(|(i, s)| chars.get(start + i) == Some(&s))
+      });
+      match symbol {
+        Some(symbol) => {
+          position += symbol.len();
+          Token::Symbol(symbol)
+        }
+        None => {
+          return Err(self.error(&format!("Unexpected character '{}'", c), start));
+        }
+      }
+    };
+    Ok(Some((token, position)))
+  }
+
+  /// Reads a string or backticked name, unescaping its contents.
+  fn quoted(&self, position: &mut usize) -> Result<String, ParseError> {
+    let start = *position;
+    let quote = self.chars[start];
+    *position += 1;
+    let mut text = String::new();
+    loop {
+      match self.chars.get(*position) {
+        None => return Err(self.error("Unterminated string", start)),
+        Some(c) if *c == quote => {
+          *position += 1;
+          return Ok(text);
+        }
+        Some('\\') => {
+          let escaped = self
+            .chars
+            .get(*position + 1)
+            .ok_or_else(|| self.error("Unterminated string", start))?;
+          *position += 2;
+          match escaped {
+            'n' => text.push('\n'),
+            'r' => text.push('\r'),
+            't' => text.push('\t'),
+            'f' => text.push('\u{c}'),
+            'u' => {
+              let code = self
+                .chars
+                .get(*position..*position + 4)
+                .map(|h| h.iter().collect::<String>())
+                .and_then(|hex| u32::from_str_radix(&hex, 16).ok())
+                .and_then(std::char::from_u32)
+                .ok_or_else(|| self.error("Invalid unicode escape", *position))?;
+              text.push(code);
+              *position += 4;
+            }
+            other => text.push(*other),
+          }
+        }
+        Some(c) => {
+          text.push(*c);
+          *position += 1;
+        }
+      }
+    }
+  }
+
+  fn peek(&mut self) -> Result<Option<Token>, ParseError> {
+    self.skip()?;
+    Ok(self.lex()?.map(|(token, _)| token))
+  }
+
+  /// Consumes the next token, returning it with where it started.
+  fn next(&mut self, expected: &str) -> Result<(Token, usize), ParseError> {
+    self.skip()?;
+    let start = self.position;
+    match self.lex()? {
+      Some((token, end)) => {
+        self.position = end;
+        self.comments.clear();
+        Ok((token, start))
+      }
+      None => Err(self.error(&format!("Expected {} but found the end", expected), start)),
+    }
+  }
+
+  fn unexpected<T>(&self, expected: &str, token: &Token, start: usize) -> Result<T, ParseError> {
+    Err(self.error(
+      &format!("Expected {} but found {}", expected, describe(token)),
+      start,
+    ))
+  }
+
+  fn is(&mut self, symbol: &str) -> Result<bool, ParseError> {
+    Ok(matches!(self.peek()?, Some(Token::Symbol(s)) if s == symbol))
+  }
+
+  fn eat(&mut self, symbol: &str) -> Result<bool, ParseError> {
+    let found = self.is(symbol)?;
+    if found {
+      self.next(symbol)?;
+    }
+    Ok(found)
+  }
+
+  fn expect(&mut self, symbol: &str) -> Result<(), ParseError> {
+    let expected = format!("'{}'", symbol);
+    match self.next(&expected)? {
+      (Token::Symbol(s), _) if s == symbol => Ok(()),
+      (token, start) => self.unexpected(&expected, &token, start),
+    }
+  }
+
+  /// The keyword at the current position, if it is one of `words`.
+  fn keyword(&mut self, words: &[&str]) -> Result<Option<String>, ParseError> {
+    Ok(match self.peek()? {
+      Some(Token::Name(name, false)) if words.contains(&name.as_str()) => Some(name),
+      _ => None,
+    })
+  }
+
+  fn eat_keyword(&mut self, word: &str) -> Result<bool, ParseError> {
+    let found = self.keyword(&[word])?.is_some();
+    if found {
+      self.next(word)?;
+    }
+    Ok(found)
+  }
+
+  fn expect_keyword(&mut self, word: &str) -> Result<(), ParseError> {
+    let expected = format!("'{}'", word);
+    match self.next(&expected)? {
+      (Token::Name(name, false), _) if name == word => Ok(()),
+      (token, start) => self.unexpected(&expected, &token, start),
+    }
+  }
+
+  fn name(&mut self, expected: &str) -> Result<String, ParseError> {
+    match self.next(expected)? {
+      (Token::Name(name, _), _) => Ok(name),
+      (token, start) => self.unexpected(expected, &token, start),
+    }
+  }
+
+  fn string(&mut self, expected: &str) -> Result<String, ParseError> {
+    match self.next(expected)? {
+      (Token::String(text), _) => Ok(text),
+      (token, start) => self.unexpected(expected, &token, start),
+    }
+  }
+
+  /// A name, or a string for names that are not identifiers.
+  fn name_or_string(&mut self, expected: &str) -> Result<String, ParseError> {
+    match self.next(expected)? {
+      (Token::Name(text, _), _) | (Token::String(text), _) => Ok(text),
+      (token, start) => self.unexpected(expected, &token, start),
+    }
+  }
+
+  /// A name followed by any number of `.name`s.
+  fn path(&mut self) -> Result<(String, Option<String>), ParseError> {
+    let context = self.name("a name")?;
+    let mut element = vec![];
+    while self.eat(".")? {
+      element.push(self.name("an element name")?);
+    }
+    Ok((context, Some(element.join(".")).filter(|e| !e.is_empty())))
+  }
+
+  /// The comments before the next token, taken as its documentation.
+  fn documentation(&mut self) -> Result<Option<String>, ParseError> {
+    self.skip()?;
+    let comments = std::mem::take(&mut self.comments);
+    Ok(Some(comments.join("\n")).filter(|_| !comments.is_empty()))
+  }
+
+  fn map(&mut self) -> Result<StructureMapBuilder, ParseError> {
+    let description = self.documentation()?;
+    self.expect_keyword("map")?;
+    let url = self.string("the map's url")?;
+    self.expect("=")?;
+    let name = self.name_or_string("the map's name")?;
+    let mut structures = vec![];
+    let mut imports = vec![];
+    let mut groups = vec![];
+    loop {
+      let documentation = self.documentation()?;
+      match self.keyword(&["uses", "imports", "group"])?.as_deref() {
+        Some("uses") => structures.push(self.structure(documentation)?),
+        Some("imports") => {
+          self.next("imports")?;
+          imports.push(self.string("the url of an imported map")?);
+        }
+        Some(_) => groups.push(self.group(documentation)?),
+        None => match self.next("'group'") {
+          Ok((token, start)) => {
+            return self.unexpected("'uses', 'imports' or 'group'", &token, start);
+          }
+          Err(_) if !groups.is_empty() => break,
+          Err(error) => return Err(error),
+        },
+      }
+    }
+    let mut builder = StructureMapBuilder::new(groups.iter().map(|g| g.build()).collect());
+    builder.value["resourceType"] = json!("StructureMap");
+    builder
+      .url(&url)
+      .name(&name)
+      .status(StructureMapStatus::Draft);
+    if let Some(description) = description {
+      builder.description(&description);
+    }
+    if !structures.is_empty() {
+      builder.structure(structures.iter().map(|s| s.build()).collect());
+    }
+    if !imports.is_empty() {
+      builder.import(imports.iter().map(String::as_str).collect());
+    }
+    Ok(builder)
+  }
+
+  fn structure(
+    &mut self,
+    documentation: Option<String>,
+  ) -> Result<StructureMap_StructureBuilder, ParseError> {
+    self.expect_keyword("uses")?;
+    let url = self.string("the url of a structure")?;
+    let mut builder = StructureMap_StructureBuilder::new(&url);
+    if self.eat_keyword("alias")? {
+      builder.alias(&self.name_or_string("an alias")?);
+    }
+    self.expect_keyword("as")?;
+    let (token, start) = self.next("a mode")?;
+    let mode = match &token {
+      Token::Name(mode, false) => StructureMap_StructureMode::from_string(mode),
+      _ => None,
+    };
+    match mode {
+      Some(mode) => builder.mode(mode),
+      None => return self.unexpected("'source', 'queried', 'target' or 'produced'", &token, start),
+    };
+    if let Some(documentation) = documentation {
+      builder.documentation(&documentation);
+    }
+    Ok(builder)
+  }
+
+  fn group(
+    &mut self,
+    documentation: Option<String>,
+  ) -> Result<StructureMap_GroupBuilder, ParseError> {
+    self.expect_keyword("group")?;
+    let name = self.name("the group's name")?;
+    self.expect("(")?;
+    let mut inputs = vec![self.input()?];
+    while self.eat(",")? {
+      inputs.push(self.input()?);
+    }
+    self.expect(")")?;
+    let extends = if self.eat_keyword("extends")? {
+      Some(self.name("the name of a group")?)
+    } else {
+      None
+    };
+    let mut type_mode = StructureMap_GroupTypeMode::None;
+    if self.eat("<<")? {
+      type_mode = match self.next("'types' or 'type+'")? {
+        (Token::Name(mode, false), _) if mode == "types" => StructureMap_GroupTypeMode::Types,
+        (Token::Name(mode, false), _) if mode == "type" => {
+          self.expect("+")?;
+          StructureMap_GroupTypeMode::TypeAndTypes
+        }
+        (token, start) => return self.unexpected("'types' or 'type+'", &token, start),
+      };
+      self.expect(">>")?;
+    }
+    let rules = self.rules()?;
+    let mut builder = StructureMap_GroupBuilder::new(
+      inputs.iter().map(|i| i.build()).collect(),
+      rules.iter().map(|r| r.build()).collect(),
+    );
+    builder.name(&name).type_mode(type_mode);
+    if let Some(extends) = extends {
+      builder.extends(&extends);
+    }
+    if let Some(documentation) = documentation {
+      builder.documentation(&documentation);
+    }
+    Ok(builder)
+  }
+
+  fn input(&mut self) -> Result<StructureMap_InputBuilder, ParseError> {
+    let (token, start) = self.next("'source' or 'target'")?;
+    let mode = match &token {
+      Token::Name(mode, false) => StructureMap_InputMode::from_string(mode),
+      _ => None,
+    };
+    let mut builder = StructureMap_InputBuilder::new();
+    match mode {
+      Some(mode) => builder.mode(mode),
+      None => return self.unexpected("'source' or 'target'", &token, start),
+    };
+    builder.name(&self.name("the input's name")?);
+    if self.eat(":")? {
+      builder.fhir_type(&self.name_or_string("a type")?);
+    }
+    Ok(builder)
+  }
+
+  fn rules(&mut self) -> Result<Vec<StructureMap_RuleBuilder>, ParseError> {
+    self.expect("{")?;
+    let mut rules = vec![];
+    while !self.eat("}")? {
+      rules.push(self.rule()?);
+    }
+    Ok(rules)
+  }
+
+  fn rule(&mut self) -> Result<StructureMap_RuleBuilder, ParseError> {
+    let documentation = self.documentation()?;
+    let mut sources = vec![self.source()?];
+    while self.eat(",")? {
+      sources.push(self.source()?);
+    }
+    let mut targets = vec![];
+    if self.eat("->")? {
+      targets.push(self.target()?);
+      while self.eat(",")? {
+        targets.push(self.target()?);
+      }
+    }
+    let mut dependents = vec![];
+    let mut rules = vec![];
+    let mut block = false;
+    if self.eat_keyword("then")? {
+      if !self.is("{")? {
+        dependents.push(self.dependent()?);
+        while self.eat(",")? {
+          dependents.push(self.dependent()?);
+        }
+      }
+      if self.is("{")? {
+        rules = self.rules()?;
+        block = true;
+      }
+    }
+    let name = match self.peek()? {
+      Some(Token::String(_)) => Some(self.string("the rule's name")?),
+      _ => None,
+    };
+    if !block || name.is_some() || self.is(";")? {
+      self.expect(";")?;
+    }
+    let mut builder = StructureMap_RuleBuilder::new(sources.iter().map(|s| s.build()).collect());
+    if let Some(name) = name {
+      builder.name(&name);
+    }
+    if !targets.is_empty() {
+      builder.target(targets.iter().map(|t| t.build()).collect());
+    }
+    if !rules.is_empty() {
+      builder.rule(rules.iter().map(|r| r.build()).collect());
+    }
+    if !dependents.is_empty() {
+      builder.dependent(dependents.iter().map(|d| d.build()).collect());
+    }
+    if let Some(documentation) = documentation {
+      builder.documentation(&documentation);
+    }
+    Ok(builder)
+  }
+
+  fn source(&mut self) -> Result<StructureMap_SourceBuilder, ParseError> {
+    let mut builder = StructureMap_SourceBuilder::new();
+    let (context, element) = self.path()?;
+    builder.context(&context);
+    if let Some(element) = element {
+      builder.element(&element);
+    }
+    if self.eat(":")? {
+      builder.fhir_type(&self.name_or_string("a type")?);
+    }
+    if let Some(Token::Number(_)) = self.peek()? {
+      let (token, start) = self.next("a minimum")?;
+      match &token {
+        Token::Number(min) if min.parse::<i64>().is_ok() => builder.min(min.parse().unwrap()),
+        _ => return self.unexpected("a whole number", &token, start),
+      };
+      self.expect("..")?;
+      match self.next("a maximum")? {
+        (Token::Number(max), _) if !max.contains('.') => builder.max(&max),
+        (Token::Symbol("*"), _) => builder.max("*"),
+        (token, start) => return self.unexpected("a whole number or '*'", &token, start),
+      };
+    }
+    if self.eat_keyword("default")? {
+      match self.parameter()? {
+        Parameter::String(text) => {
+          builder.default_value_string(&text);
+        }
+        Parameter::Integer(number) => builder.value["defaultValueInteger"] = json!(number),
+        Parameter::Decimal(number) => {
+          builder.default_value_decimal(number);
+        }
+        Parameter::Boolean(value) => {
+          builder.default_value_boolean(value);
+        }
+        Parameter::Id(_, start) => {
+          return Err(self.error("Expected a string, number or boolean default", start));
+        }
+      }
+    }
+    if let Some(mode) = self.keyword(SOURCE_LIST_MODES)? {
+      self.next(&mode)?;
+      if let Some(mode) = StructureMap_SourceListMode::from_string(&mode) {
+        builder.list_mode(mode);
+      }
+    }
+    if self.eat_keyword("as")? {
+      builder.variable(&self.name("a variable name")?);
+    }
+    if self.eat_keyword("where")? {
+      builder.condition(&self.expression()?);
+    }
+    if self.eat_keyword("check")? {
+      builder.check(&self.expression()?);
+    }
+    if self.eat_keyword("log")? {
+      builder.log_message(&self.expression()?);
+    }
+    Ok(builder)
+  }
+
+  fn target(&mut self) -> Result<StructureMap_TargetBuilder, ParseError> {
+    let mut builder = StructureMap_TargetBuilder::new();
+    self.skip()?;
+    let start = self.position;
+    let (context, element) = self.path()?;
+    if element.is_none() && self.is("(")? {
+      self.transform(&mut builder, &context, start)?;
+    } else {
+      builder
+        .context(&context)
+        .context_type(StructureMap_TargetContextType::Variable);
+      if let Some(element) = element {
+        builder.element(&element);
+      }
+      if self.eat("=")? {
+        self.value(&mut builder)?;
+      }
+    }
+    if self.eat_keyword("as")? {
+      builder.variable(&self.name("a variable name")?);
+    }
+    let mut list_modes = vec![];
+    while let Some(mode) = self.keyword(TARGET_LIST_MODES)? {
+      self.next(&mode)?;
+      list_modes.push(mode);
+    }
+    if !list_modes.is_empty() {
+      // The generated builder has no setter for this list of codes.
+      builder.value["listMode"] = json!(list_modes);
+    }
+    Ok(builder)
+  }
+
+  /// What follows `=` in a target: an invocation of a transform, a FHIRPath
+  /// expression in brackets to evaluate, or a value to copy.
+  fn value(&mut self, builder: &mut StructureMap_TargetBuilder) -> Result<(), ParseError> {
+    if self.is("(")? {
+      let expression = self.expression()?;
+      let mut parameter = StructureMap_ParameterBuilder::new();
+      parameter.value_string(&expression);
+      builder
+        .transform(StructureMap_TargetTransform::Evaluate)
+        .parameter(vec![parameter.build()]);
+      return Ok(());
+    }
+    let parameter = self.parameter()?;
+    if let Parameter::Id(name, start) = &parameter {
+      if self.is("(")? {
+        return self.transform(builder, name, *start);
+      }
+    }
+    builder
+      .transform(StructureMap_TargetTransform::Copy)
+      .parameter(vec![parameter.build().build()]);
+    Ok(())
+  }
+
+  fn transform(
+    &mut self,
+    builder: &mut StructureMap_TargetBuilder,
+    name: &str,
+    start: usize,
+  ) -> Result<(), ParseError> {
+    let transform = StructureMap_TargetTransform::from_string(name)
+      .ok_or_else(|| self.error(&format!("Unknown transform '{}'", name), start))?;
+    self.expect("(")?;
+    let mut parameters = vec![];
+    if !self.eat(")")? {
+      parameters.push(self.parameter()?.build());
+      while self.eat(",")? {
+        parameters.push(self.parameter()?.build());
+      }
+      self.expect(")")?;
+    }
+    builder.transform(transform);
+    if !parameters.is_empty() {
+      builder.parameter(parameters.iter().map(|p| p.build()).collect());
+    }
+    Ok(())
+  }
+
+  fn parameter(&mut self) -> Result<Parameter, ParseError> {
+    let expected = "a variable, string, number or boolean";
+    let (token, start) = self.next(expected)?;
+    let (negative, token, start) = match token {
+      Token::Symbol("-") => {
+        let (token, start) = self.next("a number")?;
+        (true, token, start)
+      }
+      token => (false, token, start),
+    };
+    let sign = if negative { "-" } else { "" };
+    Ok(match token {
+      Token::Number(number) if !number.contains('.') => {
+        let number = format!("{}{}", sign, number);
+        Parameter::Integer(
+          number
+            .parse()
+            .map_err(|_| self.error("The number is too large", start))?,
+        )
+      }
+      Token::Number(number) => Parameter::Decimal(format!("{}{}", sign, number).parse().unwrap()),
+      _ if negative => return self.unexpected("a number", &token, start),
+      Token::Name(name, false) if name == "true" || name == "false" => {
+        Parameter::Boolean(name == "true")
+      }
+      Token::Name(name, _) => Parameter::Id(name, start),
+      Token::String(text) => Parameter::String(text),
+      token => return self.unexpected(expected, &token, start),
+    })
+  }
+
+  fn dependent(&mut self) -> Result<StructureMap_DependentBuilder, ParseError> {
+    let name = self.name("the name of a group")?;
+    self.expect("(")?;
+    let mut variables = vec![];
+    if !self.eat(")")? {
+      variables.push(self.name("a variable name")?);
+      while self.eat(",")? {
+        variables.push(self.name("a variable name")?);
+      }
+      self.expect(")")?;
+    }
+    let mut builder = StructureMap_DependentBuilder::new();
+    builder
+      .name(&name)
+      .variable(variables.iter().map(String::as_str).collect());
+    Ok(builder)
+  }
+
+  /// A FHIRPath expression, either in brackets or running up to what ends
+  /// the clause it is in.
+  fn expression(&mut self) -> Result<String, ParseError> {
+    self.skip()?;
+    let start = self.position;
+    let bracketed = self.chars.get(start) == Some(&'(');
+    let mut position = if bracketed { start + 1 } else { start };
+    let mut depth = 0;
+    let end = loop {
+      let c = match self.chars.get(position) {
+        Some(c) => *c,
+        None if bracketed => return Err(self.error("Expected ')' but found the end", start)),
+        None => break position,
+      };
+      match c {
+        '\'' | '`' => {
+          self.quoted(&mut position)?;
+          continue;
+        }
+        '(' | '[' | '{' => depth += 1,
+        ')' | ']' | '}' if depth == 0 => break position,
+        ')' | ']' | '}' => depth -= 1,
+        ';' | ',' | '"' if depth == 0 && !bracketed => break position,
+        '-' if depth == 0 && !bracketed && self.chars.get(position + 1) == Some(&'>') => {
+          break position
+        }
+        c if (c.is_alphabetic() || c == '_') && !bracketed && depth == 0 => {
+          let word_start = position;
+          while position < self.chars.len()
+            && (self.chars[position].is_alphanumeric() || self.chars[position] == '_')
+          {
+            position += 1;
+          }
+          let word = self.chars[word_start..position].iter().collect::<String>();
+          let after_dot = self.chars[..word_start]
+            .iter()
+            .rev()
+            .find(|c| !c.is_whitespace())
+            == Some(&'.');
+          if ["check", "log", "then"].contains(&word.as_str()) && !after_dot {
+            break word_start;
+          }
+          continue;
+        }
+        _ => {}
+      }
+      position += 1;
+    };
+    let text_start = if bracketed { start + 1 } else { start };
+    let text = self.chars[text_start..end].iter().collect::<String>();
+    if bracketed {
+      if self.chars.get(end) != Some(&')') {
+        return Err(self.error("Expected ')'", end));
+      }
+      self.position = end + 1;
+    } else {
+      self.position = end;
+    }
+    self.comments.clear();
+    let trimmed = text.trim_end();
+    if trimmed.trim_start().is_empty() {
+      return Err(self.error("Expected an expression", start));
+    }
+    Expression::parse(trimmed).map_err(|error| {
+      let offset = error.offset.unwrap_or(0);
+      self.error(
+        &format!("Invalid expression: {}", error.message),
+        text_start + offset,
+      )
+    })?;
+    Ok(trimmed.trim_start().to_string())
+  }
+}
+
+/// A transform parameter, or a default value.
+enum Parameter {
+  /// A variable, with where it was found.
+  Id(String, usize),
+  String(String),
+  Integer(i64),
+  Decimal(f64),
+  Boolean(bool),
+}
+
+impl Parameter {
+  fn build(self) -> StructureMap_ParameterBuilder {
+    let mut builder = StructureMap_ParameterBuilder::new();
+    match self {
+      Parameter::Id(name, _) => builder.value_id(&name),
+      Parameter::String(text) => builder.value_string(&text),
+      // The generated setter takes integers as floats.
+      Parameter::Integer(number) => {
+        builder.value["valueInteger"] = json!(number);
+        &mut builder
+      }
+      Parameter::Decimal(number) => builder.value_decimal(number),
+      Parameter::Boolean(value) => builder.value_boolean(value),
+    };
+    builder
+  }
+}
+
+fn describe(token: &Token) -> String {
+  match token {
+    Token::Name(name, _) => format!("'{}'", name),
+    Token::String(text) => format!("the string '{}'", text),
+    Token::Number(number) => format!("the number {}", number),
+    Token::Symbol(symbol) => format!("'{}'", symbol),
+  }
+}
+
+/// Renders a StructureMap as mapping language text.
+pub fn print(map: &StructureMap) -> String {
+  let map = &*map.value;
+  let mut out = String::new();
+  comment(&mut out, "", &map["description"]);
+  out.push_str(&format!(
+    "map {} = {}\n",
+    quote(text(&map["url"]), '"'),
+    quote(text(&map["name"]), '"')
+  ));
+  if !array(&map["structure"]).is_empty() {
+    out.push('\n');
+  }
+  for structure in array(&map["structure"]) {
+    comment(&mut out, "", &structure["documentation"]);
+    out.push_str(&format!("uses {}", quote(text(&structure["url"]), '"')));
+    if let Some(alias) = structure["alias"].as_str() {
+      out.push_str(&format!(" alias {}", name(alias)));
+    }
+    out.push_str(&format!(" as {}\n", text(&structure["mode"])));
+  }
+  if !array(&map["import"]).is_empty() {
+    out.push('\n');
+  }
+  for import in array(&map["import"]) {
+    out.push_str(&format!("imports {}\n", quote(text(import), '"')));
+  }
+  for group in array(&map["group"]) {
+    out.push('\n');
+    comment(&mut out, "", &group["documentation"]);
+    let inputs = array(&group["input"]).iter().map(|input| {
+      let mut out = format!("{} {}", text(&input["mode"]), name(text(&input["name"])));
+      if let Some(fhir_type) = input["type"].as_str() {
+        out.push_str(&format!(" : {}", name(fhir_type)));
+      }
+      out
+    });
+    out.push_str(&format!(
+      "group {}({})",
+      name(text(&group["name"])),
+      inputs.collect::<Vec<_>>().join(", ")
+    ));
+    if let Some(extends) = group["extends"].as_str() {
+      out.push_str(&format!(" extends {}", name(extends)));
+    }
+    match group["typeMode"].as_str() {
+      Some("types") => out.push_str(" <<types>>"),
+      Some("type-and-types") => out.push_str(" <<type+>>"),
+      _ => {}
+    }
+    out.push_str(" {\n");
+    for rule in array(&group["rule"]) {
+      print_rule(&mut out, "  ", rule);
+    }
+    out.push_str("}\n");
+  }
+  out
+}
+
+fn print_rule(out: &mut String, indent: &str, rule: &Value) {
+  comment(out, indent, &rule["documentation"]);
+  let sources = array(&rule["source"]).iter().map(print_source);
+  out.push_str(indent);
+  out.push_str(&sources.collect::<Vec<_>>().join(", "));
+  let targets = array(&rule["target"]).iter().map(print_target);
+  let targets = targets.collect::<Vec<_>>();
+  if !targets.is_empty() {
+    out.push_str(" -> ");
+    out.push_str(&targets.join(", "));
+  }
+  let dependents = array(&rule["dependent"]);
+  let rules = array(&rule["rule"]);
+  if !dependents.is_empty() || !rules.is_empty() {
+    out.push_str(" then");
+  }
+  if !dependents.is_empty() {
+    let dependents = dependents.iter().map(|dependent| {
+      let variables = array(&dependent["variable"]).iter().map(|v| name(text(v)));
+      format!(
+        "{}({})",
+        name(text(&dependent["name"])),
+        variables.collect::<Vec<_>>().join(", ")
+      )
+    });
+    out.push(' ');
+    out.push_str(&dependents.collect::<Vec<_>>().join(", "));
+  }
+  if !rules.is_empty() {
+    out.push_str(" {\n");
+    let nested = format!("{}  ", indent);
+    for rule in rules {
+      print_rule(out, &nested, rule);
+    }
+    out.push_str(indent);
+    out.push('}');
+  }
+  if let Some(rule_name) = rule["name"].as_str() {
+    out.push_str(&format!(" {}", quote(rule_name, '"')));
+  }
+  out.push_str(";\n");
+}
+
+fn print_source(source: &Value) -> String {
+  let mut out = path(&source["context"], &source["element"]);
+  if let Some(fhir_type) = source["type"].as_str() {
+    out.push_str(&format!(" : {}", name(fhir_type)));
+  }
+  if let Some(min) = source["min"].as_i64() {
+    out.push_str(&format!(
+      " {}..{}",
+      min,
+      source["max"].as_str().unwrap_or("*")
+    ));
+  }
+  let default = source.as_object().and_then(|object| {
+    object.iter().find_map(|(key, value)| {
+      key
+        .strip_prefix("defaultValue")
+        .and_then(|_| print_parameter_value(value))
+    })
+  });
+  if let Some(default) = default {
+    out.push_str(&format!(" default {}", default));
+  }
+  if let Some(list_mode) = source["listMode"].as_str() {
+    out.push_str(&format!(" {}", list_mode));
+  }
+  if let Some(variable) = source["variable"].as_str() {
+    out.push_str(&format!(" as {}", name(variable)));
+  }
+  for (clause, key) in &[
+    ("where", "condition"),
+    ("check", "check"),
+    ("log", "logMessage"),
+  ] {
+    if let Some(expression) = source[*key].as_str() {
+      out.push_str(&format!(" {} ({})", clause, expression));
+    }
+  }
+  out
+}
+
+fn print_target(target: &Value) -> String {
+  let mut out = match target["context"].as_str() {
+    Some(_) => path(&target["context"], &target["element"]),
+    None => String::new(),
+  };
+  if let Some(transform) = target["transform"].as_str() {
+    if !out.is_empty() {
+      out.push_str(" = ");
+    }
+    let parameters = array(&target["parameter"]);
+    match (transform, parameters) {
+      ("copy", [parameter]) if !out.is_empty() => out.push_str(&print_parameter(parameter)),
+      ("evaluate", [parameter]) if parameter["valueString"].is_string() => {
+        out.push_str(&format!("({})", text(&parameter["valueString"])))
+      }
+      _ => {
+        let parameters = parameters.iter().map(print_parameter);
+        out.push_str(&format!(
+          "{}({})",
+          transform,
+          parameters.collect::<Vec<_>>().join(", ")
+        ));
+      }
+    }
+  }
+  if let Some(variable) = target["variable"].as_str() {
+    out.push_str(&format!(" as {}", name(variable)));
+  }
+  for list_mode in array(&target["listMode"]) {
+    out.push_str(&format!(" {}", text(list_mode)));
+  }
+  out.trim_start().to_string()
+}
+
+fn print_parameter(parameter: &Value) -> String {
+  match parameter["valueId"].as_str() {
+    Some(id) => name(id),
+    None => parameter
+      .as_object()
+      .and_then(|object| {
+        object
+          .iter()
+          .find(|(key, _)| key.starts_with("value"))
+          .and_then(|(_, value)| print_parameter_value(value))
+      })
+      .unwrap_or_else(|| "''".to_string()),
+  }
+}
+
+/// Literal values, which is all parameters and default values can be.
+fn print_parameter_value(value: &Value) -> Option<String> {
+  match value {
+    Value::String(text) => Some(quote(text, '\'')),
+    Value::Number(number) => Some(number.to_string()),
+    Value::Bool(value) => Some(value.to_string()),
+    _ => None,
+  }
+}
+
+fn path(context: &Value, element: &Value) -> String {
+  let mut out = name(text(context));
+  if let Some(element) = element.as_str() {
+    for part in element.split('.') {
+      out.push('.');
+      out.push_str(&name(part));
+    }
+  }
+  out
+}
+
+fn comment(out: &mut String, indent: &str, documentation: &Value) {
+  if let Some(documentation) = documentation.as_str() {
+    for line in documentation.lines() {
+      out.push_str(indent);
+      out.push_str("//");
+      if !line.is_empty() {
+        out.push(' ');
+        out.push_str(line);
+      }
+      out.push('\n');
+    }
+  }
+}
+
+/// A name as written in the text, in backticks unless it is an identifier.
+fn name(text: &str) -> String {
+  let mut chars = text.chars();
+  let identifier = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+    && chars.all(|c| c.is_alphanumeric() || c == '_');
+  if identifier {
+    text.to_string()
+  } else {
+    quote(text, '`')
+  }
+}
+
+fn quote(text: &str, quote: char) -> String {
+  let mut out = String::new();
+  out.push(quote);
+  for c in text.chars() {
+    match c {
+      '\\' => out.push_str("\\\\"),
+      '\n' => out.push_str("\\n"),
+      '\r' => out.push_str("\\r"),
+      '\t' => out.push_str("\\t"),
+      c if c == quote => {
+        out.push('\\');
+        out.push(c);
+      }
+      c => out.push(c),
+    }
+  }
+  out.push(quote);
+  out
+}
+
+fn text(value: &Value) -> &str {
+  value.as_str().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::definitions::Definitions;
+  use crate::mapping::Transformer;
+  use std::fs;
+  use std::path::Path;
+
+  fn read(file: &str) -> Value {
+    let path = format!("examples-json/{}", file);
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+  }
+
+  const PATIENT_TO_PERSON: &str = r#"// Copies patients into people.
+map "http://example.org/StructureMap/patient-person" = "PatientToPerson"
+
+uses "http://hl7.org/fhir/StructureDefinition/Patient" alias Patient as source
+uses "http://hl7.org/fhir/StructureDefinition/Person" as target
+
+group main(source src : Patient, target tgt : Person) {
+  src.id as i -> tgt.id = i "id";
+  // Only current names.
+  src.name as n where use != 'old' and use != 'maiden' -> tgt.name as tn then {
+    n.given first as g -> tn.given = g "given";
+    n.family as f -> tn.family = f "family";
+  } "name";
+  src.gender as g -> tgt.gender = translate(g, 'http://example.org/ConceptMap/gender', 'code') "gender";
+  src.birthDate : date 0..1 default '1900-01-01' as b check (length() = 10) -> tgt.birthDate = b "birthDate";
+  src -> tgt.link as l, l.target.reference = reference(src), l.assurance = ('level2') "link";
+  src.telecom as t -> tgt.telecom = copy(t) last "telecom";
+  src.address as a then address(a, tgt) "address";
+}
+
+group address(source a : Address, target tgt : Person) {
+  a.city as c -> tgt.extension as e, e.url = 'http://example.org/city', e.valueString = c, e.id = -1 "city";
+}
+"#;
+
+  #[test]
+  fn test_parse_and_print() {
+    let map = parse(PATIENT_TO_PERSON).unwrap();
+    let value = map.to_json();
+    assert_eq!(value["description"], "Copies patients into people.");
+    assert_eq!(value["structure"][0]["alias"], "Patient");
+    let rules = &value["group"][0]["rule"];
+    assert_eq!(rules[1]["documentation"], "Only current names.");
+    assert_eq!(
+      rules[1]["source"][0]["condition"],
+      "use != 'old' and use != 'maiden'"
+    );
+    assert_eq!(rules[1]["rule"][0]["source"][0]["listMode"], "first");
+    assert_eq!(
+      rules[2]["target"][0]["parameter"],
+      json!([
+        {"valueId": "g"},
+        {"valueString": "http://example.org/ConceptMap/gender"},
+        {"valueString": "code"}
+      ])
+    );
+    let birth_date = &rules[3]["source"][0];
+    assert_eq!(birth_date["type"], "date");
+    assert_eq!(
+      (birth_date["min"].clone(), birth_date["max"].clone()),
+      (json!(0), json!("1"))
+    );
+    assert_eq!(birth_date["defaultValueString"], "1900-01-01");
+    assert_eq!(birth_date["check"], "length() = 10");
+    assert_eq!(rules[4]["target"][1]["element"], "target.reference");
+    assert_eq!(rules[4]["target"][2]["transform"], "evaluate");
+    assert_eq!(rules[5]["target"][0]["listMode"], json!(["last"]));
+    assert_eq!(
+      rules[6]["dependent"],
+      json!([{"name": "address", "variable": ["a", "tgt"]}])
+    );
+    let city = &value["group"][1]["rule"][0]["target"];
+    assert_eq!(city[3]["parameter"], json!([{"valueInteger": -1}]));
+
+    let printed = print(&map);
+    assert!(printed.contains("where (use != 'old' and use != 'maiden')"));
+    let reparsed = parse(&printed).unwrap();
+    assert_eq!(reparsed.to_json(), value);
+    assert_eq!(print(&reparsed), printed);
+
+    // The parsed map runs: only the current name comes across.
+    let mut definitions = Definitions::new();
+    for file in &[
+      "profiles-types.json",
+      "patient.profile.json",
+      "person.profile.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "p1",
+      "name": [
+        {"use": "official", "family": "Chalmers", "given": ["Peter", "James"]},
+        {"use": "maiden", "family": "Windsor"}
+      ]
+    });
+    let mut transformer = Transformer::new();
+    transformer.map(&map).definitions(&definitions);
+    let person = transformer
+      .transform("http://example.org/StructureMap/patient-person", &patient)
+      .unwrap();
+    assert_eq!(person["id"], "p1");
+    assert_eq!(
+      person["name"],
+      json!([{"family": "Chalmers", "given": ["Peter"]}])
+    );
+  }
+
+  #[test]
+  fn test_print_fixtures() {
+    for file in &[
+      "structuremap-supplyrequest-transform.json",
+      "structuremap-example.json",
+    ] {
+      let original = read(file);
+      let map = StructureMap {
+        value: Cow::Borrowed(&original),
+      };
+      let parsed = parse(&print(&map)).unwrap().to_json();
+      for key in &["url", "name", "group"] {
+        assert_eq!(parsed[*key], original[*key], "{} in {}", key, file);
+      }
+    }
+  }
+
+  #[test]
+  fn test_errors() {
+    let error = |text: &str| {
+      let error = parse(text).unwrap_err();
+      (error.line, error.column, error.message)
+    };
+    let header = "map \"http://example.org/m\" = m\n";
+    assert_eq!(
+      error(&format!(
+        "{}group g(source s) {{\n  s.a as a -> t.a = a\n}}",
+        header
+      )),
+      (4, 1, "Expected ';' but found '}'".to_string())
+    );
+    assert_eq!(
+      error(&format!(
+        "{}group g(source s) {{\n  s -> t.a = frob(s);\n}}",
+        header
+      )),
+      (3, 14, "Unknown transform 'frob'".to_string())
+    );
+    assert_eq!(
+      error(&format!(
+        "{}group g(source s) {{\n  s.a where (a = ) -> t;\n}}",
+        header
+      )),
+      (
+        3,
+        17,
+        "Invalid expression: Unexpected end of expression".to_string()
+      )
+    );
+    assert_eq!(
+      error(header),
+      (2, 1, "Expected 'group' but found the end".to_string())
+    );
+    assert_eq!(
+      error("map \"u\" = m\nuses \"u\" as sauce"),
+      (
+        2,
+        13,
+        "Expected 'source', 'queried', 'target' or 'produced' but found 'sauce'".to_string()
+      )
+    );
+    assert!(parse("map 'u' = m group g(source s) { s -> t.a = 'unterminated; }").is_err());
+  }
+}
@@ -0,0 +1,1582 @@
+//! Runs StructureMaps (http://hl7.org/fhir/mapping-language.html) over JSON
+//! content, as the `$transform` operation does: the first group of the map is
+//! invoked with the content as its source and a new instance of each of its
+//! target types, and the rules fill the targets in. [`parse`] and [`print`]
+//! convert between StructureMaps and their text form.
+
+mod language;
+
+pub use self::language::{parse, print, ParseError};
+
+use crate::definitions::{element_type, Definitions};
+use crate::fhirpath::{Context, Expression, Item, Node};
+use crate::ids::new_uuid;
+use crate::model::Coding::Coding;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::model::StructureMap::StructureMap;
+use crate::outcome::Issue;
+use crate::terminology::{Terminology, Translator};
+use crate::ucum;
+use crate::util::array;
+use serde_json::json;
+use serde_json::value::{Map, Number, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// How deeply groups may invoke other groups.
+const MAX_DEPTH: usize = 64;
+
+/// Applies StructureMaps. Every map that may be needed, including the ones
+/// other maps import, has to be added with [`Transformer::map`].
+#[derive(Default)]
+pub struct Transformer<'t> {
+  maps: Vec<&'t Value>,
+  definitions: Option<&'t Definitions>,
+  terminology: Option<&'t Terminology>,
+}
+
+impl<'t> Transformer<'t> {
+  pub fn new() -> Transformer<'t> {
+    Transformer::default()
+  }
+
+  pub fn map<'b>(&'b mut self, map: &'t StructureMap) -> &'b mut Transformer<'t> {
+    self.maps.push(&map.value);
+    self
+  }
+
+  /// The definitions of the source and target types, which tell which
+  /// elements repeat, the types of new elements, and which groups to use for
+  /// rules that leave it to the types.
+  pub fn definitions<'b>(&'b mut self, definitions: &'t Definitions) -> &'b mut Transformer<'t> {
+    self.definitions = Some(definitions);
+    self
+  }
+
+  /// Where the `translate` transform finds its ConceptMaps.
+  pub fn terminology<'b>(&'b mut self, terminology: &'t Terminology) -> &'b mut Transformer<'t> {
+    self.terminology = Some(terminology);
+    self
+  }
+
+  /// `$transform`: applies the StructureMap with canonical URL `url` to
+  /// `content`, returning the instance of its first target.
+  pub fn transform(&self, url: &str, content: &Value) -> Result<Value, Vec<Issue>> {
+    let map = self.find_map(url).ok_or_else(|| {
+      vec![Issue::error(
+        OperationOutcome_IssueCode::NotFound,
+        &format!("The StructureMap '{}' is not known", url),
+      )]
+    })?;
+    let group = array(&map["group"]).first().ok_or_else(|| {
+      vec![Issue::error(
+        OperationOutcome_IssueCode::Invalid,
+        &format!("The StructureMap '{}' has no groups", url),
+      )]
+    })?;
+    let mut run = Run {
+      transformer: self,
+      content,
+      targets: vec![],
+      shared: HashMap::new(),
+    };
+    let mut arguments = vec![];
+    let mut sources = 0;
+    for input in array(&group["input"]) {
+      let type_name = input["type"]
+        .as_str()
+        .map(|alias| self.structure_type(map, alias));
+      if input["mode"] == "source" {
+        sources += 1;
+        if sources > 1 {
+          return Err(vec![Issue::error(
+            OperationOutcome_IssueCode::NotSupported,
+            &format!(
+              "The group '{}' takes more than one source, but $transform only has one",
+              text(&group["name"])
+            ),
+          )]);
+        }
+        let type_name = content["resourceType"]
+          .as_str()
+          .map(String::from)
+          .or(type_name);
+        arguments.push(Variable::Source(Item::Node(Node {
+          value: Some(Cow::Borrowed(content)),
+          element: None,
+          path: type_name.clone().unwrap_or_default(),
+          type_name,
+        })));
+      } else {
+        arguments.push(Variable::Target(
+          run.root(new_object(type_name.as_deref()), type_name),
+        ));
+      }
+    }
+    let first_target = arguments.iter().find_map(|argument| match argument {
+      Variable::Target(target) => Some(target.root),
+      Variable::Source(_) => None,
+    });
+    run
+      .invoke(map, group, arguments, 0)
+      .map_err(|issue| vec![issue])?;
+    Ok(match first_target {
+      Some(root) => run.targets.swap_remove(root),
+      None => Value::Null,
+    })
+  }
+
+  fn find_map(&self, url: &str) -> Option<&'t Value> {
+    let url = url.split('|').next().unwrap_or(url);
+    self.maps.iter().copied().find(|map| map["url"] == url)
+  }
+
+  /// The maps that a map imports, which may name them with `*` wildcards.
+  fn imports(&self, map: &Value) -> Vec<&'t Value> {
+    let mut imports = vec![];
+    for pattern in array(&map["import"]).iter().filter_map(Value::as_str) {
+      for candidate in &self.maps {
+        let url = candidate["url"].as_str().unwrap_or_default();
+        if wildcard_match(pattern, url) && !imports.contains(candidate) {
+          imports.push(*candidate);
+        }
+      }
+    }
+    imports
+  }
+
+  /// The group called `name` in a map or the maps it imports, along with the
+  /// map it is in.
+  fn group_named(&self, map: &'t Value, name: &str) -> Option<(&'t Value, &'t Value)> {
+    let in_map = |map: &'t Value| {
+      let group = array(&map["group"]).iter().find(|g| g["name"] == name);
+      group.map(|group| (map, group))
+    };
+    in_map(map).or_else(|| self.imports(map).into_iter().find_map(in_map))
+  }
+
+  /// The group that maps one type to another when a rule leaves it to the
+  /// types, as groups with a `typeMode` of `types` or `type-and-types` do.
+  fn group_for_types(
+    &self,
+    map: &'t Value,
+    source: &str,
+    target: &str,
+  ) -> Option<(&'t Value, &'t Value)> {
+    let in_map = |map: &'t Value| {
+      let group = array(&map["group"]).iter().find(|group| {
+        let inputs = array(&group["input"]);
+        matches!(group["typeMode"].as_str(), Some("types" | "type-and-types"))
+          && inputs.len() == 2
+          && inputs[0]["type"] == source
+          && inputs[1]["type"] == target
+      });
+      group.map(|group| (map, group))
+    };
+    in_map(map).or_else(|| self.imports(map).into_iter().find_map(in_map))
+  }
+
+  /// The type a group input names, which may be the alias of one of the
+  /// structures the map uses.
+  fn structure_type(&self, map: &Value, name: &str) -> String {
+    let structure = array(&map["structure"]).iter().find(|s| s["alias"] == name);
+    let url = match structure.and_then(|s| s["url"].as_str()) {
+      Some(url) => url,
+      None => return name.to_string(),
+    };
+    let definition = self.definitions.and_then(|d| d.structure(url));
+    match definition.and_then(|d| d["type"].as_str()) {
+      Some(type_name) => type_name.to_string(),
+      None => url.rsplit('/').next().unwrap_or(url).to_string(),
+    }
+  }
+
+  /// Whether an element repeats, and its type, going by the definitions. A
+  /// choice element like `valueQuantity` takes its type from its name.
+  fn declared(&self, path: &str) -> (bool, Option<String>) {
+    let definitions = match self.definitions {
+      Some(definitions) => definitions,
+      None => return (false, None),
+    };
+    if let Some(element) = definitions.element(path) {
+      let repeats = !matches!(element.max(), Some("0" | "1") | None);
+      return (repeats, element_type(&element));
+    }
+    let (parent, name) = path.rsplit_once('.').unwrap_or(("", path));
+    for (index, _) in name.char_indices().filter(|(_, c)| c.is_uppercase()) {
+      let choice = format!("{}.{}[x]", parent, &name[..index]);
+      if let Some(element) = definitions.element(&choice) {
+        let suffix = &name[index..];
+        let types = element.value["type"]
+          .as_array()
+          .cloned()
+          .unwrap_or_default();
+        let type_name = types
+          .iter()
+          .filter_map(|t| t["code"].as_str())
+          .find(|code| code.eq_ignore_ascii_case(suffix));
+        return (false, type_name.map(String::from));
+      }
+    }
+    (false, None)
+  }
+}
+
+/// What a variable of a rule refers to: an item of the source content, or a
+/// place in one of the targets being built.
+#[derive(Debug, Clone)]
+enum Variable<'a> {
+  Source(Item<'a>),
+  Target(Target),
+}
+
+#[derive(Debug, Clone)]
+struct Target {
+  root: usize,
+  steps: Vec<Step>,
+  /// Where the value sits in the type model, e.g. `SupplyRequest.orderedItem`.
+  path: String,
+  type_name: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+  Key(String),
+  Index(usize),
+}
+
+type Variables<'a> = HashMap<String, Variable<'a>>;
+
+/// The state of one transform: the targets built so far, including those
+/// created outside of the main target.
+struct Run<'a> {
+  transformer: &'a Transformer<'a>,
+  content: &'a Value,
+  targets: Vec<Value>,
+  /// How many items rules with the `share` list mode have put in each list.
+  shared: HashMap<String, usize>,
+}
+
+impl<'a> Run<'a> {
+  fn root(&mut self, value: Value, type_name: Option<String>) -> Target {
+    self.targets.push(value);
+    Target {
+      root: self.targets.len() - 1,
+      steps: vec![],
+      path: type_name.clone().unwrap_or_default(),
+      type_name,
+    }
+  }
+
+  /// Invokes a group with a variable for each of its inputs.
+  fn invoke(
+    &mut self,
+    map: &'a Value,
+    group: &'a Value,
+    arguments: Vec<Variable<'a>>,
+    depth: usize,
+  ) -> Result<(), Issue> {
+    let name = text(&group["name"]);
+    let inputs = array(&group["input"]);
+    if inputs.len() != arguments.len() {
+      return Err(Issue::error(
+        OperationOutcome_IssueCode::Processing,
+        &format!(
+          "The group '{}' takes {} inputs but was given {}",
+          name,
+          inputs.len(),
+          arguments.len()
+        ),
+      ));
+    }
+    let mut variables = Variables::new();
+    for (input, argument) in inputs.iter().zip(arguments) {
+      if input["mode"] == "target" && matches!(argument, Variable::Source(_)) {
+        return Err(Issue::error(
+          OperationOutcome_IssueCode::Processing,
+          &format!(
+            "The input '{}' of the group '{}' needs a target",
+            text(&input["name"]),
+            name
+          ),
+        ));
+      }
+      variables.insert(text(&input["name"]).to_string(), argument);
+    }
+    self.run_group(map, group, &variables, depth)
+  }
+
+  fn run_group(
+    &mut self,
+    map: &'a Value,
+    group: &'a Value,
+    variables: &Variables<'a>,
+    depth: usize,
+  ) -> Result<(), Issue> {
+    if depth > MAX_DEPTH {
+      return Err(Issue::error(
+        OperationOutcome_IssueCode::Processing,
+        &format!(
+          "The group '{}' is invoked too deeply; do groups invoke each other in a loop?",
+          text(&group["name"])
+        ),
+      ));
+    }
+    if let Some(extends) = group["extends"].as_str() {
+      let (map, base) = self.group(map, extends)?;
+      self.run_group(map, base, variables, depth + 1)?;
+    }
+    for rule in array(&group["rule"]) {
+      self.run_rule(map, rule, variables, depth)?;
+    }
+    Ok(())
+  }
+
+  fn group(&self, map: &'a Value, name: &str) -> Result<(&'a Value, &'a Value), Issue> {
+    self.transformer.group_named(map, name).ok_or_else(|| {
+      Issue::error(
+        OperationOutcome_IssueCode::NotFound,
+        &format!(
+          "The group '{}' is not in the StructureMap '{}' or the maps it imports",
+          name,
+          text(&map["url"])
+        ),
+      )
+    })
+  }
+
+  /// Runs a rule once for each combination of the items its sources select.
+  fn run_rule(
+    &mut self,
+    map: &'a Value,
+    rule: &'a Value,
+    variables: &Variables<'a>,
+    depth: usize,
+  ) -> Result<(), Issue> {
+    let mut bindings = vec![variables.clone()];
+    for source in array(&rule["source"]) {
+      let mut next = vec![];
+      for binding in bindings {
+        for item in self.select(rule, source, &binding)? {
+          let mut binding = binding.clone();
+          if let Some(variable) = source["variable"].as_str() {
+            binding.insert(variable.to_string(), Variable::Source(item));
+          }
+          next.push(binding);
+        }
+      }
+      bindings = next;
+    }
+
+    for mut variables in bindings {
+      for target in array(&rule["target"]) {
+        self.run_target(rule, target, &mut variables)?;
+      }
+      for nested in array(&rule["rule"]) {
+        self.run_rule(map, nested, &variables, depth)?;
+      }
+      for dependent in array(&rule["dependent"]) {
+        let mut arguments = vec![];
+        for name in array(&dependent["variable"]).iter().map(text) {
+          arguments.push(variable(rule, &variables, name)?.clone());
+        }
+        let (map, group) = self.group(map, text(&dependent["name"]))?;
+        self.invoke(map, group, arguments, depth + 1)?;
+      }
+      if array(&rule["rule"]).is_empty() && array(&rule["dependent"]).is_empty() {
+        self.by_types(map, rule, &variables, depth)?;
+      }
+    }
+    Ok(())
+  }
+
+  /// The items a rule source selects, after its list mode, condition and
+  /// cardinality have been applied.
+  fn select(
+    &self,
+    rule: &Value,
+    source: &Value,
+    variables: &Variables<'a>,
+  ) -> Result<Vec<Item<'a>>, Issue> {
+    let context = variable(rule, variables, text(&source["context"]))?;
+    let context = self.item(context);
+    let mut items = match source["element"].as_str() {
+      Some(element) => self.fhirpath(variables).children(&context, element),
+      None => vec![context],
+    };
+    if let Some(type_name) = source["type"].as_str() {
+      items.retain(|item| {
+        let item_type = item.type_name().unwrap_or_default();
+        item_type.rsplit('.').next() == Some(type_name)
+      });
+    }
+    if items.is_empty() {
+      let default = source
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find_map(|(key, value)| {
+          let suffix = key.strip_prefix("defaultValue")?;
+          Some(owned_node(value.clone(), Some(value_type(suffix, value))))
+        });
+      items.extend(default);
+    }
+
+    let count = items.len();
+    let min = source["min"].as_u64().unwrap_or_default() as usize;
+    let max = match source["max"].as_str() {
+      Some("*") | None => None,
+      Some(max) => max.parse::<usize>().ok(),
+    };
+    if count < min || max.is_some_and(|max| count > max) {
+      return Err(rule_issue(
+        rule,
+        OperationOutcome_IssueCode::Processing,
+        &format!(
+          "'{}' has {} items, but between {} and {} are allowed",
+          source_name(source),
+          count,
+          min,
+          source["max"].as_str().unwrap_or("*")
+        ),
+      ));
+    }
+    match source["listMode"].as_str() {
+      Some("first") => items.truncate(1),
+      Some("not_first") if count > 0 => {
+        items.remove(0);
+      }
+      Some("last") if count > 0 => {
+        items.drain(..count - 1);
+      }
+      Some("not_last") if count > 0 => items.truncate(count - 1),
+      Some("only_one") if count > 1 => {
+        return Err(rule_issue(
+          rule,
+          OperationOutcome_IssueCode::Processing,
+          &format!(
+            "'{}' has {} items, but only one is allowed",
+            source_name(source),
+            count
+          ),
+        ))
+      }
+      _ => {}
+    }
+
+    let test = |expression: &str, item: &Item<'a>| -> Result<Option<bool>, Issue> {
+      let expression = Expression::parse(expression).map_err(|error| {
+        rule_issue(
+          rule,
+          OperationOutcome_IssueCode::Invalid,
+          &error.to_string(),
+        )
+      })?;
+      let mut variables = variables.clone();
+      if let Some(name) = source["variable"].as_str() {
+        variables.insert(name.to_string(), Variable::Source(item.clone()));
+      }
+      expression
+        .evaluate_boolean(&self.fhirpath(&variables), std::slice::from_ref(item))
+        .map_err(|error| {
+          rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Processing,
+            &error.to_string(),
+          )
+        })
+    };
+    let mut selected = vec![];
+    for item in items {
+      if let Some(condition) = source["condition"].as_str() {
+        if test(condition, &item)? != Some(true) {
+          continue;
+        }
+      }
+      if let Some(check) = source["check"].as_str() {
+        if test(check, &item)? != Some(true) {
+          return Err(rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Invariant,
+            &format!("the check '{}' failed", check),
+          ));
+        }
+      }
+      selected.push(item);
+    }
+    Ok(selected)
+  }
+
+  fn run_target(
+    &mut self,
+    rule: &Value,
+    target: &Value,
+    variables: &mut Variables<'a>,
+  ) -> Result<(), Issue> {
+    let context = match target["context"].as_str() {
+      Some(name) => match variable(rule, variables, name)? {
+        Variable::Target(context) => Some(context.clone()),
+        Variable::Source(_) => {
+          return Err(rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Processing,
+            &format!("'{}' is a source, so it can't be written to", name),
+          ))
+        }
+      },
+      None => None,
+    };
+    let element = target["element"].as_str();
+    let (value, type_name) = match target["transform"].as_str() {
+      Some(transform) => match self.transform(rule, transform, target, variables)? {
+        Some(result) => result,
+        None => return Ok(()),
+      },
+      // A target with a variable gets a new instance of the element's type
+      // that later rules can fill in; without one, the rule copies its
+      // source.
+      None if !target["variable"].is_null() => (json!({}), None),
+      None => self
+        .transform(rule, "copy", target, variables)?
+        .unwrap_or_default(),
+    };
+
+    let result = match (context, element) {
+      (Some(context), Some(element)) => {
+        let list_mode = array(&target["listMode"]).first().and_then(Value::as_str);
+        self.set(rule, &context, element, value, type_name, list_mode)?
+      }
+      (None, Some(element)) => {
+        return Err(rule_issue(
+          rule,
+          OperationOutcome_IssueCode::Invalid,
+          &format!("the target element '{}' has no context", element),
+        ))
+      }
+      (_, None) => self.root(value, type_name),
+    };
+    if let Some(name) = target["variable"].as_str() {
+      variables.insert(name.to_string(), Variable::Target(result));
+    }
+    Ok(())
+  }
+
+  /// Sets an element of a target, or adds to it when it repeats, returning
+  /// where the value went. `element` may be a dotted path through elements
+  /// that are created as needed.
+  fn set(
+    &mut self,
+    rule: &Value,
+    context: &Target,
+    element: &str,
+    value: Value,
+    type_hint: Option<String>,
+    list_mode: Option<&str>,
+  ) -> Result<Target, Issue> {
+    let mut target = context.clone();
+    let names = element.split('.').collect::<Vec<_>>();
+    for (position, name) in names.iter().enumerate() {
+      let last = position == names.len() - 1;
+      let path = format!("{}.{}", target.path, name);
+      let (repeats, declared) = self.transformer.declared(&path);
+      let type_name = match last {
+        true => declared.or_else(|| type_hint.clone()),
+        false => declared,
+      };
+      let shared_key = format!("{}{:?}{}", target.root, target.steps, name);
+      let shared = self.shared.get(&shared_key).copied().unwrap_or_default();
+
+      let container = self.locate(rule, &target)?;
+      if !container.is_object() {
+        *container = json!({});
+      }
+      let object = container.as_object_mut().unwrap();
+      let mut steps = vec![Step::Key(name.to_string())];
+      let existing = object.get_mut(*name);
+      match (last, existing) {
+        (true, Some(Value::Array(items))) => {
+          steps.extend(add(items, value.clone(), list_mode, shared))
+        }
+        (true, Some(single)) if repeats => {
+          let mut items = vec![single.take()];
+          steps.extend(add(&mut items, value.clone(), list_mode, shared));
+          *single = Value::Array(items);
+        }
+        (true, _) if repeats => {
+          object.insert(name.to_string(), json!([value.clone()]));
+          steps.push(Step::Index(0));
+        }
+        (true, _) => {
+          object.insert(name.to_string(), value.clone());
+        }
+        (false, Some(Value::Array(items))) => {
+          if items.is_empty() {
+            items.push(json!({}));
+          }
+          steps.push(Step::Index(items.len() - 1));
+        }
+        (false, Some(_)) => {}
+        (false, None) => {
+          let empty = match repeats {
+            true => {
+              steps.push(Step::Index(0));
+              json!([{}])
+            }
+            false => json!({}),
+          };
+          object.insert(name.to_string(), empty);
+        }
+      }
+      if last && list_mode == Some("share") {
+        self.shared.insert(shared_key, shared + 1);
+      }
+      target.steps.extend(steps);
+      target.path = child_path(&path, type_name.as_deref());
+      target.type_name = type_name;
+    }
+    Ok(target)
+  }
+
+  fn locate(&mut self, rule: &Value, target: &Target) -> Result<&mut Value, Issue> {
+    let mut value = &mut self.targets[target.root];
+    for step in &target.steps {
+      let next = match step {
+        Step::Key(key) => value.get_mut(key.as_str()),
+        Step::Index(index) => value.get_mut(*index),
+      };
+      value = match next {
+        Some(next) => next,
+        None => {
+          return Err(rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Processing,
+            "a target variable no longer refers to anything, as a later rule replaced it",
+          ))
+        }
+      };
+    }
+    Ok(value)
+  }
+
+  /// A variable as an item, for FHIRPath and for selecting from.
+  fn item(&self, variable: &Variable<'a>) -> Item<'a> {
+    match variable {
+      Variable::Source(item) => item.clone(),
+      Variable::Target(target) => {
+        let mut value = &self.targets[target.root];
+        for step in &target.steps {
+          value = match step {
+            Step::Key(key) => &value[key.as_str()],
+            Step::Index(index) => &value[*index],
+          };
+        }
+        Item::Node(Node {
+          value: Some(Cow::Owned(value.clone())),
+          element: None,
+          type_name: target.type_name.clone(),
+          path: target.path.clone(),
+        })
+      }
+    }
+  }
+
+  /// A FHIRPath context with the variables as `%name` constants.
+  fn fhirpath(&self, variables: &Variables<'a>) -> Context<'a> {
+    let mut context = Context::new(self.content);
+    if let Some(definitions) = self.transformer.definitions {
+      context.model(definitions);
+    }
+    for (name, variable) in variables {
+      context.variable(name, vec![self.item(variable)]);
+    }
+    context
+  }
+
+  /// When a rule neither nests rules nor invokes groups, and maps a single
+  /// source variable to a new target, the group for the two types does the
+  /// mapping. Primitive values are copied across when there is no such group.
+  fn by_types(
+    &mut self,
+    map: &'a Value,
+    rule: &Value,
+    variables: &Variables<'a>,
+    depth: usize,
+  ) -> Result<(), Issue> {
+    let (sources, targets) = (array(&rule["source"]), array(&rule["target"]));
+    let (source, target) = match (sources, targets) {
+      ([source], [target]) => (source, target),
+      _ => return Ok(()),
+    };
+    let creates = match target["transform"].as_str() {
+      None => true,
+      Some("create") => array(&target["parameter"]).is_empty(),
+      Some(_) => false,
+    };
+    let names = (source["variable"].as_str(), target["variable"].as_str());
+    let (source, target) = match names {
+      (Some(source), Some(target)) if creates => (
+        variable(rule, variables, source)?.clone(),
+        variable(rule, variables, target)?.clone(),
+      ),
+      _ => return Ok(()),
+    };
+    let item = self.item(&source);
+    let source_type = item_type(&item).unwrap_or_default();
+    let target_type = match &target {
+      Variable::Target(target) => target.type_name.clone().unwrap_or_default(),
+      Variable::Source(_) => return Ok(()),
+    };
+    if let Some((map, group)) = self
+      .transformer
+      .group_for_types(map, &source_type, &target_type)
+    {
+      return self.invoke(map, group, vec![source, target], depth + 1);
+    }
+    match (item.to_json(), target) {
+      (value, Variable::Target(target)) if !value.is_object() => {
+        *self.locate(rule, &target)? = value;
+        Ok(())
+      }
+      _ => Err(rule_issue(
+        rule,
+        OperationOutcome_IssueCode::NotFound,
+        &format!(
+          "there is no group that maps '{}' to '{}'",
+          source_type, target_type
+        ),
+      )),
+    }
+  }
+
+  /// The value a target transform gives, with its type when that is known,
+  /// or `None` when there is nothing to set.
+  fn transform(
+    &mut self,
+    rule: &Value,
+    transform: &str,
+    target: &Value,
+    variables: &Variables<'a>,
+  ) -> Result<Option<(Value, Option<String>)>, Issue> {
+    let parameters = array(&target["parameter"]);
+    let argument = |index: usize| -> Result<Item<'a>, Issue> {
+      let parameter = parameters.get(index).ok_or_else(|| {
+        rule_issue(
+          rule,
+          OperationOutcome_IssueCode::Invalid,
+          &format!("the transform '{}' needs more parameters", transform),
+        )
+      })?;
+      match parameter["valueId"].as_str() {
+        Some(name) => Ok(self.item(variable(rule, variables, name)?)),
+        None => {
+          let (key, value) = parameter
+            .as_object()
+            .into_iter()
+            .flatten()
+            .find(|(key, _)| key.starts_with("value"))
+            .map(|(key, value)| (&key["value".len()..], value))
+            .unwrap_or(("String", &Value::Null));
+          Ok(owned_node(value.clone(), Some(value_type(key, value))))
+        }
+      }
+    };
+    let string = |index: usize| -> Result<String, Issue> {
+      let item = argument(index)?;
+      Ok(match item.to_json() {
+        Value::String(text) => text,
+        other => other.to_string(),
+      })
+    };
+
+    let value = match transform {
+      // Without a parameter, the rule's source is copied.
+      "copy" => {
+        let item = match parameters.is_empty() {
+          true => self.item(single_source(rule, variables)?),
+          false => argument(0)?,
+        };
+        return Ok(Some((item.to_json(), item_type(&item))));
+      }
+      "create" => {
+        let type_name = match parameters.is_empty() {
+          true => None,
+          false => Some(string(0)?),
+        };
+        return Ok(Some((new_object(type_name.as_deref()), type_name)));
+      }
+      "evaluate" => {
+        let (focus, expression) = match parameters.len() {
+          1 => (vec![], string(0)?),
+          _ => (vec![argument(0)?], string(1)?),
+        };
+        let parsed = Expression::parse(&expression).map_err(|error| {
+          rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Invalid,
+            &error.to_string(),
+          )
+        })?;
+        let result = parsed
+          .evaluate_on(&self.fhirpath(variables), &focus)
+          .map_err(|error| {
+            rule_issue(
+              rule,
+              OperationOutcome_IssueCode::Processing,
+              &error.to_string(),
+            )
+          })?;
+        return match result.as_slice() {
+          [] => Ok(None),
+          [item] => Ok(Some((item.to_json(), item_type(item)))),
+          _ => Err(rule_issue(
+            rule,
+            OperationOutcome_IssueCode::Processing,
+            &format!(
+              "'{}' gives {} values, where one was expected",
+              expression,
+              result.len()
+            ),
+          )),
+        };
+      }
+      "translate" => {
+        let output = match parameters.len() {
+          0..=2 => "code".to_string(),
+          _ => string(2)?,
+        };
+        return self.translate(rule, argument(0)?.to_json(), &string(1)?, &output);
+      }
+      "reference" => {
+        let name = parameters.first().and_then(|p| p["valueId"].as_str());
+        return Ok(Some((
+          json!(self.reference(rule, variables, name)?),
+          Some("string".to_string()),
+        )));
+      }
+      "uuid" => json!(new_uuid()),
+      "append" => {
+        let mut appended = String::new();
+        for index in 0..parameters.len() {
+          appended.push_str(&string(index)?);
+        }
+        json!(appended)
+      }
+      "c" | "cc" => {
+        let coding = match parameters.len() {
+          0 | 1 if transform == "cc" => {
+            return Ok(Some((
+              json!({"text": string(0)?}),
+              Some("CodeableConcept".to_string()),
+            )))
+          }
+          0 | 1 => json!({"code": string(0)?}),
+          2 => json!({"system": string(0)?, "code": string(1)?}),
+          _ => json!({"system": string(0)?, "code": string(1)?, "display": string(2)?}),
+        };
+        return Ok(Some(match transform {
+          "c" => (coding, Some("Coding".to_string())),
+          _ => (
+            json!({"coding": [coding]}),
+            Some("CodeableConcept".to_string()),
+          ),
+        }));
+      }
+      "qty" => {
+        let quantity = match parameters.len() {
+          0 | 1 => {
+            let text = string(0)?;
+            let (value, unit) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+            quantity(rule, value, unit.trim().trim_matches('\''), None)?
+          }
+          2 | 3 => quantity(rule, &string(0)?, &string(1)?, None)?,
+          _ => quantity(
+            rule,
+            &string(0)?,
+            &string(1)?,
+            Some((string(2)?, string(3)?)),
+          )?,
+        };
+        return Ok(Some((quantity, Some("Quantity".to_string()))));
+      }
+      _ => {
+        return Err(rule_issue(
+          rule,
+          OperationOutcome_IssueCode::NotSupported,
+          &format!("the transform '{}' is not supported", transform),
+        ))
+      }
+    };
+    Ok(Some((value, Some("string".to_string()))))
+  }
+
+  /// Translates a code, Coding or CodeableConcept with a ConceptMap, giving
+  /// the `code`, `system`, `display`, `Coding` or `CodeableConcept` of the
+  /// first match. A bare code is tried against each of the map's source
+  /// systems.
+  fn translate(
+    &self,
+    rule: &Value,
+    source: Value,
+    url: &str,
+    output: &str,
+  ) -> Result<Option<(Value, Option<String>)>, Issue> {
+    let terminology = self.transformer.terminology.ok_or_else(|| {
+      rule_issue(
+        rule,
+        OperationOutcome_IssueCode::NotSupported,
+        "the translate transform needs a terminology service",
+      )
+    })?;
+    let codings = match &source {
+      Value::String(code) => {
+        let map = terminology.concept_map(url);
+        let groups = map.as_ref().map(|m| array(&m.value["group"]).to_vec());
+        let systems = groups
+          .into_iter()
+          .flatten()
+          .filter_map(|g| g["source"].as_str().map(String::from));
+        systems
+          .map(|system| json!({"system": system, "code": code}))
+          .collect()
+      }
+      Value::Object(object) if object.contains_key("coding") => array(&source["coding"]).to_vec(),
+      _ => vec![source.clone()],
+    };
+    let mut translator = Translator::new(terminology);
+    translator.concept_map(url);
+    for coding in codings {
+      let coding = Coding {
+        value: Cow::Owned(coding),
+      };
+      let translation = translator.translate(&coding).map_err(|issues| {
+        let diagnostics = issues
+          .into_iter()
+          .map(|i| i.diagnostics)
+          .collect::<Vec<_>>();
+        rule_issue(
+          rule,
+          OperationOutcome_IssueCode::Processing,
+          &diagnostics.join("; "),
+        )
+      })?;
+      let outputs = array(&translation.value["parameter"]);
+      let matched = outputs
+        .iter()
+        .filter(|p| p["name"] == "match")
+        .find_map(|m| {
+          let parts = array(&m["part"]);
+          let equivalence = parts.iter().find(|p| p["name"] == "equivalence");
+          let unmatched = equivalence
+            .is_some_and(|e| matches!(e["valueCode"].as_str(), Some("unmatched" | "disjoint")));
+          let concept = parts.iter().find(|p| p["name"] == "concept")?;
+          Some(concept["valueCoding"].clone()).filter(|_| !unmatched)
+        });
+      if let Some(coding) = matched {
+        return Ok(Some(match output {
+          "code" | "system" | "display" => (
+            coding[output].clone(),
+            Some(output.to_string()).filter(|o| o == "code"),
+          ),
+          "Coding" => (coding, Some("Coding".to_string())),
+          "CodeableConcept" => (
+            json!({"coding": [coding]}),
+            Some("CodeableConcept".to_string()),
+          ),
+          _ => {
+            return Err(rule_issue(
+              rule,
+              OperationOutcome_IssueCode::NotSupported,
+              &format!("the translate output '{}' is not supported", output),
+            ))
+          }
+        }));
+      }
+    }
+    Err(rule_issue(
+      rule,
+      OperationOutcome_IssueCode::CodeInvalid,
+      &format!(
+        "{} has no translation with the ConceptMap '{}'",
+        source, url
+      ),
+    ))
+  }
+
+  /// `Type/id` for a resource in a variable, giving a target resource an id
+  /// when it doesn't have one yet.
+  fn reference(
+    &mut self,
+    rule: &Value,
+    variables: &Variables<'a>,
+    name: Option<&str>,
+  ) -> Result<String, Issue> {
+    let name = name.unwrap_or_default();
+    let not_a_resource = || {
+      rule_issue(
+        rule,
+        OperationOutcome_IssueCode::Processing,
+        &format!("'{}' is not a resource, so it can't be referred to", name),
+      )
+    };
+    let (resource_type, id) = match variable(rule, variables, name)? {
+      Variable::Source(item) => {
+        let value = item.to_json();
+        match (value["resourceType"].as_str(), value["id"].as_str()) {
+          (Some(resource_type), Some(id)) => (resource_type.to_string(), id.to_string()),
+          _ => return Err(not_a_resource()),
+        }
+      }
+      Variable::Target(target) => {
+        let target = target.clone();
+        let resource = self.locate(rule, &target)?;
+        let resource_type = match resource["resourceType"].as_str() {
+          Some(resource_type) => resource_type.to_string(),
+          None => return Err(not_a_resource()),
+        };
+        if resource["id"].is_null() {
+          resource["id"] = json!(new_uuid());
+        }
+        (resource_type, text(&resource["id"]).to_string())
+      }
+    };
+    Ok(format!("{}/{}", resource_type, id))
+  }
+}
+
+/// Adds a value to the items of a repeating element as the list mode says,
+/// returning the index it is at.
+fn add(
+  items: &mut Vec<Value>,
+  value: Value,
+  list_mode: Option<&str>,
+  shared: usize,
+) -> Option<Step> {
+  let index = match list_mode {
+    Some("first") => {
+      items.insert(0, value);
+      0
+    }
+    // Rules that share a list fill in the same items, in order.
+    Some("share") if shared < items.len() => shared,
+    // Collating rules keep adding to the first item.
+    Some("collate") if !items.is_empty() => {
+      if let (Some(first), Value::Object(added)) = (items[0].as_object_mut(), value) {
+        for (key, value) in added {
+          first.entry(key).or_insert(value);
+        }
+      }
+      0
+    }
+    _ => {
+      items.push(value);
+      items.len() - 1
+    }
+  };
+  Some(Step::Index(index))
+}
+
+fn variable<'v, 'a>(
+  rule: &Value,
+  variables: &'v Variables<'a>,
+  name: &str,
+) -> Result<&'v Variable<'a>, Issue> {
+  variables.get(name).ok_or_else(|| {
+    rule_issue(
+      rule,
+      OperationOutcome_IssueCode::NotFound,
+      &format!("the variable '{}' is not defined", name),
+    )
+  })
+}
+
+/// The variable of a rule's only source.
+fn single_source<'v, 'a>(
+  rule: &Value,
+  variables: &'v Variables<'a>,
+) -> Result<&'v Variable<'a>, Issue> {
+  let sources = array(&rule["source"]);
+  let names = sources.iter().filter_map(|s| s["variable"].as_str());
+  match names.collect::<Vec<_>>().as_slice() {
+    [name] => variable(rule, variables, name),
+    _ => Err(rule_issue(
+      rule,
+      OperationOutcome_IssueCode::Invalid,
+      "copying the source needs a rule with a single source variable",
+    )),
+  }
+}
+
+fn rule_issue(rule: &Value, code: OperationOutcome_IssueCode, message: &str) -> Issue {
+  Issue::error(
+    code,
+    &format!("Rule '{}': {}", text(&rule["name"]), message),
+  )
+}
+
+fn source_name(source: &Value) -> String {
+  match source["element"].as_str() {
+    Some(element) => format!("{}.{}", text(&source["context"]), element),
+    None => text(&source["context"]).to_string(),
+  }
+}
+
+/// A new, empty instance of a type, which is a resource when the model knows
+/// the type as one.
+fn new_object(type_name: Option<&str>) -> Value {
+  let type_name = match type_name {
+    Some(type_name) => type_name,
+    None => return json!({}),
+  };
+  let resource = ResourceList {
+    value: Cow::Owned(json!({ "resourceType": type_name })),
+  };
+  match resource.resource() {
+    Some(_) => resource.value.into_owned(),
+    None => json!({}),
+  }
+}
+
+fn quantity(
+  rule: &Value,
+  value: &str,
+  unit: &str,
+  coded: Option<(String, String)>,
+) -> Result<Value, Issue> {
+  let number = value
+    .parse::<i64>()
+    .map(Number::from)
+    .ok()
+    .or_else(|| value.parse::<f64>().ok().and_then(Number::from_f64))
+    .ok_or_else(|| {
+      rule_issue(
+        rule,
+        OperationOutcome_IssueCode::Invalid,
+        &format!("'{}' is not a number", value),
+      )
+    })?;
+  let mut quantity = Map::new();
+  quantity.insert("value".to_string(), Value::Number(number));
+  if !unit.is_empty() {
+    quantity.insert("unit".to_string(), json!(unit));
+  }
+  let coded = coded.or_else(|| {
+    let ucum = ucum::canonicalize(1.0, unit).is_some() && !unit.is_empty();
+    Some(("http://unitsofmeasure.org".to_string(), unit.to_string())).filter(|_| ucum)
+  });
+  if let Some((system, code)) = coded {
+    quantity.insert("system".to_string(), json!(system));
+    quantity.insert("code".to_string(), json!(code));
+  }
+  Ok(Value::Object(quantity))
+}
+
+fn owned_node<'a>(value: Value, type_name: Option<String>) -> Item<'a> {
+  Item::Node(Node {
+    value: Some(Cow::Owned(value)),
+    element: None,
+    path: type_name.clone().unwrap_or_default(),
+    type_name,
+  })
+}
+
+/// The FHIR type of an item, without its namespace, with System types given
+/// as the matching FHIR primitives.
+fn item_type(item: &Item) -> Option<String> {
+  let type_name = item.type_name()?;
+  let type_name = match type_name.split_once('.') {
+    Some(("System", name)) => match name {
+      "Boolean" => "boolean",
+      "Integer" => "integer",
+      "Decimal" => "decimal",
+      "String" => "string",
+      "Date" => "date",
+      "DateTime" => "dateTime",
+      "Time" => "time",
+      _ => name,
+    },
+    Some((_, name)) => name,
+    None => &type_name,
+  };
+  Some(type_name.to_string())
+}
+
+/// The type of a `value[x]`-style property from its suffix: primitives start
+/// in lower case.
+fn value_type(suffix: &str, value: &Value) -> String {
+  let mut chars = suffix.chars();
+  match (chars.next(), value.is_object()) {
+    (Some(first), false) => first.to_lowercase().chain(chars).collect(),
+    _ => suffix.to_string(),
+  }
+}
+
+/// Complex types restart the path at the type, as FHIRPath does, so that the
+/// definitions can be consulted for their elements.
+fn child_path(path: &str, type_name: Option<&str>) -> String {
+  match type_name {
+    Some("BackboneElement" | "Element") | None => path.to_string(),
+    Some(type_name) if type_name.starts_with(char::is_uppercase) => type_name.to_string(),
+    Some(_) => path.to_string(),
+  }
+}
+
+/// Matches a canonical URL against an import, where `*` stands for any text.
+fn wildcard_match(pattern: &str, url: &str) -> bool {
+  match pattern.split_once('*') {
+    None => pattern == url,
+    Some((prefix, rest)) => {
+      url.starts_with(prefix)
+        && (0..=url.len() - prefix.len())
+          .filter(|start| url.is_char_boundary(prefix.len() + start))
+          .any(|start| wildcard_match(rest, &url[prefix.len() + start..]))
+    }
+  }
+}
+
+fn text(value: &Value) -> &str {
+  value.as_str().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fhirpath::Temporal;
+  use std::fs;
+  use std::path::Path;
+
+  fn definitions() -> Definitions {
+    let mut definitions = Definitions::new();
+    for file in &[
+      "profiles-types.json",
+      "activitydefinition.profile.json",
+      "supplyrequest.profile.json",
+      "patient.profile.json",
+      "person.profile.json",
+      "observation.profile.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    definitions
+  }
+
+  fn read(file: &str) -> Value {
+    let path = format!("examples-json/{}", file);
+    serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()
+  }
+
+  fn structure_map(value: Value) -> StructureMap<'static> {
+    StructureMap {
+      value: Cow::Owned(value),
+    }
+  }
+
+  fn diagnostics(issues: Vec<Issue>) -> Vec<String> {
+    issues.into_iter().map(|issue| issue.diagnostics).collect()
+  }
+
+  #[test]
+  fn test_supply_request() {
+    let definitions = definitions();
+    let map = structure_map(read("structuremap-supplyrequest-transform.json"));
+    let example = structure_map(read("structuremap-example.json"));
+    let mut transformer = Transformer::new();
+    transformer
+      .map(&map)
+      .map(&example)
+      .definitions(&definitions);
+
+    let activity = read("activitydefinition-supplyrequest-example.json");
+    let request = transformer
+      .transform(
+        "http://hl7.org/fhir/StructureMap/supplyrequest-transform",
+        &activity,
+      )
+      .unwrap();
+    assert_eq!(request["resourceType"], "SupplyRequest");
+    assert_eq!(request["status"], "draft");
+    assert_eq!(request["priority"], "routine");
+    // The map sets the category twice, and the quantity comes last.
+    assert_eq!(request["category"], json!({"value": 10}));
+    assert_eq!(
+      request["orderedItem"]["itemCodeableConcept"],
+      activity["code"]
+    );
+    for element in &["occurrenceDateTime", "authoredOn"] {
+      let now = request[*element].as_str().unwrap();
+      assert!(Temporal::parse_date_time(now).is_some(), "{}", now);
+    }
+
+    assert_eq!(
+      diagnostics(
+        transformer
+          .transform(
+            "http://hl7.org/fhir/StructureMap/example",
+            &json!({"test": "x"})
+          )
+          .unwrap_err()
+      ),
+      vec!["Rule 'rule1': the variable 'Source' is not defined"]
+    );
+    assert_eq!(
+      diagnostics(
+        transformer
+          .transform("http://hl7.org/fhir/StructureMap/unknown", &activity)
+          .unwrap_err()
+      ),
+      vec!["The StructureMap 'http://hl7.org/fhir/StructureMap/unknown' is not known"]
+    );
+  }
+
+  #[test]
+  fn test_groups() {
+    let definitions = definitions();
+    let mut terminology = Terminology::new();
+    terminology.add(json!({
+      "resourceType": "ConceptMap",
+      "url": "http://example.org/ConceptMap/gender",
+      "group": [{
+        "source": "http://hl7.org/fhir/administrative-gender",
+        "target": "http://example.org/gender",
+        "element": [{"code": "male", "target": [{"code": "M", "equivalence": "equivalent"}]}]
+      }]
+    }));
+    let copy = |variable: &str| json!([{ "valueId": variable }]);
+    let map = structure_map(json!({
+      "resourceType": "StructureMap",
+      "url": "http://example.org/StructureMap/patient-person",
+      "import": ["http://example.org/StructureMap/datatypes-*"],
+      "group": [{
+        "name": "main",
+        "input": [
+          {"name": "src", "type": "Patient", "mode": "source"},
+          {"name": "tgt", "type": "Person", "mode": "target"}
+        ],
+        "rule": [
+          {
+            "name": "id",
+            "source": [{"context": "src", "element": "id", "variable": "i"}],
+            "target": [{"context": "tgt", "element": "id", "transform": "copy", "parameter": copy("i")}]
+          },
+          {
+            "name": "names",
+            "source": [{
+              "context": "src", "element": "name", "variable": "n",
+              "condition": "use != 'old' and use != 'maiden'"
+            }],
+            "target": [{"context": "tgt", "element": "name", "variable": "tn"}]
+          },
+          {
+            "name": "gender",
+            "source": [{"context": "src", "element": "gender", "variable": "g"}],
+            "target": [{"context": "tgt", "element": "gender", "transform": "translate", "parameter": [
+              {"valueId": "g"},
+              {"valueString": "http://example.org/ConceptMap/gender"},
+              {"valueString": "code"}
+            ]}]
+          },
+          {
+            "name": "active",
+            "source": [{"context": "src", "element": "active", "variable": "a", "check": "$this = true"}],
+            "target": [{"context": "tgt", "element": "active", "variable": "ta"}]
+          },
+          {
+            "name": "telecom",
+            "source": [{"context": "src", "element": "telecom", "variable": "t", "listMode": "last"}],
+            "target": [{"context": "tgt", "element": "telecom", "transform": "copy", "parameter": copy("t")}]
+          },
+          {
+            "name": "preferred",
+            "source": [{"context": "src", "element": "telecom", "variable": "t", "condition": "rank = 1"}],
+            "target": [{
+              "context": "tgt", "element": "telecom", "listMode": ["first"],
+              "transform": "copy", "parameter": copy("t")
+            }]
+          },
+          {
+            "name": "link",
+            "source": [{"context": "src", "variable": "s"}],
+            "target": [
+              {"context": "tgt", "element": "link", "variable": "l"},
+              {"context": "l", "element": "target.reference", "transform": "reference", "parameter": copy("s")},
+              {"context": "l", "element": "assurance", "transform": "evaluate", "parameter": [
+                {"valueString": "'level2'"}
+              ]}
+            ]
+          },
+          {
+            "name": "identifier",
+            "source": [{"context": "src", "element": "id", "variable": "i"}],
+            "target": [{"context": "tgt", "element": "identifier", "variable": "ti"}],
+            "dependent": [{"name": "identifier", "variable": ["i", "ti"]}]
+          }
+        ]
+      }, {
+        "name": "identifier",
+        "extends": "system",
+        "input": [{"name": "id", "mode": "source"}, {"name": "identifier", "mode": "target"}],
+        "rule": [{
+          "name": "value",
+          "source": [{"context": "id", "variable": "v"}],
+          "target": [{"context": "identifier", "element": "value", "transform": "append", "parameter": [
+            {"valueString": "patient-"},
+            {"valueId": "v"}
+          ]}]
+        }]
+      }, {
+        "name": "system",
+        "input": [{"name": "id", "mode": "source"}, {"name": "identifier", "mode": "target"}],
+        "rule": [{
+          "name": "system",
+          "source": [{"context": "id"}],
+          "target": [{"context": "identifier", "element": "system", "transform": "evaluate", "parameter": [
+            {"valueString": "'urn:example'"}
+          ]}]
+        }]
+      }]
+    }));
+    let datatypes = structure_map(json!({
+      "resourceType": "StructureMap",
+      "url": "http://example.org/StructureMap/datatypes-r4",
+      "group": [{
+        "name": "HumanName",
+        "typeMode": "types",
+        "input": [
+          {"name": "s", "type": "HumanName", "mode": "source"},
+          {"name": "t", "type": "HumanName", "mode": "target"}
+        ],
+        "rule": [
+          {
+            "name": "family",
+            "source": [{"context": "s", "element": "family", "variable": "f"}],
+            "target": [{"context": "t", "element": "family"}]
+          },
+          {
+            "name": "given",
+            "source": [{"context": "s", "element": "given", "variable": "g"}],
+            "target": [{"context": "t", "element": "given"}]
+          }
+        ]
+      }]
+    }));
+    let mut transformer = Transformer::new();
+    transformer
+      .map(&map)
+      .map(&datatypes)
+      .definitions(&definitions)
+      .terminology(&terminology);
+
+    let mut patient = read("patient-example.json");
+    let url = "http://example.org/StructureMap/patient-person";
+    let person = transformer.transform(url, &patient).unwrap();
+    assert_eq!(
+      person,
+      json!({
+        "resourceType": "Person",
+        "id": "example",
+        "name": [
+          {"family": "Chalmers", "given": ["Peter", "James"]},
+          {"given": ["Jim"]}
+        ],
+        "gender": "M",
+        "active": true,
+        "telecom": [
+          {"system": "phone", "value": "(03) 5555 6473", "use": "work", "rank": 1},
+          {"system": "phone", "value": "(03) 5555 8834", "use": "old", "period": {"end": "2014"}}
+        ],
+        "link": [{"target": {"reference": "Patient/example"}, "assurance": "level2"}],
+        "identifier": [{"system": "urn:example", "value": "patient-example"}]
+      })
+    );
+
+    patient["active"] = json!(false);
+    assert_eq!(
+      diagnostics(transformer.transform(url, &patient).unwrap_err()),
+      vec!["Rule 'active': the check '$this = true' failed"]
+    );
+  }
+
+  #[test]
+  fn test_transforms() {
+    let definitions = definitions();
+    let map = structure_map(json!({
+      "resourceType": "StructureMap",
+      "url": "http://example.org/StructureMap/weight",
+      "group": [{
+        "name": "weight",
+        "input": [
+          {"name": "src", "type": "Patient", "mode": "source"},
+          {"name": "obs", "type": "Observation", "mode": "target"}
+        ],
+        "rule": [
+          {
+            "name": "status",
+            "source": [{
+              "context": "src", "element": "multipleBirth", "variable": "m",
+              "defaultValueBoolean": false, "listMode": "only_one"
+            }],
+            "target": [{"context": "obs", "element": "status", "transform": "evaluate", "parameter": [
+              {"valueId": "m"},
+              {"valueString": "iif($this, 'preliminary', 'final')"}
+            ]}]
+          },
+          {
+            "name": "weight",
+            "source": [{"context": "src", "variable": "s"}],
+            "target": [
+              {"context": "obs", "element": "code", "transform": "cc", "parameter": [
+                {"valueString": "http://loinc.org"},
+                {"valueString": "29463-7"},
+                {"valueString": "Body weight"}
+              ]},
+              {"context": "obs", "element": "valueQuantity", "transform": "qty", "parameter": [
+                {"valueString": "72.5 'kg'"}
+              ]},
+              {"context": "obs", "element": "identifier", "variable": "i"},
+              {"context": "i", "element": "value", "transform": "uuid"},
+              {"context": "obs", "element": "subject", "variable": "subject"},
+              {"context": "subject", "element": "reference", "transform": "reference", "parameter": [
+                {"valueId": "s"}
+              ]},
+              {"context": "obs", "element": "category", "transform": "create", "variable": "c",
+                "parameter": [{"valueString": "CodeableConcept"}]},
+              {"context": "c", "element": "text", "transform": "copy", "parameter": [
+                {"valueString": "vital-signs"}
+              ]},
+              {"context": "obs", "element": "method", "transform": "cc", "parameter": [
+                {"valueString": "scales"}
+              ]}
+            ]
+          }
+        ]
+      }]
+    }));
+    let mut transformer = Transformer::new();
+    transformer.map(&map).definitions(&definitions);
+
+    let url = "http://example.org/StructureMap/weight";
+    let mut observation = transformer
+      .transform(url, &read("patient-example.json"))
+      .unwrap();
+    let identifier = observation["identifier"][0]["value"].take();
+    assert_eq!(identifier.as_str().unwrap().len(), 36);
+    assert_eq!(
+      observation,
+      json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "code": {"coding": [{"system": "http://loinc.org", "code": "29463-7", "display": "Body weight"}]},
+        "valueQuantity": {"value": 72.5, "unit": "kg", "system": "http://unitsofmeasure.org", "code": "kg"},
+        "identifier": [{"value": null}],
+        "subject": {"reference": "Patient/example"},
+        "category": [{"text": "vital-signs"}],
+        "method": {"text": "scales"}
+      })
+    );
+
+    let twins = json!({"resourceType": "Patient", "id": "twin", "multipleBirthBoolean": true});
+    let observation = transformer.transform(url, &twins).unwrap();
+    assert_eq!(observation["status"], "preliminary");
+  }
+}
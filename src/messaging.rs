@@ -0,0 +1,494 @@
+use crate::datetime::now_instant;
+use crate::ids::{new_uuid, new_uuid_urn};
+use crate::model::Bundle::{Bundle, BundleType};
+use crate::model::MessageDefinition::MessageDefinition;
+use crate::model::MessageHeader::MessageHeader;
+use crate::model::MessageHeader_Response::MessageHeader_ResponseCode;
+use crate::model::OperationOutcome::OperationOutcome;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::resolve::{is_absolute, Resolver, ResourceSource};
+use crate::util::{array, service_base};
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+
+/// A received message split into its header and the focus resources the
+/// header points at.
+#[derive(Debug)]
+pub struct Message<'a> {
+  pub header: MessageHeader<'a>,
+  pub focus: Vec<ResourceList<'a>>,
+}
+
+/// Builds `message` Bundles (http://hl7.org/fhir/messaging.html) from a
+/// MessageHeader, pulling its focus resources out of a `ResourceSource`.
+pub struct MessageAssembler<'a> {
+  base: String,
+  source: &'a dyn ResourceSource,
+  definition: Option<&'a MessageDefinition<'a>>,
+}
+
+impl<'a> MessageAssembler<'a> {
+  /// `base` is the service base that relative focus references are resolved
+  /// against, e.g. `http://example.org/fhir`.
+  pub fn new(base: &str, source: &'a dyn ResourceSource) -> MessageAssembler<'a> {
+    let base = service_base(base);
+    MessageAssembler {
+      base,
+      source,
+      definition: None,
+    }
+  }
+
+  /// Checks the assembled message against the event, focus types and focus
+  /// cardinalities of a MessageDefinition.
+  pub fn definition(&mut self, definition: &'a MessageDefinition<'a>) -> &mut MessageAssembler<'a> {
+    self.definition = Some(definition);
+    self
+  }
+
+  pub fn assemble(&self, header: &MessageHeader) -> Result<Bundle<'static>, Vec<Issue>> {
+    let mut header = (*header.value).clone();
+    header["resourceType"] = json!("MessageHeader");
+    if header.get("id").is_none() {
+      header["id"] = json!(new_uuid());
+    }
+    let mut issues = vec![];
+    let mut entries = vec![json!({"fullUrl": new_uuid_urn(), "resource": header})];
+    let focus = array(&entries[0]["resource"]["focus"]).to_vec();
+    for (index, reference) in focus.iter().enumerate() {
+      let reference = match reference["reference"].as_str() {
+        Some(reference) => reference,
+        None => continue,
+      };
+      let full_url = if is_absolute(reference) {
+        reference.to_string()
+      } else {
+        format!("{}{}", self.base, reference)
+      };
+      if entries.iter().any(|e| e["fullUrl"] == full_url.as_str()) {
+        continue;
+      }
+      match self.source.fetch(reference) {
+        Some(resource) => entries.push(json!({
+          "fullUrl": full_url,
+          "resource": resource.value.into_owned(),
+        })),
+        None => issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::NotFound,
+            &format!("Focus resource '{}' was not found", reference),
+          )
+          .at(&format!("MessageHeader.focus[{}]", index)),
+        ),
+      }
+    }
+    if let Some(definition) = self.definition {
+      let focus = entries[1..]
+        .iter()
+        .map(|e| &e["resource"])
+        .collect::<Vec<_>>();
+      issues.extend(check_definition(
+        &entries[0]["resource"],
+        &focus,
+        definition,
+      ));
+    }
+    if issues.iter().any(|i| i.is_error()) {
+      return Err(issues);
+    }
+    Ok(message_bundle(entries))
+  }
+}
+
+/// Splits a received message into its header and focus resources, checking it
+/// against a MessageDefinition when one is supplied.
+pub fn unpack_message<'a>(
+  bundle: &'a Bundle,
+  definition: Option<&MessageDefinition>,
+) -> Result<Message<'a>, Vec<Issue>> {
+  let mut issues = vec![];
+  if !matches!(bundle.fhir_type(), Some(BundleType::Message)) {
+    issues.push(
+      Issue::error(
+        OperationOutcome_IssueCode::Invalid,
+        "Bundle type must be message",
+      )
+      .at("Bundle.type"),
+    );
+  }
+  let header = match bundle
+    .value
+    .get("entry")
+    .and_then(|e| e.get(0))
+    .and_then(|e| e.get("resource"))
+  {
+    Some(header) if header["resourceType"] == "MessageHeader" => header,
+    _ => {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Structure,
+          "A message must have a MessageHeader as the first resource (bdl-12)",
+        )
+        .at("Bundle.entry[0]"),
+      );
+      return Err(issues);
+    }
+  };
+  let resolver = Resolver::new(bundle);
+  let mut focus = vec![];
+  for (index, reference) in array(&header["focus"]).iter().enumerate() {
+    let reference = match reference["reference"].as_str() {
+      Some(reference) => reference,
+      None => continue,
+    };
+    match resolver.resolve_from_entry(0, reference) {
+      Some(resource) => focus.push(resource),
+      None => issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::NotFound,
+          &format!(
+            "The message does not contain focus resource '{}'",
+            reference
+          ),
+        )
+        .at(&format!("Bundle.entry[0].resource.focus[{}]", index)),
+      ),
+    }
+  }
+  if let Some(definition) = definition {
+    let values = focus.iter().map(|f| &*f.value).collect::<Vec<_>>();
+    issues.extend(check_definition(header, &values, definition));
+  }
+  if issues.iter().any(|i| i.is_error()) {
+    return Err(issues);
+  }
+  Ok(Message {
+    header: MessageHeader {
+      value: Cow::Borrowed(header),
+    },
+    focus,
+  })
+}
+
+/// Builds the response message to a received request. The response header
+/// names the request's `MessageHeader.id` in `response.identifier`, swaps the
+/// request's source and destination and carries the same event.
+#[derive(Debug)]
+pub struct ResponseBuilder {
+  pub(crate) header: Value,
+  pub(crate) entries: Vec<Value>,
+}
+
+impl ResponseBuilder {
+  pub fn new(request: &MessageHeader, code: MessageHeader_ResponseCode) -> ResponseBuilder {
+    let request = &request.value;
+    let mut header = json!({
+      "resourceType": "MessageHeader",
+      "id": new_uuid(),
+      "source": {
+        "endpoint": request["destination"][0]["endpoint"].as_str().unwrap_or("urn:unknown"),
+      },
+      "response": {
+        "identifier": request["id"].as_str().unwrap_or_default(),
+        "code": code.to_string(),
+      },
+    });
+    if let Some(endpoint) = request["source"]["endpoint"].as_str() {
+      header["destination"] = json!([{"endpoint": endpoint}]);
+    }
+    for event in &["eventCoding", "eventUri"] {
+      if let Some(value) = request.get(*event) {
+        header[*event] = value.clone();
+      }
+    }
+    ResponseBuilder {
+      header,
+      entries: vec![],
+    }
+  }
+
+  /// Canonical URL of the MessageDefinition the response conforms to.
+  pub fn definition<'a>(&'a mut self, url: &str) -> &'a mut ResponseBuilder {
+    self.header["definition"] = json!(url);
+    self
+  }
+
+  /// Includes an OperationOutcome describing the result and points
+  /// `response.details` at it.
+  pub fn details<'a>(&'a mut self, outcome: OperationOutcome) -> &'a mut ResponseBuilder {
+    let full_url = new_uuid_urn();
+    let mut outcome = outcome.value.into_owned();
+    outcome["resourceType"] = json!("OperationOutcome");
+    self.header["response"]["details"] = json!({"reference": full_url});
+    self
+      .entries
+      .push(json!({"fullUrl": full_url, "resource": outcome}));
+    self
+  }
+
+  /// Adds a focus resource to the response.
+  pub fn focus<'a>(
+    &'a mut self,
+    full_url: &str,
+    resource: ResourceList,
+  ) -> &'a mut ResponseBuilder {
+    let mut focus = array(&self.header["focus"]).to_vec();
+    focus.push(json!({"reference": full_url}));
+    self.header["focus"] = json!(focus);
+    self
+      .entries
+      .push(json!({"fullUrl": full_url, "resource": resource.value.into_owned()}));
+    self
+  }
+
+  /// Builds the response Bundle. When the request's MessageDefinition is
+  /// supplied, the response's `definition` must be one of its allowed responses.
+  pub fn build(
+    &self,
+    request_definition: Option<&MessageDefinition>,
+  ) -> Result<Bundle<'static>, Vec<Issue>> {
+    let mut issues = vec![];
+    if let Some(definition) = request_definition {
+      let definition = &definition.value;
+      if definition["responseRequired"] == "never" {
+        issues.push(Issue::warning(
+          OperationOutcome_IssueCode::BusinessRule,
+          "The request's MessageDefinition says no response is expected",
+        ));
+      }
+      let allowed = array(&definition["allowedResponse"])
+        .iter()
+        .filter_map(|r| r["message"].as_str())
+        .collect::<Vec<_>>();
+      if !allowed.is_empty() {
+        let url = self.header["definition"].as_str().unwrap_or_default();
+        if !allowed.iter().any(|a| canonical_matches(a, url)) {
+          issues.push(
+            Issue::error(
+              OperationOutcome_IssueCode::BusinessRule,
+              &format!(
+                "Response definition '{}' is not one of the allowed responses: {}",
+                url,
+                allowed.join(", ")
+              ),
+            )
+            .at("MessageHeader.definition"),
+          );
+        }
+      }
+    }
+    if issues.iter().any(|i| i.is_error()) {
+      return Err(issues);
+    }
+    let mut entries = vec![json!({"fullUrl": new_uuid_urn(), "resource": self.header.clone()})];
+    entries.extend(self.entries.iter().cloned());
+    Ok(message_bundle(entries))
+  }
+}
+
+fn message_bundle(entries: Vec<Value>) -> Bundle<'static> {
+  Bundle {
+    value: Cow::Owned(json!({
+      "resourceType": "Bundle",
+      "id": new_uuid(),
+      "type": BundleType::Message.to_string(),
+      "timestamp": now_instant(),
+      "entry": entries,
+    })),
+  }
+}
+
+/// Checks a message header and its focus resources against the event and the
+/// focus types and cardinalities of a MessageDefinition.
+fn check_definition(
+  header: &Value,
+  focus: &[&Value],
+  definition: &MessageDefinition,
+) -> Vec<Issue> {
+  let definition = &definition.value;
+  let mut issues = vec![];
+  if let Some(coding) = definition.get("eventCoding") {
+    let event = &header["eventCoding"];
+    if event["system"] != coding["system"] || event["code"] != coding["code"] {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::BusinessRule,
+          &format!(
+            "Message event {}|{} does not match the definition's event {}|{}",
+            event["system"].as_str().unwrap_or_default(),
+            event["code"].as_str().unwrap_or_default(),
+            coding["system"].as_str().unwrap_or_default(),
+            coding["code"].as_str().unwrap_or_default()
+          ),
+        )
+        .at("MessageHeader.eventCoding"),
+      );
+    }
+  } else if let Some(uri) = definition["eventUri"].as_str() {
+    if header["eventUri"].as_str() != Some(uri) {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::BusinessRule,
+          &format!(
+            "Message event does not match the definition's event {}",
+            uri
+          ),
+        )
+        .at("MessageHeader.eventUri"),
+      );
+    }
+  }
+
+  let allowed = array(&definition["focus"]);
+  if allowed.is_empty() {
+    return issues;
+  }
+  for resource in focus {
+    let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+    if !allowed.iter().any(|f| f["code"] == resource_type) {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::BusinessRule,
+          &format!("{} is not an allowed focus for this message", resource_type),
+        )
+        .at("MessageHeader.focus"),
+      );
+    }
+  }
+  for allowed_focus in allowed {
+    let code = allowed_focus["code"].as_str().unwrap_or_default();
+    let count = focus.iter().filter(|f| f["resourceType"] == code).count() as u64;
+    let min = allowed_focus["min"].as_u64().unwrap_or(0);
+    let max = match allowed_focus["max"].as_str() {
+      Some("*") | None => None,
+      Some(max) => max.parse::<u64>().ok(),
+    };
+    if count < min || max.map(|max| count > max).unwrap_or(false) {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::BusinessRule,
+          &format!(
+            "Expected between {} and {} {} focus resources but found {}",
+            min,
+            allowed_focus["max"].as_str().unwrap_or("*"),
+            code,
+            count
+          ),
+        )
+        .at("MessageHeader.focus"),
+      );
+    }
+  }
+  issues
+}
+
+/// Canonical references in MessageDefinitions are often relative, e.g.
+/// `MessageDefinition/patient-link-response`.
+fn canonical_matches(reference: &str, url: &str) -> bool {
+  let reference = reference.split('|').next().unwrap_or_default();
+  let url = url.split('|').next().unwrap_or_default();
+  !url.is_empty() && (reference == url || url.ends_with(&format!("/{}", reference)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_assembles_and_unpacks_message() {
+    let request = load("examples-json/message-request-link.json");
+    let source = Bundle::new(&request);
+    let header = MessageHeader::new(&request["entry"][0]["resource"]);
+    let bundle = MessageAssembler::new("http://acme.com/ehr/fhir", &source)
+      .assemble(&header)
+      .unwrap();
+    let json = bundle.to_json();
+    assert_eq!(json["type"], "message");
+    assert_eq!(json["entry"].as_array().unwrap().len(), 3);
+
+    let message = unpack_message(&bundle, None).unwrap();
+    assert_eq!(
+      message.header.id(),
+      Some("267b18ce-3d37-4581-9baa-6fada338038b")
+    );
+    let ids = message
+      .focus
+      .iter()
+      .map(|f| f.value["id"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(ids, vec!["pat1", "pat2"]);
+  }
+
+  #[test]
+  fn test_checks_message_definition() {
+    let request = load("examples-json/message-request-link.json");
+    let definition_value = load("examples-json/messagedefinition-patient-link-notification.json");
+    let definition = MessageDefinition::new(&definition_value);
+    let issues = unpack_message(&Bundle::new(&request), Some(&definition)).unwrap_err();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(
+      issues[0].expression.as_deref(),
+      Some("MessageHeader.eventCoding")
+    );
+
+    let mut matching = definition_value.clone();
+    matching["eventCoding"]["code"] = json!("patient-link");
+    matching["focus"][0]["max"] = json!("1");
+    let issues = unpack_message(
+      &Bundle::new(&request),
+      Some(&MessageDefinition::new(&matching)),
+    )
+    .unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "Expected between 2 and 1 Patient focus resources but found 2"
+    );
+  }
+
+  #[test]
+  fn test_builds_response() {
+    let request = load("examples-json/message-request-link.json");
+    let header = MessageHeader::new(&request["entry"][0]["resource"]);
+    let definition_value = load("examples-json/messagedefinition-patient-link-notification.json");
+    let definition = MessageDefinition::new(&definition_value);
+    let outcome = json!({"issue": [{"severity": "information", "code": "informational"}]});
+
+    let response = ResponseBuilder::new(&header, MessageHeader_ResponseCode::Ok)
+      .definition("http://hl7.org/fhir/MessageDefinition/patient-link-response")
+      .details(OperationOutcome::new(&outcome))
+      .build(Some(&definition))
+      .unwrap()
+      .to_json();
+    let response_header = &response["entry"][0]["resource"];
+    assert_eq!(
+      response_header["response"]["identifier"],
+      "267b18ce-3d37-4581-9baa-6fada338038b"
+    );
+    assert_eq!(response_header["response"]["code"], "ok");
+    assert_eq!(
+      response_header["destination"][0]["endpoint"],
+      "http://example.org/clients/ehr-lite"
+    );
+    assert_eq!(
+      response["entry"][1]["resource"]["resourceType"],
+      "OperationOutcome"
+    );
+
+    let issues = ResponseBuilder::new(&header, MessageHeader_ResponseCode::Ok)
+      .definition("http://example.org/MessageDefinition/other")
+      .build(Some(&definition))
+      .unwrap_err();
+    assert_eq!(
+      issues[0].expression.as_deref(),
+      Some("MessageHeader.definition")
+    );
+  }
+}
@@ -0,0 +1,116 @@
+use crate::model::OperationOutcome::OperationOutcome;
+use crate::model::OperationOutcome_Issue::{
+  OperationOutcome_IssueCode, OperationOutcome_IssueSeverity,
+};
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+
+/// A single problem found while processing FHIR content, shaped like an
+/// `OperationOutcome.issue` so it can be reported back to a client unchanged.
+#[derive(Debug)]
+pub struct Issue {
+  pub severity: OperationOutcome_IssueSeverity,
+  pub code: OperationOutcome_IssueCode,
+  pub diagnostics: String,
+  pub expression: Option<String>,
+}
+
+impl Issue {
+  pub fn new(
+    severity: OperationOutcome_IssueSeverity,
+    code: OperationOutcome_IssueCode,
+    diagnostics: &str,
+  ) -> Issue {
+    Issue {
+      severity,
+      code,
+      diagnostics: diagnostics.to_string(),
+      expression: None,
+    }
+  }
+
+  pub fn error(code: OperationOutcome_IssueCode, diagnostics: &str) -> Issue {
+    Issue::new(OperationOutcome_IssueSeverity::Error, code, diagnostics)
+  }
+
+  pub fn warning(code: OperationOutcome_IssueCode, diagnostics: &str) -> Issue {
+    Issue::new(OperationOutcome_IssueSeverity::Warning, code, diagnostics)
+  }
+
+  pub fn information(code: OperationOutcome_IssueCode, diagnostics: &str) -> Issue {
+    Issue::new(
+      OperationOutcome_IssueSeverity::Information,
+      code,
+      diagnostics,
+    )
+  }
+
+  /// Sets the FHIRPath location of the element the issue refers to.
+  pub fn at(mut self, expression: &str) -> Issue {
+    self.expression = Some(expression.to_string());
+    self
+  }
+
+  /// True for `fatal` and `error` issues, which mean the content is not valid.
+  pub fn is_error(&self) -> bool {
+    matches!(
+      self.severity,
+      OperationOutcome_IssueSeverity::Fatal | OperationOutcome_IssueSeverity::Error
+    )
+  }
+
+  pub fn to_json(&self) -> Value {
+    let mut value = json!({
+      "severity": self.severity.to_string(),
+      "code": self.code.to_string(),
+      "diagnostics": self.diagnostics,
+    });
+    if let Some(expression) = &self.expression {
+      value["expression"] = json!([expression]);
+    }
+    value
+  }
+}
+
+/// Collects issues into an `OperationOutcome` resource. An outcome must carry at
+/// least one issue, so an empty list is reported as a single informational one.
+pub fn operation_outcome(issues: &[Issue]) -> OperationOutcome<'static> {
+  let mut issue = issues.iter().map(|i| i.to_json()).collect::<Vec<_>>();
+  if issue.is_empty() {
+    issue.push(Issue::information(OperationOutcome_IssueCode::Informational, "All OK").to_json());
+  }
+  OperationOutcome {
+    value: Cow::Owned(json!({
+      "resourceType": "OperationOutcome",
+      "issue": issue,
+    })),
+  }
+}
+
+/// The issues in an `OperationOutcome`, such as one a server sent back. Unknown
+/// severities and codes are read as `error` and `exception`, and the details
+/// text stands in for missing diagnostics.
+pub fn issues(outcome: &OperationOutcome) -> Vec<Issue> {
+  let issues = outcome.value["issue"].as_array().map(Vec::as_slice);
+  issues
+    .unwrap_or_default()
+    .iter()
+    .map(|issue| {
+      let severity = issue["severity"].as_str().unwrap_or_default();
+      let code = issue["code"].as_str().unwrap_or_default();
+      let diagnostics = issue["diagnostics"]
+        .as_str()
+        .or_else(|| issue["details"]["text"].as_str())
+        .unwrap_or_default();
+      Issue {
+        severity: OperationOutcome_IssueSeverity::from_string(severity)
+          .unwrap_or(OperationOutcome_IssueSeverity::Error),
+        code: OperationOutcome_IssueCode::from_string(code)
+          .unwrap_or(OperationOutcome_IssueCode::Exception),
+        diagnostics: diagnostics.to_string(),
+        expression: issue["expression"][0].as_str().map(str::to_string),
+      }
+    })
+    .collect()
+}
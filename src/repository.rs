@@ -0,0 +1,759 @@
+use crate::datetime::now_instant;
+use crate::ids::new_uuid;
+use crate::model::Bundle::{Bundle, BundleBuilder, BundleType};
+use crate::model::Bundle_Entry::{Bundle_Entry, Bundle_EntryBuilder};
+use crate::model::Bundle_Request::{Bundle_RequestBuilder, Bundle_RequestMethod};
+use crate::model::Bundle_Response::Bundle_ResponseBuilder;
+use crate::model::Meta::{Meta, MetaBuilder};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::search::{
+  date_range, query_pairs, Collection, Executor, Index, Indexer, Query, SearchParameters,
+};
+use crate::util::service_base;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+/// A version of a resource, or its deletion.
+struct Version {
+  resource: Option<Value>,
+  method: &'static str,
+  last_updated: String,
+}
+
+/// The versions of a resource, with the index of the current one while it
+/// isn't deleted.
+struct Entry {
+  versions: Vec<Version>,
+  index: Option<Index>,
+}
+
+/// The resource a write stored, and whether it was new.
+#[derive(Debug)]
+pub struct Saved {
+  pub resource: ResourceList<'static>,
+  pub created: bool,
+}
+
+/// An in-memory store of resources that keeps every version of them, for
+/// tests and small deployments. Writes keep `meta.versionId` and
+/// `meta.lastUpdated` up to date, and resources are indexed by the given
+/// SearchParameters for conditional operations and searches.
+///
+/// ```
+/// use fhir_rs::model::ResourceList::ResourceList;
+/// use fhir_rs::repository::Repository;
+/// use fhir_rs::search::SearchParameters;
+/// use serde_json::json;
+///
+/// let mut repository = Repository::new(SearchParameters::new());
+/// let patient = json!({"resourceType": "Patient", "active": true});
+/// let created = repository.create(&ResourceList::new(&patient)).unwrap().to_json();
+/// let id = created["id"].as_str().unwrap();
+/// assert_eq!(created["meta"]["versionId"], "1");
+/// assert!(repository.read("Patient", id).is_ok());
+/// ```
+pub struct Repository {
+  parameters: SearchParameters,
+  base: Option<String>,
+  id_generator: Box<dyn FnMut() -> String + Send>,
+  resources: HashMap<String, BTreeMap<String, Entry>>,
+  /// Every version by type, id and number, in the order they were made.
+  history: Vec<(String, String, usize)>,
+}
+
+impl Repository {
+  pub fn new(parameters: SearchParameters) -> Repository {
+    Repository {
+      parameters,
+      base: None,
+      id_generator: Box::new(new_uuid),
+      resources: HashMap::new(),
+      history: vec![],
+    }
+  }
+
+  /// Service base URL used for the `fullUrl` of history entries, e.g.
+  /// `http://example.org/fhir/`. Without one, `fullUrl` is left relative.
+  pub fn base<'a>(&'a mut self, base: &str) -> &'a mut Repository {
+    let base = service_base(base);
+    self.base = Some(base);
+    self
+  }
+
+  /// Replaces the id generator for created resources, which defaults to
+  /// random UUIDs.
+  pub fn id_generator<F>(&mut self, generator: F) -> &mut Repository
+  where
+    F: FnMut() -> String + Send + 'static,
+  {
+    self.id_generator = Box::new(generator);
+    self
+  }
+
+  pub fn parameters(&self) -> &SearchParameters {
+    &self.parameters
+  }
+
+  /// Stores a new resource under a new id, ignoring any id it has.
+  pub fn create(&mut self, resource: &ResourceList) -> Result<ResourceList<'static>, Vec<Issue>> {
+    let resource_type = resource_type(&resource.value)?;
+    let id = (self.id_generator)();
+    let mut value = (*resource.value).clone();
+    value["id"] = json!(id);
+    Ok(self.store(&resource_type, &id, Some(value), "POST"))
+  }
+
+  /// Creates a resource unless one matches the `If-None-Exist` search, e.g.
+  /// `identifier=http://acme.org/mrns|12345`, in which case that is
+  /// returned instead.
+  pub fn conditional_create(
+    &mut self,
+    resource: &ResourceList,
+    if_none_exist: &str,
+  ) -> Result<Saved, Vec<Issue>> {
+    let resource_type = resource_type(&resource.value)?;
+    match &self.matching(&resource_type, if_none_exist)?[..] {
+      [] => Ok(Saved {
+        resource: self.create(resource)?,
+        created: true,
+      }),
+      [id] => Ok(Saved {
+        resource: self.current(&resource_type, id)?,
+        created: false,
+      }),
+      _ => Err(multiple_matches(if_none_exist)),
+    }
+  }
+
+  pub fn read(&self, resource_type: &str, id: &str) -> Result<ResourceList<'_>, Vec<Issue>> {
+    let entry = self.entry(resource_type, id)?;
+    match &entry.versions[entry.versions.len() - 1].resource {
+      Some(resource) => Ok(ResourceList {
+        value: Cow::Borrowed(resource),
+      }),
+      None => Err(vec![Issue::error(
+        OperationOutcome_IssueCode::Deleted,
+        &format!("{}/{} has been deleted", resource_type, id),
+      )]),
+    }
+  }
+
+  pub fn vread(
+    &self,
+    resource_type: &str,
+    id: &str,
+    version_id: &str,
+  ) -> Result<ResourceList<'_>, Vec<Issue>> {
+    let entry = self.entry(resource_type, id)?;
+    let version = version_id
+      .parse::<usize>()
+      .ok()
+      .and_then(|number| entry.versions.get(number.checked_sub(1)?));
+    match version {
+      Some(Version {
+        resource: Some(resource),
+        ..
+      }) => Ok(ResourceList {
+        value: Cow::Borrowed(resource),
+      }),
+      Some(_) => Err(vec![Issue::error(
+        OperationOutcome_IssueCode::Deleted,
+        &format!(
+          "Version {} of {}/{} is a deletion",
+          version_id, resource_type, id
+        ),
+      )]),
+      None => Err(vec![Issue::error(
+        OperationOutcome_IssueCode::NotFound,
+        &format!("{}/{} has no version {}", resource_type, id, version_id),
+      )]),
+    }
+  }
+
+  /// Stores a new version of the resource with the id it has, creating it if
+  /// there is none. With an expected version, as from `If-Match`, the update
+  /// is rejected unless that is the current version.
+  pub fn update(
+    &mut self,
+    resource: &ResourceList,
+    expected_version: Option<&str>,
+  ) -> Result<Saved, Vec<Issue>> {
+    let resource_type = resource_type(&resource.value)?;
+    let id = match resource.value["id"].as_str() {
+      Some(id) => id.to_string(),
+      None => {
+        return Err(vec![Issue::error(
+          OperationOutcome_IssueCode::Required,
+          "An updated resource needs an id",
+        )
+        .at(&format!("{}.id", resource_type))])
+      }
+    };
+    let current = self.version_id(&resource_type, &id);
+    check_version(&resource_type, &id, current, expected_version)?;
+    let stored = self.store(&resource_type, &id, Some((*resource.value).clone()), "PUT");
+    Ok(Saved {
+      resource: stored,
+      created: current.is_none(),
+    })
+  }
+
+  /// Updates the resource that matches a search, or creates the resource when
+  /// none does.
+  pub fn conditional_update(
+    &mut self,
+    resource: &ResourceList,
+    criteria: &str,
+    expected_version: Option<&str>,
+  ) -> Result<Saved, Vec<Issue>> {
+    let resource_type = resource_type(&resource.value)?;
+    let given = resource.value["id"].as_str();
+    match &self.matching(&resource_type, criteria)?[..] {
+      [] if given.is_some() => self.update(resource, expected_version),
+      [] => Ok(Saved {
+        resource: self.create(resource)?,
+        created: true,
+      }),
+      [id] if given.is_some_and(|given| given != id) => Err(vec![Issue::error(
+        OperationOutcome_IssueCode::Conflict,
+        &format!(
+          "{}/{} matches, but the resource has the id {}",
+          resource_type,
+          id,
+          given.unwrap_or_default()
+        ),
+      )]),
+      [id] => {
+        let mut value = (*resource.value).clone();
+        value["id"] = json!(id);
+        self.update(&ResourceList::new(&value), expected_version)
+      }
+      _ => Err(multiple_matches(criteria)),
+    }
+  }
+
+  /// Deletes a resource, keeping its history. Deleting a deleted resource
+  /// does nothing.
+  pub fn delete(
+    &mut self,
+    resource_type: &str,
+    id: &str,
+    expected_version: Option<&str>,
+  ) -> Result<(), Vec<Issue>> {
+    let entry = self.entry(resource_type, id)?;
+    if entry.index.is_none() {
+      return Ok(());
+    }
+    check_version(
+      resource_type,
+      id,
+      Some(entry.versions.len()),
+      expected_version,
+    )?;
+    self.store(resource_type, id, None, "DELETE");
+    Ok(())
+  }
+
+  /// Deletes the resource that matches a search, returning its id, if any.
+  pub fn conditional_delete(
+    &mut self,
+    resource_type: &str,
+    criteria: &str,
+  ) -> Result<Option<String>, Vec<Issue>> {
+    match &self.matching(resource_type, criteria)?[..] {
+      [] => Ok(None),
+      [id] => {
+        let id = id.clone();
+        self.delete(resource_type, &id, None)?;
+        Ok(Some(id))
+      }
+      _ => Err(multiple_matches(criteria)),
+    }
+  }
+
+  /// The versions of a resource as a `history` Bundle, newest first,
+  /// optionally only those since an instant.
+  pub fn instance_history(
+    &self,
+    resource_type: &str,
+    id: &str,
+    since: Option<&str>,
+  ) -> Result<Bundle<'static>, Vec<Issue>> {
+    self.entry(resource_type, id)?;
+    self.history_bundle(since, |t, i| t == resource_type && i == id)
+  }
+
+  /// The versions of all resources of a type as a `history` Bundle.
+  pub fn type_history(
+    &self,
+    resource_type: &str,
+    since: Option<&str>,
+  ) -> Result<Bundle<'static>, Vec<Issue>> {
+    self.history_bundle(since, |t, _| t == resource_type)
+  }
+
+  /// The versions of all resources as a `history` Bundle.
+  pub fn system_history(&self, since: Option<&str>) -> Result<Bundle<'static>, Vec<Issue>> {
+    self.history_bundle(since, |_, _| true)
+  }
+
+  /// Runs a search over the current resources, giving a `searchset` Bundle
+  /// with links relative to the service base.
+  pub fn search(&self, query: &Query) -> Result<Bundle<'static>, Vec<Issue>> {
+    let mut executor = Executor::new(self);
+    if let Some(base) = &self.base {
+      executor.base(base);
+    }
+    executor.search(query)
+  }
+
+  /// Marks the current state, for [`Repository::rollback`] to return to.
+  pub fn savepoint(&self) -> usize {
+    self.history.len()
+  }
+
+  /// Undoes every write since a savepoint, as when a transaction fails.
+  pub fn rollback(&mut self, savepoint: usize) {
+    let indexer = Indexer::new(&self.parameters);
+    while self.history.len() > savepoint {
+      let (resource_type, id, _) = self.history.pop().unwrap();
+      let resources = self.resources.entry(resource_type).or_default();
+      let entry = match resources.get_mut(&id) {
+        Some(entry) => entry,
+        None => continue,
+      };
+      entry.versions.pop();
+      match entry.versions.last() {
+        Some(version) => {
+          entry.index = version
+            .resource
+            .as_ref()
+            .map(|resource| indexer.index(&ResourceList::new(resource)))
+        }
+        None => {
+          resources.remove(&id);
+        }
+      }
+    }
+  }
+
+  fn history_bundle<F>(
+    &self,
+    since: Option<&str>,
+    include: F,
+  ) -> Result<Bundle<'static>, Vec<Issue>>
+  where
+    F: Fn(&str, &str) -> bool,
+  {
+    let since = match since {
+      Some(since) => Some(date_range(since).map(|range| range.start).ok_or_else(|| {
+        vec![Issue::error(
+          OperationOutcome_IssueCode::Invalid,
+          &format!("_since must be an instant, not '{}'", since),
+        )]
+      })?),
+      None => None,
+    };
+    let mut entries = vec![];
+    for (resource_type, id, number) in self.history.iter().rev() {
+      if !include(resource_type, id) {
+        continue;
+      }
+      let version = &self.resources[resource_type][id].versions[*number - 1];
+      let updated = date_range(&version.last_updated).map(|range| range.start);
+      if since.is_some() && updated < since {
+        continue;
+      }
+      entries.push(self.history_entry(resource_type, id, *number, version));
+    }
+    let mut builder = BundleBuilder::new();
+    builder.value["resourceType"] = json!("Bundle");
+    builder
+      .id(&new_uuid())
+      .fhir_type(BundleType::History)
+      .timestamp(&now_instant())
+      .total(entries.len() as u64)
+      .entry(entries);
+    Ok(Bundle {
+      value: Cow::Owned(builder.value),
+    })
+  }
+
+  fn history_entry(
+    &self,
+    resource_type: &str,
+    id: &str,
+    number: usize,
+    version: &Version,
+  ) -> Bundle_Entry<'static> {
+    let location = format!("{}/{}", resource_type, id);
+    let mut request = Bundle_RequestBuilder::new();
+    let method = Bundle_RequestMethod::from_string(version.method).expect("a request method");
+    request.method(method).url(match version.method {
+      "POST" => resource_type,
+      _ => &location,
+    });
+    let status = match (version.method, number) {
+      ("DELETE", _) => "204 No Content",
+      ("POST", _) | (_, 1) => "201 Created",
+      _ => "200 OK",
+    };
+    let mut response = Bundle_ResponseBuilder::new();
+    response
+      .status(status)
+      .etag(&format!("W/\"{}\"", number))
+      .last_modified(&version.last_updated);
+    let mut entry = Bundle_EntryBuilder::new();
+    entry
+      .full_url(&match &self.base {
+        Some(base) => format!("{}{}", base, location),
+        None => location,
+      })
+      .request(request.build())
+      .response(response.build());
+    if let Some(resource) = &version.resource {
+      entry.resource(ResourceList::new(resource));
+    }
+    Bundle_Entry {
+      value: Cow::Owned(entry.value),
+    }
+  }
+
+  /// The ids of the current resources of a type that match a search.
+  fn matching(&self, resource_type: &str, criteria: &str) -> Result<Vec<String>, Vec<Issue>> {
+    let query = Query::parse_pairs(&self.parameters, resource_type, &query_pairs(criteria))?;
+    if query.criteria.is_empty() {
+      return Err(vec![Issue::error(
+        OperationOutcome_IssueCode::Invalid,
+        "Conditional operations need search criteria",
+      )]);
+    }
+    let matches = Executor::new(self).select(&query);
+    Ok(
+      matches
+        .iter()
+        .filter_map(|resource| resource.value["id"].as_str().map(String::from))
+        .collect(),
+    )
+  }
+
+  fn entry(&self, resource_type: &str, id: &str) -> Result<&Entry, Vec<Issue>> {
+    self
+      .resources
+      .get(resource_type)
+      .and_then(|resources| resources.get(id))
+      .ok_or_else(|| {
+        vec![Issue::error(
+          OperationOutcome_IssueCode::NotFound,
+          &format!("{}/{} is not known", resource_type, id),
+        )]
+      })
+  }
+
+  fn current(&self, resource_type: &str, id: &str) -> Result<ResourceList<'static>, Vec<Issue>> {
+    let resource = self.read(resource_type, id)?;
+    Ok(ResourceList {
+      value: Cow::Owned(resource.value.into_owned()),
+    })
+  }
+
+  /// The number of the current version of a resource, unless it doesn't
+  /// exist or is deleted.
+  fn version_id(&self, resource_type: &str, id: &str) -> Option<usize> {
+    let entry = self.entry(resource_type, id).ok()?;
+    entry.index.as_ref().map(|_| entry.versions.len())
+  }
+
+  /// Adds a version of a resource, or its deletion, and indexes it.
+  fn store(
+    &mut self,
+    resource_type: &str,
+    id: &str,
+    mut resource: Option<Value>,
+    method: &'static str,
+  ) -> ResourceList<'static> {
+    let last_updated = now_instant();
+    let indexer = Indexer::new(&self.parameters);
+    let entry = self
+      .resources
+      .entry(resource_type.to_string())
+      .or_default()
+      .entry(id.to_string())
+      .or_insert_with(|| Entry {
+        versions: vec![],
+        index: None,
+      });
+    let number = entry.versions.len() + 1;
+    if let Some(resource) = &mut resource {
+      let meta = Meta {
+        value: Cow::Owned(resource["meta"].take()),
+      };
+      let mut meta = match meta.value.is_object() {
+        true => MetaBuilder::with(meta),
+        false => MetaBuilder::new(),
+      };
+      meta
+        .version_id(&number.to_string())
+        .last_updated(&last_updated);
+      resource["meta"] = meta.value;
+    }
+    entry.index = resource
+      .as_ref()
+      .map(|resource| indexer.index(&ResourceList::new(resource)));
+    let stored = ResourceList {
+      value: Cow::Owned(resource.clone().unwrap_or(Value::Null)),
+    };
+    entry.versions.push(Version {
+      resource,
+      method,
+      last_updated,
+    });
+    self
+      .history
+      .push((resource_type.to_string(), id.to_string(), number));
+    stored
+  }
+}
+
+impl Collection for Repository {
+  fn resources(&self, resource_type: &str) -> Vec<(ResourceList<'_>, &Index)> {
+    let resources = self.resources.get(resource_type).into_iter().flatten();
+    resources.filter_map(|(_, entry)| current(entry)).collect()
+  }
+
+  fn get(&self, resource_type: &str, id: &str) -> Option<(ResourceList<'_>, &Index)> {
+    current(self.entry(resource_type, id).ok()?)
+  }
+}
+
+/// The current version of a resource with its index, unless it's deleted.
+fn current(entry: &Entry) -> Option<(ResourceList<'_>, &Index)> {
+  let index = entry.index.as_ref()?;
+  let resource = entry.versions.last()?.resource.as_ref()?;
+  Some((ResourceList::new(resource), index))
+}
+
+fn resource_type(resource: &Value) -> Result<String, Vec<Issue>> {
+  match resource["resourceType"].as_str() {
+    Some(resource_type) => Ok(resource_type.to_string()),
+    None => Err(vec![Issue::error(
+      OperationOutcome_IssueCode::Structure,
+      "A stored resource needs a resourceType",
+    )]),
+  }
+}
+
+fn check_version(
+  resource_type: &str,
+  id: &str,
+  current: Option<usize>,
+  expected: Option<&str>,
+) -> Result<(), Vec<Issue>> {
+  match expected {
+    Some(expected) if current.map(|c| c.to_string()).as_deref() != Some(expected) => {
+      Err(vec![Issue::error(
+        OperationOutcome_IssueCode::Conflict,
+        &format!(
+          "{}/{} is at version {}, not {}",
+          resource_type,
+          id,
+          current.map_or("none".to_string(), |c| c.to_string()),
+          expected
+        ),
+      )])
+    }
+    _ => Ok(()),
+  }
+}
+
+fn multiple_matches(criteria: &str) -> Vec<Issue> {
+  vec![Issue::error(
+    OperationOutcome_IssueCode::MultipleMatches,
+    &format!("More than one resource matches {}", criteria),
+  )]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Path;
+
+  fn repository() -> Repository {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let mut repository = Repository::new(parameters);
+    let mut next = 0;
+    repository
+      .base("http://example.org/fhir")
+      .id_generator(move || {
+        next += 1;
+        format!("p{}", next)
+      });
+    repository
+  }
+
+  #[test]
+  fn test_versions() {
+    let mut repository = repository();
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "ignored",
+      "identifier": [{"system": "http://acme.org/mrns", "value": "12345"}],
+      "meta": {"tag": [{"code": "test"}]}
+    });
+    let created = repository.create(&ResourceList::new(&patient)).unwrap();
+    assert_eq!(created.value["id"], "p1");
+    assert_eq!(created.value["meta"]["versionId"], "1");
+    assert_eq!(created.value["meta"]["tag"][0]["code"], "test");
+
+    let mut updated = created.value.into_owned();
+    updated["active"] = json!(true);
+    let saved = repository
+      .update(&ResourceList::new(&updated), Some("1"))
+      .unwrap();
+    assert!(!saved.created);
+    assert_eq!(saved.resource.value["meta"]["versionId"], "2");
+    let conflict = repository
+      .update(&ResourceList::new(&updated), Some("1"))
+      .unwrap_err();
+    assert_eq!(conflict[0].diagnostics, "Patient/p1 is at version 2, not 1");
+
+    assert_eq!(
+      repository
+        .vread("Patient", "p1", "1")
+        .unwrap()
+        .value
+        .get("active"),
+      None
+    );
+    assert_eq!(
+      repository.read("Patient", "p1").unwrap().value["active"],
+      true
+    );
+
+    repository.delete("Patient", "p1", None).unwrap();
+    let gone = repository.read("Patient", "p1").unwrap_err();
+    assert!(matches!(gone[0].code, OperationOutcome_IssueCode::Deleted));
+    assert!(repository.vread("Patient", "p1", "2").is_ok());
+    assert!(repository.read("Patient", "nobody").is_err());
+
+    let history = repository
+      .instance_history("Patient", "p1", None)
+      .unwrap()
+      .to_json();
+    assert_eq!(history["type"], "history");
+    assert_eq!(history["total"], 3);
+    let entry = |index: usize, key: &str| history["entry"][index][key].clone();
+    assert_eq!(entry(0, "request")["method"], "DELETE");
+    assert_eq!(entry(0, "request")["url"], "Patient/p1");
+    assert_eq!(entry(0, "response")["status"], "204 No Content");
+    assert!(entry(0, "resource").is_null());
+    assert_eq!(entry(2, "request")["url"], "Patient");
+    assert_eq!(entry(2, "response")["etag"], "W/\"1\"");
+    assert_eq!(entry(2, "fullUrl"), "http://example.org/fhir/Patient/p1");
+
+    repository
+      .update(
+        &ResourceList::new(&json!({"resourceType": "Observation", "id": "o1", "status": "final"})),
+        None,
+      )
+      .unwrap();
+    let history = repository.system_history(None).unwrap().to_json();
+    assert_eq!(history["total"], 4);
+    assert_eq!(history["entry"][0]["response"]["status"], "201 Created");
+    let history = repository
+      .type_history("Observation", None)
+      .unwrap()
+      .to_json();
+    assert_eq!(history["total"], 1);
+    let history = repository
+      .system_history(Some("2999-01-01"))
+      .unwrap()
+      .to_json();
+    assert_eq!(history["total"], 0);
+  }
+
+  #[test]
+  fn test_conditional() {
+    let mut repository = repository();
+    let patient = json!({
+      "resourceType": "Patient",
+      "identifier": [{"system": "http://acme.org/mrns", "value": "12345"}],
+      "name": [{"family": "Chalmers"}]
+    });
+    let criteria = "identifier=http://acme.org/mrns|12345";
+    let first = repository
+      .conditional_create(&ResourceList::new(&patient), criteria)
+      .unwrap();
+    let second = repository
+      .conditional_create(&ResourceList::new(&patient), criteria)
+      .unwrap();
+    assert!(first.created && !second.created);
+    assert_eq!(second.resource.value["id"], "p1");
+
+    let mut updated = patient.clone();
+    updated["active"] = json!(false);
+    let saved = repository
+      .conditional_update(&ResourceList::new(&updated), criteria, None)
+      .unwrap();
+    assert_eq!(saved.resource.value["id"], "p1");
+    assert_eq!(saved.resource.value["meta"]["versionId"], "2");
+    let saved = repository
+      .conditional_update(&ResourceList::new(&updated), "family=smith", None)
+      .unwrap();
+    assert!(saved.created);
+    assert_eq!(saved.resource.value["id"], "p2");
+
+    let multiple = repository
+      .conditional_delete("Patient", "active=false")
+      .unwrap_err();
+    assert!(matches!(
+      multiple[0].code,
+      OperationOutcome_IssueCode::MultipleMatches
+    ));
+    assert_eq!(
+      repository
+        .conditional_delete("Patient", "family=chalmers&_id=p2")
+        .unwrap(),
+      Some("p2".to_string())
+    );
+    assert!(repository.conditional_delete("Patient", "").is_err());
+    let observation =
+      json!({"resourceType": "Observation", "subject": {"reference": "Patient/p1"}});
+    let saved = repository
+      .conditional_create(
+        &ResourceList::new(&observation),
+        "subject:Patient.family=chalmers",
+      )
+      .unwrap();
+    assert!(saved.created);
+    assert!(
+      !repository
+        .conditional_create(
+          &ResourceList::new(&observation),
+          "subject:Patient.family=chalmers"
+        )
+        .unwrap()
+        .created
+    );
+    assert!(repository
+      .conditional_create(&ResourceList::new(&patient), "unknown=1")
+      .is_err());
+    let savepoint = repository.savepoint();
+    repository.delete("Patient", "p1", None).unwrap();
+    repository.create(&ResourceList::new(&patient)).unwrap();
+    repository.rollback(savepoint);
+    assert!(repository.read("Patient", "p1").is_ok());
+    assert!(repository.read("Patient", "p3").is_err());
+    assert_eq!(
+      repository.conditional_delete("Patient", "_id=p1").unwrap(),
+      Some("p1".to_string())
+    );
+  }
+}
@@ -0,0 +1,390 @@
+use crate::model::Bundle::Bundle;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Somewhere resources can be looked up by a literal reference, used when
+/// assembling Bundles from resources that live elsewhere.
+pub trait ResourceSource {
+  fn fetch(&self, reference: &str) -> Option<ResourceList<'_>>;
+}
+
+/// Resources keyed by their relative reference, e.g. `Patient/example`. Absolute
+/// RESTful references are also matched on their trailing `Type/id`.
+impl ResourceSource for HashMap<String, Value> {
+  fn fetch(&self, reference: &str) -> Option<ResourceList<'_>> {
+    let (reference, _) = split_history(reference);
+    self
+      .get(reference)
+      .or_else(|| self.get(trailing_id(reference)?))
+      .map(|value| ResourceList {
+        value: Cow::Borrowed(value),
+      })
+  }
+}
+
+/// Entries of a Bundle, matched on `fullUrl` or on the resource's `Type/id`.
+impl ResourceSource for Bundle<'_> {
+  fn fetch(&self, reference: &str) -> Option<ResourceList<'_>> {
+    bundle_entry(&self.value, reference).map(|value| ResourceList {
+      value: Cow::Borrowed(value),
+    })
+  }
+}
+
+/// The resource of the Bundle entry with the given `fullUrl`, or failing that
+/// the entry whose resource has the reference's `Type/id`.
+pub(crate) fn bundle_entry<'a>(bundle: &'a Value, reference: &str) -> Option<&'a Value> {
+  let (reference, _) = split_history(reference);
+  let entries = bundle.get("entry")?.as_array()?;
+  entries
+    .iter()
+    .find(|e| e["fullUrl"].as_str() == Some(reference))
+    .or_else(|| {
+      let id = trailing_id(reference).unwrap_or(reference);
+      entries
+        .iter()
+        .find(|e| relative_id(&e["resource"]).as_deref() == Some(id))
+    })
+    .and_then(|e| e.get("resource"))
+}
+
+/// Resolves literal references between the resources of a Bundle, following the
+/// rules in http://hl7.org/fhir/bundle.html#references.
+pub struct Resolver<'a> {
+  entries: Vec<&'a Value>,
+}
+
+impl<'a> Resolver<'a> {
+  pub fn new(bundle: &'a Bundle) -> Resolver<'a> {
+    let entries = match bundle.value.get("entry") {
+      Some(Value::Array(entries)) => entries.iter().collect(),
+      _ => vec![],
+    };
+    Resolver { entries }
+  }
+
+  /// Resolves `reference` as it appears inside `container`, the resource held by
+  /// the entry with the given `fullUrl`. Fragment references (`#id`) are looked up
+  /// in the container's `contained` list; a bare `#` is the container itself.
+  pub fn resolve(
+    &self,
+    full_url: Option<&str>,
+    container: &'a ResourceList,
+    reference: &str,
+  ) -> Option<ResourceList<'a>> {
+    if reference.starts_with('#') {
+      return resolve_contained(&container.value, reference).map(|value| ResourceList {
+        value: Cow::Borrowed(value),
+      });
+    }
+    self
+      .resolve_in_bundle(full_url, reference)
+      .map(|value| ResourceList {
+        value: Cow::Borrowed(value),
+      })
+  }
+
+  /// Resolves the reference held by a resource of the Bundle itself, identified
+  /// by the entry index.
+  pub fn resolve_from_entry(&self, entry: usize, reference: &str) -> Option<ResourceList<'a>> {
+    let entry_value = self.entries.get(entry)?;
+    let full_url = entry_value.get("fullUrl").and_then(|v| v.as_str());
+    let container = entry_value.get("resource")?;
+    let value = if reference.starts_with('#') {
+      resolve_contained(container, reference)
+    } else {
+      self.resolve_in_bundle(full_url, reference)
+    }?;
+    Some(ResourceList {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  /// Walks every resource in the Bundle and reports each literal reference that
+  /// cannot be resolved within it. Logical references (identifier only) and
+  /// references to contained resources that exist are not reported.
+  pub fn dangling_references(&self) -> Vec<Issue> {
+    let mut issues = vec![];
+    for (index, entry) in self.entries.iter().enumerate() {
+      if let Some(resource) = entry.get("resource") {
+        let full_url = entry.get("fullUrl").and_then(|v| v.as_str());
+        let path = format!("Bundle.entry[{}].resource", index);
+        self.check_references(full_url, resource, resource, &path, &mut issues);
+      }
+    }
+    issues
+  }
+
+  fn check_references(
+    &self,
+    full_url: Option<&str>,
+    container: &Value,
+    value: &Value,
+    path: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    match value {
+      Value::Object(map) => {
+        if let Some(Value::String(reference)) = map.get("reference") {
+          let resolved = if reference.starts_with('#') {
+            resolve_contained(container, reference).is_some()
+          } else {
+            self.resolve_in_bundle(full_url, reference).is_some()
+          };
+          if !resolved {
+            issues.push(
+              Issue::error(
+                OperationOutcome_IssueCode::NotFound,
+                &format!("Unable to resolve reference '{}'", reference),
+              )
+              .at(path),
+            );
+          }
+        }
+        for (key, child) in map {
+          // Contained resources resolve their references against the container.
+          self.check_references(
+            full_url,
+            container,
+            child,
+            &format!("{}.{}", path, key),
+            issues,
+          );
+        }
+      }
+      Value::Array(items) => {
+        for (index, item) in items.iter().enumerate() {
+          self.check_references(
+            full_url,
+            container,
+            item,
+            &format!("{}[{}]", path, index),
+            issues,
+          );
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn resolve_in_bundle(&self, full_url: Option<&str>, reference: &str) -> Option<&'a Value> {
+    let (target, version) = split_history(reference);
+    let target = if is_absolute(target) {
+      target.to_string()
+    } else {
+      match full_url.and_then(restful_base) {
+        Some(base) => format!("{}{}", base, target),
+        None => {
+          // The container has no RESTful base, so fall back to matching the
+          // relative reference against the type and id of entries without a
+          // fullUrl, or the end of RESTful fullUrls.
+          return self.find(
+            |entry, resource| match entry.get("fullUrl").and_then(|v| v.as_str()) {
+              Some(full_url) => trailing_id(split_history(full_url).0) == Some(target),
+              None => relative_id(resource).as_deref() == Some(target),
+            },
+            version,
+          );
+        }
+      }
+    };
+    self.find(
+      |entry, _| entry.get("fullUrl").and_then(|v| v.as_str()) == Some(target.as_str()),
+      version,
+    )
+  }
+
+  fn find<F>(&self, matches: F, version: Option<&str>) -> Option<&'a Value>
+  where
+    F: Fn(&Value, &Value) -> bool,
+  {
+    self
+      .entries
+      .iter()
+      .filter_map(|entry| entry.get("resource").map(|resource| (*entry, resource)))
+      .filter(|(entry, resource)| matches(entry, resource))
+      .find(|(_, resource)| match version {
+        Some(version) => resource["meta"]["versionId"].as_str() == Some(version),
+        None => true,
+      })
+      .map(|(_, resource)| resource)
+  }
+}
+
+fn resolve_contained<'v>(container: &'v Value, reference: &str) -> Option<&'v Value> {
+  let id = &reference[1..];
+  if id.is_empty() {
+    return Some(container);
+  }
+  match container.get("contained") {
+    Some(Value::Array(contained)) => contained.iter().find(|c| c["id"].as_str() == Some(id)),
+    _ => None,
+  }
+}
+
+pub(crate) fn is_absolute(reference: &str) -> bool {
+  reference.starts_with("urn:") || reference.contains("://")
+}
+
+/// Splits `Patient/1/_history/2` into the unversioned reference and its version.
+pub(crate) fn split_history(reference: &str) -> (&str, Option<&str>) {
+  match reference.find("/_history/") {
+    Some(index) => (
+      &reference[..index],
+      Some(&reference[index + "/_history/".len()..]),
+    ),
+    None => (reference, None),
+  }
+}
+
+/// The resource type named by a relative or RESTful reference, e.g. `Patient`
+/// for `Patient/1` or `http://server/fhir/Patient/1/_history/2`.
+pub(crate) fn reference_type(reference: &str) -> Option<&str> {
+  let (unversioned, _) = split_history(reference);
+  let mut parts = unversioned.rsplitn(3, '/');
+  let _id = parts.next()?;
+  let resource_type = parts.next()?;
+  if resource_type.starts_with(|c: char| c.is_ascii_uppercase())
+    && resource_type.chars().all(|c| c.is_ascii_alphanumeric())
+  {
+    Some(resource_type)
+  } else {
+    None
+  }
+}
+
+/// For a RESTful fullUrl like `http://server/fhir/Patient/1`, returns the
+/// service base `http://server/fhir/`.
+fn restful_base(full_url: &str) -> Option<&str> {
+  if full_url.starts_with("urn:") {
+    return None;
+  }
+  let (unversioned, _) = split_history(full_url);
+  let mut parts = unversioned.rsplitn(3, '/');
+  let _id = parts.next()?;
+  let _resource_type = parts.next()?;
+  let base = parts.next()?;
+  Some(&full_url[..base.len() + 1])
+}
+
+/// The `Type/id` at the end of an absolute RESTful reference.
+fn trailing_id(reference: &str) -> Option<&str> {
+  let base = restful_base(reference)?;
+  Some(&reference[base.len()..])
+}
+
+fn relative_id(resource: &Value) -> Option<String> {
+  Some(format!(
+    "{}/{}",
+    resource.get("resourceType")?.as_str()?,
+    resource.get("id")?.as_str()?
+  ))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  #[test]
+  fn test_resolves_bundle_references() {
+    let value = load("examples-json/bundle-references.json");
+    let bundle = Bundle::new(&value);
+    let resolver = Resolver::new(&bundle);
+
+    let patient = resolver.resolve_from_entry(2, "Patient/23").unwrap();
+    assert_eq!(patient.value["id"], "23");
+    let absolute = resolver
+      .resolve_from_entry(3, "http://example.org/fhir/Patient/23")
+      .unwrap();
+    assert_eq!(absolute.value["id"], "23");
+    let uuid = resolver
+      .resolve_from_entry(4, "urn:uuid:04121321-4af5-424c-a0e1-ed3aab1c349d")
+      .unwrap();
+    assert_eq!(uuid.value["resourceType"], "Patient");
+    let versioned = resolver
+      .resolve_from_entry(9, "Patient/45/_history/2")
+      .unwrap();
+    assert_eq!(versioned.value["meta"]["versionId"], "2");
+
+    let dangling = resolver.dangling_references();
+    let paths = dangling
+      .iter()
+      .map(|i| i.expression.as_deref().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      paths,
+      vec![
+        "Bundle.entry[5].resource.subject",
+        "Bundle.entry[6].resource.subject"
+      ]
+    );
+  }
+
+  #[test]
+  fn test_resolves_contained_references() {
+    let value = serde_json::json!({
+      "resourceType": "Bundle",
+      "type": "collection",
+      "entry": [{
+        "fullUrl": "urn:uuid:1",
+        "resource": {
+          "resourceType": "Condition",
+          "contained": [{"resourceType": "Practitioner", "id": "p1"}],
+          "asserter": {"reference": "#p1"},
+          "recorder": {"reference": "#p2"}
+        }
+      }]
+    });
+    let bundle = Bundle::new(&value);
+    let resolver = Resolver::new(&bundle);
+    let container = ResourceList::new(&value["entry"][0]["resource"]);
+    let practitioner = resolver
+      .resolve(Some("urn:uuid:1"), &container, "#p1")
+      .unwrap();
+    assert_eq!(practitioner.value["resourceType"], "Practitioner");
+    let dangling = resolver.dangling_references();
+    assert_eq!(dangling.len(), 1);
+    assert_eq!(dangling[0].diagnostics, "Unable to resolve reference '#p2'");
+  }
+
+  #[test]
+  fn test_resolves_relative_references_from_urns() {
+    // Without a RESTful base, relative references still match the end of
+    // RESTful fullUrls.
+    let value = serde_json::json!({
+      "resourceType": "Bundle",
+      "type": "collection",
+      "entry": [{
+        "fullUrl": "urn:uuid:1",
+        "resource": {
+          "resourceType": "Observation",
+          "subject": {"reference": "Patient/123"},
+          "performer": [{"reference": "Practitioner/123"}]
+        }
+      }, {
+        "fullUrl": "http://x/fhir/Patient/123",
+        "resource": {"resourceType": "Patient", "id": "123"}
+      }]
+    });
+    let bundle = Bundle::new(&value);
+    let resolver = Resolver::new(&bundle);
+    let patient = resolver.resolve_from_entry(0, "Patient/123").unwrap();
+    assert_eq!(patient.value["resourceType"], "Patient");
+    assert!(resolver.resolve_from_entry(0, "Practitioner/123").is_none());
+    let dangling = resolver.dangling_references();
+    assert_eq!(dangling.len(), 1);
+    assert_eq!(
+      dangling[0].expression.as_deref(),
+      Some("Bundle.entry[0].resource.performer[0]")
+    );
+  }
+}
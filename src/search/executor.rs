@@ -0,0 +1,586 @@
+use super::{Criterion, Include, Index, IndexValue, Link, Query, Summary, Total};
+use crate::datetime::now_instant;
+use crate::ids::new_uuid;
+use crate::model::Bundle::{Bundle, BundleBuilder, BundleType};
+use crate::model::Bundle_Entry::{Bundle_Entry, Bundle_EntryBuilder};
+use crate::model::Bundle_Link::{Bundle_Link, Bundle_LinkBuilder};
+use crate::model::Bundle_Search::{Bundle_SearchBuilder, Bundle_SearchMode};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::util::service_base;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+/// How many matches a page has when the search doesn't say.
+const DEFAULT_COUNT: usize = 50;
+
+/// Chains and `_has` are followed this deep at most.
+const MAX_DEPTH: usize = 8;
+
+/// Resources that can be searched: the current version of each with its index
+/// values.
+pub trait Collection {
+  /// The resources of a type.
+  fn resources(&self, resource_type: &str) -> Vec<(ResourceList<'_>, &Index)>;
+
+  fn get(&self, resource_type: &str, id: &str) -> Option<(ResourceList<'_>, &Index)>;
+}
+
+/// A resource found by a search.
+struct Found<'c> {
+  resource: ResourceList<'c>,
+  index: &'c Index,
+}
+
+impl<'c> Found<'c> {
+  fn key(&self) -> (String, String) {
+    let value = &self.resource.value;
+    let part = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    (part("resourceType"), part("id"))
+  }
+}
+
+/// Runs searches over a [`Collection`], giving `searchset` Bundles.
+///
+/// ```
+/// use fhir_rs::model::ResourceList::ResourceList;
+/// use fhir_rs::repository::Repository;
+/// use fhir_rs::search::{Executor, Query, SearchParameters};
+/// use serde_json::json;
+/// use std::path::Path;
+///
+/// let mut parameters = SearchParameters::new();
+/// parameters
+///   .load_file(Path::new("examples-json/search-parameters.json"))
+///   .unwrap();
+/// let mut repository = Repository::new(parameters);
+/// let patient = json!({"resourceType": "Patient", "name": [{"family": "Chalmers"}]});
+/// repository.create(&ResourceList::new(&patient)).unwrap();
+///
+/// let query = Query::parse(repository.parameters(), "Patient?name=chal").unwrap();
+/// let bundle = Executor::new(&repository).search(&query).unwrap().to_json();
+/// assert_eq!(bundle["total"], 1);
+/// assert_eq!(bundle["entry"][0]["search"]["mode"], "match");
+/// ```
+pub struct Executor<'c> {
+  collection: &'c dyn Collection,
+  base: Option<String>,
+}
+
+impl<'c> Executor<'c> {
+  pub fn new(collection: &'c dyn Collection) -> Executor<'c> {
+    Executor {
+      collection,
+      base: None,
+    }
+  }
+
+  /// Service base URL for links and `fullUrl`s, e.g.
+  /// `http://example.org/fhir/`. Without one, they are left relative.
+  pub fn base<'b>(&'b mut self, base: &str) -> &'b mut Executor<'c> {
+    let base = service_base(base);
+    self.base = Some(base);
+    self
+  }
+
+  /// The resources that meet all the criteria of a search, in the order it
+  /// sorts them, without paging.
+  pub fn select(&self, query: &Query) -> Vec<ResourceList<'c>> {
+    self
+      .matches(query)
+      .into_iter()
+      .map(|found| found.resource)
+      .collect()
+  }
+
+  /// Runs a search, giving a page of the matches with what they include.
+  pub fn search(&self, query: &Query) -> Result<Bundle<'static>, Vec<Issue>> {
+    if query.result.summary == Some(Summary::True) {
+      return Err(vec![Issue::error(
+        OperationOutcome_IssueCode::NotSupported,
+        "_summary=true isn't supported; use _elements to choose elements",
+      )]);
+    }
+    let matches = self.matches(query);
+    let total = matches.len();
+    let count = match query.result.summary {
+      Some(Summary::Count) => 0,
+      _ => query.result.count.unwrap_or(DEFAULT_COUNT),
+    };
+    let offset = query.result.offset.unwrap_or(0).min(total);
+    let page = matches
+      .into_iter()
+      .skip(offset)
+      .take(count)
+      .collect::<Vec<_>>();
+    let included = self.included(query, &page);
+
+    let mut entries = vec![];
+    for (found, mode) in page
+      .iter()
+      .map(|found| (found, Bundle_SearchMode::Match))
+      .chain(
+        included
+          .iter()
+          .map(|found| (found, Bundle_SearchMode::Include)),
+      )
+    {
+      let matched = matches!(mode, Bundle_SearchMode::Match);
+      let mut search = Bundle_SearchBuilder::new();
+      search.mode(mode);
+      if matched {
+        search.score(1.0);
+      }
+      let (resource_type, id) = found.key();
+      let mut entry = Bundle_EntryBuilder::new();
+      entry
+        .full_url(&self.url(&format!("{}/{}", resource_type, id)))
+        .resource(ResourceList::new(&shape(query, &found.resource.value)))
+        .search(search.build());
+      entries.push(Bundle_Entry {
+        value: Cow::Owned(entry.value),
+      });
+    }
+
+    let mut links = vec![self.link("self", query, offset, count)];
+    if offset + count < total && count > 0 {
+      links.push(self.link("next", query, offset + count, count));
+    }
+    if offset > 0 && count > 0 {
+      links.push(self.link("previous", query, offset.saturating_sub(count), count));
+    }
+
+    let mut builder = BundleBuilder::new();
+    builder.value["resourceType"] = json!("Bundle");
+    builder
+      .id(&new_uuid())
+      .fhir_type(BundleType::Searchset)
+      .timestamp(&now_instant())
+      .link(links);
+    if query.result.total != Some(Total::None) {
+      builder.total(total as u64);
+    }
+    if !entries.is_empty() {
+      builder.entry(entries);
+    }
+    Ok(Bundle {
+      value: Cow::Owned(builder.value),
+    })
+  }
+
+  fn matches(&self, query: &Query) -> Vec<Found<'c>> {
+    let mut matches = self
+      .collection
+      .resources(&query.resource_type)
+      .into_iter()
+      .map(|(resource, index)| Found { resource, index })
+      .filter(|found| {
+        query
+          .criteria
+          .iter()
+          .all(|criterion| self.meets(found, criterion, &criterion.chain, 0))
+      })
+      .collect::<Vec<_>>();
+    for sort in query.result.sort.iter().rev() {
+      // Resources without a value for the parameter go last either way.
+      matches.sort_by(|a, b| {
+        let (a, b) = (
+          sort_key(a.index, &sort.code, sort.descending),
+          sort_key(b.index, &sort.code, sort.descending),
+        );
+        match (a, b) {
+          (Some(a), Some(b)) if sort.descending => compare_keys(&b, &a),
+          (Some(a), Some(b)) => compare_keys(&a, &b),
+          (a, b) => b.is_some().cmp(&a.is_some()),
+        }
+      });
+    }
+    matches
+  }
+
+  /// Whether a resource meets a criterion, following the links of its chain
+  /// that are left.
+  fn meets(&self, found: &Found, criterion: &Criterion, chain: &[Link], depth: usize) -> bool {
+    let (link, rest) = match chain.split_first() {
+      Some(split) if depth < MAX_DEPTH => split,
+      Some(_) => return false,
+      None => return criterion.matches(found.index),
+    };
+    match link {
+      Link::Chain {
+        code,
+        resource_type,
+      } => found.index.values(code).any(|value| {
+        let target = match value {
+          IndexValue::Reference {
+            resource_type: Some(target_type),
+            id: Some(id),
+            ..
+          } if resource_type.as_ref().is_none_or(|t| t == target_type) => {
+            self.collection.get(target_type, id)
+          }
+          _ => None,
+        };
+        target.is_some_and(|(resource, index)| {
+          self.meets(&Found { resource, index }, criterion, rest, depth + 1)
+        })
+      }),
+      Link::Has {
+        resource_type,
+        code,
+      } => {
+        let key = found.key();
+        self
+          .collection
+          .resources(resource_type)
+          .into_iter()
+          .any(|(resource, index)| {
+            refers_to(index, code, &key)
+              && self.meets(&Found { resource, index }, criterion, rest, depth + 1)
+          })
+      }
+    }
+  }
+
+  /// The resources that `_include` and `_revinclude` add to a page, which
+  /// aren't on it already.
+  fn included(&self, query: &Query, page: &[Found<'c>]) -> Vec<Found<'c>> {
+    let mut seen = page.iter().map(Found::key).collect::<HashSet<_>>();
+    let mut included = vec![];
+    // Includes apply to the matches, and those marked :iterate also to what
+    // they include, until nothing new is included.
+    let mut sources = page.iter().collect::<Vec<_>>();
+    let mut iterating = false;
+    let mut added = vec![];
+    while !sources.is_empty() {
+      for found in &sources {
+        let (resource_type, _) = found.key();
+        for include in applicable(&query.result.include, iterating) {
+          if include.resource_type != resource_type {
+            continue;
+          }
+          for value in found
+            .index
+            .values(&include.code)
+            .chain(all_references(found.index, &include.code))
+          {
+            if let IndexValue::Reference {
+              resource_type: Some(target_type),
+              id: Some(id),
+              ..
+            } = value
+            {
+              if include
+                .target_type
+                .as_ref()
+                .is_some_and(|t| t != target_type)
+              {
+                continue;
+              }
+              if let Some((resource, index)) = self.collection.get(target_type, id) {
+                let target = Found { resource, index };
+                if seen.insert(target.key()) {
+                  added.push(target);
+                }
+              }
+            }
+          }
+        }
+        for include in applicable(&query.result.revinclude, iterating) {
+          if include
+            .target_type
+            .as_ref()
+            .is_some_and(|t| *t != resource_type)
+          {
+            continue;
+          }
+          let key = found.key();
+          for (resource, index) in self.collection.resources(&include.resource_type) {
+            let refers = match include.code.as_str() {
+              "*" => index
+                .entries
+                .iter()
+                .any(|e| is_reference_to(&e.value, &key)),
+              code => refers_to(index, code, &key),
+            };
+            let source = Found { resource, index };
+            if refers && seen.insert(source.key()) {
+              added.push(source);
+            }
+          }
+        }
+      }
+      let start = included.len();
+      included.append(&mut added);
+      sources = included[start..].iter().collect();
+      iterating = true;
+    }
+    included
+  }
+
+  fn link(
+    &self,
+    relation: &str,
+    query: &Query,
+    offset: usize,
+    count: usize,
+  ) -> Bundle_Link<'static> {
+    let mut query = query.clone();
+    query.result.offset = Some(offset).filter(|offset| *offset > 0);
+    if query.result.summary != Some(Summary::Count) {
+      query.result.count = Some(count);
+    }
+    let mut link = Bundle_LinkBuilder::new();
+    link.relation(relation).url(&self.url(&query.to_string()));
+    Bundle_Link {
+      value: Cow::Owned(link.value),
+    }
+  }
+
+  fn url(&self, relative: &str) -> String {
+    match &self.base {
+      Some(base) => format!("{}{}", base, relative),
+      None => relative.to_string(),
+    }
+  }
+}
+
+/// The includes to apply: all of them at first, then those that iterate.
+fn applicable(includes: &[Include], iterating: bool) -> impl Iterator<Item = &Include> {
+  includes
+    .iter()
+    .filter(move |include| !iterating || include.iterate)
+}
+
+/// With `*` for the code, the values of all the reference parameters.
+fn all_references<'i>(index: &'i Index, code: &str) -> impl Iterator<Item = &'i IndexValue> {
+  let all = code == "*";
+  index
+    .entries
+    .iter()
+    .filter(move |entry| all && matches!(entry.value, IndexValue::Reference { .. }))
+    .map(|entry| &entry.value)
+}
+
+fn refers_to(index: &Index, code: &str, (resource_type, id): &(String, String)) -> bool {
+  index
+    .values(code)
+    .any(|value| is_reference_to(value, &(resource_type.clone(), id.clone())))
+}
+
+fn is_reference_to(value: &IndexValue, (resource_type, id): &(String, String)) -> bool {
+  match value {
+    IndexValue::Reference {
+      resource_type: Some(target_type),
+      id: Some(target_id),
+      ..
+    } => target_type == resource_type && target_id == id,
+    _ => false,
+  }
+}
+
+/// What a resource is sorted on by a parameter: its lowest value, or its
+/// highest when sorting in descending order.
+#[derive(Debug, PartialEq, PartialOrd)]
+enum SortKey {
+  Number(f64),
+  Text(String),
+}
+
+fn sort_key(index: &Index, code: &str, descending: bool) -> Option<SortKey> {
+  let keys = index.values(code).filter_map(|value| {
+    Some(match value {
+      IndexValue::Number { low, high } => SortKey::Number(if descending { *high } else { *low }),
+      IndexValue::Date(range) => {
+        SortKey::Number(if descending { range.end } else { range.start } as f64)
+      }
+      IndexValue::Quantity {
+        low,
+        high,
+        canonical,
+        ..
+      } => match (canonical, descending) {
+        (Some((_, high, _)), true) => SortKey::Number(*high),
+        (Some((low, _, _)), false) => SortKey::Number(*low),
+        (None, true) => SortKey::Number(*high),
+        (None, false) => SortKey::Number(*low),
+      },
+      IndexValue::String { normalized, .. } => SortKey::Text(normalized.clone()),
+      IndexValue::Token { code, text, .. } => SortKey::Text(code.clone().or_else(|| text.clone())?),
+      IndexValue::Reference { reference, .. } => SortKey::Text(reference.clone()),
+      IndexValue::Uri(uri) => SortKey::Text(uri.clone()),
+      IndexValue::Composite(_) => return None,
+    })
+  });
+  let keys = keys.collect::<Vec<_>>().into_iter();
+  match descending {
+    true => keys.max_by(compare_keys),
+    false => keys.min_by(compare_keys),
+  }
+}
+
+fn compare_keys(a: &SortKey, b: &SortKey) -> Ordering {
+  a.partial_cmp(b).unwrap_or(Ordering::Equal)
+}
+
+/// A resource as `_summary` and `_elements` ask for it, marked as
+/// `SUBSETTED` when elements are left out.
+fn shape(query: &Query, resource: &Value) -> Value {
+  let mandatory = ["resourceType", "id", "meta"];
+  let keep = |key: &str| -> bool {
+    match query.result.summary {
+      Some(Summary::Text) => mandatory.contains(&key) || key == "text",
+      Some(Summary::Data) => key != "text",
+      _ if !query.result.elements.is_empty() => {
+        mandatory.contains(&key) || query.result.elements.iter().any(|e| e == key)
+      }
+      _ => true,
+    }
+  };
+  let object = match resource.as_object() {
+    Some(object) => object,
+    None => return resource.clone(),
+  };
+  if object.keys().all(|key| keep(key)) {
+    return resource.clone();
+  }
+  let mut shaped = Value::Object(
+    object
+      .iter()
+      .filter(|(key, _)| keep(key))
+      .map(|(key, value)| (key.clone(), value.clone()))
+      .collect(),
+  );
+  let subsetted = json!({
+    "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationValue",
+    "code": "SUBSETTED"
+  });
+  match shaped["meta"]["tag"].as_array_mut() {
+    Some(tags) => tags.push(subsetted),
+    None => shaped["meta"]["tag"] = json!([subsetted]),
+  }
+  shaped
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::repository::Repository;
+  use crate::search::SearchParameters;
+  use std::path::Path;
+
+  fn repository() -> Repository {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let mut repository = Repository::new(parameters);
+    let resources = vec![
+      json!({"resourceType": "Organization", "id": "o1", "name": "Acme"}),
+      json!({"resourceType": "Practitioner", "id": "dr", "name": [{"family": "Who"}]}),
+      json!({"resourceType": "Patient", "id": "p1", "name": [{"family": "Chalmers"}],
+        "birthDate": "1974-12-25", "managingOrganization": {"reference": "Organization/o1"},
+        "generalPractitioner": [{"reference": "Practitioner/dr"}]}),
+      json!({"resourceType": "Patient", "id": "p2", "name": [{"family": "Adams"}],
+        "birthDate": "1980-01-01", "managingOrganization": {"reference": "Organization/o1"}}),
+      json!({"resourceType": "Patient", "id": "p3", "name": [{"family": "Baker"}]}),
+      json!({"resourceType": "Observation", "id": "bp", "status": "final",
+        "code": {"coding": [{"system": "http://loinc.org", "code": "85354-9"}]},
+        "subject": {"reference": "Patient/p1"}}),
+    ];
+    for resource in &resources {
+      repository
+        .update(&ResourceList::new(resource), None)
+        .unwrap();
+    }
+    repository
+  }
+
+  fn search(repository: &Repository, search: &str) -> Value {
+    let query = Query::parse(repository.parameters(), search).unwrap();
+    let mut executor = Executor::new(repository);
+    executor.base("http://example.org/fhir");
+    executor.search(&query).unwrap().to_json()
+  }
+
+  fn ids(bundle: &Value, mode: &str) -> Vec<String> {
+    bundle["entry"]
+      .as_array()
+      .into_iter()
+      .flatten()
+      .filter(|entry| entry["search"]["mode"] == mode)
+      .map(|entry| entry["resource"]["id"].as_str().unwrap().to_string())
+      .collect()
+  }
+
+  #[test]
+  fn test_search() {
+    let repository = repository();
+    let bundle = search(&repository, "Patient?_sort=-birthdate,name&_count=2");
+    assert_eq!(bundle["type"], "searchset");
+    assert_eq!(bundle["total"], 3);
+    assert_eq!(ids(&bundle, "match"), vec!["p2", "p1"]);
+    assert_eq!(bundle["entry"][0]["search"]["score"], 1.0);
+    assert_eq!(
+      bundle["entry"][0]["fullUrl"],
+      "http://example.org/fhir/Patient/p2"
+    );
+    assert_eq!(bundle["link"][0]["relation"], "self");
+    assert_eq!(bundle["link"][1]["relation"], "next");
+    let next = bundle["link"][1]["url"].as_str().unwrap();
+    assert_eq!(
+      next,
+      "http://example.org/fhir/Patient?_sort=-birthdate,name&_count=2&_offset=2"
+    );
+    let page = search(
+      &repository,
+      next.trim_start_matches("http://example.org/fhir/"),
+    );
+    assert_eq!(ids(&page, "match"), vec!["p3"]);
+    assert_eq!(page["link"][1]["relation"], "previous");
+
+    let bundle = search(
+      &repository,
+      "Observation?subject:Patient.name=chal&_include=Observation:subject",
+    );
+    assert_eq!(ids(&bundle, "match"), vec!["bp"]);
+    assert_eq!(ids(&bundle, "include"), vec!["p1"]);
+    let bundle = search(
+      &repository,
+      "Observation?patient.organization.name=acme&_include=Observation:subject\
+       &_include:iterate=Patient:general-practitioner",
+    );
+    assert_eq!(ids(&bundle, "include"), vec!["p1", "dr"]);
+
+    let bundle = search(
+      &repository,
+      "Patient?_has:Observation:subject:code=http://loinc.org|85354-9&_revinclude=Observation:subject",
+    );
+    assert_eq!(ids(&bundle, "match"), vec!["p1"]);
+    assert_eq!(ids(&bundle, "include"), vec!["bp"]);
+    let bundle = search(
+      &repository,
+      "Organization?_revinclude=Patient:organization&_elements=active",
+    );
+    assert_eq!(ids(&bundle, "include"), vec!["p1", "p2"]);
+    assert_eq!(
+      bundle["entry"][0]["resource"]["meta"]["tag"][0]["code"],
+      "SUBSETTED"
+    );
+    assert!(bundle["entry"][0]["resource"].get("name").is_none());
+
+    let bundle = search(&repository, "Patient?_summary=count");
+    assert_eq!(bundle["total"], 3);
+    assert!(bundle.get("entry").is_none());
+    let bundle = search(
+      &repository,
+      "Patient?_lastUpdated=gt2000-01-01&_id=p1,p3&_total=none",
+    );
+    assert_eq!(ids(&bundle, "match"), vec!["p1", "p3"]);
+    assert!(bundle.get("total").is_none());
+  }
+}
@@ -0,0 +1,736 @@
+use super::{Parameter, SearchParameters};
+use crate::datetime::days_from_civil;
+use crate::fhirpath::{Context, Error, Item, ModelInfo, Precision, Temporal};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::resolve::{reference_type, split_history, ResourceSource};
+use crate::ucum;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::slice;
+
+const UCUM: &str = "http://unitsofmeasure.org";
+const CURRENCIES: &str = "urn:iso:std:iso:4217";
+
+const MINUTE: i64 = 60_000;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// Lowercase letters with diacritics and the letters they are searched as.
+const FOLDS: &[(&str, &str)] = &[
+  ("àáâãäåāăą", "a"),
+  ("æ", "ae"),
+  ("çćĉċč", "c"),
+  ("ďđ", "d"),
+  ("èéêëēĕėęě", "e"),
+  ("ĝğġģ", "g"),
+  ("ĥħ", "h"),
+  ("ìíîïĩīĭįı", "i"),
+  ("ĵ", "j"),
+  ("ķ", "k"),
+  ("ĺļľŀł", "l"),
+  ("ñńņňŉ", "n"),
+  ("òóôõöøōŏő", "o"),
+  ("œ", "oe"),
+  ("ŕŗř", "r"),
+  ("śŝşš", "s"),
+  ("ß", "ss"),
+  ("ţťŧ", "t"),
+  ("ùúûüũūŭůűų", "u"),
+  ("ŵ", "w"),
+  ("ýÿŷ", "y"),
+  ("źżž", "z"),
+];
+
+/// Works out the values a resource has for each of the search parameters that
+/// apply to it, by evaluating their expressions.
+///
+/// ```
+/// use fhir_rs::model::ResourceList::ResourceList;
+/// use fhir_rs::search::{IndexValue, Indexer, SearchParameters};
+/// use serde_json::json;
+/// use std::path::Path;
+///
+/// let mut parameters = SearchParameters::new();
+/// parameters
+///   .load_file(Path::new("examples-json/search-parameters.json"))
+///   .unwrap();
+/// let patient = json!({"resourceType": "Patient", "name": [{"family": "Müller"}]});
+/// let index = Indexer::new(&parameters).index(&ResourceList::new(&patient));
+/// let family = index.values("family").next();
+/// assert!(matches!(family, Some(IndexValue::String { normalized, .. }) if normalized == "muller"));
+/// ```
+pub struct Indexer<'s> {
+  parameters: &'s SearchParameters,
+  model: Option<&'s dyn ModelInfo>,
+}
+
+/// The values a resource has for the search parameters that apply to it.
+#[derive(Debug, Default)]
+pub struct Index {
+  pub entries: Vec<IndexEntry>,
+  /// Expressions that couldn't be evaluated on the resource, as warnings.
+  pub issues: Vec<Issue>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexEntry {
+  /// The code of the search parameter, e.g. `birthdate`.
+  pub code: String,
+  pub value: IndexValue,
+}
+
+/// A value of a resource for a search parameter, in the form that searches
+/// with the parameter's type compare against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexValue {
+  /// A code with its system, e.g. from a Coding or an Identifier. `text` is
+  /// the text of a CodeableConcept or the display of a Coding, which the
+  /// `:text` modifier searches.
+  Token {
+    system: Option<String>,
+    code: Option<String>,
+    text: Option<String>,
+    /// The system and code of each type coding of an Identifier, which
+    /// `:of-type` searches.
+    identifier_types: Vec<(String, String)>,
+  },
+  /// A string with the form it is matched on: lowercase, without accents and
+  /// with single spaces.
+  String {
+    value: String,
+    normalized: String,
+  },
+  Date(DateRange),
+  /// A literal reference or canonical URL without its version, and what it
+  /// refers to when that can be told from it.
+  Reference {
+    reference: String,
+    resource_type: Option<String>,
+    id: Option<String>,
+    version: Option<String>,
+  },
+  /// A quantity, or range of them, with its value also in canonical UCUM
+  /// units when it has a UCUM unit that can be converted.
+  Quantity {
+    low: f64,
+    high: f64,
+    system: Option<String>,
+    code: Option<String>,
+    unit: Option<String>,
+    canonical: Option<(f64, f64, String)>,
+  },
+  Uri(String),
+  Number {
+    low: f64,
+    high: f64,
+  },
+  /// The values of the components of a composite parameter, in order.
+  Composite(Vec<IndexValue>),
+}
+
+/// The instants a date covers, as inclusive milliseconds since 1970-01-01 in
+/// UTC. `2013-01` covers the whole of January, and an open end of a Period is
+/// `i64::MIN` or `i64::MAX`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateRange {
+  pub start: i64,
+  pub end: i64,
+}
+
+impl<'s> Indexer<'s> {
+  pub fn new(parameters: &'s SearchParameters) -> Indexer<'s> {
+    Indexer {
+      parameters,
+      model: None,
+    }
+  }
+
+  /// Gives expressions the types of elements, e.g. from [`Definitions`],
+  /// rather than working them out from the shape of the JSON.
+  ///
+  /// [`Definitions`]: crate::definitions::Definitions
+  pub fn model<'b>(&'b mut self, model: &'s dyn ModelInfo) -> &'b mut Indexer<'s> {
+    self.model = Some(model);
+    self
+  }
+
+  pub fn index(&self, resource: &ResourceList) -> Index {
+    let mut index = Index::default();
+    let resource = &*resource.value;
+    let resource_type = match resource["resourceType"].as_str() {
+      Some(resource_type) => resource_type,
+      None => return index,
+    };
+    let stubs = Stubs;
+    let mut context = Context::new(resource);
+    context.source(&stubs);
+    if let Some(model) = self.model {
+      context.model(model);
+    }
+    for (code, parameter) in self.parameters.applicable(resource_type) {
+      let kind = parameter.value["type"].as_str().unwrap_or_default();
+      let values = match (&parameter.expression, kind) {
+        (None, _) | (_, "special") => continue,
+        (Some(expression), "composite") => expression
+          .evaluate(&context)
+          .and_then(|items| self.composite(&context, parameter, &items)),
+        (Some(expression), kind) => expression
+          .evaluate(&context)
+          .map(|items| items.iter().flat_map(|item| extract(kind, item)).collect()),
+      };
+      match values {
+        Ok(values) => {
+          for value in values {
+            let entry = IndexEntry {
+              code: code.to_string(),
+              value,
+            };
+            if !index.entries.contains(&entry) {
+              index.entries.push(entry);
+            }
+          }
+        }
+        Err(error) => index.issues.push(Issue::warning(
+          OperationOutcome_IssueCode::Processing,
+          &format!("Can't evaluate the search parameter {}: {}", code, error),
+        )),
+      }
+    }
+    index
+  }
+
+  /// The combinations of the values of a composite's components, each
+  /// evaluated on one of the items the composite's expression selects.
+  fn composite<'a>(
+    &self,
+    context: &Context<'a>,
+    parameter: &Parameter,
+    items: &[Item<'a>],
+  ) -> Result<Vec<IndexValue>, Error> {
+    let mut components = vec![];
+    for (definition, expression) in &parameter.components {
+      let definition = self.parameters.parameter_by_url(definition);
+      let kind = definition.and_then(|d| d.value["type"].as_str());
+      match (kind, expression) {
+        (Some(kind), Some(expression)) => components.push((kind, expression)),
+        _ => return Ok(vec![]),
+      }
+    }
+    let mut values = vec![];
+    for item in items {
+      let mut combinations = vec![vec![]];
+      for (kind, expression) in &components {
+        let component = expression
+          .evaluate_on(context, slice::from_ref(item))?
+          .iter()
+          .flat_map(|item| extract(kind, item))
+          .collect::<Vec<_>>();
+        combinations = combinations
+          .into_iter()
+          .flat_map(|combination| {
+            component.iter().map(move |value| {
+              let mut combination = combination.clone();
+              combination.push(value.clone());
+              combination
+            })
+          })
+          .collect();
+      }
+      values.extend(combinations.into_iter().map(IndexValue::Composite));
+    }
+    Ok(values)
+  }
+}
+
+impl Index {
+  /// The values for a search parameter.
+  pub fn values<'i>(&'i self, code: &'i str) -> impl Iterator<Item = &'i IndexValue> + 'i {
+    self
+      .entries
+      .iter()
+      .filter(move |entry| entry.code == code)
+      .map(|entry| &entry.value)
+  }
+}
+
+/// Resolves each RESTful reference to a stand-in with only the type and id it
+/// names, so that expressions like `subject.where(resolve() is Patient)` can
+/// tell what is referred to without fetching it.
+struct Stubs;
+
+impl ResourceSource for Stubs {
+  fn fetch(&self, reference: &str) -> Option<ResourceList<'_>> {
+    let resource_type = reference_type(reference)?;
+    let id = split_history(reference).0.rsplit('/').next()?;
+    Some(ResourceList {
+      value: Cow::Owned(json!({"resourceType": resource_type, "id": id})),
+    })
+  }
+}
+
+/// The values of an item for a parameter of a type, e.g. `token`.
+fn extract(kind: &str, item: &Item) -> Vec<IndexValue> {
+  match item {
+    Item::Node(node) => match node.value() {
+      Some(value) => from_json(kind, value, node.type_name()),
+      None => vec![],
+    },
+    item => from_json(kind, &item.to_json(), None),
+  }
+}
+
+fn from_json(kind: &str, value: &Value, type_name: Option<&str>) -> Vec<IndexValue> {
+  match kind {
+    "token" => tokens(value, type_name),
+    "string" => strings(value, type_name),
+    "date" => dates(value),
+    "reference" => references(value).into_iter().collect(),
+    "quantity" => quantities(value).into_iter().collect(),
+    "uri" => value
+      .as_str()
+      .map(|uri| IndexValue::Uri(uri.to_string()))
+      .into_iter()
+      .collect(),
+    "number" => numbers(value).into_iter().collect(),
+    _ => vec![],
+  }
+}
+
+fn tokens(value: &Value, type_name: Option<&str>) -> Vec<IndexValue> {
+  let code = match value {
+    Value::String(code) => Some(code.clone()),
+    Value::Bool(value) => Some(value.to_string()),
+    Value::Number(number) => Some(number.to_string()),
+    _ => None,
+  };
+  if code.is_some() {
+    return vec![token(None, code, None)];
+  }
+  let object = match value.as_object() {
+    Some(object) => object,
+    None => return vec![],
+  };
+  let shape = type_name.unwrap_or_else(|| {
+    if object.contains_key("coding") {
+      "CodeableConcept"
+    } else if object.contains_key("code") || object.contains_key("system") {
+      match value["value"].as_str() {
+        Some(_) if !text(value, "system").is_some_and(|s| s.contains(':')) => "ContactPoint",
+        Some(_) => "Identifier",
+        None => "Coding",
+      }
+    } else if value["value"].is_string() {
+      "Identifier"
+    } else {
+      "CodeableConcept"
+    }
+  });
+  match shape {
+    "CodeableConcept" => {
+      let concept_text = text(value, "text");
+      let codings = value["coding"].as_array().into_iter().flatten();
+      let mut tokens = codings
+        .map(|coding| coding_token(coding, concept_text))
+        .collect::<Vec<_>>();
+      if tokens.is_empty() && concept_text.is_some() {
+        tokens.push(token(None, None, concept_text.map(str::to_string)));
+      }
+      tokens
+    }
+    "Coding" => vec![coding_token(value, None)],
+    "Identifier" => {
+      let codings = value["type"]["coding"].as_array().into_iter().flatten();
+      vec![IndexValue::Token {
+        system: text(value, "system").map(str::to_string),
+        code: text(value, "value").map(str::to_string),
+        text: value["type"]["text"].as_str().map(str::to_string),
+        identifier_types: codings
+          .map(|coding| {
+            let part = |key| text(coding, key).unwrap_or_default().to_string();
+            (part("system"), part("code"))
+          })
+          .collect(),
+      }]
+    }
+    "ContactPoint" => vec![token(None, text(value, "value").map(str::to_string), None)],
+    _ => vec![],
+  }
+}
+
+fn coding_token(coding: &Value, concept_text: Option<&str>) -> IndexValue {
+  token(
+    text(coding, "system").map(str::to_string),
+    text(coding, "code").map(str::to_string),
+    concept_text
+      .or_else(|| text(coding, "display"))
+      .map(str::to_string),
+  )
+}
+
+fn token(system: Option<String>, code: Option<String>, text: Option<String>) -> IndexValue {
+  IndexValue::Token {
+    system,
+    code,
+    text,
+    identifier_types: vec![],
+  }
+}
+
+fn strings(value: &Value, type_name: Option<&str>) -> Vec<IndexValue> {
+  let object = match value {
+    Value::String(value) => return vec![string(value)],
+    Value::Object(object) => object,
+    _ => return vec![],
+  };
+  let name_parts = ["text", "family", "given", "prefix", "suffix"];
+  let address_parts = [
+    "text",
+    "line",
+    "city",
+    "district",
+    "state",
+    "postalCode",
+    "country",
+  ];
+  let is_name = match type_name {
+    Some(type_name) => type_name == "HumanName",
+    None => ["family", "given"].iter().any(|k| object.contains_key(*k)),
+  };
+  let parts: &[&str] = match (is_name, type_name) {
+    (true, _) => &name_parts,
+    (false, Some("Address") | None) => &address_parts,
+    (false, Some(_)) => &[],
+  };
+  parts
+    .iter()
+    .flat_map(|part| match &value[*part] {
+      Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+      value => value.as_str().into_iter().collect::<Vec<_>>(),
+    })
+    .map(string)
+    .collect()
+}
+
+fn string(value: &str) -> IndexValue {
+  IndexValue::String {
+    value: value.to_string(),
+    normalized: normalize(value),
+  }
+}
+
+fn dates(value: &Value) -> Vec<IndexValue> {
+  if let Some(text) = value.as_str() {
+    return date_range(text).map(IndexValue::Date).into_iter().collect();
+  }
+  let period = |period: &Value| {
+    let start = text(period, "start").and_then(date_range);
+    let end = text(period, "end").and_then(date_range);
+    if start.is_none() && end.is_none() {
+      return None;
+    }
+    Some(IndexValue::Date(DateRange {
+      start: start.map_or(i64::MIN, |start| start.start),
+      end: end.map_or(i64::MAX, |end| end.end),
+    }))
+  };
+  // A Timing covers its events, or else the bounds of its repeats.
+  if let Some(events) = value["event"].as_array() {
+    return events
+      .iter()
+      .filter_map(Value::as_str)
+      .filter_map(date_range)
+      .map(IndexValue::Date)
+      .collect();
+  }
+  if value["repeat"].is_object() {
+    return period(&value["repeat"]["boundsPeriod"])
+      .into_iter()
+      .collect();
+  }
+  period(value).into_iter().collect()
+}
+
+/// The instants a date, dateTime or instant covers, e.g. all of 1974 for
+/// `1974`. Times without a timezone are taken to be in UTC.
+pub fn date_range(text: &str) -> Option<DateRange> {
+  let temporal = Temporal::parse_date_time(text)?;
+  Some(temporal_range(&temporal))
+}
+
+fn temporal_range(temporal: &Temporal) -> DateRange {
+  let days = days_from_civil(temporal.year, temporal.month, temporal.day);
+  let start = days * DAY
+    + temporal.hour as i64 * HOUR
+    + temporal.minute as i64 * MINUTE
+    + temporal.second as i64 * 1000
+    + temporal.millisecond as i64
+    - temporal.offset.unwrap_or(0) as i64 * MINUTE;
+  let length = match temporal.precision {
+    Precision::Year => (days_from_civil(temporal.year + 1, 1, 1) - days) * DAY,
+    Precision::Month if temporal.month == 12 => {
+      (days_from_civil(temporal.year + 1, 1, 1) - days) * DAY
+    }
+    Precision::Month => (days_from_civil(temporal.year, temporal.month + 1, 1) - days) * DAY,
+    Precision::Day => DAY,
+    Precision::Hour => HOUR,
+    Precision::Minute => MINUTE,
+    Precision::Second => 1000,
+    Precision::Millisecond => 1,
+  };
+  DateRange {
+    start,
+    end: start + length - 1,
+  }
+}
+
+fn references(value: &Value) -> Option<IndexValue> {
+  // A canonical or uri may name a version after a `|`.
+  if let Some(url) = value.as_str() {
+    let mut parts = url.splitn(2, '|');
+    let url = parts.next()?;
+    return Some(reference(url, parts.next()));
+  }
+  let literal = text(value, "reference")?;
+  let (unversioned, version) = split_history(literal);
+  Some(reference(unversioned, version))
+}
+
+fn reference(reference: &str, version: Option<&str>) -> IndexValue {
+  let resource_type = reference_type(reference);
+  IndexValue::Reference {
+    reference: reference.to_string(),
+    resource_type: resource_type.map(str::to_string),
+    id: resource_type
+      .and_then(|_| reference.rsplit('/').next())
+      .map(str::to_string),
+    version: version.map(str::to_string),
+  }
+}
+
+fn quantities(value: &Value) -> Option<IndexValue> {
+  // A Range covers the values between its bounds, which share a unit.
+  if value.get("low").is_some() || value.get("high").is_some() {
+    let (low, high) = (&value["low"], &value["high"]);
+    let bound = if low.is_object() { low } else { high };
+    if low.is_object() && high.is_object() && (low["code"] != high["code"]) {
+      return None;
+    }
+    return Some(quantity(
+      low["value"].as_f64().unwrap_or(f64::NEG_INFINITY),
+      high["value"].as_f64().unwrap_or(f64::INFINITY),
+      text(bound, "system"),
+      text(bound, "code"),
+      text(bound, "unit"),
+    ));
+  }
+  let number = value["value"].as_f64()?;
+  if let Some(currency) = text(value, "currency") {
+    return Some(quantity(
+      number,
+      number,
+      Some(CURRENCIES),
+      Some(currency),
+      None,
+    ));
+  }
+  let (low, high) = match text(value, "comparator") {
+    Some("<") | Some("<=") => (f64::NEG_INFINITY, number),
+    Some(">") | Some(">=") => (number, f64::INFINITY),
+    _ => (number, number),
+  };
+  Some(quantity(
+    low,
+    high,
+    text(value, "system"),
+    text(value, "code"),
+    text(value, "unit"),
+  ))
+}
+
+fn quantity(
+  low: f64,
+  high: f64,
+  system: Option<&str>,
+  code: Option<&str>,
+  unit: Option<&str>,
+) -> IndexValue {
+  let canonical = match (system, code) {
+    (Some(UCUM), Some(code)) => ucum::canonicalize(low, code).and_then(|(low, canonical)| {
+      let (high, _) = ucum::canonicalize(high, code)?;
+      Some((low, high, canonical))
+    }),
+    _ => None,
+  };
+  IndexValue::Quantity {
+    low,
+    high,
+    system: system.map(str::to_string),
+    code: code.map(str::to_string),
+    unit: unit.map(str::to_string),
+    canonical,
+  }
+}
+
+fn numbers(value: &Value) -> Option<IndexValue> {
+  if let Some(number) = value.as_f64() {
+    return Some(IndexValue::Number {
+      low: number,
+      high: number,
+    });
+  }
+  let (low, high) = (&value["low"]["value"], &value["high"]["value"]);
+  if low.is_null() && high.is_null() {
+    return None;
+  }
+  Some(IndexValue::Number {
+    low: low.as_f64().unwrap_or(f64::NEG_INFINITY),
+    high: high.as_f64().unwrap_or(f64::INFINITY),
+  })
+}
+
+fn text<'v>(value: &'v Value, key: &str) -> Option<&'v str> {
+  value.get(key).and_then(Value::as_str)
+}
+
+/// The form strings are matched in: lowercase, without accents on Latin
+/// letters and with runs of whitespace as single spaces.
+pub(crate) fn normalize(text: &str) -> String {
+  let mut normalized = String::with_capacity(text.len());
+  for word in text.split_whitespace() {
+    if !normalized.is_empty() {
+      normalized.push(' ');
+    }
+    for c in word.chars().flat_map(char::to_lowercase) {
+      // Combining accents, e.g. from decomposed text.
+      if ('\u{300}'..='\u{36f}').contains(&c) {
+        continue;
+      }
+      match FOLDS.iter().find(|(letters, _)| letters.contains(c)) {
+        Some((_, folded)) => normalized.push_str(folded),
+        None => normalized.push(c),
+      }
+    }
+  }
+  normalized
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::path::Path;
+
+  fn index(parameters: &SearchParameters, file: &str) -> Index {
+    let resource: Value = serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap();
+    Indexer::new(parameters).index(&ResourceList::new(&resource))
+  }
+
+  #[test]
+  fn test_index() {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+
+    let patient = index(&parameters, "examples-json/patient-example.json");
+    assert!(patient.issues.is_empty(), "{:?}", patient.issues);
+    let names = patient
+      .values("name")
+      .map(|value| match value {
+        IndexValue::String { normalized, .. } => normalized.as_str(),
+        other => panic!("{:?}", other),
+      })
+      .collect::<Vec<_>>();
+    assert!(names.contains(&"chalmers") && names.contains(&"jim"));
+    assert_eq!(
+      patient.values("birthdate").collect::<Vec<_>>(),
+      vec![&IndexValue::Date(date_range("1974-12-25").unwrap())]
+    );
+    assert_eq!(
+      patient.values("gender").collect::<Vec<_>>(),
+      vec![&token(None, Some("male".to_string()), None)]
+    );
+    assert!(patient.values("identifier").any(|value| *value
+      == IndexValue::Token {
+        system: Some("urn:oid:1.2.36.146.595.217.0.1".to_string()),
+        code: Some("12345".to_string()),
+        text: None,
+        identifier_types: vec![(
+          "http://terminology.hl7.org/CodeSystem/v2-0203".to_string(),
+          "MR".to_string()
+        )],
+      }));
+    assert_eq!(
+      patient.values("_id").collect::<Vec<_>>(),
+      vec![&token(None, Some("example".to_string()), None)]
+    );
+    assert_eq!(
+      patient.values("organization").collect::<Vec<_>>(),
+      vec![&IndexValue::Reference {
+        reference: "Organization/1".to_string(),
+        resource_type: Some("Organization".to_string()),
+        id: Some("1".to_string()),
+        version: None,
+      }]
+    );
+
+    let observation = index(
+      &parameters,
+      "examples-json/observation-example-bloodpressure.json",
+    );
+    assert!(observation.issues.is_empty(), "{:?}", observation.issues);
+    assert_eq!(observation.values("patient").count(), 1);
+    assert!(observation.values("code").any(|value| match value {
+      IndexValue::Token { system, code, .. } =>
+        system.as_deref() == Some("http://loinc.org") && code.as_deref() == Some("85354-9"),
+      _ => false,
+    }));
+    let systolic = observation
+      .values("component-code-value-quantity")
+      .find_map(|value| match value {
+        IndexValue::Composite(components) => match &components[..] {
+          [IndexValue::Token { code, .. }, quantity] if code.as_deref() == Some("8480-6") => {
+            Some(quantity)
+          }
+          _ => None,
+        },
+        _ => None,
+      })
+      .unwrap();
+    match systolic {
+      IndexValue::Quantity {
+        low,
+        code,
+        canonical: Some((canonical, _, unit)),
+        ..
+      } => {
+        assert_eq!((*low, code.as_deref()), (107.0, Some("mm[Hg]")));
+        assert!(*canonical > 14_000_000.0 && *canonical < 14_500_000.0);
+        assert_eq!(unit, "g.m-1.s-2");
+      }
+      other => panic!("{:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_values() {
+    let day = date_range("2013-01-14").unwrap();
+    assert_eq!(day.end - day.start, DAY - 1);
+    let month = date_range("2012-02").unwrap();
+    assert_eq!(month.start, days_from_civil(2012, 2, 1) * DAY);
+    assert_eq!(month.end, days_from_civil(2012, 3, 1) * DAY - 1);
+    let instant = date_range("2013-01-14T10:00:00+02:00").unwrap();
+    assert_eq!(instant.start, day.start + 8 * HOUR);
+    assert_eq!(instant.end, instant.start + 999);
+    assert!(date_range("not a date").is_none());
+
+    assert_eq!(
+      normalize("  Ångström\tÉCOLE  straße "),
+      "angstrom ecole strasse"
+    );
+    assert_eq!(normalize("Cafe\u{301}"), "cafe");
+  }
+}
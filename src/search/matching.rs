@@ -0,0 +1,322 @@
+use super::index::normalize;
+use super::{Criterion, DateRange, Index, IndexValue, Modifier, Prefix, SearchValue};
+use crate::resolve::reference_type;
+use crate::ucum;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const UCUM: &str = "http://unitsofmeasure.org";
+
+impl Criterion {
+  /// Whether the index values of a resource meet the criterion. A chained
+  /// criterion is matched against the index of the resource it leads to.
+  ///
+  /// Without a terminology server `:above` and `:below` on tokens match the
+  /// code itself; on URIs they match by path.
+  pub fn matches(&self, index: &Index) -> bool {
+    let mut values = index.values(&self.code).peekable();
+    if let [SearchValue::Missing(missing)] = &self.values[..] {
+      return values.peek().is_none() == *missing;
+    }
+    let values = values.collect::<Vec<_>>();
+    let matches = |search: &SearchValue| {
+      values
+        .iter()
+        .any(|value| matches(search, self.modifier.as_ref(), value))
+    };
+    match self.modifier {
+      Some(Modifier::Not) => !self.values.iter().any(matches),
+      _ => self.values.iter().any(matches),
+    }
+  }
+}
+
+fn matches(search: &SearchValue, modifier: Option<&Modifier>, value: &IndexValue) -> bool {
+  match (search, value) {
+    (
+      SearchValue::Number {
+        prefix,
+        value: number,
+        low,
+        high,
+      },
+      IndexValue::Number {
+        low: value_low,
+        high: value_high,
+      },
+    ) => compare_numbers(*prefix, (*number, *low, *high), (*value_low, *value_high)),
+    (SearchValue::Date { prefix, range }, IndexValue::Date(value)) => {
+      compare_dates(*prefix, range, value)
+    }
+    (SearchValue::String(search), IndexValue::String { value, normalized }) => match modifier {
+      Some(Modifier::Exact) => value == search,
+      Some(Modifier::Contains) => normalized.contains(search.as_str()),
+      _ => normalized.starts_with(search.as_str()),
+    },
+    (
+      SearchValue::Token { system, code },
+      IndexValue::Token {
+        system: value_system,
+        code: value_code,
+        ..
+      },
+    ) => {
+      let system_matches = match system.as_deref() {
+        None => true,
+        Some("") => value_system.is_none(),
+        Some(system) => value_system.as_deref() == Some(system),
+      };
+      system_matches && (code.is_none() || code == value_code)
+    }
+    (
+      SearchValue::OfType {
+        system,
+        code,
+        value,
+      },
+      IndexValue::Token {
+        code: value_code,
+        identifier_types,
+        ..
+      },
+    ) => {
+      value_code.as_deref() == Some(value.as_str())
+        && identifier_types
+          .iter()
+          .any(|(type_system, type_code)| type_system == system && type_code == code)
+    }
+    (SearchValue::Text(search), IndexValue::Token { text, .. }) => text
+      .as_deref()
+      .is_some_and(|text| normalize(text).contains(&normalize(search))),
+    (SearchValue::Reference(search), value @ IndexValue::Reference { .. }) => {
+      reference_matches(search, value)
+    }
+    (
+      SearchValue::Quantity {
+        prefix,
+        value: number,
+        low,
+        high,
+        system,
+        code,
+      },
+      IndexValue::Quantity {
+        low: value_low,
+        high: value_high,
+        system: value_system,
+        code: value_code,
+        unit: value_unit,
+        canonical,
+      },
+    ) => {
+      // Compare in canonical units when both sides can be converted.
+      if let (Some(UCUM) | None, Some(code), Some((canonical_low, canonical_high, unit))) =
+        (system.as_deref(), code, canonical)
+      {
+        let converted = [*number, *low, *high]
+          .iter()
+          .map(|n| ucum::canonicalize(*n, code))
+          .collect::<Option<Vec<_>>>();
+        if let Some(converted) = converted {
+          if converted.iter().all(|(_, u)| u == unit) {
+            let search = (converted[0].0, converted[1].0, converted[2].0);
+            return compare_numbers(*prefix, search, (*canonical_low, *canonical_high));
+          }
+        }
+      }
+      let system_matches = system.is_none() || system == value_system;
+      let code_matches = match code {
+        Some(code) => {
+          value_code.as_ref() == Some(code) || system.is_none() && value_unit.as_ref() == Some(code)
+        }
+        None => true,
+      };
+      system_matches
+        && code_matches
+        && compare_numbers(*prefix, (*number, *low, *high), (*value_low, *value_high))
+    }
+    (SearchValue::Uri(search), IndexValue::Uri(value)) => match modifier {
+      Some(Modifier::Above) => search.starts_with(value.as_str()),
+      Some(Modifier::Below) => value.starts_with(search.as_str()),
+      _ => value == search,
+    },
+    (SearchValue::Uri(search), value @ IndexValue::Reference { .. }) => {
+      reference_matches(search, value)
+    }
+    (SearchValue::Composite(components), IndexValue::Composite(values)) => {
+      components.len() == values.len()
+        && components
+          .iter()
+          .zip(values)
+          .all(|(search, value)| matches(search, None, value))
+    }
+    _ => false,
+  }
+}
+
+fn reference_matches(search: &str, value: &IndexValue) -> bool {
+  let (reference, resource_type, id, version) = match value {
+    IndexValue::Reference {
+      reference,
+      resource_type,
+      id,
+      version,
+    } => (reference, resource_type, id, version),
+    _ => return false,
+  };
+  // A canonical URL may be searched with its version.
+  if let Some((url, search_version)) = search.split_once('|') {
+    return reference == url && version.as_deref() == Some(search_version);
+  }
+  if reference == search {
+    return true;
+  }
+  match search.split_once('/') {
+    // An id on its own matches references to it of any type.
+    None => id.as_deref() == Some(search),
+    Some(_) if search.contains("://") => false,
+    Some((_, search_id)) => {
+      resource_type.as_deref() == reference_type(search) && id.as_deref() == Some(search_id)
+    }
+  }
+}
+
+/// Compares a number, with the range its precision gives it, and a value's
+/// range. Only equality uses the precision; `gt100` is anything above 100.
+fn compare_numbers(
+  prefix: Prefix,
+  (number, low, high): (f64, f64, f64),
+  value: (f64, f64),
+) -> bool {
+  match prefix {
+    Prefix::Eq | Prefix::Ne => compare(prefix, (low, high), value),
+    Prefix::Ap => {
+      let margin = (number * 0.1).abs().max(high - number);
+      overlaps((number - margin, number + margin), value)
+    }
+    prefix => compare(prefix, (number, number), value),
+  }
+}
+
+fn compare_dates(prefix: Prefix, range: &DateRange, value: &DateRange) -> bool {
+  let search = (range.start as f64, range.end as f64);
+  let value = (value.start as f64, value.end as f64);
+  match prefix {
+    Prefix::Ap => {
+      let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_millis() as f64);
+      let margin = ((now - search.0) * 0.1).abs();
+      overlaps((search.0 - margin, search.1 + margin), value)
+    }
+    prefix => compare(prefix, search, value),
+  }
+}
+
+/// Compares the range searched for and the range of a value, as
+/// http://hl7.org/fhir/search.html#prefix describes.
+fn compare(prefix: Prefix, (low, high): (f64, f64), (value_low, value_high): (f64, f64)) -> bool {
+  let contains = low <= value_low && value_high <= high;
+  match prefix {
+    Prefix::Eq => contains,
+    Prefix::Ne => !contains,
+    Prefix::Gt => value_high > high,
+    Prefix::Lt => value_low < low,
+    Prefix::Ge => value_high > high || contains,
+    Prefix::Le => value_low < low || contains,
+    Prefix::Sa => value_low > high,
+    Prefix::Eb => value_high < low,
+    Prefix::Ap => overlaps((low, high), (value_low, value_high)),
+  }
+}
+
+fn overlaps((low, high): (f64, f64), (value_low, value_high): (f64, f64)) -> bool {
+  low <= value_high && value_low <= high
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::{Indexer, Query, SearchParameters};
+  use crate::model::ResourceList::ResourceList;
+  use serde_json::value::Value;
+  use std::fs;
+  use std::path::Path;
+
+  #[test]
+  fn test_matches() {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let load =
+      |file: &str| -> Value { serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap() };
+    let patient = load("examples-json/patient-example.json");
+    let observation = load("examples-json/observation-example-bloodpressure.json");
+    let indexer = Indexer::new(&parameters);
+    let matches = |resource: &Value, search: &str| {
+      let index = indexer.index(&ResourceList::new(resource));
+      let query = Query::parse(&parameters, search).unwrap();
+      query
+        .criteria
+        .iter()
+        .all(|criterion| criterion.matches(&index))
+    };
+
+    assert!(matches(&patient, "Patient?family=chal&given=JIM"));
+    assert!(!matches(&patient, "Patient?family:exact=chal"));
+    assert!(matches(&patient, "Patient?family:contains=LMER"));
+    assert!(matches(
+      &patient,
+      "Patient?birthdate=1974-12&birthdate=ge1974-12-25"
+    ));
+    assert!(!matches(&patient, "Patient?birthdate=gt1974-12-25"));
+    assert!(matches(
+      &patient,
+      "Patient?birthdate=sa1974-01-01T00:00:00Z"
+    ));
+    assert!(matches(
+      &patient,
+      "Patient?gender=female,male&gender:not=female"
+    ));
+    assert!(matches(
+      &patient,
+      "Patient?identifier=urn:oid:1.2.36.146.595.217.0.1|12345"
+    ));
+    assert!(!matches(&patient, "Patient?identifier=|12345"));
+    assert!(matches(
+      &patient,
+      "Patient?identifier:of-type=http://terminology.hl7.org/CodeSystem/v2-0203|MR|12345"
+    ));
+    assert!(matches(
+      &patient,
+      "Patient?organization=Organization/1&organization=1&organization:missing=false"
+    ));
+    assert!(matches(&patient, "Patient?death-date:missing=true"));
+    assert!(matches(&patient, "Patient?_id=example"));
+
+    assert!(matches(
+      &observation,
+      "Observation?code=http://loinc.org|85354-9"
+    ));
+    assert!(matches(
+      &observation,
+      "Observation?code:text=blood pressure"
+    ));
+    assert!(matches(
+      &observation,
+      "Observation?component-value-quantity=gt100|http://unitsofmeasure.org|mm[Hg]"
+    ));
+    // 107 mm[Hg] is about 14.3 kPa.
+    assert!(matches(
+      &observation,
+      "Observation?component-value-quantity=ap14|http://unitsofmeasure.org|kPa"
+    ));
+    assert!(matches(
+      &observation,
+      "Observation?component-code-value-quantity=http://loinc.org|8480-6$lt108"
+    ));
+    assert!(!matches(
+      &observation,
+      "Observation?component-code-value-quantity=http://loinc.org|8462-4$lt60"
+    ));
+  }
+}
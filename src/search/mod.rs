@@ -0,0 +1,205 @@
+//! FHIR search (http://hl7.org/fhir/search.html): the SearchParameters that
+//! can be searched on, the [`Indexer`] that works out the values a resource
+//! has for each of them, the [`Query`] that searches are parsed into, and
+//! the [`Executor`] that runs them over a [`Collection`] of resources.
+
+mod executor;
+mod index;
+mod matching;
+mod query;
+
+pub use self::executor::{Collection, Executor};
+pub use self::index::{date_range, DateRange, Index, IndexEntry, IndexValue, Indexer};
+pub use self::query::{
+  query_pairs, query_string, Criterion, Include, Link, Modifier, Prefix, Query, ResultParameters,
+  SearchValue, Sort, Summary, Total,
+};
+
+use crate::fhirpath::Expression;
+use crate::model::SearchParameter::SearchParameter;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Resource types that aren't DomainResources, which the parameters based on
+/// `DomainResource` don't apply to.
+const NOT_DOMAIN_RESOURCES: &[&str] = &["Binary", "Bundle", "Parameters"];
+
+/// A SearchParameter with its expressions parsed, once, for indexing.
+#[derive(Debug)]
+struct Parameter {
+  value: Value,
+  expression: Option<Expression>,
+  /// The definition URL and expression of each component of a composite.
+  components: Vec<(String, Option<Expression>)>,
+}
+
+/// The SearchParameters known to a server, such as those in
+/// `search-parameters.json`, indexed by canonical URL and by the resource
+/// types and code they are searched with.
+#[derive(Debug, Default)]
+pub struct SearchParameters {
+  parameters: Vec<Parameter>,
+  urls: HashMap<String, usize>,
+  /// Parameters by their base resource type and code.
+  codes: HashMap<(String, String), usize>,
+}
+
+impl SearchParameters {
+  pub fn new() -> SearchParameters {
+    SearchParameters::default()
+  }
+
+  /// Loads a JSON file holding a SearchParameter or a Bundle of them. Files
+  /// with other content are ignored.
+  pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    if let Ok(value) = serde_json::from_str::<Value>(&contents) {
+      self.add(value);
+    }
+    Ok(())
+  }
+
+  /// Adds a SearchParameter, or every SearchParameter in a Bundle. A
+  /// parameter with the code and base of one added before replaces it.
+  pub fn add(&mut self, resource: Value) {
+    match resource["resourceType"].as_str() {
+      Some("Bundle") => {
+        if let Value::Object(mut bundle) = resource {
+          if let Some(Value::Array(entries)) = bundle.remove("entry") {
+            for mut entry in entries {
+              if let Some(resource) = entry.get_mut("resource") {
+                self.add(resource.take());
+              }
+            }
+          }
+        }
+      }
+      Some("SearchParameter") => {
+        let code = match resource["code"].as_str() {
+          Some(code) => code.to_string(),
+          None => return,
+        };
+        let expression = parse(&resource["expression"]);
+        let components = resource["component"]
+          .as_array()
+          .into_iter()
+          .flatten()
+          .map(|component| {
+            let definition = component["definition"].as_str().unwrap_or_default();
+            (definition.to_string(), parse(&component["expression"]))
+          })
+          .collect();
+        let index = self.parameters.len();
+        if let Some(url) = resource["url"].as_str() {
+          self.urls.insert(url.to_string(), index);
+        }
+        let bases = resource["base"].as_array().into_iter().flatten();
+        for base in bases.filter_map(Value::as_str) {
+          self.codes.insert((base.to_string(), code.clone()), index);
+        }
+        self.parameters.push(Parameter {
+          value: resource,
+          expression,
+          components,
+        });
+      }
+      _ => {}
+    }
+  }
+
+  /// The parameter searched on as `code` for a resource type, including the
+  /// parameters for all resources such as `_id` and `_lastUpdated`.
+  pub fn get(&self, resource_type: &str, code: &str) -> Option<SearchParameter<'_>> {
+    self
+      .parameter(resource_type, code)
+      .map(|parameter| search_parameter(&parameter.value))
+  }
+
+  /// A SearchParameter by canonical URL, as composite parameters refer to
+  /// their components.
+  pub fn by_url(&self, url: &str) -> Option<SearchParameter<'_>> {
+    self
+      .parameter_by_url(url)
+      .map(|parameter| search_parameter(&parameter.value))
+  }
+
+  /// Every parameter that applies to a resource type, ordered by code.
+  pub fn for_type(&self, resource_type: &str) -> Vec<SearchParameter<'_>> {
+    self
+      .applicable(resource_type)
+      .map(|(_, parameter)| search_parameter(&parameter.value))
+      .collect()
+  }
+
+  /// The resource types that have parameters of their own, sorted.
+  pub fn resource_types(&self) -> Vec<&str> {
+    let mut types = self
+      .codes
+      .keys()
+      .map(|(base, _)| base.as_str())
+      .filter(|base| !matches!(*base, "Resource" | "DomainResource"))
+      .collect::<Vec<_>>();
+    types.sort_unstable();
+    types.dedup();
+    types
+  }
+
+  fn parameter_by_url(&self, url: &str) -> Option<&Parameter> {
+    let index = *self.urls.get(url.split('|').next().unwrap_or(url))?;
+    Some(&self.parameters[index])
+  }
+
+  fn parameter(&self, resource_type: &str, code: &str) -> Option<&Parameter> {
+    bases(resource_type)
+      .find_map(|base| self.codes.get(&(base.to_string(), code.to_string())))
+      .map(|index| &self.parameters[*index])
+  }
+
+  /// The parameters that apply to a resource type with their codes, ordered
+  /// by code. Where a type has its own parameter with the code of a general
+  /// one, only its own applies.
+  fn applicable<'s>(
+    &'s self,
+    resource_type: &str,
+  ) -> impl Iterator<Item = (&'s str, &'s Parameter)> + 's {
+    let mut seen = HashMap::new();
+    for base in bases(resource_type) {
+      for ((parameter_base, code), index) in &self.codes {
+        if parameter_base == base {
+          seen.entry(code.as_str()).or_insert(*index);
+        }
+      }
+    }
+    let mut applicable = seen.into_iter().collect::<Vec<_>>();
+    applicable.sort_unstable();
+    applicable
+      .into_iter()
+      .map(move |(code, index)| (code, &self.parameters[index]))
+  }
+}
+
+/// The types whose parameters apply to a resource type, most specific first.
+fn bases(resource_type: &str) -> impl Iterator<Item = &str> {
+  let domain = match NOT_DOMAIN_RESOURCES.contains(&resource_type) {
+    true => None,
+    false => Some("DomainResource"),
+  };
+  Some(resource_type)
+    .into_iter()
+    .chain(domain)
+    .chain(Some("Resource"))
+}
+
+fn parse(expression: &Value) -> Option<Expression> {
+  Expression::parse(expression.as_str()?).ok()
+}
+
+fn search_parameter(value: &Value) -> SearchParameter<'_> {
+  SearchParameter {
+    value: Cow::Borrowed(value),
+  }
+}
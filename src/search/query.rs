@@ -0,0 +1,1074 @@
+use super::index::normalize;
+use super::{date_range, DateRange, SearchParameters};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::SearchParameter::SearchParameter;
+use crate::outcome::Issue;
+use std::fmt;
+
+/// Parameters that only affect how a server formats its response.
+const FORMAT_PARAMETERS: &[&str] = &["_format", "_pretty"];
+
+/// A parsed search, such as `Observation?code=http://loinc.org|1234-5&_count=50`,
+/// with each parameter checked against the SearchParameters it names.
+///
+/// ```
+/// use fhir_rs::search::{Link, Query, SearchParameters, SearchValue};
+/// use std::path::Path;
+///
+/// let mut parameters = SearchParameters::new();
+/// parameters
+///   .load_file(Path::new("examples-json/search-parameters.json"))
+///   .unwrap();
+/// let query = Query::parse(&parameters, "Observation?subject:Patient.name=smith").unwrap();
+/// let criterion = &query.criteria[0];
+/// assert_eq!(criterion.code, "name");
+/// assert_eq!(
+///   criterion.chain,
+///   vec![Link::Chain {
+///     code: "subject".to_string(),
+///     resource_type: Some("Patient".to_string())
+///   }]
+/// );
+/// assert_eq!(criterion.values, vec![SearchValue::String("smith".to_string())]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+  pub resource_type: String,
+  /// The conditions a resource has to meet, all of them.
+  pub criteria: Vec<Criterion>,
+  pub result: ResultParameters,
+}
+
+/// A parameter of a search with the values it is searched for, any one of
+/// which may match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Criterion {
+  /// The name of the parameter as written, e.g. `subject:Patient.name:exact`.
+  pub name: String,
+  /// The value as written, with its escapes.
+  pub text: String,
+  /// The references followed from the resource searched to the ones `code`
+  /// is searched on.
+  pub chain: Vec<Link>,
+  pub code: String,
+  /// The type `code` is searched on, when the chain to it settles one.
+  pub resource_type: Option<String>,
+  pub modifier: Option<Modifier>,
+  pub values: Vec<SearchValue>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Link {
+  /// The resources that a reference parameter refers to, as in
+  /// `subject:Patient.name`, optionally only those of a type.
+  Chain {
+    code: String,
+    resource_type: Option<String>,
+  },
+  /// The resources of a type whose reference parameter refers to the one
+  /// searched, as in `_has:Observation:patient:code`.
+  Has { resource_type: String, code: String },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Modifier {
+  Exact,
+  Contains,
+  Missing,
+  Not,
+  Above,
+  Below,
+  Text,
+  OfType,
+  Identifier,
+  /// A reference to a resource of the type, as in `subject:Patient=123`.
+  Type(String),
+}
+
+/// How a number, date or quantity compares with the resource's values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Prefix {
+  Eq,
+  Ne,
+  Gt,
+  Lt,
+  Ge,
+  Le,
+  Sa,
+  Eb,
+  Ap,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchValue {
+  /// A number, with the range its precision gives it: `100` is anything that
+  /// rounds to it, from 99.5 to 100.5.
+  Number {
+    prefix: Prefix,
+    value: f64,
+    low: f64,
+    high: f64,
+  },
+  Date {
+    prefix: Prefix,
+    range: DateRange,
+  },
+  /// A string in the normalized form that strings are matched in, or as
+  /// written for `:exact`.
+  String(String),
+  /// `system|code`, `code`, `|code` or `system|`. A system of `Some("")`
+  /// matches only codes without one, and `None` matches any.
+  Token {
+    system: Option<String>,
+    code: Option<String>,
+  },
+  /// The value of `:of-type`: an identifier's type and value.
+  OfType {
+    system: String,
+    code: String,
+    value: String,
+  },
+  /// The text searched for by `:text`.
+  Text(String),
+  /// A reference as `Type/id`, an id, or an absolute URL.
+  Reference(String),
+  Quantity {
+    prefix: Prefix,
+    value: f64,
+    low: f64,
+    high: f64,
+    system: Option<String>,
+    code: Option<String>,
+  },
+  Uri(String),
+  Composite(Vec<SearchValue>),
+  /// The value of `:missing`.
+  Missing(bool),
+}
+
+/// The parameters that shape the results rather than select them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultParameters {
+  pub sort: Vec<Sort>,
+  pub count: Option<usize>,
+  /// How many matches to skip, for paging.
+  pub offset: Option<usize>,
+  pub include: Vec<Include>,
+  pub revinclude: Vec<Include>,
+  pub summary: Option<Summary>,
+  pub elements: Vec<String>,
+  pub total: Option<Total>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+  pub code: String,
+  pub descending: bool,
+}
+
+/// An `_include` or `_revinclude`, e.g. `Observation:subject:Patient`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Include {
+  /// The type with the reference parameter.
+  pub resource_type: String,
+  /// The reference parameter, or `*` for all of them.
+  pub code: String,
+  pub target_type: Option<String>,
+  /// Whether included resources have their own references followed too.
+  pub iterate: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Summary {
+  True,
+  Text,
+  Data,
+  Count,
+  False,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Total {
+  None,
+  Estimate,
+  Accurate,
+}
+
+impl Query {
+  /// Parses a search of the form `Type?query`.
+  pub fn parse(parameters: &SearchParameters, search: &str) -> Result<Query, Vec<Issue>> {
+    let search = search.trim_start_matches('/');
+    let (resource_type, query) = match search.split_once('?') {
+      Some((resource_type, query)) => (resource_type, query),
+      None => (search, ""),
+    };
+    Query::parse_pairs(parameters, resource_type, &query_pairs(query))
+  }
+
+  /// Parses the decoded names and values of a search on a type, e.g. from
+  /// the form body of a `POST _search`.
+  pub fn parse_pairs(
+    parameters: &SearchParameters,
+    resource_type: &str,
+    pairs: &[(String, String)],
+  ) -> Result<Query, Vec<Issue>> {
+    if parameters.for_type(resource_type).is_empty() {
+      return Err(vec![Issue::error(
+        OperationOutcome_IssueCode::NotSupported,
+        &format!("{} can't be searched", resource_type),
+      )]);
+    }
+    let parser = Parser { parameters };
+    let mut issues = vec![];
+    let mut query = Query {
+      resource_type: resource_type.to_string(),
+      criteria: vec![],
+      result: ResultParameters::default(),
+    };
+    for (name, value) in pairs {
+      if FORMAT_PARAMETERS.contains(&name.as_str()) {
+        continue;
+      }
+      let result = match name.split(':').next().unwrap_or_default() {
+        "_has" => parser.criterion(resource_type, name, value),
+        prefix if prefix.starts_with('_') && parameters.get(resource_type, prefix).is_none() => {
+          parser.result(&mut query.result, resource_type, name, value)
+        }
+        _ => parser.criterion(resource_type, name, value),
+      };
+      match result {
+        Ok(Some(criterion)) => query.criteria.push(criterion),
+        Ok(None) => {}
+        Err(issue) => issues.push(issue),
+      }
+    }
+    match issues.is_empty() {
+      true => Ok(query),
+      false => Err(issues),
+    }
+  }
+}
+
+/// The search as a relative URL, `Type?query`, as search Bundles link to it.
+impl fmt::Display for Query {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let criteria = self
+      .criteria
+      .iter()
+      .map(|criterion| (criterion.name.clone(), criterion.text.clone()));
+    write!(f, "{}", self.resource_type)?;
+    for (index, (name, value)) in criteria.chain(self.result.pairs()).enumerate() {
+      let separator = if index == 0 { '?' } else { '&' };
+      write!(f, "{}{}={}", separator, encode(&name), encode(&value))?;
+    }
+    Ok(())
+  }
+}
+
+impl ResultParameters {
+  fn pairs(&self) -> Vec<(String, String)> {
+    let mut pairs = vec![];
+    let mut push = |name: &str, value: String| pairs.push((name.to_string(), value));
+    if !self.sort.is_empty() {
+      let sort = self
+        .sort
+        .iter()
+        .map(|sort| format!("{}{}", if sort.descending { "-" } else { "" }, sort.code))
+        .collect::<Vec<_>>();
+      push("_sort", sort.join(","));
+    }
+    for (name, includes) in [
+      ("_include", &self.include),
+      ("_revinclude", &self.revinclude),
+    ] {
+      for include in includes {
+        let mut value = format!("{}:{}", include.resource_type, include.code);
+        if let Some(target_type) = &include.target_type {
+          value = format!("{}:{}", value, target_type);
+        }
+        match include.iterate {
+          true => push(&format!("{}:iterate", name), value),
+          false => push(name, value),
+        }
+      }
+    }
+    if let Some(summary) = self.summary {
+      let summary = match summary {
+        Summary::True => "true",
+        Summary::Text => "text",
+        Summary::Data => "data",
+        Summary::Count => "count",
+        Summary::False => "false",
+      };
+      push("_summary", summary.to_string());
+    }
+    if !self.elements.is_empty() {
+      push("_elements", self.elements.join(","));
+    }
+    if let Some(total) = self.total {
+      let total = match total {
+        Total::None => "none",
+        Total::Estimate => "estimate",
+        Total::Accurate => "accurate",
+      };
+      push("_total", total.to_string());
+    }
+    if let Some(count) = self.count {
+      push("_count", count.to_string());
+    }
+    if let Some(offset) = self.offset {
+      push("_offset", offset.to_string());
+    }
+    pairs
+  }
+}
+
+struct Parser<'p> {
+  parameters: &'p SearchParameters,
+}
+
+impl<'p> Parser<'p> {
+  fn criterion(
+    &self,
+    resource_type: &str,
+    name: &str,
+    text: &str,
+  ) -> Result<Option<Criterion>, Issue> {
+    let mut chain = vec![];
+    let mut types = vec![resource_type.to_string()];
+    let mut rest = name;
+    loop {
+      if let Some(has) = rest.strip_prefix("_has:") {
+        let mut parts = has.splitn(3, ':');
+        let (source, code, tail) = match (parts.next(), parts.next(), parts.next()) {
+          (Some(source), Some(code), Some(tail)) => (source, code, tail),
+          _ => {
+            return Err(invalid(&format!(
+              "'{}' should be like _has:Observation:patient:code",
+              name
+            )))
+          }
+        };
+        let parameter = self.lookup(&[source.to_string()], code)?.1;
+        let refers = match parameter.target() {
+          Some(targets) => types.iter().any(|t| targets.contains(&t.as_str())),
+          None => true,
+        };
+        if kind(&parameter) != "reference" || !refers {
+          return Err(invalid(&format!(
+            "{}:{} doesn't refer to {}",
+            source,
+            code,
+            types.join(" or ")
+          )));
+        }
+        chain.push(Link::Has {
+          resource_type: source.to_string(),
+          code: code.to_string(),
+        });
+        types = vec![source.to_string()];
+        rest = tail;
+        continue;
+      }
+      let (head, tail) = match rest.split_once('.') {
+        Some(split) => split,
+        None => break,
+      };
+      let (code, target) = match head.split_once(':') {
+        Some((code, target)) => (code, Some(target)),
+        None => (head, None),
+      };
+      let (_, parameter) = self.lookup(&types, code)?;
+      if kind(&parameter) != "reference" {
+        return Err(invalid(&format!(
+          "{} isn't a reference, so it can't be chained",
+          code
+        )));
+      }
+      let targets = parameter.target().unwrap_or_default();
+      types = match target {
+        Some(target) if targets.contains(&target) => vec![target.to_string()],
+        Some(target) => {
+          return Err(invalid(&format!(
+            "{} doesn't refer to {} but to {}",
+            code,
+            target,
+            targets.join(", ")
+          )))
+        }
+        None => targets.iter().map(|t| t.to_string()).collect(),
+      };
+      chain.push(Link::Chain {
+        code: code.to_string(),
+        resource_type: target.map(str::to_string),
+      });
+      rest = tail;
+    }
+
+    let (code, modifier) = match rest.split_once(':') {
+      Some((code, modifier)) => (code, Some(modifier)),
+      None => (rest, None),
+    };
+    let (found, parameter) = self.lookup(&types, code)?;
+    let kind = kind(&parameter);
+    let modifier = modifier
+      .map(|modifier| parse_modifier(modifier, kind, &parameter))
+      .transpose()?;
+    let mut values = vec![];
+    for value in split(text, ',') {
+      let value = match (&modifier, kind) {
+        (Some(Modifier::Missing), _) => match value {
+          "true" => SearchValue::Missing(true),
+          "false" => SearchValue::Missing(false),
+          _ => return Err(invalid(&format!("{}:missing is true or false", code))),
+        },
+        (_, "composite") => self.composite(&parameter, value)?,
+        (modifier, kind) => parse_value(kind, modifier.as_ref(), value)?,
+      };
+      values.push(value);
+    }
+    if values.is_empty() {
+      return Ok(None);
+    }
+    Ok(Some(Criterion {
+      name: name.to_string(),
+      text: text.to_string(),
+      chain,
+      code: code.to_string(),
+      resource_type: match types.len() {
+        1 => Some(found),
+        _ => None,
+      },
+      modifier,
+      values,
+    }))
+  }
+
+  fn composite(&self, parameter: &SearchParameter, text: &str) -> Result<SearchValue, Issue> {
+    let components = parameter.value["component"]
+      .as_array()
+      .cloned()
+      .unwrap_or_default();
+    let parts = split(text, '$');
+    if parts.len() != components.len() {
+      return Err(invalid(&format!(
+        "{} has {} components separated by $",
+        parameter.code().unwrap_or_default(),
+        components.len()
+      )));
+    }
+    let mut values = vec![];
+    for (component, part) in components.iter().zip(parts) {
+      let definition = component["definition"].as_str().unwrap_or_default();
+      let kind = self
+        .parameters
+        .by_url(definition)
+        .and_then(|d| d.value["type"].as_str().map(str::to_string))
+        .ok_or_else(|| {
+          Issue::error(
+            OperationOutcome_IssueCode::NotSupported,
+            &format!("The component {} isn't known", definition),
+          )
+        })?;
+      values.push(parse_value(&kind, None, part)?);
+    }
+    Ok(SearchValue::Composite(values))
+  }
+
+  /// The first of the types with a parameter of the code, and the parameter.
+  fn lookup(&self, types: &[String], code: &str) -> Result<(String, SearchParameter<'p>), Issue> {
+    for resource_type in types {
+      if let Some(parameter) = self.parameters.get(resource_type, code) {
+        return Ok((resource_type.clone(), parameter));
+      }
+    }
+    let mut message = format!(
+      "Unknown search parameter '{}' for {}",
+      code,
+      types.join(" or ")
+    );
+    let known = types
+      .iter()
+      .flat_map(|t| self.parameters.for_type(t))
+      .filter_map(|p| p.code().map(str::to_string))
+      .collect::<Vec<_>>();
+    let closest = known
+      .iter()
+      .map(|known| (distance(code, known), known))
+      .filter(|(distance, _)| *distance <= 2)
+      .min();
+    if let Some((_, closest)) = closest {
+      message.push_str(&format!("; did you mean '{}'?", closest));
+    }
+    Err(Issue::error(
+      OperationOutcome_IssueCode::NotSupported,
+      &message,
+    ))
+  }
+
+  fn result(
+    &self,
+    result: &mut ResultParameters,
+    resource_type: &str,
+    name: &str,
+    value: &str,
+  ) -> Result<Option<Criterion>, Issue> {
+    match name {
+      "_sort" => {
+        for code in value.split(',').filter(|c| !c.is_empty()) {
+          let (code, descending) = match code.strip_prefix('-') {
+            Some(code) => (code, true),
+            None => (code, false),
+          };
+          self.lookup(&[resource_type.to_string()], code)?;
+          result.sort.push(Sort {
+            code: code.to_string(),
+            descending,
+          });
+        }
+      }
+      "_count" => result.count = Some(number(name, value)?),
+      "_offset" => result.offset = Some(number(name, value)?),
+      "_include" | "_include:iterate" | "_include:recurse" => {
+        let include = self.include(name, value)?;
+        if include.resource_type != resource_type && !name.contains(':') {
+          return Err(invalid(&format!(
+            "_include of {} needs :iterate when searching {}",
+            include.resource_type, resource_type
+          )));
+        }
+        result.include.push(include);
+      }
+      "_revinclude" | "_revinclude:iterate" | "_revinclude:recurse" => {
+        let include = self.include(name, value)?;
+        result.revinclude.push(include);
+      }
+      "_summary" => {
+        result.summary = Some(match value {
+          "true" => Summary::True,
+          "text" => Summary::Text,
+          "data" => Summary::Data,
+          "count" => Summary::Count,
+          "false" => Summary::False,
+          _ => return Err(invalid("_summary is true, text, data, count or false")),
+        })
+      }
+      "_elements" => result.elements.extend(
+        value
+          .split(',')
+          .map(str::trim)
+          .filter(|e| !e.is_empty())
+          .map(str::to_string),
+      ),
+      "_total" => {
+        result.total = Some(match value {
+          "none" => Total::None,
+          "estimate" => Total::Estimate,
+          "accurate" => Total::Accurate,
+          _ => return Err(invalid("_total is none, estimate or accurate")),
+        })
+      }
+      _ => {
+        return Err(Issue::error(
+          OperationOutcome_IssueCode::NotSupported,
+          &format!("The search parameter {} isn't supported", name),
+        ))
+      }
+    }
+    Ok(None)
+  }
+
+  fn include(&self, name: &str, value: &str) -> Result<Include, Issue> {
+    let mut parts = value.splitn(3, ':');
+    let (resource_type, code) = match (parts.next(), parts.next()) {
+      (Some(resource_type), Some(code)) if !resource_type.is_empty() => (resource_type, code),
+      _ => {
+        return Err(invalid(&format!(
+          "{} should be like Observation:subject",
+          name
+        )))
+      }
+    };
+    let target_type = parts.next().map(str::to_string);
+    if code != "*" {
+      let (_, parameter) = self.lookup(&[resource_type.to_string()], code)?;
+      if kind(&parameter) != "reference" {
+        return Err(invalid(&format!(
+          "{}:{} isn't a reference",
+          resource_type, code
+        )));
+      }
+      let targets = parameter.target().unwrap_or_default();
+      if let Some(target) = &target_type {
+        if !targets.contains(&target.as_str()) {
+          return Err(invalid(&format!(
+            "{}:{} doesn't refer to {}",
+            resource_type, code, target
+          )));
+        }
+      }
+    }
+    Ok(Include {
+      resource_type: resource_type.to_string(),
+      code: code.to_string(),
+      target_type,
+      iterate: name.contains(':'),
+    })
+  }
+}
+
+fn kind<'v>(parameter: &'v SearchParameter) -> &'v str {
+  parameter.value["type"].as_str().unwrap_or_default()
+}
+
+fn parse_modifier(
+  modifier: &str,
+  kind: &str,
+  parameter: &SearchParameter,
+) -> Result<Modifier, Issue> {
+  let parsed = match modifier {
+    "exact" => Modifier::Exact,
+    "contains" => Modifier::Contains,
+    "missing" => Modifier::Missing,
+    "not" => Modifier::Not,
+    "above" => Modifier::Above,
+    "below" => Modifier::Below,
+    "text" => Modifier::Text,
+    "of-type" => Modifier::OfType,
+    "identifier" => Modifier::Identifier,
+    target if kind == "reference" && target.starts_with(char::is_uppercase) => {
+      let targets = parameter.target().unwrap_or_default();
+      if !targets.contains(&target) {
+        return Err(invalid(&format!(
+          "{} doesn't refer to {} but to {}",
+          parameter.code().unwrap_or_default(),
+          target,
+          targets.join(", ")
+        )));
+      }
+      Modifier::Type(target.to_string())
+    }
+    _ => {
+      return Err(Issue::error(
+        OperationOutcome_IssueCode::NotSupported,
+        &format!("Unknown modifier :{}", modifier),
+      ))
+    }
+  };
+  let allowed = match parsed {
+    Modifier::Missing => true,
+    Modifier::Exact | Modifier::Contains => kind == "string",
+    Modifier::Not | Modifier::Text | Modifier::OfType => kind == "token",
+    Modifier::Above | Modifier::Below => kind == "token" || kind == "uri",
+    Modifier::Identifier | Modifier::Type(_) => kind == "reference",
+  };
+  if !allowed {
+    return Err(invalid(&format!(
+      "The modifier :{} can't be used on {}, which is a {} parameter",
+      modifier,
+      parameter.code().unwrap_or_default(),
+      kind
+    )));
+  }
+  Ok(parsed)
+}
+
+fn parse_value(kind: &str, modifier: Option<&Modifier>, text: &str) -> Result<SearchValue, Issue> {
+  match (kind, modifier) {
+    ("number", _) => {
+      let (prefix, number) = prefix(text);
+      let (value, low, high) =
+        parse_number(number).ok_or_else(|| invalid(&format!("'{}' is not a number", text)))?;
+      Ok(SearchValue::Number {
+        prefix,
+        value,
+        low,
+        high,
+      })
+    }
+    ("date", _) => {
+      let (prefix, date) = prefix(text);
+      // A `+` of a timezone that wasn't escaped arrives as a space.
+      let range = date_range(date)
+        .or_else(|| date_range(&date.replace(' ', "+")))
+        .ok_or_else(|| invalid(&format!("'{}' is not a date", text)))?;
+      Ok(SearchValue::Date { prefix, range })
+    }
+    ("string", Some(Modifier::Exact)) => Ok(SearchValue::String(unescape(text))),
+    ("string", _) => Ok(SearchValue::String(normalize(&unescape(text)))),
+    ("token", Some(Modifier::Text)) => Ok(SearchValue::Text(unescape(text))),
+    ("token", Some(Modifier::OfType)) => {
+      let parts = split(text, '|')
+        .into_iter()
+        .map(unescape)
+        .collect::<Vec<_>>();
+      match &parts[..] {
+        [system, code, value] => Ok(SearchValue::OfType {
+          system: system.clone(),
+          code: code.clone(),
+          value: value.clone(),
+        }),
+        _ => Err(invalid(&format!(
+          "'{}' should be like system|code|value",
+          text
+        ))),
+      }
+    }
+    ("token", _) | ("reference", Some(Modifier::Identifier)) => {
+      let mut parts = split(text, '|').into_iter().map(unescape);
+      let first = parts.next().unwrap_or_default();
+      let token = match parts.next() {
+        Some(code) => SearchValue::Token {
+          system: Some(first),
+          code: Some(code).filter(|c| !c.is_empty()),
+        },
+        None => SearchValue::Token {
+          system: None,
+          code: Some(first),
+        },
+      };
+      Ok(token)
+    }
+    ("reference", Some(Modifier::Type(target))) if !text.contains('/') => Ok(
+      SearchValue::Reference(format!("{}/{}", target, unescape(text))),
+    ),
+    ("reference", _) => Ok(SearchValue::Reference(unescape(text))),
+    ("quantity", _) => {
+      let mut parts = split(text, '|').into_iter();
+      let (prefix, number) = prefix(parts.next().unwrap_or_default());
+      let (value, low, high) = parse_number(number)
+        .ok_or_else(|| invalid(&format!("'{}' doesn't start with a number", text)))?;
+      let mut unit = || parts.next().map(unescape).filter(|part| !part.is_empty());
+      let (system, code) = (unit(), unit());
+      // A single unit after the number is a code in any system.
+      let (system, code) = match (system, code) {
+        (Some(code), None) if !text.contains("||") && text.matches('|').count() == 1 => {
+          (None, Some(code))
+        }
+        pair => pair,
+      };
+      Ok(SearchValue::Quantity {
+        prefix,
+        value,
+        low,
+        high,
+        system,
+        code,
+      })
+    }
+    ("uri", _) => Ok(SearchValue::Uri(unescape(text))),
+    _ => Err(Issue::error(
+      OperationOutcome_IssueCode::NotSupported,
+      &format!("Parameters of type {} can't be searched on", kind),
+    )),
+  }
+}
+
+fn prefix(text: &str) -> (Prefix, &str) {
+  let prefix = match text.get(..2) {
+    Some("eq") => Prefix::Eq,
+    Some("ne") => Prefix::Ne,
+    Some("gt") => Prefix::Gt,
+    Some("lt") => Prefix::Lt,
+    Some("ge") => Prefix::Ge,
+    Some("le") => Prefix::Le,
+    Some("sa") => Prefix::Sa,
+    Some("eb") => Prefix::Eb,
+    Some("ap") => Prefix::Ap,
+    _ => return (Prefix::Eq, text),
+  };
+  (prefix, &text[2..])
+}
+
+/// A number with the range of values that round to it at the precision it is
+/// written with: half a unit of its last digit either way.
+fn parse_number(text: &str) -> Option<(f64, f64, f64)> {
+  let value = text.parse::<f64>().ok().filter(|v| v.is_finite())?;
+  let (mantissa, exponent) = match text.find(['e', 'E']) {
+    Some(index) => (&text[..index], text[index + 1..].parse::<i32>().ok()?),
+    None => (text, 0),
+  };
+  let decimals = mantissa.split_once('.').map_or(0, |(_, d)| d.len() as i32);
+  let half = 0.5 * 10f64.powi(exponent - decimals);
+  Some((value, value - half, value + half))
+}
+
+fn number(name: &str, value: &str) -> Result<usize, Issue> {
+  value
+    .parse()
+    .map_err(|_| invalid(&format!("{} is a whole number, not '{}'", name, value)))
+}
+
+/// Splits a value at the separators that aren't escaped with `\`.
+fn split(text: &str, separator: char) -> Vec<&str> {
+  let mut parts = vec![];
+  let mut start = 0;
+  let mut escaped = false;
+  for (index, c) in text.char_indices() {
+    match c {
+      _ if escaped => escaped = false,
+      '\\' => escaped = true,
+      c if c == separator => {
+        parts.push(&text[start..index]);
+        start = index + 1;
+      }
+      _ => {}
+    }
+  }
+  parts.push(&text[start..]);
+  // Empty values between commas are left out.
+  if separator == ',' {
+    parts.retain(|part| !part.is_empty());
+  }
+  parts
+}
+
+/// Removes the `\` before escaped `\`, `,`, `$` and `|`.
+fn unescape(text: &str) -> String {
+  let mut unescaped = String::with_capacity(text.len());
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    match (c, chars.clone().next()) {
+      ('\\', Some(next @ ('\\' | ',' | '$' | '|'))) => {
+        unescaped.push(next);
+        chars.next();
+      }
+      (c, _) => unescaped.push(c),
+    }
+  }
+  unescaped
+}
+
+/// The names and values of a query string, decoded from
+/// `application/x-www-form-urlencoded`.
+pub fn query_pairs(query: &str) -> Vec<(String, String)> {
+  query
+    .split('&')
+    .filter(|pair| !pair.is_empty())
+    .map(|pair| match pair.split_once('=') {
+      Some((name, value)) => (decode(name), decode(value)),
+      None => (decode(pair), String::new()),
+    })
+    .collect()
+}
+
+/// Encodes names and values as a query string, the reverse of
+/// [`query_pairs`].
+pub fn query_string(pairs: &[(&str, &str)]) -> String {
+  pairs
+    .iter()
+    .map(|(name, value)| format!("{}={}", encode(name), encode(value)))
+    .collect::<Vec<_>>()
+    .join("&")
+}
+
+fn decode(text: &str) -> String {
+  let bytes = text.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut index = 0;
+  while index < bytes.len() {
+    let hex = text
+      .get(index + 1..index + 3)
+      .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    match (bytes[index], hex) {
+      (b'%', Some(byte)) => {
+        decoded.push(byte);
+        index += 3;
+        continue;
+      }
+      (b'+', _) => decoded.push(b' '),
+      (byte, _) => decoded.push(byte),
+    }
+    index += 1;
+  }
+  String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encodes what can't appear as is in a query value.
+fn encode(text: &str) -> String {
+  let mut encoded = String::with_capacity(text.len());
+  for byte in text.bytes() {
+    match byte {
+      b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+      b'-' | b'.' | b'_' | b'~' | b':' | b'/' | b',' | b'$' | b'*' => encoded.push(byte as char),
+      _ => encoded.push_str(&format!("%{:02X}", byte)),
+    }
+  }
+  encoded
+}
+
+fn invalid(message: &str) -> Issue {
+  Issue::error(OperationOutcome_IssueCode::Invalid, message)
+}
+
+/// The number of single character edits between two codes.
+fn distance(a: &str, b: &str) -> usize {
+  let b = b.chars().collect::<Vec<_>>();
+  let mut previous = (0..=b.len()).collect::<Vec<_>>();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + (ca != *cb) as usize;
+      current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::path::Path;
+
+  fn parameters() -> SearchParameters {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    parameters
+  }
+
+  #[test]
+  fn test_parse() {
+    let parameters = parameters();
+    let query = Query::parse(
+      &parameters,
+      "Observation?code=http://loinc.org|1234-5,|x\\,y&date=ge2020-01&subject:Patient.name=smith\
+       &_include=Observation:subject&_sort=-date,code&_count=50\
+       &value-quantity=lt5.4|http://unitsofmeasure.org|mg&code-value-quantity=http://loinc.org|8480-6$gt100\
+       &_has:DiagnosticReport:result:status=final&subject:missing=false&identifier:of-type=sys|MR|123\
+       &_include:iterate=Patient:general-practitioner&_lastUpdated=2020-01-01T10:00:00%2B02:00",
+    )
+    .unwrap();
+    assert_eq!(query.resource_type, "Observation");
+    let criteria = &query.criteria;
+    assert_eq!(criteria.len(), 9);
+    assert_eq!(
+      criteria[0].values,
+      vec![
+        SearchValue::Token {
+          system: Some("http://loinc.org".to_string()),
+          code: Some("1234-5".to_string())
+        },
+        SearchValue::Token {
+          system: Some("".to_string()),
+          code: Some("x,y".to_string())
+        },
+      ]
+    );
+    assert_eq!(
+      criteria[1].values,
+      vec![SearchValue::Date {
+        prefix: Prefix::Ge,
+        range: date_range("2020-01").unwrap()
+      }]
+    );
+    assert_eq!(criteria[2].resource_type.as_deref(), Some("Patient"));
+    match &criteria[3].values[0] {
+      SearchValue::Quantity {
+        prefix: Prefix::Lt,
+        low,
+        high,
+        system: Some(system),
+        code: Some(code),
+        ..
+      } => {
+        assert!((low - 5.35).abs() < 1e-9 && (high - 5.45).abs() < 1e-9);
+        assert_eq!(
+          (system.as_str(), code.as_str()),
+          ("http://unitsofmeasure.org", "mg")
+        );
+      }
+      other => panic!("{:?}", other),
+    }
+    match &criteria[4].values[0] {
+      SearchValue::Composite(values) => {
+        assert!(
+          matches!(&values[1], SearchValue::Quantity { prefix: Prefix::Gt, value, .. } if *value == 100.0)
+        );
+      }
+      other => panic!("{:?}", other),
+    }
+    assert_eq!(
+      criteria[5].chain,
+      vec![Link::Has {
+        resource_type: "DiagnosticReport".to_string(),
+        code: "result".to_string()
+      }]
+    );
+    assert_eq!(
+      criteria[5].resource_type.as_deref(),
+      Some("DiagnosticReport")
+    );
+    assert_eq!(criteria[6].values, vec![SearchValue::Missing(false)]);
+    assert_eq!(
+      criteria[7].values,
+      vec![SearchValue::OfType {
+        system: "sys".to_string(),
+        code: "MR".to_string(),
+        value: "123".to_string()
+      }]
+    );
+    assert_eq!(
+      criteria[8].values,
+      vec![SearchValue::Date {
+        prefix: Prefix::Eq,
+        range: date_range("2020-01-01T08:00:00Z").unwrap()
+      }]
+    );
+    assert_eq!(
+      query.result.sort,
+      vec![
+        Sort {
+          code: "date".to_string(),
+          descending: true
+        },
+        Sort {
+          code: "code".to_string(),
+          descending: false
+        }
+      ]
+    );
+    assert_eq!(query.result.count, Some(50));
+    assert_eq!(query.result.include.len(), 2);
+    assert!(query.result.include[1].iterate);
+    assert_eq!(parse_number("100"), Some((100.0, 99.5, 100.5)));
+
+    let encoded = query_string(&[("name:exact", "O'Brien & Sons"), ("date", "ge2020+01")]);
+    assert_eq!(
+      encoded,
+      "name:exact=O%27Brien%20%26%20Sons&date=ge2020%2B01"
+    );
+    assert_eq!(
+      query_pairs(&encoded)[1],
+      ("date".to_string(), "ge2020+01".to_string())
+    );
+  }
+
+  #[test]
+  fn test_errors() {
+    let parameters = parameters();
+    let errors = Query::parse(
+      &parameters,
+      "Patient?nmae=x&birthdate=soon&gender:exact=male&general-practitioner:Patient=1&_count=many&_foo=1",
+    )
+    .unwrap_err();
+    let messages = errors
+      .iter()
+      .map(|issue| issue.diagnostics.as_str())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      messages,
+      vec![
+        "Unknown search parameter 'nmae' for Patient; did you mean 'name'?",
+        "'soon' is not a date",
+        "The modifier :exact can't be used on gender, which is a token parameter",
+        "general-practitioner doesn't refer to Patient but to Practitioner, Organization, PractitionerRole",
+        "_count is a whole number, not 'many'",
+        "The search parameter _foo isn't supported",
+      ]
+    );
+    assert!(Query::parse(&parameters, "Nothing?name=x").is_err());
+  }
+}
@@ -0,0 +1,2055 @@
+//! An embeddable server for the FHIR RESTful API
+//! (http://hl7.org/fhir/http.html) over a pluggable [`Storage`], such as the
+//! in-memory [`Repository`](crate::repository::Repository). Only built with
+//! the `server` feature.
+//!
+//! [`Server::handle`] turns a [`Request`] into a [`Response`], so the server
+//! can sit behind another HTTP stack, and [`Server::bind`] serves HTTP/1.1
+//! itself with a fixed pool of threads, one per open connection. Only JSON is supported, and patches
+//! must be JSON Patch documents; in a transaction or batch, a PATCH entry
+//! holds one as the data of a `Binary`, as
+//! [`TransactionBuilder::patch`](crate::transaction::TransactionBuilder::patch)
+//! makes.
+
+mod patch;
+mod storage;
+
+pub use self::patch::apply_json_patch;
+pub use self::storage::Storage;
+pub use crate::http::{Request, Response};
+
+use crate::capabilities::{CapabilityStatementGenerator, ResourceHandler};
+use crate::datetime::now_instant;
+use crate::http::{format_http_date, parse_http_date, read_request, reason, write_response};
+use crate::ids::new_uuid;
+use crate::model::Bundle::Bundle;
+use crate::model::CapabilityStatement_Interaction1::CapabilityStatement_Interaction1Code;
+use crate::model::CapabilityStatement_Resource::{
+  CapabilityStatement_ResourceConditionalDelete, CapabilityStatement_ResourceVersioning,
+};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::{operation_outcome, Issue};
+use crate::search::{date_range, query_pairs, Query, ResultParameters};
+use crate::transaction::{
+  batch_references, method_order, rewrite_references, TransactionProcessor,
+};
+use crate::util::{decode_base64, service_base, BASE64};
+use serde_json::json;
+use serde_json::value::Value;
+use std::collections::HashMap;
+use std::io::{self, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const FHIR_JSON: &str = "application/fhir+json";
+const JSON_PATCH: &str = "application/json-patch+json";
+
+/// Serves the FHIR RESTful API over a [`Storage`]. Interactions are handled
+/// one at a time, so each one, and each transaction, sees a consistent store.
+///
+/// ```
+/// use fhir_rs::repository::Repository;
+/// use fhir_rs::search::SearchParameters;
+/// use fhir_rs::server::{Request, Server};
+///
+/// let server = Server::new(Repository::new(SearchParameters::new()));
+/// let mut request = Request::new("POST", "/Patient");
+/// request.body(br#"{"resourceType": "Patient", "active": true}"#.to_vec());
+/// let response = server.handle(&request);
+/// assert_eq!(response.status, 201);
+/// assert_eq!(response.get("ETag"), Some("W/\"1\""));
+/// ```
+pub struct Server<S> {
+  storage: Mutex<S>,
+  base: Option<String>,
+  workers: usize,
+}
+
+/// A running [`Server`], which stops when dropped.
+pub struct Running {
+  address: SocketAddr,
+  stopped: Arc<AtomicBool>,
+  listener: Option<JoinHandle<()>>,
+  workers: Vec<JoinHandle<()>>,
+  /// The connection each worker is serving, to be closed when stopping.
+  connections: Arc<Vec<Mutex<Option<TcpStream>>>>,
+}
+
+/// What an interaction gives back, before it's rendered as an HTTP response
+/// or a transaction response entry.
+struct Reply {
+  status: u16,
+  resource: Option<Value>,
+  location: Option<String>,
+  /// Whether the interaction wrote the resource, so `Prefer: return` applies.
+  written: bool,
+}
+
+struct Failure {
+  status: u16,
+  issues: Vec<Issue>,
+}
+
+type Outcome = Result<Reply, Failure>;
+
+/// What `Prefer: return` asks writes to respond with.
+#[derive(Clone, Copy, PartialEq)]
+enum Return {
+  Minimal,
+  Representation,
+  OperationOutcome,
+}
+
+impl<S: Storage + 'static> Server<S> {
+  pub fn new(storage: S) -> Server<S> {
+    Server {
+      storage: Mutex::new(storage),
+      base: None,
+      workers: 16,
+    }
+  }
+
+  /// Service base URL, e.g. `http://example.org/fhir/`, for locations and
+  /// links. Without one, it's taken from the `Host` of each request.
+  pub fn base(&mut self, base: &str) -> &mut Server<S> {
+    let base = service_base(base);
+    self.base = Some(base);
+    self
+  }
+
+  /// How many connections [`bind`](Server::bind) serves at once, 16 by
+  /// default. As many again can wait for a thread, and any more are turned
+  /// away with a 503.
+  pub fn workers(&mut self, workers: usize) -> &mut Server<S> {
+    self.workers = workers.max(1);
+    self
+  }
+
+  /// The storage, while no interaction is being handled.
+  pub fn storage(&self) -> MutexGuard<'_, S> {
+    lock(&self.storage)
+  }
+
+  /// Handles a request, whose target is a path under the base, with any
+  /// query, or an absolute URL.
+  pub fn handle(&self, request: &Request) -> Response {
+    let base = match &self.base {
+      Some(base) => base.clone(),
+      None => format!("http://{}/", request.get("Host").unwrap_or("localhost")),
+    };
+    let pairs = query_pairs(request.target.split_once('?').map_or("", |(_, q)| q));
+    let pretty = pairs.iter().any(|(k, v)| k == "_pretty" && v == "true");
+    let content_type = match negotiate(request, &pairs) {
+      Ok(content_type) => content_type,
+      Err(failure) => return render(Err(failure), Return::Representation, FHIR_JSON, pretty),
+    };
+    let outcome = match relative_target(&request.target, &base) {
+      Some(target) => route(&mut *self.storage(), &base, request, target, false),
+      None => Err(Failure::new(
+        404,
+        OperationOutcome_IssueCode::NotFound,
+        &format!("{} isn't under the base {}", request.target, base),
+      )),
+    };
+    render(outcome, preference(request), content_type, pretty)
+  }
+
+  /// Serves HTTP on an address, such as `127.0.0.1:0` for any free port.
+  pub fn bind<A: ToSocketAddrs>(self, address: A) -> io::Result<Running> {
+    let listener = TcpListener::bind(address)?;
+    let address = listener.local_addr()?;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(self.workers);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let connections = Arc::new(
+      (0..self.workers)
+        .map(|_| Mutex::new(None))
+        .collect::<Vec<_>>(),
+    );
+    let server = Arc::new(self);
+    let workers = (0..server.workers)
+      .map(|worker| {
+        let (server, receiver, connections) =
+          (server.clone(), receiver.clone(), connections.clone());
+        thread::spawn(move || loop {
+          // The queue closes once the listener stops.
+          let stream = match lock(&receiver).recv() {
+            Ok(stream) => stream,
+            Err(_) => return,
+          };
+          *lock(&connections[worker]) = stream.try_clone().ok();
+          server.serve(stream);
+          *lock(&connections[worker]) = None;
+        })
+      })
+      .collect();
+    let listener = {
+      let stopped = stopped.clone();
+      thread::spawn(move || {
+        for stream in listener.incoming() {
+          if stopped.load(Ordering::SeqCst) {
+            break;
+          }
+          if let Ok(Err(TrySendError::Full(stream))) = stream.map(|s| sender.try_send(s)) {
+            let failure = Failure::new(
+              503,
+              OperationOutcome_IssueCode::Throttled,
+              "The server is busy",
+            );
+            let response = render(Err(failure), Return::Representation, FHIR_JSON, false);
+            let _ = write_response(&mut &stream, &response, true);
+          }
+        }
+      })
+    };
+    Ok(Running {
+      address,
+      stopped,
+      listener: Some(listener),
+      workers,
+      connections,
+    })
+  }
+
+  /// Handles the requests on a connection until the client closes it.
+  fn serve(&self, stream: TcpStream) {
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(60)));
+    let mut reader = match stream.try_clone() {
+      Ok(stream) => BufReader::new(stream),
+      Err(_) => return,
+    };
+    let mut writer = stream;
+    loop {
+      let (response, close) = match read_request(&mut reader) {
+        Ok(Some(request)) => {
+          let close = request
+            .get("Connection")
+            .is_some_and(|connection| connection.eq_ignore_ascii_case("close"));
+          (self.handle(&request), close)
+        }
+        Ok(None) => return,
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+          let failure = Failure::new(
+            400,
+            OperationOutcome_IssueCode::Structure,
+            &error.to_string(),
+          );
+          (
+            render(Err(failure), Return::Representation, FHIR_JSON, false),
+            true,
+          )
+        }
+        Err(_) => return,
+      };
+      if write_response(&mut writer, &response, close).is_err() || close {
+        return;
+      }
+    }
+  }
+}
+
+impl Running {
+  pub fn address(&self) -> SocketAddr {
+    self.address
+  }
+
+  /// Stops accepting connections, closes the open ones once their current
+  /// request is answered, and waits for every thread to finish, after which
+  /// the address is free again.
+  pub fn stop(mut self) {
+    self.shutdown();
+  }
+
+  fn shutdown(&mut self) {
+    let listener = match self.listener.take() {
+      Some(listener) => listener,
+      None => return,
+    };
+    self.stopped.store(true, Ordering::SeqCst);
+    // Wake the listener so it sees it has stopped.
+    let mut address = self.address;
+    if address.ip().is_unspecified() {
+      address.set_ip([127, 0, 0, 1].into());
+    }
+    let _ = TcpStream::connect(address);
+    let _ = listener.join();
+    // Connections waiting for their next request see it end instead.
+    for connection in self.connections.iter() {
+      if let Some(stream) = &*lock(connection) {
+        let _ = stream.shutdown(Shutdown::Read);
+      }
+    }
+    for worker in self.workers.drain(..) {
+      let _ = worker.join();
+    }
+  }
+}
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    self.shutdown();
+  }
+}
+
+impl Reply {
+  fn new(status: u16, resource: Option<Value>) -> Reply {
+    Reply {
+      status,
+      resource,
+      location: None,
+      written: false,
+    }
+  }
+
+  /// The reply to a write of a resource, located at its version when it was
+  /// created.
+  fn written(status: u16, resource: Value, base: &str) -> Reply {
+    let location = match status {
+      201 => Some(format!(
+        "{}{}/{}/_history/{}",
+        base,
+        resource["resourceType"].as_str().unwrap_or_default(),
+        resource["id"].as_str().unwrap_or_default(),
+        resource["meta"]["versionId"].as_str().unwrap_or("1")
+      )),
+      _ => None,
+    };
+    Reply {
+      status,
+      resource: Some(resource),
+      location,
+      written: true,
+    }
+  }
+
+  /// What goes in the body, as `Prefer: return` asks for writes.
+  fn body(self, prefer: Return) -> Option<Value> {
+    match (self.written, prefer) {
+      (false, _) | (true, Return::Representation) => self.resource,
+      (true, Return::Minimal) => None,
+      (true, Return::OperationOutcome) => {
+        let issue = Issue::information(
+          OperationOutcome_IssueCode::Informational,
+          &format!("{} {}", self.status, reason(self.status)),
+        );
+        Some(operation_outcome(&[issue]).to_json())
+      }
+    }
+  }
+}
+
+impl Failure {
+  fn new(status: u16, code: OperationOutcome_IssueCode, message: &str) -> Failure {
+    Failure {
+      status,
+      issues: vec![Issue::error(code, message)],
+    }
+  }
+
+  /// A version conflict is a failed precondition when `If-Match` gave the
+  /// version.
+  fn precondition(issues: Vec<Issue>, if_match: bool) -> Failure {
+    let mut failure = Failure::from(issues);
+    if if_match && failure.status == 409 {
+      failure.status = 412;
+    }
+    failure
+  }
+}
+
+impl From<Vec<Issue>> for Failure {
+  fn from(issues: Vec<Issue>) -> Failure {
+    use OperationOutcome_IssueCode::*;
+    let code = issues
+      .iter()
+      .find(|issue| issue.is_error())
+      .map(|issue| &issue.code);
+    let status = match code {
+      Some(NotFound) => 404,
+      Some(Deleted) => 410,
+      Some(Conflict | Duplicate) => 409,
+      Some(MultipleMatches) => 412,
+      Some(Processing | BusinessRule) => 422,
+      Some(Login | Unknown | Expired) => 401,
+      Some(Security | Forbidden | Suppressed) => 403,
+      Some(Invalid | Structure | Required | Value | Invariant | TooLong | CodeInvalid) => 400,
+      Some(Extension | NotSupported) => 400,
+      Some(Throttled) => 429,
+      _ => 500,
+    };
+    Failure { status, issues }
+  }
+}
+
+fn route(
+  storage: &mut dyn Storage,
+  base: &str,
+  request: &Request,
+  target: &str,
+  nested: bool,
+) -> Outcome {
+  let (path, query) = target.split_once('?').unwrap_or((target, ""));
+  let mut pairs = query_pairs(query);
+  let segments = path
+    .split('/')
+    .filter(|s| !s.is_empty())
+    .collect::<Vec<_>>();
+  if let Some(resource_type) = segments.first() {
+    if resource_type.starts_with('$') || segments.iter().any(|s| s.starts_with('$')) {
+      return Err(Failure::new(
+        501,
+        OperationOutcome_IssueCode::NotSupported,
+        "Operations aren't supported",
+      ));
+    }
+    if !matches!(*resource_type, "metadata" | "_history") && !is_resource_type(resource_type) {
+      return Err(Failure::new(
+        404,
+        OperationOutcome_IssueCode::NotFound,
+        &format!("'{}' isn't a resource type", resource_type),
+      ));
+    }
+  }
+  match (request.method.as_str(), &segments[..]) {
+    ("POST", []) if nested => Err(Failure::new(
+      400,
+      OperationOutcome_IssueCode::NotSupported,
+      "Bundles can't be posted from within a transaction",
+    )),
+    ("POST", []) => transaction(storage, base, request),
+    ("GET", ["metadata"]) => Ok(Reply::new(200, Some(capabilities(storage, base)))),
+    ("GET", ["_history"]) => history(storage, base, None, None, &pairs),
+    ("GET", [resource_type]) => search(storage, base, resource_type, &pairs),
+    ("POST", [resource_type, "_search"]) => {
+      pairs.extend(query_pairs(&String::from_utf8_lossy(&request.body)));
+      search(storage, base, resource_type, &pairs)
+    }
+    ("POST", [resource_type]) => create(storage, base, request, resource_type),
+    ("PUT", [resource_type]) => conditional_update(storage, base, request, resource_type, query),
+    ("PATCH", [resource_type]) => {
+      let id = single(storage, resource_type, query)?;
+      patch(storage, base, request, resource_type, &id)
+    }
+    ("DELETE", [resource_type]) => conditional_delete(storage, resource_type, query),
+    ("GET", [resource_type, "_history"]) => {
+      history(storage, base, Some(resource_type), None, &pairs)
+    }
+    ("GET", [resource_type, id]) => read(storage, request, resource_type, id, None),
+    ("PUT", [resource_type, id]) => update(storage, base, request, resource_type, id),
+    ("PATCH", [resource_type, id]) => patch(storage, base, request, resource_type, id),
+    ("DELETE", [resource_type, id]) => {
+      let expected = if_match(request)?;
+      storage
+        .delete(resource_type, id, expected.as_deref())
+        .map_err(|issues| Failure::precondition(issues, expected.is_some()))?;
+      Ok(Reply::new(204, None))
+    }
+    ("GET", [resource_type, id, "_history"]) => {
+      history(storage, base, Some(resource_type), Some(id), &pairs)
+    }
+    ("GET", [resource_type, id, "_history", version]) => {
+      read(storage, request, resource_type, id, Some(version))
+    }
+    (_, [] | [_] | [_, _] | [_, _, "_history"] | [_, _, "_history", _]) => Err(Failure::new(
+      405,
+      OperationOutcome_IssueCode::NotSupported,
+      &format!("{} isn't supported on {}", request.method, path),
+    )),
+    _ => Err(Failure::new(
+      404,
+      OperationOutcome_IssueCode::NotFound,
+      &format!("Nothing is at {}", path),
+    )),
+  }
+}
+
+fn read(
+  storage: &dyn Storage,
+  request: &Request,
+  resource_type: &str,
+  id: &str,
+  version_id: Option<&str>,
+) -> Outcome {
+  let resource = match version_id {
+    Some(version_id) => storage.vread(resource_type, id, version_id)?,
+    None => storage.read(resource_type, id)?,
+  }
+  .to_json();
+  let status = match version_id.is_none() && not_modified(request, &resource) {
+    true => 304,
+    false => 200,
+  };
+  Ok(Reply::new(status, Some(resource)))
+}
+
+/// Whether `If-None-Match` or `If-Modified-Since` show the client has the
+/// current version.
+fn not_modified(request: &Request, resource: &Value) -> bool {
+  if let Some(tags) = request.get("If-None-Match") {
+    let etag = etag(resource);
+    return tags
+      .split(',')
+      .any(|tag| tag.trim() == "*" || Some(tag.trim()) == etag.as_deref());
+  }
+  match request.get("If-Modified-Since").and_then(parse_http_date) {
+    Some(since) => last_modified(resource).is_some_and(|modified| modified <= since),
+    None => false,
+  }
+}
+
+fn create(
+  storage: &mut dyn Storage,
+  base: &str,
+  request: &Request,
+  resource_type: &str,
+) -> Outcome {
+  let resource = body_resource(request, resource_type)?;
+  if let Some(criteria) = request.get("If-None-Exist") {
+    let criteria = criteria.trim_start_matches('?');
+    match &matches(storage, resource_type, criteria)?[..] {
+      [] => {}
+      [existing] => return Ok(Reply::new(200, Some(existing.clone()))),
+      _ => return Err(multiple_matches(criteria)),
+    }
+  }
+  let created = storage.create(&ResourceList::new(&resource))?.to_json();
+  Ok(Reply::written(201, created, base))
+}
+
+fn update(
+  storage: &mut dyn Storage,
+  base: &str,
+  request: &Request,
+  resource_type: &str,
+  id: &str,
+) -> Outcome {
+  let resource = body_resource(request, resource_type)?;
+  if resource["id"].as_str() != Some(id) || !is_id(id) {
+    return Err(Failure::new(
+      400,
+      OperationOutcome_IssueCode::Invalid,
+      &format!("The resource's id must be the one in the URL, {}", id),
+    ));
+  }
+  save(storage, base, &resource, if_match(request)?)
+}
+
+fn conditional_update(
+  storage: &mut dyn Storage,
+  base: &str,
+  request: &Request,
+  resource_type: &str,
+  criteria: &str,
+) -> Outcome {
+  let mut resource = body_resource(request, resource_type)?;
+  let expected = if_match(request)?;
+  match &matches(storage, resource_type, criteria)?[..] {
+    [] if resource.get("id").is_some() => save(storage, base, &resource, expected),
+    [] => {
+      let created = storage.create(&ResourceList::new(&resource))?.to_json();
+      Ok(Reply::written(201, created, base))
+    }
+    [existing] => {
+      let id = existing["id"].clone();
+      if resource.get("id").is_some_and(|given| *given != id) {
+        return Err(Failure::new(
+          400,
+          OperationOutcome_IssueCode::Invalid,
+          &format!("The resource's id isn't that of the match, {}", id),
+        ));
+      }
+      resource["id"] = id;
+      save(storage, base, &resource, expected)
+    }
+    _ => Err(multiple_matches(criteria)),
+  }
+}
+
+fn patch(
+  storage: &mut dyn Storage,
+  base: &str,
+  request: &Request,
+  resource_type: &str,
+  id: &str,
+) -> Outcome {
+  if !request
+    .get("Content-Type")
+    .is_some_and(|content_type| content_type.starts_with(JSON_PATCH))
+  {
+    return Err(Failure::new(
+      415,
+      OperationOutcome_IssueCode::NotSupported,
+      &format!("Patches must be {} documents", JSON_PATCH),
+    ));
+  }
+  let document = parse_body(request)?;
+  let expected = if_match(request)?;
+  let current = storage.read(resource_type, id)?.to_json();
+  let patched = apply_json_patch(&current, &document)?;
+  if patched["resourceType"] != current["resourceType"] || patched["id"] != current["id"] {
+    return Err(Failure::new(
+      422,
+      OperationOutcome_IssueCode::Processing,
+      "A patch can't change the type or id of a resource",
+    ));
+  }
+  save(storage, base, &patched, expected)
+}
+
+fn conditional_delete(storage: &mut dyn Storage, resource_type: &str, criteria: &str) -> Outcome {
+  match &matches(storage, resource_type, criteria)?[..] {
+    [] => {}
+    [existing] => {
+      let id = existing["id"].as_str().unwrap_or_default();
+      storage.delete(resource_type, id, None)?;
+    }
+    _ => return Err(multiple_matches(criteria)),
+  }
+  Ok(Reply::new(204, None))
+}
+
+fn save(
+  storage: &mut dyn Storage,
+  base: &str,
+  resource: &Value,
+  expected_version: Option<String>,
+) -> Outcome {
+  let saved = storage
+    .update(&ResourceList::new(resource), expected_version.as_deref())
+    .map_err(|issues| Failure::precondition(issues, expected_version.is_some()))?;
+  let status = match saved.created {
+    true => 201,
+    false => 200,
+  };
+  Ok(Reply::written(status, saved.resource.to_json(), base))
+}
+
+fn search(
+  storage: &dyn Storage,
+  base: &str,
+  resource_type: &str,
+  pairs: &[(String, String)],
+) -> Outcome {
+  let query = Query::parse_pairs(storage.parameters(), resource_type, pairs)?;
+  let mut bundle = storage.search(&query)?.to_json();
+  absolutize(&mut bundle, base);
+  Ok(Reply::new(200, Some(bundle)))
+}
+
+fn history(
+  storage: &dyn Storage,
+  base: &str,
+  resource_type: Option<&str>,
+  id: Option<&str>,
+  pairs: &[(String, String)],
+) -> Outcome {
+  let since = pairs
+    .iter()
+    .find(|(name, _)| name == "_since")
+    .map(|(_, since)| since.as_str());
+  let mut bundle = storage.history(resource_type, id, since)?.to_json();
+  absolutize(&mut bundle, base);
+  Ok(Reply::new(200, Some(bundle)))
+}
+
+/// The current resources that match the criteria of a conditional
+/// interaction, only looking far enough to tell if there is more than one.
+fn matches(
+  storage: &dyn Storage,
+  resource_type: &str,
+  criteria: &str,
+) -> Result<Vec<Value>, Failure> {
+  let mut query = Query::parse_pairs(storage.parameters(), resource_type, &query_pairs(criteria))?;
+  if query.criteria.is_empty() {
+    return Err(Failure::new(
+      400,
+      OperationOutcome_IssueCode::Invalid,
+      "Conditional interactions need search criteria",
+    ));
+  }
+  query.result = ResultParameters {
+    count: Some(2),
+    ..ResultParameters::default()
+  };
+  let bundle = storage.search(&query)?.to_json();
+  Ok(
+    bundle["entry"]
+      .as_array()
+      .into_iter()
+      .flatten()
+      .filter(|entry| entry["search"]["mode"] == "match")
+      .map(|entry| entry["resource"].clone())
+      .collect(),
+  )
+}
+
+/// The id of the one resource that matches a conditional patch.
+fn single(storage: &dyn Storage, resource_type: &str, criteria: &str) -> Result<String, Failure> {
+  match &matches(storage, resource_type, criteria)?[..] {
+    [] => Err(Failure::new(
+      404,
+      OperationOutcome_IssueCode::NotFound,
+      &format!("No {} matches {}", resource_type, criteria),
+    )),
+    [existing] => Ok(existing["id"].as_str().unwrap_or_default().to_string()),
+    _ => Err(multiple_matches(criteria)),
+  }
+}
+
+/// Executes the entries of a `transaction` Bundle in processing order, undone
+/// when any of them fails, or of a `batch` in the order given, reporting how
+/// each entry went.
+fn transaction(storage: &mut dyn Storage, base: &str, request: &Request) -> Outcome {
+  let bundle = body_resource(request, "Bundle")?;
+  let response_type = match bundle["type"].as_str() {
+    Some("transaction") => "transaction-response",
+    Some("batch") => "batch-response",
+    _ => {
+      return Err(Failure::new(
+        400,
+        OperationOutcome_IssueCode::Invalid,
+        "Only transaction and batch Bundles can be posted to the base",
+      ))
+    }
+  };
+  let entries = bundle["entry"].as_array().cloned().unwrap_or_default();
+  let batch = response_type == "batch-response";
+  let mut responses = vec![Value::Null; entries.len()];
+  // Batch entries that refer to others fail on their own; the rest are run.
+  let mut order = (0..entries.len()).collect::<Vec<_>>();
+  if batch {
+    for (index, issue) in batch_references(&entries) {
+      responses[index] = failed_entry(Failure {
+        status: 400,
+        issues: vec![issue],
+      });
+      order.retain(|kept| *kept != index);
+    }
+  } else {
+    // The processor sorts entries stably, so this is the order they end up in.
+    order.sort_by_key(|index| {
+      method_order(
+        entries[*index]["request"]["method"]
+          .as_str()
+          .unwrap_or_default(),
+      )
+    });
+  }
+  let mut kept = bundle.clone();
+  if batch {
+    kept["entry"] = order.iter().map(|index| entries[*index].clone()).collect();
+  }
+  let processed = TransactionProcessor::new()
+    .base(base)
+    .process(&Bundle::new(&kept))?
+    .to_json();
+  let mut processed = processed["entry"].as_array().cloned().unwrap_or_default();
+  let entry_failure = |failure: Failure, index: usize| {
+    let location = format!("Bundle.entry[{}]", index);
+    let issues = failure.issues.into_iter();
+    Failure {
+      status: failure.status,
+      issues: issues
+        .map(|issue| match issue.expression {
+          Some(_) => issue,
+          None => issue.at(&location),
+        })
+        .collect(),
+    }
+  };
+  // A transaction's conditional interactions are resolved against the store
+  // as it was before any of its writes, and every entry refers to what they
+  // resolved to.
+  let mut existing = vec![None; processed.len()];
+  if !batch {
+    let mut assigned = HashMap::new();
+    for (position, entry) in processed.iter_mut().enumerate() {
+      existing[position] = resolve_conditional(storage, base, entry, &mut assigned)
+        .map_err(|failure| entry_failure(failure, order[position]))?;
+    }
+    for entry in processed.iter_mut() {
+      rewrite_references(entry, &assigned);
+    }
+  }
+
+  let prefer = preference(request);
+  let savepoint = storage.savepoint();
+  for ((entry, existing), index) in processed.iter().zip(existing).zip(order) {
+    let outcome = match existing {
+      Some(existing) => Ok(Reply::new(200, Some(existing))),
+      None => execute(storage, base, entry),
+    };
+    match outcome {
+      Ok(reply) => responses[index] = response_entry(reply, base, prefer),
+      Err(failure) if !batch => {
+        storage.rollback(savepoint);
+        return Err(entry_failure(failure, index));
+      }
+      Err(failure) => responses[index] = failed_entry(failure),
+    }
+  }
+  Ok(Reply::new(
+    200,
+    Some(json!({
+      "resourceType": "Bundle",
+      "id": new_uuid(),
+      "type": response_type,
+      "timestamp": now_instant(),
+      "entry": responses,
+    })),
+  ))
+}
+
+/// Resolves a conditional create or update in a processed transaction entry
+/// to the resource it's about, and notes the id it has in `assigned` for the
+/// references to it. A conditional create that matches a resource gives that
+/// resource back; otherwise the entry is left to create it. A conditional
+/// update becomes an update of the resource it matches, or of a new one.
+fn resolve_conditional(
+  storage: &dyn Storage,
+  base: &str,
+  entry: &mut Value,
+  assigned: &mut HashMap<String, String>,
+) -> Result<Option<Value>, Failure> {
+  let method = entry["request"]["method"].as_str().unwrap_or_default();
+  let url = entry["request"]["url"].as_str().unwrap_or_default();
+  let url = relative_target(url, base).unwrap_or(url).to_string();
+  let resource_type = entry["resource"]["resourceType"]
+    .as_str()
+    .unwrap_or_default()
+    .to_string();
+  let local = |id: &Value| format!("{}/{}", resource_type, id.as_str().unwrap_or_default());
+  match (method, url.split_once('?')) {
+    ("POST", None) => {
+      let criteria = match entry["request"]["ifNoneExist"].as_str() {
+        Some(criteria) => criteria.trim_start_matches('?').to_string(),
+        None => return Ok(None),
+      };
+      match &matches(storage, &resource_type, &criteria)?[..] {
+        [] => {
+          // There's nothing for it to match, even once other entries are done.
+          if let Some(request) = entry["request"].as_object_mut() {
+            request.remove("ifNoneExist");
+          }
+          Ok(None)
+        }
+        [existing] => {
+          assigned.insert(local(&entry["resource"]["id"]), local(&existing["id"]));
+          Ok(Some(existing.clone()))
+        }
+        _ => Err(multiple_matches(&criteria)),
+      }
+    }
+    ("PUT", Some((path, criteria))) => {
+      let id = match &matches(storage, path, criteria)?[..] {
+        [] => match entry["resource"]["id"].as_str() {
+          Some(id) => json!(id),
+          None => json!(new_uuid()),
+        },
+        [existing] => {
+          let id = existing["id"].clone();
+          if entry["resource"]
+            .get("id")
+            .is_some_and(|given| *given != id)
+          {
+            return Err(Failure::new(
+              400,
+              OperationOutcome_IssueCode::Invalid,
+              &format!("The resource's id isn't that of the match, {}", id),
+            ));
+          }
+          id
+        }
+        _ => return Err(multiple_matches(criteria)),
+      };
+      if let Some(urn) = entry["fullUrl"].as_str().filter(|u| u.starts_with("urn:")) {
+        assigned.insert(urn.to_string(), local(&id));
+      }
+      entry["request"]["url"] = json!(format!("{}/{}", path, id.as_str().unwrap_or_default()));
+      entry["resource"]["id"] = id;
+      Ok(None)
+    }
+    _ => Ok(None),
+  }
+}
+
+/// Executes a processed transaction entry. Creates keep the id the processor
+/// gave them, which other entries may already refer to.
+fn execute(storage: &mut dyn Storage, base: &str, entry: &Value) -> Outcome {
+  let method = entry["request"]["method"].as_str().unwrap_or_default();
+  let url = entry["request"]["url"].as_str().unwrap_or_default();
+  let url = relative_target(url, base).unwrap_or(url);
+  let resource = &entry["resource"];
+  if method == "POST" && is_resource_type(url) {
+    let resource_type = resource["resourceType"].as_str().unwrap_or_default();
+    if resource_type != url {
+      return Err(Failure::new(
+        400,
+        OperationOutcome_IssueCode::Invalid,
+        &format!("A {} can't be created at {}", resource_type, url),
+      ));
+    }
+    if let Some(criteria) = entry["request"]["ifNoneExist"].as_str() {
+      let criteria = criteria.trim_start_matches('?');
+      match &matches(storage, resource_type, criteria)?[..] {
+        [] => {}
+        [existing] => return Ok(Reply::new(200, Some(existing.clone()))),
+        _ => return Err(multiple_matches(criteria)),
+      }
+    }
+    return save(storage, base, resource, None);
+  }
+
+  let mut request = Request::new(method, url);
+  for (element, header) in &[
+    ("ifMatch", "If-Match"),
+    ("ifNoneMatch", "If-None-Match"),
+    ("ifNoneExist", "If-None-Exist"),
+  ] {
+    if let Some(value) = entry["request"][element].as_str() {
+      request.header(header, value);
+    }
+  }
+  if let Some(since) = entry["request"]["ifModifiedSince"]
+    .as_str()
+    .and_then(date_range)
+  {
+    request.header(
+      "If-Modified-Since",
+      &format_http_date(since.start.div_euclid(1000)),
+    );
+  }
+  if method == "PATCH" {
+    let (content_type, document) = patch_document(resource)?;
+    request.header("Content-Type", &content_type).body(document);
+  } else if !resource.is_null() {
+    request
+      .header("Content-Type", FHIR_JSON)
+      .body(resource.to_string().into_bytes());
+  }
+  route(storage, base, &request, url, true)
+}
+
+/// The content type and body of the patch in a PATCH entry, which is a
+/// `Binary` holding a JSON Patch document.
+fn patch_document(resource: &Value) -> Result<(String, Vec<u8>), Failure> {
+  let binary = match resource["resourceType"].as_str() {
+    Some("Binary") => resource,
+    Some("Parameters") => {
+      return Err(Failure::new(
+        400,
+        OperationOutcome_IssueCode::NotSupported,
+        &format!(
+          "FHIRPath Patch isn't supported, so PATCH entries must be a Binary holding a {} document",
+          JSON_PATCH
+        ),
+      ))
+    }
+    _ => {
+      return Err(Failure::new(
+        400,
+        OperationOutcome_IssueCode::Invalid,
+        &format!(
+          "PATCH entries must be a Binary holding a {} document",
+          JSON_PATCH
+        ),
+      ))
+    }
+  };
+  let data = binary["data"].as_str().unwrap_or_default();
+  let data = data.split_whitespace().collect::<String>();
+  let document = decode_base64(&data, BASE64).ok_or_else(|| {
+    Failure::new(
+      400,
+      OperationOutcome_IssueCode::Invalid,
+      "The Binary's data isn't base64",
+    )
+  })?;
+  let content_type = binary["contentType"].as_str().unwrap_or_default();
+  Ok((content_type.to_string(), document))
+}
+
+/// The response entry of a batch entry that failed.
+fn failed_entry(failure: Failure) -> Value {
+  let status = format!("{} {}", failure.status, reason(failure.status));
+  let outcome = operation_outcome(&failure.issues).to_json();
+  json!({"response": {"status": status, "outcome": outcome}})
+}
+
+fn response_entry(reply: Reply, base: &str, prefer: Return) -> Value {
+  let mut response = json!({"status": format!("{} {}", reply.status, reason(reply.status))});
+  if let Some(location) = &reply.location {
+    response["location"] = json!(location);
+  }
+  let mut entry = json!({});
+  if let Some(resource) = &reply.resource {
+    if let Some(etag) = etag(resource) {
+      response["etag"] = json!(etag);
+      entry["fullUrl"] = json!(format!(
+        "{}{}/{}",
+        base,
+        resource["resourceType"].as_str().unwrap_or_default(),
+        resource["id"].as_str().unwrap_or_default()
+      ));
+    }
+    if let Some(last_updated) = resource["meta"]["lastUpdated"].as_str() {
+      response["lastModified"] = json!(last_updated);
+    }
+  }
+  let status = reply.status;
+  if let Some(resource) = reply.body(prefer) {
+    match resource["resourceType"] == "OperationOutcome" && status < 300 {
+      true => response["outcome"] = resource,
+      false => entry["resource"] = resource,
+    }
+  }
+  entry["response"] = response;
+  entry
+}
+
+fn render(outcome: Outcome, prefer: Return, content_type: &str, pretty: bool) -> Response {
+  let (mut response, body) = match outcome {
+    Ok(reply) => {
+      let mut response = Response::new(reply.status);
+      if let Some(location) = &reply.location {
+        response.header("Location", location);
+      }
+      if let Some(resource) = &reply.resource {
+        if let Some(etag) = etag(resource) {
+          response.header("ETag", &etag);
+        }
+        if let Some(modified) = last_modified(resource) {
+          response.header("Last-Modified", &format_http_date(modified));
+        }
+      }
+      let body = match reply.status {
+        304 => None,
+        _ => reply.body(prefer),
+      };
+      (response, body)
+    }
+    Err(failure) => (
+      Response::new(failure.status),
+      Some(operation_outcome(&failure.issues).to_json()),
+    ),
+  };
+  if let Some(body) = body {
+    response.header("Content-Type", &format!("{}; charset=utf-8", content_type));
+    response.body = match pretty {
+      true => serde_json::to_vec_pretty(&body),
+      false => serde_json::to_vec(&body),
+    }
+    .unwrap_or_default();
+  }
+  response
+}
+
+/// A CapabilityStatement for the types the storage has search parameters
+/// for.
+fn capabilities(storage: &dyn Storage, base: &str) -> Value {
+  use crate::model::CapabilityStatement_Interaction::CapabilityStatement_InteractionCode::*;
+  let parameters = storage.parameters();
+  let mut generator = CapabilityStatementGenerator::new();
+  generator
+    .implementation("fhir-rs server", base)
+    .patch_format(JSON_PATCH)
+    .interaction(CapabilityStatement_Interaction1Code::Transaction)
+    .interaction(CapabilityStatement_Interaction1Code::Batch)
+    .interaction(CapabilityStatement_Interaction1Code::HistorySystem);
+  for resource_type in parameters.resource_types() {
+    let mut handler = ResourceHandler::new(resource_type);
+    // Every resource type supports every interaction.
+    for code in [
+      Read,
+      Vread,
+      Update,
+      Patch,
+      Delete,
+      HistoryInstance,
+      HistoryType,
+      Create,
+      SearchType,
+    ] {
+      handler.interaction(code);
+    }
+    handler
+      .versioning(CapabilityStatement_ResourceVersioning::VersionedUpdate)
+      .read_history(true)
+      .update_create(true)
+      .conditional_create(true)
+      .conditional_update(true)
+      .conditional_delete(CapabilityStatement_ResourceConditionalDelete::Single)
+      .search_params(parameters);
+    generator.resource(handler);
+  }
+  generator.generate().to_json()
+}
+
+/// The content type to respond with, from `_format` or else `Accept`.
+fn negotiate(request: &Request, pairs: &[(String, String)]) -> Result<&'static str, Failure> {
+  let json = |media_type: &str| match media_type.split(';').next().unwrap_or_default().trim() {
+    "json" | FHIR_JSON | "application/json+fhir" | "*/*" | "application/*" => Some(FHIR_JSON),
+    "application/json" => Some("application/json"),
+    _ => None,
+  };
+  let format = pairs.iter().find(|(name, _)| name == "_format");
+  let content_type = match (format, request.get("Accept")) {
+    (Some((_, format)), _) => json(format),
+    (None, Some(accept)) => accept.split(',').find_map(json),
+    (None, None) => Some(FHIR_JSON),
+  };
+  content_type.ok_or_else(|| {
+    Failure::new(
+      406,
+      OperationOutcome_IssueCode::NotSupported,
+      "Only JSON is supported",
+    )
+  })
+}
+
+fn preference(request: &Request) -> Return {
+  let prefer = request.get("Prefer").unwrap_or_default();
+  let preferred = prefer
+    .split([',', ';'])
+    .find_map(|part| part.trim().strip_prefix("return="));
+  match preferred {
+    Some("minimal") => Return::Minimal,
+    Some("OperationOutcome") => Return::OperationOutcome,
+    _ => Return::Representation,
+  }
+}
+
+/// The resource in the body of a request, which must be of the type given.
+fn body_resource(request: &Request, resource_type: &str) -> Result<Value, Failure> {
+  if request
+    .get("Content-Type")
+    .is_some_and(|content_type| !content_type.contains("json"))
+  {
+    return Err(Failure::new(
+      415,
+      OperationOutcome_IssueCode::NotSupported,
+      "Only JSON is supported",
+    ));
+  }
+  let resource = parse_body(request)?;
+  match resource["resourceType"].as_str() {
+    Some(given) if given == resource_type => Ok(resource),
+    given => Err(Failure::new(
+      400,
+      OperationOutcome_IssueCode::Invalid,
+      &format!(
+        "Expected a {} but got {}",
+        resource_type,
+        given.unwrap_or("something else")
+      ),
+    )),
+  }
+}
+
+fn parse_body(request: &Request) -> Result<Value, Failure> {
+  serde_json::from_slice(&request.body).map_err(|error| {
+    Failure::new(
+      400,
+      OperationOutcome_IssueCode::Structure,
+      &format!("The body isn't valid JSON: {}", error),
+    )
+  })
+}
+
+/// The version `If-Match` expects, from an ETag like `W/"3"`.
+fn if_match(request: &Request) -> Result<Option<String>, Failure> {
+  let etag = match request.get("If-Match") {
+    Some(etag) => etag.trim(),
+    None => return Ok(None),
+  };
+  let version = etag.strip_prefix("W/").unwrap_or(etag);
+  match version.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+    Some(version) => Ok(Some(version.to_string())),
+    None => Err(Failure::new(
+      400,
+      OperationOutcome_IssueCode::Invalid,
+      &format!("If-Match must be an ETag like W/\"1\", not {}", etag),
+    )),
+  }
+}
+
+fn etag(resource: &Value) -> Option<String> {
+  let version_id = resource["meta"]["versionId"].as_str()?;
+  Some(format!("W/\"{}\"", version_id))
+}
+
+/// When a resource was last updated, in seconds since the Unix epoch.
+fn last_modified(resource: &Value) -> Option<i64> {
+  let last_updated = resource["meta"]["lastUpdated"].as_str()?;
+  date_range(last_updated).map(|range| range.start.div_euclid(1000))
+}
+
+/// Makes the relative URLs in a Bundle from the storage absolute.
+fn absolutize(bundle: &mut Value, base: &str) {
+  let absolute = |url: &mut Value| {
+    if let Some(relative) = url
+      .as_str()
+      .filter(|u| !u.contains("://") && !u.starts_with("urn:"))
+    {
+      *url = json!(format!("{}{}", base, relative));
+    }
+  };
+  for link in bundle["link"].as_array_mut().into_iter().flatten() {
+    absolute(&mut link["url"]);
+  }
+  for entry in bundle["entry"].as_array_mut().into_iter().flatten() {
+    if entry.get("fullUrl").is_some() {
+      absolute(&mut entry["fullUrl"]);
+    }
+  }
+}
+
+/// The part of a request target under the base, without its leading slash.
+fn relative_target<'t>(target: &'t str, base: &str) -> Option<&'t str> {
+  let target = path(target);
+  if !target.starts_with('/') {
+    return Some(target);
+  }
+  let base_path = path(base);
+  match target.strip_prefix(base_path) {
+    Some(relative) => Some(relative),
+    None if format!("{}/", target) == base_path => Some(""),
+    None => None,
+  }
+}
+
+/// The path and query of a URL, which are all a relative URL has.
+fn path(url: &str) -> &str {
+  match url
+    .strip_prefix("http://")
+    .or_else(|| url.strip_prefix("https://"))
+  {
+    Some(rest) => rest.find('/').map_or("/", |slash| &rest[slash..]),
+    None => url,
+  }
+}
+
+fn is_resource_type(name: &str) -> bool {
+  name.starts_with(|c: char| c.is_ascii_uppercase())
+    && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+fn is_id(id: &str) -> bool {
+  (1..=64).contains(&id.len())
+    && id
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn multiple_matches(criteria: &str) -> Failure {
+  Failure::new(
+    412,
+    OperationOutcome_IssueCode::MultipleMatches,
+    &format!("More than one resource matches {}", criteria),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::repository::Repository;
+  use crate::search::SearchParameters;
+  use crate::transaction::TransactionBuilder;
+  use std::io::{BufRead, Read, Write};
+  use std::path::Path;
+
+  struct Reply {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Value,
+  }
+
+  impl Reply {
+    fn header(&self, name: &str) -> Option<&str> {
+      let header = self
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name));
+      header.map(|(_, value)| value.as_str())
+    }
+  }
+
+  fn send(
+    address: SocketAddr,
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+  ) -> Reply {
+    let mut stream = TcpStream::connect(address).unwrap();
+    let mut head = format!(
+      "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n",
+      method, target, address
+    );
+    for (name, value) in headers {
+      head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    stream.write_all(head.as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let status = line.split_whitespace().nth(1).unwrap().parse().unwrap();
+    let mut headers = vec![];
+    loop {
+      let mut line = String::new();
+      reader.read_line(&mut line).unwrap();
+      match line.trim_end().split_once(": ") {
+        Some((name, value)) => headers.push((name.to_string(), value.to_string())),
+        None => break,
+      }
+    }
+    let mut body = String::new();
+    reader.read_to_string(&mut body).unwrap();
+    Reply {
+      status,
+      headers,
+      body: serde_json::from_str(&body).unwrap_or(Value::Null),
+    }
+  }
+
+  fn server() -> Running {
+    server_with_workers(16)
+  }
+
+  fn server_with_workers(workers: usize) -> Running {
+    let mut parameters = SearchParameters::new();
+    parameters
+      .load_file(Path::new("examples-json/search-parameters.json"))
+      .unwrap();
+    let mut repository = Repository::new(parameters);
+    let mut next = 0;
+    repository.id_generator(move || {
+      next += 1;
+      format!("p{}", next)
+    });
+    let mut server = Server::new(repository);
+    server.base("http://example.org/fhir").workers(workers);
+    server.bind("127.0.0.1:0").unwrap()
+  }
+
+  /// Sends a request on a connection that's kept open, returning the status.
+  fn keep_alive(stream: &TcpStream) -> u16 {
+    let request = "GET /fhir/metadata HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    (&mut &*stream).write_all(request.as_bytes()).unwrap();
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line).unwrap();
+    line.split_whitespace().nth(1).unwrap().parse().unwrap()
+  }
+
+  #[test]
+  fn test_interactions() {
+    let running = server();
+    let address = running.address();
+    let patient =
+      r#"{"resourceType": "Patient", "name": [{"family": "Chalmers"}], "active": true}"#;
+
+    let created = send(address, "POST", "/fhir/Patient", &[], patient);
+    assert_eq!(created.status, 201);
+    assert_eq!(
+      created.header("Location"),
+      Some("http://example.org/fhir/Patient/p1/_history/1")
+    );
+    assert_eq!(created.header("ETag"), Some("W/\"1\""));
+    assert_eq!(
+      created.header("Content-Type"),
+      Some("application/fhir+json; charset=utf-8")
+    );
+    let exists = [("If-None-Exist", "family=chalmers")];
+    let existing = send(address, "POST", "/fhir/Patient", &exists, patient);
+    assert_eq!(existing.status, 200);
+    assert_eq!(existing.body["id"], "p1");
+
+    let read = send(address, "GET", "/fhir/Patient/p1?_format=json", &[], "");
+    assert_eq!(read.body["name"][0]["family"], "Chalmers");
+    let modified = read.header("Last-Modified").unwrap().to_string();
+    let unchanged = send(
+      address,
+      "GET",
+      "/fhir/Patient/p1",
+      &[("If-Modified-Since", &modified)],
+      "",
+    );
+    assert_eq!(unchanged.status, 304);
+    let unchanged = send(
+      address,
+      "GET",
+      "/fhir/Patient/p1",
+      &[("If-None-Match", "W/\"1\"")],
+      "",
+    );
+    assert_eq!(unchanged.status, 304);
+    let xml = send(
+      address,
+      "GET",
+      "/fhir/Patient/p1",
+      &[("Accept", "application/fhir+xml")],
+      "",
+    );
+    assert_eq!(xml.status, 406);
+    assert_eq!(xml.body["resourceType"], "OperationOutcome");
+
+    let updated = r#"{"resourceType": "Patient", "id": "p1", "active": false}"#;
+    let stale = send(
+      address,
+      "PUT",
+      "/fhir/Patient/p1",
+      &[("If-Match", "W/\"2\"")],
+      updated,
+    );
+    assert_eq!(stale.status, 412);
+    let minimal = [("If-Match", "W/\"1\""), ("Prefer", "return=minimal")];
+    let saved = send(address, "PUT", "/fhir/Patient/p1", &minimal, updated);
+    assert_eq!(saved.status, 200);
+    assert_eq!(saved.header("ETag"), Some("W/\"2\""));
+    assert!(saved.body.is_null());
+
+    let patch = r#"[{"op": "add", "path": "/gender", "value": "female"}]"#;
+    let json_patch = [
+      ("Content-Type", JSON_PATCH),
+      ("Prefer", "return=OperationOutcome"),
+    ];
+    let patched = send(address, "PATCH", "/fhir/Patient/p1", &json_patch, patch);
+    assert_eq!(patched.status, 200);
+    assert_eq!(patched.body["resourceType"], "OperationOutcome");
+    let vread = send(address, "GET", "/fhir/Patient/p1/_history/3", &[], "");
+    assert_eq!(vread.body["gender"], "female");
+    let unsupported = send(address, "PATCH", "/fhir/Patient/p1", &[], patch);
+    assert_eq!(unsupported.status, 415);
+
+    let search = send(
+      address,
+      "GET",
+      "/fhir/Patient?gender=female&_count=1",
+      &[],
+      "",
+    );
+    assert_eq!(search.body["total"], 1);
+    assert_eq!(
+      search.body["entry"][0]["fullUrl"],
+      "http://example.org/fhir/Patient/p1"
+    );
+    assert!(search.body["link"][0]["url"]
+      .as_str()
+      .unwrap()
+      .starts_with("http://example.org/fhir/Patient?gender=female"));
+    let form = [("Content-Type", "application/x-www-form-urlencoded")];
+    let posted = send(
+      address,
+      "POST",
+      "/fhir/Patient/_search",
+      &form,
+      "gender=male",
+    );
+    assert_eq!(posted.body["total"], 0);
+    let unknown = send(address, "GET", "/fhir/Patient?colour=red", &[], "");
+    assert_eq!(unknown.status, 400);
+
+    let deleted = send(address, "DELETE", "/fhir/Patient?gender=female", &[], "");
+    assert_eq!(deleted.status, 204);
+    assert_eq!(
+      send(address, "GET", "/fhir/Patient/p1", &[], "").status,
+      410
+    );
+    assert_eq!(
+      send(address, "GET", "/fhir/Patient/p9", &[], "").status,
+      404
+    );
+    let history = send(address, "GET", "/fhir/Patient/p1/_history", &[], "");
+    assert_eq!(history.body["total"], 4);
+    let metadata = send(address, "GET", "/fhir/metadata", &[], "");
+    assert_eq!(metadata.body["rest"][0]["mode"], "server");
+    assert_eq!(send(address, "GET", "/fhir/patients", &[], "").status, 404);
+    assert_eq!(
+      send(address, "POST", "/fhir/Patient/p1/_history", &[], "").status,
+      405
+    );
+  }
+
+  #[test]
+  fn test_transactions() {
+    let running = server();
+    let address = running.address();
+    let transaction = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [{
+        "fullUrl": "urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059",
+        "resource": {"resourceType": "Patient", "name": [{"family": "Chalmers"}]},
+        "request": {"method": "POST", "url": "Patient", "ifNoneExist": "family=chalmers"}
+      }, {
+        "resource": {"resourceType": "Observation", "id": "o1", "status": "final",
+          "subject": {"reference": "urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059"}},
+        "request": {"method": "PUT", "url": "Observation/o1"}
+      }, {
+        "request": {"method": "GET", "url": "Observation?subject:Patient.family=chalmers"}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &transaction.to_string());
+    assert_eq!(response.status, 200);
+    let body = &response.body;
+    assert_eq!(body["type"], "transaction-response");
+    assert_eq!(body["entry"][0]["response"]["status"], "201 Created");
+    assert_eq!(body["entry"][1]["response"]["etag"], "W/\"1\"");
+    let subject = &body["entry"][1]["resource"]["subject"]["reference"];
+    let patient = body["entry"][0]["resource"]["id"].as_str().unwrap();
+    assert_eq!(*subject, format!("Patient/{}", patient));
+    assert_eq!(body["entry"][2]["resource"]["total"], 1);
+
+    // The patient exists now, so the observation refers to it again.
+    let response = send(address, "POST", "/fhir", &[], &transaction.to_string());
+    assert_eq!(response.body["entry"][0]["response"]["status"], "200 OK");
+    assert_eq!(
+      response.body["entry"][1]["resource"]["subject"]["reference"],
+      format!("Patient/{}", patient)
+    );
+
+    // The POST and the update of the patient succeed before the stale
+    // If-Match fails, and are both undone.
+    let failing = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [{
+        "resource": {"resourceType": "Patient", "name": [{"family": "Rollback"}]},
+        "request": {"method": "POST", "url": "Patient"}
+      }, {
+        "resource": {"resourceType": "Patient", "id": patient, "active": true,
+          "name": [{"family": "Chalmers"}]},
+        "request": {"method": "PUT", "url": format!("Patient/{}", patient)}
+      }, {
+        "resource": {"resourceType": "Observation", "id": "o1", "status": "amended"},
+        "request": {"method": "PUT", "url": "Observation/o1", "ifMatch": "W/\"1\""}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &failing.to_string());
+    assert_eq!(response.status, 412);
+    assert_eq!(
+      response.body["issue"][0]["expression"][0],
+      "Bundle.entry[2]"
+    );
+    let rolled_back = send(address, "GET", "/fhir/Patient?family=rollback", &[], "");
+    assert_eq!(rolled_back.body["total"], 0);
+    let current = send(
+      address,
+      "GET",
+      &format!("/fhir/Patient/{}", patient),
+      &[],
+      "",
+    );
+    assert_eq!(current.body["meta"]["versionId"], "1");
+    assert_eq!(current.body["active"], Value::Null);
+    let history = send(
+      address,
+      "GET",
+      &format!("/fhir/Patient/{}/_history", patient),
+      &[],
+      "",
+    );
+    assert_eq!(history.body["total"], 1);
+    let observation = send(address, "GET", "/fhir/Observation/o1", &[], "");
+    assert_eq!(observation.body["status"], "final");
+
+    let mut batch = failing;
+    batch["type"] = json!("batch");
+    let response = send(address, "POST", "/fhir", &[], &batch.to_string());
+    let statuses = (0..3)
+      .map(|index| response.body["entry"][index]["response"]["status"].clone())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      statuses,
+      vec![
+        json!("201 Created"),
+        json!("200 OK"),
+        json!("412 Precondition Failed")
+      ]
+    );
+    let kept = send(address, "GET", "/fhir/Patient?family=rollback", &[], "");
+    assert_eq!(kept.body["total"], 1);
+
+    // Batch entries run in the order given, and can't refer to each other.
+    let urn = "urn:uuid:0c3b2a58-7d23-4ef2-a6b4-1c54d7e1d3a1";
+    let batch = json!({
+      "resourceType": "Bundle",
+      "type": "batch",
+      "entry": [{
+        "request": {"method": "GET", "url": "Observation/o2"}
+      }, {
+        "resource": {"resourceType": "Observation", "id": "o2", "status": "final"},
+        "request": {"method": "PUT", "url": "Observation/o2"}
+      }, {
+        "resource": {"resourceType": "Observation", "id": "o3", "status": "final",
+          "subject": {"reference": urn}},
+        "request": {"method": "PUT", "url": "Observation/o3"}
+      }, {
+        "fullUrl": urn,
+        "resource": {"resourceType": "Patient"},
+        "request": {"method": "POST", "url": "Patient"}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &batch.to_string());
+    let statuses = (0..4)
+      .map(|index| response.body["entry"][index]["response"]["status"].clone())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      statuses,
+      vec![
+        json!("404 Not Found"),
+        json!("201 Created"),
+        json!("400 Bad Request"),
+        json!("201 Created")
+      ]
+    );
+    assert_eq!(
+      response.body["entry"][2]["response"]["outcome"]["issue"][0]["expression"][0],
+      "Bundle.entry[2]"
+    );
+    assert_eq!(
+      send(address, "GET", "/fhir/Observation/o3", &[], "").status,
+      404
+    );
+    running.stop();
+  }
+
+  #[test]
+  fn test_conditional_interactions() {
+    let running = server();
+    let address = running.address();
+    let windsor = r#"{"resourceType": "Patient", "name": [{"family": "Windsor"}]}"#;
+
+    // A conditional update creates the resource when nothing matches, and
+    // updates the one that does otherwise.
+    let created = send(address, "PUT", "/fhir/Patient?family=windsor", &[], windsor);
+    assert_eq!(created.status, 201);
+    assert_eq!(created.body["id"], "p1");
+    let active = r#"{"resourceType": "Patient", "name": [{"family": "Windsor"}], "active": true}"#;
+    let updated = send(address, "PUT", "/fhir/Patient?family=windsor", &[], active);
+    assert_eq!(updated.status, 200);
+    assert_eq!(updated.body["id"], "p1");
+    assert_eq!(updated.header("ETag"), Some("W/\"2\""));
+    let other = r#"{"resourceType": "Patient", "id": "p7", "name": [{"family": "Windsor"}]}"#;
+    let mismatched = send(address, "PUT", "/fhir/Patient?family=windsor", &[], other);
+    assert_eq!(mismatched.status, 400);
+    let stale = send(
+      address,
+      "PUT",
+      "/fhir/Patient?family=windsor",
+      &[("If-Match", "W/\"1\"")],
+      active,
+    );
+    assert_eq!(stale.status, 412);
+
+    // A conditional patch changes the one match.
+    let patch = r#"[{"op": "add", "path": "/gender", "value": "female"}]"#;
+    let json_patch = [("Content-Type", JSON_PATCH)];
+    let patched = send(
+      address,
+      "PATCH",
+      "/fhir/Patient?family=windsor",
+      &json_patch,
+      patch,
+    );
+    assert_eq!(patched.status, 200);
+    assert_eq!(patched.body["gender"], "female");
+    let missing = send(
+      address,
+      "PATCH",
+      "/fhir/Patient?family=tudor",
+      &json_patch,
+      patch,
+    );
+    assert_eq!(missing.status, 404);
+
+    // Every conditional interaction fails when more than one resource
+    // matches, and needs criteria to match on.
+    assert_eq!(
+      send(address, "POST", "/fhir/Patient", &[], windsor).status,
+      201
+    );
+    let ambiguous = |method: &str, target: &str, headers: &[(&str, &str)], body: &str| {
+      let response = send(address, method, target, headers, body);
+      assert_eq!(response.status, 412, "{} {}", method, target);
+      assert_eq!(response.body["issue"][0]["code"], "multiple-matches");
+    };
+    ambiguous("PUT", "/fhir/Patient?family=windsor", &[], windsor);
+    let exists = [("If-None-Exist", "family=windsor")];
+    ambiguous("POST", "/fhir/Patient", &exists, windsor);
+    ambiguous("PATCH", "/fhir/Patient?family=windsor", &json_patch, patch);
+    ambiguous("DELETE", "/fhir/Patient?family=windsor", &[], "");
+    assert_eq!(
+      send(address, "DELETE", "/fhir/Patient", &[], "").status,
+      400
+    );
+    assert_eq!(
+      send(address, "DELETE", "/fhir/Patient?_count=1", &[], "").status,
+      400
+    );
+
+    // A conditional delete of nothing succeeds, and of one match deletes it.
+    let nothing = send(address, "DELETE", "/fhir/Patient?family=tudor", &[], "");
+    assert_eq!(nothing.status, 204);
+    let deleted = send(address, "DELETE", "/fhir/Patient?gender=female", &[], "");
+    assert_eq!(deleted.status, 204);
+    assert_eq!(
+      send(address, "GET", "/fhir/Patient/p1", &[], "").status,
+      410
+    );
+    assert_eq!(
+      send(address, "GET", "/fhir/Patient/p2", &[], "").status,
+      200
+    );
+    running.stop();
+  }
+
+  #[test]
+  fn test_transaction_rules() {
+    let running = server();
+    let address = running.address();
+    for family in ["Windsor", "Tudor"] {
+      let patient = json!({"resourceType": "Patient", "name": [{"family": family}]});
+      let created = send(address, "POST", "/fhir/Patient", &[], &patient.to_string());
+      assert_eq!(created.status, 201);
+    }
+
+    // Entries are processed as DELETE, POST, PUT, then GET, whatever order
+    // they're in, and answered in the order they're in. Prefer applies to
+    // each write.
+    let transaction = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [{
+        "request": {"method": "GET", "url": "Patient?family=windsor,tudor,stuart"}
+      }, {
+        "resource": {"resourceType": "Patient", "id": "p2", "name": [{"family": "Tudor"}],
+          "active": true},
+        "request": {"method": "PUT", "url": "Patient/p2"}
+      }, {
+        "fullUrl": "urn:uuid:2b4c6d8e-0f1a-4b3c-8d5e-7f9a1b3c5d7e",
+        "resource": {"resourceType": "Patient", "name": [{"family": "Stuart"}]},
+        "request": {"method": "POST", "url": "Patient"}
+      }, {
+        "request": {"method": "DELETE", "url": "Patient?family=windsor"}
+      }]
+    });
+    let minimal = [("Prefer", "return=minimal")];
+    let response = send(address, "POST", "/fhir", &minimal, &transaction.to_string());
+    assert_eq!(response.status, 200);
+    let entries = response.body["entry"].as_array().unwrap();
+    let statuses = entries
+      .iter()
+      .map(|entry| entry["response"]["status"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      statuses,
+      vec!["200 OK", "200 OK", "201 Created", "204 No Content"]
+    );
+    assert_eq!(entries[0]["resource"]["total"], 2);
+    assert!(entries[1].get("resource").is_none());
+    assert_eq!(entries[1]["response"]["etag"], "W/\"2\"");
+    let location = entries[2]["response"]["location"].as_str().unwrap();
+    assert!(location.starts_with("http://example.org/fhir/Patient/"));
+    assert!(location.ends_with("/_history/1"));
+    assert_eq!(
+      send(address, "GET", "/fhir/Patient/p1", &[], "").status,
+      410
+    );
+
+    // A failure undoes the deletes before it too.
+    let failing = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [{
+        "resource": {"resourceType": "Patient", "id": "p2", "name": [{"family": "Tudor"}]},
+        "request": {"method": "PUT", "url": "Patient/p2", "ifMatch": "W/\"1\""}
+      }, {
+        "request": {"method": "DELETE", "url": "Patient?family=stuart"}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &failing.to_string());
+    assert_eq!(response.status, 412);
+    assert_eq!(
+      response.body["issue"][0]["expression"][0],
+      "Bundle.entry[0]"
+    );
+    let stuart = send(address, "GET", "/fhir/Patient?family=stuart", &[], "");
+    assert_eq!(stuart.body["total"], 1);
+
+    // Only transactions and batches can be posted, entries need a request,
+    // and can't hold another transaction.
+    let collection = json!({"resourceType": "Bundle", "type": "collection"});
+    let response = send(address, "POST", "/fhir", &[], &collection.to_string());
+    assert_eq!(response.status, 400);
+    let unrequested = json!({"resourceType": "Bundle", "type": "transaction",
+      "entry": [{"resource": {"resourceType": "Patient"}}]});
+    let response = send(address, "POST", "/fhir", &[], &unrequested.to_string());
+    assert_eq!(response.status, 400);
+    assert_eq!(
+      response.body["issue"][0]["expression"][0],
+      "Bundle.entry[0].request"
+    );
+    let nested = json!({"resourceType": "Bundle", "type": "transaction", "entry": [{
+      "resource": transaction,
+      "request": {"method": "POST", "url": ""}
+    }]});
+    let response = send(address, "POST", "/fhir", &[], &nested.to_string());
+    assert_eq!(response.status, 400);
+    assert_eq!(
+      response.body["issue"][0]["expression"][0],
+      "Bundle.entry[0]"
+    );
+    running.stop();
+  }
+
+  #[test]
+  fn test_batch_rules() {
+    let running = server();
+    let address = running.address();
+    for family in ["Windsor", "Windsor"] {
+      let patient = json!({"resourceType": "Patient", "name": [{"family": family}]});
+      let created = send(address, "POST", "/fhir/Patient", &[], &patient.to_string());
+      assert_eq!(created.status, 201);
+    }
+
+    // Each entry succeeds or fails on its own, in the order given.
+    let batch = json!({
+      "resourceType": "Bundle",
+      "type": "batch",
+      "entry": [{
+        "resource": {"resourceType": "Patient", "name": [{"family": "Tudor"}]},
+        "request": {"method": "POST", "url": "Patient"}
+      }, {
+        "resource": {"resourceType": "Patient", "id": "p7"},
+        "request": {"method": "PUT", "url": "Patient/p8"}
+      }, {
+        "request": {"method": "DELETE", "url": "Patient?family=windsor"}
+      }, {
+        "resource": {"resourceType": "Bundle", "type": "batch"},
+        "request": {"method": "POST", "url": ""}
+      }, {
+        "resource": {"resourceType": "Patient", "name": [{"family": "Tudor"}]},
+        "request": {"method": "POST", "url": "Patient", "ifNoneExist": "family=tudor"}
+      }, {
+        "request": {"method": "GET", "url": "Patient?family=tudor,windsor"}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &batch.to_string());
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["type"], "batch-response");
+    let entries = response.body["entry"].as_array().unwrap();
+    let statuses = entries
+      .iter()
+      .map(|entry| entry["response"]["status"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      statuses,
+      vec![
+        "201 Created",
+        "400 Bad Request",
+        "412 Precondition Failed",
+        "400 Bad Request",
+        "200 OK",
+        "200 OK"
+      ]
+    );
+    assert_eq!(
+      entries[2]["response"]["outcome"]["issue"][0]["code"],
+      "multiple-matches"
+    );
+    assert_eq!(entries[4]["resource"]["id"], entries[0]["resource"]["id"]);
+    assert_eq!(entries[5]["resource"]["total"], 3);
+    running.stop();
+  }
+
+  #[test]
+  fn test_conditional_transactions() {
+    let running = server();
+    let address = running.address();
+    let patient = r#"{"resourceType": "Patient", "name": [{"family": "Windsor"}]}"#;
+    assert_eq!(
+      send(address, "POST", "/fhir/Patient", &[], patient).status,
+      201
+    );
+
+    // The observations are processed before the conditional interactions,
+    // but still refer to the patient they match.
+    let created = "urn:uuid:5d0f2a13-4c1b-4d8e-9f6a-2b7c8e9d0a11";
+    let updated = "urn:uuid:7e1a3b24-5d2c-4e9f-8a7b-3c8d9f0e1b22";
+    let transaction = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [{
+        "resource": {"resourceType": "Observation", "status": "final",
+          "subject": {"reference": created}},
+        "request": {"method": "POST", "url": "Observation"}
+      }, {
+        "fullUrl": created,
+        "resource": {"resourceType": "Patient", "name": [{"family": "Windsor"}]},
+        "request": {"method": "POST", "url": "Patient", "ifNoneExist": "family=windsor"}
+      }, {
+        "resource": {"resourceType": "Observation", "id": "o1", "status": "final",
+          "subject": {"reference": updated}},
+        "request": {"method": "PUT", "url": "Observation/o1"}
+      }, {
+        "fullUrl": updated,
+        "resource": {"resourceType": "Patient", "name": [{"family": "Windsor"}], "active": true},
+        "request": {"method": "PUT", "url": "Patient?family=windsor"}
+      }]
+    });
+    let response = send(address, "POST", "/fhir", &[], &transaction.to_string());
+    assert_eq!(response.status, 200);
+    let statuses = (0..4)
+      .map(|index| response.body["entry"][index]["response"]["status"].clone())
+      .collect::<Vec<_>>();
+    assert_eq!(
+      statuses,
+      vec![
+        json!("201 Created"),
+        json!("200 OK"),
+        json!("201 Created"),
+        json!("200 OK")
+      ]
+    );
+    for index in [0, 2] {
+      let observation = &response.body["entry"][index]["resource"];
+      let read = send(
+        address,
+        "GET",
+        &format!("/fhir/Observation/{}", observation["id"].as_str().unwrap()),
+        &[],
+        "",
+      );
+      assert_eq!(read.body["subject"]["reference"], "Patient/p1");
+    }
+    let patients = send(address, "GET", "/fhir/Patient", &[], "");
+    assert_eq!(patients.body["total"], 1);
+    assert_eq!(patients.body["entry"][0]["resource"]["active"], true);
+
+    // Without a match, the conditional update creates the patient it refers
+    // to, and a conditional create matching more than one fails them all.
+    let mut transaction = transaction;
+    transaction["entry"][3]["request"]["url"] = json!("Patient?family=tudor");
+    transaction["entry"][3]["resource"]["name"][0]["family"] = json!("Tudor");
+    let response = send(address, "POST", "/fhir", &[], &transaction.to_string());
+    assert_eq!(
+      response.body["entry"][3]["response"]["status"],
+      "201 Created"
+    );
+    let tudor = &response.body["entry"][3]["resource"]["id"];
+    let read = send(address, "GET", "/fhir/Observation/o1", &[], "");
+    assert_eq!(
+      read.body["subject"]["reference"],
+      format!("Patient/{}", tudor.as_str().unwrap())
+    );
+    transaction["entry"][1]["request"]["ifNoneExist"] = json!("active=true,false");
+    let response = send(address, "POST", "/fhir", &[], &transaction.to_string());
+    assert_eq!(response.status, 412);
+    assert_eq!(
+      response.body["issue"][0]["expression"][0],
+      "Bundle.entry[1]"
+    );
+    running.stop();
+  }
+
+  #[test]
+  fn test_patch_entries() {
+    let running = server();
+    let address = running.address();
+    let patient = r#"{"resourceType": "Patient", "active": true}"#;
+    assert_eq!(
+      send(address, "POST", "/fhir/Patient", &[], patient).status,
+      201
+    );
+    let read = || send(address, "GET", "/fhir/Patient/p1", &[], "").body;
+
+    let replace = json!([{"op": "replace", "path": "/active", "value": false}]);
+    let bundle = TransactionBuilder::transaction()
+      .patch("Patient/p1", &replace)
+      .build()
+      .to_json();
+    let response = send(address, "POST", "/fhir", &[], &bundle.to_string());
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["entry"][0]["response"]["status"], "200 OK");
+    assert_eq!(response.body["entry"][0]["response"]["etag"], "W/\"2\"");
+    assert_eq!(read()["active"], false);
+
+    // FHIRPath Patch isn't supported, which fails a transaction but only its
+    // own entry in a batch.
+    let add = json!([{"op": "add", "path": "/gender", "value": "female"}]);
+    let fhirpath_patch = json!({
+      "resource": {"resourceType": "Parameters", "parameter": [{"name": "operation", "part": [
+        {"name": "type", "valueCode": "delete"},
+        {"name": "path", "valueString": "Patient.active"}
+      ]}]},
+      "request": {"method": "PATCH", "url": "Patient/p1"}
+    });
+    let mut bundle = TransactionBuilder::transaction()
+      .patch("Patient/p1", &add)
+      .build()
+      .to_json();
+    bundle["entry"].as_array_mut().unwrap().push(fhirpath_patch);
+    let response = send(address, "POST", "/fhir", &[], &bundle.to_string());
+    assert_eq!(response.status, 400);
+    let issue = &response.body["issue"][0];
+    assert_eq!(issue["expression"][0], "Bundle.entry[1]");
+    assert!(issue["diagnostics"]
+      .as_str()
+      .unwrap()
+      .starts_with("FHIRPath Patch isn't supported"));
+    assert_eq!(read()["meta"]["versionId"], "2");
+
+    bundle["type"] = json!("batch");
+    let response = send(address, "POST", "/fhir", &[], &bundle.to_string());
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body["entry"][0]["response"]["status"], "200 OK");
+    assert_eq!(
+      response.body["entry"][1]["response"]["status"],
+      "400 Bad Request"
+    );
+    assert_eq!(read()["gender"], "female");
+    assert_eq!(read()["meta"]["versionId"], "3");
+
+    // The Binary must hold base64.
+    bundle["entry"][0]["resource"]["data"] = json!("not base64!");
+    let response = send(address, "POST", "/fhir", &[], &bundle.to_string());
+    assert_eq!(
+      response.body["entry"][0]["response"]["status"],
+      "400 Bad Request"
+    );
+    running.stop();
+  }
+
+  #[test]
+  fn test_workers() {
+    let running = server_with_workers(1);
+    let address = running.address();
+    // The first connection stays open, so it has the only worker, the second
+    // waits for it, and there's no room for the third.
+    let first = TcpStream::connect(address).unwrap();
+    assert_eq!(keep_alive(&first), 200);
+    let second = TcpStream::connect(address).unwrap();
+    thread::sleep(Duration::from_millis(200));
+    let third = TcpStream::connect(address).unwrap();
+    assert_eq!(keep_alive(&third), 503);
+    drop(first);
+    assert_eq!(keep_alive(&second), 200);
+
+    // Stopping closes the open connection and frees the address.
+    running.stop();
+    let mut rest = vec![];
+    BufReader::new(&second).read_to_end(&mut rest).unwrap();
+    assert!(TcpListener::bind(address).is_ok());
+  }
+}
@@ -0,0 +1,162 @@
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::outcome::Issue;
+use serde_json::value::Value;
+
+/// Applies a JSON Patch (RFC 6902) document to a resource. Operations apply
+/// in order, and none apply if any fails.
+pub fn apply_json_patch(resource: &Value, patch: &Value) -> Result<Value, Vec<Issue>> {
+  let operations = patch
+    .as_array()
+    .ok_or_else(|| vec![invalid("A JSON Patch is an array of operations")])?;
+  let mut patched = resource.clone();
+  for (index, operation) in operations.iter().enumerate() {
+    apply(&mut patched, operation).map_err(|issue| vec![issue.at(&format!("[{}]", index))])?;
+  }
+  Ok(patched)
+}
+
+fn apply(value: &mut Value, operation: &Value) -> Result<(), Issue> {
+  let path = member(operation, "path")?;
+  match member(operation, "op")? {
+    "add" => add(value, path, required(operation, "value")?.clone()),
+    "remove" => remove(value, path).map(|_| ()),
+    "replace" => {
+      let replacement = required(operation, "value")?.clone();
+      *target(value, path)? = replacement;
+      Ok(())
+    }
+    "move" => {
+      let from = member(operation, "from")?;
+      if path.starts_with(&format!("{}/", from)) {
+        return Err(invalid("A value can't be moved into itself"));
+      }
+      let moved = remove(value, from)?;
+      add(value, path, moved)
+    }
+    "copy" => {
+      let copied = target(value, member(operation, "from")?)?.clone();
+      add(value, path, copied)
+    }
+    "test" => match *target(value, path)? == *required(operation, "value")? {
+      true => Ok(()),
+      false => Err(Issue::error(
+        OperationOutcome_IssueCode::Processing,
+        &format!("The value at {} isn't the one tested for", path),
+      )),
+    },
+    op => Err(invalid(&format!("Unknown JSON Patch operation '{}'", op))),
+  }
+}
+
+fn add(value: &mut Value, path: &str, added: Value) -> Result<(), Issue> {
+  if path.is_empty() {
+    *value = added;
+    return Ok(());
+  }
+  let (parent, last) = split(path)?;
+  match target(value, parent)? {
+    Value::Object(object) => {
+      object.insert(last, added);
+      Ok(())
+    }
+    Value::Array(array) => {
+      let index = match last.as_str() {
+        "-" => array.len(),
+        _ => position(&last, array.len() + 1)?,
+      };
+      array.insert(index, added);
+      Ok(())
+    }
+    _ => Err(missing(path)),
+  }
+}
+
+fn remove(value: &mut Value, path: &str) -> Result<Value, Issue> {
+  let (parent, last) = split(path)?;
+  match target(value, parent)? {
+    Value::Object(object) => object.remove(&last).ok_or_else(|| missing(path)),
+    Value::Array(array) => {
+      let index = position(&last, array.len())?;
+      Ok(array.remove(index))
+    }
+    _ => Err(missing(path)),
+  }
+}
+
+fn target<'v>(value: &'v mut Value, path: &str) -> Result<&'v mut Value, Issue> {
+  value.pointer_mut(path).ok_or_else(|| missing(path))
+}
+
+/// Splits a JSON pointer into its parent and its unescaped last token.
+fn split(path: &str) -> Result<(&str, String), Issue> {
+  match path.rfind('/') {
+    Some(slash) => Ok((
+      &path[..slash],
+      path[slash + 1..].replace("~1", "/").replace("~0", "~"),
+    )),
+    None => Err(invalid(&format!("'{}' isn't a JSON pointer", path))),
+  }
+}
+
+fn position(token: &str, limit: usize) -> Result<usize, Issue> {
+  match token.parse::<usize>() {
+    Ok(index) if index < limit && (token == "0" || !token.starts_with('0')) => Ok(index),
+    _ => Err(invalid(&format!("'{}' isn't an index in the array", token))),
+  }
+}
+
+fn member<'o>(operation: &'o Value, name: &str) -> Result<&'o str, Issue> {
+  required(operation, name)?
+    .as_str()
+    .ok_or_else(|| invalid(&format!("The operation's {} must be a string", name)))
+}
+
+fn required<'o>(operation: &'o Value, name: &str) -> Result<&'o Value, Issue> {
+  operation
+    .get(name)
+    .ok_or_else(|| invalid(&format!("The operation needs a {}", name)))
+}
+
+fn missing(path: &str) -> Issue {
+  Issue::error(
+    OperationOutcome_IssueCode::Processing,
+    &format!("Nothing is at {}", path),
+  )
+}
+
+fn invalid(message: &str) -> Issue {
+  Issue::error(OperationOutcome_IssueCode::Invalid, message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_json_patch() {
+    let patient =
+      json!({"resourceType": "Patient", "name": [{"family": "Chalmers"}], "active": true});
+    let patch = json!([
+      {"op": "test", "path": "/active", "value": true},
+      {"op": "replace", "path": "/active", "value": false},
+      {"op": "add", "path": "/name/-", "value": {"family": "Windsor"}},
+      {"op": "copy", "from": "/name/0/family", "path": "/name/1/given"},
+      {"op": "move", "from": "/name/1/given", "path": "/birthDate"},
+      {"op": "remove", "path": "/name/0"}
+    ]);
+    assert_eq!(
+      apply_json_patch(&patient, &patch).unwrap(),
+      json!({"resourceType": "Patient", "name": [{"family": "Windsor"}], "active": false, "birthDate": "Chalmers"})
+    );
+
+    let failing = json!([{"op": "remove", "path": "/active"}, {"op": "test", "path": "/gender", "value": "male"}]);
+    let issues = apply_json_patch(&patient, &failing).unwrap_err();
+    assert_eq!(issues[0].expression.as_deref(), Some("[1]"));
+    assert!(apply_json_patch(
+      &patient,
+      &json!([{"op": "add", "path": "/name/07", "value": 1}])
+    )
+    .is_err());
+  }
+}
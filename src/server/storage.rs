@@ -0,0 +1,127 @@
+use crate::model::Bundle::Bundle;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::repository::{Repository, Saved};
+use crate::search::{Query, SearchParameters};
+
+/// Where a [`Server`](super::Server) keeps resources. Conditional operations
+/// are built on `search`, so stores only need the plain interactions.
+///
+/// Writes must keep `meta.versionId` and `meta.lastUpdated` up to date, as
+/// the server reports them in `ETag` and `Last-Modified`. Errors are reported
+/// as issues whose codes decide the HTTP status: `not-found` is 404,
+/// `deleted` 410, `conflict` 409 (412 with `If-Match`), and so on.
+pub trait Storage: Send {
+  /// The parameters searches are parsed with.
+  fn parameters(&self) -> &SearchParameters;
+
+  /// Stores a new resource under a new id.
+  fn create(&mut self, resource: &ResourceList) -> Result<ResourceList<'static>, Vec<Issue>>;
+
+  fn read(&self, resource_type: &str, id: &str) -> Result<ResourceList<'_>, Vec<Issue>>;
+
+  fn vread(
+    &self,
+    resource_type: &str,
+    id: &str,
+    version_id: &str,
+  ) -> Result<ResourceList<'_>, Vec<Issue>>;
+
+  /// Stores a resource with the id it has, creating it if there's none,
+  /// unless the expected version isn't the current one.
+  fn update(
+    &mut self,
+    resource: &ResourceList,
+    expected_version: Option<&str>,
+  ) -> Result<Saved, Vec<Issue>>;
+
+  fn delete(
+    &mut self,
+    resource_type: &str,
+    id: &str,
+    expected_version: Option<&str>,
+  ) -> Result<(), Vec<Issue>>;
+
+  /// Runs a search, giving a `searchset` Bundle.
+  fn search(&self, query: &Query) -> Result<Bundle<'static>, Vec<Issue>>;
+
+  /// The history of a resource, of a type when there's no id, or of
+  /// everything when there's no type either.
+  fn history(
+    &self,
+    resource_type: Option<&str>,
+    id: Option<&str>,
+    since: Option<&str>,
+  ) -> Result<Bundle<'static>, Vec<Issue>>;
+
+  /// Marks the current state, for [`Storage::rollback`] to return to when a
+  /// transaction fails.
+  fn savepoint(&mut self) -> usize;
+
+  fn rollback(&mut self, savepoint: usize);
+}
+
+impl Storage for Repository {
+  fn parameters(&self) -> &SearchParameters {
+    Repository::parameters(self)
+  }
+
+  fn create(&mut self, resource: &ResourceList) -> Result<ResourceList<'static>, Vec<Issue>> {
+    Repository::create(self, resource)
+  }
+
+  fn read(&self, resource_type: &str, id: &str) -> Result<ResourceList<'_>, Vec<Issue>> {
+    Repository::read(self, resource_type, id)
+  }
+
+  fn vread(
+    &self,
+    resource_type: &str,
+    id: &str,
+    version_id: &str,
+  ) -> Result<ResourceList<'_>, Vec<Issue>> {
+    Repository::vread(self, resource_type, id, version_id)
+  }
+
+  fn update(
+    &mut self,
+    resource: &ResourceList,
+    expected_version: Option<&str>,
+  ) -> Result<Saved, Vec<Issue>> {
+    Repository::update(self, resource, expected_version)
+  }
+
+  fn delete(
+    &mut self,
+    resource_type: &str,
+    id: &str,
+    expected_version: Option<&str>,
+  ) -> Result<(), Vec<Issue>> {
+    Repository::delete(self, resource_type, id, expected_version)
+  }
+
+  fn search(&self, query: &Query) -> Result<Bundle<'static>, Vec<Issue>> {
+    Repository::search(self, query)
+  }
+
+  fn history(
+    &self,
+    resource_type: Option<&str>,
+    id: Option<&str>,
+    since: Option<&str>,
+  ) -> Result<Bundle<'static>, Vec<Issue>> {
+    match (resource_type, id) {
+      (Some(resource_type), Some(id)) => self.instance_history(resource_type, id, since),
+      (Some(resource_type), None) => self.type_history(resource_type, since),
+      _ => self.system_history(since),
+    }
+  }
+
+  fn savepoint(&mut self) -> usize {
+    Repository::savepoint(self)
+  }
+
+  fn rollback(&mut self, savepoint: usize) {
+    Repository::rollback(self, savepoint)
+  }
+}
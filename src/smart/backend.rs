@@ -0,0 +1,148 @@
+use super::jwt::{base64url, signing_input};
+use super::{request_token, secure, Error, Token};
+use crate::client::Transport;
+use crate::ids::new_uuid;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use serde_json::json;
+use sha2::Sha384;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const JWT_BEARER: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+/// How long client assertions are valid for, the most SMART allows.
+const ASSERTION_LIFETIME: u64 = 300;
+
+/// The private key a backend client signs its assertions with, registered
+/// with the authorization server by its public half.
+#[derive(Clone)]
+pub enum ClientKey {
+  Rs384(Box<rsa::pkcs1v15::SigningKey<Sha384>>),
+  Es384(p384::ecdsa::SigningKey),
+}
+
+impl ClientKey {
+  /// An RSA key, from PKCS#8 PEM, for `RS384` signatures.
+  pub fn rs384_pem(pem: &str) -> Result<ClientKey, Error> {
+    let key = rsa::RsaPrivateKey::from_pkcs8_pem(pem)
+      .map_err(|error| Error::Invalid(format!("Not an RSA private key: {}", error)))?;
+    Ok(ClientKey::Rs384(Box::new(rsa::pkcs1v15::SigningKey::new(
+      key,
+    ))))
+  }
+
+  /// A P-384 key, from PKCS#8 PEM, for `ES384` signatures.
+  pub fn es384_pem(pem: &str) -> Result<ClientKey, Error> {
+    let key = p384::ecdsa::SigningKey::from_pkcs8_pem(pem)
+      .map_err(|error| Error::Invalid(format!("Not a P-384 private key: {}", error)))?;
+    Ok(ClientKey::Es384(key))
+  }
+
+  pub fn algorithm(&self) -> &'static str {
+    match self {
+      ClientKey::Rs384(_) => "RS384",
+      ClientKey::Es384(_) => "ES384",
+    }
+  }
+
+  /// Signs a JWT's signing input, giving the signature as JWS has it:
+  /// PKCS #1 v1.5 for RSA, and `r` and `s` side by side for ECDSA.
+  fn sign(&self, input: &[u8]) -> Vec<u8> {
+    match self {
+      ClientKey::Rs384(key) => key.sign(input).to_vec(),
+      ClientKey::Es384(key) => {
+        let signature: p384::ecdsa::Signature = key.sign(input);
+        signature.to_bytes().to_vec()
+      }
+    }
+  }
+}
+
+// Keys are secret, so only their kind is shown.
+impl fmt::Debug for ClientKey {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "ClientKey({})", self.algorithm())
+  }
+}
+
+/// SMART Backend Services
+/// (http://hl7.org/fhir/smart-app-launch/backend-services.html): a system
+/// with no user gets tokens with the `client_credentials` grant, proving who
+/// it is with a JWT signed by its key.
+#[derive(Debug, Clone)]
+pub struct BackendServices {
+  token_endpoint: String,
+  client_id: String,
+  key_id: String,
+  key: ClientKey,
+  scope: Option<String>,
+  pub(crate) allow_http: bool,
+}
+
+impl BackendServices {
+  /// A client registered as `client_id`, whose key is published with the id
+  /// `key_id`, getting tokens from a token endpoint, such as the one in the
+  /// server's [`SmartConfiguration`](super::SmartConfiguration).
+  pub fn new(
+    token_endpoint: &str,
+    client_id: &str,
+    key_id: &str,
+    key: ClientKey,
+  ) -> BackendServices {
+    BackendServices {
+      token_endpoint: token_endpoint.to_string(),
+      client_id: client_id.to_string(),
+      key_id: key_id.to_string(),
+      key,
+      scope: None,
+      allow_http: false,
+    }
+  }
+
+  /// The scopes to ask for, e.g. `system/Observation.rs system/Patient.rs`.
+  pub fn scope<'a>(&'a mut self, scope: &str) -> &'a mut BackendServices {
+    self.scope = Some(scope.to_string());
+    self
+  }
+
+  /// Lets the token endpoint be plain `http`, as it might be for a local test
+  /// server. Assertions and tokens are then sent unencrypted.
+  pub fn allow_http(&mut self) -> &mut BackendServices {
+    self.allow_http = true;
+    self
+  }
+
+  /// A new signed JWT asserting the client's identity to the token endpoint.
+  pub fn assertion(&self) -> String {
+    let now = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map(|since| since.as_secs())
+      .unwrap_or_default();
+    let header = json!({ "alg": self.key.algorithm(), "typ": "JWT", "kid": self.key_id });
+    let claims = json!({
+      "iss": self.client_id,
+      "sub": self.client_id,
+      "aud": self.token_endpoint,
+      "exp": now + ASSERTION_LIFETIME,
+      "jti": new_uuid(),
+    });
+    let input = signing_input(&header, &claims);
+    let signature = base64url(&self.key.sign(input.as_bytes()));
+    format!("{}.{}", input, signature)
+  }
+
+  /// Gets a new access token. Backend services have no refresh tokens, so
+  /// this is also how tokens are renewed.
+  pub async fn request_token(&self, transport: &dyn Transport) -> Result<Token, Error> {
+    secure(&self.token_endpoint, self.allow_http)?;
+    let assertion = self.assertion();
+    let mut form = vec![
+      ("grant_type", "client_credentials"),
+      ("client_assertion_type", JWT_BEARER),
+      ("client_assertion", assertion.as_str()),
+    ];
+    form.extend(self.scope.as_deref().map(|scope| ("scope", scope)));
+    request_token(transport, &self.token_endpoint, &form, None).await
+  }
+}
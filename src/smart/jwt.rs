@@ -0,0 +1,52 @@
+use crate::util::{decode_base64, encode_base64};
+use serde_json::value::Value;
+
+const URL_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Base64url without padding, as in JWTs and PKCE.
+pub(crate) fn base64url(bytes: &[u8]) -> String {
+  encode_base64(bytes, URL_ALPHABET)
+}
+
+/// Decodes base64url, with or without padding.
+pub(crate) fn decode_base64url(text: &str) -> Option<Vec<u8>> {
+  decode_base64(text, URL_ALPHABET)
+}
+
+/// Encodes a JWT's header and claims as the signing input, `header.claims`.
+pub(crate) fn signing_input(header: &Value, claims: &Value) -> String {
+  format!(
+    "{}.{}",
+    base64url(header.to_string().as_bytes()),
+    base64url(claims.to_string().as_bytes())
+  )
+}
+
+/// The claims of a JWT, such as an OpenID Connect `id_token`, without
+/// checking its signature.
+pub(crate) fn claims(jwt: &str) -> Option<Value> {
+  let payload = jwt.split('.').nth(1)?;
+  serde_json::from_slice(&decode_base64url(payload)?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::util::base64;
+
+  #[test]
+  fn test_base64() {
+    assert_eq!(base64(b"client:s3cret"), "Y2xpZW50OnMzY3JldA==");
+    assert_eq!(base64(b"ab"), "YWI=");
+    assert_eq!(base64url(&[0xfb, 0xff]), "-_8");
+    for text in &["", "f", "fo", "foo", "foob", "fooba", "foobar"] {
+      assert_eq!(
+        decode_base64url(&base64url(text.as_bytes())).unwrap(),
+        text.as_bytes()
+      );
+    }
+    assert_eq!(decode_base64url("YWI="), Some(b"ab".to_vec()));
+    assert_eq!(decode_base64url("Y"), None);
+    assert_eq!(decode_base64url("Y+"), None);
+  }
+}
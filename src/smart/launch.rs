@@ -0,0 +1,219 @@
+use super::jwt::base64url;
+use super::{request_token, secure, Error, SmartConfiguration, Token};
+use crate::client::Transport;
+use crate::search::{query_pairs, query_string};
+use crate::util::base64;
+use sha2::{Digest, Sha256};
+
+/// The SMART App Launch
+/// (http://hl7.org/fhir/smart-app-launch/app-launch.html) of an app that
+/// acts for a user: the user authorizes the app at the authorization
+/// endpoint, which redirects back with a code that's exchanged for a token,
+/// protected with PKCE.
+///
+/// ```no_run
+/// use fhir_rs::client::{Client, HttpTransport};
+/// use fhir_rs::smart::{AppLaunch, Authorized, SmartConfiguration};
+///
+/// # async fn example(redirected_to: &str) -> Result<(), fhir_rs::smart::Error> {
+/// let transport = HttpTransport::new();
+/// let base = "https://example.org/fhir";
+/// let configuration = SmartConfiguration::discover(&transport, base).await?;
+/// let mut launch = AppLaunch::new(&configuration, "my-app", "http://localhost:8080/callback")?;
+/// launch.scope("launch/patient openid fhirUser patient/*.rs offline_access").audience(base);
+/// let request = launch.authorize(None)?;
+/// // Send the user to `request.url`; the redirect comes back to the app.
+/// let token = launch.exchange(&transport, &request, redirected_to).await?;
+/// let patient = token.patient();
+/// let client = Client::with_transport(base, Authorized::launched(transport, launch, token));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct AppLaunch {
+  authorization_endpoint: String,
+  token_endpoint: String,
+  client_id: String,
+  client_secret: Option<String>,
+  redirect_uri: String,
+  scope: Option<String>,
+  audience: Option<String>,
+  pub(crate) allow_http: bool,
+}
+
+/// An authorization the user is asked for, to send them to at `url`. The
+/// app keeps it until the redirect back, whose `state` must match.
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+  pub url: String,
+  pub state: String,
+  code_verifier: String,
+}
+
+impl AppLaunch {
+  /// An app registered as `client_id`, redirected back to `redirect_uri`,
+  /// using the endpoints in a server's configuration. Servers must support
+  /// PKCE with `S256`.
+  pub fn new(
+    configuration: &SmartConfiguration,
+    client_id: &str,
+    redirect_uri: &str,
+  ) -> Result<AppLaunch, Error> {
+    let endpoint = |endpoint: Option<&str>, name: &str| {
+      endpoint
+        .map(str::to_string)
+        .ok_or_else(|| Error::Invalid(format!("The server has no {} endpoint", name)))
+    };
+    let methods = configuration.code_challenge_methods_supported();
+    if !methods.contains(&"S256") {
+      return Err(Error::Invalid(
+        "The server doesn't support PKCE with S256".to_string(),
+      ));
+    }
+    Ok(AppLaunch {
+      authorization_endpoint: endpoint(configuration.authorization_endpoint(), "authorization")?,
+      token_endpoint: endpoint(configuration.token_endpoint(), "token")?,
+      client_id: client_id.to_string(),
+      client_secret: None,
+      redirect_uri: redirect_uri.to_string(),
+      scope: None,
+      audience: None,
+      allow_http: false,
+    })
+  }
+
+  /// Makes the app a confidential client, which authenticates with a secret.
+  pub fn client_secret<'a>(&'a mut self, secret: &str) -> &'a mut AppLaunch {
+    self.client_secret = Some(secret.to_string());
+    self
+  }
+
+  /// The scopes to ask for, e.g. `launch/patient openid fhirUser patient/*.rs`.
+  /// `offline_access` or `online_access` asks for a refresh token.
+  pub fn scope<'a>(&'a mut self, scope: &str) -> &'a mut AppLaunch {
+    self.scope = Some(scope.to_string());
+    self
+  }
+
+  /// The FHIR server the token is for, its service base URL.
+  pub fn audience<'a>(&'a mut self, audience: &str) -> &'a mut AppLaunch {
+    self.audience = Some(audience.to_string());
+    self
+  }
+
+  /// Lets the endpoints be plain `http`, as they might be for a local test
+  /// server. The code, the client secret and tokens are then sent
+  /// unencrypted.
+  pub fn allow_http(&mut self) -> &mut AppLaunch {
+    self.allow_http = true;
+    self
+  }
+
+  /// Starts an authorization, with a new `state` and PKCE code verifier.
+  /// Apps launched from an EHR pass the `launch` parameter they were given.
+  pub fn authorize(&self, launch: Option<&str>) -> Result<AuthorizationRequest, Error> {
+    secure(&self.authorization_endpoint, self.allow_http)?;
+    let state = base64url(&random::<16>()?);
+    let code_verifier = base64url(&random::<32>()?);
+    let code_challenge = base64url(&Sha256::digest(code_verifier.as_bytes()));
+    let mut parameters = vec![
+      ("response_type", "code"),
+      ("client_id", self.client_id.as_str()),
+      ("redirect_uri", self.redirect_uri.as_str()),
+      ("state", state.as_str()),
+      ("code_challenge", code_challenge.as_str()),
+      ("code_challenge_method", "S256"),
+    ];
+    parameters.extend(self.scope.as_deref().map(|scope| ("scope", scope)));
+    parameters.extend(self.audience.as_deref().map(|audience| ("aud", audience)));
+    parameters.extend(launch.map(|launch| ("launch", launch)));
+    let separator = match self.authorization_endpoint.contains('?') {
+      true => '&',
+      false => '?',
+    };
+    Ok(AuthorizationRequest {
+      url: format!(
+        "{}{}{}",
+        self.authorization_endpoint,
+        separator,
+        query_string(&parameters)
+      ),
+      state,
+      code_verifier,
+    })
+  }
+
+  /// Finishes an authorization with the URL the user was redirected back
+  /// to, exchanging the code in it for a token.
+  pub async fn exchange(
+    &self,
+    transport: &dyn Transport,
+    request: &AuthorizationRequest,
+    redirected_to: &str,
+  ) -> Result<Token, Error> {
+    let query = redirected_to.split_once('?').map_or("", |(_, query)| query);
+    let query = query.split('#').next().unwrap_or_default();
+    let pairs = query_pairs(query);
+    let get = |name: &str| {
+      pairs
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+    };
+    if get("state") != Some(request.state.as_str()) {
+      return Err(Error::Invalid(
+        "The redirect's state isn't the one sent".to_string(),
+      ));
+    }
+    if let Some(error) = get("error") {
+      return Err(Error::Refused {
+        error: error.to_string(),
+        description: get("error_description").map(str::to_string),
+      });
+    }
+    let code = get("code").ok_or_else(|| Error::Invalid("The redirect has no code".to_string()))?;
+    let form = [
+      ("grant_type", "authorization_code"),
+      ("code", code),
+      ("redirect_uri", self.redirect_uri.as_str()),
+      ("code_verifier", request.code_verifier.as_str()),
+    ];
+    self.token(transport, &form).await
+  }
+
+  /// Gets a new access token with the refresh token of one that's expiring.
+  /// Launch context the server doesn't send again is kept.
+  pub async fn refresh(&self, transport: &dyn Transport, token: &Token) -> Result<Token, Error> {
+    let refresh_token = token
+      .refresh_token()
+      .ok_or_else(|| Error::Invalid("The token can't be refreshed".to_string()))?;
+    let form = [
+      ("grant_type", "refresh_token"),
+      ("refresh_token", refresh_token),
+    ];
+    let refreshed = self.token(transport, &form).await?;
+    Ok(token.renewed(refreshed))
+  }
+
+  async fn token(&self, transport: &dyn Transport, form: &[(&str, &str)]) -> Result<Token, Error> {
+    secure(&self.token_endpoint, self.allow_http)?;
+    match &self.client_secret {
+      Some(secret) => {
+        let credentials = format!("{}:{}", self.client_id, secret);
+        let basic = format!("Basic {}", base64(credentials.as_bytes()));
+        request_token(transport, &self.token_endpoint, form, Some(&basic)).await
+      }
+      None => {
+        let mut form = form.to_vec();
+        form.push(("client_id", self.client_id.as_str()));
+        request_token(transport, &self.token_endpoint, &form, None).await
+      }
+    }
+  }
+}
+
+fn random<const N: usize>() -> Result<[u8; N], Error> {
+  let mut bytes = [0; N];
+  getrandom::getrandom(&mut bytes).map_err(|error| Error::Io(error.into()))?;
+  Ok(bytes)
+}
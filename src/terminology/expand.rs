@@ -0,0 +1,706 @@
+use super::{array, parameter, Hierarchy, Terminology, MAX_DEPTH};
+use crate::datetime::now_instant;
+use crate::ids::new_uuid_urn;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ValueSet::ValueSet;
+use crate::outcome::Issue;
+use regex::Regex;
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+/// `$expand` (http://hl7.org/fhir/valueset-operation-expand.html): lists the
+/// codes of a value set in its `expansion`, keeping the hierarchy of the code
+/// systems they come from unless the expansion is filtered or paged.
+pub struct Expander<'t> {
+  terminology: &'t Terminology,
+  filter: Option<String>,
+  offset: usize,
+  count: Option<usize>,
+  active_only: Option<bool>,
+}
+
+impl<'t> Expander<'t> {
+  pub fn new(terminology: &'t Terminology) -> Expander<'t> {
+    Expander {
+      terminology,
+      filter: None,
+      offset: 0,
+      count: None,
+      active_only: None,
+    }
+  }
+
+  /// Only keeps codes whose display or code contains `text`, ignoring case.
+  pub fn filter<'b>(&'b mut self, text: &str) -> &'b mut Expander<'t> {
+    self.filter = Some(text.to_string());
+    self
+  }
+
+  /// Skips the first `offset` codes of the expansion.
+  pub fn offset<'b>(&'b mut self, offset: usize) -> &'b mut Expander<'t> {
+    self.offset = offset;
+    self
+  }
+
+  /// Returns at most `count` codes.
+  pub fn count<'b>(&'b mut self, count: usize) -> &'b mut Expander<'t> {
+    self.count = Some(count);
+    self
+  }
+
+  /// Whether to leave out inactive codes, overriding `compose.inactive`.
+  pub fn active_only<'b>(&'b mut self, active_only: bool) -> &'b mut Expander<'t> {
+    self.active_only = Some(active_only);
+    self
+  }
+
+  /// Expands the value set with canonical URL `url`.
+  pub fn expand(&self, url: &str) -> Result<ValueSet<'static>, Vec<Issue>> {
+    let value_set = self
+      .terminology
+      .known_set(url)
+      .map_err(|issue| vec![issue])?;
+    self.expand_value(value_set)
+  }
+
+  /// Expands a value set that need not be known to the terminology service.
+  pub fn expand_value_set(&self, value_set: &ValueSet) -> Result<ValueSet<'static>, Vec<Issue>> {
+    self.expand_value(&value_set.value)
+  }
+
+  fn expand_value(&self, value_set: &Value) -> Result<ValueSet<'static>, Vec<Issue>> {
+    let mut used = vec![];
+    let mut contains = self
+      .compose(value_set, 0, &mut used)
+      .map_err(|issue| vec![issue])?;
+
+    let mut parameters = vec![];
+    let mut total = flatten(&contains).len();
+    let paged = self.filter.is_some() || self.offset > 0 || self.count.is_some();
+    if paged {
+      let text = self.filter.as_deref().map(str::to_lowercase);
+      let matching = flatten(&contains)
+        .into_iter()
+        .filter(|entry| match &text {
+          Some(text) => ["display", "code"].iter().any(|key| {
+            entry[*key]
+              .as_str()
+              .is_some_and(|value| value.to_lowercase().contains(text.as_str()))
+          }),
+          None => true,
+        })
+        .collect::<Vec<_>>();
+      total = matching.len();
+      let count = self.count.unwrap_or(usize::MAX);
+      contains = matching.into_iter().skip(self.offset).take(count).collect();
+    }
+    if let Some(filter) = &self.filter {
+      parameters.push(parameter("filter", "valueString", filter.as_str()));
+    }
+    if self.offset > 0 || self.count.is_some() {
+      parameters.push(parameter("offset", "valueInteger", self.offset));
+    }
+    if let Some(count) = self.count {
+      parameters.push(parameter("count", "valueInteger", count));
+    }
+    if let Some(active_only) = self.active_only {
+      parameters.push(parameter("activeOnly", "valueBoolean", active_only));
+    }
+    for system in used {
+      parameters.push(parameter("used-codesystem", "valueUri", system));
+    }
+
+    let mut expansion = json!({
+      "identifier": new_uuid_urn(),
+      "timestamp": now_instant(),
+      "total": total,
+    });
+    if self.offset > 0 || self.count.is_some() {
+      expansion["offset"] = json!(self.offset);
+    }
+    if !parameters.is_empty() {
+      expansion["parameter"] = json!(parameters);
+    }
+    if !contains.is_empty() {
+      expansion["contains"] = json!(contains);
+    }
+    let mut expanded = value_set.clone();
+    expanded["resourceType"] = json!("ValueSet");
+    expanded["expansion"] = expansion;
+    Ok(ValueSet {
+      value: Cow::Owned(expanded),
+    })
+  }
+
+  /// The `contains` entries a value set's compose selects, or its existing
+  /// expansion when it has no compose.
+  fn compose(
+    &self,
+    value_set: &Value,
+    depth: usize,
+    used: &mut Vec<String>,
+  ) -> Result<Vec<Value>, Issue> {
+    let compose = &value_set["compose"];
+    if compose.is_null() {
+      return Ok(array(&value_set["expansion"]["contains"]).to_vec());
+    }
+    if depth > MAX_DEPTH {
+      return Err(too_deep(value_set));
+    }
+    let inactive = match self.active_only {
+      Some(active_only) => !active_only,
+      None => compose["inactive"].as_bool() != Some(false),
+    };
+    let mut contains: Vec<Value> = vec![];
+    for include in array(&compose["include"]) {
+      let mut keys = flatten(&contains).iter().map(key).collect::<HashSet<_>>();
+      let entries = self.select(include, inactive, depth, used)?;
+      contains.extend(retain(entries, &mut |entry| keys.insert(key(entry))));
+    }
+    for exclude in array(&compose["exclude"]) {
+      let excluded = self.select(exclude, true, depth, used)?;
+      let excluded = flatten(&excluded).iter().map(key).collect::<HashSet<_>>();
+      contains = retain(contains, &mut |entry| !excluded.contains(&key(entry)));
+    }
+    Ok(contains)
+  }
+
+  /// The entries an `include` or `exclude` selects.
+  fn select(
+    &self,
+    include: &Value,
+    inactive: bool,
+    depth: usize,
+    used: &mut Vec<String>,
+  ) -> Result<Vec<Value>, Issue> {
+    let mut imported: Option<HashSet<(String, String)>> = None;
+    let mut first_import = vec![];
+    for url in array(&include["valueSet"]).iter().filter_map(Value::as_str) {
+      let value_set = self.terminology.known_set(url)?;
+      let entries = flatten(&self.compose(value_set, depth + 1, used)?);
+      let keys = entries.iter().map(key).collect::<HashSet<_>>();
+      imported = Some(match imported {
+        Some(previous) => previous.intersection(&keys).cloned().collect(),
+        None => {
+          first_import = entries;
+          keys
+        }
+      });
+    }
+    let in_imports = |entry: &Value| {
+      imported
+        .as_ref()
+        .is_none_or(|keys| keys.contains(&key(entry)))
+    };
+
+    let system = match include["system"].as_str() {
+      Some(system) => system,
+      None => return Ok(first_import.into_iter().filter(|e| in_imports(e)).collect()),
+    };
+    let code_system = self.terminology.system(system);
+    let version = include["version"]
+      .as_str()
+      .or_else(|| code_system.and_then(|cs| cs["version"].as_str()));
+    let used_system = match version {
+      Some(version) => format!("{}|{}", system, version),
+      None => system.to_string(),
+    };
+    if !used.contains(&used_system) {
+      used.push(used_system);
+    }
+
+    let concepts = array(&include["concept"]);
+    let entries = if !concepts.is_empty() {
+      concepts
+        .iter()
+        .filter_map(|listed| {
+          let code = listed["code"].as_str()?;
+          let found = self.terminology.hierarchy(system);
+          let concept = found.and_then(|h| h.value(code)).unwrap_or(listed);
+          let mut entry = entry(system, version, concept);
+          if let Some(display) = listed["display"].as_str() {
+            entry["display"] = json!(display);
+          }
+          Some(entry).filter(|entry| inactive || entry["inactive"] != true)
+        })
+        .collect()
+    } else {
+      let hierarchy = self.terminology.complete_system(system)?;
+      let filters = array(&include["filter"]);
+      let mut issue = None;
+      let mut keep = |concept: &Value| {
+        if !inactive && is_inactive(concept) {
+          return false;
+        }
+        let code = concept["code"].as_str().unwrap_or_default();
+        match matches_filters(hierarchy, code, filters) {
+          Ok(matches) => matches,
+          Err(error) => {
+            issue.get_or_insert(error);
+            false
+          }
+        }
+      };
+      let entries = tree(
+        hierarchy,
+        system,
+        version,
+        hierarchy.roots(),
+        &mut HashSet::new(),
+        &mut keep,
+      );
+      if let Some(issue) = issue {
+        return Err(issue);
+      }
+      entries
+    };
+    Ok(retain(entries, &mut |entry| in_imports(entry)))
+  }
+}
+
+impl Terminology {
+  /// Expands the value set with canonical URL `url` with the default
+  /// parameters of an [`Expander`].
+  pub fn expand(&self, url: &str) -> Result<ValueSet<'static>, Vec<Issue>> {
+    Expander::new(self).expand(url)
+  }
+}
+
+/// Whether the concept for a code passes all of the filters of an include.
+pub(super) fn matches_filters(
+  hierarchy: Hierarchy,
+  code: &str,
+  filters: &[Value],
+) -> Result<bool, Issue> {
+  for filter in filters {
+    let property = filter["property"].as_str().unwrap_or_default();
+    let op = filter["op"].as_str().unwrap_or_default();
+    let value = filter["value"].as_str().unwrap_or_default();
+    let hierarchical = matches!(property, "concept" | "code" | "parent" | "child");
+    let values = || property_values(hierarchy, code, property);
+    let matches = match op {
+      "is-a" if hierarchical => hierarchy.is_a(code, value),
+      "descendent-of" if hierarchical => code != value && hierarchy.is_a(code, value),
+      "is-not-a" if hierarchical => !hierarchy.is_a(code, value),
+      "generalizes" if hierarchical => hierarchy.is_a(value, code),
+      "=" => values().contains(&value.to_string()),
+      "in" => {
+        let values = values();
+        value
+          .split(',')
+          .any(|v| values.contains(&v.trim().to_string()))
+      }
+      "not-in" => {
+        let values = values();
+        !value
+          .split(',')
+          .any(|v| values.contains(&v.trim().to_string()))
+      }
+      "regex" => {
+        let regex = Regex::new(&format!("^(?:{})$", value)).map_err(|error| {
+          Issue::error(
+            OperationOutcome_IssueCode::Invalid,
+            &format!("The filter regex '{}' is not valid: {}", value, error),
+          )
+        })?;
+        values().iter().any(|v| regex.is_match(v))
+      }
+      "exists" => values().is_empty() == (value == "false"),
+      _ => {
+        return Err(Issue::error(
+          OperationOutcome_IssueCode::NotSupported,
+          &format!(
+            "The filter '{} {} {}' is not supported",
+            property, op, value
+          ),
+        ))
+      }
+    };
+    if !matches {
+      return Ok(false);
+    }
+  }
+  Ok(true)
+}
+
+/// The values a concept has for a property, as text. `code` and `display`
+/// come from the concept itself, and `parent` and `child` from the hierarchy,
+/// rather than its properties.
+fn property_values(hierarchy: Hierarchy, code: &str, property: &str) -> Vec<String> {
+  let concept = match hierarchy.value(code) {
+    Some(concept) => concept,
+    None => return vec![],
+  };
+  let text = |value: &Value| match value {
+    Value::String(text) => Some(text.clone()),
+    Value::Object(coding) => coding.get("code").and_then(Value::as_str).map(String::from),
+    Value::Null => None,
+    other => Some(other.to_string()),
+  };
+  let mut values = match property {
+    "code" | "concept" => text(&concept["code"]).into_iter().collect(),
+    "display" => text(&concept["display"]).into_iter().collect(),
+    "parent" => hierarchy
+      .parents(code)
+      .into_iter()
+      .map(String::from)
+      .collect(),
+    "child" => hierarchy
+      .children(code)
+      .into_iter()
+      .map(String::from)
+      .collect(),
+    _ => vec![],
+  };
+  for concept_property in array(&concept["property"]) {
+    if concept_property["code"] == property && !matches!(property, "parent" | "child") {
+      let value = concept_property.as_object().into_iter().flatten();
+      let value = value.filter(|(key, _)| key.starts_with("value"));
+      values.extend(value.filter_map(|(_, value)| text(value)));
+    }
+  }
+  values
+}
+
+/// Whether a concept is marked inactive, retired or deprecated.
+pub(super) fn is_inactive(concept: &Value) -> bool {
+  array(&concept["property"]).iter().any(|property| {
+    (property["code"] == "inactive" && property["valueBoolean"] == true)
+      || (property["code"] == "status"
+        && matches!(
+          property["valueCode"].as_str(),
+          Some("retired" | "inactive" | "deprecated")
+        ))
+      || (property["code"] == "deprecated" && !property["valueDateTime"].is_null())
+  })
+}
+
+/// Whether a concept can't be selected, being there only to group others.
+fn is_abstract(concept: &Value) -> bool {
+  array(&concept["property"]).iter().any(|property| {
+    matches!(
+      property["code"].as_str(),
+      Some("notSelectable" | "abstract")
+    ) && property["valueBoolean"] == true
+  })
+}
+
+/// An expansion entry for a concept.
+fn entry(system: &str, version: Option<&str>, concept: &Value) -> Value {
+  let mut entry = json!({"system": system});
+  if let Some(version) = version {
+    entry["version"] = json!(version);
+  }
+  if is_abstract(concept) {
+    entry["abstract"] = json!(true);
+  }
+  if is_inactive(concept) {
+    entry["inactive"] = json!(true);
+  }
+  entry["code"] = concept["code"].clone();
+  if let Some(display) = concept["display"].as_str() {
+    entry["display"] = json!(display);
+  }
+  entry
+}
+
+/// The entries for the concepts that `keep` accepts, nested as in the code
+/// system's hierarchy. The children of concepts that are left out move up a
+/// level, and a concept with several parents only comes under the first.
+fn tree<'v, F>(
+  hierarchy: Hierarchy<'v>,
+  system: &str,
+  version: Option<&str>,
+  codes: Vec<&'v str>,
+  seen: &mut HashSet<&'v str>,
+  keep: &mut F,
+) -> Vec<Value>
+where
+  F: FnMut(&Value) -> bool,
+{
+  let mut entries = vec![];
+  for code in codes {
+    let concept = match hierarchy.value(code) {
+      Some(concept) if seen.insert(code) => concept,
+      _ => continue,
+    };
+    let kept = keep(concept);
+    let children = hierarchy.children(code);
+    let children = tree(hierarchy, system, version, children, seen, keep);
+    match kept {
+      true => {
+        let mut entry = entry(system, version, concept);
+        if !children.is_empty() {
+          entry["contains"] = json!(children);
+        }
+        entries.push(entry);
+      }
+      false => entries.extend(children),
+    }
+  }
+  entries
+}
+
+/// Drops the entries that `keep` rejects, moving their children up a level.
+fn retain<F>(entries: Vec<Value>, keep: &mut F) -> Vec<Value>
+where
+  F: FnMut(&Value) -> bool,
+{
+  let mut kept = vec![];
+  for mut entry in entries {
+    let children = match entry.as_object_mut().and_then(|e| e.remove("contains")) {
+      Some(Value::Array(children)) => children,
+      _ => vec![],
+    };
+    let keep_entry = keep(&entry);
+    let children = retain(children, keep);
+    match keep_entry {
+      true => {
+        if !children.is_empty() {
+          entry["contains"] = json!(children);
+        }
+        kept.push(entry);
+      }
+      false => kept.extend(children),
+    }
+  }
+  kept
+}
+
+/// Every entry of an expansion in order, without their nested entries.
+fn flatten(entries: &[Value]) -> Vec<Value> {
+  let mut flat = vec![];
+  for entry in entries {
+    let mut entry = entry.clone();
+    let children = entry.as_object_mut().and_then(|e| e.remove("contains"));
+    flat.push(entry);
+    if let Some(Value::Array(children)) = children {
+      flat.extend(flatten(&children));
+    }
+  }
+  flat
+}
+
+fn key(entry: &Value) -> (String, String) {
+  let text = |key: &str| entry[key].as_str().unwrap_or_default().to_string();
+  (text("system"), text("code"))
+}
+
+pub(super) fn too_deep(value_set: &Value) -> Issue {
+  Issue::error(
+    OperationOutcome_IssueCode::Processing,
+    &format!(
+      "The value set '{}' imports too many levels of value sets",
+      value_set["url"].as_str().unwrap_or_default()
+    ),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::model::Coding::Coding;
+  use std::path::Path;
+
+  fn codes(contains: &Value) -> Vec<&str> {
+    array(contains)
+      .iter()
+      .map(|entry| entry["code"].as_str().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn test_expand_code_system() {
+    let mut terminology = Terminology::new();
+    for file in &["codesystem-item-type.json", "valueset-item-type.json"] {
+      let path = format!("examples-json/{}", file);
+      terminology.load_file(Path::new(&path)).unwrap();
+    }
+    let url = "http://hl7.org/fhir/ValueSet/item-type";
+
+    // The whole code system keeps its hierarchy.
+    let expanded = terminology.expand(url).unwrap();
+    let expansion = &expanded.value["expansion"];
+    assert_eq!(expansion["total"], 17);
+    assert_eq!(
+      codes(&expansion["contains"]),
+      vec!["group", "display", "question"]
+    );
+    let question = &expansion["contains"][2];
+    assert_eq!(question["abstract"], true);
+    assert_eq!(
+      codes(&question["contains"])[..3],
+      ["boolean", "decimal", "integer"]
+    );
+    assert_eq!(
+      expansion["parameter"],
+      json!([{"name": "used-codesystem", "valueUri": "http://hl7.org/fhir/item-type|4.0.1"}])
+    );
+    assert_eq!(
+      expanded.expansion().unwrap().contains().unwrap()[0].display(),
+      Some("Group")
+    );
+
+    // Filtered and paged expansions are flat.
+    let expanded = Expander::new(&terminology)
+      .filter("DATE")
+      .expand(url)
+      .unwrap();
+    assert_eq!(expanded.value["expansion"]["total"], 2);
+    assert_eq!(
+      codes(&expanded.value["expansion"]["contains"]),
+      vec!["date", "dateTime"]
+    );
+    let expanded = Expander::new(&terminology)
+      .offset(2)
+      .count(3)
+      .expand(url)
+      .unwrap();
+    let expansion = &expanded.value["expansion"];
+    assert_eq!(expansion["total"], 17);
+    assert_eq!(expansion["offset"], 2);
+    assert_eq!(
+      codes(&expansion["contains"]),
+      vec!["question", "boolean", "decimal"]
+    );
+    assert_eq!(
+      expansion["parameter"][0],
+      json!({"name": "offset", "valueInteger": 2})
+    );
+    assert_eq!(
+      expansion["parameter"][1],
+      json!({"name": "count", "valueInteger": 3})
+    );
+  }
+
+  #[test]
+  fn test_filters() {
+    let mut terminology = Terminology::new();
+    let colour = |value: &str| json!([{"code": "colour", "valueCode": value}]);
+    terminology.add(json!({
+      "resourceType": "CodeSystem",
+      "url": "http://example.org/shapes",
+      "content": "complete",
+      "concept": [
+        {"code": "polygon", "display": "Polygon", "concept": [
+          {"code": "quadrilateral", "display": "Quadrilateral", "concept": [
+            {"code": "square", "display": "Square", "property": colour("red")}
+          ]},
+          {"code": "pentagon", "display": "Pentagon", "property": [
+            {"code": "inactive", "valueBoolean": true}
+          ]}
+        ]},
+        {"code": "circle", "display": "Circle", "property": colour("blue")},
+        {"code": "ellipse", "display": "Ellipse"}
+      ]
+    }));
+    let expand = |filters: Value| {
+      let value_set = ValueSet {
+        value: Cow::Owned(json!({
+          "resourceType": "ValueSet",
+          "compose": {"include": [{"system": "http://example.org/shapes", "filter": filters}]}
+        })),
+      };
+      let expanded = Expander::new(&terminology)
+        .count(10)
+        .expand_value_set(&value_set);
+      let contains = &expanded.unwrap().value["expansion"]["contains"];
+      codes(contains)
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>()
+    };
+    let filter = |property: &str, op: &str, value: &str| json!([{"property": property, "op": op, "value": value}]);
+
+    assert_eq!(
+      expand(filter("concept", "is-a", "polygon")),
+      vec!["polygon", "quadrilateral", "square", "pentagon"]
+    );
+    assert_eq!(
+      expand(filter("concept", "descendent-of", "polygon")),
+      vec!["quadrilateral", "square", "pentagon"]
+    );
+    assert_eq!(
+      expand(filter("concept", "is-not-a", "quadrilateral")),
+      vec!["polygon", "pentagon", "circle", "ellipse"]
+    );
+    assert_eq!(
+      expand(filter("concept", "generalizes", "square")),
+      vec!["polygon", "quadrilateral", "square"]
+    );
+    assert_eq!(expand(filter("code", "regex", "[cp].*e")), vec!["circle"]);
+    assert_eq!(
+      expand(filter("code", "in", "circle, ellipse")),
+      vec!["circle", "ellipse"]
+    );
+    assert_eq!(expand(filter("colour", "=", "red")), vec!["square"]);
+    assert_eq!(
+      expand(filter("colour", "exists", "true")),
+      vec!["square", "circle"]
+    );
+    assert_eq!(
+      expand(json!([
+        {"property": "concept", "op": "is-a", "value": "polygon"},
+        {"property": "colour", "op": "exists", "value": "false"}
+      ])),
+      vec!["polygon", "quadrilateral", "pentagon"]
+    );
+
+    // Inactive codes are flagged, and left out when only active codes are
+    // wanted.
+    terminology.add(json!({
+      "resourceType": "ValueSet",
+      "url": "http://example.org/ValueSet/polygons",
+      "compose": {
+        "include": [{
+          "system": "http://example.org/shapes",
+          "filter": [{"property": "concept", "op": "descendent-of", "value": "polygon"}]
+        }],
+        "exclude": [{"system": "http://example.org/shapes", "concept": [{"code": "quadrilateral"}]}]
+      }
+    }));
+    let url = "http://example.org/ValueSet/polygons";
+    let expanded = terminology.expand(url).unwrap();
+    let contains = &expanded.value["expansion"]["contains"];
+    assert_eq!(codes(contains), vec!["square", "pentagon"]);
+    assert_eq!(contains[1]["inactive"], true);
+    let expanded = Expander::new(&terminology)
+      .active_only(true)
+      .expand(url)
+      .unwrap();
+    assert_eq!(
+      codes(&expanded.value["expansion"]["contains"]),
+      vec!["square"]
+    );
+
+    // Membership follows the same rules.
+    let valid = |code: &str| {
+      let coding = Coding {
+        value: Cow::Owned(json!({"system": "http://example.org/shapes", "code": code})),
+      };
+      let result = terminology.validate_code(Some(url), &coding).unwrap();
+      result.value["parameter"][0]["valueBoolean"] == true
+    };
+    assert!(valid("square"));
+    assert!(!valid("quadrilateral"));
+    assert!(!valid("circle"));
+
+    let issues = Expander::new(&terminology)
+      .expand_value_set(&ValueSet {
+        value: Cow::Owned(json!({
+          "resourceType": "ValueSet",
+          "compose": {"include": [{
+            "system": "http://example.org/shapes",
+            "filter": [{"property": "concept", "op": "child-of", "value": "polygon"}]
+          }]}
+        })),
+      })
+      .unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "The filter 'concept child-of polygon' is not supported"
+    );
+  }
+}
@@ -0,0 +1,451 @@
+use super::{array, Terminology};
+use crate::model::CodeSystem::CodeSystem;
+use crate::model::CodeSystem_Concept::CodeSystem_Concept;
+use crate::model::Coding::Coding;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::Parameters::Parameters;
+use crate::outcome::Issue;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+/// The concepts of a code system and the links between them, built when the
+/// code system is added. Concepts are linked by nesting them and by `parent`
+/// (or `subsumedBy`) and `child` properties, so a concept can have several
+/// parents.
+#[derive(Debug, Default)]
+pub(super) struct Index {
+  case_sensitive: bool,
+  codes: HashMap<String, usize>,
+  concepts: Vec<Indexed>,
+}
+
+#[derive(Debug)]
+struct Indexed {
+  /// Where the concept is in the nested `concept` arrays.
+  path: Vec<usize>,
+  parents: Vec<usize>,
+  children: Vec<usize>,
+}
+
+impl Index {
+  pub(super) fn new(code_system: &Value) -> Index {
+    let mut index = Index {
+      case_sensitive: code_system["caseSensitive"].as_bool() != Some(false),
+      ..Index::default()
+    };
+    index.add_concepts(array(&code_system["concept"]), &mut vec![], None);
+
+    let mut links = vec![];
+    for (position, concept) in index.concepts.iter().enumerate() {
+      let value = locate(code_system, &concept.path);
+      for property in array(&value["property"]) {
+        let related = property["valueCode"].as_str();
+        let related = related.and_then(|code| index.position(code));
+        match (property["code"].as_str(), related) {
+          (Some("parent" | "subsumedBy"), Some(parent)) => links.push((parent, position)),
+          (Some("child"), Some(child)) => links.push((position, child)),
+          _ => {}
+        }
+      }
+    }
+    for (parent, child) in links {
+      index.link(parent, child);
+    }
+    index
+  }
+
+  fn add_concepts(&mut self, concepts: &[Value], path: &mut Vec<usize>, parent: Option<usize>) {
+    for (number, concept) in concepts.iter().enumerate() {
+      path.push(number);
+      let key = self.key(concept["code"].as_str().unwrap_or_default());
+      let position = *self.codes.entry(key).or_insert(self.concepts.len());
+      if position == self.concepts.len() {
+        self.concepts.push(Indexed {
+          path: path.clone(),
+          parents: vec![],
+          children: vec![],
+        });
+      }
+      if let Some(parent) = parent {
+        self.link(parent, position);
+      }
+      self.add_concepts(array(&concept["concept"]), path, Some(position));
+      path.pop();
+    }
+  }
+
+  fn link(&mut self, parent: usize, child: usize) {
+    if parent != child && !self.concepts[child].parents.contains(&parent) {
+      self.concepts[child].parents.push(parent);
+      self.concepts[parent].children.push(child);
+    }
+  }
+
+  fn key(&self, code: &str) -> String {
+    match self.case_sensitive {
+      true => code.to_string(),
+      false => code.to_lowercase(),
+    }
+  }
+
+  fn position(&self, code: &str) -> Option<usize> {
+    self.codes.get(&self.key(code)).copied()
+  }
+}
+
+fn locate<'v>(code_system: &'v Value, path: &[usize]) -> &'v Value {
+  path
+    .iter()
+    .fold(code_system, |concept, number| &concept["concept"][*number])
+}
+
+/// How two concepts of a code system relate, as `$subsumes` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsumption {
+  Equivalent,
+  Subsumes,
+  SubsumedBy,
+  NotSubsumed,
+}
+
+impl fmt::Display for Subsumption {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(match self {
+      Subsumption::Equivalent => "equivalent",
+      Subsumption::Subsumes => "subsumes",
+      Subsumption::SubsumedBy => "subsumed-by",
+      Subsumption::NotSubsumed => "not-subsumed",
+    })
+  }
+}
+
+/// A code system seen through its index, for walking up and down the concept
+/// hierarchy. Codes are matched ignoring case in code systems that aren't
+/// case sensitive.
+#[derive(Clone, Copy)]
+pub struct Hierarchy<'t> {
+  code_system: &'t Value,
+  index: &'t Index,
+}
+
+impl<'t> Hierarchy<'t> {
+  pub fn code_system(&self) -> CodeSystem<'t> {
+    CodeSystem {
+      value: Cow::Borrowed(self.code_system),
+    }
+  }
+
+  pub fn concept(&self, code: &str) -> Option<CodeSystem_Concept<'t>> {
+    self.value(code).map(|value| CodeSystem_Concept {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  /// Every code of the code system, parents before their children.
+  pub fn codes(&self) -> Vec<&'t str> {
+    self
+      .index
+      .concepts
+      .iter()
+      .map(|c| self.code_at(c))
+      .collect()
+  }
+
+  /// The codes at the top of the hierarchy.
+  pub fn roots(&self) -> Vec<&'t str> {
+    let roots = self.index.concepts.iter().filter(|c| c.parents.is_empty());
+    roots.map(|c| self.code_at(c)).collect()
+  }
+
+  pub fn parents(&self, code: &str) -> Vec<&'t str> {
+    self.related(code, |concept| &concept.parents)
+  }
+
+  pub fn children(&self, code: &str) -> Vec<&'t str> {
+    self.related(code, |concept| &concept.children)
+  }
+
+  /// Every code above a code, nearest first.
+  pub fn ancestors(&self, code: &str) -> Vec<&'t str> {
+    self.reachable(code, |concept| &concept.parents)
+  }
+
+  /// Every code below a code, nearest first.
+  pub fn descendants(&self, code: &str) -> Vec<&'t str> {
+    self.reachable(code, |concept| &concept.children)
+  }
+
+  /// Whether `code` is `ancestor` or comes below it.
+  pub fn is_a(&self, code: &str, ancestor: &str) -> bool {
+    match (self.index.position(code), self.index.position(ancestor)) {
+      (Some(code), Some(ancestor)) => {
+        code == ancestor || self.walk(code, |c| &c.parents).contains(&ancestor)
+      }
+      _ => false,
+    }
+  }
+
+  /// How concept `a` relates to concept `b`, or `None` when either isn't in
+  /// the code system.
+  pub fn subsumes(&self, a: &str, b: &str) -> Option<Subsumption> {
+    let (position_a, position_b) = (self.index.position(a)?, self.index.position(b)?);
+    Some(match () {
+      _ if position_a == position_b => Subsumption::Equivalent,
+      _ if self.is_a(b, a) => Subsumption::Subsumes,
+      _ if self.is_a(a, b) => Subsumption::SubsumedBy,
+      _ => Subsumption::NotSubsumed,
+    })
+  }
+
+  pub(super) fn value(&self, code: &str) -> Option<&'t Value> {
+    let position = self.index.position(code)?;
+    Some(locate(
+      self.code_system,
+      &self.index.concepts[position].path,
+    ))
+  }
+
+  pub(super) fn contains(&self, code: &str) -> bool {
+    self.index.position(code).is_some()
+  }
+
+  fn code_at(&self, concept: &Indexed) -> &'t str {
+    let value = locate(self.code_system, &concept.path);
+    value["code"].as_str().unwrap_or_default()
+  }
+
+  fn related<F>(&self, code: &str, links: F) -> Vec<&'t str>
+  where
+    F: Fn(&Indexed) -> &Vec<usize>,
+  {
+    let concept = self.index.position(code).map(|p| &self.index.concepts[p]);
+    let related = concept.into_iter().flat_map(links);
+    related
+      .map(|p| self.code_at(&self.index.concepts[*p]))
+      .collect()
+  }
+
+  fn reachable<F>(&self, code: &str, links: F) -> Vec<&'t str>
+  where
+    F: Fn(&Indexed) -> &Vec<usize>,
+  {
+    let reached = self.index.position(code).map(|p| self.walk(p, links));
+    let reached = reached.into_iter().flatten();
+    reached
+      .map(|p| self.code_at(&self.index.concepts[p]))
+      .collect()
+  }
+
+  /// The concepts reachable from one by following links, breadth first. A
+  /// concept reachable along several paths is only listed once.
+  fn walk<F>(&self, start: usize, links: F) -> Vec<usize>
+  where
+    F: Fn(&Indexed) -> &Vec<usize>,
+  {
+    let mut reached = vec![];
+    let mut next = 0;
+    let mut current = start;
+    loop {
+      for linked in links(&self.index.concepts[current]) {
+        if *linked != start && !reached.contains(linked) {
+          reached.push(*linked);
+        }
+      }
+      match reached.get(next) {
+        Some(position) => current = *position,
+        None => return reached,
+      }
+      next += 1;
+    }
+  }
+}
+
+impl Terminology {
+  /// The concept hierarchy of the code system with canonical URL `url`.
+  pub fn hierarchy(&self, url: &str) -> Option<Hierarchy<'_>> {
+    let url = url.split('|').next().unwrap_or(url);
+    Some(Hierarchy {
+      code_system: self.code_systems.get(url)?,
+      index: self.indexes.get(url)?,
+    })
+  }
+
+  /// `$subsumes`: how the concept of Coding `a` relates to that of Coding
+  /// `b`, given as the `outcome` output.
+  pub fn subsumes(&self, a: &Coding, b: &Coding) -> Result<Parameters<'static>, Vec<Issue>> {
+    let system = match (a.system(), b.system()) {
+      (Some(system_a), Some(system_b)) if system_a == system_b => system_a,
+      (Some(_), Some(_)) => {
+        return Err(vec![Issue::error(
+          OperationOutcome_IssueCode::NotSupported,
+          "$subsumes needs both codings to be from the same code system",
+        )])
+      }
+      _ => {
+        return Err(vec![Issue::error(
+          OperationOutcome_IssueCode::Required,
+          "$subsumes needs a system for both codings",
+        )])
+      }
+    };
+    let hierarchy = self.complete_system(system).map_err(|issue| vec![issue])?;
+    let mut issues = vec![];
+    for code in [a.code(), b.code()] {
+      let code = code.unwrap_or_default();
+      if !hierarchy.contains(code) {
+        issues.push(Issue::error(
+          OperationOutcome_IssueCode::CodeInvalid,
+          &format!("Unknown code '{}' in the CodeSystem '{}'", code, system),
+        ));
+      }
+    }
+    match hierarchy.subsumes(a.code().unwrap_or_default(), b.code().unwrap_or_default()) {
+      Some(outcome) if issues.is_empty() => Ok(super::parameters_resource(vec![super::parameter(
+        "outcome",
+        "valueCode",
+        outcome.to_string(),
+      )])),
+      _ => Err(issues),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::path::Path;
+
+  fn terminology() -> Terminology {
+    let mut terminology = Terminology::new();
+    terminology
+      .load_file(Path::new("examples-json/codesystem-item-type.json"))
+      .unwrap();
+    // Shapes, linked by properties rather than by nesting, with a square
+    // being both a rectangle and a rhombus.
+    terminology.add(json!({
+      "resourceType": "CodeSystem",
+      "url": "http://example.org/CodeSystem/shapes",
+      "caseSensitive": false,
+      "content": "complete",
+      "concept": [
+        {"code": "polygon", "property": [{"code": "child", "valueCode": "quadrilateral"}]},
+        {"code": "quadrilateral", "concept": [{"code": "rectangle"}]},
+        {"code": "rhombus", "property": [{"code": "parent", "valueCode": "quadrilateral"}]},
+        {"code": "square", "property": [
+          {"code": "parent", "valueCode": "Rectangle"},
+          {"code": "subsumedBy", "valueCode": "rhombus"}
+        ]},
+        {"code": "circle"}
+      ]
+    }));
+    terminology
+  }
+
+  #[test]
+  fn test_hierarchy() {
+    let terminology = terminology();
+    let items = terminology
+      .hierarchy("http://hl7.org/fhir/item-type|4.0.1")
+      .unwrap();
+    assert_eq!(items.roots(), vec!["group", "display", "question"]);
+    assert_eq!(items.parents("boolean"), vec!["question"]);
+    assert_eq!(items.children("question").len(), 14);
+    assert!(items.is_a("boolean", "question"));
+    assert!(!items.is_a("question", "boolean"));
+    // Item types are case sensitive, unlike shapes.
+    assert!(!items.is_a("BOOLEAN", "question"));
+
+    let shapes = terminology
+      .hierarchy("http://example.org/CodeSystem/shapes")
+      .unwrap();
+    assert_eq!(shapes.roots(), vec!["polygon", "circle"]);
+    assert_eq!(shapes.parents("square"), vec!["rectangle", "rhombus"]);
+    assert_eq!(
+      shapes.ancestors("Square"),
+      vec!["rectangle", "rhombus", "quadrilateral", "polygon"]
+    );
+    assert_eq!(
+      shapes.descendants("polygon"),
+      vec!["quadrilateral", "rectangle", "rhombus", "square"]
+    );
+    assert_eq!(shapes.concept("square").unwrap().code(), Some("square"));
+    assert_eq!(
+      shapes.subsumes("polygon", "square"),
+      Some(Subsumption::Subsumes)
+    );
+    assert_eq!(
+      shapes.subsumes("square", "RHOMBUS"),
+      Some(Subsumption::SubsumedBy)
+    );
+    assert_eq!(
+      shapes.subsumes("rhombus", "rectangle"),
+      Some(Subsumption::NotSubsumed)
+    );
+    assert_eq!(
+      shapes.subsumes("circle", "Circle"),
+      Some(Subsumption::Equivalent)
+    );
+    assert_eq!(shapes.subsumes("circle", "ellipse"), None);
+
+    // Value set filters follow the same hierarchy.
+    let mut terminology = terminology;
+    terminology.add(json!({
+      "resourceType": "ValueSet",
+      "url": "http://example.org/ValueSet/quadrilaterals",
+      "compose": {"include": [{
+        "system": "http://example.org/CodeSystem/shapes",
+        "filter": [{"property": "concept", "op": "descendent-of", "value": "quadrilateral"}]
+      }]}
+    }));
+    let expansion = terminology
+      .expand("http://example.org/ValueSet/quadrilaterals")
+      .unwrap();
+    let contains = &expansion.value["expansion"]["contains"];
+    assert_eq!(contains[0]["code"], "rectangle");
+    assert_eq!(contains[0]["contains"][0]["code"], "square");
+    assert_eq!(contains[1]["code"], "rhombus");
+    assert_eq!(expansion.value["expansion"]["total"], 3);
+  }
+
+  #[test]
+  fn test_subsumes() {
+    let terminology = terminology();
+    let coding = |system: &str, code: &str| Coding {
+      value: Cow::Owned(json!({"system": system, "code": code})),
+    };
+    let shape = |code: &str| coding("http://example.org/CodeSystem/shapes", code);
+    let outcome = |a: &Coding, b: &Coding| {
+      let parameters = terminology.subsumes(a, b).unwrap();
+      parameters.value["parameter"][0]["valueCode"].clone()
+    };
+    assert_eq!(
+      outcome(&shape("quadrilateral"), &shape("square")),
+      "subsumes"
+    );
+    assert_eq!(outcome(&shape("square"), &shape("polygon")), "subsumed-by");
+    assert_eq!(outcome(&shape("circle"), &shape("square")), "not-subsumed");
+    assert_eq!(outcome(&shape("square"), &shape("square")), "equivalent");
+
+    let errors = |a: &Coding, b: &Coding| {
+      let issues = terminology.subsumes(a, b).unwrap_err();
+      issues
+        .into_iter()
+        .map(|i| i.diagnostics)
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      errors(&shape("square"), &shape("hexagon")),
+      vec!["Unknown code 'hexagon' in the CodeSystem 'http://example.org/CodeSystem/shapes'"]
+    );
+    assert_eq!(
+      errors(
+        &shape("square"),
+        &coding("http://hl7.org/fhir/item-type", "group")
+      ),
+      vec!["$subsumes needs both codings to be from the same code system"]
+    );
+  }
+}
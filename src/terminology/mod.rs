@@ -0,0 +1,773 @@
+//! An in-memory terminology service over `CodeSystem`, `ValueSet` and
+//! `ConceptMap` resources, answering the `$lookup`, `$validate-code`,
+//! `$expand`, `$translate` and `$subsumes` operations
+//! (http://hl7.org/fhir/terminology-service.html) with resources shaped like
+//! the operation outputs.
+
+mod expand;
+mod hierarchy;
+mod translate;
+
+pub use self::expand::Expander;
+pub use self::hierarchy::{Hierarchy, Subsumption};
+pub use self::translate::Translator;
+
+use self::expand::{is_inactive, matches_filters, too_deep};
+use self::hierarchy::Index;
+use crate::model::CodeSystem::CodeSystem;
+use crate::model::CodeableConcept::CodeableConcept;
+use crate::model::Coding::Coding;
+use crate::model::ConceptMap::ConceptMap;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::Parameters::Parameters;
+use crate::model::ValueSet::ValueSet;
+use crate::outcome::Issue;
+use crate::util::array;
+use serde_json::json;
+use serde_json::value::{Map, Value};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How deeply value sets may import other value sets.
+const MAX_DEPTH: usize = 16;
+
+/// The code systems, value sets and concept maps known to the service, indexed
+/// by canonical URL.
+#[derive(Debug, Default)]
+pub struct Terminology {
+  code_systems: HashMap<String, Value>,
+  indexes: HashMap<String, Index>,
+  value_sets: HashMap<String, Value>,
+  concept_maps: HashMap<String, Value>,
+}
+
+impl Terminology {
+  pub fn new() -> Terminology {
+    Terminology::default()
+  }
+
+  /// Loads every CodeSystem, ValueSet and ConceptMap in a directory of JSON
+  /// files, including those collected in Bundles.
+  pub fn load_dir(dir: &str) -> io::Result<Terminology> {
+    let mut terminology = Terminology::new();
+    for entry in fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.extension().is_some_and(|e| e == "json") {
+        terminology.load_file(&path)?;
+      }
+    }
+    Ok(terminology)
+  }
+
+  /// Loads a JSON file holding a CodeSystem, ValueSet, ConceptMap or a Bundle
+  /// of them. Files with other content are ignored.
+  pub fn load_file(&mut self, path: &Path) -> io::Result<()> {
+    let contents = fs::read_to_string(path)?;
+    if let Ok(value) = serde_json::from_str::<Value>(&contents) {
+      self.add(value);
+    }
+    Ok(())
+  }
+
+  /// Adds a CodeSystem, ValueSet or ConceptMap, or every one of them in a
+  /// Bundle.
+  pub fn add(&mut self, resource: Value) {
+    let resources = match resource["resourceType"].as_str() {
+      Some("Bundle") => {
+        if let Value::Object(mut bundle) = resource {
+          if let Some(Value::Array(entries)) = bundle.remove("entry") {
+            for mut entry in entries {
+              if let Some(resource) = entry.get_mut("resource") {
+                self.add(resource.take());
+              }
+            }
+          }
+        }
+        return;
+      }
+      Some("CodeSystem") => {
+        if let Some(url) = resource["url"].as_str() {
+          self.indexes.insert(url.to_string(), Index::new(&resource));
+        }
+        &mut self.code_systems
+      }
+      Some("ValueSet") => &mut self.value_sets,
+      Some("ConceptMap") => &mut self.concept_maps,
+      _ => return,
+    };
+    if let Some(url) = resource["url"].as_str() {
+      resources.insert(url.to_string(), resource);
+    }
+  }
+
+  /// Finds a CodeSystem by canonical URL, ignoring any `|version`.
+  pub fn code_system(&self, url: &str) -> Option<CodeSystem<'_>> {
+    self.system(url).map(|value| CodeSystem {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  /// Finds a ValueSet by canonical URL, ignoring any `|version`.
+  pub fn value_set(&self, url: &str) -> Option<ValueSet<'_>> {
+    self.set(url).map(|value| ValueSet {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  /// Finds a ConceptMap by canonical URL, ignoring any `|version`.
+  pub fn concept_map(&self, url: &str) -> Option<ConceptMap<'_>> {
+    self.map(url).map(|value| ConceptMap {
+      value: Cow::Borrowed(value),
+    })
+  }
+
+  pub(crate) fn system(&self, url: &str) -> Option<&Value> {
+    self.code_systems.get(url.split('|').next().unwrap_or(url))
+  }
+
+  pub(crate) fn set(&self, url: &str) -> Option<&Value> {
+    self.value_sets.get(url.split('|').next().unwrap_or(url))
+  }
+
+  pub(crate) fn map(&self, url: &str) -> Option<&Value> {
+    self.concept_maps.get(url.split('|').next().unwrap_or(url))
+  }
+
+  /// `$lookup`: the details of a code in a code system. `properties` picks the
+  /// properties to return, by code; when it is empty, all the properties of
+  /// the concept come back. `parent` and `child` follow the concept hierarchy.
+  pub fn lookup(
+    &self,
+    coding: &Coding,
+    properties: &[&str],
+  ) -> Result<Parameters<'static>, Vec<Issue>> {
+    let (system, code) = match (coding.system(), coding.code()) {
+      (Some(system), Some(code)) => (system, code),
+      _ => {
+        return Err(vec![Issue::error(
+          OperationOutcome_IssueCode::Required,
+          "$lookup needs both a system and a code",
+        )])
+      }
+    };
+    let code_system = self.known_system(system).map_err(|issue| vec![issue])?;
+    let hierarchy = self.hierarchy(system);
+    let concept = match hierarchy.and_then(|h| h.value(code)) {
+      Some(concept) => concept,
+      None => {
+        return Err(vec![Issue::error(
+          OperationOutcome_IssueCode::CodeInvalid,
+          &format!("Unknown code '{}' in the CodeSystem '{}'", code, system),
+        )])
+      }
+    };
+
+    let mut parameters = vec![];
+    let name = code_system["title"]
+      .as_str()
+      .or(code_system["name"].as_str());
+    parameters.push(parameter("name", "valueString", name.unwrap_or(system)));
+    if let Some(version) = code_system["version"].as_str() {
+      parameters.push(parameter("version", "valueString", version));
+    }
+    let display = concept["display"].as_str().unwrap_or(code);
+    parameters.push(parameter("display", "valueString", display));
+    for designation in array(&concept["designation"]) {
+      let mut parts = vec![];
+      if let Some(language) = designation["language"].as_str() {
+        parts.push(parameter("language", "valueCode", language));
+      }
+      if !designation["use"].is_null() {
+        parts.push(parameter("use", "valueCoding", designation["use"].clone()));
+      }
+      parts.push(parameter(
+        "value",
+        "valueString",
+        designation["value"].clone(),
+      ));
+      parameters.push(json!({"name": "designation", "part": parts}));
+    }
+
+    let wanted = |property: &str| properties.is_empty() || properties.contains(&property);
+    let description = |property: &str| {
+      array(&code_system["property"])
+        .iter()
+        .find(|p| p["code"] == property)
+        .and_then(|p| p["description"].as_str())
+    };
+    let mut add_property = |property: &str, key: &str, value: &Value| {
+      let mut parts = vec![
+        parameter("code", "valueCode", property),
+        parameter("value", key, value.clone()),
+      ];
+      if let Some(description) = description(property) {
+        parts.push(parameter("description", "valueString", description));
+      }
+      parameters.push(json!({"name": "property", "part": parts}));
+    };
+    for property in array(&concept["property"]) {
+      let code = property["code"].as_str().unwrap_or_default();
+      let value = property
+        .as_object()
+        .into_iter()
+        .flatten()
+        .find(|(key, _)| key.starts_with("value"));
+      if let (true, Some((key, value))) = (wanted(code), value) {
+        add_property(code, key, value);
+      }
+    }
+    for (property, related) in [
+      ("parent", hierarchy.map(|h| h.parents(code))),
+      ("child", hierarchy.map(|h| h.children(code))),
+    ] {
+      if properties.contains(&property) {
+        for related in related.unwrap_or_default() {
+          add_property(property, "valueCode", &json!(related));
+        }
+      }
+    }
+    Ok(parameters_resource(parameters))
+  }
+
+  /// `$validate-code` for a Coding: whether the code is in the value set
+  /// with canonical URL `value_set`, or, when there is no value set, whether
+  /// it is defined in its code system. A display that the code system
+  /// doesn't know for the code makes the coding invalid.
+  ///
+  /// An invalid code gives a `result` of false and a `message`; an `Err`
+  /// means the question can't be answered, e.g. because the value set or
+  /// code system isn't known.
+  pub fn validate_code(
+    &self,
+    value_set: Option<&str>,
+    coding: &Coding,
+  ) -> Result<Parameters<'static>, Vec<Issue>> {
+    let (display, message) = self
+      .check_coding(value_set, coding)
+      .map_err(|issue| vec![issue])?;
+    Ok(validation_result(display.as_deref(), message.as_deref()))
+  }
+
+  /// `$validate-code` for a CodeableConcept, which is valid when any of its
+  /// codings is.
+  pub fn validate_codeable_concept(
+    &self,
+    value_set: Option<&str>,
+    concept: &CodeableConcept,
+  ) -> Result<Parameters<'static>, Vec<Issue>> {
+    let mut messages = vec![];
+    let mut issues = vec![];
+    for coding in concept.coding().unwrap_or_default() {
+      match self.check_coding(value_set, &coding) {
+        Ok((display, None)) => return Ok(validation_result(display.as_deref(), None)),
+        Ok((_, Some(message))) => messages.push(message),
+        Err(issue) => issues.push(issue),
+      }
+    }
+    if messages.is_empty() && !issues.is_empty() {
+      return Err(issues);
+    }
+    if messages.is_empty() {
+      messages.push("The CodeableConcept has no codings".to_string());
+    }
+    Ok(validation_result(None, Some(&messages.join("; "))))
+  }
+
+  /// The display of a valid coding, or the reason it is invalid.
+  fn check_coding(
+    &self,
+    value_set: Option<&str>,
+    coding: &Coding,
+  ) -> Result<(Option<String>, Option<String>), Issue> {
+    let invalid = |message: String| Ok((None, Some(message)));
+    let (system, code) = match (coding.system(), coding.code(), value_set) {
+      (Some(system), Some(code), _) => (system, code),
+      // A bare code, like the value of a `code` element, is looked for in
+      // each code system the value set draws on.
+      (None, Some(code), Some(url)) => {
+        let value = self.known_set(url)?;
+        let mut found = None;
+        for system in self.systems(value, 0) {
+          if self.contains(value, system, code, 0)? {
+            found = Some(system);
+            break;
+          }
+        }
+        match found {
+          Some(system) => (system, code),
+          None => {
+            return invalid(format!(
+              "The code '{}' is not in the value set '{}'",
+              code, url
+            ))
+          }
+        }
+      }
+      (None, Some(code), None) => return invalid(format!("The code '{}' has no system", code)),
+      _ => return invalid("The coding has no code".to_string()),
+    };
+    let code_system = match value_set {
+      Some(_) => self.system(system),
+      None => Some(self.known_system(system)?),
+    };
+    let hierarchy = code_system
+      .filter(|cs| is_complete(cs))
+      .and_then(|_| self.hierarchy(system));
+    let concept = match hierarchy {
+      Some(hierarchy) => match hierarchy.value(code) {
+        Some(concept) => Some(concept),
+        None => {
+          return invalid(format!(
+            "Unknown code '{}' in the CodeSystem '{}'",
+            code, system
+          ))
+        }
+      },
+      None => None,
+    };
+
+    let mut listed = None;
+    if let Some(url) = value_set {
+      let value = self.known_set(url)?;
+      if !self.contains(value, system, code, 0)? {
+        return invalid(format!(
+          "The code '{}' from system '{}' is not in the value set '{}'",
+          code, system, url
+        ));
+      }
+      listed = listed_concept(value, system, code);
+    }
+
+    // What the code system says about the concept wins over what the value
+    // set lists for it.
+    let concept = concept.or(listed);
+    let displays = concept
+      .into_iter()
+      .flat_map(|c| {
+        let designations = array(&c["designation"]).iter().map(|d| &d["value"]);
+        std::iter::once(&c["display"]).chain(designations)
+      })
+      .filter_map(Value::as_str)
+      .collect::<Vec<_>>();
+    let display = displays.first().map(|d| d.to_string());
+    match coding.display() {
+      Some(given) if !displays.is_empty() && !displays.contains(&given) => Ok((
+        display.clone(),
+        Some(format!(
+          "The display '{}' is not a valid display for the code '{}' (expected '{}')",
+          given,
+          code,
+          display.unwrap_or_default()
+        )),
+      )),
+      _ => Ok((display, None)),
+    }
+  }
+
+  /// The code systems a value set takes codes from, including through the
+  /// value sets it imports.
+  fn systems<'v>(&'v self, value_set: &'v Value, depth: usize) -> Vec<&'v str> {
+    let mut systems = vec![];
+    let expansion = flatten_contains(array(&value_set["expansion"]["contains"]));
+    let includes = array(&value_set["compose"]["include"]);
+    let listed = includes.iter().chain(expansion).map(|i| &i["system"]);
+    for system in listed.filter_map(Value::as_str) {
+      if !systems.contains(&system) {
+        systems.push(system);
+      }
+    }
+    if depth < MAX_DEPTH {
+      let imports = includes.iter().flat_map(|i| array(&i["valueSet"]));
+      for value_set in imports.filter_map(|url| self.set(url.as_str()?)) {
+        for system in self.systems(value_set, depth + 1) {
+          if !systems.contains(&system) {
+            systems.push(system);
+          }
+        }
+      }
+    }
+    systems
+  }
+
+  /// Whether a value set holds a code, going by its compose or, for value sets
+  /// that only come expanded, by its expansion.
+  fn contains(
+    &self,
+    value_set: &Value,
+    system: &str,
+    code: &str,
+    depth: usize,
+  ) -> Result<bool, Issue> {
+    let compose = &value_set["compose"];
+    if compose.is_null() {
+      return Ok(expansion_contains(
+        array(&value_set["expansion"]["contains"]),
+        system,
+        code,
+      ));
+    }
+    if depth > MAX_DEPTH {
+      return Err(too_deep(value_set));
+    }
+    let inactive = compose["inactive"].as_bool() != Some(false);
+    for include in array(&compose["include"]) {
+      if self.includes(include, system, code, inactive, depth)? {
+        for exclude in array(&compose["exclude"]) {
+          if self.includes(exclude, system, code, true, depth)? {
+            return Ok(false);
+          }
+        }
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// Whether an `include` or `exclude` of a value set's compose selects a
+  /// code. Inactive codes are only selected when `inactive` is true.
+  fn includes(
+    &self,
+    include: &Value,
+    system: &str,
+    code: &str,
+    inactive: bool,
+    depth: usize,
+  ) -> Result<bool, Issue> {
+    if include["system"].as_str().is_some_and(|s| s != system) {
+      return Ok(false);
+    }
+    for url in array(&include["valueSet"]).iter().filter_map(Value::as_str) {
+      if !self.contains(self.known_set(url)?, system, code, depth + 1)? {
+        return Ok(false);
+      }
+    }
+    if include["system"].is_null() {
+      return Ok(true);
+    }
+    let concepts = array(&include["concept"]);
+    if !concepts.is_empty() {
+      let found = self.hierarchy(system).and_then(|h| h.value(code));
+      return Ok(
+        concepts
+          .iter()
+          .any(|c| same_code(self.system(system), &c["code"], code))
+          && (inactive || !found.is_some_and(is_inactive)),
+      );
+    }
+    let hierarchy = self.complete_system(system)?;
+    match hierarchy.value(code) {
+      Some(concept) if inactive || !is_inactive(concept) => {
+        matches_filters(hierarchy, code, array(&include["filter"]))
+      }
+      _ => Ok(false),
+    }
+  }
+
+  /// A code system that holds all of its concepts, so that the codes of value
+  /// sets taking all or a filtered part of it can be worked out.
+  fn complete_system(&self, url: &str) -> Result<Hierarchy<'_>, Issue> {
+    let code_system = self.known_system(url)?;
+    let hierarchy = self.hierarchy(url).filter(|_| is_complete(code_system));
+    hierarchy.ok_or_else(|| {
+      Issue::error(
+        OperationOutcome_IssueCode::NotSupported,
+        &format!(
+          "The CodeSystem '{}' doesn't hold all of its concepts, so its codes can't be checked",
+          url
+        ),
+      )
+    })
+  }
+
+  fn known_system(&self, url: &str) -> Result<&Value, Issue> {
+    self.system(url).ok_or_else(|| {
+      Issue::error(
+        OperationOutcome_IssueCode::NotFound,
+        &format!("The CodeSystem '{}' is not known", url),
+      )
+    })
+  }
+
+  fn known_set(&self, url: &str) -> Result<&Value, Issue> {
+    self.set(url).ok_or_else(|| {
+      Issue::error(
+        OperationOutcome_IssueCode::NotFound,
+        &format!("The ValueSet '{}' is not known", url),
+      )
+    })
+  }
+}
+
+/// Compares codes, ignoring case in code systems that aren't case sensitive.
+fn same_code(code_system: Option<&Value>, listed: &Value, code: &str) -> bool {
+  let listed = listed.as_str().unwrap_or_default();
+  match code_system.and_then(|cs| cs["caseSensitive"].as_bool()) {
+    Some(false) => listed.eq_ignore_ascii_case(code),
+    _ => listed == code,
+  }
+}
+
+/// Whether a code system holds all of its concepts, rather than none of them
+/// (like LOINC or SNOMED CT outside a terminology server) or a sample.
+fn is_complete(code_system: &Value) -> bool {
+  !matches!(
+    code_system["content"].as_str(),
+    Some("not-present" | "example" | "fragment" | "supplement")
+  )
+}
+
+/// The concept a value set lists for a code in an include.
+fn listed_concept<'v>(value_set: &'v Value, system: &str, code: &str) -> Option<&'v Value> {
+  array(&value_set["compose"]["include"])
+    .iter()
+    .filter(|include| include["system"] == system)
+    .flat_map(|include| array(&include["concept"]))
+    .find(|concept| concept["code"] == code)
+}
+
+fn flatten_contains(contains: &[Value]) -> Vec<&Value> {
+  contains
+    .iter()
+    .flat_map(|entry| std::iter::once(entry).chain(flatten_contains(array(&entry["contains"]))))
+    .collect()
+}
+
+fn expansion_contains(contains: &[Value], system: &str, code: &str) -> bool {
+  contains.iter().any(|entry| {
+    (entry["system"] == system && entry["code"] == code)
+      || expansion_contains(array(&entry["contains"]), system, code)
+  })
+}
+
+/// A `Parameters.parameter` with a value of the type `key` names.
+pub(crate) fn parameter<V: Into<Value>>(name: &str, key: &str, value: V) -> Value {
+  let mut parameter = Map::new();
+  parameter.insert("name".to_string(), json!(name));
+  parameter.insert(key.to_string(), value.into());
+  Value::Object(parameter)
+}
+
+pub(crate) fn parameters_resource(parameters: Vec<Value>) -> Parameters<'static> {
+  Parameters {
+    value: Cow::Owned(json!({"resourceType": "Parameters", "parameter": parameters})),
+  }
+}
+
+fn validation_result(display: Option<&str>, message: Option<&str>) -> Parameters<'static> {
+  let mut parameters = vec![parameter("result", "valueBoolean", message.is_none())];
+  if let Some(message) = message {
+    parameters.push(parameter("message", "valueString", message));
+  }
+  if let Some(display) = display {
+    parameters.push(parameter("display", "valueString", display));
+  }
+  parameters_resource(parameters)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn terminology() -> Terminology {
+    let mut terminology = Terminology::new();
+    for file in &[
+      "codesystem-example.json",
+      "codesystem-item-type.json",
+      "codesystem-administrative-gender.json",
+      "valueset-administrative-gender.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      terminology.load_file(Path::new(&path)).unwrap();
+    }
+    terminology
+  }
+
+  fn coding(value: Value) -> Coding<'static> {
+    Coding {
+      value: Cow::Owned(value),
+    }
+  }
+
+  fn outputs(parameters: &Parameters) -> Vec<Value> {
+    parameters.value["parameter"].as_array().unwrap().clone()
+  }
+
+  #[test]
+  fn test_lookup() {
+    let terminology = terminology();
+    let cholesterol =
+      coding(json!({"system": "http://hl7.org/fhir/CodeSystem/example", "code": "chol-mmol"}));
+    assert_eq!(
+      outputs(&terminology.lookup(&cholesterol, &[]).unwrap()),
+      vec![
+        json!({"name": "name", "valueString": "ACME Codes for Cholesterol in Serum/Plasma"}),
+        json!({"name": "version", "valueString": "20160128"}),
+        json!({"name": "display", "valueString": "SChol (mmol/L)"}),
+        json!({"name": "designation", "part": [
+          {"name": "use", "valueCoding": {
+            "system": "http://acme.com/config/fhir/codesystems/internal",
+            "code": "internal-label"
+          }},
+          {"name": "value", "valueString": "From ACME POC Testing"}
+        ]}),
+      ]
+    );
+
+    let question = coding(json!({"system": "http://hl7.org/fhir/item-type", "code": "question"}));
+    let lookup = terminology
+      .lookup(&question, &["notSelectable", "child"])
+      .unwrap();
+    let properties = outputs(&lookup)
+      .into_iter()
+      .filter(|p| p["name"] == "property")
+      .collect::<Vec<_>>();
+    assert_eq!(properties.len(), 15);
+    assert_eq!(properties[0]["part"][0]["valueCode"], "notSelectable");
+    assert_eq!(properties[0]["part"][1]["valueBoolean"], true);
+    assert_eq!(
+      properties[0]["part"][2]["valueString"],
+      "Indicates that the code is abstract - only intended to be used as a selector for other concepts"
+    );
+    assert_eq!(properties[1]["part"][1]["valueCode"], "boolean");
+
+    let boolean = coding(json!({"system": "http://hl7.org/fhir/item-type", "code": "boolean"}));
+    let lookup = terminology.lookup(&boolean, &["parent"]).unwrap();
+    assert_eq!(
+      outputs(&lookup)[2..],
+      [
+        json!({"name": "display", "valueString": "Boolean"}),
+        json!({"name": "property", "part": [
+          {"name": "code", "valueCode": "parent"},
+          {"name": "value", "valueCode": "question"}
+        ]})
+      ]
+    );
+
+    let unknown = coding(json!({"system": "http://hl7.org/fhir/item-type", "code": "essay"}));
+    let issues = terminology.lookup(&unknown, &[]).unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "Unknown code 'essay' in the CodeSystem 'http://hl7.org/fhir/item-type'"
+    );
+  }
+
+  #[test]
+  fn test_validate_code() {
+    let mut terminology = terminology();
+    let gender = "http://hl7.org/fhir/ValueSet/administrative-gender";
+    let system = "http://hl7.org/fhir/administrative-gender";
+    let result = |parameters: Parameters| outputs(&parameters);
+
+    assert_eq!(
+      result(
+        terminology
+          .validate_code(
+            Some(gender),
+            &coding(json!({"system": system, "code": "male"}))
+          )
+          .unwrap()
+      ),
+      vec![
+        json!({"name": "result", "valueBoolean": true}),
+        json!({"name": "display", "valueString": "Male"}),
+      ]
+    );
+    assert_eq!(
+      result(
+        terminology
+          .validate_code(
+            Some(gender),
+            &coding(json!({"system": system, "code": "male", "display": "Man"}))
+          )
+          .unwrap()
+      ),
+      vec![
+        json!({"name": "result", "valueBoolean": false}),
+        json!({"name": "message", "valueString":
+          "The display 'Man' is not a valid display for the code 'male' (expected 'Male')"}),
+        json!({"name": "display", "valueString": "Male"}),
+      ]
+    );
+    assert_eq!(
+      result(
+        terminology
+          .validate_code(
+            Some(gender),
+            &coding(json!({"system": system, "code": "M"}))
+          )
+          .unwrap()
+      )[1],
+      json!({"name": "message", "valueString":
+        "Unknown code 'M' in the CodeSystem 'http://hl7.org/fhir/administrative-gender'"})
+    );
+
+    // Value sets pick codes by listing them, by excluding them, and by
+    // importing other value sets.
+    terminology.add(json!({
+      "resourceType": "ValueSet",
+      "url": "http://example.org/ValueSet/known-gender",
+      "compose": {
+        "include": [{"system": system, "valueSet": [gender]}],
+        "exclude": [{"system": system, "concept": [{"code": "unknown"}]}]
+      }
+    }));
+    let known = "http://example.org/ValueSet/known-gender";
+    let valid = |value_set: &str, code: &str| {
+      let coding = coding(json!({"system": system, "code": code}));
+      result(terminology.validate_code(Some(value_set), &coding).unwrap())[0]["valueBoolean"]
+        == true
+    };
+    assert!(valid(known, "female"));
+    assert!(!valid(known, "unknown"));
+    assert!(valid(gender, "unknown"));
+
+    let concept = CodeableConcept {
+      value: Cow::Owned(json!({"coding": [
+        {"system": "http://example.org/gender", "code": "f"},
+        {"system": system, "code": "female"}
+      ]})),
+    };
+    assert_eq!(
+      result(
+        terminology
+          .validate_codeable_concept(Some(known), &concept)
+          .unwrap()
+      ),
+      vec![
+        json!({"name": "result", "valueBoolean": true}),
+        json!({"name": "display", "valueString": "Female"}),
+      ]
+    );
+
+    // Without the code system's concepts, a value set that takes all of its
+    // codes can't be checked.
+    terminology.add(json!({
+      "resourceType": "CodeSystem",
+      "url": "http://loinc.org",
+      "content": "not-present"
+    }));
+    terminology.add(json!({
+      "resourceType": "ValueSet",
+      "url": "http://example.org/ValueSet/loinc",
+      "compose": {"include": [{"system": "http://loinc.org"}]}
+    }));
+    let loinc = coding(json!({"system": "http://loinc.org", "code": "29463-7"}));
+    let issues = terminology
+      .validate_code(Some("http://example.org/ValueSet/loinc"), &loinc)
+      .unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "The CodeSystem 'http://loinc.org' doesn't hold all of its concepts, so its codes can't be checked"
+    );
+    let issues = terminology
+      .validate_code(Some("http://example.org/ValueSet/missing"), &loinc)
+      .unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "The ValueSet 'http://example.org/ValueSet/missing' is not known"
+    );
+  }
+}
@@ -0,0 +1,512 @@
+use super::{array, parameter, parameters_resource, Terminology, MAX_DEPTH};
+use crate::model::CodeableConcept::CodeableConcept;
+use crate::model::Coding::Coding;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::Parameters::Parameters;
+use crate::outcome::Issue;
+use serde_json::json;
+use serde_json::value::Value;
+
+/// `$translate` (http://hl7.org/fhir/conceptmap-operation-translate.html):
+/// maps codes with the ConceptMaps known to the terminology service, or with
+/// one of them picked by URL.
+pub struct Translator<'t> {
+  terminology: &'t Terminology,
+  url: Option<String>,
+  source: Option<String>,
+  target: Option<String>,
+  target_system: Option<String>,
+  dependencies: Vec<(String, Value)>,
+  reverse: bool,
+}
+
+impl<'t> Translator<'t> {
+  pub fn new(terminology: &'t Terminology) -> Translator<'t> {
+    Translator {
+      terminology,
+      url: None,
+      source: None,
+      target: None,
+      target_system: None,
+      dependencies: vec![],
+      reverse: false,
+    }
+  }
+
+  /// Only uses the ConceptMap with this canonical URL.
+  pub fn concept_map<'b>(&'b mut self, url: &str) -> &'b mut Translator<'t> {
+    self.url = Some(url.to_string());
+    self
+  }
+
+  /// Only uses ConceptMaps from this value set.
+  pub fn source<'b>(&'b mut self, value_set: &str) -> &'b mut Translator<'t> {
+    self.source = Some(value_set.to_string());
+    self
+  }
+
+  /// Only uses ConceptMaps to this value set.
+  pub fn target<'b>(&'b mut self, value_set: &str) -> &'b mut Translator<'t> {
+    self.target = Some(value_set.to_string());
+    self
+  }
+
+  /// Only maps to codes of this code system.
+  pub fn target_system<'b>(&'b mut self, system: &str) -> &'b mut Translator<'t> {
+    self.target_system = Some(system.to_string());
+    self
+  }
+
+  /// A fact about the concept being translated, like the specimen a test was
+  /// done on, that mappings may depend on. `element` identifies the element
+  /// the fact is about, as in `ConceptMap.group.element.target.dependsOn`.
+  pub fn dependency<'b>(&'b mut self, element: &str, concept: &Coding) -> &'b mut Translator<'t> {
+    self
+      .dependencies
+      .push((element.to_string(), concept.value.clone().into_owned()));
+    self
+  }
+
+  /// Translates from the targets of the maps back to their sources.
+  pub fn reverse<'b>(&'b mut self, reverse: bool) -> &'b mut Translator<'t> {
+    self.reverse = reverse;
+    self
+  }
+
+  /// Translates a Coding, giving the `result`, a `message` when nothing
+  /// matches, and a `match` for each mapping found.
+  pub fn translate(&self, coding: &Coding) -> Result<Parameters<'static>, Vec<Issue>> {
+    let matches = self.translate_coding(coding).map_err(|issue| vec![issue])?;
+    Ok(translation(matches, &coding.value))
+  }
+
+  /// Translates each Coding of a CodeableConcept, putting all of their
+  /// matches together.
+  pub fn translate_codeable_concept(
+    &self,
+    concept: &CodeableConcept,
+  ) -> Result<Parameters<'static>, Vec<Issue>> {
+    let mut matches = vec![];
+    for coding in concept.coding().unwrap_or_default() {
+      matches.extend(
+        self
+          .translate_coding(&coding)
+          .map_err(|issue| vec![issue])?,
+      );
+    }
+    Ok(translation(matches, &concept.value))
+  }
+
+  fn translate_coding(&self, coding: &Coding) -> Result<Vec<Value>, Issue> {
+    let (system, code) = match (coding.system(), coding.code()) {
+      (Some(system), Some(code)) => (system, code),
+      _ => {
+        return Err(Issue::error(
+          OperationOutcome_IssueCode::Required,
+          "$translate needs both a system and a code",
+        ))
+      }
+    };
+    let mut matches = vec![];
+    for map in self.maps()? {
+      matches.extend(match self.reverse {
+        true => self.reverse_matches(map, system, code),
+        false => self.matches(map, system, code, 0)?,
+      });
+    }
+    Ok(matches)
+  }
+
+  /// The ConceptMaps to translate with, in order of URL.
+  fn maps(&self) -> Result<Vec<&'t Value>, Issue> {
+    if let Some(url) = &self.url {
+      return match self.terminology.map(url) {
+        Some(map) => Ok(vec![map]),
+        None => Err(unknown_map(url)),
+      };
+    }
+    fn scope<'v>(map: &'v Value, side: &str) -> Option<&'v str> {
+      let key = |kind: &str| format!("{}{}", side, kind);
+      map[key("Uri")].as_str().or(map[key("Canonical")].as_str())
+    }
+    let (from, to) = match self.reverse {
+      true => ("target", "source"),
+      false => ("source", "target"),
+    };
+    let mut maps = self
+      .terminology
+      .concept_maps
+      .values()
+      .filter(|map| {
+        self
+          .source
+          .as_deref()
+          .is_none_or(|s| scope(map, from) == Some(s))
+          && self
+            .target
+            .as_deref()
+            .is_none_or(|t| scope(map, to) == Some(t))
+      })
+      .collect::<Vec<_>>();
+    maps.sort_by_key(|map| map["url"].as_str());
+    Ok(maps)
+  }
+
+  /// The matches for a code in a map's groups from its code system. Codes a
+  /// group has no element for fall back on the group's `unmapped` setting: the
+  /// code as provided counts as `equal`, a fixed code as `inexact`, and
+  /// another map's matches count as they are.
+  fn matches(
+    &self,
+    map: &Value,
+    system: &str,
+    code: &str,
+    depth: usize,
+  ) -> Result<Vec<Value>, Issue> {
+    if depth > MAX_DEPTH {
+      return Err(Issue::error(
+        OperationOutcome_IssueCode::Processing,
+        &format!(
+          "The ConceptMap '{}' leads to too many other maps for unmapped codes",
+          map["url"].as_str().unwrap_or_default()
+        ),
+      ));
+    }
+    let url = map["url"].as_str().unwrap_or_default();
+    let mut matches = vec![];
+    for group in array(&map["group"]) {
+      if !same_system(&group["source"], system) || !self.wanted(&group["target"]) {
+        continue;
+      }
+      let elements = array(&group["element"])
+        .iter()
+        .filter(|e| e["code"] == code);
+      let mut mapped = false;
+      for element in elements {
+        mapped = true;
+        for target in array(&element["target"]) {
+          if self.satisfied(&target["dependsOn"]) {
+            let concept = concept(&group["target"], &group["targetVersion"], target);
+            let equivalence = target["equivalence"].as_str().unwrap_or("equivalent");
+            matches.push(matched(equivalence, concept, &target["product"], url));
+          }
+        }
+      }
+      if mapped {
+        continue;
+      }
+      let unmapped = &group["unmapped"];
+      let provided = json!({"code": code});
+      match unmapped["mode"].as_str() {
+        Some("provided") => {
+          let concept = concept(&group["target"], &group["targetVersion"], &provided);
+          matches.push(matched("equal", concept, &Value::Null, url));
+        }
+        Some("fixed") => {
+          let concept = concept(&group["target"], &group["targetVersion"], unmapped);
+          matches.push(matched("inexact", concept, &Value::Null, url));
+        }
+        Some("other-map") => {
+          let other = unmapped["url"].as_str().unwrap_or_default();
+          let other = self
+            .terminology
+            .map(other)
+            .ok_or_else(|| unknown_map(other))?;
+          matches.extend(self.matches(other, system, code, depth + 1)?);
+        }
+        _ => {}
+      }
+    }
+    Ok(matches)
+  }
+
+  /// The matches for a code in the targets of a map's groups to its code
+  /// system. What the mapping depends on comes back as its product, and what
+  /// it produces must agree with the dependencies given.
+  fn reverse_matches(&self, map: &Value, system: &str, code: &str) -> Vec<Value> {
+    let url = map["url"].as_str().unwrap_or_default();
+    let mut matches = vec![];
+    for group in array(&map["group"]) {
+      if !same_system(&group["target"], system) || !self.wanted(&group["source"]) {
+        continue;
+      }
+      for element in array(&group["element"]) {
+        let targets = array(&element["target"])
+          .iter()
+          .filter(|t| t["code"] == code);
+        for target in targets.filter(|target| self.satisfied(&target["product"])) {
+          let concept = concept(&group["source"], &group["sourceVersion"], element);
+          let equivalence = target["equivalence"].as_str().unwrap_or("equivalent");
+          let equivalence = match equivalence {
+            "wider" => "narrower",
+            "narrower" => "wider",
+            "subsumes" => "specializes",
+            "specializes" => "subsumes",
+            equivalence => equivalence,
+          };
+          matches.push(matched(equivalence, concept, &target["dependsOn"], url));
+        }
+      }
+    }
+    matches
+  }
+
+  /// Whether a group maps to the code system asked for, if any.
+  fn wanted(&self, system: &Value) -> bool {
+    match &self.target_system {
+      Some(target_system) => same_system(system, target_system),
+      None => true,
+    }
+  }
+
+  /// Whether the dependencies given meet all of a mapping's conditions.
+  fn satisfied(&self, conditions: &Value) -> bool {
+    array(conditions).iter().all(|condition| {
+      self.dependencies.iter().any(|(element, concept)| {
+        condition["property"] == element.as_str()
+          && concept["code"] == condition["value"]
+          && (condition["system"].is_null() || concept["system"] == condition["system"])
+      })
+    })
+  }
+}
+
+impl Terminology {
+  /// Translates a Coding with every ConceptMap that maps from its code
+  /// system, with the defaults of a [`Translator`].
+  pub fn translate(&self, coding: &Coding) -> Result<Parameters<'static>, Vec<Issue>> {
+    Translator::new(self).translate(coding)
+  }
+}
+
+fn same_system(system: &Value, url: &str) -> bool {
+  system
+    .as_str()
+    .is_some_and(|system| system.split('|').next() == url.split('|').next())
+}
+
+/// The Coding a match maps to, with its code and display taken from a target,
+/// an element or the `unmapped` setting of a group.
+fn concept(system: &Value, version: &Value, mapped: &Value) -> Option<Value> {
+  let code = mapped["code"].as_str()?;
+  let mut concept = json!({"system": system, "code": code});
+  if let Some(version) = version.as_str() {
+    concept["version"] = json!(version);
+  }
+  if let Some(display) = mapped["display"].as_str() {
+    concept["display"] = json!(display);
+  }
+  Some(concept)
+}
+
+fn matched(equivalence: &str, concept: Option<Value>, products: &Value, url: &str) -> Value {
+  let mut parts = vec![parameter("equivalence", "valueCode", equivalence)];
+  if let Some(concept) = concept {
+    parts.push(parameter("concept", "valueCoding", concept));
+  }
+  for product in array(products) {
+    let mut concept = json!({"code": product["value"]});
+    if !product["system"].is_null() {
+      concept["system"] = product["system"].clone();
+    }
+    if !product["display"].is_null() {
+      concept["display"] = product["display"].clone();
+    }
+    parts.push(json!({"name": "product", "part": [
+      parameter("element", "valueUri", product["property"].clone()),
+      parameter("concept", "valueCoding", concept),
+    ]}));
+  }
+  parts.push(parameter("source", "valueUri", url));
+  json!({"name": "match", "part": parts})
+}
+
+/// The `$translate` output: a match that isn't `unmatched` or `disjoint`
+/// makes the result true.
+fn translation(matches: Vec<Value>, translated: &Value) -> Parameters<'static> {
+  let result = matches.iter().any(|m| {
+    !matches!(
+      m["part"][0]["valueCode"].as_str(),
+      Some("unmatched" | "disjoint")
+    )
+  });
+  let mut parameters = vec![parameter("result", "valueBoolean", result)];
+  if !result {
+    let message = match translated["coding"].is_array() {
+      true => "No mappings found for the CodeableConcept".to_string(),
+      false => format!(
+        "No mappings found for code '{}' from system '{}'",
+        translated["code"].as_str().unwrap_or_default(),
+        translated["system"].as_str().unwrap_or_default()
+      ),
+    };
+    parameters.push(parameter("message", "valueString", message));
+  }
+  parameters.extend(matches);
+  parameters_resource(parameters)
+}
+
+fn unknown_map(url: &str) -> Issue {
+  Issue::error(
+    OperationOutcome_IssueCode::NotFound,
+    &format!("The ConceptMap '{}' is not known", url),
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::borrow::Cow;
+  use std::path::Path;
+
+  fn terminology() -> Terminology {
+    let mut terminology = Terminology::new();
+    for file in &[
+      "cm-administrative-gender-v2.json",
+      "conceptmap-example.json",
+      "conceptmap-example-2.json",
+      "conceptmap-example-specimen-type.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      terminology.load_file(Path::new(&path)).unwrap();
+    }
+    terminology
+  }
+
+  fn coding(system: &str, code: &str) -> Coding<'static> {
+    Coding {
+      value: Cow::Owned(json!({"system": system, "code": code})),
+    }
+  }
+
+  /// The result, then the equivalence and code of each match.
+  fn summary(parameters: &Parameters) -> (bool, Vec<(String, String)>) {
+    let outputs = parameters.value["parameter"].as_array().unwrap();
+    let matches = outputs
+      .iter()
+      .filter(|p| p["name"] == "match")
+      .map(|m| {
+        let equivalence = m["part"][0]["valueCode"].as_str().unwrap().to_string();
+        let code = m["part"][1]["valueCoding"]["code"]
+          .as_str()
+          .unwrap_or_default();
+        (equivalence, code.to_string())
+      })
+      .collect();
+    (outputs[0]["valueBoolean"] == true, matches)
+  }
+
+  fn found(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs
+      .iter()
+      .map(|(equivalence, code)| (equivalence.to_string(), code.to_string()))
+      .collect()
+  }
+
+  #[test]
+  fn test_translate() {
+    let terminology = terminology();
+    let gender = "http://hl7.org/fhir/administrative-gender";
+    let v2 = "http://terminology.hl7.org/CodeSystem/v2-0001";
+
+    let male = terminology.translate(&coding(gender, "male")).unwrap();
+    assert_eq!(
+      male.value["parameter"],
+      json!([
+        {"name": "result", "valueBoolean": true},
+        {"name": "match", "part": [
+          {"name": "equivalence", "valueCode": "equal"},
+          {"name": "concept", "valueCoding": {"system": v2, "code": "M"}},
+          {"name": "source", "valueUri": "http://hl7.org/fhir/ConceptMap/cm-administrative-gender-v2"}
+        ]}
+      ])
+    );
+    assert_eq!(
+      summary(&terminology.translate(&coding(gender, "other")).unwrap()),
+      (true, found(&[("wider", "A"), ("wider", "O")]))
+    );
+
+    // Reverse translation flips the direction of the equivalence.
+    let mut reverse = Translator::new(&terminology);
+    reverse.reverse(true);
+    assert_eq!(
+      summary(&reverse.translate(&coding(v2, "A")).unwrap()),
+      (true, found(&[("narrower", "other")]))
+    );
+
+    // A disjoint mapping isn't a translation, and codes with no mapping fall
+    // back on the group's unmapped setting.
+    let address = "http://hl7.org/fhir/address-use";
+    let old = terminology.translate(&coding(address, "old")).unwrap();
+    assert_eq!(summary(&old), (false, found(&[("disjoint", "BAD")])));
+    assert_eq!(
+      old.value["parameter"][1]["valueString"],
+      "No mappings found for code 'old' from system 'http://hl7.org/fhir/address-use'"
+    );
+    assert_eq!(
+      summary(&terminology.translate(&coding(address, "billing")).unwrap()),
+      (true, found(&[("inexact", "temp")]))
+    );
+
+    // Products come back with the match.
+    let specimen = terminology
+      .translate(&coding(
+        "http://terminology.hl7.org/CodeSystem/v2-0487",
+        "ACNFLD",
+      ))
+      .unwrap();
+    assert_eq!(
+      specimen.value["parameter"][1]["part"][2],
+      json!({"name": "product", "part": [
+        {"name": "element", "valueUri": "TypeModifier"},
+        {"name": "concept", "valueCoding": {"code": "47002008", "system": "http://snomed.info/sct"}}
+      ]})
+    );
+  }
+
+  #[test]
+  fn test_depends_on() {
+    let mut terminology = terminology();
+    let example1 = "http://example.org/fhir/example1";
+    let url = "http://hl7.org/fhir/ConceptMap/example2";
+    let mut translator = Translator::new(&terminology);
+    translator.concept_map(url);
+    assert_eq!(
+      summary(&translator.translate(&coding(example1, "code")).unwrap()),
+      (false, vec![])
+    );
+    translator.dependency(
+      "http://example.org/fhir/property-value/example",
+      &coding("http://example.org/fhir/example3", "some-code"),
+    );
+    assert_eq!(
+      summary(&translator.translate(&coding(example1, "code")).unwrap()),
+      (true, found(&[("equivalent", "code2")]))
+    );
+    let issues = translator
+      .translate(&coding(example1, "other"))
+      .unwrap_err();
+    assert_eq!(
+      issues[0].diagnostics,
+      "The ConceptMap 'http://example.org/fhir/ConceptMap/map2' is not known"
+    );
+
+    // Codes without a mapping in the other map come through as provided.
+    terminology.add(json!({
+      "resourceType": "ConceptMap",
+      "url": "http://example.org/fhir/ConceptMap/map2",
+      "group": [{
+        "source": example1,
+        "target": "http://example.org/fhir/example2",
+        "element": [],
+        "unmapped": {"mode": "provided"}
+      }]
+    }));
+    let mut translator = Translator::new(&terminology);
+    translator.concept_map(url);
+    assert_eq!(
+      summary(&translator.translate(&coding(example1, "other")).unwrap()),
+      (true, found(&[("equal", "other")]))
+    );
+  }
+}
@@ -0,0 +1,620 @@
+use crate::ids::new_uuid;
+use crate::model::Bundle::{Bundle, BundleType};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::util::{base64, service_base};
+use regex::{Captures, Regex};
+use serde_json::json;
+use serde_json::value::Value;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+const JSON_PATCH: &str = "application/json-patch+json";
+
+/// Fluent builder for `transaction` and `batch` Bundles.
+#[derive(Debug)]
+pub struct TransactionBuilder {
+  pub(crate) value: Value,
+}
+
+impl TransactionBuilder {
+  pub fn build(&self) -> Bundle<'_> {
+    Bundle {
+      value: Cow::Owned(self.value.clone()),
+    }
+  }
+
+  pub fn transaction() -> TransactionBuilder {
+    TransactionBuilder::new(BundleType::Transaction)
+  }
+
+  pub fn batch() -> TransactionBuilder {
+    TransactionBuilder::new(BundleType::Batch)
+  }
+
+  fn new(fhir_type: BundleType) -> TransactionBuilder {
+    TransactionBuilder {
+      value: json!({
+        "resourceType": "Bundle",
+        "type": fhir_type.to_string(),
+        "entry": [],
+      }),
+    }
+  }
+
+  /// POSTs a new resource. `full_url` is usually a `urn:uuid:` that other
+  /// entries use to reference the resource before the server assigns its id.
+  pub fn create<'a>(
+    &'a mut self,
+    full_url: &str,
+    resource: ResourceList,
+  ) -> &'a mut TransactionBuilder {
+    let url = resource_type(&resource.value).to_string();
+    self.push(
+      Some(full_url),
+      Some(resource),
+      json!({"method": "POST", "url": url}),
+    )
+  }
+
+  /// POSTs a new resource only if no resource matches the `If-None-Exist`
+  /// search query, e.g. `identifier=http://acme.org/mrns|12345`.
+  pub fn conditional_create<'a>(
+    &'a mut self,
+    full_url: &str,
+    resource: ResourceList,
+    if_none_exist: &str,
+  ) -> &'a mut TransactionBuilder {
+    let url = resource_type(&resource.value).to_string();
+    self.push(
+      Some(full_url),
+      Some(resource),
+      json!({"method": "POST", "url": url, "ifNoneExist": if_none_exist}),
+    )
+  }
+
+  /// PUTs a resource to `Type/id`, taken from the resource itself.
+  pub fn update<'a>(&'a mut self, resource: ResourceList) -> &'a mut TransactionBuilder {
+    let url = format!(
+      "{}/{}",
+      resource_type(&resource.value),
+      resource.value["id"].as_str().unwrap_or_default()
+    );
+    self.push(None, Some(resource), json!({"method": "PUT", "url": url}))
+  }
+
+  /// PATCHes the resource at `url` with a JSON Patch (RFC 6902) document, the
+  /// format the server and client take. It's sent as the data of a `Binary`
+  /// with the `application/json-patch+json` content type, as entries can only
+  /// hold resources.
+  pub fn patch<'a>(&'a mut self, url: &str, patch: &Value) -> &'a mut TransactionBuilder {
+    let binary = json!({
+      "resourceType": "Binary",
+      "contentType": JSON_PATCH,
+      "data": base64(patch.to_string().as_bytes()),
+    });
+    self.push(
+      None,
+      Some(ResourceList::new(&binary)),
+      json!({"method": "PATCH", "url": url}),
+    )
+  }
+
+  /// DELETEs the resource at `url`, which may also be a conditional delete such
+  /// as `Patient?identifier=12345`.
+  pub fn delete<'a>(&'a mut self, url: &str) -> &'a mut TransactionBuilder {
+    self.push(None, None, json!({"method": "DELETE", "url": url}))
+  }
+
+  /// GETs `url`, either a read (`Patient/1`) or a search (`Patient?name=smith`).
+  pub fn read<'a>(&'a mut self, url: &str) -> &'a mut TransactionBuilder {
+    self.push(None, None, json!({"method": "GET", "url": url}))
+  }
+
+  fn push(
+    &mut self,
+    full_url: Option<&str>,
+    resource: Option<ResourceList>,
+    request: Value,
+  ) -> &mut TransactionBuilder {
+    let mut entry = json!({});
+    if let Some(full_url) = full_url {
+      entry["fullUrl"] = json!(full_url);
+    }
+    if let Some(resource) = resource {
+      entry["resource"] = resource.value.into_owned();
+    }
+    entry["request"] = request;
+    if let Some(Value::Array(entries)) = self.value.get_mut("entry") {
+      entries.push(entry);
+    }
+    self
+  }
+}
+
+/// Applies the processing rules for transaction Bundles
+/// (http://hl7.org/fhir/http.html#trules) ahead of executing them: entries are
+/// put in processing order, created resources are given new ids, and every
+/// reference to a created resource's `urn:uuid` or `urn:oid` is rewritten to
+/// the new id.
+///
+/// The entries of a `batch` are independent, so they keep their order and
+/// nothing is rewritten; created resources are still given ids. Batch entries
+/// that refer to another entry's `urn:` fullUrl are errors.
+pub struct TransactionProcessor {
+  base: Option<String>,
+  id_generator: Box<dyn FnMut() -> String>,
+}
+
+impl Default for TransactionProcessor {
+  fn default() -> TransactionProcessor {
+    TransactionProcessor::new()
+  }
+}
+
+impl TransactionProcessor {
+  pub fn new() -> TransactionProcessor {
+    TransactionProcessor {
+      base: None,
+      id_generator: Box::new(new_uuid),
+    }
+  }
+
+  /// Service base URL used for the `fullUrl` of created resources, e.g.
+  /// `http://example.org/fhir/`. Without one, `fullUrl` is left relative.
+  pub fn base<'a>(&'a mut self, base: &str) -> &'a mut TransactionProcessor {
+    let base = service_base(base);
+    self.base = Some(base);
+    self
+  }
+
+  /// Replaces the id generator, which defaults to random UUIDs.
+  pub fn id_generator<F>(&mut self, generator: F) -> &mut TransactionProcessor
+  where
+    F: FnMut() -> String + 'static,
+  {
+    self.id_generator = Box::new(generator);
+    self
+  }
+
+  /// Returns a copy of the Bundle ready to execute in order, or the problems
+  /// that stop it from being processed.
+  pub fn process(&mut self, bundle: &Bundle) -> Result<Bundle<'static>, Vec<Issue>> {
+    let mut issues = vec![];
+    let fhir_type = bundle.fhir_type();
+    match fhir_type {
+      Some(BundleType::Transaction) | Some(BundleType::Batch) => {}
+      _ => issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Invalid,
+          "Bundle type must be transaction or batch",
+        )
+        .at("Bundle.type"),
+      ),
+    }
+    let mut entries = match bundle.value.get("entry") {
+      Some(Value::Array(entries)) => entries.clone(),
+      _ => vec![],
+    };
+    for (index, entry) in entries.iter().enumerate() {
+      let method = entry["request"]["method"].as_str();
+      if method.is_none() || entry["request"]["url"].as_str().is_none() {
+        issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::Required,
+            "Transaction entries need a request method and url",
+          )
+          .at(&format!("Bundle.entry[{}].request", index)),
+        );
+      } else if matches!(method, Some("POST") | Some("PUT")) && entry.get("resource").is_none() {
+        issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::Required,
+            "POST and PUT entries need a resource",
+          )
+          .at(&format!("Bundle.entry[{}]", index)),
+        );
+      }
+    }
+    let transaction = matches!(fhir_type, Some(BundleType::Transaction));
+    if !transaction {
+      issues.extend(
+        batch_references(&entries)
+          .into_iter()
+          .map(|(_, issue)| issue),
+      );
+    }
+    if !issues.is_empty() {
+      return Err(issues);
+    }
+
+    if transaction {
+      entries
+        .sort_by_key(|entry| method_order(entry["request"]["method"].as_str().unwrap_or_default()));
+    }
+
+    let mut assigned: HashMap<String, String> = HashMap::new();
+    for entry in entries.iter_mut() {
+      if entry["request"]["method"] != "POST" {
+        continue;
+      }
+      let id = (self.id_generator)();
+      let resource_type = resource_type(&entry["resource"]).to_string();
+      let local = format!("{}/{}", resource_type, id);
+      if let Some(full_url) = entry["fullUrl"].as_str() {
+        if full_url.starts_with("urn:uuid:") || full_url.starts_with("urn:oid:") {
+          assigned.insert(full_url.to_string(), local.clone());
+        }
+      }
+      entry["resource"]["id"] = json!(id);
+      entry["fullUrl"] = json!(match &self.base {
+        Some(base) => format!("{}{}", base, local),
+        None => local,
+      });
+    }
+
+    if transaction {
+      for entry in entries.iter_mut() {
+        rewrite_references(entry, &assigned);
+      }
+    }
+
+    let mut value = (*bundle.value).clone();
+    value["entry"] = Value::Array(entries);
+    Ok(Bundle {
+      value: Cow::Owned(value),
+    })
+  }
+}
+
+pub(crate) fn method_order(method: &str) -> u8 {
+  match method {
+    "DELETE" => 0,
+    "POST" => 1,
+    "PUT" | "PATCH" => 2,
+    "GET" | "HEAD" => 3,
+    _ => 4,
+  }
+}
+
+/// The batch entries that refer to another entry by its `urn:` fullUrl, which
+/// only transactions may do, each with the issue to report for it.
+pub(crate) fn batch_references(entries: &[Value]) -> Vec<(usize, Issue)> {
+  let urns = entries
+    .iter()
+    .enumerate()
+    .filter_map(|(index, entry)| match entry["fullUrl"].as_str() {
+      Some(full_url) if full_url.starts_with("urn:") => Some((index, full_url)),
+      _ => None,
+    })
+    .collect::<Vec<_>>();
+  let mut found = vec![];
+  for (index, entry) in entries.iter().enumerate() {
+    let referenced = urns
+      .iter()
+      .find(|(other, urn)| *other != index && mentions(entry, urn));
+    if let Some((_, urn)) = referenced {
+      let issue = Issue::error(
+        OperationOutcome_IssueCode::Invalid,
+        &format!(
+          "Batch entries can't refer to other entries, as this one does to {}",
+          urn
+        ),
+      )
+      .at(&format!("Bundle.entry[{}]", index));
+      found.push((index, issue));
+    }
+  }
+  found
+}
+
+fn resource_type(resource: &Value) -> &str {
+  resource["resourceType"].as_str().unwrap_or_default()
+}
+
+/// Rewrites the references in a transaction entry to `urn:uuid`s, in its
+/// resource and in the search parameters of its request, to the ids assigned
+/// during processing. Only whole values are replaced: `Reference.reference`s,
+/// `href` and `src` links in the narrative, and search parameter values, so a
+/// urn that begins another one, like `urn:oid:1.2` in `urn:oid:1.2.3`, is left
+/// alone.
+pub(crate) fn rewrite_references(entry: &mut Value, assigned: &HashMap<String, String>) {
+  replace_references(entry, &mut |reference| assigned.get(reference).cloned());
+}
+
+/// Whether an entry refers to `urn` wherever [`rewrite_references`] would
+/// rewrite it.
+fn mentions(entry: &Value, urn: &str) -> bool {
+  let mut found = false;
+  replace_references(&mut entry.clone(), &mut |reference| {
+    found |= reference == urn;
+    None
+  });
+  found
+}
+
+fn replace_references(entry: &mut Value, replace: &mut dyn FnMut(&str) -> Option<String>) {
+  if let Some(resource) = entry.get_mut("resource") {
+    replace_in_resource(resource, replace);
+  }
+  if let Some(request) = entry.get_mut("request") {
+    for name in &["url", "ifNoneExist"] {
+      if let Some(Value::String(query)) = request.get_mut(*name) {
+        *query = replace_in_query(query, replace);
+      }
+    }
+  }
+}
+
+fn replace_in_resource(value: &mut Value, replace: &mut dyn FnMut(&str) -> Option<String>) {
+  match value {
+    Value::Array(items) => items
+      .iter_mut()
+      .for_each(|item| replace_in_resource(item, replace)),
+    Value::Object(map) => {
+      for (key, child) in map.iter_mut() {
+        match (key.as_str(), child) {
+          ("reference", Value::String(reference)) => {
+            if let Some(replaced) = replace(reference) {
+              *reference = replaced;
+            }
+          }
+          ("div", Value::String(div)) => *div = replace_in_links(div, replace),
+          (_, child) => replace_in_resource(child, replace),
+        }
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Replaces the `href` and `src` attributes of XHTML.
+fn replace_in_links(div: &str, replace: &mut dyn FnMut(&str) -> Option<String>) -> String {
+  let links = Regex::new(r#"\b((?:href|src)\s*=\s*)(?:"([^"]*)"|'([^']*)')"#).unwrap();
+  links
+    .replace_all(div, |captures: &Captures| {
+      let (link, quote) = match captures.get(2) {
+        Some(link) => (link.as_str(), '"'),
+        None => (&captures[3], '\''),
+      };
+      let link = replace(link).unwrap_or_else(|| link.to_string());
+      format!("{}{}{}{}", &captures[1], quote, link, quote)
+    })
+    .into_owned()
+}
+
+/// Replaces the values of the search parameters in a URL or query.
+fn replace_in_query(url: &str, replace: &mut dyn FnMut(&str) -> Option<String>) -> String {
+  let (path, query) = match url.split_once('?') {
+    Some((path, query)) => (Some(path), query),
+    None if url.contains('=') => (None, url),
+    None => return url.to_string(),
+  };
+  let pairs = query
+    .split('&')
+    .map(|pair| match pair.split_once('=') {
+      Some((name, value)) => match replace(value) {
+        Some(replaced) => format!("{}={}", name, replaced),
+        None => pair.to_string(),
+      },
+      None => pair.to_string(),
+    })
+    .collect::<Vec<_>>()
+    .join("&");
+  match path {
+    Some(path) => format!("{}?{}", path, pairs),
+    None => pairs,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_builds_transaction() {
+    let patient = json!({"resourceType": "Patient", "id": "p1", "active": true});
+    let bundle = TransactionBuilder::transaction()
+      .conditional_create(
+        "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+        ResourceList::new(&patient),
+        "identifier=12345",
+      )
+      .update(ResourceList::new(&patient))
+      .delete("Patient/p2")
+      .read("Patient?name=smith")
+      .patch(
+        "Patient/p1",
+        &json!([{"op": "replace", "path": "/active", "value": false}]),
+      )
+      .build()
+      .to_json();
+    assert_eq!(bundle["type"], "transaction");
+    assert_eq!(
+      bundle["entry"][0]["request"]["ifNoneExist"],
+      "identifier=12345"
+    );
+    assert_eq!(bundle["entry"][1]["request"]["url"], "Patient/p1");
+    assert_eq!(bundle["entry"][2]["request"]["method"], "DELETE");
+    assert!(bundle["entry"][3].get("resource").is_none());
+    let binary = &bundle["entry"][4]["resource"];
+    assert_eq!(binary["contentType"], "application/json-patch+json");
+    assert_eq!(
+      binary["data"],
+      "W3sib3AiOiJyZXBsYWNlIiwicGF0aCI6Ii9hY3RpdmUiLCJ2YWx1ZSI6ZmFsc2V9XQ=="
+    );
+  }
+
+  #[test]
+  fn test_processes_transaction() {
+    let patient = json!({"resourceType": "Patient"});
+    let observation = json!({
+      "resourceType": "Observation",
+      "id": "o1",
+      "subject": {"reference": "urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059"},
+      "text": {"div": "<div><a href=\"urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059\">patient</a></div>"}
+    });
+    let bundle = TransactionBuilder::transaction()
+      .read("Patient?name=smith")
+      .update(ResourceList::new(&observation))
+      .create(
+        "urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059",
+        ResourceList::new(&patient),
+      )
+      .delete("Patient/p2")
+      .build()
+      .to_json();
+    let mut ids = vec!["a".to_string(), "b".to_string()].into_iter();
+    let processed = TransactionProcessor::new()
+      .base("http://example.org/fhir")
+      .id_generator(move || ids.next().unwrap())
+      .process(&Bundle::new(&bundle))
+      .unwrap()
+      .to_json();
+    let methods = processed["entry"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|e| e["request"]["method"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(methods, vec!["DELETE", "POST", "PUT", "GET"]);
+    assert_eq!(
+      processed["entry"][1]["fullUrl"],
+      "http://example.org/fhir/Patient/a"
+    );
+    assert_eq!(processed["entry"][1]["resource"]["id"], "a");
+    assert_eq!(
+      processed["entry"][2]["resource"]["subject"]["reference"],
+      "Patient/a"
+    );
+    assert_eq!(
+      processed["entry"][2]["resource"]["text"]["div"],
+      "<div><a href=\"Patient/a\">patient</a></div>"
+    );
+  }
+
+  #[test]
+  fn test_processes_batch() {
+    let urn = "urn:uuid:88f151c0-a954-468a-88bd-5ae15c08e059";
+    let patient = json!({"resourceType": "Patient"});
+    let observation = json!({"resourceType": "Observation", "id": "o1"});
+    let mut builder = TransactionBuilder::batch();
+    builder
+      .read("Patient?name=smith")
+      .update(ResourceList::new(&observation))
+      .create(urn, ResourceList::new(&patient))
+      .delete("Patient/p2");
+    let mut ids = vec!["a".to_string()].into_iter();
+    let processed = TransactionProcessor::new()
+      .id_generator(move || ids.next().unwrap())
+      .process(&builder.build())
+      .unwrap()
+      .to_json();
+    let methods = processed["entry"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|e| e["request"]["method"].as_str().unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(methods, vec!["GET", "PUT", "POST", "DELETE"]);
+    assert_eq!(processed["entry"][2]["resource"]["id"], "a");
+
+    let referring = json!({
+      "resourceType": "Observation",
+      "id": "o1",
+      "subject": {"reference": urn}
+    });
+    let bundle = TransactionBuilder::batch()
+      .update(ResourceList::new(&referring))
+      .create(urn, ResourceList::new(&patient))
+      .build()
+      .to_json();
+    let issues = TransactionProcessor::new()
+      .process(&Bundle::new(&bundle))
+      .unwrap_err();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(issues[0].expression.as_deref(), Some("Bundle.entry[0]"));
+    assert!(issues[0].diagnostics.contains(urn));
+    // The same entries in a transaction are rewritten.
+    let mut transaction = bundle;
+    transaction["type"] = json!("transaction");
+    let processed = TransactionProcessor::new()
+      .process(&Bundle::new(&transaction))
+      .unwrap()
+      .to_json();
+    assert_ne!(
+      processed["entry"][1]["resource"]["subject"]["reference"],
+      urn
+    );
+  }
+
+  #[test]
+  fn test_rewrites_whole_references() {
+    // Each urn begins the next, which mustn't be rewritten with it.
+    let bundle = json!({
+      "resourceType": "Bundle",
+      "type": "transaction",
+      "entry": [
+        {"fullUrl": "urn:uuid:1", "resource": {"resourceType": "Patient"}, "request": {"method": "POST", "url": "Patient"}},
+        {"fullUrl": "urn:uuid:10", "resource": {"resourceType": "Patient"}, "request": {"method": "POST", "url": "Patient"}},
+        {"fullUrl": "urn:oid:1.2", "resource": {"resourceType": "Practitioner"}, "request": {"method": "POST", "url": "Practitioner"}},
+        {"fullUrl": "urn:oid:1.2.3", "resource": {"resourceType": "Practitioner"}, "request": {"method": "POST", "url": "Practitioner"}},
+        {
+          "resource": {
+            "resourceType": "Observation",
+            "id": "o1",
+            "subject": {"reference": "urn:uuid:10"},
+            "performer": [{"reference": "urn:oid:1.2.3"}, {"reference": "urn:oid:1.2"}],
+            "note": [{"text": "See urn:uuid:1"}],
+            "text": {"div": "<div><a href=\"urn:uuid:10\">urn:uuid:1</a><img src='urn:uuid:1'/></div>"}
+          },
+          "request": {"method": "PUT", "url": "Observation?subject=urn:uuid:10&performer=urn:oid:1.2"}
+        }
+      ]
+    });
+    let mut ids = vec!["a", "b", "c", "d"].into_iter().map(str::to_string);
+    let processed = TransactionProcessor::new()
+      .id_generator(move || ids.next().unwrap())
+      .process(&Bundle::new(&bundle))
+      .unwrap()
+      .to_json();
+    let observation = &processed["entry"][4]["resource"];
+    assert_eq!(observation["subject"]["reference"], "Patient/b");
+    assert_eq!(observation["performer"][0]["reference"], "Practitioner/d");
+    assert_eq!(observation["performer"][1]["reference"], "Practitioner/c");
+    assert_eq!(observation["note"][0]["text"], "See urn:uuid:1");
+    assert_eq!(
+      observation["text"]["div"],
+      "<div><a href=\"Patient/b\">urn:uuid:1</a><img src='Patient/a'/></div>"
+    );
+    assert_eq!(
+      processed["entry"][4]["request"]["url"],
+      "Observation?subject=Patient/b&performer=Practitioner/c"
+    );
+
+    // Nor does a batch entry refer to a urn that only begins one it has.
+    let mut batch = bundle;
+    batch["type"] = json!("batch");
+    batch["entry"].as_array_mut().unwrap().truncate(1);
+    batch["entry"]
+      .as_array_mut()
+      .unwrap()
+      .push(json!({"resource": {"resourceType": "Observation", "subject": {"reference": "urn:uuid:10"}}, "request": {"method": "POST", "url": "Observation"}}));
+    assert!(TransactionProcessor::new()
+      .process(&Bundle::new(&batch))
+      .is_ok());
+  }
+
+  #[test]
+  fn test_rejects_entries_without_request() {
+    let bundle = json!({"resourceType": "Bundle", "type": "transaction", "entry": [{"resource": {"resourceType": "Patient"}}]});
+    let issues = TransactionProcessor::new()
+      .process(&Bundle::new(&bundle))
+      .unwrap_err();
+    assert_eq!(
+      issues[0].expression.as_deref(),
+      Some("Bundle.entry[0].request")
+    );
+  }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+/// A unit reduced to a scale factor and the powers of its base units.
+#[derive(Debug, Clone, PartialEq)]
+struct Unit {
+  factor: f64,
+  dimensions: BTreeMap<&'static str, i32>,
+}
+
+impl Unit {
+  fn unity() -> Unit {
+    Unit {
+      factor: 1.0,
+      dimensions: BTreeMap::new(),
+    }
+  }
+
+  fn multiply(mut self, other: &Unit, sign: i32) -> Unit {
+    self.factor *= other.factor.powi(sign);
+    for (base, exponent) in &other.dimensions {
+      let entry = self.dimensions.entry(base).or_insert(0);
+      *entry += exponent * sign;
+      if *entry == 0 {
+        self.dimensions.remove(base);
+      }
+    }
+    self
+  }
+
+  fn power(mut self, exponent: i32) -> Unit {
+    self.factor = self.factor.powi(exponent);
+    for value in self.dimensions.values_mut() {
+      *value *= exponent;
+    }
+    self
+  }
+
+  fn code(&self) -> String {
+    if self.dimensions.is_empty() {
+      return "1".to_string();
+    }
+    self
+      .dimensions
+      .iter()
+      .map(|(base, exponent)| match exponent {
+        1 => base.to_string(),
+        _ => format!("{}{}", base, exponent),
+      })
+      .collect::<Vec<_>>()
+      .join(".")
+  }
+}
+
+const PREFIXES: &[(&str, f64)] = &[
+  ("da", 1e1),
+  ("Y", 1e24),
+  ("Z", 1e21),
+  ("E", 1e18),
+  ("P", 1e15),
+  ("T", 1e12),
+  ("G", 1e9),
+  ("M", 1e6),
+  ("k", 1e3),
+  ("h", 1e2),
+  ("d", 1e-1),
+  ("c", 1e-2),
+  ("m", 1e-3),
+  ("u", 1e-6),
+  ("n", 1e-9),
+  ("p", 1e-12),
+  ("f", 1e-15),
+  ("a", 1e-18),
+  ("z", 1e-21),
+  ("y", 1e-24),
+];
+
+/// (code, metric, factor, base units) for the units seen in clinical data.
+type Atom = (&'static str, bool, f64, &'static [(&'static str, i32)]);
+
+const ATOMS: &[Atom] = &[
+  ("m", true, 1.0, &[("m", 1)]),
+  ("g", true, 1.0, &[("g", 1)]),
+  ("s", true, 1.0, &[("s", 1)]),
+  ("mol", true, 1.0, &[("mol", 1)]),
+  ("K", true, 1.0, &[("K", 1)]),
+  ("cd", true, 1.0, &[("cd", 1)]),
+  ("rad", true, 1.0, &[("rad", 1)]),
+  ("sr", true, 1.0, &[("rad", 2)]),
+  ("L", true, 1e-3, &[("m", 3)]),
+  ("l", true, 1e-3, &[("m", 3)]),
+  ("min", false, 60.0, &[("s", 1)]),
+  ("h", false, 3600.0, &[("s", 1)]),
+  ("d", false, 86_400.0, &[("s", 1)]),
+  ("wk", false, 604_800.0, &[("s", 1)]),
+  ("mo", false, 2_629_800.0, &[("s", 1)]),
+  ("a", false, 31_557_600.0, &[("s", 1)]),
+  ("Hz", true, 1.0, &[("s", -1)]),
+  ("N", true, 1e3, &[("g", 1), ("m", 1), ("s", -2)]),
+  ("Pa", true, 1e3, &[("g", 1), ("m", -1), ("s", -2)]),
+  ("bar", true, 1e8, &[("g", 1), ("m", -1), ("s", -2)]),
+  (
+    "m[Hg]",
+    true,
+    133_322_387.415,
+    &[("g", 1), ("m", -1), ("s", -2)],
+  ),
+  ("J", true, 1e3, &[("g", 1), ("m", 2), ("s", -2)]),
+  ("cal", true, 4184.0, &[("g", 1), ("m", 2), ("s", -2)]),
+  ("W", true, 1e3, &[("g", 1), ("m", 2), ("s", -3)]),
+  ("eq", true, 1.0, &[("mol", 1)]),
+  ("kat", true, 1.0, &[("mol", 1), ("s", -1)]),
+  ("U", true, 1e-6 / 60.0, &[("mol", 1), ("s", -1)]),
+  ("[iU]", true, 1.0, &[("[iU]", 1)]),
+  ("[IU]", true, 1.0, &[("[iU]", 1)]),
+  ("%", false, 1e-2, &[]),
+  ("[ppth]", false, 1e-3, &[]),
+  ("[ppm]", false, 1e-6, &[]),
+  ("[ppb]", false, 1e-9, &[]),
+  ("[pi]", false, std::f64::consts::PI, &[]),
+  ("[in_i]", false, 0.0254, &[("m", 1)]),
+  ("[ft_i]", false, 0.3048, &[("m", 1)]),
+  ("[yd_i]", false, 0.9144, &[("m", 1)]),
+  ("[mi_i]", false, 1609.344, &[("m", 1)]),
+  ("[lb_av]", false, 453.592_37, &[("g", 1)]),
+  ("[oz_av]", false, 28.349_523_125, &[("g", 1)]),
+  ("[stone_av]", false, 6_350.293_18, &[("g", 1)]),
+  ("[gal_us]", false, 3.785_411_784e-3, &[("m", 3)]),
+  ("[foz_us]", false, 2.957_352_956_25e-5, &[("m", 3)]),
+];
+
+/// Converts a quantity to its canonical form, e.g. `5 mg/dL` becomes
+/// `(50.0, "g.m-3")`, so quantities in different but commensurable units can
+/// be compared. Returns `None` for units that can't be parsed or that aren't
+/// proportional (such as `Cel`).
+pub fn canonicalize(value: f64, unit: &str) -> Option<(f64, String)> {
+  let unit = parse(unit)?;
+  Some((value * unit.factor, unit.code()))
+}
+
+/// Converts `value` from one unit to another with the same dimensions.
+pub fn convert(value: f64, from: &str, to: &str) -> Option<f64> {
+  if from == to {
+    return Some(value);
+  }
+  let from = parse(from)?;
+  let to = parse(to)?;
+  if from.dimensions != to.dimensions {
+    return None;
+  }
+  Some(value * from.factor / to.factor)
+}
+
+fn parse(unit: &str) -> Option<Unit> {
+  let chars = unit.chars().collect::<Vec<_>>();
+  let mut position = 0;
+  let unit = term(&chars, &mut position)?;
+  if position == chars.len() {
+    Some(unit)
+  } else {
+    None
+  }
+}
+
+fn term(chars: &[char], position: &mut usize) -> Option<Unit> {
+  let mut result = Unit::unity();
+  let mut sign = 1;
+  if chars.get(*position) == Some(&'/') {
+    sign = -1;
+    *position += 1;
+  }
+  loop {
+    let component = component(chars, position)?;
+    result = result.multiply(&component, sign);
+    match chars.get(*position) {
+      Some('.') => sign = 1,
+      Some('/') => sign = -1,
+      _ => return Some(result),
+    }
+    *position += 1;
+  }
+}
+
+fn component(chars: &[char], position: &mut usize) -> Option<Unit> {
+  if chars.get(*position) == Some(&'(') {
+    *position += 1;
+    let unit = term(chars, position)?;
+    if chars.get(*position) != Some(&')') {
+      return None;
+    }
+    *position += 1;
+    skip_annotation(chars, position)?;
+    return Some(unit);
+  }
+  let start = *position;
+  let mut depth = 0;
+  while let Some(&c) = chars.get(*position) {
+    match c {
+      '[' => depth += 1,
+      ']' => depth -= 1,
+      '.' | '/' | '(' | ')' | '{' if depth == 0 => break,
+      _ => {}
+    }
+    *position += 1;
+  }
+  let symbol = chars[start..*position].iter().collect::<String>();
+  skip_annotation(chars, position)?;
+  if symbol.is_empty() {
+    // A bare annotation such as `{cells}` is dimensionless.
+    return if *position > start {
+      Some(Unit::unity())
+    } else {
+      None
+    };
+  }
+  simple_unit(&symbol)
+}
+
+fn skip_annotation(chars: &[char], position: &mut usize) -> Option<()> {
+  if chars.get(*position) == Some(&'{') {
+    let end = chars[*position..].iter().position(|c| *c == '}')?;
+    *position += end + 1;
+  }
+  Some(())
+}
+
+fn simple_unit(symbol: &str) -> Option<Unit> {
+  if let Some(rest) = symbol
+    .strip_prefix("10*")
+    .or_else(|| symbol.strip_prefix("10^"))
+  {
+    let exponent = rest.parse::<i32>().ok()?;
+    return Some(Unit {
+      factor: 10f64.powi(exponent),
+      dimensions: BTreeMap::new(),
+    });
+  }
+  if symbol.chars().all(|c| c.is_ascii_digit()) {
+    return Some(Unit {
+      factor: symbol.parse().ok()?,
+      dimensions: BTreeMap::new(),
+    });
+  }
+  let split = symbol
+    .rfind(|c: char| !c.is_ascii_digit() && c != '-' && c != '+')
+    .map(|i| i + 1)
+    .unwrap_or(0);
+  let (atom, exponent) = match &symbol[split..] {
+    "" => (symbol, 1),
+    digits => (&symbol[..split], digits.parse::<i32>().ok()?),
+  };
+  Some(atom_unit(atom)?.power(exponent))
+}
+
+fn atom_unit(atom: &str) -> Option<Unit> {
+  if let Some(unit) = lookup(atom, false) {
+    return Some(unit);
+  }
+  PREFIXES.iter().find_map(|(prefix, factor)| {
+    let mut unit = lookup(atom.strip_prefix(prefix)?, true)?;
+    unit.factor *= factor;
+    Some(unit)
+  })
+}
+
+fn lookup(code: &str, prefixed: bool) -> Option<Unit> {
+  ATOMS
+    .iter()
+    .find(|(atom, metric, _, _)| *atom == code && (*metric || !prefixed))
+    .map(|(_, _, factor, dimensions)| Unit {
+      factor: *factor,
+      dimensions: dimensions.iter().cloned().collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_canonicalize() {
+    let (value, unit) = canonicalize(5.0, "mg/dL").unwrap();
+    assert!((value - 50.0).abs() < 1e-9);
+    assert_eq!(unit, "g.m-3");
+    assert_eq!(canonicalize(2.0, "kg/m2").unwrap().1, "g.m-2");
+    assert_eq!(canonicalize(1.0, "10*3/uL").unwrap().1, "m-3");
+    assert_eq!(canonicalize(1.0, "mm[Hg]").unwrap().1, "g.m-1.s-2");
+    assert_eq!(canonicalize(60.0, "/min").unwrap().1, "s-1");
+    assert_eq!(canonicalize(1.0, "{beats}/min").unwrap().1, "s-1");
+    assert_eq!(canonicalize(1.0, "Cel"), None);
+  }
+
+  #[test]
+  fn test_convert() {
+    assert_eq!(convert(1.0, "m", "cm"), Some(100.0));
+    assert_eq!(convert(2.0, "h", "min"), Some(120.0));
+    assert!((convert(1.0, "[lb_av]", "kg").unwrap() - 0.453_592_37).abs() < 1e-12);
+    assert_eq!(convert(1.0, "m", "g"), None);
+  }
+}
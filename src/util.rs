@@ -0,0 +1,61 @@
+use serde_json::value::Value;
+
+/// The elements of a JSON array, or none for anything else.
+pub(crate) fn array(value: &Value) -> &[Value] {
+  value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// A service base URL ending in a slash, ready for `Type/id` to be appended.
+pub(crate) fn service_base(base: &str) -> String {
+  match base.ends_with('/') {
+    true => base.to_string(),
+    false => format!("{}/", base),
+  }
+}
+
+pub(crate) const BASE64: &[u8; 64] =
+  b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Base64 with padding, as in HTTP Basic authorization and `Binary.data`.
+pub(crate) fn base64(bytes: &[u8]) -> String {
+  let mut encoded = encode_base64(bytes, BASE64);
+  while encoded.len() % 4 != 0 {
+    encoded.push('=');
+  }
+  encoded
+}
+
+/// Base64 in an alphabet, without padding.
+pub(crate) fn encode_base64(bytes: &[u8], alphabet: &[u8; 64]) -> String {
+  let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+  for chunk in bytes.chunks(3) {
+    let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, byte)| {
+      bits | (*byte as u32) << (16 - 8 * index)
+    });
+    for index in 0..=chunk.len() {
+      encoded.push(alphabet[(bits >> (18 - 6 * index) & 0x3f) as usize] as char);
+    }
+  }
+  encoded
+}
+
+/// Decodes base64 in an alphabet, with or without padding.
+#[cfg(any(feature = "server", feature = "smart"))]
+pub(crate) fn decode_base64(text: &str, alphabet: &[u8; 64]) -> Option<Vec<u8>> {
+  let text = text.trim_end_matches('=');
+  if text.len() % 4 == 1 {
+    return None;
+  }
+  let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+  for chunk in text.as_bytes().chunks(4) {
+    let mut bits = 0u32;
+    for (index, byte) in chunk.iter().enumerate() {
+      let value = alphabet.iter().position(|c| c == byte)? as u32;
+      bits |= value << (18 - 6 * index);
+    }
+    for index in 0..chunk.len() - 1 {
+      decoded.push((bits >> (16 - 8 * index)) as u8);
+    }
+  }
+  Some(decoded)
+}
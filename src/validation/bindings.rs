@@ -0,0 +1,259 @@
+use crate::fhirpath::Node;
+use crate::model::CodeableConcept::CodeableConcept;
+use crate::model::Coding::Coding;
+use crate::model::ElementDefinition::ElementDefinition;
+use crate::model::ElementDefinition_Binding::ElementDefinition_BindingStrength;
+use crate::model::OperationOutcome_Issue::{
+  OperationOutcome_IssueCode, OperationOutcome_IssueSeverity,
+};
+use crate::outcome::Issue;
+use crate::terminology::Terminology;
+use serde_json::json;
+use std::borrow::Cow;
+
+/// Checks a `code`, `Coding` or `CodeableConcept` against the value set its
+/// definition binds it to. A value outside the value set is an error for a
+/// required binding, a warning for an extensible one, and information for
+/// preferred and example bindings. Value sets that can't be worked out, such
+/// as those drawing on code systems that aren't loaded, are reported too.
+pub(super) fn check(
+  terminology: &Terminology,
+  id: &str,
+  element: &ElementDefinition,
+  node: &Node,
+  location: &str,
+  issues: &mut Vec<Issue>,
+) {
+  let binding = match element.binding() {
+    Some(binding) => binding,
+    None => return,
+  };
+  let (strength, url) = match (binding.strength(), binding.value_set()) {
+    (Some(strength), Some(url)) => (strength, url),
+    _ => return,
+  };
+  let value = match node.value() {
+    Some(value) => value,
+    None => return,
+  };
+  let required = matches!(strength, ElementDefinition_BindingStrength::Required);
+  let result = match node.type_name() {
+    Some("code") => {
+      let coding = Coding {
+        value: Cow::Owned(json!({ "code": value })),
+      };
+      terminology.validate_code(Some(url), &coding)
+    }
+    Some("Coding") => {
+      let coding = Coding {
+        value: Cow::Borrowed(value),
+      };
+      terminology.validate_code(Some(url), &coding)
+    }
+    // Text alone will do when no code fits, unless the binding is required.
+    Some("CodeableConcept") if required || !value["coding"].is_null() => {
+      let concept = CodeableConcept {
+        value: Cow::Borrowed(value),
+      };
+      terminology.validate_codeable_concept(Some(url), &concept)
+    }
+    _ => return,
+  };
+
+  let severity = || match strength {
+    ElementDefinition_BindingStrength::Required => OperationOutcome_IssueSeverity::Error,
+    ElementDefinition_BindingStrength::Extensible => OperationOutcome_IssueSeverity::Warning,
+    _ => OperationOutcome_IssueSeverity::Information,
+  };
+  match result {
+    Ok(parameters) => {
+      let outputs = parameters.value["parameter"].as_array().cloned();
+      let output = |name: &str| {
+        outputs
+          .iter()
+          .flatten()
+          .find(|p| p["name"] == name)
+          .cloned()
+      };
+      if output("result").is_some_and(|r| r["valueBoolean"] == false) {
+        let message = output("message");
+        let message = message
+          .as_ref()
+          .and_then(|m| m["valueString"].as_str())
+          .unwrap_or_default();
+        issues.push(
+          Issue::new(
+            severity(),
+            OperationOutcome_IssueCode::CodeInvalid,
+            &format!("{}: {}", id, message),
+          )
+          .at(location),
+        );
+      }
+    }
+    Err(problems) => {
+      // Not being able to check a code is worth a warning at most.
+      for problem in problems {
+        let severity = match severity() {
+          OperationOutcome_IssueSeverity::Error => OperationOutcome_IssueSeverity::Warning,
+          severity => severity,
+        };
+        issues.push(
+          Issue::new(
+            severity,
+            problem.code,
+            &format!(
+              "{}: the code can't be checked against {}: {}",
+              id, url, problem.diagnostics
+            ),
+          )
+          .at(location),
+        );
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::definitions::Definitions;
+  use crate::model::ResourceList::ResourceList;
+  use crate::terminology::Terminology;
+  use crate::validation::Validator;
+  use serde_json::json;
+  use std::borrow::Cow;
+  use std::path::Path;
+
+  #[test]
+  fn test_binding_strength() {
+    let mut definitions = Definitions::new();
+    let mut terminology = Terminology::new();
+    for file in &["profiles-types.json", "observation.profile.json"] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    for name in &[
+      "observation-status",
+      "observation-category",
+      "data-absent-reason",
+    ] {
+      for kind in &["codesystem", "valueset"] {
+        let path = format!("examples-json/{}-{}.json", kind, name);
+        terminology.load_file(Path::new(&path)).unwrap();
+      }
+    }
+    let validator = {
+      let mut validator = Validator::new(&definitions);
+      validator.terminology(&terminology);
+      validator
+    };
+    let issues = |observation: serde_json::Value| {
+      let resource = ResourceList {
+        value: Cow::Owned(observation),
+      };
+      validator
+        .validate(&resource)
+        .into_iter()
+        .filter(|issue| !issue.diagnostics.starts_with("dom-"))
+        .map(|issue| (issue.severity.to_string(), issue.diagnostics))
+        .collect::<Vec<_>>()
+    };
+    let issue = |severity: &str, diagnostics: &str| (severity.to_string(), diagnostics.to_string());
+
+    let category = "http://terminology.hl7.org/CodeSystem/observation-category";
+    let absent = "http://terminology.hl7.org/CodeSystem/data-absent-reason";
+    assert_eq!(
+      issues(json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "category": [{"coding": [{"system": category, "code": "laboratory"}]}],
+        "code": {"text": "Cholesterol"},
+        "dataAbsentReason": {"coding": [{"system": absent, "code": "masked"}]}
+      })),
+      vec![]
+    );
+
+    assert_eq!(
+      issues(json!({
+        "resourceType": "Observation",
+        "status": "final",
+        "category": [{"coding": [{"system": category, "code": "lab"}]}],
+        "code": {"coding": [{"system": "http://loinc.org", "code": "29463-7"}]},
+        "dataAbsentReason": {"coding": [{"system": absent, "code": "lost"}]}
+      })),
+      vec![
+        issue(
+          "information",
+          "Observation.category: Unknown code 'lab' in the CodeSystem \
+           'http://terminology.hl7.org/CodeSystem/observation-category'"
+        ),
+        issue(
+          "information",
+          "Observation.code: the code can't be checked against \
+           http://hl7.org/fhir/ValueSet/observation-codes: \
+           The ValueSet 'http://hl7.org/fhir/ValueSet/observation-codes' is not known"
+        ),
+        issue(
+          "warning",
+          "Observation.dataAbsentReason: Unknown code 'lost' in the CodeSystem \
+           'http://terminology.hl7.org/CodeSystem/data-absent-reason'"
+        ),
+      ]
+    );
+
+    // A profile can make a binding required. A bare `code` is looked for in
+    // the code systems the value set draws on.
+    definitions.add(json!({
+      "resourceType": "StructureDefinition",
+      "url": "http://example.org/StructureDefinition/coded",
+      "type": "Observation",
+      "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Observation",
+      "derivation": "constraint",
+      "differential": {"element": [
+        {"id": "Observation.language", "path": "Observation.language", "binding": {
+          "strength": "required",
+          "valueSet": "http://example.org/ValueSet/languages"
+        }},
+        {"id": "Observation.category", "path": "Observation.category", "binding": {
+          "strength": "required"
+        }}
+      ]}
+    }));
+    definitions.generate_snapshots();
+    terminology.add(json!({
+      "resourceType": "ValueSet",
+      "url": "http://example.org/ValueSet/languages",
+      "compose": {"include": [{"system": "urn:ietf:bcp:47", "concept": [{"code": "en"}, {"code": "nl"}]}]}
+    }));
+    let mut validator = Validator::new(&definitions);
+    validator.terminology(&terminology);
+    let observation = |language: &str, code: &str| ResourceList {
+      value: Cow::Owned(json!({
+        "resourceType": "Observation",
+        "language": language,
+        "status": "final",
+        "category": [{"coding": [{"system": category, "code": code}]}],
+        "code": {"text": "Cholesterol"}
+      })),
+    };
+    let profile = "http://example.org/StructureDefinition/coded";
+    let errors = |resource: &ResourceList| {
+      validator
+        .validate_against(resource, profile)
+        .into_iter()
+        .filter(|issue| issue.is_error())
+        .map(|issue| issue.diagnostics)
+        .collect::<Vec<_>>()
+    };
+    assert!(errors(&observation("nl", "laboratory")).is_empty());
+    assert_eq!(
+      errors(&observation("de", "lab")),
+      vec![
+        "Observation.language: The code 'de' is not in the value set \
+         'http://example.org/ValueSet/languages'",
+        "Observation.category: Unknown code 'lab' in the CodeSystem \
+         'http://terminology.hl7.org/CodeSystem/observation-category'",
+      ]
+    );
+  }
+}
@@ -0,0 +1,53 @@
+use super::Validator;
+use crate::fhirpath::{Context, Item};
+use crate::model::ElementDefinition::ElementDefinition;
+use crate::model::ElementDefinition_Constraint::ElementDefinition_ConstraintSeverity;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::outcome::Issue;
+
+/// Evaluates the constraints of the element definitions that apply to an
+/// element. A constraint that several definitions share, such as `ele-1`, is
+/// only checked once.
+pub(super) fn check<'r>(
+  validator: &Validator,
+  context: &Context<'r>,
+  item: &Item<'r>,
+  elements: &[ElementDefinition],
+  location: &str,
+  issues: &mut Vec<Issue>,
+) {
+  let constraints = elements
+    .iter()
+    .flat_map(|e| e.constraint().unwrap_or_default())
+    .collect::<Vec<_>>();
+  let mut checked = vec![];
+  for constraint in &constraints {
+    let key = constraint.key().unwrap_or_default();
+    let expression = match constraint.expression() {
+      Some(expression) if !checked.contains(&key) => expression,
+      _ => continue,
+    };
+    checked.push(key);
+    let human = constraint.human().unwrap_or_default();
+    match validator.evaluate(expression, context, item) {
+      Ok(Some(false)) => {
+        let diagnostics = format!("{}: {}", key, human);
+        let issue = match constraint.severity() {
+          Some(ElementDefinition_ConstraintSeverity::Warning) => {
+            Issue::warning(OperationOutcome_IssueCode::Invariant, &diagnostics)
+          }
+          _ => Issue::error(OperationOutcome_IssueCode::Invariant, &diagnostics),
+        };
+        issues.push(issue.at(location));
+      }
+      Ok(_) => {}
+      Err(error) => issues.push(
+        Issue::warning(
+          OperationOutcome_IssueCode::Processing,
+          &format!("Unable to evaluate {} ({}): {}", key, expression, error),
+        )
+        .at(location),
+      ),
+    }
+  }
+}
@@ -0,0 +1,313 @@
+//! Validation of resources against the StructureDefinitions in a
+//! [`Definitions`] registry, reporting problems as `OperationOutcome` issues.
+
+mod bindings;
+mod invariants;
+mod slicing;
+mod structure;
+
+use crate::definitions::Definitions;
+use crate::fhirpath::{self, Context, Expression, Item};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::model::ResourceList::ResourceList;
+use crate::outcome::Issue;
+use crate::resolve::ResourceSource;
+use crate::terminology::Terminology;
+use serde_json::value::Value;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+pub struct Validator<'a> {
+  definitions: &'a Definitions,
+  source: Option<&'a dyn ResourceSource>,
+  terminology: Option<&'a Terminology>,
+  expressions: RefCell<HashMap<String, Result<Expression, fhirpath::Error>>>,
+}
+
+impl<'a> Validator<'a> {
+  pub fn new(definitions: &'a Definitions) -> Validator<'a> {
+    Validator {
+      definitions,
+      source: None,
+      terminology: None,
+      expressions: RefCell::new(HashMap::new()),
+    }
+  }
+
+  /// Where invariants that call `resolve()` find referenced resources.
+  pub fn source<'b>(&'b mut self, source: &'a dyn ResourceSource) -> &'b mut Validator<'a> {
+    self.source = Some(source);
+    self
+  }
+
+  /// The CodeSystems and ValueSets that coded elements are checked against.
+  /// Without them, bindings aren't checked.
+  pub fn terminology<'b>(&'b mut self, terminology: &'a Terminology) -> &'b mut Validator<'a> {
+    self.terminology = Some(terminology);
+    self
+  }
+
+  /// Checks a resource, including any contained resources and Bundle entries,
+  /// against the base definition of its type and the profiles listed in its
+  /// `meta.profile`.
+  pub fn validate(&self, resource: &ResourceList) -> Vec<Issue> {
+    self.run(resource, |context, item, location, issues| {
+      self.validate_resource(context, item, location, issues)
+    })
+  }
+
+  /// Checks a resource against one profile, such as
+  /// `http://hl7.org/fhir/StructureDefinition/bodyweight`, whatever its
+  /// `meta.profile` says.
+  pub fn validate_against(&self, resource: &ResourceList, profile: &str) -> Vec<Issue> {
+    self.run(resource, |context, item, location, issues| {
+      self.validate_profile(context, item, profile, location, issues)
+    })
+  }
+
+  /// True when validation finds no errors. Warnings don't make a resource
+  /// invalid.
+  pub fn is_valid(&self, resource: &ResourceList) -> bool {
+    !self.validate(resource).iter().any(Issue::is_error)
+  }
+
+  fn run<'r, F>(&'r self, resource: &'r ResourceList, validate: F) -> Vec<Issue>
+  where
+    F: Fn(&Context<'r>, &Item<'r>, &str, &mut Vec<Issue>),
+  {
+    let mut issues = vec![];
+    let value = &*resource.value;
+    let resource_type = value["resourceType"].as_str().unwrap_or_default();
+    if !resource.validate() {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Structure,
+          &format!(
+            "The resource does not match the structure of {}",
+            resource_type
+          ),
+        )
+        .at(resource_type),
+      );
+    }
+    let context = self.context(value, value);
+    let item = context.node(value, None);
+    validate(&context, &item, resource_type, &mut issues);
+    // The base definition and profiles share most of their rules, so drop
+    // the repeats.
+    let mut seen = HashSet::new();
+    issues.retain(|issue| {
+      seen.insert((
+        issue.severity.to_string(),
+        issue.code.to_string(),
+        issue.expression.clone(),
+        issue.diagnostics.clone(),
+      ))
+    });
+    issues
+  }
+
+  fn context<'r>(&'r self, resource: &'r Value, root: &'r Value) -> Context<'r> {
+    let mut context = Context::new(resource);
+    context.root(root).model(self.definitions);
+    if let Some(source) = self.source {
+      context.source(source);
+    }
+    context
+  }
+
+  /// Evaluates a FHIRPath condition, parsing each distinct expression once.
+  fn evaluate<'r>(
+    &self,
+    expression: &str,
+    context: &Context<'r>,
+    focus: &Item<'r>,
+  ) -> Result<Option<bool>, fhirpath::Error> {
+    self.with_expression(expression, |parsed| {
+      parsed.evaluate_boolean(context, std::slice::from_ref(focus))
+    })
+  }
+
+  /// Evaluates a FHIRPath expression such as a discriminator path.
+  fn select<'r>(
+    &self,
+    expression: &str,
+    context: &Context<'r>,
+    focus: &Item<'r>,
+  ) -> Result<Vec<Item<'r>>, fhirpath::Error> {
+    self.with_expression(expression, |parsed| {
+      parsed.evaluate_on(context, std::slice::from_ref(focus))
+    })
+  }
+
+  fn with_expression<T, F>(&self, expression: &str, evaluate: F) -> Result<T, fhirpath::Error>
+  where
+    F: FnOnce(&Expression) -> Result<T, fhirpath::Error>,
+  {
+    let mut expressions = self.expressions.borrow_mut();
+    let parsed = expressions
+      .entry(expression.to_string())
+      .or_insert_with(|| Expression::parse(expression));
+    match parsed {
+      Ok(parsed) => evaluate(parsed),
+      Err(error) => Err(error.clone()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+  use std::borrow::Cow;
+  use std::fs;
+  use std::path::Path;
+
+  fn load(file: &str) -> Value {
+    serde_json::from_str(&fs::read_to_string(file).unwrap()).unwrap()
+  }
+
+  fn definitions() -> Definitions {
+    let mut definitions = Definitions::new();
+    for file in &[
+      "profiles-types.json",
+      "patient.profile.json",
+      "observation.profile.json",
+      "bundle.profile.json",
+      "questionnaire.profile.json",
+      "medicationrequest.profile.json",
+      "medication.profile.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    definitions
+  }
+
+  fn validate(validator: &Validator, value: &Value) -> Vec<(String, String, Option<String>)> {
+    let resource = ResourceList {
+      value: Cow::Borrowed(value),
+    };
+    validator
+      .validate(&resource)
+      .iter()
+      .map(|issue| {
+        (
+          issue.severity.to_string(),
+          issue.diagnostics.split(':').next().unwrap().to_string(),
+          issue.expression.clone(),
+        )
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_validate_examples() {
+    let definitions = definitions();
+    let validator = Validator::new(&definitions);
+    for file in &[
+      "patient-example.json",
+      "observation-example.json",
+      "observation-example-bloodpressure.json",
+      "questionnaire-example.json",
+      "bundle-example.json",
+    ] {
+      let value = load(&format!("examples-json/{}", file));
+      let resource = ResourceList {
+        value: Cow::Borrowed(&value),
+      };
+      let issues = validator.validate(&resource);
+      assert!(
+        !issues.iter().any(Issue::is_error),
+        "{}: {:?}",
+        file,
+        issues
+      );
+    }
+
+    let unknown = json!({"resourceType": "Unknown"});
+    let resource = ResourceList {
+      value: Cow::Borrowed(&unknown),
+    };
+    assert!(!validator.is_valid(&resource));
+  }
+
+  #[test]
+  fn test_invariants() {
+    let definitions = definitions();
+    let validator = Validator::new(&definitions);
+
+    let patient = json!({
+      "resourceType": "Patient",
+      "text": {"status": "generated", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Pat</div>"},
+      "contact": [{"name": {"family": "Chalmers"}}, {"gender": "female"}],
+      "_birthDate": {},
+      "generalPractitioner": [{"reference": "#missing"}]
+    });
+    assert_eq!(
+      validate(&validator, &patient),
+      vec![
+        (
+          "error".to_string(),
+          "ele-1".to_string(),
+          Some("Patient.birthDate".to_string())
+        ),
+        (
+          "error".to_string(),
+          "pat-1".to_string(),
+          Some("Patient.contact[1]".to_string())
+        ),
+        (
+          "error".to_string(),
+          "ref-1".to_string(),
+          Some("Patient.generalPractitioner[0]".to_string())
+        ),
+      ]
+    );
+
+    // Warnings are reported but don't make the resource invalid.
+    let observation = json!({
+      "resourceType": "Observation",
+      "status": "final",
+      "code": {"text": "Weight"},
+      "valueQuantity": {"value": 70, "unit": "kg"}
+    });
+    assert_eq!(
+      validate(&validator, &observation),
+      vec![(
+        "warning".to_string(),
+        "dom-6".to_string(),
+        Some("Observation".to_string())
+      )]
+    );
+    let resource = ResourceList {
+      value: Cow::Borrowed(&observation),
+    };
+    assert!(validator.is_valid(&resource));
+
+    // Entries are validated as resources, with the Bundle as %rootResource.
+    let bundle = json!({
+      "resourceType": "Bundle",
+      "type": "collection",
+      "entry": [{
+        "fullUrl": "http://example.org/Observation/1",
+        "resource": {
+          "resourceType": "Observation",
+          "text": {"status": "empty", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">-</div>"},
+          "status": "final",
+          "code": {"text": "Weight"},
+          "valueQuantity": {"value": 70, "unit": "kg"},
+          "dataAbsentReason": {"text": "Not measured"}
+        }
+      }]
+    });
+    assert_eq!(
+      validate(&validator, &bundle),
+      vec![(
+        "error".to_string(),
+        "obs-6".to_string(),
+        Some("Bundle.entry[0].resource".to_string())
+      )]
+    );
+  }
+}
@@ -0,0 +1,503 @@
+use super::structure::{json_equal, matches_pattern, Profile};
+use super::Validator;
+use crate::definitions::type_code;
+use crate::fhirpath::{Context, Item, Node};
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::outcome::Issue;
+use serde_json::json;
+use serde_json::value::Value;
+
+impl<'a> Validator<'a> {
+  /// Works out which slice of a sliced element each of its items belongs to,
+  /// checking the slicing rules, the order of the slices and the cardinality
+  /// of each slice along the way. Items that match no slice stay with the
+  /// sliced element itself.
+  pub(super) fn assign_slices<'r>(
+    &'r self,
+    context: &Context<'r>,
+    profile: Profile<'r>,
+    sliced: usize,
+    items: &[(Item<'r>, String)],
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) -> Vec<usize> {
+    let mut assigned = vec![sliced; items.len()];
+    let slices = profile.slices(sliced);
+    if slices.is_empty() {
+      return assigned;
+    }
+    let id = profile.id(sliced);
+    let slicing = &profile.elements[sliced]["slicing"];
+    // Extensions are sliced by url even when the snapshot doesn't say so.
+    let default = json!([{"type": "value", "path": "url"}]);
+    let discriminators = match &slicing["discriminator"] {
+      Value::Null if id.ends_with("xtension") => &default,
+      discriminators => discriminators,
+    };
+    let discriminators = discriminators.as_array().cloned().unwrap_or_default();
+
+    for ((item, _), assignment) in items.iter().zip(&mut assigned) {
+      let matched = slices
+        .iter()
+        .find(|slice| self.matches_slice(context, item, profile, **slice, &discriminators, issues));
+      if let Some(slice) = matched {
+        *assignment = *slice;
+      }
+    }
+
+    let ordered = slicing["ordered"].as_bool() == Some(true);
+    let rules = slicing["rules"].as_str().unwrap_or("open");
+    let mut last = 0;
+    let mut unmatched = false;
+    for ((_, item_location), assignment) in items.iter().zip(&assigned) {
+      let mut error = |diagnostics: String| {
+        issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::Structure,
+            &format!("{}: {}", id, diagnostics),
+          )
+          .at(item_location),
+        );
+      };
+      match slices.iter().position(|slice| slice == assignment) {
+        Some(position) => {
+          let name = profile.elements[*assignment]["sliceName"]
+            .as_str()
+            .unwrap_or_default();
+          if ordered && position < last {
+            error(format!("the item in slice {} is out of order", name));
+          }
+          if rules == "openAtEnd" && unmatched {
+            error(format!(
+              "the item in slice {} comes after items that match no slice",
+              name
+            ));
+          }
+          last = last.max(position);
+        }
+        None => {
+          unmatched = true;
+          if rules == "closed" {
+            error("the item does not match any slice, and the slicing is closed".to_string());
+          }
+        }
+      }
+    }
+
+    for slice in slices {
+      let members = (0..items.len())
+        .filter(|i| assigned[*i] == slice)
+        .collect::<Vec<_>>();
+      let element = profile.element(slice);
+      self.check_cardinality(profile.id(slice), &element, members.len(), location, issues);
+      // A slice can be sliced further into re-slices.
+      let resliced = members
+        .iter()
+        .map(|i| items[*i].clone())
+        .collect::<Vec<_>>();
+      let reslices = self.assign_slices(context, profile, slice, &resliced, location, issues);
+      for (member, reslice) in members.into_iter().zip(reslices) {
+        assigned[member] = reslice;
+      }
+    }
+    assigned
+  }
+
+  /// Whether an item belongs to a slice. Without discriminators, an item
+  /// belongs to the slices it conforms to.
+  fn matches_slice<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    profile: Profile<'r>,
+    slice: usize,
+    discriminators: &[Value],
+    issues: &mut Vec<Issue>,
+  ) -> bool {
+    if discriminators.is_empty() {
+      let mut found = vec![];
+      self.validate_element(context, item, profile, slice, "", &mut found);
+      return !found.iter().any(Issue::is_error);
+    }
+    discriminators.iter().all(|discriminator| {
+      let kind = discriminator["type"].as_str().unwrap_or_default();
+      let path = discriminator["path"].as_str().unwrap_or("$this");
+      match self.matches_discriminator(context, item, profile, slice, kind, path) {
+        Some(matches) => matches,
+        None => {
+          issues.push(Issue::warning(
+            OperationOutcome_IssueCode::NotSupported,
+            &format!(
+              "{}: the {} discriminator '{}' can't be evaluated",
+              profile.id(slice),
+              kind,
+              path
+            ),
+          ));
+          false
+        }
+      }
+    })
+  }
+
+  /// Tests one discriminator, or gives `None` when the slice doesn't
+  /// constrain the element the discriminator points at.
+  fn matches_discriminator<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    profile: Profile<'r>,
+    slice: usize,
+    kind: &str,
+    path: &str,
+  ) -> Option<bool> {
+    let values = match path {
+      "$this" => vec![item.clone()],
+      _ => self.select(path, context, item).ok()?,
+    };
+    let definitions = self.discriminated(profile, slice, path);
+    match kind {
+      "value" | "pattern" => {
+        let expected = definitions
+          .iter()
+          .flat_map(|(profile, index)| {
+            let element = profile.elements[*index].as_object().into_iter().flatten();
+            element.filter(|(key, _)| key.starts_with("fixed") || key.starts_with("pattern"))
+          })
+          .collect::<Vec<_>>();
+        if expected.is_empty() {
+          return None;
+        }
+        Some(expected.iter().all(|(key, expected)| {
+          values.iter().any(|value| match item_value(value) {
+            Some(value) if key.starts_with("fixed") => json_equal(value, expected),
+            Some(value) => matches_pattern(value, expected),
+            None => false,
+          })
+        }))
+      }
+      "exists" => {
+        let (profile, index) = definitions.first()?;
+        let element = profile.element(*index);
+        match (element.min(), element.max()) {
+          (Some(min), _) if min > 0 => Some(!values.is_empty()),
+          (_, Some("0")) => Some(values.is_empty()),
+          _ => None,
+        }
+      }
+      "type" => {
+        let (profile, index) = definitions.first()?;
+        let types = profile.elements[*index]["type"].as_array()?;
+        let codes = types.iter().filter_map(type_code).collect::<Vec<_>>();
+        Some(
+          !values.is_empty()
+            && values
+              .iter()
+              .all(|value| item_type(value).is_some_and(|t| codes.contains(&t))),
+        )
+      }
+      "profile" => {
+        let (profile, index) = definitions.first()?;
+        let key = match path.ends_with("resolve()") {
+          true => "targetProfile",
+          false => "profile",
+        };
+        let types = profile.elements[*index]["type"].as_array()?;
+        let urls = types
+          .iter()
+          .flat_map(|t| t[key].as_array().into_iter().flatten())
+          .filter_map(Value::as_str)
+          .collect::<Vec<_>>();
+        if urls.is_empty() {
+          return None;
+        }
+        Some(
+          values
+            .iter()
+            .any(|value| urls.iter().any(|url| self.conforms(context, value, url))),
+        )
+      }
+      _ => None,
+    }
+  }
+
+  /// The definitions of the element a discriminator path points at within a
+  /// slice. A path that goes beyond the elements of the slice continues in the
+  /// profile of their type, as with `url` for slices of extensions that name
+  /// their extension definition.
+  fn discriminated<'r>(
+    &'r self,
+    profile: Profile<'r>,
+    slice: usize,
+    path: &str,
+  ) -> Vec<(Profile<'r>, usize)> {
+    let names = path
+      .split('.')
+      .filter(|name| *name != "$this" && !name.contains('('))
+      .collect::<Vec<_>>();
+    let root = profile.elements[slice]["path"].as_str().unwrap_or_default();
+    let mut found = vec![];
+    let mut deepest = (0, slice);
+    for index in profile.descendants(slice) {
+      let element_path = profile.elements[index]["path"].as_str().unwrap_or_default();
+      let relative = match element_path.strip_prefix(root) {
+        Some("") => vec![],
+        Some(relative) => relative[1..].split('.').collect::<Vec<_>>(),
+        None => continue,
+      };
+      if relative == names {
+        found.push((profile, index));
+      } else if names.starts_with(&relative) && relative.len() > deepest.0 {
+        deepest = (relative.len(), index);
+      }
+    }
+    if !found.is_empty() {
+      return found;
+    }
+
+    let (depth, index) = deepest;
+    let types = profile.elements[index]["type"].as_array();
+    let url = types
+      .and_then(|types| types.first())
+      .and_then(|t| t["profile"][0].as_str());
+    match url.and_then(|url| self.profile(url)) {
+      Some(type_profile) => self.discriminated(type_profile, 0, &names[depth..].join(".")),
+      None => vec![],
+    }
+  }
+
+  /// Whether an item, which may be a resource of its own, has no errors
+  /// against a profile.
+  fn conforms<'r>(&'r self, context: &Context<'r>, item: &Item<'r>, url: &str) -> bool {
+    let mut found = vec![];
+    let resource = match item.as_node().and_then(Node::value) {
+      Some(value) if value.get("resourceType").is_some() => value,
+      _ => {
+        let profile = match self.profile(url) {
+          Some(profile) => profile,
+          None => return false,
+        };
+        self.validate_element(context, item, profile, 0, "", &mut found);
+        return !found.iter().any(Issue::is_error);
+      }
+    };
+    let nested = self.context(resource, context.root);
+    let item = nested.node(resource, None);
+    self.validate_profile(&nested, &item, url, "", &mut found);
+    !found.iter().any(Issue::is_error)
+  }
+}
+
+/// The JSON value of an item, for comparing against fixed values and
+/// patterns.
+fn item_value<'v>(item: &'v Item) -> Option<&'v Value> {
+  item.as_node().and_then(Node::value)
+}
+
+/// The type of an item, using the `resourceType` of resources.
+fn item_type<'v>(item: &'v Item) -> Option<&'v str> {
+  let node = item.as_node()?;
+  match node.value().and_then(|v| v["resourceType"].as_str()) {
+    Some(resource_type) => Some(resource_type),
+    None => node.type_name(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::definitions::Definitions;
+  use crate::model::ResourceList::ResourceList;
+  use std::borrow::Cow;
+  use std::fs;
+  use std::path::Path;
+
+  fn definitions() -> Definitions {
+    let mut definitions = Definitions::new();
+    for file in &[
+      "profiles-types.json",
+      "observation.profile.json",
+      "patient.profile.json",
+      "vitalsigns.profile.json",
+      "bp.profile.json",
+      "bodyweight.profile.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    definitions
+  }
+
+  fn errors(validator: &Validator, value: &Value, profile: &str) -> Vec<(String, String)> {
+    let resource = ResourceList {
+      value: Cow::Borrowed(value),
+    };
+    validator
+      .validate_against(&resource, profile)
+      .into_iter()
+      .filter(Issue::is_error)
+      .map(|issue| (issue.expression.unwrap_or_default(), issue.diagnostics))
+      .collect()
+  }
+
+  fn error(expression: &str, diagnostics: &str) -> (String, String) {
+    (expression.to_string(), diagnostics.to_string())
+  }
+
+  #[test]
+  fn test_vital_signs() {
+    let definitions = definitions();
+    let validator = Validator::new(&definitions);
+    let bp = "http://hl7.org/fhir/StructureDefinition/bp";
+    let text = fs::read_to_string("examples-json/observation-example-bloodpressure.json").unwrap();
+    let mut observation: Value = serde_json::from_str(&text).unwrap();
+    assert_eq!(errors(&validator, &observation, bp), vec![]);
+
+    // The components are told apart by their codes.
+    observation["component"][1]["code"]["coding"][0]["code"] = json!("8462-5");
+    assert_eq!(
+      errors(&validator, &observation, bp),
+      vec![error(
+        "Observation",
+        "Observation.component:DiastolicBP: minimum required = 1, but only found 0"
+      )]
+    );
+
+    // Slices of a choice are told apart by type.
+    let weight = "http://hl7.org/fhir/StructureDefinition/bodyweight";
+    let mut observation = json!({
+      "resourceType": "Observation",
+      "status": "final",
+      "category": [{"coding": [{
+        "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+        "code": "vital-signs"
+      }]}],
+      "code": {"coding": [{"system": "http://loinc.org", "code": "29463-7"}]},
+      "subject": {"reference": "Patient/example"},
+      "effectiveDateTime": "2016-03-28",
+      "valueQuantity": {"value": 185, "unit": "lbs"}
+    });
+    assert_eq!(
+      errors(&validator, &observation, weight),
+      vec![
+        error(
+          "Observation.valueQuantity",
+          "Observation.value[x]:valueQuantity.system: minimum required = 1, but only found 0"
+        ),
+        error(
+          "Observation.valueQuantity",
+          "Observation.value[x]:valueQuantity.code: minimum required = 1, but only found 0"
+        )
+      ]
+    );
+    observation["valueQuantity"]["system"] = json!("http://unitsofmeasure.org");
+    observation["valueQuantity"]["code"] = json!("[lb_av]");
+    assert_eq!(errors(&validator, &observation, weight), vec![]);
+  }
+
+  #[test]
+  fn test_slicing_rules() {
+    let mut definitions = definitions();
+    let identifier = |rules: &str| {
+      json!({
+        "resourceType": "StructureDefinition",
+        "url": format!("http://example.org/StructureDefinition/patient-{}", rules),
+        "name": "NationalPatient",
+        "type": "Patient",
+        "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+        "derivation": "constraint",
+        "differential": {"element": [
+          {"id": "Patient.identifier", "path": "Patient.identifier", "slicing": {
+            "discriminator": [{"type": "value", "path": "system"}],
+            "ordered": true,
+            "rules": rules
+          }},
+          {"id": "Patient.identifier:national", "path": "Patient.identifier",
+           "sliceName": "national", "min": 1, "max": "1"},
+          {"id": "Patient.identifier:national.system", "path": "Patient.identifier.system",
+           "fixedUri": "urn:oid:2.16.840.1.113883.2.4.6.3"},
+          {"id": "Patient.identifier:local", "path": "Patient.identifier", "sliceName": "local"},
+          {"id": "Patient.identifier:local.system", "path": "Patient.identifier.system",
+           "fixedUri": "http://example.org/mrn"},
+          {"id": "Patient.telecom", "path": "Patient.telecom", "slicing": {
+            "discriminator": [{"type": "exists", "path": "period"}],
+            "rules": "closed"
+          }},
+          {"id": "Patient.telecom:current", "path": "Patient.telecom", "sliceName": "current"},
+          {"id": "Patient.telecom:current.period", "path": "Patient.telecom.period", "max": "0"},
+          {"id": "Patient.telecom:dated", "path": "Patient.telecom", "sliceName": "dated",
+           "max": "1"},
+          {"id": "Patient.telecom:dated.period", "path": "Patient.telecom.period", "min": 1}
+        ]}
+      })
+    };
+    for rules in &["closed", "openAtEnd"] {
+      definitions.add(identifier(rules));
+    }
+    assert!(definitions.generate_snapshots().is_empty());
+    let validator = Validator::new(&definitions);
+    let closed = "http://example.org/StructureDefinition/patient-closed";
+    let open_at_end = "http://example.org/StructureDefinition/patient-openAtEnd";
+
+    let national = json!({"system": "urn:oid:2.16.840.1.113883.2.4.6.3", "value": "738472983"});
+    let local = json!({"system": "http://example.org/mrn", "value": "12345"});
+    let other = json!({"system": "http://example.org/other", "value": "1"});
+    let patient = |identifiers: Vec<&Value>| {
+      json!({
+        "resourceType": "Patient",
+        "identifier": identifiers,
+        "telecom": [
+          {"system": "phone", "value": "555-1234"},
+          {"system": "phone", "value": "555-9876", "period": {"end": "2020-01-01"}}
+        ]
+      })
+    };
+    assert_eq!(
+      errors(&validator, &patient(vec![&national, &local]), closed),
+      vec![]
+    );
+    assert_eq!(
+      errors(&validator, &patient(vec![&local, &national]), closed),
+      vec![error(
+        "Patient.identifier[1]",
+        "Patient.identifier: the item in slice national is out of order"
+      )]
+    );
+    assert_eq!(
+      errors(&validator, &patient(vec![&national, &other]), closed),
+      vec![error(
+        "Patient.identifier[1]",
+        "Patient.identifier: the item does not match any slice, and the slicing is closed"
+      )]
+    );
+    assert_eq!(
+      errors(&validator, &patient(vec![&local]), closed),
+      vec![error(
+        "Patient",
+        "Patient.identifier:national: minimum required = 1, but only found 0"
+      )]
+    );
+    assert_eq!(
+      errors(&validator, &patient(vec![&national, &other]), open_at_end),
+      vec![]
+    );
+    assert_eq!(
+      errors(&validator, &patient(vec![&other, &national]), open_at_end),
+      vec![error(
+        "Patient.identifier[1]",
+        "Patient.identifier: the item in slice national comes after items that match no slice"
+      )]
+    );
+
+    // Telecoms are told apart by whether they have a period.
+    let mut dated = patient(vec![&national]);
+    dated["telecom"][0]["period"] = json!({"start": "2019-01-01"});
+    assert_eq!(
+      errors(&validator, &dated, closed),
+      vec![error(
+        "Patient",
+        "Patient.telecom:dated: maximum allowed = 1, but found 2"
+      )]
+    );
+  }
+}
@@ -0,0 +1,817 @@
+use super::{bindings, invariants, Validator};
+use crate::definitions::type_code;
+use crate::fhirpath::{Context, Item, Node};
+use crate::model::ElementDefinition::ElementDefinition;
+use crate::model::OperationOutcome_Issue::OperationOutcome_IssueCode;
+use crate::outcome::Issue;
+use crate::resolve::{bundle_entry, reference_type};
+use serde_json::value::Value;
+use std::borrow::Cow;
+
+/// The snapshot of a StructureDefinition that elements are checked against.
+#[derive(Clone, Copy)]
+pub(super) struct Profile<'d> {
+  pub(super) type_name: &'d str,
+  pub(super) elements: &'d [Value],
+}
+
+impl<'d> Profile<'d> {
+  pub(super) fn new(structure: &'d Value) -> Option<Profile<'d>> {
+    Some(Profile {
+      type_name: structure["type"].as_str()?,
+      elements: structure["snapshot"]["element"].as_array()?,
+    })
+  }
+
+  pub(super) fn element(&self, index: usize) -> ElementDefinition<'d> {
+    ElementDefinition {
+      value: Cow::Borrowed(&self.elements[index]),
+    }
+  }
+
+  pub(super) fn id(&self, index: usize) -> &'d str {
+    let element = &self.elements[index];
+    element["id"]
+      .as_str()
+      .or_else(|| element["path"].as_str())
+      .unwrap_or_default()
+  }
+
+  /// The elements directly below an element, leaving out slices. A snapshot
+  /// lists the descendants of an element straight after it.
+  fn children(&self, index: usize) -> Vec<usize> {
+    let prefix = format!("{}.", self.id(index));
+    (index + 1..self.elements.len())
+      .map(|child| (child, self.id(child).strip_prefix(&prefix)))
+      .take_while(|(_, name)| name.is_some())
+      .filter(|(_, name)| name.is_some_and(|name| !name.contains('.') && !name.contains(':')))
+      .map(|(child, _)| child)
+      .collect()
+  }
+
+  /// The slices of an element, or the re-slices of a slice, in the order
+  /// they are defined.
+  pub(super) fn slices(&self, index: usize) -> Vec<usize> {
+    let id = self.id(index);
+    let prefix = match id.rsplit('.').next().is_some_and(|name| name.contains(':')) {
+      true => format!("{}/", id),
+      false => format!("{}:", id),
+    };
+    (index + 1..self.elements.len())
+      .filter(|slice| {
+        self
+          .id(*slice)
+          .strip_prefix(&prefix)
+          .is_some_and(|name| !name.contains(['.', '/']))
+      })
+      .collect()
+  }
+
+  /// The elements from an element down, for as long as they are its
+  /// descendants.
+  pub(super) fn descendants(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+    let prefix = format!("{}.", self.id(index));
+    let start = index + 1;
+    std::iter::once(index).chain(
+      (start..self.elements.len()).take_while(move |child| self.id(*child).starts_with(&prefix)),
+    )
+  }
+
+  pub(super) fn find(&self, id: &str) -> Option<usize> {
+    (0..self.elements.len()).find(|index| self.id(*index) == id)
+  }
+}
+
+impl<'a> Validator<'a> {
+  pub(super) fn profile(&self, url: &str) -> Option<Profile<'a>> {
+    Profile::new(self.definitions.structure(url)?)
+  }
+
+  /// Checks a resource against the base definition of its type and the
+  /// profiles it claims to conform to in `meta.profile`.
+  pub(super) fn validate_resource<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let value = match item.as_node().and_then(Node::value) {
+      Some(value) => value,
+      None => return,
+    };
+    let resource_type = value["resourceType"].as_str().unwrap_or_default();
+    let base = self.definitions.type_url(resource_type);
+    match base.and_then(|url| self.profile(url)) {
+      Some(profile) => self.validate_element(context, item, profile, 0, location, issues),
+      None => issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::NotSupported,
+          &format!(
+            "No StructureDefinition for resource type '{}'",
+            resource_type
+          ),
+        )
+        .at(location),
+      ),
+    }
+    let claimed = value["meta"]["profile"].as_array().into_iter().flatten();
+    for url in claimed.filter_map(Value::as_str) {
+      if Some(url) != base {
+        self.validate_profile(context, item, url, location, issues);
+      }
+    }
+  }
+
+  /// Checks a resource against a single profile.
+  pub(super) fn validate_profile<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    url: &str,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let resource_type = item.as_node().and_then(Node::type_name);
+    match self.profile(url) {
+      Some(profile) if Some(profile.type_name) == resource_type => {
+        self.validate_element(context, item, profile, 0, location, issues)
+      }
+      Some(profile) => issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Invalid,
+          &format!(
+            "Profile {} applies to {} rather than {}",
+            url,
+            profile.type_name,
+            resource_type.unwrap_or_default()
+          ),
+        )
+        .at(location),
+      ),
+      None => issues.push(
+        Issue::warning(
+          OperationOutcome_IssueCode::NotFound,
+          &format!("Profile {} is not known, so it was not checked", url),
+        )
+        .at(location),
+      ),
+    }
+  }
+
+  /// Checks an element against its definition in a profile, then checks its
+  /// children against the definitions below it. Elements the profile doesn't
+  /// describe further are checked against the definition of their type.
+  pub(super) fn validate_element<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    profile: Profile<'r>,
+    index: usize,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let node = match item.as_node() {
+      Some(node) => node,
+      None => return,
+    };
+    let element = profile.element(index);
+    let type_profile = match index {
+      0 => None,
+      _ => self.type_profile(&element, node),
+    };
+    let mut definitions = vec![profile.element(index)];
+    definitions.extend(type_profile.map(|p| p.element(0)));
+    invariants::check(self, context, item, &definitions, location, issues);
+    self.check_value(context, profile.id(index), &element, node, location, issues);
+    if let Some(terminology) = self.terminology {
+      bindings::check(
+        terminology,
+        profile.id(index),
+        &element,
+        node,
+        location,
+        issues,
+      );
+    }
+
+    if !profile.children(index).is_empty() {
+      self.validate_children(context, item, profile, index, location, issues);
+    } else if let Some(target) = element.content_reference() {
+      if let Some(target) = profile.find(target.trim_start_matches('#')) {
+        self.validate_children(context, item, profile, target, location, issues);
+      }
+    } else if let Some(type_profile) = type_profile {
+      self.validate_children(context, item, type_profile, 0, location, issues);
+    }
+  }
+
+  /// The profile for the type of an element: the profile the definition names
+  /// for it (like SimpleQuantity), or else the base definition of the type.
+  pub(super) fn type_profile(
+    &self,
+    element: &ElementDefinition,
+    node: &Node,
+  ) -> Option<Profile<'a>> {
+    let types = element.value.get("type")?.as_array()?;
+    let element_type = match types.as_slice() {
+      [single] => single,
+      _ => types
+        .iter()
+        .find(|t| type_code(t).is_some() && type_code(t) == node.type_name())?,
+    };
+    let code = type_code(element_type)?;
+    if matches!(code, "BackboneElement" | "Element" | "Resource") {
+      return None;
+    }
+    match element_type["profile"].as_array().and_then(|p| p.first()) {
+      Some(url) => self.profile(url.as_str()?),
+      None => self.profile(self.definitions.type_url(code)?),
+    }
+  }
+
+  fn validate_children<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    profile: Profile<'r>,
+    parent: usize,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let node = match item.as_node() {
+      Some(node) => node,
+      None => return,
+    };
+    let children = profile.children(parent);
+    let names = children
+      .iter()
+      .map(|child| {
+        element_name(
+          profile.elements[*child]["path"]
+            .as_str()
+            .unwrap_or_default(),
+        )
+      })
+      .collect::<Vec<_>>();
+
+    let container = match (node.value(), node.element.as_deref()) {
+      (Some(Value::Object(object)), _) | (_, Some(Value::Object(object))) => Some(object),
+      _ => None,
+    };
+    for key in container.into_iter().flat_map(|object| object.keys()) {
+      let key = key.strip_prefix('_').unwrap_or(key);
+      if key != "resourceType" && !names.iter().any(|name| matches_name(name, key)) {
+        issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::Structure,
+            &format!("Unknown element '{}' in {}", key, profile.id(parent)),
+          )
+          .at(location),
+        );
+      }
+    }
+
+    for (child, name) in children.into_iter().zip(names) {
+      let element = profile.element(child);
+      let id = profile.id(child);
+      // The value of a primitive is the node itself rather than a child.
+      if name == "value" && parent == 0 && profile.type_name.starts_with(char::is_lowercase) {
+        let count = node.value().is_some() as usize;
+        self.check_cardinality(id, &element, count, location, issues);
+        continue;
+      }
+      let choice = name.strip_suffix("[x]");
+      let items = context.children(item, choice.unwrap_or(name));
+      self.check_cardinality(id, &element, items.len(), location, issues);
+      let repeats = element.max() != Some("1");
+      let mut checked = vec![];
+      for (index, child_item) in items.iter().enumerate() {
+        let child_node = match child_item.as_node() {
+          Some(child_node) => child_node,
+          None => continue,
+        };
+        let key = match (choice, child_node.type_name()) {
+          (Some(choice), Some(type_name)) => format!("{}{}", choice, capitalize(type_name)),
+          _ => name.to_string(),
+        };
+        let location = match repeats {
+          true => format!("{}.{}[{}]", location, key, index),
+          false => format!("{}.{}", location, key),
+        };
+        if choice.is_some() && !allows_type(&element, child_node.type_name()) {
+          issues.push(
+            Issue::error(
+              OperationOutcome_IssueCode::Structure,
+              &format!(
+                "{}: type {} is not allowed",
+                id,
+                child_node.type_name().unwrap_or("unknown")
+              ),
+            )
+            .at(&location),
+          );
+          continue;
+        }
+        checked.push((child_item.clone(), location));
+      }
+
+      let slices = self.assign_slices(context, profile, child, &checked, location, issues);
+      for ((child_item, location), slice) in checked.iter().zip(slices) {
+        if self.holds_resources(&element) {
+          self.validate_nested(
+            context,
+            child_item,
+            &profile.element(slice),
+            location,
+            issues,
+          );
+        } else {
+          self.validate_element(context, child_item, profile, slice, location, issues);
+        }
+      }
+    }
+  }
+
+  /// Whether an element holds resources, like `contained` or
+  /// `Bundle.entry.resource`, rather than data types.
+  fn holds_resources(&self, element: &ElementDefinition) -> bool {
+    let types = element.value["type"].as_array().into_iter().flatten();
+    types.filter_map(type_code).any(|code| {
+      matches!(code, "Resource" | "DomainResource")
+        || self
+          .definitions
+          .for_type(code)
+          .is_some_and(|s| s.value["kind"] == "resource")
+    })
+  }
+
+  /// Checks a resource inside another one, such as a contained resource or a
+  /// Bundle entry, as a resource in its own right.
+  fn validate_nested<'r>(
+    &'r self,
+    context: &Context<'r>,
+    item: &Item<'r>,
+    element: &ElementDefinition,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let value = match item.as_node().map(|node| &node.value) {
+      Some(Some(Cow::Borrowed(value))) => *value,
+      _ => return,
+    };
+    invariants::check(
+      self,
+      context,
+      item,
+      std::slice::from_ref(element),
+      location,
+      issues,
+    );
+    let nested = self.context(value, context.resource);
+    self.validate_resource(&nested, item, location, issues);
+    let types = element.fhir_type().unwrap_or_default();
+    for url in types.iter().flat_map(|t| t.profile().unwrap_or_default()) {
+      self.validate_profile(&nested, item, url, location, issues);
+    }
+  }
+
+  pub(super) fn check_cardinality(
+    &self,
+    id: &str,
+    element: &ElementDefinition,
+    count: usize,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let min = element.min().unwrap_or(0);
+    if (count as u64) < min {
+      issues.push(
+        Issue::error(
+          OperationOutcome_IssueCode::Required,
+          &format!(
+            "{}: minimum required = {}, but only found {}",
+            id, min, count
+          ),
+        )
+        .at(location),
+      );
+    }
+    if let Some(max) = element.max().and_then(|max| max.parse::<usize>().ok()) {
+      if count > max {
+        issues.push(
+          Issue::error(
+            OperationOutcome_IssueCode::Structure,
+            &format!("{}: maximum allowed = {}, but found {}", id, max, count),
+          )
+          .at(location),
+        );
+      }
+    }
+    if count == 0 && element.must_support() == Some(true) {
+      issues.push(
+        Issue::information(
+          OperationOutcome_IssueCode::Informational,
+          &format!("{}: must-support element is not present", id),
+        )
+        .at(location),
+      );
+    }
+  }
+
+  /// Checks `fixed[x]`, `pattern[x]`, `maxLength` and reference targets.
+  fn check_value(
+    &self,
+    context: &Context,
+    id: &str,
+    element: &ElementDefinition,
+    node: &Node,
+    location: &str,
+    issues: &mut Vec<Issue>,
+  ) {
+    let mut error = |code, diagnostics: String| {
+      issues.push(Issue::error(code, &format!("{}: {}", id, diagnostics)).at(location));
+    };
+    let definition = match element.value.as_object() {
+      Some(definition) => definition,
+      None => return,
+    };
+    let value = node.value();
+    // A primitive with only extensions, such as a data absent reason, has no
+    // value to compare.
+    if let Some(value) = value {
+      for (key, expected) in definition {
+        if key.starts_with("fixed") && !json_equal(value, expected) {
+          error(
+            OperationOutcome_IssueCode::Value,
+            format!("value must be exactly {}", expected),
+          );
+        } else if key.starts_with("pattern") && !matches_pattern(value, expected) {
+          error(
+            OperationOutcome_IssueCode::Value,
+            format!("value must match the pattern {}", expected),
+          );
+        }
+      }
+    }
+    if let (Some(max), Some(Value::String(text))) = (element.max_length(), value) {
+      if text.chars().count() as i64 > max {
+        error(
+          OperationOutcome_IssueCode::TooLong,
+          format!("value is longer than the maximum length of {}", max),
+        );
+      }
+    }
+
+    let reference = match (node.type_name(), value) {
+      (Some("Reference"), Some(value)) => value["reference"].as_str(),
+      _ => None,
+    };
+    let targets = element
+      .fhir_type()
+      .unwrap_or_default()
+      .iter()
+      .filter(|t| t.code() == Some("Reference"))
+      .flat_map(|t| t.target_profile().unwrap_or_default())
+      .map(str::to_string)
+      .collect::<Vec<_>>();
+    let target_type = match reference {
+      Some(reference) if !targets.is_empty() => self.target_type(context, reference),
+      _ => None,
+    };
+    if let Some(target_type) = target_type {
+      let allowed = targets.iter().any(|url| {
+        url.ends_with("/Resource")
+          || match self.profile(url) {
+            Some(profile) => profile.type_name == target_type,
+            None => url.ends_with(&format!("/{}", target_type)),
+          }
+      });
+      if !allowed {
+        error(
+          OperationOutcome_IssueCode::Structure,
+          format!(
+            "a reference to {} is not allowed, it must refer to one of {}",
+            target_type,
+            targets.join(", ")
+          ),
+        );
+      }
+    }
+  }
+
+  /// The type of resource a reference points at, from a contained resource, a
+  /// Bundle entry, or the reference itself.
+  fn target_type(&self, context: &Context, reference: &str) -> Option<String> {
+    let resource = match reference.strip_prefix('#') {
+      Some(id) => context.resource["contained"]
+        .as_array()?
+        .iter()
+        .find(|c| c["id"].as_str() == Some(id)),
+      None => bundle_entry(context.root, reference),
+    };
+    match resource {
+      Some(resource) => resource["resourceType"].as_str().map(str::to_string),
+      None => reference_type(reference).map(str::to_string),
+    }
+  }
+}
+
+/// The last part of an element path, e.g. `value[x]` for `Observation.value[x]`.
+fn element_name(path: &str) -> &str {
+  path.rsplit('.').next().unwrap_or(path)
+}
+
+/// Whether a JSON property is the element with a given name, including the
+/// typed names of choice elements.
+fn matches_name(name: &str, key: &str) -> bool {
+  match name.strip_suffix("[x]") {
+    Some(choice) => key
+      .strip_prefix(choice)
+      .is_some_and(|suffix| suffix.starts_with(char::is_uppercase)),
+    None => name == key,
+  }
+}
+
+fn allows_type(element: &ElementDefinition, type_name: Option<&str>) -> bool {
+  let types = element.value["type"].as_array().into_iter().flatten();
+  types
+    .filter_map(type_code)
+    .any(|code| Some(code) == type_name)
+}
+
+fn capitalize(text: &str) -> String {
+  let mut chars = text.chars();
+  match chars.next() {
+    Some(first) => first.to_uppercase().chain(chars).collect(),
+    None => String::new(),
+  }
+}
+
+/// Equality of JSON values that treats `1` and `1.0` as the same number.
+pub(super) fn json_equal(a: &Value, b: &Value) -> bool {
+  match (a, b) {
+    (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+    (Value::Array(a), Value::Array(b)) => {
+      a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b))
+    }
+    (Value::Object(a), Value::Object(b)) => {
+      a.len() == b.len()
+        && a
+          .iter()
+          .all(|(key, value)| b.get(key).is_some_and(|other| json_equal(value, other)))
+    }
+    _ => a == b,
+  }
+}
+
+/// Whether a value has everything in a pattern: each property of the pattern,
+/// and for arrays, a matching item for each item of the pattern.
+pub(super) fn matches_pattern(value: &Value, pattern: &Value) -> bool {
+  match (value, pattern) {
+    (Value::Object(value), Value::Object(pattern)) => pattern.iter().all(|(key, expected)| {
+      value
+        .get(key)
+        .is_some_and(|actual| matches_pattern(actual, expected))
+    }),
+    (Value::Array(values), Value::Array(patterns)) => patterns
+      .iter()
+      .all(|pattern| values.iter().any(|value| matches_pattern(value, pattern))),
+    (value, pattern) => json_equal(value, pattern),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::definitions::Definitions;
+  use crate::model::ResourceList::ResourceList;
+  use serde_json::json;
+  use std::fs;
+  use std::path::Path;
+
+  fn definitions() -> Definitions {
+    let mut definitions = Definitions::new();
+    for file in &[
+      "profiles-types.json",
+      "observation.profile.json",
+      "vitalsigns.profile.json",
+      "bodyweight.profile.json",
+      "patient.profile.json",
+    ] {
+      let path = format!("examples-json/{}", file);
+      definitions.load_file(Path::new(&path)).unwrap();
+    }
+    definitions
+  }
+
+  fn issues(issues: Vec<Issue>) -> Vec<(String, String, String)> {
+    issues
+      .iter()
+      .map(|issue| {
+        (
+          issue.severity.to_string(),
+          issue.expression.clone().unwrap_or_default(),
+          issue.diagnostics.clone(),
+        )
+      })
+      .collect()
+  }
+
+  fn issue(severity: &str, expression: &str, diagnostics: &str) -> (String, String, String) {
+    (
+      severity.to_string(),
+      expression.to_string(),
+      diagnostics.to_string(),
+    )
+  }
+
+  #[test]
+  fn test_meta_profile() {
+    let definitions = definitions();
+    let validator = Validator::new(&definitions);
+    let observation = json!({
+      "resourceType": "Observation",
+      "meta": {"profile": [
+        "http://hl7.org/fhir/StructureDefinition/bodyweight",
+        "http://example.org/StructureDefinition/unknown"
+      ]},
+      "text": {"status": "empty", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">-</div>"},
+      "status": "final",
+      "category": [{"coding": [{
+        "system": "http://terminology.hl7.org/CodeSystem/observation-category",
+        "code": "vital-signs"
+      }]}],
+      "code": {"coding": [{"system": "http://loinc.org", "code": "29463-7"}]},
+      "subject": {"reference": "Group/102"},
+      "valueString": "heavy"
+    });
+    let resource = ResourceList {
+      value: Cow::Borrowed(&observation),
+    };
+    assert_eq!(
+      issues(validator.validate(&resource)),
+      vec![
+        issue(
+          "error",
+          "Observation.subject",
+          "Observation.subject: a reference to Group is not allowed, it must refer to one of \
+           http://hl7.org/fhir/StructureDefinition/Patient"
+        ),
+        issue(
+          "error",
+          "Observation",
+          "Observation.effective[x]: minimum required = 1, but only found 0"
+        ),
+        issue(
+          "information",
+          "Observation",
+          "Observation.effective[x]: must-support element is not present"
+        ),
+        issue(
+          "error",
+          "Observation.valueString",
+          "Observation.value[x]: type string is not allowed"
+        ),
+        issue(
+          "information",
+          "Observation",
+          "Observation.value[x]:valueQuantity: must-support element is not present"
+        ),
+        issue(
+          "information",
+          "Observation",
+          "Observation.dataAbsentReason: must-support element is not present"
+        ),
+        issue(
+          "information",
+          "Observation",
+          "Observation.component: must-support element is not present"
+        ),
+        issue(
+          "warning",
+          "Observation",
+          "Profile http://example.org/StructureDefinition/unknown is not known, so it was not \
+           checked"
+        ),
+      ]
+    );
+
+    // The base definition alone is satisfied.
+    let base = Validator::new(&definitions).validate_against(
+      &resource,
+      "http://hl7.org/fhir/StructureDefinition/Observation",
+    );
+    assert!(!base.iter().any(Issue::is_error), "{:?}", base);
+  }
+
+  #[test]
+  fn test_fixed_pattern_max_length() {
+    // A profile on Patient that fixes the gender, requires a marital status
+    // coding and limits the length of the id.
+    let contents = fs::read_to_string("examples-json/patient.profile.json").unwrap();
+    let mut profile: Value = serde_json::from_str(&contents).unwrap();
+    profile["url"] = json!("http://example.org/StructureDefinition/married-woman");
+    profile["derivation"] = json!("constraint");
+    for element in profile["snapshot"]["element"].as_array_mut().unwrap() {
+      match element["id"].as_str().unwrap() {
+        "Patient.id" => element["maxLength"] = json!(8),
+        "Patient.gender" => element["fixedCode"] = json!("female"),
+        "Patient.maritalStatus" => {
+          element["min"] = json!(1);
+          element["patternCodeableConcept"] = json!({"coding": [{
+            "system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus",
+            "code": "M"
+          }]});
+        }
+        _ => {}
+      }
+    }
+    let mut definitions = definitions();
+    definitions.add(profile);
+    let validator = Validator::new(&definitions);
+    let url = "http://example.org/StructureDefinition/married-woman";
+
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "married-woman-1",
+      "text": {"status": "empty", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">-</div>"},
+      "gender": "male",
+      "maritalStatus": {
+        "coding": [{"system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus", "code": "S"}],
+        "text": "Single"
+      }
+    });
+    let resource = ResourceList {
+      value: Cow::Borrowed(&patient),
+    };
+    assert_eq!(
+      issues(validator.validate_against(&resource, url)),
+      vec![
+        issue(
+          "error",
+          "Patient.id",
+          "Patient.id: value is longer than the maximum length of 8"
+        ),
+        issue(
+          "error",
+          "Patient.gender",
+          "Patient.gender: value must be exactly \"female\""
+        ),
+        issue(
+          "error",
+          "Patient.maritalStatus",
+          "Patient.maritalStatus: value must match the pattern {\"coding\":[{\"code\":\"M\",\
+           \"system\":\"http://terminology.hl7.org/CodeSystem/v3-MaritalStatus\"}]}"
+        ),
+      ]
+    );
+
+    // Extra codings and text don't stop a pattern from matching.
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "mw-1",
+      "text": {"status": "empty", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">-</div>"},
+      "gender": "female",
+      "maritalStatus": {
+        "coding": [
+          {"system": "http://example.org/local", "code": "wed"},
+          {"system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus", "code": "M"}
+        ],
+        "text": "Married"
+      }
+    });
+    let resource = ResourceList {
+      value: Cow::Borrowed(&patient),
+    };
+    assert_eq!(issues(validator.validate_against(&resource, url)), vec![]);
+
+    // A gender with only a data absent reason has no value to fix.
+    let patient = json!({
+      "resourceType": "Patient",
+      "id": "mw-2",
+      "text": {"status": "empty", "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">-</div>"},
+      "_gender": {"extension": [{
+        "url": "http://hl7.org/fhir/StructureDefinition/data-absent-reason",
+        "valueCode": "asked-declined"
+      }]},
+      "maritalStatus": {
+        "coding": [{"system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus", "code": "M"}]
+      }
+    });
+    let resource = ResourceList {
+      value: Cow::Borrowed(&patient),
+    };
+    assert_eq!(issues(validator.validate_against(&resource, url)), vec![]);
+
+    let observation = json!({"resourceType": "Observation"});
+    let resource = ResourceList {
+      value: Cow::Borrowed(&observation),
+    };
+    let found = validator.validate_against(&resource, url);
+    assert_eq!(
+      found[0].diagnostics,
+      format!("Profile {} applies to Patient rather than Observation", url)
+    );
+  }
+}